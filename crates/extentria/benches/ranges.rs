@@ -0,0 +1,88 @@
+//! Timing range reads of fragmented and unfragmented files, with and without buffer tuning.
+//!
+//! Run with `cargo bench -p extentria`, optionally with a name filter. Files are made in the
+//! temp dir, so what's measured is that filesystem's extent queries.
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{Seek as _, SeekFrom, Write as _};
+use std::time::{Duration, Instant};
+
+use extentria::{RangeReader, RangeReaderImpl};
+
+/// How long to run each benchmark for, after warming up.
+const RUN_FOR: Duration = Duration::from_secs(2);
+
+/// A file with a data block every other block, so each is an extent of its own.
+fn fragmented(blocks: u64) -> File {
+    let mut file = tempfile::tempfile().unwrap();
+    for block in 0..blocks {
+        file.seek(SeekFrom::Start(block * 8192)).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+    }
+    file.sync_all().unwrap();
+    file
+}
+
+/// A file of one data range.
+fn contiguous() -> File {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&[1; 64 * 1024]).unwrap();
+    file.sync_all().unwrap();
+    file
+}
+
+/// Read ranges of each file in turn with a reader until the time's up, and print the time
+/// taken per file.
+fn bench(name: &str, filter: Option<&str>, mut reader: RangeReader, files: &[File]) {
+    if filter.is_some_and(|filter| !name.contains(filter)) {
+        return;
+    }
+    let mut read = |count: u32| {
+        for file in files.iter().cycle().take(count as usize) {
+            for range in reader.read_ranges(file).unwrap() {
+                black_box(range.unwrap());
+            }
+        }
+    };
+    read(files.len() as u32);
+
+    let mut count = 0;
+    let start = Instant::now();
+    while start.elapsed() < RUN_FOR {
+        read(100);
+        count += 100;
+    }
+    println!("{name:40} {:>12.2?}/file", start.elapsed() / count);
+}
+
+fn main() {
+    // Cargo passes --bench, and any name filter after it
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let filter = filter.as_deref();
+
+    let tuned = |size| {
+        let mut reader = RangeReader::with_buffer_size(size);
+        reader.set_buffer_tuning(true);
+        reader
+    };
+
+    let files = [fragmented(5000)];
+    bench("fragmented/default", filter, RangeReader::new(), &files);
+    bench(
+        "fragmented/small",
+        filter,
+        RangeReader::with_buffer_size(4096),
+        &files,
+    );
+    bench("fragmented/tuned", filter, tuned(4096), &files);
+
+    let files = [contiguous()];
+    bench("contiguous/default", filter, RangeReader::new(), &files);
+    bench("contiguous/tuned", filter, tuned(64 * 1024), &files);
+
+    let mut files: Vec<File> = (0..50).map(|_| contiguous()).collect();
+    files.push(fragmented(5000));
+    bench("mixed/default", filter, RangeReader::new(), &files);
+    bench("mixed/tuned", filter, tuned(64 * 1024), &files);
+}
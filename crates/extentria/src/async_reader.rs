@@ -0,0 +1,168 @@
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io;
+
+use crate::RangeReader;
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::fs_kind::RangeSource;
+use crate::types::{DataRange, RangeReaderImpl};
+
+/// Range reader for async code, running queries on tokio's blocking pool.
+///
+/// Extent queries are blocking syscalls, so this moves a [`RangeReader`] onto the blocking
+/// pool for each query and takes it back afterwards, keeping its buffer across calls as the
+/// sync reader does. As the iterator can't leave the blocking thread, ranges are collected.
+///
+/// If a query's future is dropped before it completes, the query still runs to the end but
+/// its reader is lost, and the next query starts with a fresh one.
+#[derive(Debug, Default)]
+pub struct AsyncRangeReader {
+    reader: Option<RangeReader>,
+}
+
+impl AsyncRangeReader {
+    /// Create a new reader with default buffer size.
+    pub fn new() -> Self {
+        Self::from(RangeReader::new())
+    }
+
+    /// Create a reader with a specific buffer size.
+    ///
+    /// On platforms that don't use buffers, this is equivalent to `new()`.
+    pub fn with_buffer_size(size: usize) -> Self {
+        Self::from(RangeReader::with_buffer_size(size))
+    }
+
+    /// Create a reader reusing an existing buffer.
+    ///
+    /// On platforms that don't use buffers, the buffer is ignored.
+    pub fn with_buffer(buf: Box<[u8]>) -> Self {
+        Self::from(RangeReader::with_buffer(buf))
+    }
+
+    /// Consume the reader and return its buffer for reuse.
+    ///
+    /// Returns `None` on platforms that don't use buffers.
+    pub fn into_buffer(self) -> Option<Box<[u8]>> {
+        self.reader.and_then(RangeReader::into_buffer)
+    }
+
+    /// Enable or disable caching which extent queries each filesystem supports.
+    ///
+    /// See [`RangeReaderImpl::set_support_cache()`].
+    pub fn set_support_cache(&mut self, enabled: bool) {
+        self.reader
+            .get_or_insert_default()
+            .set_support_cache(enabled);
+    }
+
+    /// Enable or disable merging adjacent ranges that are stored the same way.
+    ///
+    /// See [`RangeReaderImpl::set_coalesce()`].
+    pub fn set_coalesce(&mut self, enabled: bool) {
+        self.reader.get_or_insert_default().set_coalesce(enabled);
+    }
+
+    /// Cap data ranges at a maximum length, or stop capping them with `None`.
+    ///
+    /// See [`RangeReaderImpl::split_at()`].
+    pub fn split_at(&mut self, max_len: Option<u64>) {
+        self.reader.get_or_insert_default().split_at(max_len);
+    }
+
+    /// Read files with no extent query for runs of zeros, or stop with `None`.
+    ///
+    /// See [`RangeReaderImpl::set_zero_scan()`].
+    pub fn set_zero_scan(&mut self, min_run: Option<u64>) {
+        self.reader.get_or_insert_default().set_zero_scan(min_run);
+    }
+
+    /// Forget which extent queries filesystems support.
+    pub fn clear_support_cache(&mut self) {
+        if let Some(reader) = &mut self.reader {
+            reader.clear_support_cache();
+        }
+    }
+
+    /// How ranges were found by the last query.
+    ///
+    /// See [`RangeReaderImpl::last_method()`]. Returns `None` if the last query's future was
+    /// dropped before it completed.
+    pub fn last_method(&self) -> Option<Method> {
+        self.reader.as_ref().map(RangeReader::last_method)
+    }
+
+    /// Where the ranges from the last query came from.
+    ///
+    /// See [`RangeReaderImpl::last_source()`]. Returns `None` if the last query's future was
+    /// dropped before it completed.
+    pub fn last_source(&self) -> Option<RangeSource> {
+        self.reader.as_ref().map(RangeReader::last_source)
+    }
+
+    /// Read data ranges for a file.
+    ///
+    /// The file can be given owned or shared (e.g. as an `Arc<File>`), as it has to be sent to
+    /// the blocking pool.
+    ///
+    /// # Panics
+    ///
+    /// This method panics when called outside of a tokio runtime.
+    pub async fn read_ranges<F>(&mut self, file: F) -> Result<Vec<DataRange>, ExtentError>
+    where
+        F: Borrow<File> + Send + 'static,
+    {
+        let mut reader = self.reader.take().unwrap_or_default();
+        let task = tokio::task::spawn_blocking(move || {
+            let ranges = reader
+                .read_ranges(file.borrow())
+                .and_then(|iter| iter.collect());
+            (reader, ranges)
+        });
+
+        match task.await {
+            Ok((reader, ranges)) => {
+                self.reader = Some(reader);
+                ranges
+            }
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => Err(io::Error::other(err).into()),
+        }
+    }
+}
+
+impl From<RangeReader> for AsyncRangeReader {
+    fn from(reader: RangeReader) -> Self {
+        Self {
+            reader: Some(reader),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn matches_sync_reader() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 10000]).unwrap();
+        temp.flush().unwrap();
+
+        let expected = crate::ranges_for_file(temp.as_file());
+        let mut reader = AsyncRangeReader::new();
+        let file = Arc::new(temp.reopen().unwrap());
+        for _ in 0..2 {
+            let ranges = reader.read_ranges(Arc::clone(&file)).await;
+            match (&expected, ranges) {
+                (Ok(expected), Ok(ranges)) => assert_eq!(&ranges, expected),
+                (Err(expected), Err(err)) => assert_eq!(err.to_string(), expected.to_string()),
+                (expected, ranges) => panic!("{expected:?} != {ranges:?}"),
+            }
+        }
+    }
+}
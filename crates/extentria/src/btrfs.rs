@@ -0,0 +1,180 @@
+//! Reading the generations btrfs wrote a file's extents in.
+//!
+//! Every btrfs file extent item records the transaction (generation) that wrote it, which
+//! FIEMAP doesn't give. Searching the file's subvolume tree for its extent items with
+//! BTRFS_IOC_TREE_SEARCH gets them, so incremental backups can tell which ranges were
+//! written since the last one without reading them. The search is only allowed to
+//! privileged processes (CAP_SYS_ADMIN).
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::MetadataExt as _;
+
+use linux_raw_sys::btrfs::{
+    BTRFS_EXTENT_DATA_KEY, BTRFS_FILE_EXTENT_INLINE, btrfs_ioctl_search_args,
+    btrfs_ioctl_search_header, btrfs_ioctl_search_key,
+};
+use linux_raw_sys::ioctl::BTRFS_IOC_TREE_SEARCH;
+
+use crate::types::DataRange;
+
+/// `f_type` of btrfs filesystems.
+const BTRFS_MAGIC: u32 = 0x9123_683e;
+
+/// Length of a search result header in the results buffer.
+const HEADER_LEN: usize = size_of::<btrfs_ioctl_search_header>();
+
+/// A file's extent items, as the spans of the file they cover and their generations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ExtentGenerations {
+    /// Start, end, and generation of each extent item, in order of start.
+    items: Vec<(u64, u64, u64)>,
+}
+
+impl ExtentGenerations {
+    /// Read the generations of a file's extents, if it's on btrfs and the search is
+    /// allowed.
+    pub(crate) fn read(file: &File) -> Option<Self> {
+        if crate::capabilities::filesystem_type(file).ok()? != BTRFS_MAGIC {
+            return None;
+        }
+        Self::search(file).ok()
+    }
+
+    /// Search the file's subvolume tree for its extent items.
+    fn search(file: &File) -> io::Result<Self> {
+        let inode = file.metadata()?.ino();
+        // SAFETY: all zeroes is valid for the plain integers of the struct
+        let mut args: btrfs_ioctl_search_args = unsafe { std::mem::zeroed() };
+        let mut generations = Self::default();
+        let mut min_offset = 0;
+        loop {
+            args.key = btrfs_ioctl_search_key {
+                // The subvolume the file is in
+                tree_id: 0,
+                min_objectid: inode,
+                max_objectid: inode,
+                min_offset,
+                max_offset: u64::MAX,
+                min_transid: 0,
+                max_transid: u64::MAX,
+                min_type: BTRFS_EXTENT_DATA_KEY,
+                max_type: BTRFS_EXTENT_DATA_KEY,
+                nr_items: u32::MAX,
+                ..args.key
+            };
+            // SAFETY: the fd is borrowed from a live File, and the kernel reads the key and
+            // fills the buffer of the struct it's given
+            if unsafe { libc::ioctl(file.as_raw_fd(), BTRFS_IOC_TREE_SEARCH as _, &raw mut args) }
+                != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: c_char and u8 have the same layout
+            let buf =
+                unsafe { std::slice::from_raw_parts(args.buf.as_ptr().cast(), args.buf.len()) };
+            match generations.parse(buf, args.key.nr_items as usize) {
+                Some(last) if last < u64::MAX => min_offset = last + 1,
+                _ => break,
+            }
+        }
+        Ok(generations)
+    }
+
+    /// Add the extent items among `count` search results, giving the offset of the last
+    /// result, or `None` if there were none.
+    fn parse(&mut self, buf: &[u8], count: usize) -> Option<u64> {
+        let mut pos = 0;
+        let mut last = None;
+        for _ in 0..count {
+            // Headers are in the CPU's byte order, unlike the items
+            let header = buf.get(pos..pos + HEADER_LEN)?;
+            let word = |at: usize| u32::from_ne_bytes(header[at..at + 4].try_into().unwrap());
+            let offset = u64::from_ne_bytes(header[16..24].try_into().unwrap());
+            let (kind, len) = (word(24), word(28) as usize);
+            let item = buf.get(pos + HEADER_LEN..pos + HEADER_LEN + len)?;
+            pos += HEADER_LEN + len;
+            last = Some(offset);
+
+            if kind == BTRFS_EXTENT_DATA_KEY
+                && let Some((generation, length)) = extent_item(item)
+            {
+                self.items
+                    .push((offset, offset.saturating_add(length), generation));
+            }
+        }
+        last
+    }
+
+    /// The latest generation of the extent items overlapping a range, if any do.
+    pub(crate) fn of(&self, range: &DataRange) -> Option<u64> {
+        let first = self
+            .items
+            .partition_point(|&(_, end, _)| end <= range.offset);
+        self.items[first..]
+            .iter()
+            .take_while(|&&(start, _, _)| start < range.end())
+            .map(|&(_, _, generation)| generation)
+            .max()
+    }
+}
+
+/// The generation of a file extent item, and the length of the file it covers.
+fn extent_item(item: &[u8]) -> Option<(u64, u64)> {
+    let le = |at: usize| Some(u64::from_le_bytes(item.get(at..at + 8)?.try_into().ok()?));
+    let generation = le(0)?;
+    // Inline extents hold their data right after the type, so their length is the data's
+    let length = match *item.get(20)? {
+        kind if kind == BTRFS_FILE_EXTENT_INLINE as u8 => le(8)?,
+        _ => le(45)?,
+    };
+    Some((generation, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A search result with a regular extent item.
+    fn result(offset: u64, generation: u64, num_bytes: u64) -> Vec<u8> {
+        let mut item = vec![0; 53];
+        item[0..8].copy_from_slice(&generation.to_le_bytes());
+        item[20] = 1;
+        item[45..53].copy_from_slice(&num_bytes.to_le_bytes());
+
+        let mut buf = Vec::new();
+        buf.extend(7u64.to_ne_bytes());
+        buf.extend(257u64.to_ne_bytes());
+        buf.extend(offset.to_ne_bytes());
+        buf.extend(BTRFS_EXTENT_DATA_KEY.to_ne_bytes());
+        buf.extend((item.len() as u32).to_ne_bytes());
+        buf.extend(item);
+        buf
+    }
+
+    #[test]
+    fn parses_extent_items() {
+        let mut buf = result(0, 10, 4096);
+        buf.extend(result(4096, 12, 8192));
+        buf.extend(result(65536, 11, 4096));
+
+        let mut generations = ExtentGenerations::default();
+        assert_eq!(generations.parse(&buf, 3), Some(65536));
+        assert_eq!(generations.parse(&[], 0), None);
+
+        assert_eq!(generations.of(&DataRange::new(0, 4096)), Some(10));
+        assert_eq!(generations.of(&DataRange::new(0, 12288)), Some(12));
+        assert_eq!(generations.of(&DataRange::new(8192, 100)), Some(12));
+        assert_eq!(generations.of(&DataRange::new(12288, 4096)), None);
+        assert_eq!(generations.of(&DataRange::new(65536, 1 << 20)), Some(11));
+    }
+
+    #[test]
+    fn skips_other_filesystems() {
+        let file = tempfile::tempfile().unwrap();
+        if crate::capabilities::filesystem_type(&file).unwrap() != BTRFS_MAGIC {
+            assert_eq!(ExtentGenerations::read(&file), None);
+        }
+    }
+}
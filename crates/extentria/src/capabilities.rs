@@ -0,0 +1,345 @@
+//! Reporting what extent information can be had for a file's filesystem.
+//!
+//! Readers fall back silently when a filesystem doesn't support the platform's extent
+//! query, which keeps scans going but makes it hard to tell afterwards how faithful they
+//! were. [`capabilities()`] probes a file once the way [`RangeReader`](crate::RangeReader)
+//! would, and reports which method is used and what it can tell apart.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::error::ExtentError;
+
+/// How ranges are found for files on a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Method {
+    /// The FIEMAP ioctl (Linux).
+    Fiemap,
+    /// `lseek` with SEEK_HOLE and SEEK_DATA.
+    SeekHole,
+    /// FSCTL_QUERY_ALLOCATED_RANGES (Windows).
+    AllocatedRanges,
+    /// No query: the whole file is one data range.
+    WholeFile,
+    /// No query, but the file is read, and runs of zeros reported as holes (see
+    /// [`set_zero_scan`](crate::RangeReaderImpl::set_zero_scan)).
+    ZeroScan,
+}
+
+/// What extent information can be had for files on a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    /// How ranges are found.
+    pub extent_query: Method,
+    /// Sparse holes are told apart from data.
+    pub sparse: bool,
+    /// The filesystem can share extents between files (reflinks), and the query says so.
+    pub shared: bool,
+    /// The query knows where extents are on disk.
+    pub physical: bool,
+    /// The smallest hole the filesystem reports, where it says (FreeBSD).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub min_hole_size: Option<u64>,
+    /// Holes in data not yet written out are reported as data, until the file is synced
+    /// (ZFS); see [`set_sync_first`](crate::RangeReaderImpl::set_sync_first).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub holes_need_sync: bool,
+}
+
+impl Capabilities {
+    fn with_method(extent_query: Method) -> Self {
+        Self {
+            extent_query,
+            sparse: extent_query != Method::WholeFile,
+            shared: false,
+            physical: extent_query == Method::Fiemap,
+            min_hole_size: None,
+            holes_need_sync: false,
+        }
+    }
+}
+
+/// Probe what extent information can be had for a file's filesystem.
+///
+/// This issues the same queries as reading the file's ranges would, but only asks for the
+/// first extent, so it's cheap on any file. Results hold for every file on the same
+/// filesystem.
+pub fn capabilities(file: &File) -> Result<Capabilities, ExtentError> {
+    probe(file)
+}
+
+/// Probe what extent information can be had for the filesystem of the file at a path.
+///
+/// See [`capabilities()`]. The path must be a file that can be opened for reading.
+pub fn capabilities_of_path(path: impl AsRef<Path>) -> Result<Capabilities, ExtentError> {
+    probe(&File::open(path)?)
+}
+
+/// The block size of a file's filesystem, in bytes: clusters on Windows.
+///
+/// Filesystems allocate space in whole blocks, so extents start at block boundaries and
+/// their lengths are multiples of the block size, except at the end of a file; holes
+/// smaller than a block can't be made.
+pub fn block_size(file: &File) -> Result<u64, ExtentError> {
+    fs_block_size(file)
+}
+
+#[cfg(unix)]
+fn fs_block_size(file: &File) -> Result<u64, ExtentError> {
+    use std::os::fd::AsRawFd;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the fd is borrowed from a live File, and fstatvfs fills the struct on success
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: initialised by the successful call above
+    let stat = unsafe { stat.assume_init() };
+    // The fragment size is the unit of allocation; some systems leave it zero
+    #[allow(
+        clippy::unnecessary_cast,
+        reason = "the fields are narrower on some platforms"
+    )]
+    Ok(match stat.f_frsize {
+        0 => stat.f_bsize as u64,
+        size => size as u64,
+    })
+}
+
+#[cfg(target_os = "windows")]
+fn fs_block_size(file: &File) -> Result<u64, ExtentError> {
+    use std::os::windows::io::AsRawHandle;
+
+    let handle = file.as_raw_handle() as windows_sys::Win32::Foundation::HANDLE;
+    Ok(crate::retrieval::cluster_size(handle)?)
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+fn fs_block_size(file: &File) -> Result<u64, ExtentError> {
+    let _ = file;
+    Err(ExtentError::unsupported())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn probe(file: &File) -> Result<Capabilities, ExtentError> {
+    use std::os::fd::AsFd;
+
+    use crate::fiemap::{FiemapLookup, result_size};
+
+    /// Filesystems whose FIEMAP flags extents shared with other files.
+    const REFLINK_FILESYSTEMS: [u32; 4] = [
+        0x9123_683e, // btrfs
+        0x5846_5342, // xfs
+        0xca45_1a4e, // bcachefs
+        0x7461_636f, // ocfs2
+    ];
+
+    let file_size = file.metadata()?.len();
+    let lookup = FiemapLookup::for_file_size(file_size.max(1));
+    match lookup.with_buf_size(file.as_fd(), result_size()) {
+        Ok(_) => {
+            let fs_type = filesystem_type(file)?;
+            Ok(Capabilities {
+                shared: REFLINK_FILESYSTEMS.contains(&fs_type),
+                ..Capabilities::with_method(Method::Fiemap)
+            })
+        }
+        Err(err) if crate::linux::is_fiemap_unsupported(&err) => probe_seek(file),
+        Err(err) => Err(ExtentError::kernel(Method::Fiemap)(err)),
+    }
+}
+
+/// The `f_type` magic number of a file's filesystem.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn filesystem_type(file: &File) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: the fd is borrowed from a live File, and fstatfs fills the struct on success
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialised by the successful call above
+    let stat = unsafe { stat.assume_init() };
+    // The field's type varies by architecture, but magic numbers fit 32 bits
+    Ok(stat.f_type as u32)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos"
+))]
+fn probe_seek(file: &File) -> Result<Capabilities, ExtentError> {
+    use std::os::fd::AsRawFd;
+
+    match crate::unix_seek::seek_data(file.as_raw_fd(), 0) {
+        // ENXIO: no data after the offset, as for an empty or entirely sparse file
+        Ok(_) => Ok(Capabilities::with_method(Method::SeekHole)),
+        Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {
+            Ok(Capabilities::with_method(Method::SeekHole))
+        }
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ESPIPE)
+            ) =>
+        {
+            Ok(Capabilities::with_method(Method::WholeFile))
+        }
+        Err(err) => Err(ExtentError::kernel(Method::SeekHole)(err)),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn probe(file: &File) -> Result<Capabilities, ExtentError> {
+    let caps = probe_seek(file)?;
+    if caps.extent_query != Method::SeekHole {
+        return Ok(caps);
+    }
+    // F_LOG2PHYS_EXT locates data on both, but only APFS has clones
+    let fs_name = crate::macos::filesystem_name(file)?;
+    Ok(Capabilities {
+        shared: fs_name == "apfs",
+        physical: matches!(fs_name.as_str(), "apfs" | "hfs"),
+        ..caps
+    })
+}
+
+#[cfg(target_os = "freebsd")]
+fn probe(file: &File) -> Result<Capabilities, ExtentError> {
+    // SEEK_HOLE works everywhere, but only finds holes on filesystems that have them
+    let Some(min_hole_size) = crate::freebsd::min_hole_size(file)? else {
+        return Ok(Capabilities::with_method(Method::WholeFile));
+    };
+    let caps = probe_seek(file)?;
+    if caps.extent_query != Method::SeekHole {
+        return Ok(caps);
+    }
+    // UFS finds holes as soon as they're made, ZFS once they're written out
+    let fs_name = crate::freebsd::filesystem_name(file)?;
+    Ok(Capabilities {
+        min_hole_size: Some(min_hole_size),
+        holes_need_sync: fs_name == "zfs",
+        ..caps
+    })
+}
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+fn probe(file: &File) -> Result<Capabilities, ExtentError> {
+    probe_seek(file)
+}
+
+#[cfg(target_os = "windows")]
+fn probe(file: &File) -> Result<Capabilities, ExtentError> {
+    use crate::{RangeReader, RangeReaderImpl as _};
+
+    let mut reader = RangeReader::new();
+    match reader.read_ranges(file)?.next() {
+        Some(Err(ExtentError::Unsupported { .. })) => {
+            Ok(Capabilities::with_method(Method::WholeFile))
+        }
+        Some(Err(err)) => Err(err),
+        _ => {
+            use std::os::windows::io::AsRawHandle;
+
+            use crate::retrieval::{ClusterMap, Volume};
+
+            // Physical locations come from retrieval pointers, which not all filesystems have,
+            // and sharing from their reference counts, which only ReFS has
+            let handle = file.as_raw_handle() as windows_sys::Win32::Foundation::HANDLE;
+            let physical = ClusterMap::query(handle, &mut Default::default()).is_ok();
+            let shared = physical && Volume::of(handle).is_ok_and(|v| v.block_refcounting);
+            Ok(Capabilities {
+                physical,
+                shared,
+                ..Capabilities::with_method(Method::AllocatedRanges)
+            })
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "windows"
+)))]
+fn probe(file: &File) -> Result<Capabilities, ExtentError> {
+    let _ = file;
+    Ok(Capabilities::with_method(Method::WholeFile))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn probes_a_file() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(b"probe").unwrap();
+        temp.flush().unwrap();
+
+        let caps = capabilities(temp.as_file()).unwrap();
+        assert_eq!(caps, capabilities_of_path(temp.path()).unwrap());
+        assert_eq!(caps.sparse, caps.extent_query != Method::WholeFile);
+        assert!(!caps.physical || caps.extent_query == Method::Fiemap);
+        assert!(
+            caps.min_hole_size
+                .is_none_or(|size| caps.sparse && size > 0)
+        );
+        assert!(!caps.holes_need_sync || caps.sparse);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_block_size() {
+        use crate::{RangeReader, RangeReaderImpl as _};
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 10_000]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let size = block_size(temp.as_file()).unwrap();
+        assert!(size.is_power_of_two(), "block size {size}");
+        let mut reader = RangeReader::new();
+        for range in reader.read_ranges(temp.as_file()).unwrap() {
+            if let Some(physical) = range.unwrap().physical_offset {
+                assert_eq!(physical % size, 0, "extent at {physical}");
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tmpfs_uses_seek_hole() {
+        let Ok(dir) = tempfile::tempdir_in("/dev/shm") else {
+            eprintln!("Skipping test: no /dev/shm");
+            return;
+        };
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        let caps = capabilities_of_path(&path).unwrap();
+        if caps.extent_query == Method::Fiemap {
+            eprintln!("Skipping test: /dev/shm supports FIEMAP");
+            return;
+        }
+        assert_eq!(caps, Capabilities::with_method(Method::SeekHole));
+        assert!(!caps.physical && !caps.shared);
+    }
+}
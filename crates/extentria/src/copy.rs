@@ -0,0 +1,170 @@
+//! Copying files without filling in their holes.
+
+use std::fs::File;
+use std::io;
+
+use crate::error::ExtentError;
+use crate::punch::punch_holes;
+use crate::types::{DataRange, RangeReaderImpl as _};
+
+/// How much data is copied at a time.
+const CHUNK: usize = 1024 * 1024;
+
+/// Copy a file's contents to another, keeping its holes, and give how many bytes of data
+/// were copied.
+///
+/// `dst` is truncated and then set to the size of `src`, which leaves it all holes; only
+/// `src`'s data ranges are then copied over, at the same offsets. On Windows, files aren't
+/// sparse unless marked, so `dst` is marked and its holes [punched](punch_holes) after.
+/// Unwritten (preallocated) ranges read as zeros, so they're left as holes too. Where
+/// `src`'s holes can't be found, or `dst`'s filesystem can't have any, the whole file is
+/// still copied, as data or zeros. `dst` must be open for writing; neither file's cursor
+/// is moved.
+pub fn copy_sparse(src: &File, dst: &File) -> Result<u64, ExtentError> {
+    let len = src.metadata()?.len();
+    let mut reader = crate::RangeReader::new();
+    reader.set_coalesce(true);
+    let ranges: Vec<DataRange> = reader.read_ranges(src)?.collect::<Result<_, _>>()?;
+
+    dst.set_len(0)?;
+    dst.set_len(len)?;
+
+    let mut buf = vec![0; CHUNK];
+    let mut copied = 0;
+    for range in ranges.iter().filter(|range| !range.is_zero()) {
+        let end = range.end().min(len);
+        let mut offset = range.offset;
+        while offset < end {
+            let chunk = &mut buf[..(end - offset).min(CHUNK as u64) as usize];
+            read_exact_at(src, chunk, offset)?;
+            write_all_at(dst, chunk, offset)?;
+            offset += chunk.len() as u64;
+        }
+        copied += end.saturating_sub(range.offset);
+    }
+
+    if cfg!(windows) {
+        let holes: Vec<DataRange> = ranges
+            .iter()
+            .filter(|range| range.is_zero())
+            .map(|range| DataRange::hole(range.offset, range.length))
+            .collect();
+        match punch_holes(dst, &holes) {
+            // Extending the file already zeroed it
+            Ok(()) | Err(ExtentError::Unsupported { .. }) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(copied)
+}
+
+/// Fill `buf` from `offset` of a file, without moving its cursor.
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt as _;
+        file.read_exact_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt as _;
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            match file.seek_read(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::io::{Read as _, Seek as _, SeekFrom};
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+}
+
+/// Write all of `buf` at `offset` of a file, without moving its cursor.
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt as _;
+        file.write_all_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt as _;
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            match file.seek_write(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::io::{Seek as _, SeekFrom, Write as _};
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+
+    use super::*;
+    use crate::{RangeSet, ranges_for_file};
+
+    #[test]
+    fn copies_data_and_keeps_holes() {
+        let mut src = tempfile::NamedTempFile::new().unwrap();
+        src.write_all(&[1; 8192]).unwrap();
+        src.seek(SeekFrom::Start(1 << 20)).unwrap();
+        src.write_all(&[2; 8192]).unwrap();
+        src.as_file().set_len(2 << 20).unwrap();
+        src.as_file().sync_all().unwrap();
+
+        // Stale contents of the destination are dropped
+        let mut dst = tempfile::NamedTempFile::new().unwrap();
+        dst.write_all(&[9; 3 << 20]).unwrap();
+
+        let copied = copy_sparse(src.as_file(), dst.as_file()).unwrap();
+        assert!((16384..=2 << 20).contains(&copied), "copied {copied}");
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        src.reopen().unwrap().read_to_end(&mut a).unwrap();
+        dst.reopen().unwrap().read_to_end(&mut b).unwrap();
+        assert!(a == b, "contents differ");
+
+        let src_data: RangeSet = ranges_for_file(src.as_file())
+            .unwrap()
+            .into_iter()
+            .collect();
+        if src_data.bytes() < 2 << 20 {
+            // Holes found in the source are holes in the copy
+            dst.as_file().sync_all().unwrap();
+            let dst_data: RangeSet = ranges_for_file(dst.as_file())
+                .unwrap()
+                .into_iter()
+                .collect();
+            assert_eq!(copied, src_data.bytes());
+            assert!(dst_data.bytes() <= src_data.bytes(), "{dst_data:?}");
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Comparing how two files are laid out on disk.
+//!
+//! [`diff_layout()`] reads both files' ranges and says which parts of them are allocated
+//! differently: data in one and a hole in the other, shared in one and private in the other,
+//! or past the end of one. Where the extent query knows where data is on disk, data in both
+//! files that isn't in the same place differs too, so a reflinked copy of a file has no
+//! differences from it, where a plain copy differs everywhere it has data.
+
+use std::fs::File;
+
+use crate::error::ExtentError;
+use crate::types::{DataRange, RangeReaderImpl as _};
+
+/// How a part of a file is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Allocation {
+    /// Past the end of the file.
+    Absent,
+    /// A sparse hole.
+    Hole,
+    /// Allocated but unwritten (preallocated), reading as zeros.
+    Unwritten,
+    /// Data only this file has.
+    Private,
+    /// Data shared with other files or snapshots.
+    Shared,
+}
+
+impl Allocation {
+    /// How a range is allocated.
+    pub fn of(range: &DataRange) -> Self {
+        if range.hole {
+            Self::Hole
+        } else if range.unwritten {
+            Self::Unwritten
+        } else if range.flags.shared {
+            Self::Shared
+        } else {
+            Self::Private
+        }
+    }
+}
+
+/// A part of two files that's allocated differently in each.
+///
+/// When `a` and `b` are the same, the part has data in both files, in different places on
+/// disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LayoutDiff {
+    /// Byte offset within the files.
+    pub offset: u64,
+    /// Length in bytes.
+    pub length: u64,
+    /// How the part is allocated in the first file.
+    pub a: Allocation,
+    /// How the part is allocated in the second file.
+    pub b: Allocation,
+}
+
+impl LayoutDiff {
+    /// End offset (exclusive).
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Compare how two files are allocated, giving the parts that differ in order.
+///
+/// See [`diff_ranges()`] for comparing ranges already read.
+pub fn diff_layout(a: &File, b: &File) -> Result<Vec<LayoutDiff>, ExtentError> {
+    let mut reader = crate::RangeReader::new();
+    let a_ranges: Vec<DataRange> = reader.read_ranges(a)?.collect::<Result<_, _>>()?;
+    let b_ranges: Vec<DataRange> = reader.read_ranges(b)?.collect::<Result<_, _>>()?;
+    Ok(diff_ranges(&a_ranges, &b_ranges))
+}
+
+/// Compare two files' ranges, as read in order, giving the parts that differ in order.
+///
+/// Gaps between ranges are taken as holes, and each file as ending with its last range.
+/// Adjacent parts that differ the same way are merged.
+pub fn diff_ranges(a: &[DataRange], b: &[DataRange]) -> Vec<LayoutDiff> {
+    let end = |ranges: &[DataRange]| ranges.iter().map(DataRange::end).max().unwrap_or(0);
+    let end = end(a).max(end(b));
+    let (mut a, mut b) = (Cursor::new(a), Cursor::new(b));
+
+    let mut diffs: Vec<LayoutDiff> = Vec::new();
+    let mut position = 0;
+    while position < end {
+        let (a_piece, b_piece) = (a.at(position), b.at(position));
+        let piece_end = a_piece.end.min(b_piece.end).min(end);
+        let moved = matches!(
+            (a_piece.physical, b_piece.physical),
+            (Some(a), Some(b)) if a != b
+        );
+        if a_piece.allocation != b_piece.allocation || moved {
+            match diffs.last_mut() {
+                Some(last)
+                    if last.end() == position
+                        && (last.a, last.b) == (a_piece.allocation, b_piece.allocation) =>
+                {
+                    last.length += piece_end - position;
+                }
+                _ => diffs.push(LayoutDiff {
+                    offset: position,
+                    length: piece_end - position,
+                    a: a_piece.allocation,
+                    b: b_piece.allocation,
+                }),
+            }
+        }
+        position = piece_end;
+    }
+    diffs
+}
+
+/// The allocation of a file from some position, up to where it next changes.
+struct Piece {
+    allocation: Allocation,
+    /// Where the position is on disk, if known.
+    physical: Option<u64>,
+    end: u64,
+}
+
+/// Position in a file's ranges, moving forward only.
+struct Cursor<'r> {
+    ranges: &'r [DataRange],
+    index: usize,
+}
+
+impl<'r> Cursor<'r> {
+    fn new(ranges: &'r [DataRange]) -> Self {
+        Self { ranges, index: 0 }
+    }
+
+    fn at(&mut self, position: u64) -> Piece {
+        while self
+            .ranges
+            .get(self.index)
+            .is_some_and(|range| range.end() <= position)
+        {
+            self.index += 1;
+        }
+
+        match self.ranges.get(self.index) {
+            None => Piece {
+                allocation: Allocation::Absent,
+                physical: None,
+                end: u64::MAX,
+            },
+            Some(range) if range.offset > position => Piece {
+                allocation: Allocation::Hole,
+                physical: None,
+                end: range.offset,
+            },
+            Some(range) => Piece {
+                allocation: Allocation::of(range),
+                physical: range
+                    .physical_offset
+                    .map(|physical| physical + position - range.offset),
+                end: range.end(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RangeFlags;
+
+    fn shared(offset: u64, length: u64) -> DataRange {
+        DataRange::new(offset, length).with_flags(RangeFlags {
+            shared: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn finds_allocation_differences() {
+        let a = [
+            DataRange::new(0, 4096),
+            DataRange::hole(4096, 4096),
+            shared(8192, 8192),
+        ];
+        let b = [
+            DataRange::new(0, 8192),
+            DataRange::new(8192, 4096),
+            DataRange::unwritten(12288, 4096),
+            DataRange::new(16384, 100),
+        ];
+        assert_eq!(
+            diff_ranges(&a, &b),
+            [
+                LayoutDiff {
+                    offset: 4096,
+                    length: 4096,
+                    a: Allocation::Hole,
+                    b: Allocation::Private,
+                },
+                LayoutDiff {
+                    offset: 8192,
+                    length: 4096,
+                    a: Allocation::Shared,
+                    b: Allocation::Private,
+                },
+                LayoutDiff {
+                    offset: 12288,
+                    length: 4096,
+                    a: Allocation::Shared,
+                    b: Allocation::Unwritten,
+                },
+                LayoutDiff {
+                    offset: 16384,
+                    length: 100,
+                    a: Allocation::Absent,
+                    b: Allocation::Private,
+                },
+            ]
+        );
+        assert!(diff_ranges(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn data_in_different_places_differs() {
+        // A reflinked copy has the same physical offsets, however it's split into extents
+        let a = [shared(0, 8192).with_physical_offset(1 << 20)];
+        let reflinked = [
+            shared(0, 4096).with_physical_offset(1 << 20),
+            shared(4096, 4096).with_physical_offset((1 << 20) + 4096),
+        ];
+        assert!(diff_ranges(&a, &reflinked).is_empty());
+
+        // A plain copy has its own, which differ from the original's throughout
+        let copied = [
+            DataRange::new(0, 4096).with_physical_offset(1 << 30),
+            DataRange::new(4096, 4096).with_physical_offset(1 << 31),
+        ];
+        assert_eq!(
+            diff_ranges(&a, &copied),
+            [LayoutDiff {
+                offset: 0,
+                length: 8192,
+                a: Allocation::Shared,
+                b: Allocation::Private,
+            }]
+        );
+
+        // Without physical offsets, data is only compared by how it's allocated
+        let unknown = [DataRange::new(0, 8192)];
+        let private = [DataRange::new(0, 8192).with_physical_offset(1 << 20)];
+        assert!(diff_ranges(&unknown, &private).is_empty());
+    }
+}
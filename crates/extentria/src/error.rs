@@ -0,0 +1,132 @@
+//! Errors reading how files are laid out.
+
+use std::fs::File;
+use std::io;
+
+use thiserror::Error;
+
+use crate::capabilities::Method;
+use crate::fs_kind::FsKind;
+
+/// Error reading a file's ranges, or what its filesystem can tell about them.
+///
+/// Readers fall back to less precise methods rather than fail where a filesystem doesn't
+/// support the native extent query, so [`Unsupported`](Self::Unsupported) only comes from
+/// queries that can't fall back: mapping extended attributes, or the allocated ranges
+/// query on Windows. Anything else going wrong in the extent query is
+/// [`Kernel`](Self::Kernel), and opening, reading, or syncing the file is
+/// [`Io`](Self::Io). Converting to [`io::Error`] keeps the OS error code where there is
+/// one, so code working in `io::Result` can still use `?`.
+#[derive(Debug, Error)]
+pub enum ExtentError {
+    /// The query isn't supported for the file, by its filesystem or by the platform.
+    #[error("not supported{}", on(fs.as_deref(), *kind))]
+    Unsupported {
+        /// The name of the filesystem, where it's known.
+        fs: Option<String>,
+        /// What kind of filesystem it is, where it's known: network and FUSE filesystems
+        /// often support less than local ones.
+        kind: FsKind,
+    },
+
+    /// The file isn't a regular file: directories, devices and the like have no ranges.
+    #[error("not a regular file")]
+    NotARegularFile,
+
+    /// Opening, reading, or syncing the file failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// The kernel failed the extent query.
+    #[error("extent query with {method:?} failed: {source}")]
+    Kernel {
+        /// The query that failed.
+        method: Method,
+        /// The error the kernel gave.
+        source: io::Error,
+    },
+}
+
+impl ExtentError {
+    /// An unsupported query, on a filesystem of unknown name.
+    #[cfg_attr(
+        any(target_os = "linux", target_os = "android"),
+        allow(dead_code, reason = "Linux always knows the file it's failing on")
+    )]
+    pub(crate) fn unsupported() -> Self {
+        Self::Unsupported {
+            fs: None,
+            kind: FsKind::Unknown,
+        }
+    }
+
+    /// An unsupported query, on a file's filesystem.
+    pub(crate) fn unsupported_on(file: &File) -> Self {
+        let (fs, kind) = crate::fs_kind::describe(file);
+        Self::Unsupported { fs, kind }
+    }
+
+    /// Wrap an error from the kernel's extent query.
+    pub(crate) fn kernel(method: Method) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::Kernel { method, source }
+    }
+
+    /// The OS error code behind the error, if there is one.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Self::Io(err) | Self::Kernel { source: err, .. } => err.raw_os_error(),
+            Self::Unsupported { .. } | Self::NotARegularFile => None,
+        }
+    }
+}
+
+/// Where an unsupported query was, for its message: the filesystem's name if known, or its
+/// kind if it isn't local.
+fn on(fs: Option<&str>, kind: FsKind) -> String {
+    match (fs, kind) {
+        (Some(fs), _) => format!(" on {fs}"),
+        (None, FsKind::Local | FsKind::Unknown) => String::new(),
+        (None, kind) => format!(" on {kind}"),
+    }
+}
+
+impl From<ExtentError> for io::Error {
+    fn from(err: ExtentError) -> Self {
+        match err {
+            ExtentError::Io(err) => err,
+            // The OS error code matters more to callers than which query it came from
+            ExtentError::Kernel { source, .. } => source,
+            err @ ExtentError::Unsupported { .. } => {
+                io::Error::new(io::ErrorKind::Unsupported, err)
+            }
+            err @ ExtentError::NotARegularFile => io::Error::new(io::ErrorKind::InvalidInput, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_io_error() {
+        let err = io::Error::from(ExtentError::Unsupported {
+            fs: Some("vfat".into()),
+            kind: FsKind::Local,
+        });
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(err.to_string(), "not supported on vfat");
+        let err = ExtentError::Unsupported {
+            fs: None,
+            kind: FsKind::Nfs,
+        };
+        assert_eq!(err.to_string(), "not supported on NFS");
+
+        let err = ExtentError::kernel(Method::Fiemap)(io::Error::from_raw_os_error(5));
+        assert_eq!(err.raw_os_error(), Some(5));
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(5));
+
+        let err = io::Error::from(ExtentError::NotARegularFile);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -2,35 +2,82 @@
 //!
 //! This is used on platforms where we don't have a way to query extent information.
 //! It simply returns the entire file as a single data range.
+//!
+//! This includes OpenBSD and NetBSD: neither implements SEEK_HOLE/SEEK_DATA (nor has
+//! another way to find holes), so there's nothing better to do there.
 
-use std::{fs::File, io};
+use std::fs::File;
 
-use crate::types::{DataRange, RangeIter, RangeReaderImpl, private::Sealed};
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::types::{
+    DataRange, RangeIter, RangeReaderImpl, private::Sealed, regular_file_metadata, split,
+    split_len, sync_data,
+};
+use crate::zeros::ZeroScan;
 
 /// Fallback range reader that treats the whole file as one extent.
 #[derive(Debug)]
-pub struct RangeReader;
+pub struct RangeReader {
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    /// Shortest run of zeros to report as a hole, if scanning for them.
+    zero_scan: Option<u64>,
+}
 
 impl Sealed for RangeReader {}
 
 impl RangeReaderImpl for RangeReader {
     /// Create a new fallback range reader.
     fn new() -> Self {
-        Self
+        Self {
+            split: None,
+            sync_first: false,
+            zero_scan: None,
+        }
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    fn set_zero_scan(&mut self, min_run: Option<u64>) {
+        self.zero_scan = split_len(min_run);
+    }
+
+    fn last_method(&self) -> Method {
+        match self.zero_scan {
+            Some(_) => Method::ZeroScan,
+            None => Method::WholeFile,
+        }
     }
 
     /// Read data ranges for a file.
     ///
     /// On platforms without extent support, this returns the entire file
-    /// as a single data range (or nothing for empty files).
-    fn read_ranges<'a>(&'a mut self, file: &'a File) -> io::Result<RangeIter<'a>> {
-        let len = file.metadata()?.len();
+    /// as a single data range (or nothing for empty files), unless scanning it for zeros.
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        let len = regular_file_metadata(file)?.len();
+        if let Some(min_run) = self.zero_scan {
+            return Ok(split(
+                Box::new(ZeroScan::new(file, 0, len, min_run)),
+                self.split,
+            ));
+        }
         let range = if len > 0 {
             Some(DataRange::new(0, len))
         } else {
             None
         };
-        Ok(Box::new(range.into_iter().map(Ok)))
+        Ok(split(Box::new(range.into_iter().map(Ok)), self.split))
     }
 }
 
@@ -4,8 +4,14 @@ use std::{
     os::fd::{AsRawFd, BorrowedFd},
 };
 
-use linux_raw_sys::ioctl::{FIEMAP_EXTENT_LAST, FS_IOC_FIEMAP};
+use linux_raw_sys::ioctl::{
+    FIEMAP_EXTENT_DATA_ENCRYPTED, FIEMAP_EXTENT_DATA_INLINE, FIEMAP_EXTENT_DELALLOC,
+    FIEMAP_EXTENT_ENCODED, FIEMAP_EXTENT_LAST, FIEMAP_EXTENT_SHARED, FIEMAP_EXTENT_UNKNOWN,
+    FIEMAP_EXTENT_UNWRITTEN, FIEMAP_FLAG_CACHE, FIEMAP_FLAG_SYNC, FIEMAP_FLAG_XATTR, FS_IOC_FIEMAP,
+};
 use zerocopy::{FromBytes, IntoBytes as _, KnownLayout};
+
+use crate::RangeFlags;
 use zerocopy_derive::*;
 
 /// An extent lookup using FIEMAP.
@@ -68,10 +74,35 @@ impl FiemapExtent {
     pub fn last(&self) -> bool {
         self.flags & FIEMAP_EXTENT_LAST != 0
     }
+
+    /// The extent is allocated but unwritten (preallocated), and reads as zeros.
+    pub fn unwritten(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_UNWRITTEN != 0
+    }
+
+    /// Where the extent is on disk, unless the filesystem doesn't know yet (delayed
+    /// allocation) or keeps the data inline with metadata.
+    pub fn physical(&self) -> Option<u64> {
+        (self.flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) == 0)
+            .then_some(self.physical_offset)
+    }
+
+    /// How the extent is stored, from its flags.
+    pub fn range_flags(&self) -> RangeFlags {
+        let has = |flag: u32| self.flags & flag != 0;
+        RangeFlags {
+            shared: has(FIEMAP_EXTENT_SHARED),
+            inline: has(FIEMAP_EXTENT_DATA_INLINE),
+            encrypted: has(FIEMAP_EXTENT_DATA_ENCRYPTED),
+            compressed: has(FIEMAP_EXTENT_ENCODED),
+            delalloc: has(FIEMAP_EXTENT_DELALLOC),
+            unknown_location: has(FIEMAP_EXTENT_UNKNOWN),
+        }
+    }
 }
 
 /// The size of the request structure (exclusive of the results buf), in bytes.
-fn request_size() -> usize {
+pub(crate) fn request_size() -> usize {
     FiemapRequest::size_for_metadata(()).unwrap()
 }
 
@@ -100,6 +131,39 @@ impl FiemapLookup {
         }
     }
 
+    /// Map the file's extended attribute tree instead of its data (`FIEMAP_FLAG_XATTR`).
+    ///
+    /// Filesystems that can't map extended attributes fail the lookup with `EBADR`.
+    pub fn on_xattr_tree(mut self) -> Self {
+        self.flags |= FIEMAP_FLAG_XATTR;
+        self
+    }
+
+    /// Sync the file before mapping it (`FIEMAP_FLAG_SYNC`).
+    pub fn synced(mut self) -> Self {
+        self.flags |= FIEMAP_FLAG_SYNC;
+        self
+    }
+
+    /// Ask the filesystem to cache the file's extent tree (`FIEMAP_FLAG_CACHE`).
+    ///
+    /// Only ext4 takes this; other filesystems fail the lookup with `EBADR`.
+    pub fn cached(mut self) -> Self {
+        self.flags |= FIEMAP_FLAG_CACHE;
+        self
+    }
+
+    /// Whether the lookup asks for the extent tree to be [cached](Self::cached).
+    pub fn is_cached(&self) -> bool {
+        self.flags & FIEMAP_FLAG_CACHE != 0
+    }
+
+    /// The same lookup, without asking for the extent tree to be cached.
+    pub fn uncached(mut self) -> Self {
+        self.flags &= !FIEMAP_FLAG_CACHE;
+        self
+    }
+
     /// Execute an extent lookup on the filesystem.
     ///
     /// The `buf_size` specifies the size of the buffer the kernel will write results to.
@@ -111,7 +175,7 @@ impl FiemapLookup {
     /// The iterator will detect that and issue additional search calls when reaching the end of
     /// result pages, re-using the buffer each time instead of creating new ones internally. You
     /// can retrieve the buffer for further re-use with [`with_buf()`](Self::with_buf()) once done
-    /// with the iterator, see [`FiemapSearchResults::into_buf()`].
+    /// with the iterator, see [`FiemapSearchResults::take_buf()`].
     ///
     /// Compared to calling [`with_buf()`](Self::with_buf()) with your own new buffer, this method
     /// is slightly more performant as it doesn't zero the buffer twice on initial allocation.
@@ -167,6 +231,44 @@ impl FiemapLookup {
         self.with_buf(fd, buf)
     }
 
+    /// Count the extents in the lookup's range, without reading them.
+    ///
+    /// With no room for results, the kernel only says how many extents there are, so no
+    /// buffer is needed.
+    pub fn count(self, fd: BorrowedFd<'_>) -> Result<u64> {
+        let mut request = FiemapRequest {
+            start: self.start,
+            length: self.length,
+            flags: self.flags,
+            _reserved: 0,
+            written: 0,
+            array_size: 0,
+        };
+
+        // SAFETY: the request is a valid, initialised FIEMAP request, and with an array size
+        // of zero the kernel writes nothing past it.
+        if {
+            #[cfg(miri)]
+            {
+                dbg!(fd.as_raw_fd(), FS_IOC_FIEMAP, &mut request);
+                0
+            }
+            #[cfg(not(miri))]
+            unsafe {
+                libc::ioctl(
+                    fd.as_raw_fd(),
+                    FS_IOC_FIEMAP as _,
+                    request.as_mut_bytes().as_mut_ptr(),
+                )
+            }
+        } != 0
+        {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(u64::from(request.written))
+    }
+
     /// Execute an extent lookup on the filesystem, re-using a buffer.
     ///
     /// This is typically used after obtaining a buffer from the iterator of a previous search.
@@ -184,7 +286,7 @@ impl FiemapLookup {
     /// results available. The iterator will detect that and issue additional search calls when
     /// reaching the end of results, re-using the buffer each time instead of creating new ones
     /// internally. You can retrieve the buffer for further re-use once done with the iterator,
-    /// see [`FiemapSearchResults::into_buf()`].
+    /// see [`FiemapSearchResults::take_buf()`].
     ///
     /// Note that the `fd` borrow is passed to the iterator, as it must remain valid so that the
     /// iterator can execute further searches as required.
@@ -290,6 +392,16 @@ pub struct FiemapSearchResults<'fd> {
     seen_last_extent: bool,
 }
 
+impl FiemapSearchResults<'_> {
+    /// Take the buffer back for re-use with [`FiemapLookup::with_buf()`], ending the search.
+    ///
+    /// Returns `None` if the buffer was lost to a failed search while paginating.
+    pub fn take_buf(&mut self) -> Option<Box<[u8]>> {
+        self.seen_last_extent = true;
+        Some(take(&mut self.buf)).filter(|buf| !buf.is_empty())
+    }
+}
+
 impl<'f> Iterator for FiemapSearchResults<'f> {
     type Item = std::io::Result<FiemapExtent>;
 
@@ -355,7 +467,7 @@ impl<'f> Iterator for FiemapSearchResults<'f> {
 
         let lookup = FiemapLookup {
             start: off,
-            length: self.response.length.saturating_sub(off),
+            length: (self.response.start + self.response.length).saturating_sub(off),
             flags: self.response.flags,
         };
 
@@ -0,0 +1,121 @@
+//! Formatting sizes and ranges for people to read.
+
+use std::fmt;
+
+use crate::types::DataRange;
+
+/// A size in bytes, displayed in binary units: `512B`, `128KiB`, `1.5MiB`.
+///
+/// Sizes that are a whole number of the unit are shown without decimals, others with one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanSize(pub u64);
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+        let bytes = self.0;
+        let Some((exponent, unit)) = UNITS
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(exponent, _)| bytes >= 1 << (10 * (exponent + 1)))
+        else {
+            return write!(f, "{bytes}B");
+        };
+        let scale = 1u64 << (10 * (exponent + 1));
+        if bytes.is_multiple_of(scale) {
+            write!(f, "{}{unit}", bytes / scale)
+        } else {
+            write!(f, "{:.1}{unit}", bytes as f64 / scale as f64)
+        }
+    }
+}
+
+/// Displays as the range's bounds and what it is, like `0–128KiB data` or
+/// `128KiB–1MiB hole (sparse)`, followed by any [flags](crate::RangeFlags) in brackets.
+///
+/// Bounds are in [binary units](HumanSize), which may be rounded; the alternate form
+/// (`{:#}`) gives them in bytes.
+impl fmt::Display for DataRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{}–{}", self.offset, self.end())?;
+        } else if self.offset == 0 {
+            write!(f, "0–{}", HumanSize(self.end()))?;
+        } else {
+            write!(f, "{}–{}", HumanSize(self.offset), HumanSize(self.end()))?;
+        }
+        f.write_str(if self.hole {
+            " hole (sparse)"
+        } else if self.unwritten {
+            " unwritten (preallocated)"
+        } else {
+            " data"
+        })?;
+
+        let flags = self.flags;
+        let names = [
+            (flags.shared, "shared"),
+            (flags.inline, "inline"),
+            (flags.encrypted, "encrypted"),
+            (flags.compressed, "compressed"),
+            (flags.delalloc, "delalloc"),
+            (flags.unknown_location, "unknown location"),
+        ];
+        let mut set = names.iter().filter(|(set, _)| *set).map(|(_, name)| name);
+        if let Some(first) = set.next() {
+            write!(f, " [{first}")?;
+            for name in set {
+                write!(f, ", {name}")?;
+            }
+            f.write_str("]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RangeFlags;
+
+    #[test]
+    fn human_sizes() {
+        let cases = [
+            (0, "0B"),
+            (1023, "1023B"),
+            (1024, "1KiB"),
+            (1536, "1.5KiB"),
+            (128 * 1024, "128KiB"),
+            (1 << 20, "1MiB"),
+            ((1 << 30) + (1 << 29), "1.5GiB"),
+            (u64::MAX, "16.0EiB"),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(HumanSize(bytes).to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(DataRange::new(0, 128 * 1024).to_string(), "0–128KiB data");
+        assert_eq!(
+            DataRange::hole(128 * 1024, 896 * 1024).to_string(),
+            "128KiB–1MiB hole (sparse)"
+        );
+        assert_eq!(
+            format!("{:#}", DataRange::unwritten(4096, 4096)),
+            "4096–8192 unwritten (preallocated)"
+        );
+        let flags = RangeFlags {
+            shared: true,
+            compressed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            DataRange::new(0, 10).with_flags(flags).to_string(),
+            "0–10B data [shared, compressed]"
+        );
+    }
+}
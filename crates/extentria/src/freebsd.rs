@@ -1,22 +1,163 @@
-use std::{fs::File, io};
+use std::ffi::{CStr, c_int};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd as _;
+use std::sync::OnceLock;
 
 use crate::{
-    types::{RangeIter, RangeReaderImpl, private::Sealed},
+    capabilities::Method,
+    error::ExtentError,
+    fs_kind::{FsKind, RangeSource, filesystem_kind},
+    types::{
+        DataRange, RangeIter, RangeReaderImpl, private::Sealed, regular_file_metadata, split,
+        split_len, sync_data,
+    },
     unix_seek,
 };
 
 /// Range reader for FreeBSD using SEEK_HOLE/SEEK_DATA.
-#[derive(Debug, Default)]
-pub struct RangeReader;
+///
+/// FreeBSD answers SEEK_HOLE on every filesystem, taking files as all data on those that
+/// can't have holes; those say so through `_PC_MIN_HOLE_SIZE`, and their files are read as
+/// one data range without seeking, with [`Method::WholeFile`]. ZFS only finds holes in data
+/// once it's written out, so [syncing first](RangeReaderImpl::set_sync_first) matters there
+/// more than on UFS.
+#[derive(Debug)]
+pub struct RangeReader {
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    method: Method,
+    /// Kind of filesystem the last file read was on.
+    kind: FsKind,
+}
+
+impl Default for RangeReader {
+    fn default() -> Self {
+        Self {
+            split: None,
+            sync_first: false,
+            method: Method::SeekHole,
+            kind: FsKind::Unknown,
+        }
+    }
+}
 
 impl Sealed for RangeReader {}
 
 impl RangeReaderImpl for RangeReader {
     fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    fn last_method(&self) -> Method {
+        self.method
+    }
+
+    fn last_source(&self) -> RangeSource {
+        RangeSource::new(self.method, self.kind)
+    }
+
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        self.ranges(file, 0, u64::MAX)
     }
 
-    fn read_ranges<'a>(&'a mut self, file: &'a File) -> io::Result<RangeIter<'a>> {
-        Ok(Box::new(unix_seek::read_ranges(file)?))
+    fn read_ranges_in<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        self.ranges(file, offset, length)
     }
 }
+
+impl RangeReader {
+    /// Read data ranges for `length` bytes of a file from `offset`.
+    fn ranges<'a>(
+        &mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        self.kind = filesystem_kind(file).unwrap_or_default();
+        if min_hole_size(file)?.is_some() {
+            self.method = Method::SeekHole;
+            return Ok(split(
+                Box::new(unix_seek::read_ranges_in(file, offset, length)?),
+                self.split,
+            ));
+        }
+
+        self.method = Method::WholeFile;
+        let end = offset
+            .saturating_add(length)
+            .min(regular_file_metadata(file)?.len());
+        let range = (offset < end).then(|| DataRange::new(offset, end - offset));
+        Ok(split(Box::new(range.into_iter().map(Ok)), self.split))
+    }
+}
+
+/// The smallest hole a file's filesystem reports, or `None` if it can't have holes.
+pub(crate) fn min_hole_size(file: &File) -> io::Result<Option<u64>> {
+    // SAFETY: the fd is borrowed from a live File
+    let size = unsafe { libc::fpathconf(file.as_raw_fd(), libc::_PC_MIN_HOLE_SIZE) };
+    if size >= 0 {
+        return Ok((size > 0).then_some(size as u64));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // EINVAL: the filesystem doesn't know the name, so has no holes
+        Some(libc::EINVAL) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+/// The name of a file's filesystem type, as `statfs` gives it (`zfs`, `ufs`, ...).
+pub(crate) fn filesystem_name(file: &File) -> io::Result<String> {
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: the fd is borrowed from a live File, and fstatfs fills the struct on success
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialised by the successful call above
+    let stat = unsafe { stat.assume_init() };
+    // SAFETY: the name is nul-terminated within the array
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Signature of `fspacectl(2)`.
+pub(crate) type Fspacectl = unsafe extern "C" fn(
+    c_int,
+    c_int,
+    *const libc::spacectl_range,
+    c_int,
+    *mut libc::spacectl_range,
+) -> c_int;
+
+/// `fspacectl(2)`, if the running system has it.
+///
+/// It's new in FreeBSD 14, so it's looked up when first needed rather than linked to, which
+/// would stop binaries loading on older releases.
+pub(crate) fn fspacectl() -> Option<Fspacectl> {
+    static FSPACECTL: OnceLock<Option<Fspacectl>> = OnceLock::new();
+    *FSPACECTL.get_or_init(|| {
+        // SAFETY: the name is nul-terminated, and a non-null result is the libc function
+        let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"fspacectl".as_ptr()) };
+        // SAFETY: the symbol has this signature wherever it exists
+        (!symbol.is_null())
+            .then(|| unsafe { std::mem::transmute::<*mut libc::c_void, Fspacectl>(symbol) })
+    })
+}
@@ -0,0 +1,290 @@
+//! Telling local filesystems from network and userspace ones.
+//!
+//! Network filesystems pass extent queries on to a server, and FUSE filesystems to a
+//! daemon. Either may not support them, or fail them in ways local filesystems don't, and
+//! where they do answer, the answer is only as good as the server's or the daemon's idea of
+//! the file. [`filesystem_kind()`] tells which a file is on, and [`RangeSource`] tells
+//! callers how far to trust the holes they were given.
+
+use std::fmt;
+use std::fs::File;
+
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+
+/// What kind of filesystem a file is on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum FsKind {
+    /// A filesystem on a local device, or in memory.
+    Local,
+    /// NFS, of any version.
+    Nfs,
+    /// SMB or CIFS.
+    Smb,
+    /// A filesystem served by a userspace daemon through FUSE (macFUSE on macOS).
+    Fuse,
+    /// Another network filesystem: 9P, Ceph, AFS, or another Windows network provider.
+    Network,
+    /// The platform can't tell.
+    #[default]
+    Unknown,
+}
+
+impl FsKind {
+    /// Whether the filesystem is served over the network.
+    pub fn is_remote(self) -> bool {
+        matches!(self, Self::Nfs | Self::Smb | Self::Network)
+    }
+
+    /// Whether extent queries are answered by something other than the kernel's own
+    /// filesystem: a server, or a FUSE daemon.
+    pub fn is_delegated(self) -> bool {
+        self.is_remote() || self == Self::Fuse
+    }
+}
+
+impl fmt::Display for FsKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Local => "local filesystem",
+            Self::Nfs => "NFS",
+            Self::Smb => "SMB",
+            Self::Fuse => "FUSE",
+            Self::Network => "network filesystem",
+            Self::Unknown => "unknown filesystem",
+        })
+    }
+}
+
+/// Where a file's ranges came from, and so whether its holes can be trusted.
+///
+/// See [`RangeReaderImpl::last_source`](crate::RangeReaderImpl::last_source).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum RangeSource {
+    /// Queried from a local filesystem, which knows where its holes are.
+    Filesystem,
+    /// Queried from a network filesystem's server or a FUSE daemon, which may not know, and
+    /// can say a file is all data when it isn't.
+    Delegated,
+    /// Found by reading the file for runs of zeros, which needn't be holes on disk.
+    Scanned,
+    /// Not found at all: the whole file was taken as one data range.
+    Assumed,
+}
+
+impl RangeSource {
+    /// The source of ranges found with a method, on a kind of filesystem.
+    ///
+    /// Queries on filesystems of unknown kind are taken as local, as platforms that can't
+    /// tell mostly have only local filesystems that answer them.
+    pub fn new(method: Method, kind: FsKind) -> Self {
+        match method {
+            Method::WholeFile => Self::Assumed,
+            Method::ZeroScan => Self::Scanned,
+            _ if kind.is_delegated() => Self::Delegated,
+            _ => Self::Filesystem,
+        }
+    }
+
+    /// Whether the ranges' holes are exactly the file's holes on disk.
+    pub fn is_authoritative(self) -> bool {
+        self == Self::Filesystem
+    }
+}
+
+/// Tell what kind of filesystem a file is on.
+///
+/// This is from the filesystem's type on Unix (`f_type` on Linux, its name elsewhere), and
+/// the remote protocol of the file on Windows. It's [`FsKind::Unknown`] on other platforms.
+pub fn filesystem_kind(file: &File) -> Result<FsKind, ExtentError> {
+    Ok(kind(file)?)
+}
+
+/// The name and kind of a file's filesystem, as far as they can be told, for errors.
+pub(crate) fn describe(file: &File) -> (Option<String>, FsKind) {
+    #[cfg(target_os = "macos")]
+    let name = crate::macos::filesystem_name(file).ok();
+    #[cfg(target_os = "freebsd")]
+    let name = crate::freebsd::filesystem_name(file).ok();
+    #[cfg(target_os = "windows")]
+    let name = {
+        use std::os::windows::io::AsRawHandle as _;
+        let handle = file.as_raw_handle() as windows_sys::Win32::Foundation::HANDLE;
+        crate::retrieval::filesystem_name(handle).ok()
+    };
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "windows")))]
+    let name = None;
+    (name, kind(file).unwrap_or_default())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn kind(file: &File) -> std::io::Result<FsKind> {
+    Ok(match crate::capabilities::filesystem_type(file)? {
+        0x6969 => FsKind::Nfs,
+        // SMB, CIFS, SMB2
+        0x517b | 0xff53_4d42 | 0xfe53_4d42 => FsKind::Smb,
+        0x6573_5546 => FsKind::Fuse,
+        // 9P, Ceph, AFS (kAFS and OpenAFS)
+        0x0102_1997 | 0x00c3_6400 | 0x6b41_4653 | 0x5346_414f => FsKind::Network,
+        _ => FsKind::Local,
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn kind(file: &File) -> std::io::Result<FsKind> {
+    Ok(kind_of_name(&crate::macos::filesystem_name(file)?))
+}
+
+#[cfg(target_os = "freebsd")]
+fn kind(file: &File) -> std::io::Result<FsKind> {
+    Ok(kind_of_name(&crate::freebsd::filesystem_name(file)?))
+}
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+fn kind(file: &File) -> std::io::Result<FsKind> {
+    use std::os::fd::AsRawFd as _;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the fd is borrowed from a live File, and fstatvfs fills the struct on success
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: initialised by the successful call above
+    let stat = unsafe { stat.assume_init() };
+    // SAFETY: the name is nul-terminated within the array
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_basetype.as_ptr()) };
+    Ok(kind_of_name(&name.to_string_lossy()))
+}
+
+/// The kind of a filesystem from the name of its type, as `statfs` gives it on BSDs.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos"
+))]
+fn kind_of_name(name: &str) -> FsKind {
+    match name {
+        "nfs" | "nfs4" => FsKind::Nfs,
+        "smbfs" => FsKind::Smb,
+        "fusefs" | "macfuse" | "osxfuse" => FsKind::Fuse,
+        "webdav" | "afpfs" => FsKind::Network,
+        _ => FsKind::Local,
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn kind(file: &File) -> std::io::Result<FsKind> {
+    use std::os::windows::io::AsRawHandle as _;
+
+    handle_kind(file.as_raw_handle() as windows_sys::Win32::Foundation::HANDLE)
+}
+
+/// The kind of filesystem an open handle's file is on, from its remote protocol.
+#[cfg(target_os = "windows")]
+pub(crate) fn handle_kind(
+    handle: windows_sys::Win32::Foundation::HANDLE,
+) -> std::io::Result<FsKind> {
+    use std::io;
+
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_REMOTE_PROTOCOL_INFO, FileRemoteProtocolInfo, GetFileInformationByHandleEx,
+    };
+
+    /// `WNNC_NET_SMB`: the Windows SMB client.
+    const SMB: u32 = 0x0002_0000;
+    /// `WNNC_NET_MS_NFS`: the Windows NFS client.
+    const NFS: u32 = 0x0042_0000;
+    /// `ERROR_INVALID_PARAMETER`, as for files on local volumes.
+    const INVALID_PARAMETER: i32 = 87;
+
+    let mut info = FILE_REMOTE_PROTOCOL_INFO::default();
+    // SAFETY: the handle is borrowed from a live File, and the buffer is valid for its size
+    let result = unsafe {
+        GetFileInformationByHandleEx(
+            handle,
+            FileRemoteProtocolInfo,
+            (&raw mut info).cast(),
+            size_of::<FILE_REMOTE_PROTOCOL_INFO>() as u32,
+        )
+    };
+    if result == 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(INVALID_PARAMETER) => Ok(FsKind::Local),
+            _ => Err(err),
+        };
+    }
+    Ok(match info.Protocol {
+        SMB => FsKind::Smb,
+        NFS => FsKind::Nfs,
+        _ => FsKind::Network,
+    })
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "windows"
+)))]
+fn kind(file: &File) -> std::io::Result<FsKind> {
+    let _ = file;
+    Ok(FsKind::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_follow_method_and_kind() {
+        assert_eq!(
+            RangeSource::new(Method::Fiemap, FsKind::Local),
+            RangeSource::Filesystem
+        );
+        assert_eq!(
+            RangeSource::new(Method::SeekHole, FsKind::Nfs),
+            RangeSource::Delegated
+        );
+        assert_eq!(
+            RangeSource::new(Method::SeekHole, FsKind::Fuse),
+            RangeSource::Delegated
+        );
+        assert_eq!(
+            RangeSource::new(Method::WholeFile, FsKind::Local),
+            RangeSource::Assumed
+        );
+        assert_eq!(
+            RangeSource::new(Method::ZeroScan, FsKind::Smb),
+            RangeSource::Scanned
+        );
+        assert!(RangeSource::new(Method::AllocatedRanges, FsKind::Unknown).is_authoritative());
+        assert!(!FsKind::Fuse.is_remote() && FsKind::Fuse.is_delegated());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_files_are_local() {
+        let file = tempfile::tempfile().unwrap();
+        let kind = filesystem_kind(&file).unwrap();
+        if kind.is_delegated() {
+            eprintln!("Skipping test: the temp dir is on {kind}");
+            return;
+        }
+        assert_eq!(kind, FsKind::Local);
+    }
+}
@@ -3,40 +3,106 @@
 //! This crate provides a unified API for reading how files are laid out
 //! on disk, including detection of sparse holes.
 
-use std::{fs::File, io};
+use std::{fs::File, path::Path};
 
-pub use types::{DataRange, RangeIter, RangeReaderImpl};
+pub use capabilities::{Capabilities, Method, block_size, capabilities, capabilities_of_path};
+pub use copy::copy_sparse;
+pub use diff::{Allocation, LayoutDiff, diff_layout, diff_ranges};
+pub use error::ExtentError;
+pub use format::HumanSize;
+pub use fs_kind::{FsKind, RangeSource, filesystem_kind};
+pub use owned::OwnedRanges;
+pub use punch::{is_sparse, preallocate, punch_holes, set_sparse};
+pub use range_set::RangeSet;
+pub use types::{DataRange, RangeFlags, RangeIter, RangeReaderImpl, RangeSummary, ReadOptions};
+pub use validate::{ValidationReport, validate_layout};
 
+mod capabilities;
+mod copy;
+mod diff;
+mod error;
+mod format;
+mod fs_kind;
+mod owned;
+mod punch;
+mod range_set;
 mod types;
+mod validate;
+
+#[cfg(feature = "tokio")]
+mod async_reader;
+#[cfg(feature = "tokio")]
+pub use async_reader::AsyncRangeReader;
+
+#[cfg(feature = "scan")]
+mod scan;
+#[cfg(feature = "scan")]
+pub use scan::{Scan, ScanOptions, scan_dir};
+
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(feature = "mock")]
+pub use mock::MockRangeReader;
+
+#[cfg(feature = "rayon")]
+mod par;
+#[cfg(feature = "rayon")]
+pub use par::par_ranges_for_files;
 
 // Platform-specific implementations
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod btrfs;
+#[cfg(any(target_os = "linux", target_os = "android"))]
 mod fiemap;
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 mod linux;
 
-#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos"
+))]
 mod unix_seek;
 
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "windows"
+)))]
+mod zeros;
+
 #[cfg(target_os = "macos")]
 mod macos;
 
 #[cfg(target_os = "freebsd")]
 mod freebsd;
 
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+mod solaris;
+
+#[cfg(target_os = "windows")]
+mod retrieval;
 #[cfg(target_os = "windows")]
 mod windows;
 
 #[cfg(not(any(
     target_os = "linux",
+    target_os = "android",
     target_os = "macos",
     target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
     target_os = "windows"
 )))]
 mod fallback;
 
 // Re-export the appropriate RangeReader
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 pub use linux::RangeReader;
 
 #[cfg(target_os = "macos")]
@@ -45,13 +111,19 @@ pub use macos::RangeReader;
 #[cfg(target_os = "freebsd")]
 pub use freebsd::RangeReader;
 
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+pub use solaris::RangeReader;
+
 #[cfg(target_os = "windows")]
 pub use windows::RangeReader;
 
 #[cfg(not(any(
     target_os = "linux",
+    target_os = "android",
     target_os = "macos",
     target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
     target_os = "windows"
 )))]
 pub use fallback::RangeReader;
@@ -59,42 +131,117 @@ pub use fallback::RangeReader;
 /// Convenience function: get data ranges for a file using default settings.
 ///
 /// For processing multiple files, consider using [`RangeReader`] directly
-/// to reuse buffers between calls.
-pub fn ranges_for_file(file: &File) -> io::Result<Vec<DataRange>> {
+/// to reuse buffers between calls, or `AsyncRangeReader` (with the `tokio`
+/// feature) from async code.
+pub fn ranges_for_file(file: &File) -> Result<Vec<DataRange>, ExtentError> {
     use crate::types::RangeReaderImpl as _;
     let mut reader = RangeReader::new();
     reader.read_ranges(file)?.collect()
 }
 
+/// Convenience function: get data ranges for the file at a path.
+///
+/// See [`ranges_for_file`]. For a whole directory, see `scan_dir` (with the `scan`
+/// feature).
+pub fn ranges_for_path(path: impl AsRef<Path>) -> Result<Vec<DataRange>, ExtentError> {
+    ranges_for_file(&File::open(path)?)
+}
+
+/// Convenience function: get data ranges for a file, and how they were found.
+///
+/// Like [`ranges_for_file`], this succeeds on any filesystem by falling back to less
+/// precise methods; the [`Method`] tells whether holes could be found at all.
+pub fn ranges_for_file_with_method(file: &File) -> Result<(Vec<DataRange>, Method), ExtentError> {
+    use crate::types::RangeReaderImpl as _;
+    let mut reader = RangeReader::new();
+    let ranges = reader.read_ranges(file)?.collect::<Result<_, _>>()?;
+    Ok((ranges, reader.last_method()))
+}
+
+/// Convenience function: how many bytes of a file's contents are allocated on disk.
+///
+/// This adds up the lengths of the file's data ranges (including preallocated ones) as it
+/// reads them, leaving out sparse holes, so it's never more than the file's size. Where
+/// holes can't be found, the file's block count is used instead on Unix.
+pub fn allocated_size(file: &File) -> Result<u64, ExtentError> {
+    use crate::types::RangeReaderImpl as _;
+    let mut reader = RangeReader::new();
+    let mut allocated = reader.summarize(file)?.data_bytes;
+
+    #[cfg(unix)]
+    if reader.last_method() == Method::WholeFile {
+        use std::os::unix::fs::MetadataExt as _;
+        let meta = file.metadata()?;
+        allocated = allocated.min(meta.blocks() * 512);
+    }
+    Ok(allocated)
+}
+
+/// Convenience function: get data ranges for many files, on several threads.
+///
+/// Each thread has its own [`RangeReader`], whose buffer is reused for every file it
+/// handles, and takes the next file as it finishes one. Results are in the order of
+/// `files`. With `threads` at 0, as many threads as there are CPUs are used; with 1, files
+/// are read on the current thread, as with [`RangeReaderImpl::read_ranges_batch()`]. To use
+/// rayon's thread pool instead, see `par_ranges_for_files` (with the `rayon` feature).
+pub fn ranges_for_files(
+    files: &[&File],
+    threads: usize,
+) -> Vec<Result<Vec<DataRange>, ExtentError>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::types::RangeReaderImpl as _;
+
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(files.len());
+    if threads <= 1 {
+        return RangeReader::new()
+            .read_ranges_batch(files.iter().copied())
+            .collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<Vec<DataRange>, ExtentError>)> =
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut reader = RangeReader::new();
+                        let mut done = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(file) = files.get(index) else {
+                                break done;
+                            };
+                            done.push((
+                                index,
+                                reader.read_ranges(file).and_then(|iter| iter.collect()),
+                            ));
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                })
+                .collect()
+        });
+    results.sort_unstable_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::Write;
 
-    /// Check if an error indicates the filesystem doesn't support extent queries.
-    /// This can happen on tmpfs, some network filesystems, etc.
-    fn is_unsupported_error(err: &io::Error) -> bool {
-        // EOPNOTSUPP = 95 on Linux, ENOTTY = 25 on Linux, EINVAL = 22 on Linux
-        // EINVAL can happen on some filesystems that don't properly support FIEMAP
-        // On Windows, we might get ERROR_NOT_SUPPORTED = 50
-        #[cfg(unix)]
-        {
-            matches!(
-                err.raw_os_error(),
-                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL)
-            )
-        }
-        #[cfg(windows)]
-        {
-            matches!(err.raw_os_error(), Some(50)) // ERROR_NOT_SUPPORTED
-        }
-        #[cfg(not(any(unix, windows)))]
-        {
-            let _ = err;
-            false
-        }
-    }
-
     #[test]
     fn empty_file() {
         let temp = tempfile::NamedTempFile::new().unwrap();
@@ -105,7 +252,7 @@ mod tests {
                 // Empty file should have no ranges or a single zero-length range
                 assert!(ranges.is_empty() || ranges.iter().all(|r| r.length == 0));
             }
-            Err(e) if is_unsupported_error(&e) => {
+            Err(ExtentError::Unsupported { .. }) => {
                 // Skip test on filesystems that don't support extent queries
                 eprintln!("Skipping test: filesystem doesn't support extent queries");
             }
@@ -133,7 +280,7 @@ mod tests {
                     "total length {total_len} should be >= file size 13"
                 );
             }
-            Err(e) if is_unsupported_error(&e) => {
+            Err(ExtentError::Unsupported { .. }) => {
                 // Skip test on filesystems that don't support extent queries
                 eprintln!("Skipping test: filesystem doesn't support extent queries");
             }
@@ -141,6 +288,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn allocated_size_leaves_out_holes() {
+        use std::io::{Seek, SeekFrom};
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 4096]).unwrap();
+        temp.seek(SeekFrom::Start(1 << 20)).unwrap();
+        temp.write_all(&[2; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let len = temp.as_file().metadata().unwrap().len();
+
+        let allocated = match allocated_size(temp.as_file()) {
+            Ok(allocated) => allocated,
+            Err(ExtentError::Unsupported { .. }) => {
+                eprintln!("Skipping test: filesystem doesn't support extent queries");
+                return;
+            }
+            Err(e) => panic!("Unexpected error: {e}"),
+        };
+        assert!(allocated <= len);
+        let (_, method) = ranges_for_file_with_method(temp.as_file()).unwrap();
+        if method != Method::WholeFile {
+            assert!(allocated >= 8192, "allocated {allocated}");
+            assert!(allocated < len, "allocated {allocated} of {len}");
+        }
+    }
+
     #[test]
     fn range_reader_reuse() {
         let mut temp1 = tempfile::NamedTempFile::new().unwrap();
@@ -158,7 +332,7 @@ mod tests {
                 let ranges1: Vec<_> = iter.collect();
                 assert!(!ranges1.is_empty());
             }
-            Err(e) if is_unsupported_error(&e) => {
+            Err(ExtentError::Unsupported { .. }) => {
                 eprintln!("Skipping test: filesystem doesn't support extent queries");
                 return;
             }
@@ -170,7 +344,7 @@ mod tests {
                 let ranges2: Vec<_> = iter.collect();
                 assert!(!ranges2.is_empty());
             }
-            Err(e) if is_unsupported_error(&e) => {
+            Err(ExtentError::Unsupported { .. }) => {
                 eprintln!("Skipping test: filesystem doesn't support extent queries");
             }
             Err(e) => panic!("Unexpected error: {e}"),
@@ -1,16 +1,58 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use std::os::fd::AsFd;
+use std::os::fd::{AsFd, AsRawFd as _};
+use std::os::unix::fs::MetadataExt as _;
 
-use crate::fiemap::FiemapLookup;
-use crate::types::{DataRange, RangeIter, RangeReaderImpl, private::Sealed};
+use crate::btrfs::ExtentGenerations;
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::fiemap::{FiemapLookup, FiemapSearchResults, request_size, result_size};
+use crate::fs_kind::{FsKind, RangeSource, filesystem_kind};
+use crate::types::{
+    DataRange, RangeIter, RangeReaderImpl, ReadOptions, coalesced, private::Sealed,
+    regular_file_metadata, split, split_len, sync_data,
+};
 use crate::unix_seek;
+use crate::zeros::ZeroScan;
 
-/// Range reader for Linux using FIEMAP.
+/// Range reader for Linux (and Android) using FIEMAP.
 #[derive(Debug)]
 pub struct RangeReader {
     buf_size: usize,
     buf: Option<Box<[u8]>>,
+    /// Filesystems (by device) known not to support FIEMAP, with what to use instead.
+    /// `None` when support caching is disabled.
+    unsupported: Option<HashMap<u64, Method>>,
+    /// Kinds of filesystems (by device) seen, while support caching is enabled.
+    kinds: HashMap<u64, FsKind>,
+    last_method: Method,
+    last_kind: FsKind,
+    coalesce: bool,
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    /// Shortest run of zeros to report as a hole, if scanning for them.
+    zero_scan: Option<u64>,
+    /// Whether to read the generations of extents, where the filesystem has them.
+    generations: bool,
+    tuning: BufferTuning,
+}
+
+/// Fewest extents a tuned buffer holds.
+const MIN_TUNED_EXTENTS: u64 = 64;
+
+/// Most extents a tuned buffer holds, about 3.5 MiB of them.
+const MAX_TUNED_EXTENTS: u64 = 1 << 16;
+
+/// State for sizing the FIEMAP buffer to the files read.
+#[derive(Debug, Default)]
+struct BufferTuning {
+    enabled: bool,
+    /// Extents seen in the last file read, until the buffer is tuned for them.
+    observed: Option<u64>,
+    /// Most extents seen in a file lately, decaying by an eighth with every file read.
+    peak: u64,
 }
 
 impl Sealed for RangeReader {}
@@ -21,6 +63,16 @@ impl RangeReaderImpl for RangeReader {
         Self {
             buf_size: 64 * 1024, // 64KB default
             buf: None,
+            unsupported: Some(HashMap::new()),
+            kinds: HashMap::new(),
+            last_method: Method::Fiemap,
+            last_kind: FsKind::Unknown,
+            coalesce: false,
+            split: None,
+            sync_first: false,
+            zero_scan: None,
+            generations: false,
+            tuning: BufferTuning::default(),
         }
     }
 
@@ -29,6 +81,16 @@ impl RangeReaderImpl for RangeReader {
         Self {
             buf_size: size,
             buf: None,
+            unsupported: Some(HashMap::new()),
+            kinds: HashMap::new(),
+            last_method: Method::Fiemap,
+            last_kind: FsKind::Unknown,
+            coalesce: false,
+            split: None,
+            sync_first: false,
+            zero_scan: None,
+            generations: false,
+            tuning: BufferTuning::default(),
         }
     }
 
@@ -38,6 +100,16 @@ impl RangeReaderImpl for RangeReader {
         Self {
             buf_size,
             buf: Some(buf),
+            unsupported: Some(HashMap::new()),
+            kinds: HashMap::new(),
+            last_method: Method::Fiemap,
+            last_kind: FsKind::Unknown,
+            coalesce: false,
+            split: None,
+            sync_first: false,
+            zero_scan: None,
+            generations: false,
+            tuning: BufferTuning::default(),
         }
     }
 
@@ -46,52 +118,406 @@ impl RangeReaderImpl for RangeReader {
         self.buf
     }
 
+    fn set_support_cache(&mut self, enabled: bool) {
+        match (enabled, &self.unsupported) {
+            (true, None) => self.unsupported = Some(HashMap::new()),
+            (false, Some(_)) => self.unsupported = None,
+            _ => {}
+        }
+        self.kinds.clear();
+    }
+
+    fn clear_support_cache(&mut self) {
+        if let Some(unsupported) = &mut self.unsupported {
+            unsupported.clear();
+        }
+        self.kinds.clear();
+    }
+
+    fn set_coalesce(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    /// Sync each file before mapping it, with FIEMAP_FLAG_SYNC or, where FIEMAP isn't
+    /// supported, fdatasync.
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    /// Scan for zeros on filesystems that support neither FIEMAP nor SEEK_HOLE/SEEK_DATA.
+    fn set_zero_scan(&mut self, min_run: Option<u64>) {
+        self.zero_scan = split_len(min_run);
+    }
+
+    /// Read generations with a tree search on btrfs.
+    fn set_generations(&mut self, enabled: bool) {
+        self.generations = enabled;
+    }
+
+    fn set_buffer_tuning(&mut self, enabled: bool) {
+        if enabled && !self.tuning.enabled {
+            self.tuning.peak = self.capacity();
+        }
+        self.tuning.enabled = enabled;
+    }
+
+    fn last_method(&self) -> Method {
+        self.last_method
+    }
+
+    fn last_source(&self) -> RangeSource {
+        RangeSource::new(self.last_method, self.last_kind)
+    }
+
     /// Read data ranges for a file.
     ///
-    /// If the filesystem doesn't support FIEMAP (e.g., tmpfs, some network filesystems),
-    /// this will fall back to treating the entire file as a single data range.
-    fn read_ranges<'a>(&'a mut self, file: &'a File) -> io::Result<RangeIter<'a>> {
-        let file_size = file.metadata()?.len();
+    /// If the filesystem doesn't support FIEMAP (e.g., tmpfs, some network filesystems), or
+    /// security policy denies it (as on Android), this will fall back to SEEK_HOLE/SEEK_DATA, or failing that to treating the entire
+    /// file as a single data range.
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        self.read_ranges_with(file, self.options())
+    }
 
-        let fiemap_result = if let Some(buf) = self.buf.take() {
-            FiemapLookup::for_file_size(file_size).with_buf(file.as_fd(), buf)
+    /// Read data ranges for a file, passing the options to FIEMAP as request flags.
+    ///
+    /// Where FIEMAP isn't supported, syncing is done with an fsync and caching is skipped,
+    /// as it is on filesystems that don't take it. The xattr tree can only be mapped with
+    /// FIEMAP, so there's no falling back for it.
+    fn read_ranges_with<'a>(
+        &'a mut self,
+        file: &'a File,
+        options: ReadOptions,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        let options = ReadOptions {
+            sync: options.sync || self.sync_first,
+            ..options
+        };
+        let (coalesce, max_len) = (self.coalesce, self.split);
+        let ranges = if options.xattr_tree {
+            self.xattr_ranges(file, options)?
         } else {
-            FiemapLookup::for_file_size(file_size).with_buf_size(file.as_fd(), self.buf_size)
-        };
-
-        match fiemap_result {
-            Ok(results) => Ok(Box::new(LinuxRangeIter::Fiemap(FiemapRangeIter {
-                inner: results,
-                file_size,
-                current_pos: 0,
-                pending_range: None,
-                done: false,
-            }))),
-            Err(e) if is_fiemap_unsupported(&e) => {
+            self.ranges(file, 0, u64::MAX, options)?
+        };
+        Ok(split(coalesced(ranges, coalesce), max_len))
+    }
+
+    /// Read data ranges for part of a file, asking FIEMAP about only that part.
+    fn read_ranges_in<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        let (coalesce, max_len) = (self.coalesce, self.split);
+        Ok(split(
+            coalesced(self.ranges(file, offset, length, self.options())?, coalesce),
+            max_len,
+        ))
+    }
+
+    /// Count a file's extents with FIEMAP, without reading them.
+    fn extent_count(&mut self, file: &File) -> Result<u64, ExtentError> {
+        let meta = regular_file_metadata(file)?;
+        if meta.len() == 0 {
+            self.last_method = Method::WholeFile;
+            return Ok(0);
+        }
+
+        let known = self
+            .unsupported
+            .as_ref()
+            .is_some_and(|unsupported| unsupported.contains_key(&meta.dev()));
+        // FIEMAP counts extents as stored, which coalescing would merge and splitting cut
+        if !known && !self.coalesce && self.split.is_none() {
+            let kind = self.kind(file, meta.dev());
+            self.last_kind = kind;
+            let lookup = FiemapLookup::for_file_size(meta.len());
+            let lookup = if self.sync_first {
+                lookup.synced()
+            } else {
+                lookup
+            };
+            match lookup.count(file.as_fd()) {
+                Ok(count) => {
+                    self.last_method = Method::Fiemap;
+                    return Ok(count);
+                }
+                Err(e) if is_fiemap_unsupported(&e) || kind.is_delegated() && is_refused(&e) => {}
+                Err(e) => return Err(ExtentError::kernel(Method::Fiemap)(e)),
+            }
+        }
+
+        // Otherwise, count the data ranges as read
+        let mut count = 0;
+        self.visit_ranges(file, |range| count += u64::from(!range.hole))?;
+        Ok(count)
+    }
+
+    /// Call `visit` with each data range of a file, straight from the FIEMAP buffer unless
+    /// coalescing or splitting.
+    fn visit_ranges<F>(&mut self, file: &File, mut visit: F) -> Result<(), ExtentError>
+    where
+        F: FnMut(DataRange),
+    {
+        if self.coalesce || self.split.is_some() {
+            for range in self.read_ranges(file)? {
+                visit(range?);
+            }
+        } else {
+            for range in self.ranges(file, 0, u64::MAX, self.options())? {
+                visit(range?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RangeReader {
+    /// Options for queries that weren't given any.
+    fn options(&self) -> ReadOptions {
+        ReadOptions {
+            sync: self.sync_first,
+            ..Default::default()
+        }
+    }
+
+    /// Read data ranges for `length` bytes of a file from `offset`, without boxing the
+    /// iterator.
+    fn ranges<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+        options: ReadOptions,
+    ) -> Result<LinuxRangeIter<'a>, ExtentError> {
+        let meta = regular_file_metadata(file)?;
+        let end = offset.saturating_add(length).min(meta.len());
+        if offset >= end {
+            self.last_method = Method::WholeFile;
+            return Ok(LinuxRangeIter::Fallback(FallbackRangeIter::new(0, 0)));
+        }
+
+        let kind = self.kind(file, meta.dev());
+        self.last_kind = kind;
+        let known = self
+            .unsupported
+            .as_ref()
+            .and_then(|unsupported| unsupported.get(&meta.dev()).copied());
+        if let Some(method) = known {
+            return self.fallback(method, file, offset, end, options);
+        }
+
+        let lookup = FiemapLookup {
+            start: offset,
+            length: end - offset,
+            flags: 0,
+        };
+        match self.lookup(file, with_options(lookup, options)) {
+            Ok(results) => {
+                self.last_method = Method::Fiemap;
+                // After FIEMAP, which wrote out the data if syncing
+                let generations = self
+                    .generations
+                    .then(|| ExtentGenerations::read(file))
+                    .flatten();
+                Ok(LinuxRangeIter::Fiemap(FiemapRangeIter {
+                    inner: results,
+                    buf_slot: &mut self.buf,
+                    end,
+                    current_pos: offset,
+                    pending_range: None,
+                    done: false,
+                    holes: true,
+                    generations,
+                    observed: &mut self.tuning.observed,
+                    extents: 0,
+                }))
+            }
+            Err(e) if is_fiemap_unsupported(&e) || kind.is_delegated() && is_refused(&e) => {
                 // Filesystem doesn't support FIEMAP, try SEEK_HOLE/SEEK_DATA first
-                // to at least detect sparse holes before falling back to single extent
-                match unix_seek::read_ranges(file) {
-                    Ok(iter) => Ok(Box::new(LinuxRangeIter::SeekHole(iter))),
-                    Err(e) if is_seek_hole_unsupported(&e) => {
-                        // SEEK_HOLE/SEEK_DATA also not supported, fall back to single extent
-                        Ok(Box::new(LinuxRangeIter::Fallback(FallbackRangeIter::new(
-                            file_size,
-                        ))))
-                    }
-                    Err(e) => Err(e),
+                // to at least detect sparse holes before falling back to single extent.
+                // Probe it here rather than failing halfway through iterating.
+                let method = match unix_seek::seek_data(file.as_raw_fd(), offset) {
+                    // ENXIO: no data at all, the file is entirely sparse
+                    Ok(_) => Method::SeekHole,
+                    Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Method::SeekHole,
+                    // SEEK_HOLE/SEEK_DATA also not supported, fall back to single extent
+                    Err(e) if is_seek_hole_unsupported(&e) => Method::WholeFile,
+                    Err(e) if kind.is_delegated() && is_refused(&e) => Method::WholeFile,
+                    Err(e) => return Err(ExtentError::kernel(Method::SeekHole)(e)),
+                };
+                if let Some(unsupported) = &mut self.unsupported {
+                    unsupported.insert(meta.dev(), method);
                 }
+                self.fallback(method, file, offset, end, options)
+            }
+            Err(e) => Err(ExtentError::kernel(Method::Fiemap)(e)),
+        }
+    }
+
+    /// Read the ranges of a file's extended attribute tree, as placed by the filesystem.
+    fn xattr_ranges<'a>(
+        &'a mut self,
+        file: &'a File,
+        options: ReadOptions,
+    ) -> Result<LinuxRangeIter<'a>, ExtentError> {
+        regular_file_metadata(file)?;
+        let lookup = FiemapLookup {
+            start: 0,
+            length: u64::MAX,
+            flags: 0,
+        };
+        match self.lookup(file, with_options(lookup, options)) {
+            Ok(results) => {
+                self.last_method = Method::Fiemap;
+                Ok(LinuxRangeIter::Fiemap(FiemapRangeIter {
+                    inner: results,
+                    buf_slot: &mut self.buf,
+                    end: u64::MAX,
+                    current_pos: 0,
+                    pending_range: None,
+                    done: false,
+                    holes: false,
+                    generations: None,
+                    observed: &mut self.tuning.observed,
+                    extents: 0,
+                }))
+            }
+            // EBADR: the filesystem doesn't take the xattr flag
+            Err(e) if is_fiemap_unsupported(&e) || e.raw_os_error() == Some(libc::EBADR) => {
+                Err(ExtentError::unsupported_on(file))
+            }
+            Err(e) => Err(ExtentError::kernel(Method::Fiemap)(e)),
+        }
+    }
+
+    /// Read ranges without FIEMAP: with SEEK_HOLE/SEEK_DATA, by scanning for zeros where
+    /// that's not supported either and the reader is set to, or as a single range.
+    fn fallback<'a>(
+        &mut self,
+        method: Method,
+        file: &'a File,
+        start: u64,
+        end: u64,
+        options: ReadOptions,
+    ) -> Result<LinuxRangeIter<'a>, ExtentError> {
+        if options.sync {
+            sync_data(file)?;
+        }
+        let iter = match (method, self.zero_scan) {
+            (Method::SeekHole, _) => {
+                LinuxRangeIter::SeekHole(unix_seek::read_ranges_in(file, start, end - start)?)
+            }
+            (_, Some(min_run)) => {
+                self.last_method = Method::ZeroScan;
+                return Ok(LinuxRangeIter::ZeroScan(ZeroScan::new(
+                    file, start, end, min_run,
+                )));
             }
-            Err(e) => Err(e),
+            _ => LinuxRangeIter::Fallback(FallbackRangeIter::new(start, end)),
+        };
+        self.last_method = method;
+        Ok(iter)
+    }
+
+    /// The kind of a file's filesystem, cached by device while support caching is enabled.
+    ///
+    /// Not being able to tell isn't worth failing the read for.
+    fn kind(&mut self, file: &File, dev: u64) -> FsKind {
+        let caching = self.unsupported.is_some();
+        if caching && let Some(&kind) = self.kinds.get(&dev) {
+            return kind;
+        }
+        let kind = filesystem_kind(file).unwrap_or_default();
+        if caching {
+            self.kinds.insert(dev, kind);
+        }
+        kind
+    }
+
+    /// Run a FIEMAP lookup with the reader's buffer, dropping the cache flag if the
+    /// filesystem doesn't take it.
+    fn lookup<'fd>(
+        &mut self,
+        file: &'fd File,
+        lookup: FiemapLookup,
+    ) -> io::Result<FiemapSearchResults<'fd>> {
+        self.tune_buffer();
+        let result = if let Some(buf) = self.buf.take() {
+            lookup.with_buf(file.as_fd(), buf)
+        } else {
+            lookup.with_buf_size(file.as_fd(), self.buf_size)
+        };
+        match result {
+            Err(e) if e.raw_os_error() == Some(libc::EBADR) && lookup.is_cached() => {
+                self.lookup(file, lookup.uncached())
+            }
+            result => result,
+        }
+    }
+
+    /// How many extents the buffer holds.
+    fn capacity(&self) -> u64 {
+        let size = match &self.buf {
+            Some(buf) => buf.len().saturating_sub(request_size()),
+            None => self.buf_size,
+        };
+        (size / result_size()) as u64
+    }
+
+    /// Resize the buffer for the extents seen in the last file, if tuning.
+    ///
+    /// It's sized up to the next power of two extents from the peak, so it's only resized
+    /// when files' extent counts change by a factor of two or more.
+    fn tune_buffer(&mut self) {
+        let Some(extents) = self.tuning.observed.take() else {
+            return;
+        };
+        if !self.tuning.enabled {
+            return;
+        }
+        let peak = self.tuning.peak;
+        self.tuning.peak = extents.max(peak - peak / 8);
+
+        let wanted = self
+            .tuning
+            .peak
+            .max(1)
+            .next_power_of_two()
+            .clamp(MIN_TUNED_EXTENTS, MAX_TUNED_EXTENTS);
+        if wanted != self.capacity() {
+            self.buf_size = wanted as usize * result_size();
+            self.buf = None;
         }
     }
 }
 
-/// Check if an error indicates FIEMAP is not supported by this filesystem.
-fn is_fiemap_unsupported(err: &io::Error) -> bool {
+/// Add the request flags for read options to a FIEMAP lookup.
+fn with_options(mut lookup: FiemapLookup, options: ReadOptions) -> FiemapLookup {
+    if options.xattr_tree {
+        lookup = lookup.on_xattr_tree();
+    }
+    if options.sync {
+        lookup = lookup.synced();
+    }
+    if options.cache {
+        lookup = lookup.cached();
+    }
+    lookup
+}
+
+/// Check if an error indicates FIEMAP is not supported by this filesystem, or not allowed.
+pub(crate) fn is_fiemap_unsupported(err: &io::Error) -> bool {
     // note: ENOTSUP and EOPNOTSUPP are the same value on Linux
+    // EPERM and EACCES come from security policy denying the ioctl, as SELinux commonly
+    // does on Android, while reading the file is still allowed
     matches!(
         err.raw_os_error(),
-        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY)
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EPERM) | Some(libc::EACCES)
     )
 }
 
@@ -99,24 +525,40 @@ fn is_fiemap_unsupported(err: &io::Error) -> bool {
 fn is_seek_hole_unsupported(err: &io::Error) -> bool {
     matches!(
         err.raw_os_error(),
-        Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ESPIPE)
+        Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ESPIPE) | Some(libc::ENOSYS)
     )
 }
 
-/// Iterator that can be FIEMAP-based, SEEK_HOLE-based, or fallback.
+/// Check if an error from a network or FUSE filesystem means its server or daemon doesn't
+/// support the query, rather than that the query failed.
+///
+/// These pass on errors local filesystems don't give: NFS can leak the kernel's internal
+/// ENOTSUPP, and FUSE daemons give EINVAL or ENOSYS for requests they don't handle.
+fn is_refused(err: &io::Error) -> bool {
+    /// The kernel's internal ENOTSUPP, which isn't meant to reach userspace.
+    const ENOTSUPP: i32 = 524;
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(ENOTSUPP)
+    )
+}
+
+/// Iterator that can be FIEMAP-based, SEEK_HOLE-based, zero scanning, or fallback.
 enum LinuxRangeIter<'a> {
     Fiemap(FiemapRangeIter<'a>),
     SeekHole(unix_seek::SeekRangeIter),
+    ZeroScan(ZeroScan<'a>),
     Fallback(FallbackRangeIter),
 }
 
 impl Iterator for LinuxRangeIter<'_> {
-    type Item = io::Result<DataRange>;
+    type Item = Result<DataRange, ExtentError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self {
             LinuxRangeIter::Fiemap(iter) => iter.next(),
             LinuxRangeIter::SeekHole(iter) => iter.next(),
+            LinuxRangeIter::ZeroScan(iter) => iter.next(),
             LinuxRangeIter::Fallback(iter) => iter.next(),
         }
     }
@@ -128,18 +570,14 @@ struct FallbackRangeIter {
 }
 
 impl FallbackRangeIter {
-    fn new(file_size: u64) -> Self {
-        let range = if file_size > 0 {
-            Some(DataRange::new(0, file_size))
-        } else {
-            None
-        };
+    fn new(start: u64, end: u64) -> Self {
+        let range = (start < end).then(|| DataRange::new(start, end - start));
         Self { range }
     }
 }
 
 impl Iterator for FallbackRangeIter {
-    type Item = io::Result<DataRange>;
+    type Item = Result<DataRange, ExtentError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.range.take().map(Ok)
@@ -147,16 +585,37 @@ impl Iterator for FallbackRangeIter {
 }
 
 /// Iterator over FIEMAP results, converting to DataRange.
+///
+/// Gives the buffer back to the reader when dropped, with how many extents it held, for the
+/// next file.
 struct FiemapRangeIter<'a> {
     inner: crate::fiemap::FiemapSearchResults<'a>,
-    file_size: u64,
+    buf_slot: &'a mut Option<Box<[u8]>>,
+    /// Where to stop: the end of the file, or of the window asked for.
+    end: u64,
     current_pos: u64,
     pending_range: Option<DataRange>,
     done: bool,
+    /// Whether to fill in the holes between extents, which the xattr tree doesn't have.
+    holes: bool,
+    /// Generations of the file's extents, if being read.
+    generations: Option<ExtentGenerations>,
+    /// Where to leave the number of extents seen, for tuning the buffer.
+    observed: &'a mut Option<u64>,
+    extents: u64,
+}
+
+impl Drop for FiemapRangeIter<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.inner.take_buf() {
+            *self.buf_slot = Some(buf);
+        }
+        *self.observed = Some(self.extents);
+    }
 }
 
 impl Iterator for FiemapRangeIter<'_> {
-    type Item = io::Result<DataRange>;
+    type Item = Result<DataRange, ExtentError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Return any pending range first, even if done is set
@@ -171,53 +630,48 @@ impl Iterator for FiemapRangeIter<'_> {
 
         match self.inner.next() {
             Some(Ok(extent)) => {
-                // Check for sparse hole before this extent
-                if extent.logical_offset > self.current_pos {
-                    let hole =
-                        DataRange::hole(self.current_pos, extent.logical_offset - self.current_pos);
-
-                    // Store the data range to return next iteration
-                    // Clamp length to not exceed file size (extents can extend beyond
-                    // logical file size due to preallocation or block alignment)
-                    let clamped_length = if extent.logical_offset + extent.length > self.file_size {
-                        self.file_size.saturating_sub(extent.logical_offset)
-                    } else {
-                        extent.length
-                    };
-                    let range = DataRange::new(extent.logical_offset, clamped_length);
-                    self.current_pos = extent.logical_offset + extent.length;
-
-                    if extent.last() && self.current_pos >= self.file_size {
-                        self.done = true;
-                    }
-
-                    self.pending_range = Some(range);
-                    return Some(Ok(hole));
+                self.extents += 1;
+                let extent_end = extent.logical_offset + extent.length;
+                if extent_end >= self.end {
+                    self.done = true;
                 }
 
-                // Return this extent as a data range
-                // Clamp length to not exceed file size (extents can extend beyond
-                // logical file size due to preallocation or block alignment)
-                let clamped_length = if extent.logical_offset + extent.length > self.file_size {
-                    self.file_size.saturating_sub(extent.logical_offset)
-                } else {
-                    extent.length
-                };
-                let range = DataRange::new(extent.logical_offset, clamped_length);
-                self.current_pos = extent.logical_offset + extent.length;
+                // Check for sparse hole before this extent
+                let hole = (self.holes && extent.logical_offset > self.current_pos).then(|| {
+                    DataRange::hole(
+                        self.current_pos,
+                        extent.logical_offset.min(self.end) - self.current_pos,
+                    )
+                });
 
-                if extent.last() && self.current_pos >= self.file_size {
-                    self.done = true;
-                }
+                // Cut the extent to the window: extents can start before it, and extend
+                // beyond the logical file size due to preallocation or block alignment
+                let range = extent_range(&extent)
+                    .clip(self.current_pos, self.end)
+                    .map(
+                        |range| match self.generations.as_ref().and_then(|g| g.of(&range)) {
+                            Some(generation) => range.with_generation(generation),
+                            None => range,
+                        },
+                    );
+                self.current_pos = self.current_pos.max(extent_end);
 
-                Some(Ok(range))
+                match (hole, range) {
+                    (Some(hole), range) => {
+                        // Store the data range to return next iteration
+                        self.pending_range = range;
+                        Some(Ok(hole))
+                    }
+                    (None, Some(range)) => Some(Ok(range)),
+                    (None, None) => self.next(),
+                }
             }
-            Some(Err(e)) => Some(Err(e)),
+            Some(Err(e)) => Some(Err(ExtentError::kernel(Method::Fiemap)(e))),
             None => {
                 // Check for trailing sparse hole
-                if self.current_pos < self.file_size {
-                    let hole = DataRange::hole(self.current_pos, self.file_size - self.current_pos);
-                    self.current_pos = self.file_size;
+                if self.holes && self.current_pos < self.end {
+                    let hole = DataRange::hole(self.current_pos, self.end - self.current_pos);
+                    self.current_pos = self.end;
                     self.done = true;
                     return Some(Ok(hole));
                 }
@@ -228,8 +682,342 @@ impl Iterator for FiemapRangeIter<'_> {
     }
 }
 
+/// Convert a FIEMAP extent to a data range, keeping track of preallocated extents.
+fn extent_range(extent: &crate::fiemap::FiemapExtent) -> DataRange {
+    let range = if extent.unwritten() {
+        DataRange::unwritten(extent.logical_offset, extent.length)
+    } else {
+        DataRange::new(extent.logical_offset, extent.length)
+    }
+    .with_flags(extent.range_flags());
+    match extent.physical() {
+        Some(physical) => range.with_physical_offset(physical),
+        None => range,
+    }
+}
+
 impl Default for RangeReader {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    #[test]
+    fn denied_fiemap_falls_back() {
+        for errno in [libc::EOPNOTSUPP, libc::ENOTTY, libc::EPERM, libc::EACCES] {
+            assert!(is_fiemap_unsupported(&io::Error::from_raw_os_error(errno)));
+        }
+        assert!(!is_fiemap_unsupported(&io::Error::from_raw_os_error(
+            libc::EIO
+        )));
+        // Network and FUSE filesystems also refuse it in their own ways
+        for errno in [libc::EINVAL, libc::ENOSYS, 524] {
+            assert!(is_refused(&io::Error::from_raw_os_error(errno)));
+        }
+        assert!(!is_refused(&io::Error::from_raw_os_error(libc::EIO)));
+    }
+
+    #[test]
+    fn extent_flags_are_kept() {
+        use linux_raw_sys::ioctl::{
+            FIEMAP_EXTENT_DATA_INLINE, FIEMAP_EXTENT_DELALLOC, FIEMAP_EXTENT_ENCODED,
+            FIEMAP_EXTENT_LAST, FIEMAP_EXTENT_SHARED, FIEMAP_EXTENT_UNKNOWN,
+            FIEMAP_EXTENT_UNWRITTEN,
+        };
+        use zerocopy::FromZeros;
+
+        use crate::RangeFlags;
+
+        let mut extent = crate::fiemap::FiemapExtent::new_zeroed();
+        extent.logical_offset = 4096;
+        extent.length = 100;
+        extent.physical_offset = 1 << 20;
+        extent.flags = FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_ENCODED;
+        let range = extent_range(&extent);
+        assert_eq!(range.physical_offset, Some(1 << 20));
+        assert_eq!(
+            range.flags,
+            RangeFlags {
+                shared: true,
+                compressed: true,
+                ..Default::default()
+            }
+        );
+
+        // Inline data has no location of its own
+        extent.flags = FIEMAP_EXTENT_DATA_INLINE | FIEMAP_EXTENT_LAST;
+        let range = extent_range(&extent);
+        assert!(range.flags.inline && !range.is_zero());
+        assert_eq!(range.physical_offset, None);
+
+        extent.flags = FIEMAP_EXTENT_UNWRITTEN | FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_UNKNOWN;
+        let range = extent_range(&extent);
+        assert!(range.unwritten && range.is_zero());
+        assert!(range.flags.delalloc && range.flags.unknown_location);
+        assert_eq!(range.physical_offset, None);
+    }
+
+    #[test]
+    fn reports_method_used() {
+        let Ok(dir) = tempfile::tempdir_in("/dev/shm") else {
+            eprintln!("Skipping test: no /dev/shm");
+            return;
+        };
+        let path = dir.path().join("file");
+        let file = File::create(&path).unwrap();
+        file.set_len(1 << 20).unwrap();
+        (&file).write_all(b"content").unwrap();
+
+        let (ranges, method) = crate::ranges_for_file_with_method(&file).unwrap();
+        assert_eq!(
+            method,
+            crate::capabilities(&file).unwrap().extent_query,
+            "reading ranges and probing should agree"
+        );
+        if method == Method::SeekHole {
+            // tmpfs is sparse, so the hole after the data is still found without FIEMAP
+            assert!(!ranges[0].hole);
+            assert!(ranges.last().unwrap().hole);
+        }
+
+        let mut reader = RangeReader::new();
+        reader
+            .read_ranges(&File::open(&path).unwrap())
+            .unwrap()
+            .for_each(drop);
+        assert_eq!(reader.last_method(), method);
+        // tmpfs is local, so its holes are to be trusted
+        assert_eq!(reader.last_kind, FsKind::Local);
+        assert_eq!(
+            reader.last_source(),
+            RangeSource::new(method, FsKind::Local)
+        );
+        assert!(method == Method::WholeFile || reader.last_source().is_authoritative());
+        let empty = File::create(dir.path().join("empty")).unwrap();
+        reader.read_ranges(&empty).unwrap().for_each(drop);
+        assert_eq!(reader.last_method(), Method::WholeFile);
+        assert_eq!(reader.last_source(), RangeSource::Assumed);
+    }
+
+    #[test]
+    fn windows_without_fiemap() {
+        // tmpfs doesn't support FIEMAP, so this goes through SEEK_HOLE
+        let Ok(dir) = tempfile::tempdir_in("/dev/shm") else {
+            eprintln!("Skipping test: no /dev/shm");
+            return;
+        };
+        let mut file = File::create(dir.path().join("file")).unwrap();
+        file.set_len(1 << 20).unwrap();
+        file.write_all(&[1; 8192]).unwrap();
+
+        let mut reader = RangeReader::new();
+        let whole: Vec<_> = reader
+            .read_ranges(&file)
+            .unwrap()
+            .collect::<Result<_, ExtentError>>()
+            .unwrap();
+        let windowed: Vec<_> = reader
+            .read_ranges_in(&file, 4096, 8192)
+            .unwrap()
+            .collect::<Result<_, ExtentError>>()
+            .unwrap();
+        let expected: Vec<_> = whole
+            .iter()
+            .filter_map(|range| range.clip(4096, 12288))
+            .collect();
+        assert_eq!(windowed, expected);
+        assert_eq!(windowed.iter().map(|r| r.length).sum::<u64>(), 8192);
+    }
+
+    #[test]
+    fn caches_fiemap_support_per_filesystem() {
+        // tmpfs doesn't support FIEMAP
+        let Ok(dir) = tempfile::tempdir_in("/dev/shm") else {
+            eprintln!("Skipping test: no /dev/shm");
+            return;
+        };
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        let file = File::open(&path).unwrap();
+        let dev = file.metadata().unwrap().dev();
+
+        let mut reader = RangeReader::new();
+        let ranges: Vec<_> = reader.read_ranges(&file).unwrap().collect();
+        let cached = reader.unsupported.as_ref().unwrap().get(&dev).copied();
+        if cached.is_none() {
+            eprintln!("Skipping test: /dev/shm supports FIEMAP");
+            return;
+        }
+
+        assert_eq!(cached, Some(reader.last_method()));
+
+        // The cached path gives the same ranges, found the same way
+        let again: Vec<_> = reader.read_ranges(&file).unwrap().collect();
+        assert_eq!(cached, Some(reader.last_method()));
+        assert_eq!(
+            ranges
+                .into_iter()
+                .collect::<Result<Vec<_>, ExtentError>>()
+                .unwrap(),
+            again
+                .into_iter()
+                .collect::<Result<Vec<_>, ExtentError>>()
+                .unwrap()
+        );
+
+        reader.clear_support_cache();
+        assert!(reader.unsupported.as_ref().unwrap().is_empty());
+
+        reader.set_support_cache(false);
+        reader.read_ranges(&file).unwrap().for_each(drop);
+        assert!(reader.unsupported.is_none());
+    }
+
+    #[test]
+    fn scans_for_zeros_without_queries() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 4096]).unwrap();
+        temp.write_all(&[0; 16384]).unwrap();
+        temp.write_all(&[2; 100]).unwrap();
+        let file = temp.as_file();
+
+        // As if the filesystem supported neither FIEMAP nor SEEK_HOLE/SEEK_DATA
+        let mut reader = RangeReader::new();
+        let dev = file.metadata().unwrap().dev();
+        reader
+            .unsupported
+            .as_mut()
+            .unwrap()
+            .insert(dev, Method::WholeFile);
+        let ranges = |reader: &mut RangeReader| {
+            reader
+                .read_ranges(file)
+                .unwrap()
+                .collect::<Result<Vec<_>, ExtentError>>()
+                .unwrap()
+        };
+        assert_eq!(ranges(&mut reader), [DataRange::new(0, 20580)]);
+        assert_eq!(reader.last_method(), Method::WholeFile);
+
+        reader.set_zero_scan(Some(8192));
+        assert_eq!(
+            ranges(&mut reader),
+            [
+                DataRange::new(0, 4096),
+                DataRange::hole(4096, 16384),
+                DataRange::new(20480, 100),
+            ]
+        );
+        assert_eq!(reader.last_method(), Method::ZeroScan);
+    }
+
+    #[test]
+    fn maps_xattr_tree() {
+        use std::os::unix::ffi::OsStrExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, vec![1; 8192]).unwrap();
+        let file = File::open(&path).unwrap();
+
+        // Syncing places delayed allocations, without changing where the data is
+        let mut reader = RangeReader::new();
+        let options = ReadOptions {
+            sync: true,
+            cache: true,
+            ..Default::default()
+        };
+        let synced = reader
+            .read_ranges_with(&file, options)
+            .unwrap()
+            .collect::<Result<Vec<_>, ExtentError>>()
+            .unwrap();
+        assert_eq!(
+            synced.iter().map(DataRange::to_tuple).collect::<Vec<_>>(),
+            vec![(0, 8192)]
+        );
+        assert!(synced.iter().all(|range| !range.flags.delalloc));
+
+        // Too big to fit in the inode, so it gets a block of its own
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let value = [7u8; 2048];
+        // SAFETY: the path is nul-terminated, and the value is valid for its length
+        let set = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                c"user.extentria".as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if set != 0 {
+            eprintln!("Skipping test: can't set xattrs in the temp dir");
+            return;
+        }
+
+        let options = ReadOptions {
+            xattr_tree: true,
+            ..Default::default()
+        };
+        let ranges = match reader.read_ranges_with(&file, options) {
+            Ok(ranges) => ranges.collect::<Result<Vec<_>, ExtentError>>().unwrap(),
+            Err(ExtentError::Unsupported { .. }) => {
+                eprintln!("Skipping test: the temp dir can't map xattrs");
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(reader.last_method(), Method::Fiemap);
+        assert!(!ranges.is_empty());
+        assert!(ranges.iter().all(|range| !range.hole));
+    }
+
+    #[test]
+    fn tunes_buffer_to_extent_counts() {
+        use std::os::unix::fs::FileExt as _;
+
+        // A block every other block, so each is an extent of its own
+        let fragmented = tempfile::tempfile().unwrap();
+        for block in 0..300 {
+            fragmented.write_all_at(&[1; 4096], block * 8192).unwrap();
+        }
+        fragmented.sync_all().unwrap();
+        let small = tempfile::tempfile().unwrap();
+        small.write_all_at(b"content", 0).unwrap();
+
+        let mut reader = RangeReader::with_buffer_size(result_size() * 16);
+        reader.set_buffer_tuning(true);
+        let extents = reader
+            .read_ranges(&fragmented)
+            .unwrap()
+            .filter(|range| !range.as_ref().unwrap().hole)
+            .count() as u64;
+        if reader.last_method() != Method::Fiemap {
+            eprintln!("Skipping test: the temp dir doesn't support FIEMAP");
+            return;
+        }
+        assert_eq!(reader.capacity(), 16, "tuned only from the next query");
+
+        reader.read_ranges(&small).unwrap().for_each(drop);
+        assert!(reader.capacity() >= extents, "{extents} extents");
+        assert!(reader.capacity().is_power_of_two());
+
+        for _ in 0..50 {
+            reader.read_ranges(&small).unwrap().for_each(drop);
+        }
+        assert_eq!(reader.capacity(), MIN_TUNED_EXTENTS);
+
+        // Without tuning, the buffer stays as it was made
+        let mut reader = RangeReader::with_buffer_size(result_size() * 16);
+        reader.read_ranges(&fragmented).unwrap().for_each(drop);
+        reader.read_ranges(&small).unwrap().for_each(drop);
+        assert_eq!(reader.capacity(), 16);
+    }
+}
@@ -1,21 +1,227 @@
 use std::fs::File;
 use std::io;
+use std::os::fd::AsRawFd as _;
 
-use crate::types::{RangeIter, RangeReaderImpl, private::Sealed};
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::fs_kind::{FsKind, RangeSource, filesystem_kind};
+use crate::types::{
+    DataRange, RangeFlags, RangeIter, RangeReaderImpl, coalesced, private::Sealed, split,
+    split_len, sync_data,
+};
 use crate::unix_seek;
 
+/// `ATTR_CMNEXT_EXT_FLAGS` flag: the file may share blocks with another file.
+const EF_MAY_SHARE_BLOCKS: u64 = 0x1;
+
 /// Range reader for macOS using SEEK_HOLE/SEEK_DATA.
+///
+/// Data ranges are then located on the device with F_LOG2PHYS_EXT, split where they're
+/// fragmented on it, and flagged shared in files that APFS says may share blocks, as clones
+/// made with `clonefile()` do.
 #[derive(Debug, Default)]
-pub struct RangeReader;
+pub struct RangeReader {
+    coalesce: bool,
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    /// Kind of filesystem the last file read was on.
+    kind: FsKind,
+}
 
 impl Sealed for RangeReader {}
 
 impl RangeReaderImpl for RangeReader {
     fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    /// Merge adjacent ranges, undoing the split where data is fragmented on the device.
+    fn set_coalesce(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    fn last_method(&self) -> Method {
+        Method::SeekHole
+    }
+
+    fn last_source(&self) -> RangeSource {
+        RangeSource::new(Method::SeekHole, self.kind)
+    }
+
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        self.ranges(file, 0, u64::MAX)
+    }
+
+    fn read_ranges_in<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        self.ranges(file, offset, length)
+    }
+}
+
+impl RangeReader {
+    /// Read data ranges for `length` bytes of a file from `offset`.
+    fn ranges<'a>(
+        &mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        self.kind = filesystem_kind(file).unwrap_or_default();
+        let located = Located {
+            file,
+            inner: unix_seek::read_ranges_in(file, offset, length)?,
+            shared: may_share_blocks(file),
+            rest: None,
+            locate: true,
+        };
+        Ok(split(coalesced(located, self.coalesce), self.split))
+    }
+}
+
+/// Iterator locating data ranges on the device, splitting them where they're fragmented.
+struct Located<'a, I> {
+    file: &'a File,
+    inner: I,
+    /// Whether the file may share blocks with other files.
+    shared: bool,
+    /// What's left of the range being located.
+    rest: Option<DataRange>,
+    /// Whether to keep locating: stops at the first range that can't be.
+    locate: bool,
+}
+
+impl<I> Iterator for Located<'_, I>
+where
+    I: Iterator<Item = Result<DataRange, ExtentError>>,
+{
+    type Item = Result<DataRange, ExtentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = match self.rest.take() {
+            Some(range) => range,
+            None => match self.inner.next()? {
+                Ok(range) => range,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        if range.hole {
+            return Some(Ok(range));
+        }
+
+        let range = range.with_flags(RangeFlags {
+            shared: self.shared,
+            ..range.flags
+        });
+        if !self.locate {
+            return Some(Ok(range));
+        }
+        match log2phys(self.file, range.offset, range.length) {
+            Ok((physical, contiguous)) if contiguous > 0 => {
+                if contiguous < range.length {
+                    self.rest = Some(DataRange {
+                        offset: range.offset + contiguous,
+                        length: range.length - contiguous,
+                        ..range
+                    });
+                }
+                Some(Ok(DataRange {
+                    length: contiguous.min(range.length),
+                    ..range
+                }
+                .with_physical_offset(physical)))
+            }
+            // Not supported by the filesystem, or not allocated yet
+            _ => {
+                self.locate = false;
+                Some(Ok(range))
+            }
+        }
     }
+}
+
+/// Where `offset` of a file is on its device, and how many of the `length` bytes from there
+/// follow on from it on the device.
+fn log2phys(file: &File, offset: u64, length: u64) -> io::Result<(u64, u64)> {
+    let mut l2p = libc::log2phys {
+        l2p_flags: 0,
+        l2p_contigbytes: length.min(i64::MAX as u64) as libc::off_t,
+        l2p_devoffset: offset as libc::off_t,
+    };
+    // SAFETY: the fd is borrowed from a live File, and F_LOG2PHYS_EXT reads and writes the
+    // struct it's given
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_LOG2PHYS_EXT, &raw mut l2p) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (physical, contiguous) = (l2p.l2p_devoffset, l2p.l2p_contigbytes);
+    Ok((physical as u64, contiguous.max(0) as u64))
+}
+
+/// Whether APFS says a file may share blocks with other files, as clones do.
+///
+/// This is for the whole file: APFS doesn't say which blocks are shared, nor whether they
+/// still are once a clone's been written to.
+fn may_share_blocks(file: &File) -> bool {
+    /// The attributes asked for, as `fgetattrlist` packs them.
+    #[repr(C, packed(4))]
+    struct ExtFlags {
+        length: u32,
+        returned: libc::attribute_set_t,
+        ext_flags: u64,
+    }
+
+    let mut list = libc::attrlist {
+        bitmapcount: libc::ATTR_BIT_MAP_COUNT,
+        reserved: 0,
+        commonattr: libc::ATTR_CMN_RETURNED_ATTRS,
+        volattr: 0,
+        dirattr: 0,
+        fileattr: 0,
+        forkattr: libc::ATTR_CMNEXT_EXT_FLAGS,
+    };
+    // SAFETY: all zeroes is valid for the plain integers of the struct
+    let mut attrs: ExtFlags = unsafe { std::mem::zeroed() };
+    // SAFETY: the fd is borrowed from a live File, and the buffer is valid for its size
+    let ret = unsafe {
+        libc::fgetattrlist(
+            file.as_raw_fd(),
+            (&raw mut list).cast(),
+            (&raw mut attrs).cast(),
+            size_of::<ExtFlags>(),
+            libc::FSOPT_ATTR_CMN_EXTENDED,
+        )
+    };
+    // Filesystems other than APFS don't return the extended flags
+    let returned = attrs.returned.forkattr;
+    let ext_flags = attrs.ext_flags;
+    ret == 0 && returned & libc::ATTR_CMNEXT_EXT_FLAGS != 0 && ext_flags & EF_MAY_SHARE_BLOCKS != 0
+}
 
-    fn read_ranges<'a>(&'a mut self, file: &'a File) -> io::Result<RangeIter<'a>> {
-        Ok(Box::new(unix_seek::read_ranges(file)?))
+/// The name of a file's filesystem type, as `statfs` gives it (`apfs`, `hfs`, ...).
+pub(crate) fn filesystem_name(file: &File) -> io::Result<String> {
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: the fd is borrowed from a live File, and fstatfs fills the struct on success
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    // SAFETY: initialised by the successful call above
+    let stat = unsafe { stat.assume_init() };
+    // SAFETY: the name is nul-terminated within the array
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
 }
@@ -0,0 +1,163 @@
+//! Range reader giving programmed ranges, for testing code that reads ranges.
+//!
+//! Sparse, shared, unwritten and inline ranges only come from filesystems that have them,
+//! which test machines (and Miri) often don't. [`MockRangeReader`] implements the same
+//! trait as the platform readers, but never asks the kernel anything: it gives whatever
+//! ranges, or errors, it's been told to.
+
+use std::collections::VecDeque;
+use std::fs::File;
+
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::types::{
+    DataRange, RangeIter, RangeReaderImpl, coalesced, private::Sealed, split, split_len,
+};
+
+/// Range reader yielding programmed ranges, whatever the file.
+///
+/// Each file read takes the next response [queued](Self::push_ranges), in order; once none
+/// are left, files get the reader's [default ranges](Self::with_ranges), which are none
+/// unless set. Files aren't looked at, not even for their size, so any open file will do,
+/// and ranges needn't match it.
+///
+/// Coalescing and splitting apply to the programmed ranges as they would to a platform
+/// reader's; syncing does nothing.
+#[derive(Debug)]
+pub struct MockRangeReader {
+    /// Responses for the next files read, in order.
+    queue: VecDeque<Result<Vec<DataRange>, ExtentError>>,
+    /// Ranges for files read once the queue is empty.
+    ranges: Vec<DataRange>,
+    method: Method,
+    coalesce: bool,
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    reads: usize,
+}
+
+impl MockRangeReader {
+    /// Create a reader giving these ranges for every file.
+    pub fn with_ranges(ranges: impl IntoIterator<Item = DataRange>) -> Self {
+        Self {
+            ranges: ranges.into_iter().collect(),
+            ..Self::new()
+        }
+    }
+
+    /// Give these ranges for the next file read, after those already queued.
+    pub fn push_ranges(&mut self, ranges: impl IntoIterator<Item = DataRange>) {
+        self.queue.push_back(Ok(ranges.into_iter().collect()));
+    }
+
+    /// Fail the next file read with this error, after those already queued.
+    pub fn push_error(&mut self, err: ExtentError) {
+        self.queue.push_back(Err(err));
+    }
+
+    /// Set the method the reader says ranges were found with, [`Method::Fiemap`] by
+    /// default.
+    pub fn set_method(&mut self, method: Method) {
+        self.method = method;
+    }
+
+    /// How many files have been read, including those that failed.
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// Whether the reader has been set to [sync files first](RangeReaderImpl::set_sync_first).
+    pub fn sync_first(&self) -> bool {
+        self.sync_first
+    }
+}
+
+impl Default for MockRangeReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sealed for MockRangeReader {}
+
+impl RangeReaderImpl for MockRangeReader {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            ranges: Vec::new(),
+            method: Method::Fiemap,
+            coalesce: false,
+            split: None,
+            sync_first: false,
+            reads: 0,
+        }
+    }
+
+    fn set_coalesce(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    fn last_method(&self) -> Method {
+        self.method
+    }
+
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        let _ = file;
+        self.reads += 1;
+        let ranges = match self.queue.pop_front() {
+            Some(response) => response?,
+            None => self.ranges.clone(),
+        };
+        Ok(split(
+            coalesced(ranges.into_iter().map(Ok), self.coalesce),
+            self.split,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_queued_then_default_ranges() {
+        let file = tempfile::tempfile().unwrap();
+        let mut reader = MockRangeReader::with_ranges([DataRange::new(0, 100)]);
+        reader.push_ranges([DataRange::new(0, 4096), DataRange::hole(4096, 4096)]);
+        reader.push_error(ExtentError::unsupported());
+
+        let read = |reader: &mut MockRangeReader| {
+            reader
+                .read_ranges(&file)
+                .and_then(|ranges| ranges.collect::<Result<Vec<_>, _>>())
+        };
+        assert_eq!(
+            read(&mut reader).unwrap(),
+            [DataRange::new(0, 4096), DataRange::hole(4096, 4096)]
+        );
+        assert!(matches!(
+            read(&mut reader),
+            Err(ExtentError::Unsupported { .. })
+        ));
+        assert_eq!(read(&mut reader).unwrap(), [DataRange::new(0, 100)]);
+        assert_eq!(reader.reads(), 3);
+
+        reader.split_at(Some(64));
+        assert_eq!(reader.extent_count(&file).unwrap(), 2);
+        let windowed: Vec<_> = reader
+            .read_ranges_in(&file, 10, 20)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(windowed, [DataRange::new(10, 20)]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Reading ranges with an iterator that owns its reader and file.
+
+use std::collections::VecDeque;
+use std::fs::File;
+
+use crate::error::ExtentError;
+use crate::types::{DataRange, RangeReaderImpl};
+
+/// How many ranges are read at a time.
+const BATCH: usize = 1024;
+
+/// Data ranges of a file, from an iterator that owns the reader and the file.
+///
+/// [`read_ranges`](RangeReaderImpl::read_ranges) borrows both, as its iterator reads from
+/// the file with the reader's buffer as it's advanced. This one is `'static` instead, and
+/// can be sent to another thread if the reader can. It reads ranges in batches, each from
+/// where the last left off with [`read_ranges_in`](RangeReaderImpl::read_ranges_in), so
+/// only a batch is held at a time, at the cost of a query per batch. Made with
+/// [`RangeReaderImpl::into_ranges`].
+#[derive(Debug)]
+pub struct OwnedRanges<R> {
+    reader: R,
+    file: File,
+    /// Ranges read and not yet yielded, in order.
+    pending: VecDeque<DataRange>,
+    /// Where the next batch starts, or `None` once the last has been read.
+    next: Option<u64>,
+    /// Error that ended reading, to give after the ranges before it.
+    error: Option<ExtentError>,
+    batch: usize,
+}
+
+impl<R: RangeReaderImpl> OwnedRanges<R> {
+    /// Take a reader and a file, reading the first batch of ranges.
+    pub(crate) fn new(reader: R, file: File) -> Result<Self, ExtentError> {
+        Self::with_batch(reader, file, BATCH)
+    }
+
+    fn with_batch(reader: R, file: File, batch: usize) -> Result<Self, ExtentError> {
+        let mut ranges = Self {
+            reader,
+            file,
+            pending: VecDeque::new(),
+            next: Some(0),
+            error: None,
+            batch: batch.max(2),
+        };
+        ranges.refill()?;
+        Ok(ranges)
+    }
+
+    /// The reader, as for its [last method](RangeReaderImpl::last_method).
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+
+    /// Give the reader and the file back, dropping any ranges not yet read.
+    pub fn into_inner(self) -> (R, File) {
+        (self.reader, self.file)
+    }
+
+    /// Read the next batch of ranges.
+    ///
+    /// The last range of a full batch is held back and read again at the start of the
+    /// next, as the ranges after it could have been merged into it, if the reader's
+    /// coalescing, or it could have been cut at the end of an extent buffer.
+    fn refill(&mut self) -> Result<(), ExtentError> {
+        let Some(offset) = self.next.take() else {
+            return Ok(());
+        };
+        for range in self
+            .reader
+            .read_ranges_in(&self.file, offset, u64::MAX)?
+            .take(self.batch)
+        {
+            self.pending.push_back(range?);
+        }
+        if self.pending.len() == self.batch {
+            self.next = self.pending.pop_back().map(|last| last.offset);
+        }
+        Ok(())
+    }
+}
+
+impl<R: RangeReaderImpl> Iterator for OwnedRanges<R> {
+    type Item = Result<DataRange, ExtentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_empty()
+            && let Err(err) = self.refill()
+        {
+            self.error = Some(err);
+        }
+        match self.pending.pop_front() {
+            Some(range) => Some(Ok(range)),
+            None => self.error.take().map(Err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek as _, SeekFrom, Write as _};
+
+    use super::*;
+    use crate::RangeReader;
+
+    /// A file with data every other 64 KiB, starting with a hole.
+    fn striped(stripes: u64) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        for stripe in 0..stripes {
+            file.seek(SeekFrom::Start((2 * stripe + 1) * 65536))
+                .unwrap();
+            file.write_all(&[1; 65536]).unwrap();
+        }
+        file.sync_all().unwrap();
+        file
+    }
+
+    #[test]
+    fn reads_in_batches() {
+        let file = striped(10);
+        let expected: Vec<DataRange> = crate::ranges_for_file(&file).unwrap();
+
+        let ranges = OwnedRanges::with_batch(RangeReader::new(), file, 3).unwrap();
+        let ranges: Vec<DataRange> = ranges.collect::<Result<_, _>>().unwrap();
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn coalesces_across_batches() {
+        let mut file = tempfile::tempfile().unwrap();
+        for _ in 0..20 {
+            file.write_all(&[1; 4096]).unwrap();
+            file.sync_all().unwrap();
+        }
+
+        let mut reader = RangeReader::new();
+        reader.set_coalesce(true);
+        let ranges = OwnedRanges::with_batch(reader, file, 2).unwrap();
+        let ranges: Vec<_> = ranges.map(|range| range.unwrap().to_tuple()).collect();
+        assert_eq!(ranges, [(0, 20 * 4096)]);
+    }
+
+    #[test]
+    fn sends_ranges_across_threads() {
+        let file = striped(3);
+        let expected = crate::ranges_for_file(&file).unwrap();
+
+        let ranges = RangeReader::new().into_ranges(file).unwrap();
+        let ranges = std::thread::spawn(move || ranges.collect::<Result<Vec<_>, _>>())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(ranges, expected);
+
+        // Directories can only be opened as files on Unix
+        if cfg!(unix) {
+            let dir = tempfile::tempdir().unwrap();
+            let err = RangeReader::new()
+                .into_ranges(File::open(dir.path()).unwrap())
+                .unwrap_err();
+            assert!(matches!(err, ExtentError::NotARegularFile));
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Reading ranges for many files on rayon's thread pool.
+
+use std::fs::File;
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::RangeReader;
+use crate::error::ExtentError;
+use crate::types::{DataRange, RangeReaderImpl as _};
+
+/// Get data ranges for the files at many paths, on rayon's thread pool.
+///
+/// Paths are shared out between the pool's threads, each with its own [`RangeReader`],
+/// whose buffer is reused for every file in the batches it's given. Results are in the
+/// order of `paths`, with the error opening or reading each file that failed. This runs on
+/// the current pool: to choose the number of threads, call it within
+/// [`ThreadPool::install`](rayon::ThreadPool::install). Without rayon, see
+/// [`ranges_for_files()`](crate::ranges_for_files).
+pub fn par_ranges_for_files<P>(paths: &[P]) -> Vec<Result<Vec<DataRange>, ExtentError>>
+where
+    P: AsRef<Path> + Sync,
+{
+    paths
+        .par_iter()
+        .map_init(RangeReader::new, |reader, path| {
+            let file = File::open(path)?;
+            reader.read_ranges(&file)?.collect()
+        })
+        .collect()
+}
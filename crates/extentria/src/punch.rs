@@ -0,0 +1,416 @@
+//! Punching holes in files, to make them as sparse as the ranges read from another copy,
+//! preallocating their unwritten ranges, and marking files sparse where that's needed first.
+
+use std::fs::File;
+
+use crate::error::ExtentError;
+use crate::types::DataRange;
+
+/// Punch the holes among `ranges` into a file, freeing the storage under them.
+///
+/// Data and unwritten ranges are left alone, so a file's whole layout, as read from
+/// another copy of it, can be given to restore its holes: afterwards, the holes read as
+/// zeros and take no space. The file must be open for writing, and keeps its size: holes
+/// are cut at its end.
+///
+/// This is fallocate with FALLOC_FL_PUNCH_HOLE on Linux; F_PUNCHHOLE on macOS, which needs
+/// holes to start and end on the filesystem's [blocks](crate::block_size) (except at the
+/// end of the file); fspacectl with SPACECTL_DEALLOC on FreeBSD 14 and later, which
+/// writes zeros instead on filesystems that can't free space; and FSCTL_SET_ZERO_DATA on
+/// Windows, after [marking the file sparse](set_sparse). Elsewhere, and on filesystems
+/// that can't have holes, this fails with [`ExtentError::Unsupported`]. If punching fails
+/// partway, the holes before have already been punched.
+pub fn punch_holes(file: &File, ranges: &[DataRange]) -> Result<(), ExtentError> {
+    let len = file.metadata()?.len();
+    let mut holes = ranges
+        .iter()
+        .filter(|range| range.hole)
+        .filter_map(|range| range.clip(0, len))
+        .peekable();
+    if holes.peek().is_none() {
+        return Ok(());
+    }
+
+    prepare(file)?;
+    for hole in holes {
+        punch(file, hole.offset, hole.length)?;
+    }
+    Ok(())
+}
+
+/// Preallocate the unwritten ranges among `ranges` in a file, as they were in another copy.
+///
+/// Like [`punch_holes()`], data ranges and holes are left alone, so a file's whole layout
+/// can be given. The file must be open for writing, and keeps its size. This is fallocate
+/// with FALLOC_FL_KEEP_SIZE on Linux, which leaves the ranges unwritten: they read as zeros
+/// until written. Elsewhere, space can't be allocated at an offset, and this fails with
+/// [`ExtentError::Unsupported`].
+pub fn preallocate(file: &File, ranges: &[DataRange]) -> Result<(), ExtentError> {
+    for range in ranges.iter().filter(|range| range.unwritten) {
+        allocate(file, range.offset, range.length)?;
+    }
+    Ok(())
+}
+
+/// Whether a file is marked sparse.
+///
+/// Windows only has holes in files marked sparse (with FILE_ATTRIBUTE_SPARSE_FILE): others
+/// are fully allocated, so their ranges are all data, and zeroing them writes zeros. Files
+/// elsewhere need no marking to have holes, so this is always true there, whether or not
+/// their filesystem can have any.
+pub fn is_sparse(file: &File) -> Result<bool, ExtentError> {
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::fs::MetadataExt as _;
+
+        use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_SPARSE_FILE;
+
+        Ok(file.metadata()?.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0)
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = file;
+        Ok(true)
+    }
+}
+
+/// Mark a file sparse, or not, so its holes can be punched.
+///
+/// This is FSCTL_SET_SPARSE on Windows, which fails with [`ExtentError::Unsupported`] on
+/// filesystems without sparse files. Unmarking a file allocates its holes, as zeros.
+/// [`punch_holes()`] marks files itself, so this is for restoring holes by other means, or
+/// the attribute for its own sake. Elsewhere, marking does nothing, and unmarking fails
+/// with [`ExtentError::Unsupported`]: holes can only be filled by writing over them.
+pub fn set_sparse(file: &File, sparse: bool) -> Result<(), ExtentError> {
+    #[cfg(target_os = "windows")]
+    {
+        use windows_sys::Win32::System::Ioctl::{FILE_SET_SPARSE_BUFFER, FSCTL_SET_SPARSE};
+
+        let buffer = FILE_SET_SPARSE_BUFFER { SetSparse: sparse };
+        control(
+            file,
+            FSCTL_SET_SPARSE,
+            (&raw const buffer).cast(),
+            size_of::<FILE_SET_SPARSE_BUFFER>() as u32,
+        )
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        match sparse {
+            true => Ok(()),
+            false => Err(ExtentError::unsupported_on(file)),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn prepare(_file: &File) -> Result<(), ExtentError> {
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn punch(file: &File, offset: u64, length: u64) -> Result<(), ExtentError> {
+    use std::io;
+    use std::os::fd::AsRawFd as _;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: the fd is borrowed from a live File
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as _, length as _) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => ExtentError::unsupported_on(file),
+            _ => err.into(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn allocate(file: &File, offset: u64, length: u64) -> Result<(), ExtentError> {
+    use std::io;
+    use std::os::fd::AsRawFd as _;
+
+    // SAFETY: the fd is borrowed from a live File
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            offset as _,
+            length as _,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => ExtentError::unsupported_on(file),
+            _ => err.into(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn allocate(file: &File, _offset: u64, _length: u64) -> Result<(), ExtentError> {
+    Err(ExtentError::unsupported_on(file))
+}
+
+#[cfg(target_os = "macos")]
+fn prepare(_file: &File) -> Result<(), ExtentError> {
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn punch(file: &File, offset: u64, length: u64) -> Result<(), ExtentError> {
+    use std::io;
+    use std::os::fd::AsRawFd as _;
+
+    let punch = libc::fpunchhole_t {
+        fp_flags: 0,
+        reserved: 0,
+        fp_offset: offset as libc::off_t,
+        fp_length: length as libc::off_t,
+    };
+    // SAFETY: the fd is borrowed from a live File, and F_PUNCHHOLE only reads the struct
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PUNCHHOLE, &raw const punch) } == -1 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENOTSUP) | Some(libc::ENOTTY) => ExtentError::unsupported_on(file),
+            _ => err.into(),
+        });
+    }
+    Ok(())
+}
+
+/// Check the system has fspacectl, which is looked up at runtime.
+#[cfg(target_os = "freebsd")]
+fn prepare(file: &File) -> Result<(), ExtentError> {
+    match crate::freebsd::fspacectl() {
+        Some(_) => Ok(()),
+        None => Err(ExtentError::unsupported_on(file)),
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn punch(file: &File, offset: u64, length: u64) -> Result<(), ExtentError> {
+    use std::io;
+    use std::os::fd::AsRawFd as _;
+
+    let fspacectl = crate::freebsd::fspacectl().ok_or_else(ExtentError::unsupported)?;
+    let mut range = libc::spacectl_range {
+        r_offset: offset as libc::off_t,
+        r_len: length as libc::off_t,
+    };
+    // The kernel can stop partway, leaving in the range what's still to do
+    while range.r_len > 0 {
+        let request = range;
+        // SAFETY: the fd is borrowed from a live File, and both ranges are valid
+        let ret = unsafe {
+            fspacectl(
+                file.as_raw_fd(),
+                libc::SPACECTL_DEALLOC,
+                &raw const request,
+                0,
+                &raw mut range,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) => ExtentError::unsupported_on(file),
+                _ => err.into(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Mark the file sparse, so zeroing its data frees clusters instead of writing zeros.
+#[cfg(target_os = "windows")]
+fn prepare(file: &File) -> Result<(), ExtentError> {
+    set_sparse(file, true)
+}
+
+#[cfg(target_os = "windows")]
+fn punch(file: &File, offset: u64, length: u64) -> Result<(), ExtentError> {
+    use windows_sys::Win32::System::Ioctl::{FILE_ZERO_DATA_INFORMATION, FSCTL_SET_ZERO_DATA};
+
+    let zero = FILE_ZERO_DATA_INFORMATION {
+        FileOffset: offset as i64,
+        BeyondFinalZero: (offset + length) as i64,
+    };
+    control(
+        file,
+        FSCTL_SET_ZERO_DATA,
+        (&raw const zero).cast(),
+        size_of::<FILE_ZERO_DATA_INFORMATION>() as u32,
+    )
+}
+
+/// Send a control code with an input buffer, and no output, to a file.
+#[cfg(target_os = "windows")]
+fn control(
+    file: &File,
+    code: u32,
+    input: *const std::ffi::c_void,
+    input_len: u32,
+) -> Result<(), ExtentError> {
+    use std::io;
+    use std::os::windows::io::AsRawHandle as _;
+
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let handle = file.as_raw_handle() as HANDLE;
+    let mut bytes_returned = 0u32;
+    // SAFETY: the handle is borrowed from a live File, and the input is valid for its length
+    let result = unsafe {
+        DeviceIoControl(
+            handle,
+            code,
+            input,
+            input_len,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if result == 0 {
+        let err = io::Error::last_os_error();
+        // ERROR_INVALID_FUNCTION or ERROR_NOT_SUPPORTED: the file system has no sparse files
+        return Err(match err.raw_os_error() {
+            Some(1) | Some(50) => ExtentError::unsupported_on(file),
+            _ => err.into(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "windows"
+)))]
+fn prepare(_file: &File) -> Result<(), ExtentError> {
+    Err(ExtentError::unsupported())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "windows"
+)))]
+fn punch(_file: &File, _offset: u64, _length: u64) -> Result<(), ExtentError> {
+    Err(ExtentError::unsupported())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read as _, Write as _};
+
+    use super::*;
+    use crate::{Method, RangeReader, RangeReaderImpl as _, RangeSet, ranges_for_file};
+
+    #[test]
+    fn marks_files_sparse() {
+        let file = tempfile::tempfile().unwrap();
+        match set_sparse(&file, true) {
+            Ok(()) => {}
+            Err(ExtentError::Unsupported { .. }) => {
+                eprintln!("Skipping test: the temp dir has no sparse files");
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        }
+        assert!(is_sparse(&file).unwrap());
+
+        if cfg!(windows) {
+            set_sparse(&file, false).unwrap();
+            assert!(!is_sparse(&file).unwrap());
+        } else {
+            assert!(matches!(
+                set_sparse(&file, false),
+                Err(ExtentError::Unsupported { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn preallocates_unwritten_ranges() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(3 * 65536).unwrap();
+
+        let layout = [
+            DataRange::hole(0, 65536),
+            DataRange::unwritten(65536, 65536),
+            DataRange::hole(2 * 65536, 65536),
+        ];
+        match preallocate(&file, &layout) {
+            Ok(()) => {}
+            Err(ExtentError::Unsupported { .. }) => {
+                eprintln!("Skipping test: can't preallocate in the temp dir");
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        }
+        assert_eq!(file.metadata().unwrap().len(), 3 * 65536);
+
+        // Only FIEMAP tells unwritten ranges apart
+        let mut reader = RangeReader::new();
+        let ranges: Vec<DataRange> = reader
+            .read_ranges(&file)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        if reader.last_method() == Method::Fiemap {
+            let unwritten: RangeSet = ranges
+                .iter()
+                .filter(|range| range.unwritten)
+                .map(|range| range.offset..range.end())
+                .collect();
+            assert_eq!(unwritten.bytes(), 65536, "{ranges:?}");
+            assert!(unwritten.contains(65536));
+        }
+    }
+
+    #[test]
+    fn punches_holes() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 3 * 65536]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let file = temp.as_file();
+
+        let layout = [
+            DataRange::new(0, 65536),
+            DataRange::hole(65536, 65536),
+            DataRange::new(2 * 65536, 65536),
+            // Past the end of the file
+            DataRange::hole(3 * 65536, 65536),
+        ];
+        match punch_holes(file, &layout) {
+            Ok(()) => {}
+            Err(ExtentError::Unsupported { .. }) => {
+                eprintln!("Skipping test: can't punch holes in the temp dir");
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        }
+        assert_eq!(file.metadata().unwrap().len(), 3 * 65536);
+
+        let mut contents = Vec::new();
+        temp.reopen().unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents[..65536].iter().all(|&b| b == 1));
+        assert!(contents[65536..2 * 65536].iter().all(|&b| b == 0));
+        assert!(contents[2 * 65536..].iter().all(|&b| b == 1));
+
+        let allocated: RangeSet = ranges_for_file(file).unwrap().into_iter().collect();
+        if allocated.bytes() < 3 * 65536 {
+            assert!(!allocated.contains(65536));
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Sets of byte ranges, for working out where files have data without loops over ranges.
+
+use std::ops::Range;
+
+use crate::types::DataRange;
+
+/// A set of byte offsets, held as the ranges they make up.
+///
+/// Ranges are kept sorted, and merged where they overlap or follow on from each other, so
+/// any set has one normal form: two sets with the same offsets are equal. Empty ranges are
+/// left out.
+///
+/// Collecting [`DataRange`]s gives the set of allocated bytes: data ranges, including
+/// preallocated ones, without holes. As `Result` collects too, a reader's ranges collect
+/// straight into a set with `collect::<Result<RangeSet, _>>()`, and the
+/// [complement](Self::complement) of that within the file's size is its holes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RangeSet {
+    ranges: Vec<Range<u64>>,
+}
+
+impl RangeSet {
+    /// An empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ranges of the set, in order.
+    pub fn as_slice(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    /// Iterate over the ranges of the set, in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, Range<u64>> {
+        self.ranges.iter()
+    }
+
+    /// How many ranges the set is made of.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Whether the set has no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// How many bytes are in the set.
+    pub fn bytes(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    /// From the first byte in the set to the end of the last one.
+    pub fn span(&self) -> Option<Range<u64>> {
+        Some(self.ranges.first()?.start..self.ranges.last()?.end)
+    }
+
+    /// Whether a byte offset is in the set.
+    pub fn contains(&self, offset: u64) -> bool {
+        let index = self.ranges.partition_point(|range| range.end <= offset);
+        self.ranges
+            .get(index)
+            .is_some_and(|range| range.start <= offset)
+    }
+
+    /// Add a range of bytes to the set.
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        // Ranges overlapping or touching the new one are merged into it
+        let first = self.ranges.partition_point(|r| r.end < range.start);
+        let last = self.ranges.partition_point(|r| r.start <= range.end);
+        let merged = if first < last {
+            self.ranges[first].start.min(range.start)..self.ranges[last - 1].end.max(range.end)
+        } else {
+            range
+        };
+        self.ranges.splice(first..last, std::iter::once(merged));
+    }
+
+    /// Take a range of bytes out of the set.
+    pub fn remove(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let first = self.ranges.partition_point(|r| r.end <= range.start);
+        let last = self.ranges.partition_point(|r| r.start < range.end);
+        if first == last {
+            return;
+        }
+        // Only the ranges at either end can stick out of the one taken out
+        let (head, tail) = (self.ranges[first].start, self.ranges[last - 1].end);
+        let kept = [head..range.start, range.end..tail];
+        self.ranges.splice(
+            first..last,
+            kept.into_iter().filter(|range| !range.is_empty()),
+        );
+    }
+
+    /// Bytes in either set.
+    pub fn union(&self, other: &Self) -> Self {
+        let mut ranges: Vec<_> = self.iter().chain(other).cloned().collect();
+        // Two sorted runs, which the stable sort merges in linear time
+        ranges.sort_by_key(|range| range.start);
+        Self::from_sorted(ranges)
+    }
+
+    /// Bytes in both sets.
+    pub fn intersection(&self, other: &Self) -> Self {
+        let (a, b) = (&self.ranges, &other.ranges);
+        let (mut i, mut j) = (0, 0);
+        let mut ranges = Vec::new();
+        while i < a.len() && j < b.len() {
+            let start = a[i].start.max(b[j].start);
+            let end = a[i].end.min(b[j].end);
+            if start < end {
+                ranges.push(start..end);
+            }
+            if a[i].end < b[j].end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        Self { ranges }
+    }
+
+    /// Bytes in this set but not the other.
+    pub fn difference(&self, other: &Self) -> Self {
+        let b = &other.ranges;
+        let mut j = 0;
+        let mut ranges = Vec::new();
+        for range in &self.ranges {
+            let mut start = range.start;
+            while j < b.len() && b[j].end <= start {
+                j += 1;
+            }
+            // Ranges of the other set reaching past this one are looked at again for the next
+            let mut k = j;
+            while k < b.len() && b[k].start < range.end {
+                if b[k].start > start {
+                    ranges.push(start..b[k].start);
+                }
+                start = start.max(b[k].end);
+                k += 1;
+            }
+            if start < range.end {
+                ranges.push(start..range.end);
+            }
+        }
+        Self { ranges }
+    }
+
+    /// Bytes within `bounds` that aren't in the set: for the allocated bytes of a file
+    /// bounded by its size, its holes.
+    pub fn complement(&self, bounds: Range<u64>) -> Self {
+        Self::from(bounds).difference(self)
+    }
+
+    /// Merge ranges sorted by start into normal form.
+    fn from_sorted(sorted: impl IntoIterator<Item = Range<u64>>) -> Self {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for range in sorted.into_iter().filter(|range| !range.is_empty()) {
+            match ranges.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => ranges.push(range),
+            }
+        }
+        Self { ranges }
+    }
+}
+
+impl From<Range<u64>> for RangeSet {
+    fn from(range: Range<u64>) -> Self {
+        Self::from_sorted([range])
+    }
+}
+
+impl FromIterator<Range<u64>> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = Range<u64>>>(iter: I) -> Self {
+        let mut ranges: Vec<_> = iter.into_iter().collect();
+        ranges.sort_by_key(|range| range.start);
+        Self::from_sorted(ranges)
+    }
+}
+
+/// The allocated bytes: data ranges, including preallocated ones, without holes.
+impl FromIterator<DataRange> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = DataRange>>(iter: I) -> Self {
+        iter.into_iter()
+            .filter(|range| !range.hole)
+            .map(|range| range.offset..range.end())
+            .collect()
+    }
+}
+
+impl Extend<Range<u64>> for RangeSet {
+    fn extend<I: IntoIterator<Item = Range<u64>>>(&mut self, iter: I) {
+        for range in iter {
+            self.insert(range);
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a RangeSet {
+    type Item = &'a Range<u64>;
+    type IntoIter = std::slice::Iter<'a, Range<u64>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl IntoIterator for RangeSet {
+    type Item = Range<u64>;
+    type IntoIter = std::vec::IntoIter<Range<u64>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ranges.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(ranges: &[Range<u64>]) -> RangeSet {
+        ranges.iter().cloned().collect()
+    }
+
+    #[test]
+    fn normalises() {
+        let ranges = set(&[10..20, 0..5, 15..30, 5..8, 40..40]);
+        assert_eq!(ranges.as_slice(), [0..8, 10..30]);
+        assert_eq!(ranges.bytes(), 28);
+        assert_eq!(ranges.span(), Some(0..30));
+        assert!(ranges.contains(0) && ranges.contains(29));
+        assert!(!ranges.contains(8) && !ranges.contains(30));
+
+        let data = [
+            DataRange::new(0, 10),
+            DataRange::hole(10, 10),
+            DataRange::unwritten(20, 10),
+            DataRange::new(30, 10),
+        ];
+        let allocated: RangeSet = data.into_iter().collect();
+        assert_eq!(allocated.as_slice(), [0..10, 20..40]);
+        assert_eq!(allocated.complement(0..50).as_slice(), [10..20, 40..50]);
+    }
+
+    #[test]
+    fn inserts_and_removes() {
+        let mut ranges = set(&[0..10, 20..30, 40..50]);
+        ranges.insert(10..20);
+        assert_eq!(ranges.as_slice(), [0..30, 40..50]);
+        ranges.insert(35..38);
+        assert_eq!(ranges.as_slice(), [0..30, 35..38, 40..50]);
+
+        ranges.remove(5..45);
+        assert_eq!(ranges.as_slice(), [0..5, 45..50]);
+        ranges.remove(46..47);
+        assert_eq!(ranges.as_slice(), [0..5, 45..46, 47..50]);
+        ranges.remove(100..200);
+        assert_eq!(ranges.as_slice(), [0..5, 45..46, 47..50]);
+    }
+
+    #[test]
+    fn set_operations() {
+        let a = set(&[0..10, 20..30, 40..50]);
+        let b = set(&[5..25, 28..42, 60..70]);
+        assert_eq!(a.union(&b).as_slice(), [0..50, 60..70]);
+        assert_eq!(
+            a.intersection(&b).as_slice(),
+            [5..10, 20..25, 28..30, 40..42]
+        );
+        assert_eq!(a.difference(&b).as_slice(), [0..5, 25..28, 42..50]);
+        assert_eq!(b.difference(&a).as_slice(), [10..20, 30..40, 60..70]);
+        assert!(a.difference(&a).is_empty());
+        assert_eq!(a.union(&RangeSet::new()), a);
+        assert!(a.intersection(&RangeSet::new()).is_empty());
+    }
+}
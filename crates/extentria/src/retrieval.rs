@@ -0,0 +1,462 @@
+//! Where files' data is on Windows volumes, from FSCTL_GET_RETRIEVAL_POINTERS.
+//!
+//! FSCTL_QUERY_ALLOCATED_RANGES only says which parts of a file have data. Retrieval
+//! pointers map the file's clusters to clusters of the volume, which says where each range
+//! is on disk (as FIEMAP does on Linux), and whether its data has clusters at all: small
+//! files on NTFS are resident in their MFT record instead.
+//!
+//! On volumes that count references to blocks (ReFS, which shares clusters between files
+//! cloned with FSCTL_DUPLICATE_EXTENTS_TO_FILE), FSCTL_GET_RETRIEVAL_POINTERS_AND_REFCOUNT
+//! gives each run's reference count too, and runs referenced more than once are shared.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::mem::{offset_of, size_of};
+
+use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, ERROR_MORE_DATA, HANDLE};
+use windows_sys::Win32::Storage::FileSystem::{
+    BY_HANDLE_FILE_INFORMATION, FILE_ATTRIBUTE_COMPRESSED, FILE_ATTRIBUTE_ENCRYPTED,
+    GetDiskFreeSpaceW, GetFileInformationByHandle, GetFinalPathNameByHandleW,
+    GetVolumeInformationByHandleW, VOLUME_NAME_GUID,
+};
+use windows_sys::Win32::System::IO::DeviceIoControl;
+use windows_sys::Win32::System::Ioctl::{
+    FSCTL_GET_RETRIEVAL_POINTERS, FSCTL_GET_RETRIEVAL_POINTERS_AND_REFCOUNT,
+    RETRIEVAL_POINTERS_AND_REFCOUNT_BUFFER, RETRIEVAL_POINTERS_AND_REFCOUNT_BUFFER_0,
+    RETRIEVAL_POINTERS_BUFFER, RETRIEVAL_POINTERS_BUFFER_0, STARTING_VCN_INPUT_BUFFER,
+};
+
+use crate::types::{DataRange, RangeFlags};
+
+/// Buffer for retrieval pointers, in `u64`s to be aligned for the structure's fields.
+const BUFFER_WORDS: usize = 512;
+
+/// File system flag: the volume counts references to blocks, so files can share them.
+const FILE_SUPPORTS_BLOCK_REFCOUNTING: u32 = 0x0800_0000;
+
+/// What matters about a volume for mapping files on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Volume {
+    /// Size of clusters, in bytes.
+    pub(crate) cluster_size: u64,
+    /// Whether clusters can be shared between files, and their references counted.
+    pub(crate) block_refcounting: bool,
+}
+
+impl Volume {
+    /// Query the volume of a file.
+    pub(crate) fn of(handle: HANDLE) -> io::Result<Self> {
+        let mut flags = 0u32;
+        let result = unsafe {
+            GetVolumeInformationByHandleW(
+                handle,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut flags,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            cluster_size: cluster_size(handle)?,
+            block_refcounting: flags & FILE_SUPPORTS_BLOCK_REFCOUNTING != 0,
+        })
+    }
+}
+
+/// A run of a file's clusters: bytes `start..end` of the file, at `physical` on the volume.
+///
+/// Runs without clusters (`physical` is `None`) are holes, or in compressed files, the
+/// part of a compression unit its compressed data didn't need. Runs are `shared` when
+/// their clusters are referenced more than once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Run {
+    start: u64,
+    end: u64,
+    physical: Option<u64>,
+    shared: bool,
+}
+
+/// Where a file's data is on its volume.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClusterMap {
+    runs: Vec<Run>,
+    flags: RangeFlags,
+}
+
+impl ClusterMap {
+    /// Query where a file's data is.
+    ///
+    /// Volumes are cached by serial number in `volumes`.
+    pub(crate) fn query(handle: HANDLE, volumes: &mut HashMap<u32, Volume>) -> io::Result<Self> {
+        let info = file_info(handle)?;
+        let volume = match volumes.get(&info.dwVolumeSerialNumber) {
+            Some(&volume) => volume,
+            None => {
+                let volume = Volume::of(handle)?;
+                volumes.insert(info.dwVolumeSerialNumber, volume);
+                volume
+            }
+        };
+
+        let runs = match retrieval_pointers(handle, volume) {
+            // Reference counts need a newer ReFS than block cloning does
+            Err(_) if volume.block_refcounting => retrieval_pointers(
+                handle,
+                Volume {
+                    block_refcounting: false,
+                    ..volume
+                },
+            )?,
+            runs => runs?,
+        };
+
+        let attributes = info.dwFileAttributes;
+        Ok(Self {
+            runs,
+            flags: RangeFlags {
+                compressed: attributes & FILE_ATTRIBUTE_COMPRESSED != 0,
+                encrypted: attributes & FILE_ATTRIBUTE_ENCRYPTED != 0,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Split a data range where its clusters are discontiguous on the volume, and say where
+    /// each part is.
+    pub(crate) fn split(&self, range: DataRange, out: &mut VecDeque<DataRange>) {
+        let end = range.end();
+        let mut position = range.offset;
+        let piece = |offset: u64, end: u64| {
+            DataRange {
+                offset,
+                length: end - offset,
+                ..range
+            }
+            .with_flags(self.flags)
+        };
+
+        for run in self
+            .runs
+            .iter()
+            .filter(|run| run.end > range.offset && run.start < end)
+        {
+            if run.start > position {
+                out.push_back(self.unmapped(piece(position, run.start)));
+                position = run.start;
+            }
+            let run_end = run.end.min(end);
+            let mut range = match run.physical {
+                Some(physical) => {
+                    piece(position, run_end).with_physical_offset(physical + position - run.start)
+                }
+                None => self.unmapped(piece(position, run_end)),
+            };
+            range.flags.shared = run.shared;
+            out.push_back(range);
+            position = run_end;
+        }
+        if position < end {
+            out.push_back(self.unmapped(piece(position, end)));
+        }
+    }
+
+    /// Mark data without clusters of its own.
+    fn unmapped(&self, mut range: DataRange) -> DataRange {
+        if self.runs.is_empty() {
+            // Data but no clusters at all: resident in the MFT record
+            range.flags.inline = true;
+        } else if !self.flags.compressed {
+            range.flags.unknown_location = true;
+        }
+        range
+    }
+}
+
+/// Attributes and volume of a file.
+fn file_info(handle: HANDLE) -> io::Result<BY_HANDLE_FILE_INFORMATION> {
+    let mut info = BY_HANDLE_FILE_INFORMATION::default();
+    if unsafe { GetFileInformationByHandle(handle, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Size of clusters on the volume of a file, in bytes.
+pub(crate) fn cluster_size(handle: HANDLE) -> io::Result<u64> {
+    // The path by volume GUID, as `\\?\Volume{...}\dir\file`
+    let mut path = vec![0u16; 1024];
+    let len = unsafe {
+        GetFinalPathNameByHandleW(
+            handle,
+            path.as_mut_ptr(),
+            path.len() as u32,
+            VOLUME_NAME_GUID,
+        )
+    } as usize;
+    if len == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if len >= path.len() {
+        path.resize(len + 1, 0);
+        let len = unsafe {
+            GetFinalPathNameByHandleW(
+                handle,
+                path.as_mut_ptr(),
+                path.len() as u32,
+                VOLUME_NAME_GUID,
+            )
+        } as usize;
+        if len == 0 || len >= path.len() {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // The volume root is everything up to the first separator after the `\\?\` prefix
+    let separator = path[4..]
+        .iter()
+        .position(|&c| c == u16::from(b'\\'))
+        .ok_or_else(|| io::Error::other("unexpected volume path"))?;
+    let mut root = path[..4 + separator + 1].to_vec();
+    root.push(0);
+
+    let (mut sectors_per_cluster, mut bytes_per_sector) = (0u32, 0u32);
+    let (mut free, mut total) = (0u32, 0u32);
+    let result = unsafe {
+        GetDiskFreeSpaceW(
+            root.as_ptr(),
+            &mut sectors_per_cluster,
+            &mut bytes_per_sector,
+            &mut free,
+            &mut total,
+        )
+    };
+    if result == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(u64::from(sectors_per_cluster) * u64::from(bytes_per_sector))
+}
+
+/// The name of the file system on the volume of a file (`NTFS`, `ReFS`, `FAT32`, ...).
+pub(crate) fn filesystem_name(handle: HANDLE) -> io::Result<String> {
+    // MAX_PATH + 1, the most the call can give
+    let mut name = [0u16; 261];
+    let result = unsafe {
+        GetVolumeInformationByHandleW(
+            handle,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if result == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(String::from_utf16_lossy(&name[..len]))
+}
+
+/// Read all of a file's cluster runs, with their reference counts if the volume has them.
+fn retrieval_pointers(handle: HANDLE, volume: Volume) -> io::Result<Vec<Run>> {
+    let (cluster_size, refcounts) = (volume.cluster_size, volume.block_refcounting);
+    let (control, extents_offset, extent_size) = if refcounts {
+        (
+            FSCTL_GET_RETRIEVAL_POINTERS_AND_REFCOUNT,
+            offset_of!(RETRIEVAL_POINTERS_AND_REFCOUNT_BUFFER, Extents),
+            size_of::<RETRIEVAL_POINTERS_AND_REFCOUNT_BUFFER_0>(),
+        )
+    } else {
+        (
+            FSCTL_GET_RETRIEVAL_POINTERS,
+            offset_of!(RETRIEVAL_POINTERS_BUFFER, Extents),
+            size_of::<RETRIEVAL_POINTERS_BUFFER_0>(),
+        )
+    };
+    let mut buffer = vec![0u64; BUFFER_WORDS];
+    let mut runs = Vec::new();
+    let mut vcn = 0i64;
+
+    loop {
+        let input = STARTING_VCN_INPUT_BUFFER { StartingVcn: vcn };
+        let mut bytes_returned: u32 = 0;
+        let result = unsafe {
+            DeviceIoControl(
+                handle,
+                control,
+                &input as *const _ as *const _,
+                size_of::<STARTING_VCN_INPUT_BUFFER>() as u32,
+                buffer.as_mut_ptr() as *mut _,
+                (buffer.len() * size_of::<u64>()) as u32,
+                &mut bytes_returned,
+                std::ptr::null_mut(),
+            )
+        };
+
+        let more = if result == 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // ERROR_MORE_DATA means the buffer was filled, with more runs after
+                Some(code) if code == ERROR_MORE_DATA as i32 => true,
+                // ERROR_HANDLE_EOF means no clusters: the file is empty or resident
+                Some(code) if code == ERROR_HANDLE_EOF as i32 => break,
+                _ => return Err(err),
+            }
+        } else {
+            false
+        };
+
+        // SAFETY: the buffer is big enough for the header and aligned for its fields, and
+        // the count is bounded by what the kernel says it wrote. Both buffer structures
+        // start with the same header.
+        let header = unsafe { buffer.as_ptr().cast::<RETRIEVAL_POINTERS_BUFFER>().read() };
+        let written = (bytes_returned as usize).saturating_sub(extents_offset) / extent_size;
+        let count = (header.ExtentCount as usize).min(written);
+        let extents = unsafe { buffer.as_ptr().cast::<u8>().add(extents_offset) };
+
+        let mut start = header.StartingVcn;
+        for index in 0..count {
+            let (next_vcn, lcn, references) = unsafe {
+                if refcounts {
+                    let extent = extents
+                        .cast::<RETRIEVAL_POINTERS_AND_REFCOUNT_BUFFER_0>()
+                        .add(index)
+                        .read();
+                    (extent.NextVcn, extent.Lcn, extent.ReferenceCount)
+                } else {
+                    let extent = extents
+                        .cast::<RETRIEVAL_POINTERS_BUFFER_0>()
+                        .add(index)
+                        .read();
+                    (extent.NextVcn, extent.Lcn, 1)
+                }
+            };
+            runs.push(Run {
+                start: start as u64 * cluster_size,
+                end: next_vcn as u64 * cluster_size,
+                // LCN -1: no clusters for this run
+                physical: (lcn >= 0).then(|| lcn as u64 * cluster_size),
+                shared: lcn >= 0 && references > 1,
+            });
+            start = next_vcn;
+        }
+
+        if !more || count == 0 {
+            break;
+        }
+        vcn = start;
+    }
+
+    Ok(runs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(map: &ClusterMap, range: DataRange) -> Vec<DataRange> {
+        let mut out = VecDeque::new();
+        map.split(range, &mut out);
+        out.into()
+    }
+
+    #[test]
+    fn splits_at_fragments() {
+        let map = ClusterMap {
+            runs: vec![
+                Run {
+                    start: 0,
+                    end: 8192,
+                    physical: Some(1 << 20),
+                    shared: false,
+                },
+                Run {
+                    start: 8192,
+                    end: 12288,
+                    physical: Some(1 << 30),
+                    shared: false,
+                },
+            ],
+            flags: RangeFlags::default(),
+        };
+        let ranges = split(&map, DataRange::new(4096, 6000));
+        assert_eq!(
+            ranges,
+            [
+                DataRange::new(4096, 4096).with_physical_offset((1 << 20) + 4096),
+                DataRange::new(8192, 1904).with_physical_offset(1 << 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn resident_data_is_inline() {
+        let map = ClusterMap::default();
+        let ranges = split(&map, DataRange::new(0, 100));
+        assert_eq!(ranges.len(), 1);
+        assert!(ranges[0].flags.inline);
+        assert_eq!(ranges[0].physical_offset, None);
+    }
+
+    #[test]
+    fn compressed_units_have_no_unknown_location() {
+        let map = ClusterMap {
+            runs: vec![
+                Run {
+                    start: 0,
+                    end: 4096,
+                    physical: Some(4096),
+                    shared: false,
+                },
+                Run {
+                    start: 4096,
+                    end: 65536,
+                    physical: None,
+                    shared: false,
+                },
+            ],
+            flags: RangeFlags {
+                compressed: true,
+                ..Default::default()
+            },
+        };
+        let ranges = split(&map, DataRange::new(0, 65536));
+        assert_eq!(ranges.len(), 2);
+        assert!(ranges.iter().all(|r| r.flags.compressed));
+        assert!(!ranges[1].flags.unknown_location);
+        assert_eq!(ranges[1].physical_offset, None);
+    }
+
+    #[test]
+    fn refcounted_runs_are_shared() {
+        let map = ClusterMap {
+            runs: vec![
+                Run {
+                    start: 0,
+                    end: 4096,
+                    physical: Some(4096),
+                    shared: true,
+                },
+                Run {
+                    start: 4096,
+                    end: 8192,
+                    physical: Some(1 << 20),
+                    shared: false,
+                },
+            ],
+            flags: RangeFlags::default(),
+        };
+        let ranges = split(&map, DataRange::new(0, 8192));
+        assert_eq!(ranges.len(), 2);
+        assert!(ranges[0].flags.shared);
+        assert!(!ranges[1].flags.shared);
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::RangeReader;
+use crate::error::ExtentError;
+use crate::types::{DataRange, RangeReaderImpl as _};
+
+/// How to [scan a directory](scan_dir).
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Follow symbolic links, to files and directories, instead of skipping them.
+    pub follow_links: bool,
+    /// Stay on the directory's filesystem, leaving out what's mounted under it.
+    pub same_file_system: bool,
+    /// Levels of subdirectories to go into, or all of them with `None`: with 0, only the
+    /// files directly in the directory are scanned.
+    pub max_depth: Option<usize>,
+    /// Merge adjacent ranges that are stored the same way, as
+    /// [`set_coalesce`](crate::RangeReaderImpl::set_coalesce) does.
+    pub coalesce: bool,
+}
+
+/// Walk a directory, reading the ranges of every regular file in it.
+///
+/// Yields each file's path with its ranges, or the error reading them. Errors reading
+/// directories are yielded with the directory's path, and the walk goes on without them.
+/// Files are read with one [`RangeReader`], whose buffer is reused throughout, as the
+/// iterator is advanced; within each directory, in order of file name. If `path` is a
+/// file, it's the only one scanned.
+pub fn scan_dir(path: impl AsRef<Path>, options: &ScanOptions) -> Scan {
+    let mut walk = walkdir::WalkDir::new(path)
+        .follow_links(options.follow_links)
+        .same_file_system(options.same_file_system)
+        .sort_by_file_name();
+    if let Some(depth) = options.max_depth {
+        walk = walk.max_depth(depth.saturating_add(1));
+    }
+    let mut reader = RangeReader::new();
+    reader.set_coalesce(options.coalesce);
+    Scan {
+        walk: walk.into_iter(),
+        reader,
+    }
+}
+
+/// Iterator over the files of a directory and their ranges, from [`scan_dir()`].
+#[derive(Debug)]
+pub struct Scan {
+    walk: walkdir::IntoIter,
+    reader: RangeReader,
+}
+
+impl Iterator for Scan {
+    type Item = (PathBuf, Result<Vec<DataRange>, ExtentError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.walk.next()? {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let path = err.path().map(Path::to_path_buf).unwrap_or_default();
+                    return Some((path, Err(io::Error::from(err).into())));
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let ranges = File::open(entry.path())
+                .map_err(ExtentError::from)
+                .and_then(|file| self.reader.read_ranges(&file)?.collect());
+            return Some((entry.into_path(), ranges));
+        }
+    }
+}
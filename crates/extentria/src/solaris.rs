@@ -0,0 +1,70 @@
+use std::fs::File;
+
+use crate::{
+    capabilities::Method,
+    error::ExtentError,
+    fs_kind::{FsKind, RangeSource, filesystem_kind},
+    types::{RangeIter, RangeReaderImpl, private::Sealed, split, split_len, sync_data},
+    unix_seek,
+};
+
+/// Range reader for Solaris and illumos using SEEK_HOLE/SEEK_DATA, which originated there.
+#[derive(Debug, Default)]
+pub struct RangeReader {
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    /// Kind of filesystem the last file read was on.
+    kind: FsKind,
+}
+
+impl Sealed for RangeReader {}
+
+impl RangeReaderImpl for RangeReader {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    fn last_method(&self) -> Method {
+        Method::SeekHole
+    }
+
+    fn last_source(&self) -> RangeSource {
+        RangeSource::new(Method::SeekHole, self.kind)
+    }
+
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        self.kind = filesystem_kind(file).unwrap_or_default();
+        Ok(split(
+            Box::new(unix_seek::read_ranges_in(file, 0, u64::MAX)?),
+            self.split,
+        ))
+    }
+
+    fn read_ranges_in<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        self.kind = filesystem_kind(file).unwrap_or_default();
+        Ok(split(
+            Box::new(unix_seek::read_ranges_in(file, offset, length)?),
+            self.split,
+        ))
+    }
+}
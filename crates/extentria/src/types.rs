@@ -1,8 +1,13 @@
 use std::fs::File;
 use std::io;
 
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::fs_kind::{FsKind, RangeSource};
+use crate::owned::OwnedRanges;
+
 /// Iterator over data ranges returned by a RangeReader.
-pub type RangeIter<'a> = Box<dyn Iterator<Item = io::Result<DataRange>> + 'a>;
+pub type RangeIter<'a> = Box<dyn Iterator<Item = Result<DataRange, ExtentError>> + 'a>;
 
 pub(crate) mod private {
     /// Sealed trait marker to prevent external implementations of RangeReaderImpl.
@@ -39,16 +44,357 @@ pub trait RangeReaderImpl: std::fmt::Debug + Default + private::Sealed {
     /// Consume the reader and return its buffer for reuse.
     ///
     /// Returns `None` on platforms that don't use buffers, or if the buffer
-    /// is currently in use by an active iterator.
+    /// was lost to a failed lookup.
     fn into_buffer(self) -> Option<Box<[u8]>> {
         None
     }
 
+    /// Enable or disable caching which extent queries each filesystem supports.
+    ///
+    /// When a filesystem turns out not to support the native extent query, readers that
+    /// fall back to another method remember that per filesystem (by device ID), so later
+    /// files on it go straight to the fallback instead of failing a syscall first. This is
+    /// enabled by default, and does nothing on platforms without fallbacks.
+    fn set_support_cache(&mut self, enabled: bool) {
+        let _ = enabled;
+    }
+
+    /// Forget which extent queries filesystems support.
+    ///
+    /// Device IDs can be reused once a filesystem is unmounted, so long-lived readers should
+    /// clear the cache when mounts may have changed.
+    fn clear_support_cache(&mut self) {}
+
+    /// Enable or disable merging adjacent ranges that are stored the same way.
+    ///
+    /// Extent queries often give data written in many small pieces as many extents, which
+    /// callers mostly treat as one. With coalescing, ranges that follow on from each other
+    /// with the same flags are yielded as one (see [`DataRange::merge`]), so heavily
+    /// fragmented files give far fewer ranges. This is disabled by default, and does nothing
+    /// on platforms that only find ranges with SEEK_HOLE/SEEK_DATA (FreeBSD, illumos), as
+    /// those never follow on from each other the same way.
+    fn set_coalesce(&mut self, enabled: bool) {
+        let _ = enabled;
+    }
+
+    /// Cap data ranges at `max_len` bytes, or stop capping them with `None`.
+    ///
+    /// For callers that chunk data as they go: data ranges longer than `max_len` are
+    /// yielded in pieces of that length (and what's left), the pieces after the first marked
+    /// [`continued`](DataRange::continued), so the extent they came from can still be told
+    /// apart. Holes and unwritten ranges have no data to chunk, and are left whole. Splitting
+    /// comes after [coalescing](Self::set_coalesce). This is disabled by default, and a
+    /// `max_len` of zero disables it too.
+    fn split_at(&mut self, max_len: Option<u64>);
+
+    /// Enable or disable syncing each file before reading its ranges.
+    ///
+    /// Ranges of freshly written files can still be waiting for delayed allocation or
+    /// preallocated and unwritten, and get their place on disk at writeback; reading them
+    /// before then gives a layout that's about to change. Syncing first writes the data out:
+    /// with FIEMAP_FLAG_SYNC on Linux, FlushFileBuffers on Windows, and fdatasync elsewhere.
+    /// This is disabled by default, as it costs a flush per file. To sync for a single
+    /// query instead, see [`ReadOptions::sync`].
+    fn set_sync_first(&mut self, enabled: bool);
+
+    /// Read files with no extent query for runs of zeros, or stop with `None`.
+    ///
+    /// Where the filesystem can't say where holes are at all, readers take the whole file
+    /// as one data range ([`Method::WholeFile`]). With zero scanning, they read it instead,
+    /// and report runs of zeros at least `min_run` bytes long as holes
+    /// ([`Method::ZeroScan`]), so sparse files can still be restored sparse, at the cost of
+    /// a read pass. Zeros are found in 4 KiB blocks, so shorter runs are never holes. This
+    /// is disabled by default, and a `min_run` of zero disables it too. It does nothing on
+    /// platforms whose readers always have a query (macOS, FreeBSD, illumos, Windows).
+    fn set_zero_scan(&mut self, min_run: Option<u64>) {
+        let _ = min_run;
+    }
+
+    /// Enable or disable reading the generation each data range was last written in.
+    ///
+    /// For incremental backups: ranges get the [generation](DataRange::generation) of the
+    /// filesystem transaction that wrote them, so files whose ranges all have the same
+    /// generations as last time can be skipped. Only btrfs records generations, and only
+    /// privileged processes can read them (with CAP_SYS_ADMIN); elsewhere, and for data not
+    /// yet written out, ranges have none. This costs a search of the filesystem's tree per
+    /// file. It's disabled by default, and does nothing on platforms other than Linux.
+    fn set_generations(&mut self, enabled: bool) {
+        let _ = enabled;
+    }
+
+    /// Enable or disable sizing the reader's buffer to the files it reads.
+    ///
+    /// A buffer too small for a file's extents takes a query per bufferful, which adds up on
+    /// fragmented filesystems; one much larger than files need is memory for nothing. With
+    /// tuning, the buffer grows to fit the extents of the most fragmented file read lately,
+    /// from the next query on, and shrinks back as files with fewer extents are read. The
+    /// size given at creation is where it starts from, and a buffer given at creation is
+    /// dropped the first time it's resized. It's disabled by default, and does nothing on
+    /// platforms other than Linux.
+    fn set_buffer_tuning(&mut self, enabled: bool) {
+        let _ = enabled;
+    }
+
+    /// How ranges were found by the last call to [`read_ranges`](Self::read_ranges).
+    ///
+    /// Readers fall back to less precise methods when a filesystem doesn't support the
+    /// native query, down to treating the whole file as one data range; this tells which
+    /// one the last file got. Before any file is read, this is the method tried first.
+    fn last_method(&self) -> Method;
+
+    /// Where the ranges from the last call to [`read_ranges`](Self::read_ranges) came from,
+    /// and so whether their holes can be trusted.
+    ///
+    /// Network and FUSE filesystems answer extent queries however their server or daemon
+    /// does, and zero scanning and whole-file fallbacks don't find holes on disk at all;
+    /// only ranges from a local filesystem's own query are
+    /// [authoritative](RangeSource::is_authoritative). Readers that can't tell what kind of
+    /// filesystem a file is on take it as local.
+    fn last_source(&self) -> RangeSource {
+        RangeSource::new(self.last_method(), FsKind::Unknown)
+    }
+
     /// Read data ranges for a file.
     ///
     /// Returns an iterator that yields data ranges (including sparse holes)
     /// for the file. The iterator may lazily fetch data from the kernel.
-    fn read_ranges<'a>(&'a mut self, file: &'a File) -> io::Result<RangeIter<'a>>;
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError>;
+
+    /// Read data ranges for a file, with [options](ReadOptions) for the query.
+    ///
+    /// With the default options, this is [`read_ranges`](Self::read_ranges). Only FIEMAP
+    /// can map extended attributes, so asking for the [xattr tree](ReadOptions::xattr_tree)
+    /// fails with [`ExtentError::Unsupported`] on other platforms, and on filesystems that
+    /// can't map them. Where the query can't sync the file itself, it's synced first.
+    fn read_ranges_with<'a>(
+        &'a mut self,
+        file: &'a File,
+        options: ReadOptions,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        if options.xattr_tree {
+            return Err(ExtentError::unsupported_on(file));
+        }
+        if options.sync {
+            sync_data(file)?;
+        }
+        self.read_ranges(file)
+    }
+
+    /// Call `visit` with each data range of a file, in order.
+    ///
+    /// This gives the same ranges as [`read_ranges`](Self::read_ranges), without an
+    /// iterator to allocate or drive: on Linux, ranges are made straight from the extents in
+    /// the reader's buffer. For scanners going through many files that only look at each
+    /// range once. If reading fails partway, the ranges before have already been visited.
+    fn visit_ranges<F>(&mut self, file: &File, mut visit: F) -> Result<(), ExtentError>
+    where
+        F: FnMut(DataRange),
+    {
+        for range in self.read_ranges(file)? {
+            visit(range?);
+        }
+        Ok(())
+    }
+
+    /// Count the extents a file's data is stored in.
+    ///
+    /// Holes aren't counted. On Linux, FIEMAP is asked for just the count, so nothing is
+    /// read or allocated per extent; elsewhere, where FIEMAP isn't supported, and when
+    /// [coalescing](Self::set_coalesce) or [splitting](Self::split_at), this is the number
+    /// of data ranges [`read_ranges`](Self::read_ranges) gives.
+    fn extent_count(&mut self, file: &File) -> Result<u64, ExtentError> {
+        let mut count = 0;
+        self.visit_ranges(file, |range| count += u64::from(!range.hole))?;
+        Ok(count)
+    }
+
+    /// Add up a file's ranges in one pass, without holding them.
+    fn summarize(&mut self, file: &File) -> Result<RangeSummary, ExtentError> {
+        let mut summary = RangeSummary::default();
+        self.visit_ranges(file, |range| summary.add(&range))?;
+        Ok(summary)
+    }
+
+    /// Read data ranges for part of a file: `length` bytes from `offset`.
+    ///
+    /// Ranges are as [`read_ranges`](Self::read_ranges) gives them, cut to the window, which
+    /// is itself cut at the end of the file. Where the platform's query can be limited to a
+    /// window (FIEMAP, SEEK_DATA, FSCTL_QUERY_ALLOCATED_RANGES), only the window is asked
+    /// about, so probing a small part of a very large file stays cheap.
+    fn read_ranges_in<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        let end = offset.saturating_add(length);
+        Ok(Box::new(
+            self.read_ranges(file)?
+                .take_while(move |range| !matches!(range, Ok(range) if range.offset >= end))
+                .filter_map(move |range| range.map(|range| range.clip(offset, end)).transpose()),
+        ))
+    }
+
+    /// Read data ranges for a file, with an iterator that owns the reader and the file.
+    ///
+    /// Unlike [`read_ranges`](Self::read_ranges), the iterator borrows nothing, so it can be
+    /// kept or sent to another thread; see [`OwnedRanges`] for how it reads. Once done with
+    /// it, the reader can be had back with [`OwnedRanges::into_inner`].
+    fn into_ranges(self, file: File) -> Result<OwnedRanges<Self>, ExtentError>
+    where
+        Self: Sized,
+    {
+        OwnedRanges::new(self, file)
+    }
+
+    /// Read data ranges for many files, one after the other.
+    ///
+    /// Yields the ranges of each file in turn, or the error reading them, reusing the
+    /// reader's buffer throughout. Files are only queried as the iterator is advanced, so
+    /// this can be fed from a directory walk without holding all files open.
+    ///
+    /// Each file still costs a metadata call to get its size, as there is no batched stat
+    /// on the supported platforms, but nothing more is asked of the kernel for empty files.
+    fn read_ranges_batch<'a, I>(
+        &'a mut self,
+        files: I,
+    ) -> impl Iterator<Item = Result<Vec<DataRange>, ExtentError>> + 'a
+    where
+        I: IntoIterator<Item = &'a File>,
+        I::IntoIter: 'a,
+    {
+        files
+            .into_iter()
+            .map(|file| self.read_ranges(file)?.collect())
+    }
+}
+
+/// Write a file's data out before reading its ranges.
+///
+/// This is fdatasync on Unix, and FlushFileBuffers on Windows, which can only flush handles
+/// open for writing: others have nothing written through them to flush, so are left as is.
+pub(crate) fn sync_data(file: &File) -> io::Result<()> {
+    match file.sync_data() {
+        Err(e) if cfg!(windows) && e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
+}
+
+/// The metadata of a file to read the ranges of, which must be a regular file.
+pub(crate) fn regular_file_metadata(file: &File) -> Result<std::fs::Metadata, ExtentError> {
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(ExtentError::NotARegularFile);
+    }
+    Ok(meta)
+}
+
+/// Wrap ranges from a reader, merging them if it's coalescing.
+pub(crate) fn coalesced<'a, I>(ranges: I, coalesce: bool) -> RangeIter<'a>
+where
+    I: Iterator<Item = Result<DataRange, ExtentError>> + 'a,
+{
+    if coalesce {
+        Box::new(Coalesce::new(ranges))
+    } else {
+        Box::new(ranges)
+    }
+}
+
+/// Wrap ranges from a reader, capping data ranges at its split length, if it has one.
+pub(crate) fn split<'a>(ranges: RangeIter<'a>, max_len: Option<u64>) -> RangeIter<'a> {
+    match max_len {
+        Some(max_len) => Box::new(Split::new(ranges, max_len)),
+        None => ranges,
+    }
+}
+
+/// The split length to use for a requested `max_len`.
+pub(crate) fn split_len(max_len: Option<u64>) -> Option<u64> {
+    max_len.filter(|&max_len| max_len > 0)
+}
+
+/// Iterator capping data ranges at a maximum length.
+pub(crate) struct Split<I> {
+    inner: I,
+    max_len: u64,
+    /// What's left of the range being split.
+    rest: Option<DataRange>,
+}
+
+impl<I> Split<I> {
+    pub(crate) fn new(inner: I, max_len: u64) -> Self {
+        Self {
+            inner,
+            max_len,
+            rest: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<DataRange, ExtentError>>> Iterator for Split<I> {
+    type Item = Result<DataRange, ExtentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = match self.rest.take() {
+            Some(rest) => rest,
+            None => match self.inner.next()? {
+                Ok(range) => range,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+        let (piece, rest) = range.split_at(self.max_len);
+        self.rest = rest;
+        Some(Ok(piece))
+    }
+}
+
+/// Iterator merging adjacent ranges that are stored the same way.
+pub(crate) struct Coalesce<I> {
+    inner: I,
+    /// The range being added to, yielded once the next one can't be merged into it.
+    pending: Option<DataRange>,
+    /// An error that came while a range was pending, yielded after it.
+    error: Option<ExtentError>,
+}
+
+impl<I> Coalesce<I> {
+    pub(crate) fn new(inner: I) -> Self {
+        Self {
+            inner,
+            pending: None,
+            error: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<DataRange, ExtentError>>> Iterator for Coalesce<I> {
+    type Item = Result<DataRange, ExtentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+
+        loop {
+            match (self.inner.next(), self.pending.take()) {
+                (Some(Ok(range)), None) => self.pending = Some(range),
+                (Some(Ok(range)), Some(pending)) => match pending.merge(&range) {
+                    Some(merged) => self.pending = Some(merged),
+                    None => {
+                        self.pending = Some(range);
+                        return Some(Ok(pending));
+                    }
+                },
+                (Some(Err(err)), Some(pending)) => {
+                    self.error = Some(err);
+                    return Some(Ok(pending));
+                }
+                (Some(Err(err)), None) => return Some(Err(err)),
+                (None, pending) => return pending.map(Ok),
+            }
+        }
+    }
 }
 
 /// A contiguous range of data (or sparse hole) in a file.
@@ -60,6 +406,109 @@ pub struct DataRange {
     pub length: u64,
     /// This range is a sparse hole (no data stored, reads as zeros).
     pub hole: bool,
+    /// This range is allocated but unwritten (preallocated, reads as zeros).
+    ///
+    /// Only reported on platforms that can tell preallocated space apart from data.
+    pub unwritten: bool,
+    /// Byte offset of the range on the underlying device, if known.
+    ///
+    /// Only reported on platforms that can tell where data is on disk (FIEMAP on Linux,
+    /// retrieval pointers on Windows, F_LOG2PHYS_EXT on macOS), and never for holes or
+    /// [inline](RangeFlags::inline) ranges. Ranges with the
+    /// same physical offset in different files share storage (as with reflinks).
+    pub physical_offset: Option<u64>,
+    /// What else the extent query said about how the range is stored.
+    pub flags: RangeFlags,
+    /// This range is the rest of the one before it, cut off by
+    /// [`split_at`](RangeReaderImpl::split_at), so is part of the same extent.
+    pub continued: bool,
+    /// The filesystem transaction (generation) that last wrote the range's data, if known.
+    ///
+    /// Only reported for data on btrfs, by readers set to
+    /// [read generations](RangeReaderImpl::set_generations). Generations only go up, so a
+    /// range whose generation is the same as in an earlier backup hasn't been written since.
+    pub generation: Option<u64>,
+}
+
+/// How a range is stored, beyond whether it has data.
+///
+/// Only reported on platforms whose extent query says (FIEMAP on Linux), with some flags
+/// found otherwise on Windows and macOS; elsewhere, all flags are false.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RangeFlags {
+    /// The range's storage is shared with other files or snapshots (as with reflinks).
+    ///
+    /// On Windows, only ReFS counts references to clusters, so only it can say. On macOS,
+    /// APFS only says whether a file as a whole may share blocks with its clones, so this
+    /// is set on every data range of such files, even ranges since written over.
+    pub shared: bool,
+    /// The data is stored inline with the file's metadata rather than in its own blocks, as
+    /// btrfs does for small files.
+    ///
+    /// Inline ranges have no [physical offset](DataRange::physical_offset): the one the
+    /// extent query reports is where the metadata is, which is meaningless for the data
+    /// (and can be the same for many files), so it's never used to tell ranges apart.
+    pub inline: bool,
+    /// The data is encrypted by the filesystem.
+    pub encrypted: bool,
+    /// The data is encoded by the filesystem (compressed), so takes up a different amount
+    /// of space on disk than its length.
+    pub compressed: bool,
+    /// The data is waiting for delayed allocation, and isn't on disk yet.
+    pub delalloc: bool,
+    /// Where the data is on disk isn't known.
+    pub unknown_location: bool,
+}
+
+/// Options for an extent query, for [`RangeReaderImpl::read_ranges_with`].
+///
+/// These map to FIEMAP's request flags on Linux; elsewhere only [`sync`](Self::sync) does
+/// anything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ReadOptions {
+    /// Map the file's extended attribute storage instead of its data.
+    ///
+    /// Only filesystems that keep extended attributes in blocks of their own can map them
+    /// (ext4 does). Ranges are as the filesystem places the attributes, from offset zero,
+    /// with no holes filled in between.
+    pub xattr_tree: bool,
+    /// Write the file's dirty data out before querying, so ranges waiting for delayed
+    /// allocation have a place on disk.
+    pub sync: bool,
+    /// Ask the filesystem to keep the file's extent tree cached, for files that will be
+    /// queried again. This is only a hint, dropped where the filesystem doesn't take it.
+    pub cache: bool,
+}
+
+/// Totals over a file's ranges, from [`RangeReaderImpl::summarize`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeSummary {
+    /// Bytes in data ranges, including preallocated ones.
+    pub data_bytes: u64,
+    /// Bytes in sparse holes.
+    pub hole_bytes: u64,
+    /// Bytes in data ranges whose storage is shared with other files or snapshots.
+    pub shared_bytes: u64,
+    /// How many data ranges there are.
+    pub extent_count: u64,
+    /// Length of the longest data range.
+    pub largest_extent: u64,
+}
+
+impl RangeSummary {
+    /// Count a range in the totals.
+    pub fn add(&mut self, range: &DataRange) {
+        if range.hole {
+            self.hole_bytes += range.length;
+            return;
+        }
+        self.data_bytes += range.length;
+        if range.flags.shared {
+            self.shared_bytes += range.length;
+        }
+        self.extent_count += 1;
+        self.largest_extent = self.largest_extent.max(range.length);
+    }
 }
 
 impl DataRange {
@@ -69,6 +518,11 @@ impl DataRange {
             offset,
             length,
             hole: false,
+            unwritten: false,
+            physical_offset: None,
+            flags: RangeFlags::default(),
+            continued: false,
+            generation: None,
         }
     }
 
@@ -78,11 +532,229 @@ impl DataRange {
             offset,
             length,
             hole: true,
+            unwritten: false,
+            physical_offset: None,
+            flags: RangeFlags::default(),
+            continued: false,
+            generation: None,
+        }
+    }
+
+    /// Create an allocated but unwritten (preallocated) range.
+    pub fn unwritten(offset: u64, length: u64) -> Self {
+        Self {
+            offset,
+            length,
+            hole: false,
+            unwritten: true,
+            physical_offset: None,
+            flags: RangeFlags::default(),
+            continued: false,
+            generation: None,
         }
     }
 
+    /// Set where the range is on the underlying device.
+    pub fn with_physical_offset(self, physical_offset: u64) -> Self {
+        Self {
+            physical_offset: Some(physical_offset),
+            ..self
+        }
+    }
+
+    /// Set how the range is stored.
+    pub fn with_flags(self, flags: RangeFlags) -> Self {
+        Self { flags, ..self }
+    }
+
+    /// Set the generation that last wrote the range.
+    pub fn with_generation(self, generation: u64) -> Self {
+        Self {
+            generation: Some(generation),
+            ..self
+        }
+    }
+
+    /// Whether this range reads as zeros without any data stored for it.
+    pub fn is_zero(&self) -> bool {
+        self.hole || self.unwritten
+    }
+
     /// The end offset (exclusive) of this range.
     pub fn end(&self) -> u64 {
         self.offset + self.length
     }
+
+    /// The offset and length of this range.
+    pub fn to_tuple(&self) -> (u64, u64) {
+        (self.offset, self.length)
+    }
+
+    /// This range joined with `next`, if it follows on and is stored the same way.
+    ///
+    /// Ranges join when `next` starts where this one ends, and both are holes, unwritten,
+    /// or data alike, with the same [`flags`](Self::flags). The joined range keeps this
+    /// one's physical offset only if `next` also follows on from it on the device, and
+    /// the later of their generations if both have one.
+    pub fn merge(&self, next: &Self) -> Option<Self> {
+        if next.offset != self.end()
+            || next.hole != self.hole
+            || next.unwritten != self.unwritten
+            || next.flags != self.flags
+        {
+            return None;
+        }
+        let physical_offset = match (self.physical_offset, next.physical_offset) {
+            (Some(physical), Some(next_physical)) if physical + self.length == next_physical => {
+                Some(physical)
+            }
+            _ => None,
+        };
+        let generation = match (self.generation, next.generation) {
+            (Some(generation), Some(next_generation)) => Some(generation.max(next_generation)),
+            _ => None,
+        };
+        Some(Self {
+            length: self.length + next.length,
+            physical_offset,
+            generation,
+            ..*self
+        })
+    }
+
+    /// This range cut after `max_len` bytes, and the rest, marked
+    /// [`continued`](Self::continued), if there is any.
+    ///
+    /// Holes and unwritten ranges aren't cut, and neither is anything with a `max_len` of
+    /// zero. The physical offset of the rest is moved along with its start.
+    pub fn split_at(&self, max_len: u64) -> (Self, Option<Self>) {
+        if self.is_zero() || max_len == 0 || self.length <= max_len {
+            return (*self, None);
+        }
+        let rest = Self {
+            offset: self.offset + max_len,
+            length: self.length - max_len,
+            physical_offset: self.physical_offset.map(|physical| physical + max_len),
+            continued: true,
+            ..*self
+        };
+        (
+            Self {
+                length: max_len,
+                ..*self
+            },
+            Some(rest),
+        )
+    }
+
+    /// The part of this range between `start` and `end`, if any.
+    ///
+    /// The physical offset is moved along with the start of the range.
+    pub fn clip(&self, start: u64, end: u64) -> Option<Self> {
+        let offset = self.offset.max(start);
+        let range_end = self.end().min(end);
+        if offset >= range_end {
+            return None;
+        }
+        Some(Self {
+            offset,
+            length: range_end - offset,
+            physical_offset: self
+                .physical_offset
+                .map(|physical| physical + (offset - self.offset)),
+            ..*self
+        })
+    }
+}
+
+/// A data range from an offset and length.
+impl From<(u64, u64)> for DataRange {
+    fn from((offset, length): (u64, u64)) -> Self {
+        Self::new(offset, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_ranges_stored_alike() {
+        let shared = RangeFlags {
+            shared: true,
+            ..Default::default()
+        };
+        let ranges = [
+            DataRange::new(0, 4096).with_physical_offset(8192),
+            DataRange::new(4096, 4096).with_physical_offset(12288),
+            DataRange::new(8192, 4096).with_physical_offset(40960),
+            DataRange::hole(12288, 4096),
+            DataRange::hole(16384, 4096),
+            DataRange::new(20480, 4096).with_flags(shared),
+            DataRange::new(24576, 4096),
+        ];
+        let merged: Vec<_> = coalesced(ranges.into_iter().map(Ok), true)
+            .collect::<Result<_, ExtentError>>()
+            .unwrap();
+        assert_eq!(
+            merged,
+            [
+                DataRange::new(0, 12288),
+                DataRange::hole(12288, 8192),
+                DataRange::new(20480, 4096).with_flags(shared),
+                DataRange::new(24576, 4096),
+            ]
+        );
+        assert_eq!(
+            ranges[0].merge(&ranges[1]),
+            Some(DataRange::new(0, 8192).with_physical_offset(8192))
+        );
+
+        let unmerged: Vec<_> = coalesced(ranges.into_iter().map(Ok), false)
+            .collect::<Result<_, ExtentError>>()
+            .unwrap();
+        assert_eq!(unmerged, ranges);
+    }
+
+    #[test]
+    fn splits_data_ranges() {
+        let ranges = [
+            DataRange::new(0, 10).with_physical_offset(100),
+            DataRange::hole(10, 10),
+            DataRange::new(20, 4),
+            DataRange::unwritten(24, 10),
+        ];
+        let split: Vec<_> = Split::new(ranges.into_iter().map(Ok), 4)
+            .collect::<Result<_, ExtentError>>()
+            .unwrap();
+        let continued = |range: DataRange| DataRange {
+            continued: true,
+            ..range
+        };
+        assert_eq!(
+            split,
+            [
+                DataRange::new(0, 4).with_physical_offset(100),
+                continued(DataRange::new(4, 4).with_physical_offset(104)),
+                continued(DataRange::new(8, 2).with_physical_offset(108)),
+                DataRange::hole(10, 10),
+                DataRange::new(20, 4),
+                DataRange::unwritten(24, 10),
+            ]
+        );
+        assert_eq!(ranges[0].split_at(0), (ranges[0], None));
+    }
+
+    #[test]
+    fn coalescing_yields_ranges_before_errors() {
+        let ranges = [
+            Ok(DataRange::new(0, 4096)),
+            Ok(DataRange::new(4096, 4096)),
+            Err(io::Error::other("lost").into()),
+        ];
+        let mut merged = coalesced(ranges.into_iter(), true);
+        assert_eq!(merged.next().unwrap().unwrap(), DataRange::new(0, 8192));
+        assert!(merged.next().unwrap().is_err());
+        assert!(merged.next().is_none());
+    }
 }
@@ -1,26 +1,28 @@
 //! SEEK_HOLE/SEEK_DATA implementation for Unix systems.
 //!
 //! This module provides a shared implementation for platforms that support
-//! the SEEK_HOLE and SEEK_DATA lseek operations (macOS, FreeBSD, etc.).
+//! the SEEK_HOLE and SEEK_DATA lseek operations (macOS, FreeBSD, illumos, etc.).
 
 use std::fs::File;
 use std::io;
 use std::os::unix::io::AsRawFd;
 
-use crate::types::DataRange;
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::types::{DataRange, regular_file_metadata};
 
-/// Read data ranges using SEEK_HOLE and SEEK_DATA.
+/// Read data ranges using SEEK_HOLE and SEEK_DATA, for `length` bytes from `offset`.
 ///
-/// Returns an iterator of data ranges. Sparse holes are represented as
-/// `DataRange` with `flags.sparse = true`.
-pub fn read_ranges(file: &File) -> io::Result<SeekRangeIter> {
-    let file_size = file.metadata()?.len();
+/// Returns an iterator of data ranges, cut to the window, which is cut at the end of the
+/// file. Sparse holes are represented as `DataRange` with `hole = true`.
+pub fn read_ranges_in(file: &File, offset: u64, length: u64) -> Result<SeekRangeIter, ExtentError> {
+    let file_size = regular_file_metadata(file)?.len();
     let fd = file.as_raw_fd();
 
     Ok(SeekRangeIter {
         fd,
-        file_size,
-        current_pos: 0,
+        end: offset.saturating_add(length).min(file_size),
+        current_pos: offset,
         done: false,
     })
 }
@@ -28,16 +30,17 @@ pub fn read_ranges(file: &File) -> io::Result<SeekRangeIter> {
 /// Iterator over data ranges using SEEK_HOLE/SEEK_DATA.
 pub struct SeekRangeIter {
     fd: i32,
-    file_size: u64,
+    /// Where to stop: the end of the file, or of the window asked for.
+    end: u64,
     current_pos: u64,
     done: bool,
 }
 
 impl Iterator for SeekRangeIter {
-    type Item = io::Result<DataRange>;
+    type Item = Result<DataRange, ExtentError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.done || self.current_pos >= self.file_size {
+        if self.done || self.current_pos >= self.end {
             return None;
         }
 
@@ -46,18 +49,19 @@ impl Iterator for SeekRangeIter {
             Ok(pos) => pos,
             Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
                 // No more data - rest is sparse or we're at EOF
-                if self.current_pos < self.file_size {
-                    let hole = DataRange::hole(self.current_pos, self.file_size - self.current_pos);
+                if self.current_pos < self.end {
+                    let hole = DataRange::hole(self.current_pos, self.end - self.current_pos);
                     self.done = true;
                     return Some(Ok(hole));
                 }
                 return None;
             }
-            Err(e) => return Some(Err(e)),
+            Err(e) => return Some(self.fail(e)),
         };
 
         // If there's a hole before data, return it
         if data_start > self.current_pos {
+            let data_start = data_start.min(self.end);
             let hole = DataRange::hole(self.current_pos, data_start - self.current_pos);
             self.current_pos = data_start;
             return Some(Ok(hole));
@@ -68,10 +72,11 @@ impl Iterator for SeekRangeIter {
             Ok(pos) => pos,
             Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
                 // No hole found - data goes to end of file
-                self.file_size
+                self.end
             }
-            Err(e) => return Some(Err(e)),
+            Err(e) => return Some(self.fail(e)),
         };
+        let data_end = data_end.min(self.end);
 
         let range = DataRange::new(data_start, data_end - data_start);
         self.current_pos = data_end;
@@ -80,6 +85,26 @@ impl Iterator for SeekRangeIter {
     }
 }
 
+impl SeekRangeIter {
+    /// End on a failed seek: where the filesystem refuses it partway, as network
+    /// filesystems whose server doesn't support it can, the rest is taken as data.
+    fn fail(&mut self, err: io::Error) -> Result<DataRange, ExtentError> {
+        self.done = true;
+        // ENOTSUP and EOPNOTSUPP are the same on Linux, but not on BSDs
+        let refused = [libc::EOPNOTSUPP, libc::ENOTSUP, libc::ENOSYS];
+        if err
+            .raw_os_error()
+            .is_some_and(|errno| refused.contains(&errno))
+        {
+            return Ok(DataRange::new(
+                self.current_pos,
+                self.end - self.current_pos,
+            ));
+        }
+        Err(ExtentError::kernel(Method::SeekHole)(err))
+    }
+}
+
 /// Seek to the next data region at or after the given offset.
 pub fn seek_data(fd: i32, offset: u64) -> io::Result<u64> {
     let result = unsafe { libc::lseek(fd, offset as i64, libc::SEEK_DATA) };
@@ -0,0 +1,191 @@
+//! Checking a file's ranges against the space its filesystem says it takes.
+//!
+//! Extent queries can be wrong without failing: a filesystem that doesn't support SEEK_HOLE
+//! properly says a sparse file is all data, and network filesystems can pass on layouts
+//! from servers that make them up. The space a file takes (`st_blocks` on Unix) is counted
+//! separately, so [`validate_layout()`] compares the two, for callers that rely on ranges
+//! being right.
+
+use std::fs::File;
+
+use crate::capabilities::{Method, block_size};
+use crate::error::ExtentError;
+use crate::range_set::RangeSet;
+use crate::types::{DataRange, RangeReaderImpl as _};
+
+/// How a file's ranges compare to the space it takes, from [`validate_layout()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ValidationReport {
+    /// How the ranges were found.
+    pub method: Method,
+    /// Bytes in data ranges, including unwritten ones, in whole blocks.
+    pub range_bytes: u64,
+    /// Bytes the filesystem says the file takes: `st_blocks * 512` on Unix, and the
+    /// allocation size on Windows.
+    pub allocated_bytes: u64,
+    /// Bytes in data ranges that are compressed, so can take less space than their length.
+    pub compressed_bytes: u64,
+    /// Bytes in data ranges stored inline with the file's metadata, so take no blocks.
+    pub inline_bytes: u64,
+    /// Bytes in data ranges waiting for delayed allocation, which not all filesystems count
+    /// until they're written out.
+    pub delalloc_bytes: u64,
+}
+
+impl ValidationReport {
+    /// How many more bytes the file takes than its ranges account for; negative if fewer.
+    pub fn discrepancy(&self) -> i64 {
+        self.allocated_bytes as i64 - self.range_bytes as i64
+    }
+
+    /// How much of the discrepancy the ranges' flags don't explain.
+    ///
+    /// Compressed, inline and delayed data can take less space than its length, so that
+    /// much of a shortfall is explained. More space than the ranges can be metadata (as
+    /// ext4 extent tree blocks), or preallocation past the end of the file, neither of which
+    /// ranges show.
+    pub fn unexplained(&self) -> u64 {
+        match self.discrepancy() {
+            more if more >= 0 => more as u64,
+            less => less
+                .unsigned_abs()
+                .saturating_sub(self.compressed_bytes + self.inline_bytes + self.delalloc_bytes),
+        }
+    }
+
+    /// Whether the ranges account for the space the file takes, give or take `tolerance`
+    /// bytes of unexplained discrepancy.
+    pub fn is_consistent(&self, tolerance: u64) -> bool {
+        self.unexplained() <= tolerance
+    }
+}
+
+/// Read a file's ranges, and compare them to the space its filesystem says it takes.
+///
+/// Ranges are counted in whole blocks of the filesystem, as it allocates them. This fails
+/// with [`ExtentError::Unsupported`] on platforms with no count of the space files take.
+pub fn validate_layout(file: &File) -> Result<ValidationReport, ExtentError> {
+    let allocated_bytes = allocated_bytes(file)?;
+    let block = block_size(file)?.max(1);
+
+    let mut reader = crate::RangeReader::new();
+    let ranges: Vec<DataRange> = reader.read_ranges(file)?.collect::<Result<_, _>>()?;
+    let blocks: RangeSet = ranges
+        .iter()
+        .filter(|range| !range.hole)
+        .map(|range| {
+            range.offset / block * block..range.end().div_ceil(block).saturating_mul(block)
+        })
+        .collect();
+
+    let flagged = |flag: fn(&DataRange) -> bool| -> u64 {
+        ranges
+            .iter()
+            .filter(|range| !range.hole && flag(range))
+            .map(|range| range.length)
+            .sum()
+    };
+    Ok(ValidationReport {
+        method: reader.last_method(),
+        range_bytes: blocks.bytes(),
+        allocated_bytes,
+        compressed_bytes: flagged(|range| range.flags.compressed),
+        inline_bytes: flagged(|range| range.flags.inline),
+        delalloc_bytes: flagged(|range| range.flags.delalloc),
+    })
+}
+
+#[cfg(unix)]
+fn allocated_bytes(file: &File) -> Result<u64, ExtentError> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    Ok(file.metadata()?.blocks() * 512)
+}
+
+#[cfg(target_os = "windows")]
+fn allocated_bytes(file: &File) -> Result<u64, ExtentError> {
+    use std::io;
+    use std::os::windows::io::AsRawHandle as _;
+
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_STANDARD_INFO, FileStandardInfo, GetFileInformationByHandleEx,
+    };
+
+    let mut info = FILE_STANDARD_INFO::default();
+    // SAFETY: the handle is borrowed from a live File, and the buffer is valid for its size
+    let result = unsafe {
+        GetFileInformationByHandleEx(
+            file.as_raw_handle() as HANDLE,
+            FileStandardInfo,
+            (&raw mut info).cast(),
+            size_of::<FILE_STANDARD_INFO>() as u32,
+        )
+    };
+    if result == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(info.AllocationSize.max(0) as u64)
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+fn allocated_bytes(_file: &File) -> Result<u64, ExtentError> {
+    Err(ExtentError::unsupported())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek as _, SeekFrom, Write as _};
+
+    use super::*;
+
+    fn report(range_bytes: u64, allocated_bytes: u64) -> ValidationReport {
+        ValidationReport {
+            method: Method::Fiemap,
+            range_bytes,
+            allocated_bytes,
+            compressed_bytes: 0,
+            inline_bytes: 0,
+            delalloc_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn explains_discrepancies_by_flags() {
+        assert!(report(8192, 8192).is_consistent(0));
+
+        // A sparse file taken as all data
+        let lying = report(1 << 20, 8192);
+        assert_eq!(lying.discrepancy(), 8192 - (1 << 20));
+        assert!(!lying.is_consistent(4096));
+
+        let compressed = ValidationReport {
+            compressed_bytes: 1 << 20,
+            ..lying
+        };
+        assert!(compressed.is_consistent(0));
+
+        // Metadata blocks are only let through with some tolerance
+        let indexed = report(1 << 20, (1 << 20) + 4096);
+        assert_eq!(indexed.unexplained(), 4096);
+        assert!(!indexed.is_consistent(0) && indexed.is_consistent(4096));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn validates_a_sparse_file() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 10_000]).unwrap();
+        temp.seek(SeekFrom::Start(1 << 20)).unwrap();
+        temp.write_all(&[2; 10_000]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let report = validate_layout(temp.as_file()).unwrap();
+        assert!(report.allocated_bytes > 0);
+        if report.method != Method::WholeFile {
+            assert!(report.range_bytes < 1 << 20, "{report:?}");
+            assert!(report.is_consistent(64 * 1024), "{report:?}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::os::windows::io::AsRawHandle;
@@ -8,7 +9,14 @@ use windows_sys::Win32::System::Ioctl::{
     FILE_ALLOCATED_RANGE_BUFFER, FSCTL_QUERY_ALLOCATED_RANGES,
 };
 
-use crate::types::{DataRange, RangeIter, RangeReaderImpl, private::Sealed};
+use crate::capabilities::Method;
+use crate::error::ExtentError;
+use crate::fs_kind::{FsKind, RangeSource, handle_kind};
+use crate::retrieval::{ClusterMap, Volume, filesystem_name};
+use crate::types::{
+    DataRange, RangeIter, RangeReaderImpl, coalesced, private::Sealed, regular_file_metadata,
+    split, split_len, sync_data,
+};
 
 /// Minimum buffer size: enough for the input struct plus at least a few results.
 const MIN_BUFFER_SIZE: usize = std::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>() * 16;
@@ -18,10 +26,23 @@ const MIN_BUFFER_SIZE: usize = std::mem::size_of::<FILE_ALLOCATED_RANGE_BUFFER>(
 /// This implementation uses a raw byte buffer that can be reused across multiple
 /// file lookups to minimize allocations. Results are yielded lazily via an iterator
 /// that paginates through the kernel's results on demand.
+///
+/// Where the filesystem supports FSCTL_GET_RETRIEVAL_POINTERS (NTFS, FAT), data ranges are
+/// also split where they're fragmented on the volume and given their physical offsets,
+/// and data resident in NTFS's MFT is flagged inline. On ReFS, data in clusters that are
+/// shared with other files (cloned with FSCTL_DUPLICATE_EXTENTS_TO_FILE) is flagged shared.
 #[derive(Debug)]
 pub struct RangeReader {
     buffer: Option<Box<[u8]>>,
     buffer_size: usize,
+    /// Volumes by serial number, for their cluster sizes and block refcounting.
+    volumes: HashMap<u32, Volume>,
+    coalesce: bool,
+    /// Length to cap data ranges at, if any.
+    split: Option<u64>,
+    sync_first: bool,
+    /// Kind of filesystem the last file read was on.
+    kind: FsKind,
 }
 
 impl Sealed for RangeReader {}
@@ -41,6 +62,11 @@ impl RangeReaderImpl for RangeReader {
         Self {
             buffer: None,
             buffer_size: size,
+            volumes: HashMap::new(),
+            coalesce: false,
+            split: None,
+            sync_first: false,
+            kind: FsKind::Unknown,
         }
     }
 
@@ -54,6 +80,11 @@ impl RangeReaderImpl for RangeReader {
         Self {
             buffer: Some(buf),
             buffer_size,
+            volumes: HashMap::new(),
+            coalesce: false,
+            split: None,
+            sync_first: false,
+            kind: FsKind::Unknown,
         }
     }
 
@@ -65,6 +96,33 @@ impl RangeReaderImpl for RangeReader {
         self.buffer
     }
 
+    /// Forget what's known of volumes, as their serial numbers can be reused.
+    fn clear_support_cache(&mut self) {
+        self.volumes.clear();
+    }
+
+    /// Merge adjacent ranges, undoing the split where data is fragmented on the volume.
+    fn set_coalesce(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
+
+    fn split_at(&mut self, max_len: Option<u64>) {
+        self.split = split_len(max_len);
+    }
+
+    /// Flush each file with FlushFileBuffers before querying it.
+    fn set_sync_first(&mut self, enabled: bool) {
+        self.sync_first = enabled;
+    }
+
+    fn last_method(&self) -> Method {
+        Method::AllocatedRanges
+    }
+
+    fn last_source(&self) -> RangeSource {
+        RangeSource::new(Method::AllocatedRanges, self.kind)
+    }
+
     /// Read data ranges for a file.
     ///
     /// Returns an iterator that lazily fetches extent information from the kernel.
@@ -73,9 +131,55 @@ impl RangeReaderImpl for RangeReader {
     ///
     /// When the iterator is dropped or fully consumed, the buffer is returned to
     /// this `RangeReader` for reuse in subsequent calls.
-    fn read_ranges<'a>(&'a mut self, file: &'a File) -> io::Result<RangeIter<'a>> {
-        let file_size = file.metadata()?.len();
+    fn read_ranges<'a>(&'a mut self, file: &'a File) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        let (coalesce, max_len) = (self.coalesce, self.split);
+        Ok(split(
+            coalesced(self.ranges(file, 0, u64::MAX)?, coalesce),
+            max_len,
+        ))
+    }
+
+    /// Read data ranges for part of a file, asking for allocated ranges in only that part.
+    fn read_ranges_in<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<RangeIter<'a>, ExtentError> {
+        if self.sync_first {
+            sync_data(file)?;
+        }
+        let (coalesce, max_len) = (self.coalesce, self.split);
+        Ok(split(
+            coalesced(self.ranges(file, offset, length)?, coalesce),
+            max_len,
+        ))
+    }
+}
+
+impl RangeReader {
+    /// Read data ranges for `length` bytes of a file from `offset`.
+    fn ranges<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        length: u64,
+    ) -> Result<WindowsRangeIter<'a>, ExtentError> {
+        let end = offset
+            .saturating_add(length)
+            .min(regular_file_metadata(file)?.len());
         let handle = file.as_raw_handle() as HANDLE;
+        self.kind = handle_kind(handle).unwrap_or_default();
+
+        // Physical locations are best-effort: without them, ranges are as allocated
+        let clusters = if offset < end {
+            ClusterMap::query(handle, &mut self.volumes).ok()
+        } else {
+            None
+        };
 
         // Take ownership of the buffer, or allocate a new one
         let buffer = self
@@ -83,19 +187,20 @@ impl RangeReaderImpl for RangeReader {
             .take()
             .unwrap_or_else(|| vec![0u8; self.buffer_size].into_boxed_slice());
 
-        Ok(Box::new(WindowsRangeIter {
+        Ok(WindowsRangeIter {
             handle,
-            file_size,
+            end,
             buffer: Some(buffer),
             buffer_return: &mut self.buffer,
-            query_offset: 0,
-            current_pos: 0,
+            clusters,
+            query_offset: offset,
+            current_pos: offset,
             buf_index: 0,
             items_in_buffer: 0,
-            pending_data: None,
+            queue: VecDeque::new(),
             done: false,
             needs_fetch: true,
-        }))
+        })
     }
 }
 
@@ -112,14 +217,17 @@ impl Default for RangeReader {
 /// detecting gaps between allocated ranges.
 pub struct WindowsRangeIter<'a> {
     handle: HANDLE,
-    file_size: u64,
+    /// Where to stop: the end of the file, or of the window asked for.
+    end: u64,
     buffer: Option<Box<[u8]>>,
     buffer_return: &'a mut Option<Box<[u8]>>,
+    clusters: Option<ClusterMap>,
     query_offset: u64,
     current_pos: u64,
     buf_index: usize,
     items_in_buffer: usize,
-    pending_data: Option<DataRange>,
+    /// Ranges to return before querying further.
+    queue: VecDeque<DataRange>,
     done: bool,
     needs_fetch: bool,
 }
@@ -131,7 +239,7 @@ impl WindowsRangeIter<'_> {
     /// or `Err` on failure.
     fn fetch_page(&mut self) -> io::Result<bool> {
         // Empty files or already past end - no more data
-        if self.file_size == 0 || self.query_offset >= self.file_size {
+        if self.query_offset >= self.end {
             return Ok(false);
         }
 
@@ -142,7 +250,7 @@ impl WindowsRangeIter<'_> {
 
         let input = FILE_ALLOCATED_RANGE_BUFFER {
             FileOffset: self.query_offset as i64,
-            Length: (self.file_size - self.query_offset) as i64,
+            Length: (self.end - self.query_offset) as i64,
         };
 
         let mut bytes_returned: u32 = 0;
@@ -199,20 +307,20 @@ impl WindowsRangeIter<'_> {
         let range_offset = entry.FileOffset as u64;
         let mut range_length = entry.Length as u64;
 
-        // Clamp length to not exceed file size (Windows returns allocated ranges
+        // Clamp length to the end of the window (Windows returns allocated ranges
         // which may extend beyond the logical file size due to cluster alignment)
-        if range_offset + range_length > self.file_size {
-            range_length = self.file_size.saturating_sub(range_offset);
+        if range_offset + range_length > self.end {
+            range_length = self.end.saturating_sub(range_offset);
         }
 
         Some((range_offset, range_length))
     }
 
     /// Handle the end of iteration, returning trailing sparse hole if needed.
-    fn handle_end(&mut self) -> Option<io::Result<DataRange>> {
-        if self.current_pos < self.file_size {
-            let hole = DataRange::hole(self.current_pos, self.file_size - self.current_pos);
-            self.current_pos = self.file_size;
+    fn handle_end(&mut self) -> Option<Result<DataRange, ExtentError>> {
+        if self.current_pos < self.end {
+            let hole = DataRange::hole(self.current_pos, self.end - self.current_pos);
+            self.current_pos = self.end;
             self.done = true;
             Some(Ok(hole))
         } else {
@@ -223,11 +331,11 @@ impl WindowsRangeIter<'_> {
 }
 
 impl Iterator for WindowsRangeIter<'_> {
-    type Item = io::Result<DataRange>;
+    type Item = Result<DataRange, ExtentError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // Return pending data range first (happens after returning a sparse hole)
-        if let Some(range) = self.pending_data.take() {
+        // Return queued ranges first (data after a sparse hole, or fragments of a range)
+        if let Some(range) = self.queue.pop_front() {
             return Some(Ok(range));
         }
 
@@ -236,8 +344,8 @@ impl Iterator for WindowsRangeIter<'_> {
             return None;
         }
 
-        // Handle empty files
-        if self.file_size == 0 {
+        // Handle empty files and windows
+        if self.current_pos >= self.end {
             self.done = true;
             return None;
         }
@@ -254,7 +362,14 @@ impl Iterator for WindowsRangeIter<'_> {
                 }
                 Err(e) => {
                     self.done = true;
-                    return Some(Err(e));
+                    // ERROR_INVALID_FUNCTION or ERROR_NOT_SUPPORTED: the file system has no
+                    // sparse files, as FAT doesn't
+                    if matches!(e.raw_os_error(), Some(1) | Some(50)) {
+                        let fs = filesystem_name(self.handle).ok();
+                        let kind = handle_kind(self.handle).unwrap_or_default();
+                        return Some(Err(ExtentError::Unsupported { fs, kind }));
+                    }
+                    return Some(Err(ExtentError::kernel(Method::AllocatedRanges)(e)));
                 }
             }
         }
@@ -271,18 +386,27 @@ impl Iterator for WindowsRangeIter<'_> {
         // Update query_offset for next page (if we need one)
         self.query_offset = offset + length;
 
+        // The first range can start before the window
+        let skip = self.current_pos.saturating_sub(offset).min(length);
+        let (offset, length) = (offset + skip, length - skip);
+        if length == 0 {
+            return self.next();
+        }
+
         // Check for sparse hole before this range
         if offset > self.current_pos {
-            let hole = DataRange::hole(self.current_pos, offset - self.current_pos);
-            // Store the data range to return on next iteration
-            self.pending_data = Some(DataRange::new(offset, length));
-            self.current_pos = offset + length;
-            return Some(Ok(hole));
+            self.queue
+                .push_back(DataRange::hole(self.current_pos, offset - self.current_pos));
         }
 
-        // Return this extent as a data range
+        // Then this extent as data, in fragments if its location is known
+        let range = DataRange::new(offset, length);
+        match &self.clusters {
+            Some(clusters) => clusters.split(range, &mut self.queue),
+            None => self.queue.push_back(range),
+        }
         self.current_pos = offset + length;
-        Some(Ok(DataRange::new(offset, length)))
+        self.queue.pop_front().map(Ok)
     }
 }
 
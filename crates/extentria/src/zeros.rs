@@ -0,0 +1,189 @@
+//! Finding holes by reading, for filesystems that can't say where they are.
+//!
+//! Where there's no extent query at all, readers take the whole file as data. With
+//! [zero scanning](crate::RangeReaderImpl::set_zero_scan) on, they read it instead, and
+//! report long enough runs of zeros as holes, as `cp --sparse=always` does: what a backup
+//! restores sparse is then what reads as zeros, whether or not it was a hole on disk.
+
+use std::fs::File;
+use std::io;
+
+use crate::copy::read_exact_at;
+use crate::error::ExtentError;
+use crate::types::DataRange;
+
+/// Size of the blocks files are checked in: runs of zeros are whole blocks, from offsets
+/// that are multiples of this.
+const BLOCK: u64 = 4096;
+
+/// How much of the file is read at a time.
+const CHUNK: usize = 256 * 1024;
+
+/// Iterator over a file's ranges as found by reading it, with runs of zeros as holes.
+pub(crate) struct ZeroScan<'a> {
+    file: &'a File,
+    /// Next byte to check.
+    pos: u64,
+    /// Where to stop: the end of the file, or of the window asked for.
+    end: u64,
+    /// Shortest run of zeros to report as a hole.
+    min_run: u64,
+    buf: Vec<u8>,
+    /// Offset in the file of the start of the buffer.
+    buf_start: u64,
+    /// Start of the data not yet yielded, if any.
+    data: Option<u64>,
+    /// Start of the run of zeros being read, if any.
+    zeros: Option<u64>,
+    /// A hole to yield after the data before it.
+    pending: Option<DataRange>,
+}
+
+impl<'a> ZeroScan<'a> {
+    /// Scan bytes `start..end` of a file, for runs of zeros at least `min_run` long.
+    pub(crate) fn new(file: &'a File, start: u64, end: u64, min_run: u64) -> Self {
+        Self {
+            file,
+            pos: start,
+            end,
+            min_run,
+            buf: Vec::new(),
+            buf_start: start,
+            data: None,
+            zeros: None,
+            pending: None,
+        }
+    }
+
+    /// Whether the block at the current position is all zeros, and where it ends.
+    fn block(&mut self) -> io::Result<(bool, u64)> {
+        let block_end = ((self.pos / BLOCK + 1) * BLOCK).min(self.end);
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if self.pos < self.buf_start || block_end > buf_end {
+            // Refill up to a block boundary, so blocks are never split between reads
+            let read_end = ((self.pos + CHUNK as u64) / BLOCK * BLOCK).min(self.end);
+            self.buf.resize((read_end - self.pos) as usize, 0);
+            read_exact_at(self.file, &mut self.buf, self.pos)?;
+            self.buf_start = self.pos;
+        }
+        let block =
+            &self.buf[(self.pos - self.buf_start) as usize..][..(block_end - self.pos) as usize];
+        Ok((block.iter().all(|&byte| byte == 0), block_end))
+    }
+
+    /// Yield what's left once the end is reached.
+    fn finish(&mut self) -> Option<DataRange> {
+        let (data, zeros) = (self.data.take(), self.zeros.take());
+        match zeros {
+            Some(zeros) if self.end - zeros >= self.min_run => {
+                let hole = DataRange::hole(zeros, self.end - zeros);
+                match data {
+                    Some(data) => {
+                        self.pending = Some(hole);
+                        Some(DataRange::new(data, zeros - data))
+                    }
+                    None => Some(hole),
+                }
+            }
+            _ => {
+                let start = data.or(zeros)?;
+                Some(DataRange::new(start, self.end - start))
+            }
+        }
+    }
+}
+
+impl Iterator for ZeroScan<'_> {
+    type Item = Result<DataRange, ExtentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(hole) = self.pending.take() {
+            return Some(Ok(hole));
+        }
+
+        while self.pos < self.end {
+            let (zero, block_end) = match self.block() {
+                Ok(block) => block,
+                Err(err) => {
+                    self.pos = self.end;
+                    return Some(Err(err.into()));
+                }
+            };
+            let pos = std::mem::replace(&mut self.pos, block_end);
+
+            if zero {
+                let zeros = *self.zeros.get_or_insert(pos);
+                // Once the run is long enough to be a hole, the data before it is done
+                if block_end - zeros >= self.min_run
+                    && let Some(data) = self.data.take()
+                {
+                    return Some(Ok(DataRange::new(data, zeros - data)));
+                }
+                continue;
+            }
+
+            if let Some(zeros) = self.zeros.take() {
+                if pos - zeros >= self.min_run {
+                    self.data = Some(pos);
+                    return Some(Ok(DataRange::hole(zeros, pos - zeros)));
+                }
+                // Too short to be a hole: the zeros are part of the data
+                self.data.get_or_insert(zeros);
+            }
+            self.data.get_or_insert(pos);
+        }
+        self.finish().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    fn scan(contents: &[u8], start: u64, end: u64, min_run: u64) -> Vec<DataRange> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        ZeroScan::new(&file, start, end, min_run)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn reports_long_zero_runs_as_holes() {
+        let block = BLOCK as usize;
+        let mut contents = vec![1; block];
+        contents.extend(vec![0; 3 * block]);
+        contents.extend(vec![2; block]);
+        contents.extend(vec![0; block]);
+        contents.extend(vec![3; block]);
+        contents.extend(vec![0; 2 * block + 100]);
+        let len = contents.len() as u64;
+
+        assert_eq!(
+            scan(&contents, 0, len, 2 * BLOCK),
+            [
+                DataRange::new(0, BLOCK),
+                DataRange::hole(BLOCK, 3 * BLOCK),
+                // The short run of zeros is left in the data
+                DataRange::new(4 * BLOCK, 3 * BLOCK),
+                DataRange::hole(7 * BLOCK, len - 7 * BLOCK),
+            ]
+        );
+        assert_eq!(scan(&contents, 0, len, 8 * BLOCK), [DataRange::new(0, len)]);
+        // Windows start mid-block, and end where they're asked to
+        assert_eq!(
+            scan(&contents, 100, 3 * BLOCK, BLOCK),
+            [
+                DataRange::new(100, BLOCK - 100),
+                DataRange::hole(BLOCK, 2 * BLOCK)
+            ]
+        );
+        assert_eq!(
+            scan(&vec![0; 3 * block], 0, 3 * BLOCK, BLOCK),
+            [DataRange::hole(0, 3 * BLOCK)]
+        );
+        assert!(scan(&[], 0, 0, BLOCK).is_empty());
+    }
+}
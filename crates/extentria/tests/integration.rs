@@ -4,32 +4,12 @@
 //! across different platforms and file types.
 
 use std::fs::{self, File};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{Seek, SeekFrom, Write};
 
-use extentria::{RangeReader, RangeReaderImpl, ranges_for_file};
-
-/// Helper to check if an error indicates unsupported filesystem.
-fn is_unsupported_error(err: &io::Error) -> bool {
-    #[cfg(unix)]
-    {
-        // EOPNOTSUPP = 95, ENOTTY = 25, EINVAL = 22 on Linux
-        // EINVAL can happen on some filesystems that don't properly support FIEMAP
-        matches!(
-            err.raw_os_error(),
-            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL)
-        )
-    }
-    #[cfg(windows)]
-    {
-        // ERROR_NOT_SUPPORTED = 50, ERROR_INVALID_FUNCTION = 1
-        matches!(err.raw_os_error(), Some(50) | Some(1))
-    }
-    #[cfg(not(any(unix, windows)))]
-    {
-        let _ = err;
-        false
-    }
-}
+use extentria::{
+    DataRange, ExtentError, Method, RangeReader, RangeReaderImpl, RangeSummary, ranges_for_file,
+    ranges_for_files,
+};
 
 #[test]
 fn test_empty_file_returns_no_ranges() {
@@ -45,7 +25,7 @@ fn test_empty_file_returns_no_ranges() {
                 ranges
             );
         }
-        Err(e) if is_unsupported_error(&e) => {
+        Err(ExtentError::Unsupported { .. }) => {
             eprintln!("Skipping: filesystem doesn't support extent queries");
         }
         Err(e) => panic!("Unexpected error: {e}"),
@@ -81,12 +61,11 @@ fn test_regular_file_returns_single_range() {
             for range in &ranges {
                 assert!(
                     !range.hole,
-                    "Regular file should not have sparse ranges: {:?}",
-                    range
+                    "Regular file should not have sparse ranges: {range}"
                 );
             }
         }
-        Err(e) if is_unsupported_error(&e) => {
+        Err(ExtentError::Unsupported { .. }) => {
             eprintln!("Skipping: filesystem doesn't support extent queries");
         }
         Err(e) => panic!("Unexpected error: {e}"),
@@ -129,7 +108,7 @@ fn test_larger_file_coverage() {
                 "Ranges should cover entire file"
             );
         }
-        Err(e) if is_unsupported_error(&e) => {
+        Err(ExtentError::Unsupported { .. }) => {
             eprintln!("Skipping: filesystem doesn't support extent queries");
         }
         Err(e) => panic!("Unexpected error: {e}"),
@@ -183,7 +162,7 @@ fn test_sparse_file_detection() {
                 expected_offset = range.offset + range.length;
             }
         }
-        Err(e) if is_unsupported_error(&e) => {
+        Err(ExtentError::Unsupported { .. }) => {
             eprintln!("Skipping: filesystem doesn't support extent queries");
         }
         Err(e) => panic!("Unexpected error: {e}"),
@@ -243,7 +222,7 @@ fn test_sparse_file_with_multiple_holes() {
             // We expect some data ranges at minimum
             assert!(data_count >= 1, "Should have at least one data range");
         }
-        Err(e) if is_unsupported_error(&e) => {
+        Err(ExtentError::Unsupported { .. }) => {
             eprintln!("Skipping: filesystem doesn't support extent queries");
         }
         Err(e) => panic!("Unexpected error: {e}"),
@@ -266,7 +245,7 @@ fn test_range_reader_reuse_across_files() {
                 let ranges1: Vec<_> = iter.collect::<Result<Vec<_>, _>>().unwrap();
                 assert!(!ranges1.is_empty());
             }
-            Err(e) if is_unsupported_error(&e) => {
+            Err(ExtentError::Unsupported { .. }) => {
                 eprintln!("Skipping: filesystem doesn't support extent queries");
                 return;
             }
@@ -309,6 +288,352 @@ fn test_range_reader_reuse_across_files() {
     }
 }
 
+#[test]
+fn test_range_reader_batch() {
+    let sizes = [18, 0, 100_000, 5];
+    let temps: Vec<_> = sizes
+        .iter()
+        .map(|&size| {
+            let mut temp = tempfile::NamedTempFile::new().unwrap();
+            temp.write_all(&vec![7; size]).unwrap();
+            temp.flush().unwrap();
+            temp
+        })
+        .collect();
+
+    let mut reader = RangeReader::with_buffer_size(4096);
+    let results: Vec<_> = reader
+        .read_ranges_batch(temps.iter().map(|temp| temp.as_file()))
+        .collect();
+    assert_eq!(results.len(), sizes.len());
+
+    for ((temp, size), result) in temps.iter().zip(sizes).zip(results) {
+        let ranges = match result {
+            Ok(ranges) => ranges,
+            Err(ExtentError::Unsupported { .. }) => {
+                eprintln!("Skipping: filesystem doesn't support extent queries");
+                return;
+            }
+            Err(e) => panic!("Unexpected error: {e}"),
+        };
+        assert_eq!(ranges, ranges_for_file(temp.as_file()).unwrap());
+        if size == 0 {
+            assert!(ranges.is_empty());
+        } else {
+            let total_len: u64 = ranges.iter().map(|r| r.length).sum();
+            assert!(total_len >= size as u64);
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_visit_ranges_matches_read_ranges() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    let data = vec![0xABu8; 64 * 1024];
+    file.write_all(&data).unwrap();
+    file.seek(SeekFrom::Current(64 * 1024)).unwrap();
+    file.write_all(&data).unwrap();
+    file.flush().unwrap();
+
+    let mut reader = RangeReader::new();
+    let mut visited = Vec::new();
+    match reader.visit_ranges(temp.as_file(), |range| visited.push(range)) {
+        Ok(()) => {}
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    }
+    assert_eq!(visited, ranges_for_file(temp.as_file()).unwrap());
+
+    // The reader can be reused after visiting, and empty files visit nothing
+    let empty = tempfile::NamedTempFile::new().unwrap();
+    let mut count = 0;
+    reader
+        .visit_ranges(empty.as_file(), |_| count += 1)
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extent_count() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    let data = vec![0xABu8; 64 * 1024];
+    file.write_all(&data).unwrap();
+    file.seek(SeekFrom::Current(64 * 1024)).unwrap();
+    file.write_all(&data).unwrap();
+    file.sync_all().unwrap();
+
+    let mut reader = RangeReader::new();
+    let count = match reader.extent_count(temp.as_file()) {
+        Ok(count) => count,
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    };
+    let ranges = ranges_for_file(temp.as_file()).unwrap();
+    assert_eq!(count, ranges.iter().filter(|r| !r.hole).count() as u64);
+    assert!(count >= 1);
+
+    let empty = tempfile::NamedTempFile::new().unwrap();
+    assert_eq!(reader.extent_count(empty.as_file()).unwrap(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_summarize() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    file.write_all(&vec![1u8; 64 * 1024]).unwrap();
+    file.seek(SeekFrom::Current(256 * 1024)).unwrap();
+    file.write_all(&vec![2u8; 128 * 1024]).unwrap();
+    file.sync_all().unwrap();
+
+    let mut reader = RangeReader::new();
+    let summary = match reader.summarize(temp.as_file()) {
+        Ok(summary) => summary,
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    };
+    let ranges = ranges_for_file(temp.as_file()).unwrap();
+    let mut expected = RangeSummary::default();
+    for range in &ranges {
+        expected.add(range);
+    }
+    assert_eq!(summary, expected);
+    assert_eq!(summary.data_bytes + summary.hole_bytes, 448 * 1024);
+    assert!(summary.largest_extent <= summary.data_bytes);
+    assert!(summary.shared_bytes <= summary.data_bytes);
+    if reader.last_method() != extentria::Method::WholeFile {
+        assert!(summary.hole_bytes >= 256 * 1024 - 4096);
+    }
+}
+
+#[test]
+fn test_coalesce() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    // Written and synced piece by piece, so the filesystem may store it in many extents
+    for piece in 0..32u8 {
+        file.write_all(&vec![piece; 16 * 1024]).unwrap();
+        file.sync_data().unwrap();
+    }
+    file.seek(SeekFrom::Current(256 * 1024)).unwrap();
+    file.write_all(&vec![1u8; 16 * 1024]).unwrap();
+    file.sync_all().unwrap();
+
+    let mut reader = RangeReader::new();
+    let ranges: Vec<DataRange> = match reader.read_ranges(temp.as_file()) {
+        Ok(iter) => iter.collect::<Result<_, ExtentError>>().unwrap(),
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    };
+    reader.set_coalesce(true);
+    let merged: Vec<DataRange> = reader
+        .read_ranges(temp.as_file())
+        .unwrap()
+        .collect::<Result<_, ExtentError>>()
+        .unwrap();
+
+    assert!(merged.len() <= ranges.len());
+    assert_eq!(
+        merged.first().map(|r| r.offset),
+        ranges.first().map(|r| r.offset)
+    );
+    assert_eq!(
+        merged.last().map(DataRange::end),
+        ranges.last().map(DataRange::end)
+    );
+    for pair in merged.windows(2) {
+        assert_eq!(pair[0].end(), pair[1].offset, "{merged:?}");
+        assert_eq!(pair[0].merge(&pair[1]), None, "{merged:?}");
+    }
+
+    let mut visited = Vec::new();
+    reader
+        .visit_ranges(temp.as_file(), |range| visited.push(range))
+        .unwrap();
+    assert_eq!(visited, merged);
+    let data_ranges = merged.iter().filter(|r| !r.hole).count() as u64;
+    assert_eq!(reader.extent_count(temp.as_file()).unwrap(), data_ranges);
+}
+
+#[test]
+fn test_split_at() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    file.write_all(&vec![1u8; 1024 * 1024]).unwrap();
+    file.seek(SeekFrom::Current(1024 * 1024)).unwrap();
+    file.write_all(&vec![2u8; 300 * 1024]).unwrap();
+    file.sync_all().unwrap();
+
+    let mut reader = RangeReader::new();
+    let ranges: Vec<DataRange> = match reader.read_ranges(temp.as_file()) {
+        Ok(iter) => iter.collect::<Result<_, ExtentError>>().unwrap(),
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    };
+    let max_len = 128 * 1024;
+    reader.split_at(Some(max_len));
+    let split: Vec<DataRange> = reader
+        .read_ranges(temp.as_file())
+        .unwrap()
+        .collect::<Result<_, ExtentError>>()
+        .unwrap();
+
+    for range in split.iter().filter(|r| !r.is_zero()) {
+        assert!(range.length <= max_len, "{split:?}");
+    }
+    for pair in split.windows(2) {
+        assert_eq!(pair[0].end(), pair[1].offset, "{split:?}");
+    }
+    // Putting the pieces back together gives the ranges as they were
+    let mut joined: Vec<DataRange> = Vec::new();
+    for range in &split {
+        match joined.last_mut() {
+            Some(last) if range.continued => last.length += range.length,
+            _ => joined.push(DataRange {
+                continued: false,
+                generation: None,
+                ..*range
+            }),
+        }
+    }
+    assert_eq!(joined, ranges);
+
+    let mut visited = Vec::new();
+    reader
+        .visit_ranges(temp.as_file(), |range| visited.push(range))
+        .unwrap();
+    assert_eq!(visited, split);
+    let data_ranges = split.iter().filter(|r| !r.hole).count() as u64;
+    assert_eq!(reader.extent_count(temp.as_file()).unwrap(), data_ranges);
+
+    reader.split_at(None);
+    let unsplit: Vec<DataRange> = reader
+        .read_ranges(temp.as_file())
+        .unwrap()
+        .collect::<Result<_, ExtentError>>()
+        .unwrap();
+    assert_eq!(unsplit, ranges);
+}
+
+#[test]
+fn test_sync_first() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    file.write_all(&vec![1u8; 256 * 1024]).unwrap();
+
+    // Written but not synced, so possibly waiting for delayed allocation
+    let mut reader = RangeReader::new();
+    reader.set_sync_first(true);
+    let ranges: Vec<DataRange> = match reader.read_ranges(&file) {
+        Ok(iter) => iter.collect::<Result<_, ExtentError>>().unwrap(),
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    };
+    assert_eq!(ranges.iter().map(|r| r.length).sum::<u64>(), 256 * 1024);
+    for range in &ranges {
+        assert!(!range.flags.delalloc, "{ranges:?}");
+        assert!(!range.flags.unknown_location, "{ranges:?}");
+    }
+    assert!(reader.extent_count(&file).unwrap() >= 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_read_ranges_in_window() {
+    let temp = tempfile::NamedTempFile::new().unwrap();
+    let mut file = temp.reopen().unwrap();
+    let chunk = 64 * 1024u64;
+    let data = vec![0xABu8; chunk as usize];
+    file.write_all(&data).unwrap();
+    file.seek(SeekFrom::Current(chunk as i64)).unwrap();
+    file.write_all(&data).unwrap();
+    file.flush().unwrap();
+
+    let whole = match ranges_for_file(temp.as_file()) {
+        Ok(ranges) => ranges,
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+            return;
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    };
+
+    let mut reader = RangeReader::new();
+    for (offset, length) in [
+        (0, 3 * chunk),
+        (chunk / 2, chunk),
+        (chunk + 10, 10),
+        (2 * chunk, u64::MAX),
+        (3 * chunk, 100),
+        (0, 0),
+    ] {
+        let end = offset.saturating_add(length);
+        let expected: Vec<_> = whole
+            .iter()
+            .filter_map(|range| range.clip(offset, end))
+            .collect();
+        let windowed = reader
+            .read_ranges_in(temp.as_file(), offset, length)
+            .unwrap()
+            .collect::<Result<Vec<_>, ExtentError>>()
+            .unwrap();
+        assert_eq!(windowed, expected, "window {offset}+{length}");
+        let covered: u64 = windowed.iter().map(|r| r.length).sum();
+        assert_eq!(covered, end.min(3 * chunk).saturating_sub(offset));
+    }
+}
+
+#[test]
+fn test_ranges_for_files_in_parallel() {
+    let temps: Vec<_> = (0..50)
+        .map(|i| {
+            let mut temp = tempfile::NamedTempFile::new().unwrap();
+            temp.write_all(&vec![7; i * 1000]).unwrap();
+            temp.flush().unwrap();
+            temp
+        })
+        .collect();
+    let files: Vec<&File> = temps.iter().map(|temp| temp.as_file()).collect();
+
+    let sequential = ranges_for_files(&files, 1);
+    for threads in [0, 4] {
+        let parallel = ranges_for_files(&files, threads);
+        assert_eq!(parallel.len(), files.len());
+        for (parallel, sequential) in parallel.iter().zip(&sequential) {
+            match (parallel, sequential) {
+                (Ok(parallel), Ok(sequential)) => assert_eq!(parallel, sequential),
+                (Err(parallel), Err(sequential)) => {
+                    assert_eq!(parallel.to_string(), sequential.to_string())
+                }
+                (parallel, sequential) => panic!("{parallel:?} != {sequential:?}"),
+            }
+        }
+    }
+    assert!(ranges_for_files(&[], 4).is_empty());
+}
+
 #[test]
 fn test_range_reader_with_custom_buffer_size() {
     let mut reader = RangeReader::with_buffer_size(128 * 1024); // 128KB buffer
@@ -322,7 +647,7 @@ fn test_range_reader_with_custom_buffer_size() {
             let ranges: Vec<_> = iter.collect::<Result<Vec<_>, _>>().unwrap();
             assert!(!ranges.is_empty());
         }
-        Err(e) if is_unsupported_error(&e) => {
+        Err(ExtentError::Unsupported { .. }) => {
             eprintln!("Skipping: filesystem doesn't support extent queries");
         }
         Err(e) => panic!("Unexpected error: {e}"),
@@ -370,13 +695,15 @@ mod fallback_tests {
         );
 
         let range = &ranges[0];
-        assert_eq!(range.offset, 0, "Range should start at offset 0");
         assert_eq!(
-            range.length,
-            content.len() as u64,
-            "Range length should match file size"
+            range.to_tuple(),
+            (0, content.len() as u64),
+            "Range should cover the whole file"
+        );
+        assert!(
+            !range.hole,
+            "Fallback range should not be marked sparse: {range}"
         );
-        assert!(!range.hole, "Fallback range should not be marked sparse");
     }
 
     /// Test that the fallback works correctly for empty files on tmpfs.
@@ -401,7 +728,7 @@ mod fallback_tests {
                 let ranges = ranges.unwrap();
                 assert!(ranges.is_empty(), "Empty file should have no ranges");
             }
-            Err(e) if is_unsupported_error(&e) => {
+            Err(ExtentError::Unsupported { .. }) => {
                 eprintln!("Skipping: filesystem doesn't support extent queries");
             }
             Err(e) => panic!("Unexpected error: {e}"),
@@ -440,3 +767,247 @@ mod fallback_tests {
         assert_eq!(ranges2[0].length, 11); // "Second file"
     }
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_preallocated_range_is_unwritten() {
+    use std::os::fd::AsRawFd;
+
+    // Use the target dir rather than /tmp, which is often tmpfs without FIEMAP support
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let path = temp_dir.path().join("preallocated.bin");
+    let file = File::create(&path).unwrap();
+
+    let size = 256 * 1024;
+    // SAFETY: the fd is valid for the lifetime of `file`
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size) } != 0 {
+        eprintln!("Skipping: fallocate not supported");
+        return;
+    }
+
+    let file = File::open(&path).unwrap();
+    let mut reader = RangeReader::new();
+    match reader
+        .read_ranges(&file)
+        .and_then(|ranges| ranges.collect::<Result<Vec<_>, _>>())
+    {
+        Ok(ranges) => {
+            let total_len: u64 = ranges.iter().map(|r| r.length).sum();
+            assert_eq!(total_len, size as u64, "Ranges should cover the file");
+
+            for range in &ranges {
+                assert!(
+                    !(range.hole && range.unwritten),
+                    "A range can't be both a hole and unwritten: {:?}",
+                    range
+                );
+            }
+
+            // FIEMAP reports preallocated extents as unwritten; other queries can't tell
+            if reader.last_method() == Method::Fiemap {
+                assert!(
+                    ranges.iter().all(|r| r.unwritten),
+                    "Fallocated ranges should be unwritten: {:?}",
+                    ranges
+                );
+            }
+        }
+        Err(ExtentError::Unsupported { .. }) => {
+            eprintln!("Skipping: filesystem doesn't support extent queries");
+        }
+        Err(e) => panic!("Unexpected error: {e}"),
+    }
+}
+
+#[test]
+fn test_physical_offsets() {
+    // Use the target dir rather than /tmp, which is often tmpfs without FIEMAP support
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let path = temp_dir.path().join("physical.bin");
+    let mut file = File::create(&path).unwrap();
+    file.write_all(&[1; 8192]).unwrap();
+    file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+    file.write_all(&[2; 8192]).unwrap();
+    // Filesystems with delayed allocation only know where data goes once it's written out
+    file.sync_all().unwrap();
+
+    let file = File::open(&path).unwrap();
+    let physical = extentria::capabilities(&file).unwrap().physical;
+    let ranges = ranges_for_file(&file).unwrap();
+    for range in &ranges {
+        if range.hole || !physical {
+            assert_eq!(range.physical_offset, None, "{range:?}");
+        } else {
+            assert!(range.physical_offset.is_some(), "{range:?}");
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_ranges_for_files() {
+    use extentria::{par_ranges_for_files, ranges_for_path};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut paths: Vec<_> = (0..50)
+        .map(|i| {
+            let path = temp_dir.path().join(format!("{i}.bin"));
+            fs::write(&path, vec![7; i * 1000]).unwrap();
+            path
+        })
+        .collect();
+    paths.insert(10, temp_dir.path().join("missing"));
+
+    let parallel = par_ranges_for_files(&paths);
+    assert_eq!(parallel.len(), paths.len());
+    for (path, parallel) in paths.iter().zip(&parallel) {
+        match (parallel, ranges_for_path(path)) {
+            (Ok(parallel), Ok(sequential)) => assert_eq!(parallel, &sequential, "{path:?}"),
+            (Err(ExtentError::Io(parallel)), Err(ExtentError::Io(sequential))) => {
+                assert_eq!(parallel.kind(), sequential.kind(), "{path:?}")
+            }
+            (parallel, sequential) => panic!("{path:?}: {parallel:?} != {sequential:?}"),
+        }
+    }
+    assert!(parallel[10].is_err());
+    assert!(par_ranges_for_files::<&str>(&[]).is_empty());
+}
+
+#[cfg(feature = "scan")]
+#[test]
+fn test_scan_dir() {
+    use extentria::{ScanOptions, ranges_for_path, scan_dir};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let root = temp_dir.path();
+    fs::create_dir_all(root.join("sub/deeper")).unwrap();
+    fs::write(root.join("a.bin"), [1; 100]).unwrap();
+    fs::write(root.join("sub/b.bin"), [2; 200]).unwrap();
+    fs::write(root.join("sub/deeper/c.bin"), [3; 300]).unwrap();
+    File::create(root.join("sub/empty")).unwrap();
+
+    let scanned: Vec<_> = scan_dir(root, &ScanOptions::default())
+        .map(|(path, ranges)| {
+            let length: u64 = ranges.unwrap().iter().map(|r| r.length).sum();
+            (path.strip_prefix(root).unwrap().to_path_buf(), length)
+        })
+        .collect();
+    assert_eq!(
+        scanned,
+        [
+            ("a.bin".into(), 100),
+            ("sub/b.bin".into(), 200),
+            ("sub/deeper/c.bin".into(), 300),
+            ("sub/empty".into(), 0),
+        ]
+    );
+    assert_eq!(
+        ranges_for_path(root.join("sub/b.bin")).unwrap(),
+        ranges_for_file(&File::open(root.join("sub/b.bin")).unwrap()).unwrap()
+    );
+
+    let options = ScanOptions {
+        max_depth: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(scan_dir(root, &options).count(), 3);
+
+    // A missing directory is an error, with its path
+    let missing = root.join("missing");
+    let errors: Vec<_> = scan_dir(&missing, &ScanOptions::default()).collect();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, missing);
+    assert!(errors[0].1.is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_diff_layout() {
+    use extentria::{Allocation, diff_layout};
+
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let dense_path = temp_dir.path().join("dense.bin");
+    fs::write(&dense_path, vec![1u8; 3 * 1024 * 1024]).unwrap();
+    let sparse_path = temp_dir.path().join("sparse.bin");
+    let mut sparse = File::create(&sparse_path).unwrap();
+    sparse.write_all(&vec![1u8; 1024 * 1024]).unwrap();
+    sparse.set_len(3 * 1024 * 1024).unwrap();
+    for path in [&dense_path, &sparse_path] {
+        File::open(path).unwrap().sync_all().unwrap();
+    }
+
+    let (dense, sparse) = (
+        File::open(&dense_path).unwrap(),
+        File::open(&sparse_path).unwrap(),
+    );
+    assert!(diff_layout(&dense, &dense).unwrap().is_empty());
+    let diffs = diff_layout(&dense, &sparse).unwrap();
+    if extentria::capabilities(&sparse).unwrap().physical {
+        // Each file has its own data, so they differ throughout
+        assert_eq!(diffs[0].offset, 0, "{diffs:?}");
+        assert_eq!(diffs.last().unwrap().end(), 3 * 1024 * 1024, "{diffs:?}");
+    }
+    if extentria::capabilities(&sparse).unwrap().sparse {
+        // The hole is data in the dense file
+        let hole = diffs.iter().find(|d| d.b == Allocation::Hole).unwrap();
+        assert_eq!(hole.a, Allocation::Private);
+        assert_eq!(hole.end(), 3 * 1024 * 1024);
+    }
+}
+
+/// Directories can be opened as files on Unix, but have no ranges to read.
+#[cfg(unix)]
+#[test]
+fn test_directory_is_not_a_regular_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = File::open(temp_dir.path()).unwrap();
+
+    assert!(matches!(
+        ranges_for_file(&dir),
+        Err(ExtentError::NotARegularFile)
+    ));
+    let mut reader = RangeReader::new();
+    assert!(matches!(
+        reader.read_ranges_in(&dir, 0, 4096).map(|_| ()),
+        Err(ExtentError::NotARegularFile)
+    ));
+    let err = std::io::Error::from(reader.extent_count(&dir).unwrap_err());
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn test_apfs_clones_are_shared() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt as _;
+
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let original = temp_dir.path().join("original.bin");
+    fs::write(&original, vec![3u8; 256 * 1024]).unwrap();
+    File::open(&original).unwrap().sync_all().unwrap();
+
+    let clone = temp_dir.path().join("clone.bin");
+    let (src, dst) = (
+        CString::new(original.as_os_str().as_bytes()).unwrap(),
+        CString::new(clone.as_os_str().as_bytes()).unwrap(),
+    );
+    // SAFETY: both paths are nul-terminated
+    if unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) } != 0 {
+        eprintln!("Skipping: clonefile not supported");
+        return;
+    }
+
+    let file = File::open(&clone).unwrap();
+    assert!(extentria::capabilities(&file).unwrap().shared);
+    let original = ranges_for_file(&File::open(&original).unwrap()).unwrap();
+    let cloned = ranges_for_file(&file).unwrap();
+    assert!(cloned.iter().all(|r| r.flags.shared), "{cloned:?}");
+    // The clone's data is where the original's is
+    assert_eq!(
+        original
+            .iter()
+            .map(|r| r.physical_offset)
+            .collect::<Vec<_>>(),
+        cloned.iter().map(|r| r.physical_offset).collect::<Vec<_>>()
+    );
+}
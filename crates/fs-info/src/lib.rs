@@ -1,6 +1,7 @@
 //! Filesystem information utilities.
 //!
-//! Provides functions to get filesystem type, UUID, and read-only status.
+//! Provides functions to get filesystem type, UUID, read-only status, and how file names
+//! are compared.
 
 use std::{fs, io, path::Path};
 #[cfg(target_os = "linux")]
@@ -44,7 +45,7 @@ pub fn get_fs_info(path: &Path) -> io::Result<FsInfo> {
 /// Get filesystem information for a path (macOS/FreeBSD implementation).
 #[cfg(all(unix, not(target_os = "linux")))]
 pub fn get_fs_info(path: &Path) -> io::Result<FsInfo> {
-    let stat = statfs(path).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let stat = statfs(path).map_err(io::Error::other)?;
 
     // On macOS/FreeBSD, use filesystem_type_name() which returns a string
     let fs_type = Some(stat.filesystem_type_name().to_string());
@@ -55,6 +56,182 @@ pub fn get_fs_info(path: &Path) -> io::Result<FsInfo> {
     Ok(FsInfo { fs_type, fs_id })
 }
 
+/// Get just the filesystem type for a path.
+///
+/// This is cheaper than [`get_fs_info`] as it doesn't look up the filesystem UUID.
+#[cfg(target_os = "linux")]
+pub fn get_fs_type(path: &Path) -> io::Result<Option<String>> {
+    let stat = statfs(path).map_err(io::Error::other)?;
+    Ok(get_fs_type_name(stat.filesystem_type().0 as u64))
+}
+
+/// Get just the filesystem type for a path (macOS/FreeBSD implementation).
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn get_fs_type(path: &Path) -> io::Result<Option<String>> {
+    let stat = statfs(path).map_err(io::Error::other)?;
+    Ok(Some(stat.filesystem_type_name().to_string()))
+}
+
+/// Get just the filesystem type for a path (Windows implementation).
+#[cfg(windows)]
+pub fn get_fs_type(path: &Path) -> io::Result<Option<String>> {
+    get_fs_info(path).map(|info| info.fs_type)
+}
+
+/// How a filesystem treats the Unicode normalization of file names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Names are stored and compared as given, byte for byte (most Unix filesystems, NTFS).
+    Exact,
+    /// Names are stored as given, but different normalizations of a name are the same
+    /// file (APFS).
+    Insensitive,
+    /// Names are stored decomposed (NFD), however they were given (HFS+).
+    Nfd,
+}
+
+impl Normalization {
+    /// Name of the normalization, as stored in catalogs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Exact => "exact",
+            Self::Insensitive => "insensitive",
+            Self::Nfd => "nfd",
+        }
+    }
+
+    /// Parse a normalization from its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "exact" => Some(Self::Exact),
+            "insensitive" => Some(Self::Insensitive),
+            "nfd" => Some(Self::Nfd),
+            _ => None,
+        }
+    }
+}
+
+/// How a filesystem compares file names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NameRules {
+    /// Whether names differing only in case are different files.
+    pub case_sensitive: bool,
+    /// How Unicode normalization of names is handled.
+    pub normalization: Normalization,
+}
+
+impl NameRules {
+    /// The usual rules for a filesystem type, as formatted by default.
+    pub fn for_fs_type(fs_type: &str) -> Self {
+        let case_sensitive = !matches!(
+            fs_type,
+            "apfs"
+                | "hfs"
+                | "hfsplus"
+                | "ntfs"
+                | "refs"
+                | "vfat"
+                | "fat"
+                | "fat32"
+                | "msdos"
+                | "msdosfs"
+                | "exfat"
+                | "smb2"
+                | "smbfs"
+                | "cifs"
+        );
+        let normalization = match fs_type {
+            "apfs" => Normalization::Insensitive,
+            "hfs" | "hfsplus" => Normalization::Nfd,
+            _ => Normalization::Exact,
+        };
+        Self {
+            case_sensitive,
+            normalization,
+        }
+    }
+}
+
+/// Find out how the filesystem at a directory compares file names.
+///
+/// Starts from the usual rules for the filesystem type, then checks case sensitivity
+/// against the directory's entries where it can: if an entry with its ASCII letters'
+/// case swapped resolves to a file without being listed separately, the directory is
+/// case-insensitive. This catches case-sensitive APFS volumes and case-folding ext4
+/// directories. Nothing is written to the directory.
+pub fn get_name_rules(dir: &Path) -> io::Result<NameRules> {
+    let fs_type = get_fs_type(dir)?;
+    let mut rules = NameRules::for_fs_type(fs_type.as_deref().unwrap_or_default());
+    if let Some(case_sensitive) = probe_case_sensitivity(dir)? {
+        rules.case_sensitive = case_sensitive;
+    }
+    Ok(rules)
+}
+
+/// Check whether a directory is case-sensitive by looking up its entries with their case
+/// swapped, if it has an entry with ASCII letters.
+fn probe_case_sensitivity(dir: &Path) -> io::Result<Option<bool>> {
+    let names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+
+    let Some((swapped, name)) = names.iter().find_map(|name| {
+        let swapped: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_lowercase() {
+                    c.to_ascii_uppercase()
+                } else {
+                    c.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        (swapped != *name).then_some((swapped, name))
+    }) else {
+        return Ok(None);
+    };
+
+    if names.contains(&swapped) {
+        return Ok(Some(true));
+    }
+    match fs::symlink_metadata(dir.join(&swapped)) {
+        Ok(_) => Ok(Some(false)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Some(true)),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("probing case sensitivity with {name:?}: {err}"),
+        )),
+    }
+}
+
+/// Whether a filesystem type is a pseudo-filesystem.
+///
+/// These are synthesised by the kernel rather than stored anywhere: backing them up is
+/// pointless at best, and reading some of their files can block forever.
+pub fn is_pseudo_fs(fs_type: &str) -> bool {
+    matches!(
+        fs_type,
+        "proc"
+            | "sysfs"
+            | "devpts"
+            | "devfs"
+            | "devtmpfs"
+            | "debugfs"
+            | "tracefs"
+            | "securityfs"
+            | "cgroup"
+            | "cgroup2"
+            | "bpf"
+            | "mqueue"
+            | "pstorefs"
+            | "efivarfs"
+            | "fdescfs"
+            | "procfs"
+            | "linprocfs"
+            | "linsysfs"
+    )
+}
+
 /// Get filesystem information for a path (Windows implementation).
 #[cfg(windows)]
 pub fn get_fs_info(path: &Path) -> io::Result<FsInfo> {
@@ -171,6 +348,7 @@ fn get_fs_type_name(magic: u64) -> Option<String> {
         0x61756673 => "aufs",
         0x73717368 => "squashfs",
         0xde5e81e4 => "efivarfs",
+        0x74726163 => "tracefs",
         0x00011954 => "ufs",
         0x15013346 => "udf",
         0x4006 => "fat",
@@ -299,13 +477,7 @@ fn is_btrfs_subvol_readonly(path: &Path) -> io::Result<bool> {
 
     // SAFETY: We're calling ioctl with a valid fd and a pointer to a u64.
     // The ioctl reads flags into the provided buffer.
-    let result = unsafe {
-        libc::ioctl(
-            fd,
-            BTRFS_IOC_SUBVOL_GETFLAGS.try_into().unwrap(),
-            &mut flags as *mut u64,
-        )
-    };
+    let result = unsafe { libc::ioctl(fd, BTRFS_IOC_SUBVOL_GETFLAGS as _, &mut flags as *mut u64) };
 
     if result < 0 {
         return Err(io::Error::last_os_error());
@@ -331,6 +503,45 @@ mod tests {
         assert!(info.fs_type.is_some());
     }
 
+    #[test]
+    fn name_rules() {
+        let rules = super::NameRules::for_fs_type("apfs");
+        assert!(!rules.case_sensitive);
+        assert_eq!(rules.normalization, super::Normalization::Insensitive);
+        assert_eq!(
+            super::NameRules::for_fs_type("hfs").normalization,
+            super::Normalization::Nfd
+        );
+        assert!(super::NameRules::for_fs_type("ext4").case_sensitive);
+        for norm in ["exact", "insensitive", "nfd"] {
+            assert_eq!(
+                super::Normalization::from_name(norm).unwrap().as_str(),
+                norm
+            );
+        }
+    }
+
+    #[test]
+    fn probe_case_sensitivity() {
+        let dir = std::env::temp_dir().join(format!("fs-info-case-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(super::probe_case_sensitivity(&dir).unwrap(), None);
+
+        std::fs::write(dir.join("Probe"), "").unwrap();
+        let probed = super::probe_case_sensitivity(&dir).unwrap();
+        let insensitive = dir.join("pROBE").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(probed, Some(!insensitive));
+    }
+
+    #[test]
+    fn pseudo_fs() {
+        assert!(super::is_pseudo_fs("proc"));
+        assert!(super::is_pseudo_fs("sysfs"));
+        assert!(!super::is_pseudo_fs("ext4"));
+        assert!(!super::is_pseudo_fs("tmpfs"));
+    }
+
     #[test]
     fn is_readonly() {
         // Use a path that exists on all platforms and is typically writable
@@ -1,6 +1,12 @@
 //! Subcommands for the tumulus CLI.
 
+pub mod browse;
 pub mod catalog;
 pub mod compare;
 pub mod debug_extents;
+pub mod migrate;
+pub mod progress;
+pub mod repository;
+pub mod sync;
 pub mod upload;
+pub mod verify;
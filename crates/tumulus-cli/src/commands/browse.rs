@@ -0,0 +1,570 @@
+//! Browse a catalog's files in the terminal, and restore some of them.
+//!
+//! The catalog is read from a file, or downloaded from a server: given a server URL, its
+//! complete catalogs are listed newest first to pick one from. The tree is shown a
+//! directory at a time, with the size of everything under each entry, and for the selected
+//! entry how much is stored for it once deduplicated (see [`tumulus::browse`]). Paths can be
+//! marked, and restored with their contents under `--restore-to`, fetching data from the
+//! server.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use extentria::HumanSize;
+use jiff::Timestamp;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+};
+use reqwest::{
+    Client,
+    header::{AUTHORIZATION, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use tumulus::{
+    BlobFetcher, CatalogCipher, CatalogTree, SecretSource, SecretsProvider, TreeStats,
+    catalog_name_rules, open_catalog, read_catalog_files, restore_entries,
+};
+
+use crate::commands::catalog::metadata_value;
+
+/// Catalogs to check for existence per request.
+const CHECK_BATCH: usize = 1000;
+
+/// Browse a catalog's files, and restore some of them
+#[derive(Args, Debug)]
+pub struct BrowseArgs {
+    /// Catalog file, or the URL of a server to pick a catalog from
+    source: String,
+
+    /// Catalog to browse on the server, instead of picking one
+    #[arg(long, value_name = "ID")]
+    catalog: Option<Uuid>,
+
+    /// Server to restore from, when browsing a catalog file
+    #[arg(long, short)]
+    server: Option<String>,
+
+    /// Bearer token to authenticate to the server with, read from a file path, or from
+    /// `env:NAME`, `file:PATH`, `cmd:COMMAND`, or `keychain:SERVICE/ACCOUNT`
+    #[arg(long, value_name = "SECRET")]
+    token: Option<SecretSource>,
+
+    /// Key for a catalog with encrypted paths, as given to `catalog --encrypt-key`
+    #[arg(long, value_name = "SECRET")]
+    key: Option<SecretSource>,
+
+    /// Directory to restore marked paths into
+    #[arg(long, value_name = "PATH", default_value = "restore")]
+    restore_to: PathBuf,
+
+    /// Maximum number of extents to fetch at once when restoring
+    #[arg(long, short = 'j', default_value = "8")]
+    parallel: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckCatalogsRequest<'a> {
+    ids: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckCatalogsResponse {
+    existing: Vec<String>,
+}
+
+/// A catalog on the server to pick from.
+struct CatalogChoice {
+    id: Uuid,
+    created: Option<Timestamp>,
+}
+
+/// What browsing ended with.
+enum Outcome {
+    Quit,
+    /// Restore these nodes and everything under them.
+    Restore(Vec<usize>),
+}
+
+pub fn run(args: BrowseArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = build_client(args.token.as_ref())?;
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    let is_url = args.source.starts_with("http://") || args.source.starts_with("https://");
+    let (server, catalog) = if is_url {
+        let server = args.source.trim_end_matches('/').to_string();
+        let id = match args.catalog {
+            Some(id) => id,
+            None => {
+                let choices = runtime.block_on(list_catalogs(&client, &server))?;
+                match pick_catalog(&server, &choices)? {
+                    Some(id) => id,
+                    None => return Ok(()),
+                }
+            }
+        };
+        let data = runtime.block_on(download_catalog(&client, &server, id))?;
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&data)?;
+        (Some(server), CatalogFile::Downloaded(file))
+    } else {
+        let server = args
+            .server
+            .as_deref()
+            .map(|server| server.trim_end_matches('/').to_string());
+        (server, CatalogFile::Local(PathBuf::from(&args.source)))
+    };
+
+    let (conn, _tempfile) = open_catalog(catalog.path())?;
+    let key_id = metadata_value(&conn, "path_encryption")
+        .and_then(|value| value.get("key_id")?.as_str().map(String::from));
+    let cipher = match (key_id, &args.key) {
+        (None, _) => None,
+        (Some(key_id), None) => {
+            return Err(format!("catalog paths are encrypted with key {key_id}, use --key").into());
+        }
+        (Some(key_id), Some(key)) => {
+            let cipher = CatalogCipher::from_secret(key)?;
+            if cipher.key_id() != key_id {
+                return Err(format!(
+                    "catalog paths are encrypted with key {key_id}, but the given key is {}",
+                    cipher.key_id()
+                )
+                .into());
+            }
+            Some(cipher)
+        }
+    };
+
+    let mut files = read_catalog_files(&conn)?;
+    if let Some(ref cipher) = cipher {
+        files = files
+            .into_iter()
+            .map(|info| cipher.decrypt_file(info))
+            .collect::<Result<_, _>>()?;
+    }
+    let tree = CatalogTree::new(files);
+
+    let mut browser = Browser::new(&tree, server.is_some(), &args.restore_to);
+    let mut terminal = ratatui::init();
+    let outcome = browser.run(&mut terminal);
+    ratatui::restore();
+
+    let (Outcome::Restore(marked), Some(server)) = (outcome?, server) else {
+        return Ok(());
+    };
+    let entries = tree.entries_under(marked);
+    info!(entries = entries.len(), target = ?args.restore_to, "Restoring");
+    eprintln!(
+        "Restoring {} entries into {}",
+        entries.len(),
+        args.restore_to.display()
+    );
+    let fetcher = BlobFetcher::new(client, server).with_parallel(args.parallel);
+    let report = runtime.block_on(restore_entries(
+        &entries,
+        &args.restore_to,
+        &fetcher,
+        catalog_name_rules(&conn),
+    ))?;
+    eprintln!(
+        "Restored {} entries ({})",
+        report.restored,
+        HumanSize(report.bytes)
+    );
+    if report.renamed > 0 {
+        eprintln!(
+            "  {} under names normalized for the target filesystem",
+            report.renamed
+        );
+    }
+    if !report.skipped.is_empty() {
+        eprintln!("Skipped {} entries:", report.skipped.len());
+        for (path, reason) in &report.skipped {
+            eprintln!("  {path}: {reason}");
+        }
+    }
+    Ok(())
+}
+
+/// Where the catalog being browsed is.
+enum CatalogFile {
+    Local(PathBuf),
+    Downloaded(tempfile::NamedTempFile),
+}
+
+impl CatalogFile {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Local(path) => path,
+            Self::Downloaded(file) => file.path(),
+        }
+    }
+}
+
+/// The complete catalogs on a server, newest first.
+async fn list_catalogs(
+    client: &Client,
+    server: &str,
+) -> Result<Vec<CatalogChoice>, Box<dyn std::error::Error + Send + Sync>> {
+    let ids: Vec<String> = client
+        .get(format!("{server}/catalogs"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let mut complete = Vec::new();
+    for batch in ids.chunks(CHECK_BATCH) {
+        let resp = client
+            .post(format!("{server}/catalogs/check"))
+            .json(&CheckCatalogsRequest { ids: batch })
+            .send()
+            .await?
+            .error_for_status()?;
+        complete.extend(resp.json::<CheckCatalogsResponse>().await?.existing);
+    }
+
+    let mut choices = Vec::with_capacity(complete.len());
+    for id in complete {
+        let id = Uuid::parse_str(&id).map_err(|_| format!("invalid catalog ID: {id}"))?;
+        let resp = client
+            .head(format!("{server}/catalogs/{}", id.simple()))
+            .send()
+            .await?
+            .error_for_status()?;
+        let created = resp
+            .headers()
+            .get("x-catalog-created")
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .and_then(|seconds| Timestamp::from_second(seconds).ok());
+        choices.push(CatalogChoice { id, created });
+    }
+    // Each batch comes back sorted, but not the batches between them
+    choices.sort_by_key(|choice| std::cmp::Reverse(choice.created));
+    Ok(choices)
+}
+
+/// Download a catalog from a server, checking it against its checksum.
+async fn download_catalog(
+    client: &Client,
+    server: &str,
+    id: Uuid,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let resp = client
+        .get(format!("{server}/catalogs/{}", id.simple()))
+        .send()
+        .await?
+        .error_for_status()?;
+    let expected = resp
+        .headers()
+        .get("x-catalog-checksum")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let data = resp.bytes().await?;
+    let checksum = blake3::hash(&data).to_hex().to_string();
+    if let Some(expected) = expected
+        && expected != checksum
+    {
+        return Err(format!(
+            "catalog {id} doesn't match its checksum: expected {expected}, got {checksum}"
+        )
+        .into());
+    }
+    Ok(data.to_vec())
+}
+
+/// Let the user pick a catalog, or none by quitting.
+fn pick_catalog(
+    server: &str,
+    choices: &[CatalogChoice],
+) -> Result<Option<Uuid>, Box<dyn std::error::Error + Send + Sync>> {
+    if choices.is_empty() {
+        return Err(format!("no complete catalogs on {server}").into());
+    }
+    let items: Vec<ListItem> = choices
+        .iter()
+        .map(|choice| {
+            let created = choice
+                .created
+                .map(|created| created.strftime("%Y-%m-%d %H:%M:%S UTC").to_string())
+                .unwrap_or_default();
+            ListItem::new(format!("{}  {created}", choice.id))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(format!(" Catalogs on {server} ")))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(Some(0));
+
+    let mut terminal = ratatui::init();
+    let picked = loop {
+        let drawn = terminal.draw(|frame| {
+            let [body, footer] =
+                Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+            frame.render_stateful_widget(&list, body, &mut state);
+            frame.render_widget(Line::from(" ↑↓ move  Enter browse  q quit"), footer);
+        });
+        if let Err(err) = drawn {
+            break Err(err);
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => match key.code {
+                KeyCode::Up | KeyCode::Char('k') => state.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => state.select_next(),
+                KeyCode::Enter => {
+                    break Ok(state.selected().and_then(|index| choices.get(index)));
+                }
+                KeyCode::Char('q') | KeyCode::Esc => break Ok(None),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    Ok(picked?.map(|choice| choice.id))
+}
+
+/// State of the tree browser.
+struct Browser<'a> {
+    tree: &'a CatalogTree,
+    /// Directory being shown.
+    dir: usize,
+    list: ListState,
+    marked: HashSet<usize>,
+    /// Stats of entries selected so far, as they take a walk of everything under them.
+    stats: HashMap<usize, TreeStats>,
+    can_restore: bool,
+    restore_to: &'a Path,
+    confirming: bool,
+    message: Option<String>,
+}
+
+impl<'a> Browser<'a> {
+    fn new(tree: &'a CatalogTree, can_restore: bool, restore_to: &'a Path) -> Self {
+        Self {
+            tree,
+            dir: CatalogTree::ROOT,
+            list: ListState::default().with_selected(Some(0)),
+            marked: HashSet::new(),
+            stats: HashMap::new(),
+            can_restore,
+            restore_to,
+            confirming: false,
+            message: None,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<Outcome> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && let Some(outcome) = self.handle(key.code)
+            {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    /// The selected node, if the directory has any children.
+    fn selected(&self) -> Option<usize> {
+        let children = &self.tree.node(self.dir).children;
+        self.list
+            .selected()
+            .and_then(|index| children.get(index).copied())
+    }
+
+    fn handle(&mut self, code: KeyCode) -> Option<Outcome> {
+        if self.confirming {
+            self.confirming = false;
+            if matches!(code, KeyCode::Char('y')) {
+                let mut marked: Vec<usize> = self.marked.iter().copied().collect();
+                marked.sort_unstable();
+                return Some(Outcome::Restore(marked));
+            }
+            self.message = Some("Restore cancelled".into());
+            return None;
+        }
+
+        self.message = None;
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Outcome::Quit),
+            KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+            KeyCode::Home => self.list.select_first(),
+            KeyCode::End => self.list.select_last(),
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                if let Some(id) = self.selected()
+                    && self.tree.node(id).is_dir
+                {
+                    self.dir = id;
+                    self.list.select(Some(0));
+                }
+            }
+            KeyCode::Left | KeyCode::Backspace | KeyCode::Char('h') => {
+                if let Some(parent) = self.tree.node(self.dir).parent {
+                    let position = self
+                        .tree
+                        .node(parent)
+                        .children
+                        .iter()
+                        .position(|&child| child == self.dir);
+                    self.dir = parent;
+                    self.list.select(position);
+                }
+            }
+            KeyCode::Char(' ') => {
+                if let Some(id) = self.selected() {
+                    if !self.marked.remove(&id) {
+                        self.marked.insert(id);
+                    }
+                    self.list.select_next();
+                }
+            }
+            KeyCode::Char('r') => {
+                if self.marked.is_empty() {
+                    self.message = Some("Mark paths to restore with Space first".into());
+                } else if !self.can_restore {
+                    self.message =
+                        Some("Restoring needs a server to fetch data from: give --server".into());
+                } else {
+                    self.confirming = true;
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [body, footer] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [list_area, details_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(body);
+
+        let dir = self.tree.node(self.dir);
+        let items: Vec<ListItem> = dir
+            .children
+            .iter()
+            .map(|&id| {
+                let node = self.tree.node(id);
+                let mark = if self.marked.contains(&id) { '*' } else { ' ' };
+                let name = if node.is_dir {
+                    format!("{}/", node.name)
+                } else {
+                    node.name.clone()
+                };
+                ListItem::new(format!(
+                    "{mark} {name:<40} {:>10} {:>8}",
+                    HumanSize(node.bytes).to_string(),
+                    node.files
+                ))
+            })
+            .collect();
+        let title = format!(" /{} ", dir.path);
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+
+        let details = match self.selected() {
+            Some(id) => self.details(id),
+            None => vec![Line::from("Empty directory")],
+        };
+        frame.render_widget(
+            Paragraph::new(details).block(Block::bordered().title(" Details ")),
+            details_area,
+        );
+
+        let status = if self.confirming {
+            format!(
+                " Restore {} marked paths into {}? (y/n)",
+                self.marked.len(),
+                self.restore_to.display()
+            )
+        } else if let Some(message) = &self.message {
+            format!(" {message}")
+        } else {
+            format!(
+                " ↑↓ move  Enter open  ← up  Space mark  r restore  q quit  ({} marked)",
+                self.marked.len()
+            )
+        };
+        frame.render_widget(Line::from(status), footer);
+    }
+
+    /// Describe a node and what's under it.
+    fn details(&mut self, id: usize) -> Vec<Line<'static>> {
+        let tree = self.tree;
+        let node = tree.node(id);
+        let stats = *self.stats.entry(id).or_insert_with(|| tree.stats(id));
+        let entry = node.entry.map(|entry| &tree.files()[entry]);
+
+        let mut lines = vec![
+            Line::from(format!("Path:     {}", node.path)),
+            Line::from(format!(
+                "Size:     {} ({} bytes)",
+                HumanSize(stats.bytes),
+                stats.bytes
+            )),
+            Line::from(format!("Stored:   {}", HumanSize(stats.stored_bytes))),
+            Line::from(format!("Dedup:    {:.2}x", stats.dedup_ratio())),
+        ];
+        if node.is_dir {
+            lines.push(Line::from(format!("Files:    {}", stats.files)));
+        }
+        if let Some(entry) = entry {
+            let kind = entry
+                .special
+                .as_ref()
+                .and_then(|special| special.get("type")?.as_str())
+                .unwrap_or("file");
+            lines.push(Line::from(format!("Type:     {kind}")));
+            if let Some(mode) = entry.unix_mode {
+                lines.push(Line::from(format!("Mode:     {:o}", mode & 0o7777)));
+            }
+            if let Some(modified) = entry
+                .ts_modified
+                .and_then(|modified| Timestamp::from_millisecond(modified).ok())
+            {
+                lines.push(Line::from(format!(
+                    "Modified: {}",
+                    modified.strftime("%Y-%m-%d %H:%M:%S UTC")
+                )));
+            }
+            if let Some(target) = entry
+                .special
+                .as_ref()
+                .and_then(|special| special.get("target")?.as_str())
+            {
+                lines.push(Line::from(format!("Target:   {target}")));
+            }
+        }
+        lines
+    }
+}
+
+/// Build the HTTP client for the server, sending the token in every request.
+fn build_client(
+    token: Option<&SecretSource>,
+) -> Result<Client, Box<dyn std::error::Error + Send + Sync>> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = token {
+        let mut value = HeaderValue::try_from(format!("Bearer {}", token.fetch_text()?))?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Ok(Client::builder().default_headers(headers).build()?)
+}
@@ -0,0 +1,945 @@
+//! Build a snapshot catalog from a directory tree
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clap::{Args, ValueEnum};
+use jiff::{SignedDuration, Timestamp};
+use rayon::prelude::*;
+use rusqlite::{Connection, params};
+use serde_json::json;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use fs_info::{get_fs_info, get_name_rules, is_readonly};
+use tumulus::{
+    AutoExclude, BlobIndex, CatalogCipher, CatalogWriter, DEFAULT_COMPRESSION_LEVEL, ExtentKey,
+    FileInfo, Manifest, PreviousTree, PriorityPatterns, RangeReader, RangeReaderImpl,
+    SUBTREE_HASH_VERSION, SecretSource, TreeHasher, compression::compress_file_with_level,
+    compute_tree_hash, compute_tree_hashes, create_catalog_schema, exclude::device_id,
+    get_hostname, get_machine_id, open_catalog, process_file_from_manifest,
+    process_file_with_index, process_file_with_previous, process_file_with_reader,
+    read_catalog_files, read_tree_hashes, record_renames, root_prefix, system_manifest,
+    write_tree_hashes,
+};
+
+use crate::commands::progress::{Progress, ProgressFormat};
+
+/// How many files are written to the catalog in each transaction.
+const WRITE_BATCH: usize = 10_000;
+
+/// How many batches can wait to be written before the scanner waits for the writer.
+const WRITE_QUEUE: usize = 4;
+
+/// Build a snapshot catalog from a directory tree
+#[derive(Args, Debug)]
+pub struct CatalogArgs {
+    /// Source directory to catalog
+    source_path: PathBuf,
+
+    /// Output catalog file path, or `-` to write the catalog to stdout once it's complete
+    /// (logs and progress go to stderr)
+    catalog_output: PathBuf,
+
+    /// Additional source directories to include in the same catalog (can be specified
+    /// multiple times). Files from each root are stored under its absolute path, without
+    /// the leading slash: `/var/lib/foo` is stored as `var/lib/foo`.
+    #[arg(long = "source", short = 's', value_name = "PATH")]
+    extra_sources: Vec<PathBuf>,
+
+    /// Preset for what kind of backup this is
+    #[arg(long, value_enum, default_value_t = Profile::Default)]
+    profile: Profile,
+
+    /// Don't descend into other filesystems mounted below the source roots
+    #[arg(long, short = 'x')]
+    one_file_system: bool,
+
+    /// Don't automatically skip pseudo-filesystems, swapfiles, and server storage
+    #[arg(long)]
+    no_auto_exclude: bool,
+
+    /// Never automatically skip this path or anything under it (can be specified
+    /// multiple times)
+    #[arg(long, value_name = "PATH")]
+    allow: Vec<PathBuf>,
+
+    /// Encrypt file paths and revealing metadata with this key (32 raw bytes or 64 hex
+    /// characters), so the server never sees file names. Paths are encrypted
+    /// deterministically, so catalogs made with the same key can still be compared.
+    ///
+    /// The key is read from a file path, or from `env:NAME`, `file:PATH`, `cmd:COMMAND`,
+    /// or `keychain:SERVICE/ACCOUNT`.
+    #[arg(long, value_name = "SECRET")]
+    encrypt_key: Option<SecretSource>,
+
+    /// Derive extent and blob IDs with a keyed hash from this repository secret (32 raw
+    /// bytes or 64 hex characters), so stored data can't be matched against hashes of
+    /// well-known files. Catalogs only share extents with catalogs made with the same key,
+    /// and the server must be set up to accept keyed extents.
+    ///
+    /// The secret is read like `--encrypt-key`'s.
+    #[arg(long, value_name = "SECRET")]
+    extent_key: Option<SecretSource>,
+
+    /// Glob patterns of paths to scan, hash, and upload first, highest priority first (can
+    /// be specified multiple times, or comma-separated). Patterns without a `/` match any
+    /// file or directory name, like `*.sqlite` or `Documents`.
+    #[arg(long, value_name = "PATTERN", value_delimiter = ',')]
+    priority_patterns: Vec<String>,
+
+    /// Stop after this long (like `90m` or `2h 30m`), finishing the files in progress, and
+    /// write a partial catalog marked as truncated, which can be continued with `--resume`
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Continue a catalog truncated by `--max-duration`: its entries are carried over, and
+    /// scanning picks up where it stopped
+    #[arg(long, value_name = "CATALOG")]
+    resume: Option<PathBuf>,
+
+    /// Previous catalog of the same source: files unchanged since (by size, inode, times,
+    /// and change cookie) take their blob from it instead of being read, and files moved
+    /// since are recorded as renames rather than as deleted and added
+    #[arg(long, value_name = "CATALOG")]
+    previous: Option<PathBuf>,
+
+    /// Manifest of files whose hashes are already known, as JSON lines of catalog path,
+    /// size, hash, and optionally extents: files it has extents for aren't read
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<PathBuf>,
+
+    /// Index of blobs already uploaded, as kept by `upload --blob-index`: files identical
+    /// to one of them, like copies, are hashed whole and take its extents instead of having
+    /// each of their own hashed
+    #[arg(long, value_name = "PATH")]
+    blob_index: Option<PathBuf>,
+
+    /// Don't capture macOS Finder metadata (`com.apple.*` extended attributes like tags and
+    /// quarantine flags) and resource forks
+    #[arg(long)]
+    no_apple_metadata: bool,
+
+    /// Make extent read errors fatal (exit on first error)
+    #[arg(long, short = 'e')]
+    fatal_errors: bool,
+
+    /// Zstd compression level (0 to disable, 1-22 for compression)
+    #[arg(long, short = 'c', default_value_t = DEFAULT_COMPRESSION_LEVEL)]
+    compression: i32,
+
+    /// Friendly name for this catalog
+    #[arg(long, short = 'n')]
+    name: Option<String>,
+
+    /// Extra metadata in KEY=VALUE format (can be specified multiple times)
+    #[arg(long, short = 'm', value_parser = parse_key_value)]
+    meta: Vec<(String, String)>,
+
+    /// Also report progress as JSON lines on stderr, for wrappers to display
+    #[arg(long, value_enum, default_value_t)]
+    progress: ProgressFormat,
+}
+
+/// Backup presets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Profile {
+    /// Just the files
+    Default,
+    /// A full system, for restoring onto new disks: stays on one filesystem per root,
+    /// captures all extended attributes (ACLs and file capabilities included) and
+    /// hardlinks, and records the partition and mount layout in the catalog
+    System,
+}
+
+/// Reject root sets where one root contains another, or two roots share a prefix.
+fn check_roots(roots: &[(Option<String>, PathBuf)]) -> Result<(), String> {
+    for (i, (prefix_a, root_a)) in roots.iter().enumerate() {
+        for (prefix_b, root_b) in &roots[i + 1..] {
+            if prefix_a == prefix_b {
+                return Err(format!(
+                    "source roots {:?} and {:?} would be stored under the same prefix",
+                    root_a, root_b
+                ));
+            }
+            if root_a.starts_with(root_b) || root_b.starts_with(root_a) {
+                return Err(format!(
+                    "source roots {:?} and {:?} overlap",
+                    root_a, root_b
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a non-negative duration like `90m`, `2h 30m`, or `PT1H`.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let duration: SignedDuration = s
+        .parse()
+        .map_err(|err| format!("invalid duration: {err}"))?;
+    Duration::try_from(duration).map_err(|_| "duration can't be negative".to_string())
+}
+
+/// A catalog truncated by `--max-duration`, to be resumed.
+struct PartialCatalog {
+    id: String,
+    files: Vec<FileInfo>,
+    /// The first entry that wasn't processed.
+    resume_from: PathBuf,
+    /// The priority patterns entries were ordered by.
+    priority_patterns: Vec<String>,
+}
+
+/// Read a metadata value from a catalog.
+pub(crate) fn metadata_value(conn: &Connection, key: &str) -> Option<serde_json::Value> {
+    conn.query_row("SELECT value FROM metadata WHERE key = ?1", [key], |row| {
+        row.get::<_, String>(0)
+    })
+    .ok()
+    .and_then(|value| serde_json::from_str(&value).ok())
+}
+
+/// Check a catalog was made with the same encryption and extent keys as this run.
+fn check_same_keys(
+    conn: &Connection,
+    cipher: Option<&CatalogCipher>,
+    extent_key: Option<&ExtentKey>,
+    what: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let key_id = |key: &str| {
+        metadata_value(conn, key).and_then(|v| v.get("key_id")?.as_str().map(String::from))
+    };
+    if key_id("extent_key").as_deref() != extent_key.map(ExtentKey::key_id) {
+        return Err(format!("{what} must use the same --extent-key as this run").into());
+    }
+    if key_id("path_encryption").as_deref() != cipher.map(CatalogCipher::key_id) {
+        return Err(format!("{what} must use the same --encrypt-key as this run").into());
+    }
+    Ok(())
+}
+
+/// A previous catalog of the same source.
+struct PreviousCatalog {
+    /// Its files, by their stored path.
+    files: HashMap<String, FileInfo>,
+    /// When its scan started, in milliseconds.
+    started: i64,
+    /// Its file blobs and subtree hashes, to reuse those of unchanged directories.
+    tree: PreviousTree,
+}
+
+/// Read a previous catalog of the same source.
+///
+/// If its paths are encrypted, the files are decrypted, but still keyed by their
+/// encrypted path. Without a start time, every file counts as possibly changed since.
+fn read_previous_catalog(
+    path: &Path,
+    cipher: Option<&CatalogCipher>,
+    extent_key: Option<&ExtentKey>,
+) -> Result<PreviousCatalog, Box<dyn std::error::Error + Send + Sync>> {
+    let (conn, _tempfile) = open_catalog(path)?;
+    check_same_keys(&conn, cipher, extent_key, "the previous catalog")?;
+    let started = metadata_value(&conn, "started")
+        .and_then(|started| started.as_i64())
+        .unwrap_or(i64::MIN);
+    let mut files = HashMap::new();
+    for info in read_catalog_files(&conn)? {
+        let stored = info.relative_path.clone();
+        let info = match cipher {
+            Some(cipher) => cipher.decrypt_file(info)?,
+            None => info,
+        };
+        files.insert(stored, info);
+    }
+    let blobs = files.iter().filter_map(|(stored, info)| {
+        let blob = info.blob.as_ref()?;
+        Some((stored.clone(), blob.blob_id))
+    });
+    let tree = PreviousTree::new(blobs, read_tree_hashes(&conn)?);
+    Ok(PreviousCatalog {
+        files,
+        started,
+        tree,
+    })
+}
+
+/// Read a truncated catalog to resume from.
+///
+/// If it has encrypted paths or keyed extent IDs, it must have been made with the same keys
+/// as this run.
+fn read_partial_catalog(
+    path: &Path,
+    cipher: Option<&CatalogCipher>,
+    extent_key: Option<&ExtentKey>,
+) -> Result<PartialCatalog, Box<dyn std::error::Error + Send + Sync>> {
+    let (conn, _tempfile) = open_catalog(path)?;
+
+    let Some(truncated) = metadata_value(&conn, "truncated") else {
+        return Err(format!("catalog {:?} is not truncated, nothing to resume", path).into());
+    };
+    check_same_keys(&conn, cipher, extent_key, "the catalog to resume")?;
+
+    let reveal = |value: serde_json::Value| match cipher {
+        Some(cipher) => cipher.open_value(&value),
+        None => Ok(value),
+    };
+
+    let resume_from = truncated
+        .get("resume_from")
+        .cloned()
+        .map(reveal)
+        .transpose()?;
+    let resume_from = resume_from
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .map(PathBuf::from)
+        .ok_or("truncated catalog has no resume position")?;
+
+    let priority_patterns = metadata_value(&conn, "priority_patterns")
+        .map(reveal)
+        .transpose()?
+        .map(serde_json::from_value)
+        .transpose()?
+        .unwrap_or_default();
+
+    let id = metadata_value(&conn, "id")
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default();
+
+    Ok(PartialCatalog {
+        id,
+        files: read_catalog_files(&conn)?,
+        resume_from,
+        priority_patterns,
+    })
+}
+
+/// Parse a KEY=VALUE string into a tuple.
+pub(crate) fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid KEY=VALUE: no '=' found in '{}'", s))?;
+    Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
+}
+
+pub fn run(args: CatalogArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = args.max_duration.map(|budget| Instant::now() + budget);
+    let progress = Progress::new(args.progress);
+    let source_path = args.source_path.canonicalize()?;
+    // A catalog for stdout is built in a temporary file, and copied out once complete
+    let stdout_catalog = (args.catalog_output == Path::new("-"))
+        .then(tempfile::NamedTempFile::new)
+        .transpose()?;
+    let catalog_path = stdout_catalog
+        .as_ref()
+        .map_or(args.catalog_output.as_path(), |file| file.path());
+
+    // With more than one root, every file is stored under its root's prefix
+    let mut roots: Vec<(Option<String>, PathBuf)> = vec![(None, source_path.clone())];
+    if !args.extra_sources.is_empty() {
+        roots.clear();
+        for root in std::iter::once(&args.source_path).chain(&args.extra_sources) {
+            let root = root.canonicalize()?;
+            roots.push((Some(root_prefix(&root)), root));
+        }
+        check_roots(&roots)?;
+    }
+
+    let one_file_system = args.one_file_system || args.profile == Profile::System;
+
+    let cipher = args
+        .encrypt_key
+        .as_ref()
+        .map(|key| CatalogCipher::from_secret(key))
+        .transpose()?;
+    let extent_key = args
+        .extent_key
+        .as_ref()
+        .map(|key| ExtentKey::from_secret(key))
+        .transpose()?;
+    if let Some(ref key) = extent_key {
+        info!(key_id = key.key_id(), "Deriving keyed extent IDs");
+    }
+    let manifest = args.manifest.as_deref().map(Manifest::open).transpose()?;
+    if let Some(ref manifest) = manifest {
+        info!(entries = manifest.len(), "Using pre-hashed manifest");
+    }
+    let blob_index = args
+        .blob_index
+        .as_deref()
+        .map(BlobIndex::open)
+        .transpose()?;
+    if let Some(ref index) = blob_index {
+        info!(blobs = index.len(), "Using blob index");
+    }
+    if args.previous.as_deref() == Some(catalog_path) {
+        return Err("write the catalog to a different path than --previous".into());
+    }
+    let mut previous_catalog = args
+        .previous
+        .as_deref()
+        .map(|path| read_previous_catalog(path, cipher.as_ref(), extent_key.as_ref()))
+        .transpose()?;
+    if let Some(ref catalog) = previous_catalog {
+        info!(files = catalog.files.len(), "Using previous catalog");
+    }
+
+    let started = Timestamp::now();
+    let catalog_id = Uuid::new_v4();
+    let machine_id = get_machine_id()?;
+
+    info!(
+        ?catalog_id,
+        ?source_path,
+        roots = roots.len(),
+        "Building catalog"
+    );
+
+    let auto_exclude = (!args.no_auto_exclude).then(|| {
+        AutoExclude::new(
+            args.allow
+                .iter()
+                .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+                .collect(),
+        )
+    });
+
+    // Collect all file paths first, skipping automatic exclusions below each root
+    progress.phase("scan", None, None);
+    let paths: Vec<(usize, PathBuf)> = roots
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, (_, root))| {
+            let root_dev = fs::metadata(root).ok().and_then(|m| device_id(&m));
+            let auto_exclude = auto_exclude.as_ref();
+            WalkDir::new(root)
+                .same_file_system(one_file_system)
+                .into_iter()
+                .filter_entry(move |entry| {
+                    let Some(auto_exclude) = auto_exclude.filter(|_| entry.depth() > 0) else {
+                        return true;
+                    };
+                    let is_dir = entry.file_type().is_dir();
+                    let crosses_mount =
+                        is_dir && entry.metadata().ok().and_then(|m| device_id(&m)) != root_dev;
+                    match auto_exclude.check(entry.path(), is_dir, crosses_mount) {
+                        Some(reason) => {
+                            info!(path = ?entry.path(), %reason, "Automatically excluded");
+                            false
+                        }
+                        None => true,
+                    }
+                })
+                .filter_map(|e| e.ok())
+                .map(move |e| (idx, e.into_path()))
+        })
+        .collect();
+
+    info!(entries = paths.len(), "Found entries");
+    progress.advance(paths.len() as u64, 0);
+
+    // Order entries by priority, then by path: walk order isn't stable, but resume
+    // positions need to be
+    let priorities = PriorityPatterns::new(args.priority_patterns.clone());
+    let catalog_path_of = |idx: usize, path: &Path| {
+        let (prefix, root) = &roots[idx];
+        let relative = path
+            .strip_prefix(root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        match prefix {
+            Some(prefix) if relative.is_empty() => prefix.clone(),
+            Some(prefix) => format!("{}/{}", prefix, relative),
+            None => relative,
+        }
+    };
+    // Previous catalogs and subtree hashes have paths as stored, encrypted if they are
+    let stored_path_of = |idx: usize, path: &Path| {
+        let path = catalog_path_of(idx, path);
+        match cipher {
+            Some(ref cipher) => cipher.encrypt_path(&path),
+            None => path,
+        }
+    };
+    let rank = |idx: usize, path: &Path| priorities.rank(&catalog_path_of(idx, path));
+    let mut paths: Vec<(u32, usize, PathBuf)> = paths
+        .into_iter()
+        .map(|(idx, path)| (rank(idx, &path), idx, path))
+        .collect();
+    paths.sort();
+
+    // When resuming, carry over the previous entries and skip past them
+    let mut previous = None;
+    let mut start = 0;
+    if let Some(ref resume) = args.resume {
+        if resume.as_path() == catalog_path {
+            return Err("write the resumed catalog to a different path than --resume".into());
+        }
+        let partial = read_partial_catalog(resume, cipher.as_ref(), extent_key.as_ref())?;
+        if partial.priority_patterns != priorities.patterns() {
+            return Err(format!(
+                "catalog {:?} was made with different --priority-patterns: {:?}",
+                resume, partial.priority_patterns
+            )
+            .into());
+        }
+        let Some(resume_idx) = roots
+            .iter()
+            .position(|(_, root)| partial.resume_from.starts_with(root))
+        else {
+            return Err(format!(
+                "catalog {:?} stopped at {:?}, which isn't under any source root",
+                resume, partial.resume_from
+            )
+            .into());
+        };
+        let resume_rank = rank(resume_idx, &partial.resume_from);
+        start = paths.partition_point(|(rank, idx, path)| {
+            (*rank, *idx, path.as_path()) < (resume_rank, resume_idx, partial.resume_from.as_path())
+        });
+        info!(
+            previous_id = %partial.id,
+            carried_over = partial.files.len(),
+            skipped = start,
+            "Resuming truncated catalog"
+        );
+        previous = Some(partial);
+    }
+
+    // Directories are hashed as the scan completes them, except when resuming: entries
+    // carried over aren't scanned again
+    let tree_hasher = previous.is_none().then(|| {
+        let mut hasher = match previous_catalog {
+            Some(ref mut catalog) => TreeHasher::with_previous(std::mem::take(&mut catalog.tree)),
+            None => TreeHasher::new(),
+        };
+        let stored: Vec<String> = paths
+            .par_iter()
+            .map(|(_, idx, path)| stored_path_of(*idx, path))
+            .collect();
+        for path in &stored {
+            hasher.register(path);
+        }
+        hasher
+    });
+
+    // Process files in parallel, with per-thread RangeReader for buffer reuse. Once past
+    // the deadline, files that haven't been started yet are skipped.
+    let process = |reader: &mut RangeReader, (rank, idx, path): &(u32, usize, PathBuf)| {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+
+        let (prefix, root) = &roots[*idx];
+        let key = extent_key.as_ref();
+        let stored = stored_path_of(*idx, path);
+        let entry = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.get(&catalog_path_of(*idx, path)));
+        let earlier = previous_catalog.as_ref().and_then(|catalog| {
+            let earlier = catalog.files.get(&stored)?;
+            Some((earlier, catalog.started))
+        });
+        let result = match (entry, earlier, &blob_index) {
+            (Some(entry), _, _) => process_file_from_manifest(path, root, entry, reader, key),
+            (None, Some((earlier, started)), _) => {
+                process_file_with_previous(path, root, earlier, started, reader, key)
+            }
+            (None, None, Some(index)) => process_file_with_index(path, root, index, reader, key),
+            (None, None, None) => process_file_with_reader(path, root, reader, key),
+        };
+        let result = result.and_then(|info| {
+            if args.no_apple_metadata {
+                Ok(info)
+            } else {
+                info.with_apple_metadata(path, reader, key)
+            }
+        });
+        let result = match args.profile {
+            Profile::System => result.and_then(|info| info.with_system_metadata(path)),
+            Profile::Default => result,
+        };
+        let result = match prefix {
+            Some(prefix) => result.map(|info| info.with_root(prefix)),
+            None => result,
+        };
+        let result = result.map(|mut info| {
+            info.priority = (!priorities.is_empty()).then_some(*rank);
+            info
+        });
+        let blob = result.as_ref().ok().and_then(|info| info.blob.as_ref());
+        if let Some(ref hasher) = tree_hasher {
+            hasher.finish(&stored, blob.map(|blob| &blob.blob_id));
+        }
+        progress.advance(1, blob.map_or(0, |blob| blob.bytes));
+        Some((path.clone(), result))
+    };
+
+    // Each priority tier is finished before the next one is started
+    progress.phase("files", Some((paths.len() - start) as u64), None);
+    let mut results = Vec::with_capacity(paths.len() - start);
+    for tier in paths[start..].chunk_by(|a, b| a.0 == b.0) {
+        results.par_extend(tier.par_iter().map_init(RangeReader::new, process));
+    }
+
+    // Keep only the unbroken run of processed entries, so the rest can be resumed in order
+    let processed = results
+        .iter()
+        .position(Option::is_none)
+        .unwrap_or(results.len());
+    let truncated = paths[start..]
+        .get(processed)
+        .map(|(_, _, path)| (path.clone(), results.len() - processed));
+    if let Some((ref resume_from, remaining)) = truncated {
+        warn!(
+            ?resume_from,
+            remaining, "Time budget exhausted, catalog will be truncated"
+        );
+    }
+
+    // Collect successful results and handle errors
+    let mut file_infos: Vec<FileInfo> = Vec::new();
+    let mut error_count = 0;
+
+    for (path, result) in results.into_iter().map_while(|result| result) {
+        match result {
+            Ok(info) => file_infos.push(info),
+            Err(err) => {
+                error_count += 1;
+                if args.fatal_errors {
+                    error!(?path, %err, "Fatal error processing file");
+                    return Err(err.into());
+                } else {
+                    warn!(?path, %err, "Skipping file due to error");
+                }
+            }
+        }
+    }
+
+    if error_count > 0 {
+        warn!(error_count, "Some files were skipped due to errors");
+    }
+
+    info!(files = file_infos.len(), "Processed files");
+
+    // Encrypt paths before hashing, so the tree and subtree hashes match the stored paths
+    if let Some(ref cipher) = cipher {
+        info!(key_id = cipher.key_id(), "Encrypting paths");
+        file_infos = file_infos
+            .into_par_iter()
+            .map(|info| cipher.encrypt_file(info))
+            .collect();
+    }
+
+    // Entries carried over from a resumed catalog are already encrypted
+    if let Some(ref mut previous) = previous {
+        file_infos.splice(0..0, previous.files.drain(..));
+    }
+
+    // Encrypt a revealing metadata value, if encryption is enabled
+    let conceal = |value: serde_json::Value| match cipher {
+        Some(ref cipher) => cipher.seal_value(&value),
+        None => value,
+    };
+
+    // Compute tree hash, and the subtree hashes if the scan didn't complete every directory
+    let tree_hash = compute_tree_hash(&file_infos);
+    let tree_hashes = tree_hasher
+        .and_then(|hasher| {
+            let reused = hasher.reused();
+            let hashes = hasher.into_hashes()?;
+            debug!(reused, "Subtree hashes computed during the scan");
+            Some(hashes)
+        })
+        .unwrap_or_else(|| compute_tree_hashes(&file_infos));
+
+    // Create the catalog database
+    progress.phase("write", None, None);
+    let conn = Connection::open(catalog_path)?;
+    create_catalog_schema(&conn)?;
+
+    // Files are written on another thread while the metadata is gathered
+    let mut writer = CatalogWriter::new(conn, WRITE_QUEUE);
+    let mut files = file_infos.into_iter();
+    loop {
+        let batch: Vec<FileInfo> = files.by_ref().take(WRITE_BATCH).collect();
+        if batch.is_empty() {
+            break;
+        }
+        writer.write(batch)?;
+    }
+
+    let created = Timestamp::now();
+
+    // Collect all metadata
+    let mut metadata: HashMap<&str, serde_json::Value> = HashMap::new();
+
+    // Mandatory metadata
+    metadata.insert("protocol", json!(1));
+    metadata.insert("id", json!(catalog_id.simple().to_string()));
+    metadata.insert("machine", json!(machine_id));
+    metadata.insert("tree", json!(tree_hash.as_hex()));
+    metadata.insert("created", json!(created.as_millisecond()));
+
+    // Optional metadata - started, and source_path or roots
+    metadata.insert("started", json!(started.as_millisecond()));
+    if roots.len() > 1 {
+        let roots: serde_json::Map<String, serde_json::Value> = roots
+            .iter()
+            .filter_map(|(prefix, root)| {
+                let prefix = prefix.as_deref()?;
+                let prefix = match cipher {
+                    Some(ref cipher) => cipher.encrypt_path(prefix),
+                    None => prefix.to_string(),
+                };
+                Some((prefix, conceal(json!(root.to_string_lossy()))))
+            })
+            .collect();
+        metadata.insert("roots", json!(roots));
+    } else {
+        metadata.insert("source_path", conceal(json!(source_path.to_string_lossy())));
+    }
+
+    // Optional: where a truncated catalog stopped, and which catalog this one continues
+    if let Some((ref resume_from, remaining)) = truncated {
+        metadata.insert(
+            "truncated",
+            json!({
+                "resume_from": conceal(json!(resume_from.to_string_lossy())),
+                "remaining": remaining,
+            }),
+        );
+    }
+    if let Some(ref previous) = previous {
+        metadata.insert("resumed_from", json!(previous.id));
+    }
+
+    // Optional: the priority patterns entries were ordered by
+    if !priorities.is_empty() {
+        metadata.insert("priority_patterns", conceal(json!(priorities.patterns())));
+    }
+
+    // Optional: what extent information each root's filesystem gave, from its first file
+    let capabilities: Vec<Option<extentria::Capabilities>> = (0..roots.len())
+        .map(|idx| {
+            let (_, _, path) = paths.iter().find(|(_, root, path)| {
+                *root == idx && fs::symlink_metadata(path).is_ok_and(|m| m.is_file())
+            })?;
+            extentria::capabilities_of_path(path)
+                .inspect_err(|err| debug!(?path, %err, "Could not probe extent capabilities"))
+                .ok()
+        })
+        .collect();
+    if capabilities.iter().any(Option::is_some) {
+        metadata.insert("extent_capabilities", json!(capabilities));
+    }
+
+    // Optional: how paths are encrypted
+    if let Some(ref cipher) = cipher {
+        metadata.insert(
+            "path_encryption",
+            json!({ "version": 1, "key_id": cipher.key_id() }),
+        );
+    }
+
+    // Optional: the key extent and blob IDs are derived with
+    if let Some(ref key) = extent_key {
+        metadata.insert(
+            "extent_key",
+            json!({ "version": 1, "key_id": key.key_id() }),
+        );
+    }
+
+    let (conn, stats) = writer.finish()?;
+
+    // Subtree hashes go in first, so finding renames can skip unchanged directories
+    write_tree_hashes(&conn, &tree_hashes)?;
+    conn.execute(
+        "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+        params![
+            "trees",
+            json!({ "version": SUBTREE_HASH_VERSION }).to_string()
+        ],
+    )?;
+
+    // Optional: files renamed since a previous catalog, and which catalog that is
+    if let Some(ref previous_path) = args.previous {
+        let (previous_conn, previous_tempfile) = open_catalog(previous_path)?;
+        let previous_id = metadata_value(&previous_conn, "id");
+        drop(previous_conn);
+
+        let db_path = previous_tempfile
+            .as_ref()
+            .map_or(previous_path.as_path(), |t| t.path());
+        conn.execute(
+            "ATTACH DATABASE ?1 AS previous",
+            [db_path.to_string_lossy().as_ref()],
+        )?;
+        let renames = record_renames(&conn, "previous")?;
+        conn.execute("DETACH DATABASE previous", [])?;
+        info!(
+            renames = renames.len(),
+            "Recorded renames since the previous catalog"
+        );
+
+        if let Some(id) = previous_id {
+            metadata.insert("previous", id);
+        }
+    }
+
+    // Insert mandatory and basic optional metadata
+    for (key, value) in &metadata {
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params![key, value.to_string()],
+        )?;
+    }
+
+    // Optional: backup profile, and for system backups the storage layout
+    if args.profile == Profile::System {
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params!["profile", json!("system").to_string()],
+        )?;
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params!["system", conceal(system_manifest(&source_path)).to_string()],
+        )?;
+    }
+
+    // Optional: catalog name
+    if let Some(ref name) = args.name {
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params!["name", conceal(json!(name)).to_string()],
+        )?;
+    }
+
+    // Optional: machine hostname
+    if let Some(hostname) = get_hostname() {
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params!["machine_hostname", conceal(json!(hostname)).to_string()],
+        )?;
+    }
+
+    // Optional: filesystem info
+    if let Ok(fs_info) = get_fs_info(&source_path) {
+        if let Some(ref fs_type) = fs_info.fs_type {
+            conn.execute(
+                "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+                params!["fs_type", json!(fs_type).to_string()],
+            )?;
+        }
+        if let Some(ref fs_id) = fs_info.fs_id {
+            conn.execute(
+                "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+                params!["fs_id", json!(fs_id).to_string()],
+            )?;
+        }
+    }
+
+    // Optional: how the filesystem compares names, for restoring across platforms
+    if let Ok(rules) = get_name_rules(&source_path) {
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params!["fs_case_sensitive", json!(rules.case_sensitive).to_string()],
+        )?;
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params![
+                "fs_normalization",
+                json!(rules.normalization.as_str()).to_string()
+            ],
+        )?;
+    }
+
+    // Optional: fs_writeable (true if not readonly)
+    if let Ok(readonly) = is_readonly(&source_path)
+        && !readonly
+    {
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params!["fs_writeable", json!(true).to_string()],
+        )?;
+    }
+
+    // User-provided extra metadata
+    for (key, value) in &args.meta {
+        let prefixed_key = format!("extra.{}", key);
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+            params![prefixed_key, conceal(json!(value)).to_string()],
+        )?;
+    }
+
+    // Close the connection before compressing
+    drop(conn);
+
+    // Compress the catalog file
+    if args.compression > 0 {
+        info!(level = args.compression, "Compressing catalog");
+        progress.phase("compress", None, None);
+        let temp_output = tempfile::NamedTempFile::new_in(
+            catalog_path.parent().unwrap_or(std::path::Path::new(".")),
+        )?;
+        compress_file_with_level(catalog_path, temp_output.path(), args.compression)?;
+        temp_output.persist(catalog_path)?;
+    }
+
+    if stdout_catalog.is_some() {
+        let mut stdout = io::stdout().lock();
+        io::copy(&mut File::open(catalog_path)?, &mut stdout)?;
+        stdout.flush()?;
+    }
+
+    progress.complete();
+    let output = match stdout_catalog {
+        Some(_) => "stdout".to_string(),
+        None => format!("{:?}", catalog_path),
+    };
+    let resume_with = match stdout_catalog {
+        Some(_) => "the catalog saved from stdout".to_string(),
+        None => output.clone(),
+    };
+    info!(%output, "Catalog written");
+    eprintln!("Catalog written to {}", output);
+    eprintln!("  ID: {}", catalog_id);
+    eprintln!("  Tree hash: {}", tree_hash.as_hex());
+    eprintln!("  Files: {}", stats.file_count);
+    eprintln!(
+        "  Extents: {} ({} unique)",
+        stats.total_extents, stats.unique_extent_count
+    );
+    eprintln!(
+        "  Total size: {} bytes ({} unique)",
+        stats.total_bytes, stats.unique_bytes
+    );
+    if stats.sparse_bytes > 0 {
+        eprintln!("  Sparse holes: {} bytes", stats.sparse_bytes);
+    }
+    if stats.preallocated_bytes > 0 {
+        eprintln!("  Preallocated: {} bytes", stats.preallocated_bytes);
+    }
+    eprintln!(
+        "  Dedup ratio: {:.2}x ({:.1}% space saved, {} bytes)",
+        stats.dedup_ratio(),
+        stats.space_saved_pct(),
+        stats.space_saved()
+    );
+
+    if let Some((_, remaining)) = truncated {
+        eprintln!(
+            "  Truncated: {} entries left, continue with --resume {}",
+            remaining, resume_with
+        );
+    }
+
+    Ok(())
+}
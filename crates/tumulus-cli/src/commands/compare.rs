@@ -5,7 +5,9 @@ use std::path::PathBuf;
 use clap::Args;
 use tracing::info;
 
-use tumulus::open_catalog;
+use tumulus::{RenameMatch, changed_directories, detect_renames, open_catalog, recorded_renames};
+
+use crate::commands::catalog::metadata_value;
 
 /// Compare two catalogs and report transfer requirements
 #[derive(Args, Debug)]
@@ -15,6 +17,14 @@ pub struct CompareArgs {
 
     /// Remote catalog file (destination)
     remote_catalog: PathBuf,
+
+    /// List each renamed file instead of only counting them
+    #[arg(long)]
+    list_renames: bool,
+
+    /// List each directory whose contents changed instead of only counting them
+    #[arg(long)]
+    list_changed_dirs: bool,
 }
 
 pub fn run(args: CompareArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -141,9 +151,62 @@ pub fn run(args: CompareArgs) -> Result<(), Box<dyn std::error::Error + Send + S
         println!("  Bytes: {}", remote_only_bytes);
     }
 
+    // Files that moved between the catalogs rather than being deleted and re-added, as
+    // recorded when the local catalog was made if that was against the remote one
+    let remote_id = metadata_value(&remote_conn, "id");
+    let renames = if remote_id.is_some() && metadata_value(&local_conn, "previous") == remote_id {
+        recorded_renames(&local_conn)?
+    } else {
+        detect_renames(&local_conn, "remote")?
+    };
+    if !renames.is_empty() {
+        let by_inode = renames
+            .iter()
+            .filter(|r| r.matched_by == RenameMatch::Inode)
+            .count();
+
+        println!();
+        println!("Renamed (remote -> local):");
+        println!("  Files: {}", renames.len());
+        println!("  Same inode: {}", by_inode);
+        println!("  Same contents: {}", renames.len() - by_inode);
+
+        if args.list_renames {
+            for rename in &renames {
+                println!(
+                    "  {} -> {}",
+                    String::from_utf8_lossy(&rename.from),
+                    String::from_utf8_lossy(&rename.to)
+                );
+            }
+        }
+    }
+
+    // Directories whose subtree hash is unchanged don't need looking into at all
+    if let Some(changed) = changed_directories(&local_conn, "remote")? {
+        let total: i64 =
+            local_conn.query_row("SELECT COUNT(*) FROM trees", [], |row| row.get(0))?;
+
+        println!();
+        println!("Directories:");
+        println!("  Changed: {}", changed.len());
+        println!("  Unchanged: {}", total - changed.len() as i64);
+
+        if args.list_changed_dirs {
+            for path in &changed {
+                let path = String::from_utf8_lossy(path);
+                println!("  {}", if path.is_empty() { "." } else { &path });
+            }
+        }
+    }
+
     info!(
         missing_count,
-        missing_bytes, shared_count, shared_bytes, "Comparison complete"
+        missing_bytes,
+        shared_count,
+        shared_bytes,
+        renamed_count = renames.len(),
+        "Comparison complete"
     );
 
     Ok(())
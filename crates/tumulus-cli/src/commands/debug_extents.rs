@@ -1,9 +1,9 @@
 //! Display extent information for files
 
-use std::{fs::File, io, path::PathBuf};
+use std::{fs::File, path::PathBuf};
 
 use clap::Args;
-use extentria::{DataRange, RangeReader, RangeReaderImpl};
+use extentria::{DataRange, ExtentError, RangeReader, RangeReaderImpl};
 use memmap2::Mmap;
 use rayon::prelude::*;
 use tracing::{debug, error, info, warn};
@@ -36,7 +36,7 @@ struct FileResult {
     total_read: u64,
     true_size: u64,
     sparse_bytes: u64,
-    extent_read_error: Option<io::Error>,
+    extent_read_error: Option<ExtentError>,
 }
 
 fn process_file(path: PathBuf) -> Result<FileResult, std::io::Error> {
@@ -63,9 +63,9 @@ fn process_file(path: PathBuf) -> Result<FileResult, std::io::Error> {
     // Get extent information using cross-platform API
     let mut reader = RangeReader::new();
     let mut extent_displays: Vec<ExtentDisplay> = Vec::new();
-    let mut extent_read_error: Option<io::Error> = None;
+    let mut extent_read_error: Option<ExtentError> = None;
 
-    let ranges: Result<Vec<DataRange>, io::Error> = match reader.read_ranges(&file) {
+    let ranges: Result<Vec<DataRange>, ExtentError> = match reader.read_ranges(&file) {
         Ok(iter) => iter.collect(),
         Err(e) => Err(e),
     };
@@ -82,6 +82,15 @@ fn process_file(path: PathBuf) -> Result<FileResult, std::io::Error> {
                         hash: None,
                         bytes_read: 0,
                     });
+                } else if range.unwritten {
+                    extent_displays.push(ExtentDisplay {
+                        logical_offset: range.offset,
+                        length: range.length,
+                        flags: "unwritten".to_string(),
+                        is_sparse: false,
+                        hash: None,
+                        bytes_read: 0,
+                    });
                 } else {
                     let start = (range.offset as usize).min(file_len);
                     let end = (start + range.length as usize).min(file_len);
@@ -146,7 +155,11 @@ pub fn run(args: DebugExtentsArgs) -> Result<(), Box<dyn std::error::Error + Sen
         match result {
             Ok(file_result) => {
                 for ext in &file_result.extents {
-                    let hash_str = ext.hash.as_deref().unwrap_or("(sparse)");
+                    let hash_str = ext.hash.as_deref().unwrap_or(if ext.is_sparse {
+                        "(sparse)"
+                    } else {
+                        "(unwritten)"
+                    });
                     println!(
                         "{}\textent start={:7}\tend={:7}\tsize={:7}\tflags={}\thash={}\tread={}",
                         file_result.path.display(),
@@ -0,0 +1,328 @@
+//! Have the server derive a new catalog's extents from a previous catalog's.
+//!
+//! After a change to how files are chunked, a new catalog of the same tree has different
+//! extents even where nothing changed. Rather than uploading that data again, each new
+//! extent is described as byte ranges of extents the server already has (see
+//! [`tumulus::migrate`]), and the server assembles it with `POST /extents/derive`. The new
+//! catalog is then uploaded as usual, and only has to send what couldn't be derived.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use clap::Args;
+use futures::{StreamExt, stream};
+use reqwest::{
+    Client, StatusCode,
+    header::{AUTHORIZATION, HeaderMap, HeaderValue},
+};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use tumulus::{
+    B3Id, DerivedExtent, SecretSource, SecretsProvider, Transport, open_catalog, plan_migration,
+};
+
+use crate::commands::repository::Destination;
+use crate::commands::upload::{
+    CheckExtentsRequest, CheckExtentsResponse, EXTENT_KEY_HEADER, ErrorResponse,
+};
+
+/// Extents to check for existence per request.
+const CHECK_BATCH: usize = 1000;
+
+/// Derive a catalog's extents on the server from those of a previous catalog of the same
+/// tree, to avoid uploading them again after chunking changed
+#[derive(Args, Debug)]
+pub struct MigrateArgs {
+    /// Previous catalog, whose extents are on the server
+    previous_catalog: PathBuf,
+
+    /// New catalog of the same tree, to be uploaded next
+    catalog: PathBuf,
+
+    #[command(flatten)]
+    destination: Destination,
+
+    /// Bearer token to authenticate to the server with, read from a file path, or from
+    /// `env:NAME`, `file:PATH`, `cmd:COMMAND`, or `keychain:SERVICE/ACCOUNT`
+    #[arg(long, value_name = "SECRET", conflicts_with = "repository")]
+    token: Option<SecretSource>,
+
+    /// Maximum number of derive requests in flight at once
+    #[arg(long, short = 'j', default_value = "8")]
+    parallel: usize,
+
+    /// Only report what could be derived
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DeriveRequest {
+    id: String,
+    segments: Vec<DeriveSegment>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeriveSegment {
+    source: String,
+    offset: u64,
+    length: u64,
+}
+
+impl From<&DerivedExtent> for DeriveRequest {
+    fn from(extent: &DerivedExtent) -> Self {
+        Self {
+            id: extent.id.as_hex(),
+            segments: extent
+                .segments
+                .iter()
+                .map(|slice| DeriveSegment {
+                    source: slice.source.as_hex(),
+                    offset: slice.offset,
+                    length: slice.length,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn run(args: MigrateArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let previous_path = &args.previous_catalog;
+    let current_path = &args.catalog;
+    info!(?previous_path, ?current_path, "Planning extent migration");
+
+    // Open catalogs (automatically decompresses if needed), and compare them over one
+    // connection
+    let (conn, _current_tempfile) = open_catalog(current_path)?;
+    let (_previous_conn, previous_tempfile) = open_catalog(previous_path)?;
+    let previous_db_path = previous_tempfile
+        .as_ref()
+        .map(|t| t.path().to_path_buf())
+        .unwrap_or_else(|| previous_path.clone());
+    conn.execute(
+        "ATTACH DATABASE ?1 AS previous",
+        [previous_db_path.to_string_lossy().as_ref()],
+    )?;
+
+    let plan = plan_migration(&conn, "previous")?;
+    info!(extents = plan.len(), "Found extents to derive");
+    if plan.is_empty() {
+        eprintln!("No extents can be derived from the previous catalog");
+        return Ok(());
+    }
+
+    // Derived extents of a catalog with keyed IDs can only be checked with its key
+    let extent_key_id: Option<String> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'extent_key'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .ok()
+        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+        .and_then(|v| v.get("key_id")?.as_str().map(String::from));
+
+    let transport = build_transport(args.token.as_ref(), extent_key_id.as_deref())?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let connection = args
+            .destination
+            .connect(transport, extent_key_id.as_deref())
+            .await?;
+        migrate(connection.transport(), connection.url(), &plan, &args).await
+    })
+}
+
+async fn migrate(
+    client: &Transport,
+    server_url: &str,
+    plan: &[DerivedExtent],
+    args: &MigrateArgs,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Skip what the server already has, and what it can't derive for lack of sources
+    let stored = check_extents(client, server_url, plan.iter().map(|e| e.id)).await?;
+    let sources = check_extents(
+        client,
+        server_url,
+        plan.iter()
+            .flat_map(|e| &e.segments)
+            .map(|slice| slice.source),
+    )
+    .await?;
+
+    let mut already_stored = 0;
+    let mut unavailable = 0;
+    let mut todo = Vec::new();
+    for extent in plan {
+        if stored.contains(&extent.id) {
+            already_stored += 1;
+        } else if extent.segments.iter().all(|s| sources.contains(&s.source)) {
+            todo.push(extent);
+        } else {
+            unavailable += 1;
+        }
+    }
+    let todo_bytes: u64 = todo.iter().map(|e| e.bytes).sum();
+
+    eprintln!("Extent migration:");
+    eprintln!("  Derivable extents: {}", plan.len());
+    eprintln!("  Already on the server: {}", already_stored);
+    if unavailable > 0 {
+        eprintln!("  Sources missing from the server: {}", unavailable);
+    }
+    if args.dry_run {
+        eprintln!("  Would derive: {} ({} bytes)", todo.len(), todo_bytes);
+        return Ok(());
+    }
+
+    let outcome = derive_extents(client, server_url, todo, args.parallel).await;
+    eprintln!("  Derived: {}", outcome.created);
+    if outcome.failed > 0 {
+        eprintln!("  Failed (will be uploaded instead): {}", outcome.failed);
+    }
+
+    Ok(())
+}
+
+/// What came of asking the server to derive extents.
+#[derive(Debug, Default)]
+pub(crate) struct DeriveOutcome {
+    /// Extents now on the server, whether derived or already there
+    pub stored: HashSet<B3Id>,
+    /// How many extents were derived
+    pub created: usize,
+    /// How many extents the server couldn't derive
+    pub failed: usize,
+}
+
+/// Have the server derive extents, with at most `parallel` requests in flight.
+///
+/// Failures are logged and counted rather than returned, as the extents can still be
+/// uploaded.
+pub(crate) async fn derive_extents(
+    client: &Transport,
+    server_url: &str,
+    extents: Vec<&DerivedExtent>,
+    parallel: usize,
+) -> DeriveOutcome {
+    let url = format!("{}/extents/derive", server_url);
+    let results: Vec<(B3Id, Result<bool, String>)> = stream::iter(extents)
+        .map(|extent| {
+            let url = &url;
+            let result = async move {
+                let resp = client
+                    .send(client.post(url).json(&DeriveRequest::from(extent)))
+                    .await
+                    .map_err(|err| err.to_string())?;
+                match resp.status() {
+                    StatusCode::CREATED => {
+                        debug!(extent = %extent.id.as_hex(), "Derived extent");
+                        Ok(true)
+                    }
+                    status if status.is_success() => Ok(false),
+                    status => {
+                        let reason = match resp.json::<ErrorResponse>().await {
+                            Ok(err) => match err.detail {
+                                Some(detail) => format!("{} - {}", err.error, detail),
+                                None => err.error,
+                            },
+                            Err(_) => status.to_string(),
+                        };
+                        warn!(extent = %extent.id.as_hex(), %reason, "Failed to derive extent");
+                        Err(reason)
+                    }
+                }
+            };
+            async move { (extent.id, result.await) }
+        })
+        .buffer_unordered(parallel.max(1))
+        .collect()
+        .await;
+
+    let mut outcome = DeriveOutcome::default();
+    for (id, result) in results {
+        match result {
+            Ok(created) => {
+                outcome.stored.insert(id);
+                outcome.created += usize::from(created);
+            }
+            Err(_) => outcome.failed += 1,
+        }
+    }
+    outcome
+}
+
+/// Have the server derive the extents of the plan that it's missing and has the sources
+/// of, returning which are now stored.
+pub(crate) async fn derive_available(
+    client: &Transport,
+    server_url: &str,
+    plan: &[DerivedExtent],
+    parallel: usize,
+) -> Result<DeriveOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let sources = check_extents(
+        client,
+        server_url,
+        plan.iter()
+            .flat_map(|e| &e.segments)
+            .map(|slice| slice.source),
+    )
+    .await?;
+    let todo = plan
+        .iter()
+        .filter(|extent| extent.segments.iter().all(|s| sources.contains(&s.source)))
+        .collect();
+    Ok(derive_extents(client, server_url, todo, parallel).await)
+}
+
+/// Ask the server which of some extents it has.
+async fn check_extents(
+    client: &Transport,
+    server_url: &str,
+    ids: impl Iterator<Item = B3Id>,
+) -> Result<HashSet<B3Id>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut seen = HashSet::new();
+    let ids: Vec<String> = ids
+        .filter(|id| seen.insert(*id))
+        .map(|id| id.as_hex())
+        .collect();
+    let url = format!("{}/extents/check", server_url);
+
+    let mut present = HashSet::new();
+    for batch in ids.chunks(CHECK_BATCH) {
+        let batch: Vec<&String> = batch.iter().collect();
+        let resp = client
+            .send(client.post(&url).json(&CheckExtentsRequest { ids: &batch }))
+            .await?;
+        if !resp.status().is_success() {
+            let err: ErrorResponse = resp.json().await?;
+            return Err(format!("server error: {}", err.error).into());
+        }
+        let check: CheckExtentsResponse = resp.json().await?;
+        for (id, exists) in batch.iter().zip(check.exists) {
+            if exists {
+                present.insert(B3Id::try_from(hex::decode(id)?)?);
+            }
+        }
+    }
+    Ok(present)
+}
+
+/// Build the transport to the server, sending the token and the ID of the key extent IDs are derived
+/// with in every request, if there are any.
+fn build_transport(
+    token: Option<&SecretSource>,
+    extent_key_id: Option<&str>,
+) -> Result<Transport, Box<dyn std::error::Error + Send + Sync>> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = token {
+        let mut value = HeaderValue::try_from(format!("Bearer {}", token.fetch_text()?))?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    if let Some(key_id) = extent_key_id {
+        headers.insert(EXTENT_KEY_HEADER, HeaderValue::try_from(key_id)?);
+    }
+    Ok(Transport::new(Client::new()).with_headers(headers))
+}
@@ -0,0 +1,266 @@
+//! Progress reporting.
+//!
+//! By default, progress is logged every few seconds, with how far through the current phase
+//! the command is, its recent throughput, and how long it should take to finish.
+//!
+//! With `--progress json`, commands instead write their progress to stderr as one JSON
+//! object per line, for wrappers to render without parsing logs:
+//!
+//! ```json
+//! {"phase":"extents","done":120,"total":800,"bytes":503316480,"total_bytes":3355443200,"elapsed_secs":12.5,"bytes_per_sec":41943040.0,"eta_secs":68.0}
+//! ```
+//!
+//! Each phase starts with an event where `done` is 0 and ends with one where it has reached
+//! `total` (or stopped short, if the command was cut off by its time budget); in between,
+//! events come at most a few times a second. The `total` and `total_bytes` are absent when
+//! not known ahead of time, as are `bytes_per_sec` (over the last few seconds) and
+//! `eta_secs` until there's enough to estimate from. A final `complete` phase is reported
+//! when the command succeeds, with a `summary` object for commands that have one. Other
+//! lines on stderr are logs.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::info;
+
+/// How often progress events are written, at most, within a phase.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often progress is logged, at most, within a phase.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How far back throughput is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How to report progress.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Only in the logs
+    #[default]
+    Log,
+    /// As JSON lines on stderr
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'s> {
+    phase: &'static str,
+    done: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+    bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_bytes: Option<u64>,
+    elapsed_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_per_sec: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'s serde_json::Value>,
+}
+
+#[derive(Debug)]
+struct Phase {
+    name: &'static str,
+    started: Instant,
+    last_emitted: Instant,
+    done: u64,
+    total: Option<u64>,
+    bytes: u64,
+    total_bytes: Option<u64>,
+    /// Bytes done by some recent times, oldest first, for the rolling throughput
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Phase {
+    fn new(name: &'static str, total: Option<u64>, total_bytes: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            name,
+            started: now,
+            last_emitted: now,
+            done: 0,
+            total,
+            bytes: 0,
+            total_bytes,
+            samples: VecDeque::from([(now, 0)]),
+        }
+    }
+
+    fn sample(&mut self, now: Instant) {
+        if self
+            .samples
+            .back()
+            .is_none_or(|&(at, _)| now.duration_since(at) >= INTERVAL)
+        {
+            self.samples.push_back((now, self.bytes));
+        }
+        while self.samples.len() > 2
+            && self
+                .samples
+                .get(1)
+                .is_some_and(|&(at, _)| now.duration_since(at) >= RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the last few seconds.
+    fn rate(&self) -> Option<f64> {
+        let &(since, bytes_then) = self.samples.front()?;
+        let secs = since.elapsed().as_secs_f64();
+        (secs >= 1.0 && self.bytes > 0).then(|| (self.bytes - bytes_then) as f64 / secs)
+    }
+
+    /// How far through the phase, by bytes if known, as items can vary wildly in size,
+    /// else by count.
+    fn fraction(&self) -> Option<f64> {
+        match (self.total_bytes, self.total) {
+            (Some(total), _) if total > 0 => Some(self.bytes as f64 / total as f64),
+            (_, Some(total)) if total > 0 => Some(self.done as f64 / total as f64),
+            _ => None,
+        }
+    }
+
+    fn event(&self) -> ProgressEvent<'_> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let bytes_per_sec = self.rate();
+
+        // From recent throughput if there's a byte count to go, else by extrapolating
+        let eta_secs = match (self.total_bytes, bytes_per_sec) {
+            (Some(total), Some(rate)) if rate > 0.0 => {
+                Some(total.saturating_sub(self.bytes) as f64 / rate)
+            }
+            _ => self
+                .fraction()
+                .filter(|&fraction| fraction > 0.0)
+                .map(|fraction| (elapsed / fraction - elapsed).max(0.0)),
+        };
+
+        ProgressEvent {
+            phase: self.name,
+            done: self.done,
+            total: self.total,
+            bytes: self.bytes,
+            total_bytes: self.total_bytes,
+            elapsed_secs: elapsed,
+            bytes_per_sec,
+            eta_secs,
+            summary: None,
+        }
+    }
+
+    fn log(&self) {
+        let event = self.event();
+        info!(
+            phase = self.name,
+            done = self.done,
+            total = ?self.total,
+            percent = ?self.fraction().map(|fraction| format!("{:.1}", fraction * 100.0)),
+            bytes_per_sec = ?event.bytes_per_sec.map(|rate| rate as u64),
+            eta_secs = ?event.eta_secs.map(|eta| eta.ceil() as u64),
+            "Progress"
+        );
+    }
+}
+
+/// Reports the progress of a command through its phases.
+///
+/// Safe to advance from several threads at once.
+#[derive(Debug)]
+pub struct Progress {
+    format: ProgressFormat,
+    phase: Mutex<Option<Phase>>,
+}
+
+impl Progress {
+    pub fn new(format: ProgressFormat) -> Self {
+        Self {
+            format,
+            phase: Mutex::new(None),
+        }
+    }
+
+    /// Start a phase, finishing the current one.
+    pub fn phase(&self, name: &'static str, total: Option<u64>, total_bytes: Option<u64>) {
+        let mut phase = self.phase.lock().unwrap();
+        if let Some(current) = phase.take() {
+            self.emit(&current);
+        }
+        let started = Phase::new(name, total, total_bytes);
+        self.emit(&started);
+        *phase = Some(started);
+    }
+
+    /// Count items and bytes done in the current phase.
+    pub fn advance(&self, done: u64, bytes: u64) {
+        let mut phase = self.phase.lock().unwrap();
+        let Some(phase) = phase.as_mut() else {
+            return;
+        };
+        phase.done += done;
+        phase.bytes += bytes;
+
+        let now = Instant::now();
+        phase.sample(now);
+        let interval = match self.format {
+            ProgressFormat::Log => LOG_INTERVAL,
+            ProgressFormat::Json => INTERVAL,
+        };
+        if now.duration_since(phase.last_emitted) >= interval {
+            phase.last_emitted = now;
+            match self.format {
+                ProgressFormat::Log => phase.log(),
+                ProgressFormat::Json => emit(phase.event()),
+            }
+        }
+    }
+
+    /// Finish the current phase, and report that the command is complete.
+    pub fn complete(&self) {
+        self.phase("complete", None, None);
+        *self.phase.lock().unwrap() = None;
+    }
+
+    /// Finish the current phase, and report that the command is complete with a summary of
+    /// what it did.
+    pub fn complete_with(&self, summary: &impl Serialize) {
+        let Ok(summary) = serde_json::to_value(summary) else {
+            return self.complete();
+        };
+        match self.format {
+            ProgressFormat::Log => info!(%summary, "Summary"),
+            ProgressFormat::Json => {
+                let mut phase = self.phase.lock().unwrap();
+                if let Some(current) = phase.take() {
+                    emit(current.event());
+                }
+                emit(ProgressEvent {
+                    summary: Some(&summary),
+                    ..Phase::new("complete", None, None).event()
+                });
+            }
+        }
+        *self.phase.lock().unwrap() = None;
+    }
+
+    /// Report the start or end of a phase; logs only have the periodic updates.
+    fn emit(&self, phase: &Phase) {
+        if self.format == ProgressFormat::Json {
+            emit(phase.event());
+        }
+    }
+}
+
+fn emit(event: ProgressEvent<'_>) {
+    if let Ok(mut line) = serde_json::to_vec(&event) {
+        line.push(b'\n');
+        // Written in one go so lines from different threads don't interleave
+        let _ = std::io::stderr().lock().write_all(&line);
+    }
+}
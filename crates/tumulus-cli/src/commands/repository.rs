@@ -0,0 +1,148 @@
+//! Where commands send data: a server, or a repository directory.
+//!
+//! A repository is a directory laid out as a server's storage, such as on an external
+//! drive or NFS share, for backing up without running a server. Commands given
+//! `--repository` open its storage and upload database in-process, and hand their requests
+//! straight to the server's handlers over it, with no listening socket, so data goes
+//! through the same checks and catalog processing as it would on a server.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::Router;
+use clap::Args;
+use futures::future::BoxFuture;
+use reqwest::Client;
+use tower::ServiceExt as _;
+use tracing::debug;
+
+use tumulus::{LocalHandler, Transport};
+use tumulus_server::{ApiOptions, Server, ServerError, TrustedKeys};
+
+/// Base URL of requests to a repository, which are never resolved.
+const REPOSITORY_URL: &str = "http://repository.invalid";
+
+/// A server or repository to send data to.
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct Destination {
+    /// Server URL (e.g., http://localhost:3000)
+    #[arg(long, short)]
+    server: Option<String>,
+
+    /// Repository directory to write to directly, without a server (created if needed)
+    #[arg(long, value_name = "PATH")]
+    repository: Option<PathBuf>,
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.server, &self.repository) {
+            (Some(url), _) => f.write_str(url),
+            (None, Some(path)) => write!(f, "repository {}", path.display()),
+            (None, None) => f.write_str("nowhere"),
+        }
+    }
+}
+
+impl Destination {
+    /// Get ready to send requests through `transport`: for a repository, open it, trusting
+    /// extents keyed with `extent_key_id` as this process derived them.
+    pub async fn connect(
+        &self,
+        transport: Transport,
+        extent_key_id: Option<&str>,
+    ) -> Result<Connection, ServerError> {
+        let Some(path) = &self.repository else {
+            let url = self.server.as_deref().unwrap_or_default();
+            return Ok(Connection {
+                url: url.trim_end_matches('/').to_string(),
+                transport,
+            });
+        };
+
+        let trusted = match extent_key_id {
+            Some(key_id) => TrustedKeys::Only(vec![key_id.to_string()]),
+            None => TrustedKeys::None,
+        };
+        Ok(Connection {
+            url: REPOSITORY_URL.to_string(),
+            transport: transport.with_local(repository_handler(path, trusted).await?),
+        })
+    }
+}
+
+/// Open a repository directory, to send requests to on its own.
+///
+/// Extents with keyed IDs are stored as they're given, whatever their key, as when copying
+/// them from a server that has accepted them.
+pub async fn open_repository(path: &Path) -> Result<Connection, ServerError> {
+    let handler = repository_handler(path, TrustedKeys::Any).await?;
+    Ok(Connection {
+        url: REPOSITORY_URL.to_string(),
+        transport: Transport::new(Client::new()).with_local(handler),
+    })
+}
+
+/// Open a repository directory, to handle this process's requests in-process.
+///
+/// The only client is this process, so keyed extents can be trusted as it says.
+async fn repository_handler(
+    path: &Path,
+    trusted_extent_keys: TrustedKeys,
+) -> Result<Arc<dyn LocalHandler>, ServerError> {
+    let options = ApiOptions {
+        trusted_extent_keys,
+        ..Default::default()
+    };
+    let router = Server::builder()
+        .fs_storage(path)
+        .options(options)
+        .router()
+        .await?;
+    debug!(repository = ?path, "Opened repository");
+    Ok(Arc::new(Repository { router }))
+}
+
+/// The server's handlers over a repository.
+struct Repository {
+    router: Router,
+}
+
+impl LocalHandler for Repository {
+    fn handle(
+        &self,
+        request: reqwest::Request,
+    ) -> BoxFuture<'static, reqwest::Result<reqwest::Response>> {
+        let router = self.router.clone();
+        Box::pin(async move {
+            let request = axum::http::Request::try_from(request)?.map(axum::body::Body::new);
+            let response = match router.oneshot(request).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            Ok(response
+                .map(|body| reqwest::Body::wrap_stream(body.into_data_stream()))
+                .into())
+        })
+    }
+}
+
+/// A destination ready for requests.
+pub struct Connection {
+    url: String,
+    transport: Transport,
+}
+
+impl Connection {
+    /// Base URL to send requests to, without a trailing slash.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// What to send requests with.
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+}
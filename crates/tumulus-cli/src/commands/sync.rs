@@ -0,0 +1,409 @@
+//! Copy catalogs between a local repository and a server.
+//!
+//! For backing up locally and replicating offsite: catalogs uploaded into a repository with
+//! `upload --repository` are pushed to a server, and optionally the server's catalogs are
+//! pulled into the repository. The repository's handlers run in-process as for uploads, so
+//! both ends are spoken to over the same API. Only complete catalogs are copied, and when given
+//! a catalog, the receiving end says which of its extents it's missing, so only those are
+//! sent.
+
+use std::collections::HashSet;
+use std::io::Write as _;
+use std::path::PathBuf;
+
+use clap::Args;
+use futures::{StreamExt, TryStreamExt, stream};
+use reqwest::{
+    Body, Client, Response, StatusCode,
+    header::{AUTHORIZATION, CONTENT_LENGTH, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use tumulus::{SecretError, SecretSource, SecretsProvider, Transport, open_catalog};
+
+use crate::commands::catalog::metadata_value;
+use crate::commands::repository::open_repository;
+use crate::commands::upload::{EXTENT_KEY_HEADER, ErrorResponse};
+
+/// Catalogs to check for existence per request.
+const CHECK_BATCH: usize = 1000;
+
+/// Copy catalogs missing from a server there from a local repository, and optionally the
+/// other way around
+#[derive(Args, Debug)]
+pub struct SyncArgs {
+    /// Repository directory to sync (created if needed)
+    #[arg(long, value_name = "PATH")]
+    repository: PathBuf,
+
+    /// Server URL (e.g., http://localhost:3000)
+    #[arg(long, short)]
+    server: String,
+
+    /// Bearer token to authenticate to the server with, read from a file path, or from
+    /// `env:NAME`, `file:PATH`, `cmd:COMMAND`, or `keychain:SERVICE/ACCOUNT`
+    #[arg(long, value_name = "SECRET")]
+    token: Option<SecretSource>,
+
+    /// Also copy catalogs the repository is missing from the server
+    #[arg(long)]
+    pull: bool,
+
+    /// Maximum number of extent copies in flight at once
+    #[arg(long, short = 'j', default_value = "8")]
+    parallel: usize,
+
+    /// Only report which catalogs would be copied
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum SyncError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Server error: {error}{}", detail.as_ref().map(|d| format!(" - {}", d)).unwrap_or_default())]
+    Server {
+        error: String,
+        detail: Option<String>,
+    },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to open repository: {0}")]
+    Repository(#[from] tumulus_server::ServerError),
+
+    #[error("Failed to get secret: {0}")]
+    Secret(#[from] SecretError),
+
+    #[error("Server token contains characters not allowed in an HTTP header")]
+    InvalidToken,
+
+    #[error("Invalid ID in response: {0}")]
+    InvalidId(String),
+
+    #[error("Catalog {catalog} doesn't match its checksum: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        catalog: Uuid,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(
+        "Catalog {original} conflicts with a different catalog at {to}, which gave it ID {new}"
+    )]
+    IdChanged {
+        original: Uuid,
+        new: Uuid,
+        to: String,
+    },
+
+    #[error("Catalog {catalog} is still missing {missing} extents at {to}")]
+    Incomplete {
+        catalog: Uuid,
+        missing: usize,
+        to: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct InitiateRequest {
+    id: Uuid,
+    checksum: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct InitiateResponse {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadResponse {
+    missing_extents: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckCatalogsRequest<'a> {
+    ids: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckCatalogsResponse {
+    existing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FinalizeResponse {
+    #[serde(default)]
+    missing_extents: Option<Vec<String>>,
+}
+
+/// One end of a sync.
+struct Endpoint<'a> {
+    client: &'a Transport,
+    url: &'a str,
+    /// What to call it in messages.
+    name: &'a str,
+}
+
+/// What copying catalogs one way did.
+#[derive(Debug, Default)]
+struct CopySummary {
+    catalogs: usize,
+    extents: usize,
+    bytes: u64,
+}
+
+pub fn run(args: SyncArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server_client = Transport::new(build_client(args.token.as_ref())?);
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let connection = open_repository(&args.repository).await?;
+        let repository = Endpoint {
+            client: connection.transport(),
+            url: connection.url(),
+            name: "the repository",
+        };
+        let server = Endpoint {
+            client: &server_client,
+            url: args.server.trim_end_matches('/'),
+            name: &args.server,
+        };
+
+        eprintln!(
+            "Sync of {} with {}:",
+            args.repository.display(),
+            args.server
+        );
+        let pushed = sync(&repository, &server, &args).await?;
+        report("Pushed", &pushed, args.dry_run);
+        if args.pull {
+            let pulled = sync(&server, &repository, &args).await?;
+            report("Pulled", &pulled, args.dry_run);
+        }
+        Ok(())
+    })
+}
+
+fn report(what: &str, summary: &CopySummary, dry_run: bool) {
+    if dry_run {
+        eprintln!("  {what} (dry run): {} catalogs", summary.catalogs);
+    } else {
+        eprintln!(
+            "  {what}: {} catalogs, {} extents ({} bytes)",
+            summary.catalogs, summary.extents, summary.bytes
+        );
+    }
+}
+
+/// Copy the complete catalogs of `from` that `to` doesn't have, oldest first.
+async fn sync(
+    from: &Endpoint<'_>,
+    to: &Endpoint<'_>,
+    args: &SyncArgs,
+) -> Result<CopySummary, SyncError> {
+    let listed = list_catalogs(from).await?;
+    // Newest first, so reversed to copy in the order they were made
+    let mut todo = check_catalogs(from, &listed).await?;
+    todo.reverse();
+    let present: HashSet<String> = check_catalogs(to, &todo).await?.into_iter().collect();
+    todo.retain(|id| !present.contains(id));
+    info!(
+        from = from.name,
+        to = to.name,
+        catalogs = todo.len(),
+        "Found catalogs to copy"
+    );
+
+    let mut summary = CopySummary::default();
+    for id in &todo {
+        let id = Uuid::parse_str(id).map_err(|_| SyncError::InvalidId(id.clone()))?;
+        if !args.dry_run {
+            let (extents, bytes) = copy_catalog(from, to, id, args.parallel).await?;
+            summary.extents += extents;
+            summary.bytes += bytes;
+        }
+        summary.catalogs += 1;
+    }
+    Ok(summary)
+}
+
+/// Copy a catalog and the extents the receiving end is missing for it.
+///
+/// Returns how many extents were copied, and their bytes.
+async fn copy_catalog(
+    from: &Endpoint<'_>,
+    to: &Endpoint<'_>,
+    id: Uuid,
+    parallel: usize,
+) -> Result<(usize, u64), SyncError> {
+    let resp = checked(
+        from.client
+            .send(
+                from.client
+                    .get(format!("{}/catalogs/{}", from.url, id.simple())),
+            )
+            .await?,
+    )
+    .await?;
+    let expected = resp
+        .headers()
+        .get("x-catalog-checksum")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let data = resp.bytes().await?;
+    let checksum = blake3::hash(&data).to_hex().to_string();
+    if let Some(expected) = expected
+        && expected != checksum
+    {
+        return Err(SyncError::ChecksumMismatch {
+            catalog: id,
+            expected,
+            actual: checksum,
+        });
+    }
+    let extent_key_id = read_extent_key_id(&data)?;
+
+    // Resuming a copy that was interrupted is the same as starting it: uploading the
+    // catalog again gets the extents still missing
+    let request = to
+        .client
+        .post(format!("{}/catalogs", to.url))
+        .json(&InitiateRequest { id, checksum });
+    let resp = to.client.send(request).await?;
+    let initiated: InitiateResponse = checked(resp).await?.json().await?;
+    let new_id =
+        Uuid::parse_str(&initiated.id).map_err(|_| SyncError::InvalidId(initiated.id.clone()))?;
+    if new_id != id {
+        return Err(SyncError::IdChanged {
+            original: id,
+            new: new_id,
+            to: to.name.to_string(),
+        });
+    }
+
+    let catalog_url = format!("{}/catalogs/{}", to.url, id.simple());
+    let request = to
+        .client
+        .put(&catalog_url)
+        .header("Content-Type", "application/octet-stream")
+        .body(data);
+    let resp = checked(to.client.send(request).await?).await?;
+    let missing = resp.json::<UploadResponse>().await?.missing_extents;
+    debug!(catalog = %id.simple(), missing = missing.len(), "Copying catalog");
+
+    let bytes: Vec<u64> = stream::iter(&missing)
+        .map(|extent| copy_extent(from, to, extent, extent_key_id.as_deref()))
+        .buffer_unordered(parallel.max(1))
+        .try_collect()
+        .await?;
+
+    let resp = checked(to.client.send(to.client.post(&catalog_url)).await?).await?;
+    if resp.status() != StatusCode::NO_CONTENT {
+        let finalized: FinalizeResponse = resp.json().await?;
+        return Err(SyncError::Incomplete {
+            catalog: id,
+            missing: finalized.missing_extents.map_or(0, |m| m.len()),
+            to: to.name.to_string(),
+        });
+    }
+
+    info!(catalog = %id.simple(), from = from.name, to = to.name, "Copied catalog");
+    Ok((bytes.len(), bytes.iter().sum()))
+}
+
+/// Stream an extent from one end to the other, returning its size.
+async fn copy_extent(
+    from: &Endpoint<'_>,
+    to: &Endpoint<'_>,
+    extent: &str,
+    extent_key_id: Option<&str>,
+) -> Result<u64, SyncError> {
+    let resp = checked(
+        from.client
+            .send(from.client.get(format!("{}/extents/{}", from.url, extent)))
+            .await?,
+    )
+    .await?;
+    let size = resp.content_length().unwrap_or_default();
+
+    let mut request = to
+        .client
+        .put(format!("{}/extents/{}", to.url, extent))
+        .header(CONTENT_LENGTH, size)
+        .body(Body::wrap_stream(resp.bytes_stream()));
+    if let Some(key_id) = extent_key_id {
+        request = request.header(EXTENT_KEY_HEADER, key_id);
+    }
+    checked(to.client.send(request).await?).await?;
+    debug!(extent, size, "Copied extent");
+    Ok(size)
+}
+
+/// IDs of all catalogs stored at an end.
+async fn list_catalogs(end: &Endpoint<'_>) -> Result<Vec<String>, SyncError> {
+    let resp = checked(
+        end.client
+            .send(end.client.get(format!("{}/catalogs", end.url)))
+            .await?,
+    )
+    .await?;
+    Ok(resp.json().await?)
+}
+
+/// Which of some catalogs are complete at an end, newest first.
+async fn check_catalogs(end: &Endpoint<'_>, ids: &[String]) -> Result<Vec<String>, SyncError> {
+    let mut existing = Vec::new();
+    for batch in ids.chunks(CHECK_BATCH) {
+        let request = end
+            .client
+            .post(format!("{}/catalogs/check", end.url))
+            .json(&CheckCatalogsRequest { ids: batch });
+        let resp = checked(end.client.send(request).await?).await?;
+        existing.extend(resp.json::<CheckCatalogsResponse>().await?.existing);
+    }
+    Ok(existing)
+}
+
+/// Read the ID of the key a catalog's extent IDs are derived with, if any.
+fn read_extent_key_id(data: &[u8]) -> Result<Option<String>, SyncError> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(data)?;
+    let (conn, _tempfile) = open_catalog(file.path())?;
+    Ok(metadata_value(&conn, "extent_key")
+        .and_then(|v| v.get("key_id")?.as_str().map(String::from)))
+}
+
+/// Turn an error response into an error.
+async fn checked(resp: Response) -> Result<Response, SyncError> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    Err(match resp.json::<ErrorResponse>().await {
+        Ok(err) => SyncError::Server {
+            error: err.error,
+            detail: err.detail,
+        },
+        Err(_) => SyncError::Server {
+            error: status.to_string(),
+            detail: None,
+        },
+    })
+}
+
+/// Build the HTTP client for the server, sending the token in every request.
+fn build_client(token: Option<&SecretSource>) -> Result<Client, SyncError> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = token {
+        let mut value = HeaderValue::try_from(format!("Bearer {}", token.fetch_text()?))
+            .map_err(|_| SyncError::InvalidToken)?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Ok(Client::builder().default_headers(headers).build()?)
+}
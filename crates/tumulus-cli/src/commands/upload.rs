@@ -0,0 +1,1783 @@
+//! Upload catalogs to a tumulus server.
+//!
+//! This command takes a catalog file, verifies it matches the local machine,
+//! and uploads it to a tumulus server, or straight into a repository directory.
+//!
+//! Supports delta uploads using `--reference` to specify previous catalog files.
+//! When references are provided and the server knows one of them, a binary patch
+//! is generated and uploaded instead of the full catalog.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use futures::{StreamExt, TryStreamExt, stream};
+use jiff::SignedDuration;
+use reqwest::{
+    Client,
+    header::{AUTHORIZATION, HeaderMap, HeaderValue},
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use tumulus::{
+    B3Id, BlobIndex, CatalogCipher, CipherError, ExtentKey, ExtentUploader, SecretError,
+    SecretSource, SecretsProvider, Transport, UploadExtentError, decompress_file,
+    is_zstd_compressed, open_catalog, plan_migration, stream_path,
+};
+
+use crate::commands::catalog::{parse_duration, parse_key_value};
+use crate::commands::migrate::derive_available;
+use crate::commands::progress::{Progress, ProgressFormat};
+use crate::commands::repository::Destination;
+
+/// Header declaring which key extent IDs are derived with.
+pub(crate) const EXTENT_KEY_HEADER: &str = "x-extent-key";
+
+/// Upload a catalog to a tumulus server
+#[derive(Args, Debug)]
+pub struct UploadArgs {
+    /// Path to the catalog file to upload, or `-` to read it from stdin
+    catalog: PathBuf,
+
+    #[command(flatten)]
+    destination: Destination,
+
+    /// Skip machine ID verification
+    #[arg(long)]
+    skip_machine_check: bool,
+
+    /// Override the source path from the catalog with a different path
+    #[arg(long)]
+    override_source: Option<PathBuf>,
+
+    /// Override the path of one root of a multi-root catalog, in PREFIX=PATH format
+    /// (can be specified multiple times)
+    #[arg(long, value_parser = parse_key_value)]
+    override_root: Vec<(String, String)>,
+
+    /// Key for a catalog with encrypted paths, as given to `catalog --encrypt-key`
+    #[arg(long, value_name = "SECRET")]
+    key: Option<SecretSource>,
+
+    /// Secret for a catalog with keyed extent IDs, as given to `catalog --extent-key`
+    #[arg(long, value_name = "SECRET")]
+    extent_key: Option<SecretSource>,
+
+    /// Bearer token to authenticate to the server with, read from a file path, or from
+    /// `env:NAME`, `file:PATH`, `cmd:COMMAND`, or `keychain:SERVICE/ACCOUNT`
+    #[arg(long, value_name = "SECRET", conflicts_with = "repository")]
+    token: Option<SecretSource>,
+
+    /// Maximum number of extent uploads in flight at once (default: 32)
+    #[arg(long, short = 'j', default_value = "32")]
+    parallel: usize,
+
+    /// Speak HTTP/2 to the server without negotiating it first, for plain http:// URLs.
+    /// Over https:// HTTP/2 is negotiated automatically. With HTTP/2, all extent uploads
+    /// are multiplexed as streams over a single connection.
+    #[arg(long)]
+    http2: bool,
+
+    /// How long to keep idle connections to the server open, in seconds
+    #[arg(long, default_value = "90")]
+    keep_alive: u64,
+
+    /// Stop starting new extent uploads after this long (like `90m` or `2h 30m`), finishing
+    /// those in flight. Running the upload again resumes where it stopped. With
+    /// `--estimate`, abort before uploading anything if it's estimated to take longer.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Before uploading, estimate how much data will be sent and how long it will take, by
+    /// asking the server which extents it's missing and measuring the link to it
+    #[arg(long)]
+    estimate: bool,
+
+    /// Abort before uploading anything if more than this much data (like `500M` or `20G`,
+    /// in binary multiples) is estimated to be sent. Implies `--estimate`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_transfer: Option<u64>,
+
+    /// Reference catalogs to use for delta uploads.
+    /// When provided, the tool will check if the server knows any of these catalogs
+    /// and use the most recent one to generate a binary patch instead of uploading
+    /// the full catalog.
+    #[arg(long, short = 'r')]
+    reference: Vec<PathBuf>,
+
+    /// Before uploading missing extents, have the server derive those it can from the
+    /// extents of the reference catalogs at the same place in the same files, like after
+    /// a change to how files are chunked
+    #[arg(long, requires = "reference")]
+    derive: bool,
+
+    /// Index of uploaded blobs to keep, created if needed: once the upload completes, the
+    /// catalog's blobs are recorded in it as being on this server. `catalog --blob-index`
+    /// then skips hashing the extents of files identical to them, and `--estimate` doesn't
+    /// ask the server about extents it's known to have.
+    #[arg(long, value_name = "PATH")]
+    blob_index: Option<PathBuf>,
+
+    /// Also report progress as JSON lines on stderr, for wrappers to display
+    #[arg(long, value_enum, default_value_t)]
+    progress: ProgressFormat,
+}
+
+/// Request body for initiating a catalog upload.
+#[derive(Debug, Serialize)]
+struct InitiateRequest {
+    id: Uuid,
+    checksum: String,
+}
+
+/// Response from initiating a catalog upload.
+#[derive(Debug, Deserialize)]
+struct InitiateResponse {
+    id: String,
+    resuming: bool,
+    #[serde(default)]
+    missing_extents: Option<Vec<String>>,
+}
+
+/// Response from uploading a catalog.
+#[derive(Debug, Deserialize)]
+struct UploadResponse {
+    missing_extents: Vec<String>,
+}
+
+/// Request body for checking catalog existence.
+#[derive(Debug, Serialize)]
+struct CheckCatalogsRequest {
+    ids: Vec<String>,
+    /// Sample of the extents of the catalog being uploaded, see [`catalog_sketch`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sketch: Option<Vec<SketchExtent>>,
+}
+
+/// An extent in a catalog sketch.
+#[derive(Debug, Serialize)]
+struct SketchExtent {
+    id: String,
+    bytes: u64,
+}
+
+/// Response from checking catalog existence.
+/// Returns catalog IDs sorted by preference (best choice first).
+#[derive(Debug, Deserialize)]
+struct CheckCatalogsResponse {
+    existing: Vec<String>,
+    /// How much of the sketch each existing catalog shares, if one was sent
+    #[serde(default)]
+    shared: Option<Vec<SharedSummary>>,
+}
+
+/// How much of the sketch an existing catalog shares.
+#[derive(Debug, Deserialize)]
+struct SharedSummary {
+    id: String,
+    extents: u64,
+    bytes: u64,
+}
+
+/// Extents with IDs below this byte go in the sketch: about one in sixteen.
+const SKETCH_BELOW: u8 = 0x10;
+
+/// Most extents to send in a sketch.
+const SKETCH_MAX: i64 = 4096;
+
+/// Metadata about a reference catalog on disk.
+#[derive(Debug, Clone)]
+struct ReferenceCatalogInfo {
+    path: PathBuf,
+    id: Uuid,
+}
+
+/// Request body for batch checking extent existence.
+#[derive(Debug, Serialize)]
+pub(crate) struct CheckExtentsRequest<'a> {
+    pub ids: &'a [&'a String],
+}
+
+/// Response from batch checking extent existence, in request order.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CheckExtentsResponse {
+    pub exists: Vec<bool>,
+}
+
+/// Response from finalizing a catalog.
+#[derive(Debug, Deserialize)]
+struct FinalizeResponse {
+    complete: bool,
+    #[serde(default)]
+    missing_extents: Option<Vec<String>>,
+}
+
+/// Error response from the server.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorResponse {
+    pub error: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum UploadError {
+    #[error("Failed to open catalog: {0}")]
+    OpenCatalog(String),
+
+    #[error("Machine ID mismatch: catalog is for '{catalog}', but this machine is '{local}'")]
+    MachineIdMismatch { catalog: String, local: String },
+
+    #[error("Source path does not exist: {0}")]
+    SourcePathNotFound(PathBuf),
+
+    #[error("Missing metadata in catalog: {0}")]
+    MissingMetadata(String),
+
+    #[error("Invalid metadata value: {0}")]
+    InvalidMetadata(String),
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Server error: {error}{}", detail.as_ref().map(|d| format!(" - {}", d)).unwrap_or_default())]
+    Server {
+        error: String,
+        detail: Option<String>,
+    },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error(
+        "Catalog ID changed by server from {original} to {new}. Please update the catalog and retry."
+    )]
+    IdChanged { original: Uuid, new: Uuid },
+
+    #[error(
+        "Extent {extent_id} has changed on disk: expected hash {expected}, got {actual}. Aborting upload."
+    )]
+    ExtentChanged {
+        extent_id: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(transparent)]
+    Extent(#[from] UploadExtentError),
+
+    #[error("Extent {extent_id} not found in catalog")]
+    ExtentNotInCatalog { extent_id: String },
+
+    #[error("File not found for extent {extent_id}: {path}")]
+    FileNotFound { extent_id: String, path: PathBuf },
+
+    #[error("Failed to read reference catalog: {0}")]
+    ReferenceCatalog(String),
+
+    #[error("Binary diff error: {0}")]
+    BinaryDiff(String),
+
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    #[error("Catalog paths are encrypted with key {0}, use --key to provide it")]
+    KeyRequired(String),
+
+    #[error("Catalog paths are encrypted with key {catalog}, but the given key is {given}")]
+    WrongKey { catalog: String, given: String },
+
+    #[error("Catalog extent IDs are keyed with key {0}, use --extent-key to provide it")]
+    ExtentKeyRequired(String),
+
+    #[error("Catalog extent IDs are keyed with key {catalog}, but the given key is {given}")]
+    WrongExtentKey { catalog: String, given: String },
+
+    #[error("Failed to decrypt catalog: {0}")]
+    Decryption(#[from] CipherError),
+
+    #[error("Failed to get secret: {0}")]
+    Secret(#[from] SecretError),
+
+    #[error("Failed to open repository: {0}")]
+    Repository(#[from] tumulus_server::ServerError),
+
+    #[error("Server token contains characters not allowed in an HTTP header")]
+    InvalidToken,
+
+    #[error("Upload would send an estimated {estimated} bytes, more than the limit of {limit}")]
+    TransferTooLarge { estimated: u64, limit: u64 },
+
+    #[error(
+        "Upload would take an estimated {}, longer than the limit of {}",
+        format_duration(*estimated),
+        format_duration(*limit)
+    )]
+    TooSlow {
+        estimated: Duration,
+        limit: Duration,
+    },
+}
+
+/// Metadata extracted from the catalog.
+struct CatalogMetadata {
+    id: Uuid,
+    machine_id: String,
+    source_path: Option<PathBuf>,
+    /// Source roots by prefix, for multi-root catalogs.
+    roots: Option<HashMap<String, PathBuf>>,
+    /// ID of the key the catalog's paths are encrypted with, if any.
+    key_id: Option<String>,
+    /// ID of the key the catalog's extent IDs are derived with, if any.
+    extent_key_id: Option<String>,
+}
+
+/// Information about where to find an extent on disk.
+#[derive(Debug, Clone)]
+struct ExtentLocation {
+    /// Prefix of the source root containing the file (empty for single-root catalogs)
+    root: String,
+    /// Path to the file containing this extent, relative to its source root
+    file_path: String,
+    /// Offset within the file where the extent starts
+    offset: u64,
+    /// Length of the extent in bytes
+    length: u64,
+    /// Priority rank of the file (lower first), if the catalog has priorities
+    priority: Option<u32>,
+    /// Secondary stream of the file containing this extent (like a resource fork), if it's
+    /// not in the file's main data
+    stream: Option<String>,
+}
+
+/// What an upload did, reported when it ends for monitoring across runs.
+#[derive(Debug, Default, Serialize)]
+struct UploadSummary {
+    /// Whether the upload finished, rather than stopping at the time budget
+    complete: bool,
+    /// Bytes of catalog sent: the file, a patch against a reference, or none when resuming
+    catalog_bytes: u64,
+    /// How many extents were sent
+    extents_sent: u64,
+    /// Bytes of extents sent
+    extent_bytes_sent: u64,
+    /// How many of the catalog's extents didn't need sending, as the server had them,
+    /// derived them, or got them in an earlier run
+    extents_deduplicated: u64,
+    /// Bytes of extents that didn't need sending
+    bytes_deduplicated: u64,
+    /// How many extents the server derived from reference catalogs
+    extents_derived: u64,
+    /// All bytes sent, catalog and extents
+    bytes_sent: u64,
+    /// How long the upload took
+    elapsed_secs: f64,
+    /// Average upload throughput over the whole upload
+    bytes_per_sec: f64,
+    /// How many more rounds of extent uploads were needed after the first, when
+    /// finalizing found extents still missing
+    retries: u64,
+}
+
+impl UploadSummary {
+    /// Fill in the totals from the counts, once the upload is over.
+    fn finish(
+        mut self,
+        started: Instant,
+        extent_locations: &HashMap<String, ExtentLocation>,
+    ) -> Self {
+        let total_extents = extent_locations.len() as u64;
+        let total_bytes: u64 = extent_locations
+            .values()
+            .map(|location| location.length)
+            .sum();
+        if self.complete {
+            self.extents_deduplicated = total_extents.saturating_sub(self.extents_sent);
+            self.bytes_deduplicated = total_bytes.saturating_sub(self.extent_bytes_sent);
+        }
+        self.bytes_sent = self.catalog_bytes + self.extent_bytes_sent;
+        self.elapsed_secs = started.elapsed().as_secs_f64();
+        if self.elapsed_secs > 0.0 {
+            self.bytes_per_sec = self.bytes_sent as f64 / self.elapsed_secs;
+        }
+        self
+    }
+}
+
+/// Copy stdin to a temporary file, which is deleted when dropped.
+fn buffer_stdin() -> Result<tempfile::NamedTempFile, UploadError> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    let bytes = std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+    file.flush()?;
+    if bytes == 0 {
+        return Err(UploadError::OpenCatalog("no catalog on stdin".to_string()));
+    }
+    debug!(bytes, path = ?file.path(), "Buffered catalog from stdin");
+    Ok(file)
+}
+
+pub fn run(args: UploadArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    if let Err(e) = runtime.block_on(run_inner(args)) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run_inner(mut args: UploadArgs) -> Result<(), UploadError> {
+    let started = Instant::now();
+    let deadline = args.max_duration.map(|budget| started + budget);
+    let progress = Progress::new(args.progress);
+    let mut summary = UploadSummary::default();
+    info!(catalog = ?args.catalog, destination = %args.destination, "Starting catalog upload");
+
+    // The catalog is read more than once, so a piped one is buffered to a file first
+    let _stdin_catalog = if args.catalog == Path::new("-") {
+        let buffered = buffer_stdin()?;
+        args.catalog = buffered.path().to_path_buf();
+        Some(buffered)
+    } else {
+        None
+    };
+
+    // Open and read catalog metadata
+    let (conn, _tempfile) =
+        open_catalog(&args.catalog).map_err(|e| UploadError::OpenCatalog(e.to_string()))?;
+
+    let cipher = args
+        .key
+        .as_ref()
+        .map(|key| CatalogCipher::from_secret(key))
+        .transpose()?;
+    let metadata = read_catalog_metadata(&conn, cipher.as_ref())?;
+    let cipher = match cipher {
+        Some(_) if metadata.key_id.is_none() => {
+            warn!("Catalog paths are not encrypted, ignoring --key");
+            None
+        }
+        cipher => cipher,
+    };
+    let extent_key = args
+        .extent_key
+        .as_ref()
+        .map(|key| ExtentKey::from_secret(key))
+        .transpose()?;
+    let extent_key = match (&metadata.extent_key_id, extent_key) {
+        (Some(key_id), None) => return Err(UploadError::ExtentKeyRequired(key_id.clone())),
+        (Some(key_id), Some(key)) if key.key_id() != key_id => {
+            return Err(UploadError::WrongExtentKey {
+                catalog: key_id.clone(),
+                given: key.key_id().to_string(),
+            });
+        }
+        (Some(_), key) => key,
+        (None, Some(_)) => {
+            warn!("Catalog extent IDs are not keyed, ignoring --extent-key");
+            None
+        }
+        (None, None) => None,
+    };
+    info!(
+        catalog_id = %metadata.id,
+        machine_id = %metadata.machine_id,
+        source_path = ?metadata.source_path,
+        "Read catalog metadata"
+    );
+
+    // Verify machine ID matches
+    if !args.skip_machine_check {
+        let local_machine_id = tumulus::get_machine_id()
+            .map_err(|e| UploadError::OpenCatalog(format!("Failed to get machine ID: {}", e)))?;
+
+        if metadata.machine_id != local_machine_id {
+            return Err(UploadError::MachineIdMismatch {
+                catalog: metadata.machine_id,
+                local: local_machine_id,
+            });
+        }
+        debug!("Machine ID verified");
+    } else {
+        warn!("Skipping machine ID verification");
+    }
+
+    // Determine the source root(s) to use
+    let source_roots = resolve_source_roots(&args, &metadata, cipher.as_ref())?;
+
+    // Verify source paths exist
+    for source_path in source_roots.values() {
+        if !source_path.exists() {
+            return Err(UploadError::SourcePathNotFound(source_path.clone()));
+        }
+        debug!(path = ?source_path, "Source path verified");
+    }
+
+    // Build extent location map from catalog
+    let extent_locations =
+        build_extent_location_map(&conn, metadata.roots.is_some(), cipher.as_ref())?;
+    info!(
+        extent_count = extent_locations.len(),
+        "Built extent location map"
+    );
+
+    // Compute checksum of the catalog file
+    let catalog_data = fs::read(&args.catalog)?;
+    let checksum = blake3::hash(&catalog_data);
+    let checksum_hex = checksum.to_hex().to_string();
+    info!(checksum = %checksum_hex, size = catalog_data.len(), "Computed catalog checksum");
+
+    // Create HTTP client, shared by all requests so connections are reused
+    let transport = build_transport(&args, metadata.extent_key_id.as_deref())?;
+    let connection = args
+        .destination
+        .connect(transport, metadata.extent_key_id.as_deref())
+        .await?;
+    let client = connection.transport();
+    let server_url = connection.url();
+
+    let mut blob_index = args
+        .blob_index
+        .as_deref()
+        .map(BlobIndex::open)
+        .transpose()?;
+    let destination = args.destination.to_string();
+
+    if args.estimate || args.max_transfer.is_some() {
+        let known = match blob_index {
+            Some(ref index) => index.extents_on(&destination)?,
+            None => HashSet::new(),
+        };
+        let estimate = estimate_upload(
+            client,
+            server_url,
+            &extent_locations,
+            &known,
+            catalog_data.len() as u64,
+            args.parallel,
+        )
+        .await?;
+
+        eprintln!("Upload estimate:");
+        eprintln!(
+            "  Extents to send: {} of {}",
+            estimate.extents,
+            extent_locations.len()
+        );
+        eprintln!("  Bytes to send: {}", estimate.bytes);
+        match estimate.duration {
+            Some(duration) => eprintln!("  Time: {}", format_duration(duration)),
+            None => eprintln!("  Time: unknown (server can't be probed)"),
+        }
+
+        if let Some(limit) = args.max_transfer
+            && estimate.bytes > limit
+        {
+            return Err(UploadError::TransferTooLarge {
+                estimated: estimate.bytes,
+                limit,
+            });
+        }
+        if let (Some(limit), Some(estimated)) = (args.max_duration, estimate.duration)
+            && estimated > limit
+        {
+            return Err(UploadError::TooSlow { estimated, limit });
+        }
+    }
+
+    // Step 1: Initiate upload
+    info!("Initiating upload with server");
+    let initiate_resp = initiate_upload(client, server_url, metadata.id, &checksum_hex).await?;
+
+    // Check if server assigned a different ID
+    let server_id = Uuid::parse_str(&initiate_resp.id).map_err(|_| {
+        UploadError::InvalidMetadata(format!("Invalid UUID from server: {}", initiate_resp.id))
+    })?;
+
+    if server_id != metadata.id {
+        if !initiate_resp.resuming {
+            return Err(UploadError::IdChanged {
+                original: metadata.id,
+                new: server_id,
+            });
+        }
+
+        // The server already has an identical catalog under another ID
+        info!(
+            catalog_id = %metadata.id,
+            existing_id = %server_id,
+            "Server has this catalog under another ID, resuming that upload"
+        );
+    }
+
+    let missing_extents = if initiate_resp.resuming {
+        info!(
+            missing_count = initiate_resp
+                .missing_extents
+                .as_ref()
+                .map(|v| v.len())
+                .unwrap_or(0),
+            "Resuming existing upload"
+        );
+        initiate_resp.missing_extents.unwrap_or_default()
+    } else {
+        progress.phase("catalog", Some(1), Some(catalog_data.len() as u64));
+
+        // Check if we should try delta upload with reference catalogs
+        let delta_result = if !args.reference.is_empty() {
+            try_delta_upload(
+                client,
+                &conn,
+                server_url,
+                server_id,
+                &args.catalog,
+                &args.reference,
+            )
+            .await?
+        } else {
+            None
+        };
+
+        if let Some((upload_resp, patch_bytes)) = delta_result {
+            // Delta upload succeeded
+            info!(
+                missing_count = upload_resp.missing_extents.len(),
+                "Catalog uploaded via delta patch"
+            );
+            summary.catalog_bytes = patch_bytes;
+            progress.advance(1, catalog_data.len() as u64);
+            upload_resp.missing_extents
+        } else {
+            // Step 2: Upload the catalog data (full upload)
+            info!("Uploading catalog data");
+            let upload_resp = upload_catalog(client, server_url, server_id, &catalog_data).await?;
+            summary.catalog_bytes = catalog_data.len() as u64;
+            progress.advance(1, catalog_data.len() as u64);
+            info!(
+                missing_count = upload_resp.missing_extents.len(),
+                "Catalog uploaded"
+            );
+            upload_resp.missing_extents
+        }
+    };
+
+    // The server may have lost what the index says it has
+    if let Some(ref mut index) = blob_index {
+        let missing: Vec<B3Id> = missing_extents
+            .iter()
+            .filter_map(|id| blake3::Hash::from_hex(id).ok().map(B3Id::from))
+            .collect();
+        let forgotten = index.forget_extents(&destination, &missing)?;
+        if forgotten > 0 {
+            warn!(
+                forgotten,
+                "Server is missing blobs the index had it down as having"
+            );
+        }
+    }
+
+    // Step 3 & 4: Upload extents and finalize in a loop until complete
+    let mut current_missing = missing_extents;
+
+    if args.derive && !current_missing.is_empty() {
+        match derive_from_references(&conn, client, server_url, &args, &current_missing).await {
+            Ok(stored) => {
+                info!(
+                    derived = stored.len(),
+                    "Derived extents from reference catalogs"
+                );
+                summary.extents_derived = stored.len() as u64;
+                current_missing.retain(|id| {
+                    !blake3::Hash::from_hex(id)
+                        .map(B3Id::from)
+                        .is_ok_and(|id| stored.contains(&id))
+                });
+            }
+            Err(err) => {
+                warn!(%err, "Couldn't derive extents from the reference catalogs, uploading them");
+            }
+        }
+    }
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        // Upload missing extents, highest priority first
+        current_missing.sort_by_key(|id| {
+            extent_locations
+                .get(&id.to_lowercase())
+                .and_then(|location| location.priority)
+                .unwrap_or(u32::MAX)
+        });
+        if !current_missing.is_empty() {
+            info!(
+                attempt,
+                count = current_missing.len(),
+                "Uploading missing extents"
+            );
+            let total_bytes = current_missing
+                .iter()
+                .filter_map(|id| extent_locations.get(&id.to_lowercase()))
+                .map(|location| location.length)
+                .sum();
+            progress.phase(
+                "extents",
+                Some(current_missing.len() as u64),
+                Some(total_bytes),
+            );
+
+            let (uploaded, uploaded_bytes) = upload_extents(
+                client,
+                server_url,
+                &current_missing,
+                &extent_locations,
+                &source_roots,
+                extent_key.as_ref(),
+                args.parallel,
+                deadline,
+                &progress,
+            )
+            .await?;
+            summary.extents_sent += uploaded as u64;
+            summary.extent_bytes_sent += uploaded_bytes;
+
+            if uploaded < current_missing.len() {
+                warn!(
+                    catalog_id = %server_id,
+                    uploaded,
+                    remaining = current_missing.len() - uploaded,
+                    "Time budget exhausted, stopping; run the upload again to resume"
+                );
+                progress.complete_with(&summary.finish(started, &extent_locations));
+                return Ok(());
+            }
+
+            info!(
+                attempt,
+                count = current_missing.len(),
+                "Finished uploading extents"
+            );
+        }
+
+        // Try to finalize
+        info!(attempt, "Finalizing upload");
+        progress.phase("finalize", None, None);
+        let finalize_resp = finalize_upload(client, server_url, server_id).await?;
+
+        match finalize_resp {
+            None => {
+                // 204 No Content - success!
+                break;
+            }
+            Some(resp) if resp.complete => {
+                // Explicitly complete
+                break;
+            }
+            Some(resp) => {
+                // Not complete, get the new list of missing extents
+                current_missing = resp.missing_extents.unwrap_or_default();
+                warn!(
+                    attempt,
+                    missing_count = current_missing.len(),
+                    "Finalization reported missing extents, continuing upload"
+                );
+
+                if current_missing.is_empty() {
+                    // Server said not complete but no missing extents? Weird, but treat as done
+                    warn!(
+                        "Server reported incomplete but no missing extents, treating as complete"
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(ref mut index) = blob_index {
+        let recorded = index.record_catalog(&conn, &destination)?;
+        debug!(recorded, "Recorded uploaded blobs in the index");
+    }
+
+    summary.complete = true;
+    summary.retries = attempt - 1;
+    progress.complete_with(&summary.finish(started, &extent_locations));
+    info!(catalog_id = %server_id, "Upload complete!");
+    Ok(())
+}
+
+/// Have the server derive missing extents from those of the reference catalogs, returning
+/// which are now stored.
+async fn derive_from_references(
+    conn: &Connection,
+    client: &Transport,
+    server_url: &str,
+    args: &UploadArgs,
+    missing: &[String],
+) -> Result<HashSet<B3Id>, Box<dyn std::error::Error + Send + Sync>> {
+    let missing: HashSet<B3Id> = missing
+        .iter()
+        .filter_map(|id| blake3::Hash::from_hex(id).map(B3Id::from).ok())
+        .collect();
+
+    let mut plan = Vec::new();
+    let mut planned = HashSet::new();
+    for reference in &args.reference {
+        let (_reference_conn, tempfile) = open_catalog(reference)?;
+        let path = tempfile
+            .as_ref()
+            .map(|t| t.path().to_path_buf())
+            .unwrap_or_else(|| reference.clone());
+        conn.execute(
+            "ATTACH DATABASE ?1 AS reference",
+            [path.to_string_lossy().as_ref()],
+        )?;
+        let derivable = plan_migration(conn, "reference");
+        conn.execute("DETACH DATABASE reference", [])?;
+
+        for extent in derivable? {
+            if missing.contains(&extent.id) && planned.insert(extent.id) {
+                plan.push(extent);
+            }
+        }
+    }
+    debug!(extents = plan.len(), "Planned extents to derive");
+    if plan.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let outcome = derive_available(client, server_url, &plan, args.parallel).await?;
+    Ok(outcome.stored)
+}
+
+/// Parse a size in bytes like `1048576`, `500M`, or `1.5GiB` (multiples are binary).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size '{s}'"))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size unit in '{s}'")),
+    };
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// Format a duration to the second, like `1h 2m 3s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = Duration::from_secs(duration.as_secs_f64().ceil() as u64);
+    match SignedDuration::try_from(seconds) {
+        Ok(duration) => format!("{duration:#}"),
+        Err(_) => format!("{seconds:?}"),
+    }
+}
+
+/// What an upload is expected to send, and how long that should take.
+struct UploadEstimate {
+    /// How many extents the server is missing.
+    extents: usize,
+    /// Bytes to send: the missing extents and the catalog itself.
+    bytes: u64,
+    /// Time to send it all, if the link to the server could be measured.
+    duration: Option<Duration>,
+}
+
+/// How many extents to ask the server about at once when estimating.
+const ESTIMATE_CHECK_BATCH: usize = 1000;
+
+/// How much data to send to the server to measure throughput.
+const PROBE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Estimate an upload without changing anything on the server.
+///
+/// The server is asked which of the catalog's extents it already has, and the link to it is
+/// measured by timing an empty request (for latency) and a [`PROBE_SIZE`] one (for
+/// throughput). The estimated time is the missing data at that throughput, plus a
+/// round trip per extent spread over the parallel uploads. Delta catalog uploads aren't
+/// accounted for, so the estimate errs on the high side. Extents in `known` are taken to be
+/// on the server without asking.
+async fn estimate_upload(
+    client: &Transport,
+    server_url: &str,
+    extent_locations: &HashMap<String, ExtentLocation>,
+    known: &HashSet<B3Id>,
+    catalog_size: u64,
+    parallel: usize,
+) -> Result<UploadEstimate, UploadError> {
+    let ids: Vec<&String> = extent_locations
+        .keys()
+        .filter(|id| {
+            !blake3::Hash::from_hex(id.as_str())
+                .map(B3Id::from)
+                .is_ok_and(|id| known.contains(&id))
+        })
+        .collect();
+    let url = format!("{}/extents/check", server_url);
+
+    let mut extents: usize = 0;
+    let mut bytes = catalog_size;
+    for batch in ids.chunks(ESTIMATE_CHECK_BATCH) {
+        let resp = client
+            .send(client.post(&url).json(&CheckExtentsRequest { ids: batch }))
+            .await?;
+        if !resp.status().is_success() {
+            let error_resp: ErrorResponse = resp.json().await?;
+            return Err(UploadError::Server {
+                error: error_resp.error,
+                detail: error_resp.detail,
+            });
+        }
+        let check: CheckExtentsResponse = resp.json().await?;
+
+        for (id, exists) in batch.iter().zip(check.exists) {
+            if !exists {
+                extents += 1;
+                bytes += extent_locations[*id].length;
+            }
+        }
+    }
+    debug!(
+        extents,
+        bytes, "Checked which extents the server is missing"
+    );
+
+    let duration = measure_link(client, server_url)
+        .await?
+        .map(|(latency, throughput)| {
+            let round_trips = latency.mul_f64(extents.div_ceil(parallel.max(1)) as f64);
+            Duration::from_secs_f64(bytes as f64 / throughput) + round_trips
+        });
+
+    Ok(UploadEstimate {
+        extents,
+        bytes,
+        duration,
+    })
+}
+
+/// Measure the latency and throughput (in bytes per second) to the server, or `None` if
+/// the server doesn't support probing.
+async fn measure_link(
+    client: &Transport,
+    server_url: &str,
+) -> Result<Option<(Duration, f64)>, UploadError> {
+    let url = format!("{}/probe", server_url);
+    let send = |body: Vec<u8>| async {
+        let start = Instant::now();
+        let resp = client.send(client.post(&url).body(body)).await?;
+        let status = resp.status();
+        resp.bytes().await?;
+        Ok::<_, UploadError>((status, start.elapsed()))
+    };
+
+    // The first request may also have to connect, so latency is taken from the second
+    let (status, _) = send(Vec::new()).await?;
+    if !status.is_success() {
+        warn!(%status, "Server doesn't support link probes, can't estimate upload time");
+        return Ok(None);
+    }
+    let (_, latency) = send(Vec::new()).await?;
+    let (_, elapsed) = send(vec![0; PROBE_SIZE]).await?;
+
+    let transfer = elapsed
+        .saturating_sub(latency)
+        .max(Duration::from_millis(1));
+    let throughput = PROBE_SIZE as f64 / transfer.as_secs_f64();
+    debug!(?latency, throughput, "Measured link to server");
+    Ok(Some((latency, throughput)))
+}
+
+/// Build the HTTP client for all requests, and the headers they all carry.
+///
+/// For catalogs with keyed extent IDs, every request declares the key's ID in an
+/// `X-Extent-Key` header, so the server knows extents can't be checked with a plain hash.
+fn build_transport(
+    args: &UploadArgs,
+    extent_key_id: Option<&str>,
+) -> Result<Transport, UploadError> {
+    let keep_alive = Duration::from_secs(args.keep_alive);
+    let mut builder = Client::builder()
+        .pool_idle_timeout(keep_alive)
+        .pool_max_idle_per_host(args.parallel)
+        .tcp_keepalive(keep_alive)
+        .tcp_nodelay(true)
+        .http2_adaptive_window(true)
+        .http2_keep_alive_interval(Duration::from_secs(30))
+        .http2_keep_alive_while_idle(true);
+
+    if args.http2 {
+        debug!("Using HTTP/2 with prior knowledge");
+        builder = builder.http2_prior_knowledge();
+    }
+
+    let mut headers = HeaderMap::new();
+    if let Some(ref token) = args.token {
+        let mut value = HeaderValue::try_from(format!("Bearer {}", token.fetch_text()?))
+            .map_err(|_| UploadError::InvalidToken)?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    if let Some(key_id) = extent_key_id {
+        let value = HeaderValue::try_from(key_id).map_err(|_| {
+            UploadError::InvalidMetadata(format!("Invalid extent key ID: {}", key_id))
+        })?;
+        headers.insert(EXTENT_KEY_HEADER, value);
+    }
+    Ok(Transport::new(builder.build()?).with_headers(headers))
+}
+
+/// Work out where each source root of the catalog is on disk.
+///
+/// Single-root catalogs have one root with an empty prefix.
+fn resolve_source_roots(
+    args: &UploadArgs,
+    metadata: &CatalogMetadata,
+    cipher: Option<&CatalogCipher>,
+) -> Result<HashMap<String, PathBuf>, UploadError> {
+    let Some(ref roots) = metadata.roots else {
+        let source_path = if let Some(ref override_path) = args.override_source {
+            info!(
+                catalog_path = ?metadata.source_path,
+                override_path = ?override_path,
+                "Using overridden source path"
+            );
+            override_path.clone()
+        } else if let Some(ref catalog_path) = metadata.source_path {
+            catalog_path.clone()
+        } else {
+            return Err(UploadError::MissingMetadata(
+                "source_path (use --override-source to specify one)".to_string(),
+            ));
+        };
+
+        return Ok(HashMap::from([(String::new(), source_path)]));
+    };
+
+    if args.override_source.is_some() {
+        return Err(UploadError::InvalidMetadata(
+            "catalog has multiple source roots, use --override-root instead of --override-source"
+                .to_string(),
+        ));
+    }
+
+    let mut roots = roots.clone();
+    for (prefix, override_path) in &args.override_root {
+        // Prefixes are stored encrypted, like the paths they are part of
+        let stored_prefix = match cipher {
+            Some(cipher) => cipher.encrypt_path(prefix),
+            None => prefix.clone(),
+        };
+        let Some(root) = roots.get_mut(&stored_prefix) else {
+            return Err(UploadError::InvalidMetadata(format!(
+                "no source root with prefix '{}' in catalog",
+                prefix
+            )));
+        };
+        info!(
+            prefix,
+            catalog_path = ?root,
+            override_path,
+            "Using overridden source root"
+        );
+        *root = PathBuf::from(override_path);
+    }
+
+    Ok(roots)
+}
+
+/// Try to upload the catalog using a delta patch against a reference catalog.
+/// Returns Some(UploadResponse) if successful, None if no suitable reference was found.
+async fn try_delta_upload(
+    client: &Transport,
+    conn: &Connection,
+    server_url: &str,
+    catalog_id: Uuid,
+    target_catalog: &Path,
+    reference_paths: &[PathBuf],
+) -> Result<Option<(UploadResponse, u64)>, UploadError> {
+    // Read metadata from each reference catalog
+    let mut reference_infos = Vec::new();
+    for path in reference_paths {
+        match read_reference_catalog_info(path) {
+            Ok(info) => {
+                debug!(path = ?path, id = %info.id, "Found reference catalog");
+                reference_infos.push(info);
+            }
+            Err(e) => {
+                warn!(path = ?path, error = %e, "Failed to read reference catalog, skipping");
+            }
+        }
+    }
+
+    if reference_infos.is_empty() {
+        info!("No valid reference catalogs found, falling back to full upload");
+        return Ok(None);
+    }
+
+    // Ask the server which of these catalogs it knows about, and which shares the most
+    // extents with this one
+    let sketch = match catalog_sketch(conn) {
+        Ok(sketch) => Some(sketch),
+        Err(err) => {
+            warn!(%err, "Couldn't sample the catalog's extents, choosing a reference by age");
+            None
+        }
+    };
+    let check_req = CheckCatalogsRequest {
+        ids: reference_infos
+            .iter()
+            .map(|r| r.id.simple().to_string())
+            .collect(),
+        sketch,
+    };
+
+    let url = format!("{}/catalogs/check", server_url);
+    let resp = client.send(client.post(&url).json(&check_req)).await?;
+
+    if !resp.status().is_success() {
+        warn!("Server doesn't support catalog check endpoint, falling back to full upload");
+        return Ok(None);
+    }
+
+    let check_resp: CheckCatalogsResponse = resp.json().await?;
+
+    if check_resp.existing.is_empty() {
+        info!("Server doesn't have any of the reference catalogs, falling back to full upload");
+        return Ok(None);
+    }
+    for shared in check_resp.shared.iter().flatten() {
+        debug!(
+            id = %shared.id,
+            extents = shared.extents,
+            bytes = shared.bytes,
+            "Reference catalog shares sampled extents"
+        );
+    }
+
+    // Server returns IDs sorted by preference (best first), so use the first one we have locally
+    let best_reference = check_resp.existing.iter().find_map(|server_id| {
+        reference_infos
+            .iter()
+            .find(|r| r.id.simple().to_string().to_lowercase() == server_id.to_lowercase())
+    });
+
+    let best_reference = match best_reference {
+        Some(r) => r,
+        None => {
+            info!("No matching reference catalog found on server, falling back to full upload");
+            return Ok(None);
+        }
+    };
+
+    info!(
+        reference_id = %best_reference.id,
+        reference_path = ?best_reference.path,
+        "Using reference catalog for delta upload"
+    );
+
+    // Decompress both catalogs to get raw SQLite data
+    let target_data = decompress_catalog_data(target_catalog)?;
+    let reference_data = decompress_catalog_data(&best_reference.path)?;
+
+    info!(
+        target_size = target_data.len(),
+        reference_size = reference_data.len(),
+        "Decompressed catalogs for diff"
+    );
+
+    // Read the compressed catalog size for comparison
+    let compressed_catalog_size = fs::metadata(target_catalog)?.len();
+
+    // Generate binary diff using qbsdiff
+    let mut patch_data = Vec::new();
+    qbsdiff::Bsdiff::new(&reference_data, &target_data)
+        .compare(&mut patch_data)
+        .map_err(|e| UploadError::BinaryDiff(format!("Failed to generate diff: {}", e)))?;
+
+    info!(patch_size = patch_data.len(), "Generated binary patch");
+
+    // Compress the patch
+    let mut compressed_patch = Vec::new();
+    {
+        let mut encoder =
+            zstd::stream::Encoder::new(&mut compressed_patch, 19).map_err(UploadError::Io)?;
+        encoder.write_all(&patch_data).map_err(UploadError::Io)?;
+        encoder.finish().map_err(UploadError::Io)?;
+    }
+
+    let savings = compressed_catalog_size as f64 - compressed_patch.len() as f64;
+    let savings_pct = (savings / compressed_catalog_size as f64) * 100.0;
+
+    debug!(
+        compressed_patch_size = compressed_patch.len(),
+        compressed_catalog_size = compressed_catalog_size,
+        savings_bytes = savings as i64,
+        savings_pct = format!("{:.1}%", savings_pct),
+        "Delta upload: patch vs full catalog"
+    );
+
+    // Compute checksum of the decompressed target (what the patch reconstructs)
+    let target_checksum = blake3::hash(&target_data).to_hex().to_string();
+
+    // Upload via patch endpoint
+    let url = format!(
+        "{}/catalogs/{}/patch?reference={}&checksum={}",
+        server_url,
+        catalog_id.simple(),
+        best_reference.id.simple(),
+        target_checksum
+    );
+
+    let patch_bytes = compressed_patch.len() as u64;
+    let request = client
+        .put(&url)
+        .header("Content-Type", "application/octet-stream")
+        .body(compressed_patch);
+    let resp = client.send(request).await?;
+
+    if !resp.status().is_success() {
+        let error_resp: ErrorResponse = resp.json().await?;
+        return Err(UploadError::Server {
+            error: error_resp.error,
+            detail: error_resp.detail,
+        });
+    }
+
+    let upload_resp: UploadResponse = resp.json().await?;
+    Ok(Some((upload_resp, patch_bytes)))
+}
+
+/// Read metadata from a reference catalog file.
+fn read_reference_catalog_info(path: &Path) -> Result<ReferenceCatalogInfo, UploadError> {
+    let (conn, _tempfile) = open_catalog(path).map_err(|e| {
+        UploadError::ReferenceCatalog(format!("Failed to open {}: {}", path.display(), e))
+    })?;
+
+    // Read catalog ID
+    let id_str: String = conn
+        .query_row("SELECT value FROM metadata WHERE key = 'id'", [], |row| {
+            row.get(0)
+        })
+        .map_err(|_| UploadError::ReferenceCatalog(format!("Missing id in {}", path.display())))?;
+
+    let id_str: String = serde_json::from_str(&id_str)
+        .map_err(|_| UploadError::ReferenceCatalog(format!("Invalid id in {}", path.display())))?;
+
+    let id = Uuid::parse_str(&id_str).map_err(|_| {
+        UploadError::ReferenceCatalog(format!("Invalid UUID in {}", path.display()))
+    })?;
+
+    Ok(ReferenceCatalogInfo {
+        path: path.to_path_buf(),
+        id,
+    })
+}
+
+/// Sample a catalog's extents, for the server to compare its catalogs against.
+///
+/// Extents are picked by ID, so the same extents are sampled from every catalog that has
+/// them, and the bytes a catalog on the server shares with the sample scale with those it
+/// shares with the whole catalog.
+fn catalog_sketch(conn: &Connection) -> rusqlite::Result<Vec<SketchExtent>> {
+    let mut stmt = conn.prepare(
+        "SELECT extent_id, bytes FROM extents WHERE extent_id < ?1
+         ORDER BY extent_id LIMIT ?2",
+    )?;
+    stmt.query_map(rusqlite::params![[SKETCH_BELOW], SKETCH_MAX], |row| {
+        Ok(SketchExtent {
+            id: hex::encode(row.get::<_, Vec<u8>>(0)?),
+            bytes: row.get(1)?,
+        })
+    })?
+    .collect()
+}
+
+/// Decompress a catalog file and return the raw SQLite data.
+fn decompress_catalog_data(path: &Path) -> Result<Vec<u8>, UploadError> {
+    if is_zstd_compressed(path)? {
+        // Decompress to a temp file and read it
+        let temp_file = tempfile::NamedTempFile::new()?;
+        decompress_file(path, temp_file.path())?;
+        Ok(fs::read(temp_file.path())?)
+    } else {
+        // Already uncompressed
+        Ok(fs::read(path)?)
+    }
+}
+
+/// Read the catalog metadata needed for uploading.
+///
+/// If the catalog's paths are encrypted, the cipher must be for the same key; source
+/// paths are decrypted with it.
+fn read_catalog_metadata(
+    conn: &Connection,
+    cipher: Option<&CatalogCipher>,
+) -> Result<CatalogMetadata, UploadError> {
+    // Read catalog ID
+    let id_str: String = conn
+        .query_row("SELECT value FROM metadata WHERE key = 'id'", [], |row| {
+            row.get(0)
+        })
+        .map_err(|_| UploadError::MissingMetadata("id".to_string()))?;
+
+    // Parse the JSON string value
+    let id_str: String = serde_json::from_str(&id_str)
+        .map_err(|_| UploadError::InvalidMetadata(format!("Invalid id value: {}", id_str)))?;
+
+    let id = Uuid::parse_str(&id_str)
+        .map_err(|_| UploadError::InvalidMetadata(format!("Invalid UUID: {}", id_str)))?;
+
+    // Read machine ID
+    let machine_str: String = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'machine'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|_| UploadError::MissingMetadata("machine".to_string()))?;
+
+    let machine_id: String = serde_json::from_str(&machine_str).map_err(|_| {
+        UploadError::InvalidMetadata(format!("Invalid machine value: {}", machine_str))
+    })?;
+
+    // Read path encryption (optional), and check we have the right key for it
+    let key_id: Option<String> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'path_encryption'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .ok()
+        .map(|s| {
+            serde_json::from_str::<serde_json::Value>(&s)
+                .ok()
+                .and_then(|v| v.get("key_id")?.as_str().map(String::from))
+                .ok_or_else(|| {
+                    UploadError::InvalidMetadata(format!("Invalid path_encryption value: {}", s))
+                })
+        })
+        .transpose()?;
+
+    let cipher = match (&key_id, cipher) {
+        (Some(key_id), None) => return Err(UploadError::KeyRequired(key_id.clone())),
+        (Some(key_id), Some(cipher)) if cipher.key_id() != key_id => {
+            return Err(UploadError::WrongKey {
+                catalog: key_id.clone(),
+                given: cipher.key_id().to_string(),
+            });
+        }
+        (Some(_), cipher) => cipher,
+        (None, _) => None,
+    };
+    let reveal = |value: serde_json::Value| -> Result<Option<String>, UploadError> {
+        let value = match cipher {
+            Some(cipher) => cipher.open_value(&value)?,
+            None => value,
+        };
+        Ok(value.as_str().map(String::from))
+    };
+
+    // Read source path (optional)
+    let source_path: Option<PathBuf> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'source_path'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .map(reveal)
+        .transpose()?
+        .flatten()
+        .map(PathBuf::from);
+
+    // Read source roots (only in multi-root catalogs)
+    let roots: Option<HashMap<String, serde_json::Value>> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'roots'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .ok()
+        .map(|s| {
+            serde_json::from_str(&s)
+                .map_err(|_| UploadError::InvalidMetadata(format!("Invalid roots value: {}", s)))
+        })
+        .transpose()?;
+    let roots: Option<HashMap<String, PathBuf>> = roots
+        .map(|roots| {
+            roots
+                .into_iter()
+                .map(|(prefix, path)| {
+                    let path = reveal(path)?.ok_or_else(|| {
+                        UploadError::InvalidMetadata(format!("Invalid path for root {}", prefix))
+                    })?;
+                    Ok((prefix, PathBuf::from(path)))
+                })
+                .collect::<Result<_, UploadError>>()
+        })
+        .transpose()?;
+
+    // Read the extent ID key (optional)
+    let extent_key_id: Option<String> = conn
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'extent_key'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .ok()
+        .map(|s| {
+            serde_json::from_str::<serde_json::Value>(&s)
+                .ok()
+                .and_then(|v| v.get("key_id")?.as_str().map(String::from))
+                .ok_or_else(|| {
+                    UploadError::InvalidMetadata(format!("Invalid extent_key value: {}", s))
+                })
+        })
+        .transpose()?;
+
+    Ok(CatalogMetadata {
+        id,
+        machine_id,
+        source_path,
+        roots,
+        key_id,
+        extent_key_id,
+    })
+}
+
+/// Build a map from extent ID (hex) to its location on disk.
+///
+/// This queries the catalog to find all extents and which files contain them.
+/// For multi-root catalogs, file paths are split into their root prefix and the
+/// path within that root. Root prefixes are kept as stored, but encrypted paths within
+/// the root are decrypted. Extents shared by several files get the highest priority of them,
+/// and are read from a file's main data rather than a secondary stream when possible.
+fn build_extent_location_map(
+    conn: &Connection,
+    multi_root: bool,
+    cipher: Option<&CatalogCipher>,
+) -> Result<HashMap<String, ExtentLocation>, UploadError> {
+    let mut map = HashMap::new();
+
+    // Query to find extent locations:
+    // For each extent, find a file that contains it via:
+    // files.blob_id -> blob_extents.blob_id -> blob_extents.extent_id
+    // Single-root catalogs may predate the root column
+    let root_column = if multi_root { "f.root" } else { "NULL" };
+    // Catalogs may also predate the priority column
+    let has_priority: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('files') WHERE name = 'priority'",
+        [],
+        |row| row.get(0),
+    )?;
+    let priority_column = if has_priority { "f.priority" } else { "NULL" };
+    // And the streams table
+    let has_streams: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'streams'",
+        [],
+        |row| row.get(0),
+    )?;
+    let streams_query = if has_streams {
+        format!(
+            r#"
+            UNION ALL
+            SELECT
+                hex(be.extent_id),
+                s.path,
+                be.offset,
+                be.bytes,
+                {root_column},
+                {priority_column},
+                s.name
+            FROM blob_extents be
+            JOIN streams s ON s.blob_id = be.blob_id
+            JOIN files f ON f.path = s.path
+            WHERE be.extent_id IS NOT NULL
+            "#
+        )
+    } else {
+        String::new()
+    };
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT * FROM (
+            SELECT
+                hex(be.extent_id) as extent_id,
+                f.path as path,
+                be.offset as offset,
+                be.bytes as bytes,
+                {root_column} as root,
+                {priority_column} as priority,
+                NULL as stream
+            FROM blob_extents be
+            JOIN files f ON f.blob_id = be.blob_id
+            WHERE be.extent_id IS NOT NULL
+            {streams_query}
+        )
+        ORDER BY priority IS NULL, priority, stream IS NOT NULL
+        "#
+    ))?;
+
+    let rows = stmt.query_map([], |row| {
+        let extent_id: String = row.get(0)?;
+        let path_bytes: Vec<u8> = row.get(1)?;
+        let offset: i64 = row.get(2)?;
+        let bytes: i64 = row.get(3)?;
+        let root: Option<String> = row.get(4)?;
+        let priority: Option<u32> = row.get(5)?;
+        let stream: Option<String> = row.get(6)?;
+
+        Ok((
+            extent_id,
+            path_bytes,
+            offset as u64,
+            bytes as u64,
+            root,
+            priority,
+            stream,
+        ))
+    })?;
+
+    for row in rows {
+        let (extent_id, path_bytes, offset, length, root, priority, stream) = row?;
+
+        // Convert path bytes to string, relative to the source root
+        let path = String::from_utf8_lossy(&path_bytes).to_string();
+        let root = root.unwrap_or_default();
+        let file_path = if root.is_empty() {
+            path
+        } else {
+            path.strip_prefix(&root)
+                .and_then(|p| p.strip_prefix('/'))
+                .unwrap_or_default()
+                .to_string()
+        };
+        let file_path = match cipher {
+            Some(cipher) => cipher.decrypt_path(&file_path)?,
+            None => file_path,
+        };
+
+        // Only insert if we don't already have this extent
+        // (multiple files might reference the same extent due to dedup, and the
+        // highest priority one comes first)
+        map.entry(extent_id.to_lowercase())
+            .or_insert(ExtentLocation {
+                root,
+                file_path,
+                offset,
+                length,
+                priority,
+                stream,
+            });
+    }
+
+    Ok(map)
+}
+
+async fn initiate_upload(
+    client: &Transport,
+    server_url: &str,
+    catalog_id: Uuid,
+    checksum: &str,
+) -> Result<InitiateResponse, UploadError> {
+    let url = format!("{}/catalogs", server_url);
+    let req = InitiateRequest {
+        id: catalog_id,
+        checksum: checksum.to_string(),
+    };
+
+    let resp = client.send(client.post(&url).json(&req)).await?;
+
+    if !resp.status().is_success() {
+        let error_resp: ErrorResponse = resp.json().await?;
+        return Err(UploadError::Server {
+            error: error_resp.error,
+            detail: error_resp.detail,
+        });
+    }
+
+    let initiate_resp: InitiateResponse = resp.json().await?;
+    Ok(initiate_resp)
+}
+
+async fn upload_catalog(
+    client: &Transport,
+    server_url: &str,
+    catalog_id: Uuid,
+    data: &[u8],
+) -> Result<UploadResponse, UploadError> {
+    let url = format!("{}/catalogs/{}", server_url, catalog_id.simple());
+
+    let request = client
+        .put(&url)
+        .header("Content-Type", "application/octet-stream")
+        .body(data.to_vec());
+    let resp = client.send(request).await?;
+
+    if !resp.status().is_success() {
+        let error_resp: ErrorResponse = resp.json().await?;
+        return Err(UploadError::Server {
+            error: error_resp.error,
+            detail: error_resp.detail,
+        });
+    }
+
+    let upload_resp: UploadResponse = resp.json().await?;
+    Ok(upload_resp)
+}
+
+/// Upload a list of extents to the server concurrently.
+///
+/// For each extent:
+/// 1. Look up its location in the catalog
+/// 2. Stream data to the server from the source file at the specified offset
+/// 3. Compute BLAKE3 hash while reading
+/// 4. If hash doesn't match, abort the entire upload
+///
+/// At most `max_in_flight` extents are being read or uploaded at once. Returns how many
+/// extents were uploaded, which is fewer than asked if the deadline passed, and their bytes.
+#[allow(clippy::too_many_arguments)]
+async fn upload_extents(
+    client: &Transport,
+    server_url: &str,
+    extent_ids: &[String],
+    extent_locations: &HashMap<String, ExtentLocation>,
+    source_roots: &HashMap<String, PathBuf>,
+    extent_key: Option<&ExtentKey>,
+    max_in_flight: usize,
+    deadline: Option<Instant>,
+    progress: &Progress,
+) -> Result<(usize, u64), UploadError> {
+    let total = extent_ids.len();
+    let mut completed = 0;
+    let mut bytes = 0;
+    let mut last_logged = 0;
+    let uploader = ExtentUploader::new(client.clone(), server_url).with_key(extent_key.cloned());
+    let uploader = &uploader;
+
+    // Past the deadline, no new uploads are started, but those in flight are finished
+    stream::iter(extent_ids)
+        .take_while(|_| {
+            futures::future::ready(deadline.is_none_or(|deadline| Instant::now() < deadline))
+        })
+        .map(|extent_id_hex| async move {
+            let extent_id_lower = extent_id_hex.to_lowercase();
+
+            // Find the extent location in our map
+            let location = extent_locations.get(&extent_id_lower).ok_or_else(|| {
+                UploadError::ExtentNotInCatalog {
+                    extent_id: extent_id_hex.clone(),
+                }
+            })?;
+
+            debug!(
+                extent = %extent_id_hex,
+                root = %location.root,
+                file = %location.file_path,
+                stream = ?location.stream,
+                offset = location.offset,
+                length = location.length,
+                "Uploading extent"
+            );
+
+            // Construct full path to the file
+            let source_path = source_roots.get(&location.root).ok_or_else(|| {
+                UploadError::MissingMetadata(format!("source root '{}'", location.root))
+            })?;
+            let mut file_path = source_path.join(&location.file_path);
+            if let Some(ref stream) = location.stream {
+                file_path = stream_path(&file_path, stream);
+            }
+
+            if !file_path.exists() {
+                return Err(UploadError::FileNotFound {
+                    extent_id: extent_id_hex.clone(),
+                    path: file_path,
+                });
+            }
+
+            let extent_id = blake3::Hash::from_hex(&extent_id_lower)
+                .map(B3Id::from)
+                .map_err(|_| UploadError::InvalidMetadata(format!("extent ID {extent_id_hex}")))?;
+            let mut file = File::open(&file_path)?;
+            file.seek(SeekFrom::Start(location.offset))?;
+
+            // The shared transport's client has an internal connection pool
+            uploader
+                .upload_blocking(extent_id, file, location.length)
+                .await
+                .map_err(|err| match err {
+                    UploadExtentError::Changed { extent, actual } => UploadError::ExtentChanged {
+                        extent_id: extent_id_hex.clone(),
+                        expected: extent.as_hex(),
+                        actual: actual.as_hex(),
+                    },
+                    err => err.into(),
+                })?;
+            Ok(location.length)
+        })
+        .buffer_unordered(max_in_flight.max(1))
+        .try_for_each(|length| {
+            completed += 1;
+            bytes += length;
+            progress.advance(1, length);
+
+            // Log progress every 100 extents or at completion
+            if completed == total || completed >= last_logged + 100 {
+                last_logged = completed;
+                info!(
+                    progress = format!("{}/{}", completed, total),
+                    "Extent upload progress"
+                );
+            }
+
+            futures::future::ready(Ok(()))
+        })
+        .await?;
+
+    Ok((completed, bytes))
+}
+
+async fn finalize_upload(
+    client: &Transport,
+    server_url: &str,
+    catalog_id: Uuid,
+) -> Result<Option<FinalizeResponse>, UploadError> {
+    let url = format!("{}/catalogs/{}", server_url, catalog_id.simple());
+
+    let resp = client.send(client.post(&url)).await?;
+
+    if resp.status().as_u16() == 204 {
+        // Success, no content
+        return Ok(None);
+    }
+
+    if !resp.status().is_success() {
+        let error_resp: ErrorResponse = resp.json().await?;
+        return Err(UploadError::Server {
+            error: error_resp.error,
+            detail: error_resp.detail,
+        });
+    }
+
+    let finalize_resp: FinalizeResponse = resp.json().await?;
+    Ok(Some(finalize_resp))
+}
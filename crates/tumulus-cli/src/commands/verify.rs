@@ -0,0 +1,166 @@
+//! Check a restored tree against the catalog it was restored from.
+//!
+//! Each entry is scanned again under the target directory and compared with the catalog
+//! (see [`tumulus::verify`]). A summary is printed, and with `--json` the full report is
+//! written to stdout, as a record of how faithful the restore was.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use rusqlite::Connection;
+use tracing::info;
+
+use tumulus::{
+    CatalogCipher, CatalogOpenOptions, ExtentKey, Problem, SecretSource, VerifyOptions,
+    open_catalog_with_options, read_catalog_files, verify_tree,
+};
+
+/// Check a restored tree against the catalog it was restored from
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Catalog the tree was restored from
+    catalog: PathBuf,
+
+    /// Directory the catalog was restored into
+    target: PathBuf,
+
+    /// Key for a catalog with encrypted paths, as given to `catalog --encrypt-key`
+    #[arg(long, value_name = "SECRET")]
+    key: Option<SecretSource>,
+
+    /// Secret for a catalog with keyed extent IDs, as given to `catalog --extent-key`
+    #[arg(long, value_name = "SECRET")]
+    extent_key: Option<SecretSource>,
+
+    /// Don't compare owners and groups, as for a restore by an unprivileged user
+    #[arg(long)]
+    no_ownership: bool,
+
+    /// Don't compare modification times
+    #[arg(long)]
+    no_times: bool,
+
+    /// Write the full report as JSON to stdout
+    #[arg(long)]
+    json: bool,
+}
+
+/// Read the key ID of a catalog metadata entry, if it's there.
+fn metadata_key_id(conn: &Connection, key: &str) -> Option<String> {
+    conn.query_row("SELECT value FROM metadata WHERE key = ?1", [key], |row| {
+        row.get::<_, String>(0)
+    })
+    .ok()
+    .and_then(|value| serde_json::from_str::<serde_json::Value>(&value).ok())
+    .and_then(|value| value.get("key_id")?.as_str().map(String::from))
+}
+
+/// Whether the catalog was made with the system backup profile.
+fn is_system_backup(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT value FROM metadata WHERE key = 'profile'",
+        [],
+        |row| row.get::<_, String>(0),
+    )
+    .ok()
+    .and_then(|value| serde_json::from_str::<serde_json::Value>(&value).ok())
+    .is_some_and(|value| value == "system")
+}
+
+pub fn run(args: VerifyArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // The catalog is the reference, so it must itself be intact
+    let options = CatalogOpenOptions {
+        verify: true,
+        read_only: true,
+    };
+    let (conn, _tempfile) = open_catalog_with_options(&args.catalog, options)?;
+
+    let cipher = match (metadata_key_id(&conn, "path_encryption"), &args.key) {
+        (None, _) => None,
+        (Some(key_id), None) => {
+            return Err(format!("catalog paths are encrypted with key {key_id}, use --key").into());
+        }
+        (Some(key_id), Some(key)) => {
+            let cipher = CatalogCipher::from_secret(key)?;
+            if cipher.key_id() != key_id {
+                return Err(format!(
+                    "catalog paths are encrypted with key {key_id}, but the given key is {}",
+                    cipher.key_id()
+                )
+                .into());
+            }
+            Some(cipher)
+        }
+    };
+    let extent_key = match (metadata_key_id(&conn, "extent_key"), &args.extent_key) {
+        (None, _) => None,
+        (Some(key_id), None) => {
+            return Err(format!(
+                "catalog extent IDs are keyed with key {key_id}, use --extent-key"
+            )
+            .into());
+        }
+        (Some(key_id), Some(key)) => {
+            let key = ExtentKey::from_secret(key)?;
+            if key.key_id() != key_id {
+                return Err(format!(
+                    "catalog extent IDs are keyed with key {key_id}, but the given key is {}",
+                    key.key_id()
+                )
+                .into());
+            }
+            Some(key)
+        }
+    };
+
+    let mut files = read_catalog_files(&conn)?;
+    if let Some(ref cipher) = cipher {
+        files = files
+            .into_iter()
+            .map(|info| cipher.decrypt_file(info))
+            .collect::<Result<_, _>>()?;
+    }
+
+    let target = args.target.canonicalize()?;
+    info!(entries = files.len(), ?target, "Verifying restored tree");
+    let options = VerifyOptions {
+        ownership: !args.no_ownership,
+        times: !args.no_times,
+        system_metadata: is_system_backup(&conn),
+    };
+    let report = verify_tree(&files, &target, extent_key.as_ref(), options);
+
+    let count =
+        |matches: fn(&Problem) -> bool| report.problems.iter().filter(|p| matches(p)).count();
+    eprintln!("Verified {} of {} entries", report.verified, report.entries);
+    eprintln!(
+        "  Missing: {}",
+        count(|p| matches!(p, Problem::Missing { .. }))
+    );
+    eprintln!(
+        "  Different: {}",
+        count(|p| matches!(p, Problem::Different { .. }))
+    );
+    eprintln!(
+        "  Unreadable: {}",
+        count(|p| matches!(p, Problem::Unreadable { .. }))
+    );
+    eprintln!(
+        "  Not in the catalog: {}",
+        count(|p| matches!(p, Problem::Extra { .. }))
+    );
+
+    if args.json {
+        println!("{}", serde_json::to_string(&report)?);
+    }
+
+    if report.is_faithful() {
+        Ok(())
+    } else {
+        Err(format!(
+            "restored tree differs from the catalog in {} entries",
+            report.problems.len()
+        )
+        .into())
+    }
+}
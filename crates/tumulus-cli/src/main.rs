@@ -17,6 +17,9 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Browse a catalog's files and restore some of them
+    Browse(commands::browse::BrowseArgs),
+
     /// Build a snapshot catalog from a directory tree
     Catalog(commands::catalog::CatalogArgs),
 
@@ -26,8 +29,17 @@ enum Commands {
     /// Display extent information for files
     DebugExtents(commands::debug_extents::DebugExtentsArgs),
 
+    /// Derive a catalog's extents on the server from those of a previous catalog
+    Migrate(commands::migrate::MigrateArgs),
+
+    /// Copy catalogs between a local repository and a tumulus server
+    Sync(commands::sync::SyncArgs),
+
     /// Upload a catalog to a tumulus server
     Upload(commands::upload::UploadArgs),
+
+    /// Check a restored tree against the catalog it was restored from
+    Verify(commands::verify::VerifyArgs),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -40,9 +52,13 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     })?;
 
     match cli.command {
+        Commands::Browse(args) => commands::browse::run(args),
         Commands::Catalog(args) => commands::catalog::run(args),
         Commands::Compare(args) => commands::compare::run(args),
         Commands::DebugExtents(args) => commands::debug_extents::run(args),
+        Commands::Migrate(args) => commands::migrate::run(args),
+        Commands::Sync(args) => commands::sync::run(args),
         Commands::Upload(args) => commands::upload::run(args),
+        Commands::Verify(args) => commands::verify::run(args),
     }
 }
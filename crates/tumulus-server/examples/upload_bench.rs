@@ -0,0 +1,120 @@
+//! Benchmark extent uploads over different HTTP connection strategies.
+//!
+//! This runs a server in-process on a temporary storage directory, then uploads the same
+//! number of fresh extents with each strategy:
+//!
+//! - `http1-serial`: a new HTTP/1.1 connection per extent, one extent at a time
+//! - `http1-pooled`: pooled, kept-alive HTTP/1.1 connections, many extents in flight
+//! - `http2`: a single HTTP/2 connection, with extents multiplexed as streams
+//!
+//! ```sh
+//! cargo run --release -p tumulus-server --example upload_bench -- [EXTENTS] [IN_FLIGHT] [SIZE]
+//! ```
+
+use std::time::{Duration, Instant};
+
+use futures::{StreamExt, TryStreamExt, stream};
+use reqwest::Client;
+use tempfile::TempDir;
+
+use tumulus_server::{FsStorage, UploadDb, router};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut args = std::env::args().skip(1);
+    let extents: u64 = args.next().map(|s| s.parse()).transpose()?.unwrap_or(2000);
+    let in_flight: usize = args.next().map(|s| s.parse()).transpose()?.unwrap_or(32);
+    let size: usize = args
+        .next()
+        .map(|s| s.parse())
+        .transpose()?
+        .unwrap_or(64 * 1024);
+
+    let storage_dir = TempDir::new()?;
+    let storage = FsStorage::new(storage_dir.path());
+    storage.init().await?;
+    let db = UploadDb::open(&storage_dir.path().join("uploads.db"))?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, router(storage, db)).await });
+
+    println!("{extents} extents of {size} bytes, up to {in_flight} in flight");
+
+    let strategies = [
+        (
+            "http1-serial",
+            Client::builder()
+                .http1_only()
+                .pool_max_idle_per_host(0)
+                .build()?,
+            1,
+        ),
+        (
+            "http1-pooled",
+            Client::builder()
+                .http1_only()
+                .pool_max_idle_per_host(in_flight)
+                .tcp_nodelay(true)
+                .build()?,
+            in_flight,
+        ),
+        (
+            "http2",
+            Client::builder()
+                .http2_prior_knowledge()
+                .http2_adaptive_window(true)
+                .tcp_nodelay(true)
+                .build()?,
+            in_flight,
+        ),
+    ];
+
+    for (seed, (name, client, in_flight)) in strategies.into_iter().enumerate() {
+        let elapsed = upload(&client, &url, seed as u64, extents, size, in_flight).await?;
+        let mib = (extents as f64 * size as f64) / (1024.0 * 1024.0);
+        println!(
+            "{name:>13}: {:>8.2?} ({:>7.1} extents/s, {:>7.1} MiB/s)",
+            elapsed,
+            extents as f64 / elapsed.as_secs_f64(),
+            mib / elapsed.as_secs_f64(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Upload `count` distinct extents, returning how long it took.
+async fn upload(
+    client: &Client,
+    url: &str,
+    seed: u64,
+    count: u64,
+    size: usize,
+    in_flight: usize,
+) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    let start = Instant::now();
+
+    stream::iter(0..count)
+        .map(|n| async move {
+            // Deterministic but distinct contents for every extent of every strategy
+            let mut data = vec![0u8; size];
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&seed.to_le_bytes()).update(&n.to_le_bytes());
+            hasher.finalize_xof().fill(&mut data);
+            let id = blake3::hash(&data).to_hex();
+
+            client
+                .put(format!("{url}/extents/{id}"))
+                .body(data)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<_, reqwest::Error>(())
+        })
+        .buffer_unordered(in_flight)
+        .try_collect::<()>()
+        .await?;
+
+    Ok(start.elapsed())
+}
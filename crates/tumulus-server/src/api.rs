@@ -1,23 +1,126 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::Router;
+use axum::{Router, middleware};
 use std::sync::Mutex;
 
+use tumulus::ExtentKey;
+
+use crate::consistency::DEFAULT_SESSION_TTL;
 use crate::db::UploadDb;
-use crate::storage::Storage;
+use crate::parity::ParityScheme;
+use crate::prune::RetentionPolicy;
+use crate::scratch::Scratch;
+use crate::storage::{Storage, TracedStorage};
+use crate::trace;
 
+mod admin;
+mod auth;
 mod catalogs;
 mod error;
 mod extents;
+mod probe;
 
+pub use auth::AuthToken;
 pub use catalogs::{
     CatalogError, FinalizeResponse, InitiateRequest, InitiateResponse, UploadResponse,
 };
+pub(crate) use catalogs::{CatalogReader, find_missing_extents};
 pub use error::ErrorResponse;
+pub use extents::ExtentUploadError;
+
+/// Tunables for the API handlers.
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    /// How many extents to check for existence in a single storage call.
+    pub check_batch_size: usize,
+    /// How many existence check batches may be in flight at once.
+    pub check_concurrency: usize,
+    /// Where to put temporary files while processing catalogs (the system temporary
+    /// directory if not set).
+    pub scratch_dir: Option<PathBuf>,
+    /// How many bytes of scratch space may be in use at once, across all requests.
+    ///
+    /// Requests that would go over are refused with 503 Service Unavailable.
+    pub max_scratch_bytes: Option<u64>,
+    /// Which catalogs to keep when pruning.
+    pub retention: RetentionPolicy,
+    /// Only accept uploads of extents that a pending or uploading catalog is missing.
+    ///
+    /// Other extents are refused with 409 Conflict, so storage can't be filled with data
+    /// no catalog references.
+    pub strict_extents: bool,
+    /// Bearer token clients must present, if any.
+    ///
+    /// Requests without it are refused with 401 Unauthorized.
+    pub auth: Option<AuthToken>,
+    /// Keys to verify keyed extent IDs with.
+    ///
+    /// Clients declare which key a catalog's extent IDs are derived with, and extents
+    /// declaring a key that isn't here are refused with 400 Bad Request, unless it's
+    /// trusted in `trusted_extent_keys`.
+    pub extent_keys: Vec<ExtentKey>,
+    /// Keys whose extents are stored without verifying them, when the key isn't known.
+    pub trusted_extent_keys: TrustedKeys,
+    /// How long a catalog upload may stay pending (initiated, with no catalog sent) before
+    /// it's expired when the server starts.
+    pub session_ttl: Duration,
+    /// Store a parity block with every new extent, to repair corruption with (see
+    /// [`crate::parity`] and [`crate::scrub`]).
+    pub parity: Option<ParityScheme>,
+}
+
+impl Default for ApiOptions {
+    fn default() -> Self {
+        Self {
+            check_batch_size: 1000,
+            check_concurrency: 4,
+            scratch_dir: None,
+            max_scratch_bytes: None,
+            retention: RetentionPolicy::default(),
+            strict_extents: false,
+            auth: None,
+            extent_keys: Vec::new(),
+            trusted_extent_keys: TrustedKeys::None,
+            session_ttl: DEFAULT_SESSION_TTL,
+            parity: None,
+        }
+    }
+}
+
+/// Which keyed extents to store without verifying them against their IDs.
+///
+/// Key IDs aren't secret, so any client that can upload can declare a trusted key and store
+/// data under any ID, even the plain BLAKE3 ID of other content, which other clients would
+/// then take as already stored. Only trust keys whose clients are all trusted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TrustedKeys {
+    /// None: extents with unknown keys are refused.
+    #[default]
+    None,
+    /// Only extents declaring one of these key IDs.
+    Only(Vec<String>),
+    /// Extents declaring any key, for a server whose only client is its own process.
+    Any,
+}
+
+impl TrustedKeys {
+    /// Whether extents declaring this key ID are trusted.
+    pub fn trusts(&self, key_id: &str) -> bool {
+        match self {
+            Self::None => false,
+            Self::Only(key_ids) => key_ids.iter().any(|trusted| trusted == key_id),
+            Self::Any => true,
+        }
+    }
+}
 
 pub struct AppState<S: Storage> {
     pub storage: Arc<S>,
     pub db: Arc<Mutex<UploadDb>>,
+    pub options: Arc<ApiOptions>,
+    pub scratch: Scratch,
 }
 
 impl<S: Storage> Clone for AppState<S> {
@@ -25,18 +128,35 @@ impl<S: Storage> Clone for AppState<S> {
         Self {
             storage: Arc::clone(&self.storage),
             db: Arc::clone(&self.db),
+            options: Arc::clone(&self.options),
+            scratch: self.scratch.clone(),
         }
     }
 }
 
 pub fn router<S: Storage>(storage: S, db: UploadDb) -> Router {
+    router_with_options(storage, db, ApiOptions::default())
+}
+
+pub fn router_with_options<S: Storage>(storage: S, db: UploadDb, options: ApiOptions) -> Router {
+    let auth = options.auth.clone();
     let state = AppState {
-        storage: Arc::new(storage),
+        storage: Arc::new(TracedStorage::new(storage)),
         db: Arc::new(Mutex::new(db)),
+        scratch: Scratch::new(options.scratch_dir.clone(), options.max_scratch_bytes),
+        options: Arc::new(options),
     };
 
-    Router::new()
+    let router = Router::new()
         .nest("/extents", extents::router())
         .nest("/catalogs", catalogs::router())
-        .with_state(state)
+        .nest("/admin", admin::router())
+        .nest("/probe", probe::router())
+        .with_state(state);
+
+    let router = match auth {
+        Some(token) => router.layer(middleware::from_fn_with_state(token, auth::require_token)),
+        None => router,
+    };
+    router.layer(middleware::from_fn(trace::trace_request))
 }
@@ -0,0 +1,346 @@
+//! Administration API handlers.
+//!
+//! - POST /admin/prune?dry_run=true - Estimate what pruning by the retention rules would free
+//! - GET /admin/oplog?after=N - Get operation log entries, for replicas to follow
+//! - GET /admin/orphans?min_age=24h - Report extents in storage that no catalog references
+//! - GET /admin/consistency - Report where the upload database and storage disagree
+//! - GET /admin/sketches/:id - Get the similarity sketch of an extent
+//! - POST /admin/sketches - Batch get similarity sketches of extents
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::B3Id;
+use crate::api::extents::parse_id;
+use crate::api::{AppState, ErrorResponse};
+use crate::consistency::{ConsistencyError, check_consistency};
+use crate::db::CatalogInfo;
+use crate::oplog::{LogEntry, OpLogError, read_log};
+use crate::orphans::{DEFAULT_MIN_AGE, OrphanError, OrphanReport, find_orphans, parse_min_age};
+use crate::prune::{PruneError, PrunePlan, RetentionPolicy, estimate_prune};
+use crate::sketch::{ExtentSketch, Sketcher};
+use crate::storage::{Storage, StorageError};
+
+pub fn router<S: Storage>() -> Router<AppState<S>> {
+    Router::new()
+        .route("/prune", post(prune))
+        .route("/oplog", get(get_oplog))
+        .route("/orphans", get(get_orphans))
+        .route("/consistency", get(get_consistency))
+        .route("/sketches", post(get_sketches))
+        .route("/sketches/{id}", get(get_sketch))
+}
+
+/// Query parameters for pruning: the retention rules given override the configured ones.
+#[derive(Debug, Deserialize)]
+struct PruneParams {
+    #[serde(default)]
+    dry_run: bool,
+    keep_last: Option<usize>,
+    keep_daily: Option<usize>,
+    keep_weekly: Option<usize>,
+    keep_monthly: Option<usize>,
+}
+
+/// Report of what pruning would do.
+#[derive(Debug, Serialize)]
+struct PruneResponse {
+    dry_run: bool,
+    /// The retention rules that were applied
+    policy: RetentionPolicy,
+    /// IDs of the catalogs that would be kept
+    kept: Vec<String>,
+    /// Catalogs that would be removed, oldest first
+    removed: Vec<RemovedCatalog>,
+    /// How many extents no remaining catalog would reference (and are in storage)
+    unreferenced_extents: usize,
+    /// Bytes freed by removing the catalog files
+    catalog_bytes: u64,
+    /// Bytes freed by removing the unreferenced extents
+    extent_bytes: u64,
+    /// Total bytes freed
+    total_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct RemovedCatalog {
+    id: String,
+    machine: Option<String>,
+    created_at: i64,
+    /// Size of the catalog file
+    bytes: u64,
+}
+
+/// POST /admin/prune - Evaluate the retention rules
+///
+/// Only dry runs are supported: the catalogs that would be removed are listed along with
+/// exactly how many bytes that would free, and nothing is deleted.
+async fn prune<S: Storage>(
+    State(state): State<AppState<S>>,
+    Query(params): Query<PruneParams>,
+) -> Result<Response, PruneError> {
+    if !params.dry_run {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: "Only dry runs are supported".to_string(),
+                detail: Some("pass dry_run=true".to_string()),
+            }),
+        )
+            .into_response());
+    }
+
+    let policy = state.options.retention.with_overrides(&RetentionPolicy {
+        keep_last: params.keep_last,
+        keep_daily: params.keep_daily,
+        keep_weekly: params.keep_weekly,
+        keep_monthly: params.keep_monthly,
+    });
+
+    let plan = {
+        let db = state.db.lock().unwrap();
+        PrunePlan::new(&db, &policy)?
+    };
+    let estimate = estimate_prune(
+        state.storage.clone(),
+        &plan,
+        state.options.check_concurrency,
+    )
+    .await?;
+
+    let id = |c: &CatalogInfo| c.id.simple().to_string();
+    Ok(Json(PruneResponse {
+        dry_run: true,
+        kept: plan.kept.iter().map(id).collect(),
+        removed: plan
+            .removed
+            .iter()
+            .zip(&estimate.catalog_sizes)
+            .map(|(c, &bytes)| RemovedCatalog {
+                id: id(c),
+                machine: c.machine.clone(),
+                created_at: c.created_at,
+                bytes,
+            })
+            .collect(),
+        unreferenced_extents: estimate.stored_extents,
+        catalog_bytes: estimate.catalog_bytes(),
+        extent_bytes: estimate.extent_bytes,
+        total_bytes: estimate.total_bytes(),
+        policy,
+    })
+    .into_response())
+}
+
+/// Most log entries returned at once.
+const OPLOG_PAGE: usize = 1000;
+
+/// Query parameters for reading the operation log.
+#[derive(Debug, Deserialize)]
+struct OpLogParams {
+    /// Sequence number of the last entry already applied
+    #[serde(default)]
+    after: u64,
+}
+
+/// Operation log entries, in order.
+#[derive(Debug, Serialize)]
+struct OpLogResponse {
+    /// Sequence number of the last entry in the log
+    last_seq: u64,
+    /// Entries after the one asked for, at most [`OPLOG_PAGE`] of them
+    entries: Vec<LogEntry>,
+}
+
+/// GET /admin/oplog?after=N - Get operation log entries after a sequence number
+///
+/// A replica applies the entries in order and asks again from the last one, until it has
+/// caught up with `last_seq`.
+async fn get_oplog<S: Storage>(
+    State(state): State<AppState<S>>,
+    Query(params): Query<OpLogParams>,
+) -> Result<Response, OpLogError> {
+    // Changes are logged while the database is locked, so this sees whole entries only
+    let db = state.db.lock().unwrap();
+    let Some(oplog) = db.oplog() else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Operation log not enabled".to_string(),
+                detail: None,
+            }),
+        )
+            .into_response());
+    };
+
+    let mut entries = read_log(oplog.path(), params.after)?;
+    entries.truncate(OPLOG_PAGE);
+    Ok(Json(OpLogResponse {
+        last_seq: oplog.last_seq(),
+        entries,
+    })
+    .into_response())
+}
+
+/// Query parameters for the orphan extent report.
+#[derive(Debug, Deserialize)]
+struct OrphansParams {
+    /// Leave out extents stored more recently than this, as a duration like `24h`
+    min_age: Option<String>,
+}
+
+/// GET /admin/orphans?min_age=24h - Report extents in storage that no catalog references
+///
+/// Nothing is deleted; see the `orphans --delete` subcommand of the server.
+async fn get_orphans<S: Storage>(
+    State(state): State<AppState<S>>,
+    Query(params): Query<OrphansParams>,
+) -> Result<Response, OrphanError> {
+    let min_age = match params.min_age.as_deref().map(parse_min_age) {
+        None => DEFAULT_MIN_AGE,
+        Some(Ok(min_age)) => min_age,
+        Some(Err(detail)) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: "Invalid min_age".to_string(),
+                    detail: Some(detail),
+                }),
+            )
+                .into_response());
+        }
+    };
+
+    let report: OrphanReport = find_orphans(&*state.storage, &state.db, min_age).await?;
+    Ok(Json(report).into_response())
+}
+
+/// Where the upload database and storage disagree.
+#[derive(Debug, Serialize)]
+struct ConsistencyResponse {
+    /// Whether they agree, ignoring stale sessions
+    consistent: bool,
+    /// IDs of uploading or complete catalogs that aren't in storage
+    missing: Vec<String>,
+    /// IDs of catalogs in storage that the database doesn't know about
+    untracked: Vec<String>,
+    /// IDs of pending catalogs older than the session TTL, to be expired at the next start
+    stale: Vec<String>,
+}
+
+/// GET /admin/consistency - Report where the upload database and storage disagree
+///
+/// Runs the same check as at startup, but doesn't expire anything.
+async fn get_consistency<S: Storage>(
+    State(state): State<AppState<S>>,
+) -> Result<Json<ConsistencyResponse>, ConsistencyError> {
+    let report = check_consistency(&*state.storage, &state.db, state.options.session_ttl).await?;
+    let ids = |ids: &[uuid::Uuid]| ids.iter().map(|id| id.simple().to_string()).collect();
+    Ok(Json(ConsistencyResponse {
+        consistent: report.is_consistent(),
+        missing: ids(&report.missing),
+        untracked: ids(&report.untracked),
+        stale: ids(&report.stale),
+    }))
+}
+
+/// Similarity sketch of an extent, with hashes in hex.
+#[derive(Debug, Serialize)]
+struct SketchResponse {
+    id: String,
+    simhash: String,
+    minhash: Vec<String>,
+}
+
+impl SketchResponse {
+    fn new(id: &B3Id, sketch: &ExtentSketch) -> Self {
+        Self {
+            id: id.as_hex(),
+            simhash: format!("{:016x}", sketch.simhash),
+            minhash: sketch.minhash.iter().map(|h| format!("{h:016x}")).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SketchesRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SketchesResponse {
+    /// Sketches in request order, null for extents not in storage
+    sketches: Vec<Option<SketchResponse>>,
+}
+
+/// GET /admin/sketches/:id - Get the similarity sketch of an extent
+async fn get_sketch<S: Storage>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StorageError> {
+    let id = parse_id(&id)?;
+    let sketch = load_sketch(&state, &id)
+        .await?
+        .ok_or(StorageError::NotFound)?;
+    Ok(Json(SketchResponse::new(&id, &sketch)))
+}
+
+/// POST /admin/sketches - Batch get similarity sketches of extents
+async fn get_sketches<S: Storage>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<SketchesRequest>,
+) -> Result<impl IntoResponse, StorageError> {
+    let ids: Vec<B3Id> = req
+        .ids
+        .iter()
+        .map(|s| parse_id(s))
+        .collect::<Result<_, _>>()?;
+
+    let mut sketches = Vec::with_capacity(ids.len());
+    for id in &ids {
+        let sketch = load_sketch(&state, id).await?;
+        sketches.push(sketch.map(|sketch| SketchResponse::new(id, &sketch)));
+    }
+    Ok(Json(SketchesResponse { sketches }))
+}
+
+/// Get the sketch of an extent, or `None` if the extent isn't in storage.
+///
+/// Extents stored before sketching was added (or whose sketch is unreadable) are sketched
+/// now, and the sketch stored for next time.
+async fn load_sketch<S: Storage>(
+    state: &AppState<S>,
+    id: &B3Id,
+) -> Result<Option<ExtentSketch>, StorageError> {
+    match state.storage.get_sketch(id).await {
+        Ok(data) => match ExtentSketch::decode(&data) {
+            Ok(sketch) => return Ok(Some(sketch)),
+            Err(err) => warn!(extent = %id.as_hex(), %err, "Unreadable extent sketch, redoing it"),
+        },
+        Err(StorageError::NotFound) => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut stream = match state.storage.get_extent(id).await {
+        Ok(stream) => stream,
+        Err(StorageError::NotFound) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut sketcher = Sketcher::default();
+    while let Some(chunk) = stream.try_next().await? {
+        sketcher.update(&chunk);
+    }
+    let sketch = sketcher.finish();
+
+    debug!(extent = %id.as_hex(), "Sketched stored extent");
+    state.storage.put_sketch(id, sketch.encode()).await?;
+    Ok(Some(sketch))
+}
@@ -0,0 +1,52 @@
+use axum::Json;
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header::AUTHORIZATION};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use super::error::ErrorResponse;
+
+/// A bearer token clients must present in the `Authorization` header.
+///
+/// Only a hash of the token is kept, and tokens are compared by hash in constant time.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken(blake3::Hash);
+
+impl AuthToken {
+    pub fn new(token: &str) -> Self {
+        Self(blake3::hash(token.as_bytes()))
+    }
+
+    fn matches(&self, presented: &str) -> bool {
+        self.0 == blake3::hash(presented.as_bytes())
+    }
+}
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+/// Refuse requests without the expected bearer token.
+pub(super) async fn require_token(
+    State(token): State<AuthToken>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if presented.is_some_and(|presented| token.matches(presented)) {
+        return next.run(request).await;
+    }
+
+    let body = ErrorResponse {
+        error: "Unauthorized".to_string(),
+        detail: Some("a valid bearer token is required".to_string()),
+    };
+    (StatusCode::UNAUTHORIZED, Json(body)).into_response()
+}
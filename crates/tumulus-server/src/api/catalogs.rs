@@ -4,31 +4,36 @@
 //! - POST /catalog - Initiate upload with catalog ID + checksum
 //! - PUT /catalog/:id - Upload catalog data
 //! - POST /catalog/:id - Finalize upload, check for missing extents
+//! - GET /catalogs?limit=N&continuation_token=T - List catalogs, a page at a time
 //! - POST /catalogs/check - Batch check which catalogs exist
+//! - HEAD /catalogs/:id - Check a catalog's status and checksum from headers alone
+//! - GET /catalogs/:id - Download a catalog file, once received
 //! - PUT /catalog/:id/patch - Upload a binary patch against a reference catalog
 
 use std::io::{BufReader, Write};
-use std::sync::Arc;
 
 use axum::{
     Json, Router,
     body::Bytes,
     extract::{DefaultBodyLimit, Path, Query, State},
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     response::IntoResponse,
-    routing::{get, post, put},
+    routing::{get, head, post, put},
 };
 use bytes::Buf;
-use rusqlite::Connection;
+use futures::{StreamExt, stream};
+use rusqlite::{Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 use crate::B3Id;
-use crate::api::AppState;
+use crate::api::extents::parse_id;
+use crate::api::{ApiOptions, AppState};
 use crate::blob::BlobLayout;
-use crate::db::CatalogStatus;
+use crate::db::{CatalogStatus, SharedExtents};
+use crate::scratch::{Reservation, ReservedWriter, Scratch, ScratchFull};
 use crate::storage::{Storage, StorageError};
 
 /// Request body for initiating a catalog upload.
@@ -74,15 +79,44 @@ pub struct FinalizeResponse {
 pub struct CheckCatalogsRequest {
     /// List of catalog IDs to check (UUID strings)
     pub ids: Vec<String>,
+    /// A sample of the extents of the catalog about to be uploaded, to compare the
+    /// existing catalogs against
+    #[serde(default)]
+    pub sketch: Option<Vec<SketchExtent>>,
+}
+
+/// An extent in a catalog sketch.
+#[derive(Debug, Deserialize)]
+pub struct SketchExtent {
+    /// Extent ID (hex-encoded)
+    pub id: String,
+    /// Size of the extent in bytes
+    pub bytes: u64,
+}
+
+/// How much of the sketch an existing catalog shares.
+#[derive(Debug, Serialize)]
+pub struct SharedSummary {
+    /// Catalog ID
+    pub id: String,
+    /// How many of the sketch's extents the catalog references
+    pub extents: u64,
+    /// Total bytes of those extents, each counted once
+    pub bytes: u64,
 }
 
 /// Response for batch catalog existence check.
 /// Returns catalog IDs sorted by preference (best choice first).
-/// The server decides the sorting algorithm (currently by creation time, newest first).
+/// The server decides the sorting algorithm (currently by bytes shared with the sketch if
+/// one was given, then by creation time, newest first).
 #[derive(Debug, Serialize)]
 pub struct CheckCatalogsResponse {
     /// List of catalog IDs that exist on the server, sorted by preference (best first)
     pub existing: Vec<String>,
+    /// If a sketch was given, how much of it each existing catalog shares, in the same
+    /// order as `existing`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared: Option<Vec<SharedSummary>>,
 }
 
 /// Query parameters for patch upload.
@@ -99,6 +133,8 @@ pub fn router<S: Storage>() -> Router<AppState<S>> {
         .route("/", get(list_catalogs))
         .route("/", post(initiate_upload))
         .route("/check", post(check_catalogs))
+        .route("/{id}", head(catalog_head))
+        .route("/{id}", get(download_catalog))
         .route("/{id}", put(upload_catalog))
         .route("/{id}", post(finalize_upload))
         .route("/{id}/patch", put(upload_catalog_patch))
@@ -106,13 +142,54 @@ pub fn router<S: Storage>() -> Router<AppState<S>> {
         .layer(DefaultBodyLimit::max(256 * 1024 * 1024))
 }
 
+/// Query parameters for listing catalogs.
+#[derive(Debug, Deserialize)]
+struct ListCatalogsParams {
+    /// Most catalogs to return; all of them if not set
+    limit: Option<usize>,
+    /// Where to carry on from, as returned with the previous page
+    continuation_token: Option<String>,
+}
+
+/// Header with the token to get the next page of catalogs with.
+const CONTINUATION_TOKEN: &str = "x-continuation-token";
+
 /// GET /catalogs - List all complete catalogs
+///
+/// With a `limit` or `continuation_token`, catalogs are listed a page at a time in ID
+/// order. When there are more, the response has an `X-Continuation-Token` header to pass
+/// back for the next page.
 async fn list_catalogs<S: Storage>(
     State(state): State<AppState<S>>,
+    Query(params): Query<ListCatalogsParams>,
 ) -> Result<impl IntoResponse, StorageError> {
-    let ids = state.storage.list_catalogs().await?;
-    let ids: Vec<String> = ids.iter().map(|id| id.simple().to_string()).collect();
-    Ok(Json(ids))
+    let simple = |id: &Uuid| id.simple().to_string();
+    if params.limit.is_none() && params.continuation_token.is_none() {
+        let ids = state.storage.list_catalogs().await?;
+        return Ok(Json(ids.iter().map(simple).collect::<Vec<_>>()).into_response());
+    }
+
+    let after = params
+        .continuation_token
+        .map(|token| {
+            Uuid::parse_str(&token)
+                .map_err(|_| StorageError::InvalidData("invalid continuation token".into()))
+        })
+        .transpose()?;
+    let page = state
+        .storage
+        .list_catalogs_page(after, params.limit.unwrap_or(usize::MAX))
+        .await?;
+
+    let ids: Vec<String> = page.ids.iter().map(simple).collect();
+    let mut response = Json(ids).into_response();
+    if let Some(next) = page.next {
+        response.headers_mut().insert(
+            CONTINUATION_TOKEN,
+            HeaderValue::from_str(&simple(&next)).expect("UUIDs are valid header values"),
+        );
+    }
+    Ok(response)
 }
 
 /// POST /catalogs/check - Batch check which catalogs exist
@@ -120,11 +197,16 @@ async fn list_catalogs<S: Storage>(
 /// Returns the subset of requested catalog IDs that exist on the server,
 /// sorted by preference (best choice for use as a reference first).
 /// Currently sorts by creation time (newest first).
+///
+/// With a sketch (a sample of extent IDs and sizes from the catalog about to be uploaded),
+/// each existing catalog is also summarised by how many of the sketch's extents it
+/// references and their total size, and catalogs sharing more bytes come first: those
+/// leave the least to upload. Sketch extents with invalid IDs are skipped.
 async fn check_catalogs<S: Storage>(
     State(state): State<AppState<S>>,
     Json(req): Json<CheckCatalogsRequest>,
 ) -> Result<impl IntoResponse, CatalogError> {
-    let mut existing: Vec<(String, i64)> = Vec::new();
+    let mut existing: Vec<(Uuid, i64)> = Vec::new();
 
     let db = state.db.lock().unwrap();
     for id_str in &req.ids {
@@ -136,23 +218,174 @@ async fn check_catalogs<S: Storage>(
         if let Some(info) = db.get_catalog(catalog_id)? {
             // Only include complete catalogs
             if info.status == CatalogStatus::Complete {
-                existing.push((catalog_id.simple().to_string(), info.created_at));
+                existing.push((catalog_id, info.created_at));
             }
         }
     }
 
     // Sort by creation time, newest first (best reference choice)
-    existing.sort_by(|a, b| b.1.cmp(&a.1));
+    existing.sort_by_key(|(_, created_at)| std::cmp::Reverse(*created_at));
+
+    let Some(sketch) = req.sketch else {
+        let existing = existing
+            .into_iter()
+            .map(|(id, _)| id.simple().to_string())
+            .collect();
+        return Ok(Json(CheckCatalogsResponse {
+            existing,
+            shared: None,
+        }));
+    };
+
+    let sketch: Vec<(B3Id, u64)> = sketch
+        .iter()
+        .filter_map(|extent| Some((parse_id(&extent.id).ok()?, extent.bytes)))
+        .collect();
+    let ids: Vec<Uuid> = existing.iter().map(|(id, _)| *id).collect();
+    let mut shared: Vec<(Uuid, SharedExtents)> = ids
+        .iter()
+        .copied()
+        .zip(db.shared_extents(&ids, &sketch)?)
+        .collect();
+    drop(db);
+
+    // Most bytes shared first, keeping the newest first among equals
+    shared.sort_by_key(|(_, shared)| std::cmp::Reverse(shared.bytes));
+
+    Ok(Json(CheckCatalogsResponse {
+        existing: shared
+            .iter()
+            .map(|(id, _)| id.simple().to_string())
+            .collect(),
+        shared: Some(
+            shared
+                .into_iter()
+                .map(|(id, shared)| SharedSummary {
+                    id: id.simple().to_string(),
+                    extents: shared.extents,
+                    bytes: shared.bytes,
+                })
+                .collect(),
+        ),
+    }))
+}
 
-    let existing: Vec<String> = existing.into_iter().map(|(id, _)| id).collect();
+/// HEAD /catalogs/:id - Describe a catalog in headers, without a body
+///
+/// For freshness checks without listing or downloading anything:
+/// - `ETag` and `X-Catalog-Checksum`: the catalog's BLAKE3 checksum (hex, quoted in the ETag)
+/// - `X-Catalog-Status`: `pending`, `uploading`, or `complete`
+/// - `Last-Modified` and `X-Catalog-Created`: when the catalog was created (the latter in
+///   seconds since the epoch)
+/// - `Content-Length`: size of the stored catalog file, once it has been received
+async fn catalog_head<S: Storage>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, CatalogError> {
+    let catalog_id = parse_uuid(&id)?;
+    let info = state
+        .db
+        .lock()
+        .unwrap()
+        .get_catalog(catalog_id)?
+        .ok_or(CatalogError::NotFound(catalog_id))?;
+
+    let size = if info.status == CatalogStatus::Pending {
+        None
+    } else {
+        match state.storage.catalog_meta(catalog_id).await {
+            Ok(meta) => Some(meta.size),
+            Err(StorageError::NotFound) => None,
+            Err(err) => return Err(CatalogError::Storage(err)),
+        }
+    };
 
-    Ok(Json(CheckCatalogsResponse { existing }))
+    let checksum = info.checksum.as_hex();
+    let mut headers = vec![
+        (header::ETAG, format!("\"{checksum}\"")),
+        (
+            header::HeaderName::from_static("x-catalog-checksum"),
+            checksum,
+        ),
+        (
+            header::HeaderName::from_static("x-catalog-status"),
+            info.status.as_str().to_string(),
+        ),
+        (
+            header::HeaderName::from_static("x-catalog-created"),
+            info.created_at.to_string(),
+        ),
+    ];
+    let printer = jiff::fmt::rfc2822::DateTimePrinter::new();
+    if let Ok(created) = jiff::Timestamp::from_second(info.created_at)
+        && let Ok(date) = printer.timestamp_to_rfc9110_string(&created)
+    {
+        headers.push((header::LAST_MODIFIED, date));
+    }
+    if let Some(size) = size {
+        headers.push((header::CONTENT_LENGTH, size.to_string()));
+    }
+
+    let mut response = StatusCode::OK.into_response();
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    Ok(response)
+}
+
+/// GET /catalogs/:id - Download a catalog file, as it was uploaded
+///
+/// The catalog's checksum is in the `X-Catalog-Checksum` header (and quoted as the `ETag`),
+/// and its upload status in `X-Catalog-Status`. Catalogs still pending aren't found, as
+/// their file hasn't been received yet.
+async fn download_catalog<S: Storage>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, CatalogError> {
+    let catalog_id = parse_uuid(&id)?;
+    let info = state
+        .db
+        .lock()
+        .unwrap()
+        .get_catalog(catalog_id)?
+        .filter(|info| info.status != CatalogStatus::Pending)
+        .ok_or(CatalogError::NotFound(catalog_id))?;
+
+    let data = state
+        .storage
+        .get_catalog(catalog_id)
+        .await
+        .map_err(|err| match err {
+            StorageError::NotFound => CatalogError::NotFound(catalog_id),
+            other => CatalogError::Storage(other),
+        })?;
+
+    let checksum = info.checksum.as_hex();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::ETAG, format!("\"{checksum}\"")),
+            (
+                header::HeaderName::from_static("x-catalog-status"),
+                info.status.as_str().to_string(),
+            ),
+            (
+                header::HeaderName::from_static("x-catalog-checksum"),
+                checksum,
+            ),
+        ],
+        data,
+    ))
 }
 
 /// Result of checking catalog state in the database
 enum CatalogCheckResult {
     /// Catalog exists with matching checksum, return extent IDs to check
     ResumeUpload { extent_ids: Vec<B3Id> },
+    /// Catalog with the same checksum exists under another ID, resume that upload instead
+    ResumeExisting { id: Uuid, extent_ids: Vec<B3Id> },
     /// Catalog exists with different checksum, use new ID
     NewId { new_id: Uuid },
     /// Catalog doesn't exist, created new entry
@@ -164,7 +397,12 @@ enum CatalogCheckResult {
 /// Checks if the catalog ID exists:
 /// - If exists with matching checksum → resuming upload
 /// - If exists with different checksum → generate new ID
+/// - If another catalog with the same checksum was received → resume that upload
 /// - Otherwise → create new entry
+///
+/// Each responds 200 OK, with the ID to carry on under in the body. Servers before
+/// checksum lookup answered a change of ID with 303 See Other, without a `Location` (which
+/// HTTP clients would otherwise follow with a GET); clients need only read the body.
 async fn initiate_upload<S: Storage>(
     State(state): State<AppState<S>>,
     Json(req): Json<InitiateRequest>,
@@ -178,7 +416,7 @@ async fn initiate_upload<S: Storage>(
         if let Some(existing) = db.get_catalog(req.id)? {
             if existing.checksum == checksum {
                 // Resuming - get extent IDs to check
-                let extent_ids = db.get_catalog_extents(req.id)?;
+                let extent_ids = db.get_unverified_catalog_extents(req.id)?;
                 CatalogCheckResult::ResumeUpload { extent_ids }
             } else {
                 // Checksum mismatch - generate a new ID
@@ -186,6 +424,17 @@ async fn initiate_upload<S: Storage>(
                 db.create_catalog(new_id, &checksum)?;
                 CatalogCheckResult::NewId { new_id }
             }
+        } else if let Some(existing) = db
+            .find_catalog_by_checksum(&checksum)?
+            .filter(|existing| existing.status != CatalogStatus::Pending)
+        {
+            // Same catalog contents already received under another ID (e.g. the
+            // catalog file was regenerated), so carry on with that upload
+            let extent_ids = db.get_unverified_catalog_extents(existing.id)?;
+            CatalogCheckResult::ResumeExisting {
+                id: existing.id,
+                extent_ids,
+            }
         } else {
             // New catalog upload
             db.create_catalog(req.id, &checksum)?;
@@ -198,7 +447,7 @@ async fn initiate_upload<S: Storage>(
             info!(catalog_id = %req.id, "Resuming catalog upload");
 
             // Now do async storage check outside of lock
-            let missing = get_missing_extents_from_ids(&state.storage, extent_ids).await?;
+            let missing = missing_extents(&state, Some(req.id), extent_ids).await?;
             let missing_hex: Vec<String> = missing.iter().map(|id| id.as_hex()).collect();
 
             Ok((
@@ -210,6 +459,25 @@ async fn initiate_upload<S: Storage>(
                 }),
             ))
         }
+        CatalogCheckResult::ResumeExisting { id, extent_ids } => {
+            info!(
+                requested_id = %req.id,
+                catalog_id = %id,
+                "Catalog with the same checksum exists, resuming its upload"
+            );
+
+            let missing = missing_extents(&state, Some(id), extent_ids).await?;
+            let missing_hex: Vec<String> = missing.iter().map(|id| id.as_hex()).collect();
+
+            Ok((
+                StatusCode::OK,
+                Json(InitiateResponse {
+                    id: id.simple().to_string(),
+                    resuming: true,
+                    missing_extents: Some(missing_hex),
+                }),
+            ))
+        }
         CatalogCheckResult::NewId { new_id } => {
             info!(
                 old_id = %req.id,
@@ -218,7 +486,7 @@ async fn initiate_upload<S: Storage>(
             );
 
             Ok((
-                StatusCode::SEE_OTHER,
+                StatusCode::OK,
                 Json(InitiateResponse {
                     id: new_id.simple().to_string(),
                     resuming: false,
@@ -269,7 +537,7 @@ async fn upload_catalog<S: Storage>(
             Some(info) => {
                 if info.status != CatalogStatus::Pending {
                     // Catalog already uploaded, get extent IDs to check
-                    let extent_ids = db.get_catalog_extents(catalog_id)?;
+                    let extent_ids = db.get_unverified_catalog_extents(catalog_id)?;
                     UploadCheckResult::AlreadyUploaded { extent_ids }
                 } else {
                     UploadCheckResult::Pending {
@@ -285,7 +553,7 @@ async fn upload_catalog<S: Storage>(
         UploadCheckResult::NotFound => Err(CatalogError::NotFound(catalog_id)),
         UploadCheckResult::AlreadyUploaded { extent_ids } => {
             // Just return missing extents
-            let missing = get_missing_extents_from_ids(&state.storage, extent_ids).await?;
+            let missing = missing_extents(&state, Some(catalog_id), extent_ids).await?;
             let missing_hex: Vec<String> = missing.iter().map(|id| id.as_hex()).collect();
             Ok(Json(UploadResponse {
                 missing_extents: missing_hex,
@@ -331,12 +599,24 @@ async fn process_catalog_contents<S: Storage>(
     log_message: &str,
 ) -> Result<Vec<B3Id>, CatalogError> {
     // Create a streaming catalog reader to avoid loading everything into memory
-    let catalog_reader = CatalogReader::new(catalog_data)?;
+    let catalog_reader = CatalogReader::new(catalog_data, &state.scratch)?;
 
     // Extract extent IDs (we need all of them for the batch existence check)
     let extent_ids = catalog_reader.extent_ids()?;
     let blob_count = catalog_reader.blob_count()?;
 
+    let machine = catalog_reader.machine()?;
+
+    // Remember everything the catalog references, not just what's missing, so that
+    // extents can be traced back to catalogs
+    {
+        let db = state.db.lock().unwrap();
+        db.set_extent_references(catalog_id, &extent_ids)?;
+        if let Some(machine) = &machine {
+            db.set_machine(catalog_id, machine)?;
+        }
+    }
+
     info!(
         catalog_id = %catalog_id,
         extent_count = extent_ids.len(),
@@ -365,19 +645,8 @@ async fn process_catalog_contents<S: Storage>(
         }
     }
 
-    // Batch check which extents already exist
-    let exists = state
-        .storage
-        .extents_exist(&extent_ids)
-        .await
-        .map_err(CatalogError::Storage)?;
-
-    // Filter to only missing extents
-    let missing_extents: Vec<B3Id> = extent_ids
-        .into_iter()
-        .zip(exists.iter())
-        .filter_map(|(id, &exists)| if exists { None } else { Some(id) })
-        .collect();
+    // Check which extents already exist; only the missing ones are tracked from here on
+    let missing_extents = missing_extents(state, None, extent_ids).await?;
 
     info!(
         catalog_id = %catalog_id,
@@ -426,11 +695,14 @@ async fn upload_catalog_patch<S: Storage>(
             other => CatalogError::Storage(other),
         })?;
 
+    // Everything patching holds in memory counts against the scratch limit
+    let mut reservation = state.scratch.reserve(0)?;
+
     // Decompress the patch data (it should be zstd-compressed)
-    let patch_data = decompress_if_needed(&body)?;
+    let patch_data = decompress_if_needed(&body, &mut reservation)?;
 
     // Decompress reference catalog if needed
-    let reference_decompressed = decompress_if_needed(&reference_data)?;
+    let reference_decompressed = decompress_if_needed(&reference_data, &mut reservation)?;
 
     // Apply the patch to get the target catalog
     let mut target_decompressed = Vec::new();
@@ -438,9 +710,15 @@ async fn upload_catalog_patch<S: Storage>(
         .map_err(|e| CatalogError::InvalidCatalog(format!("Invalid patch data: {}", e)))?
         .apply(
             &reference_decompressed,
-            std::io::Cursor::new(&mut target_decompressed),
+            ReservedWriter::new(&mut target_decompressed, &mut reservation),
         )
-        .map_err(|e| CatalogError::InvalidCatalog(format!("Failed to apply patch: {}", e)))?;
+        .map_err(|e| match CatalogError::from(e) {
+            CatalogError::Io(e) => {
+                CatalogError::InvalidCatalog(format!("Failed to apply patch: {}", e))
+            }
+            other => other,
+        })?;
+    drop(patch_data);
 
     // Verify the checksum of the reconstructed catalog
     let actual_checksum = blake3::hash(&target_decompressed);
@@ -460,10 +738,10 @@ async fn upload_catalog_patch<S: Storage>(
     // Compress the reconstructed catalog for storage
     let mut compressed = Vec::new();
     {
-        let mut encoder =
-            zstd::stream::Encoder::new(&mut compressed, 19).map_err(CatalogError::Io)?;
-        std::io::Write::write_all(&mut encoder, &target_decompressed).map_err(CatalogError::Io)?;
-        encoder.finish().map_err(CatalogError::Io)?;
+        let writer = ReservedWriter::new(&mut compressed, &mut reservation);
+        let mut encoder = zstd::stream::Encoder::new(writer, 19)?;
+        encoder.write_all(&target_decompressed)?;
+        encoder.finish()?;
     }
 
     // Create the catalog entry if it doesn't exist
@@ -498,17 +776,22 @@ async fn upload_catalog_patch<S: Storage>(
     }))
 }
 
-/// Decompress data if it's zstd-compressed, otherwise return as-is.
-fn decompress_if_needed(data: &[u8]) -> Result<Vec<u8>, CatalogError> {
+/// Decompress data if it's zstd-compressed, otherwise copy it as-is, counting the result
+/// against a scratch reservation.
+fn decompress_if_needed(
+    data: &[u8],
+    reservation: &mut Reservation,
+) -> Result<Vec<u8>, CatalogError> {
+    let mut decompressed = Vec::new();
+    let mut writer = ReservedWriter::new(&mut decompressed, reservation);
     if data.len() >= 4 && data[0..4] == [0x28, 0xB5, 0x2F, 0xFD] {
         let reader = BufReader::new(data);
-        let mut decoder = zstd::stream::Decoder::new(reader).map_err(CatalogError::Io)?;
-        let mut decompressed = Vec::new();
-        std::io::Read::read_to_end(&mut decoder, &mut decompressed).map_err(CatalogError::Io)?;
-        Ok(decompressed)
+        let mut decoder = zstd::stream::Decoder::new(reader)?;
+        std::io::copy(&mut decoder, &mut writer)?;
     } else {
-        Ok(data.to_vec())
+        writer.write_all(data)?;
     }
+    Ok(decompressed)
 }
 
 /// Result of checking catalog for finalization
@@ -540,7 +823,7 @@ async fn finalize_upload<S: Storage>(
                 if info.status == CatalogStatus::Complete {
                     FinalizeCheckResult::Complete
                 } else {
-                    let extent_ids = db.get_catalog_extents(catalog_id)?;
+                    let extent_ids = db.get_unverified_catalog_extents(catalog_id)?;
                     FinalizeCheckResult::CheckExtents { extent_ids }
                 }
             }
@@ -555,7 +838,7 @@ async fn finalize_upload<S: Storage>(
         }
         FinalizeCheckResult::CheckExtents { extent_ids } => {
             // Check which extents are still missing (async)
-            let missing = get_missing_extents_from_ids(&state.storage, extent_ids).await?;
+            let missing = missing_extents(&state, Some(catalog_id), extent_ids).await?;
 
             if missing.is_empty() {
                 // All extents are present, mark as complete
@@ -590,25 +873,73 @@ async fn finalize_upload<S: Storage>(
     }
 }
 
-/// Get the list of extents that are still missing given a list of extent IDs.
-async fn get_missing_extents_from_ids<S: Storage>(
-    storage: &Arc<S>,
+/// Find which of the given extents are missing from storage, for a catalog being uploaded.
+///
+/// When a `catalog_id` is given, extents found in storage are recorded as verified as each
+/// batch completes, so an interrupted check (e.g. by a server restart) resumes where it left
+/// off instead of starting over.
+async fn missing_extents<S: Storage>(
+    state: &AppState<S>,
+    catalog_id: Option<Uuid>,
     extent_ids: Vec<B3Id>,
+) -> Result<Vec<B3Id>, CatalogError> {
+    find_missing_extents(&*state.storage, &state.options, extent_ids, |present| {
+        if let Some(catalog_id) = catalog_id {
+            let db = state.db.lock().unwrap();
+            db.mark_extents_verified(catalog_id, present)?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Find which of the given extents are missing from storage.
+///
+/// Extents are checked in batches of `check_batch_size`, with at most `check_concurrency`
+/// batches in flight, so that very large catalogs don't stampede the storage backend. The
+/// extents of each batch found in storage are given to `present` as it completes.
+pub(crate) async fn find_missing_extents<S: Storage>(
+    storage: &S,
+    options: &ApiOptions,
+    extent_ids: Vec<B3Id>,
+    mut present: impl FnMut(&[B3Id]) -> Result<(), CatalogError>,
 ) -> Result<Vec<B3Id>, CatalogError> {
     if extent_ids.is_empty() {
         return Ok(Vec::new());
     }
 
-    let exists = storage
-        .extents_exist(&extent_ids)
-        .await
-        .map_err(CatalogError::Storage)?;
+    let batch_size = options.check_batch_size.max(1);
+    let concurrency = options.check_concurrency.max(1);
+    let total = extent_ids.len();
 
-    let missing: Vec<B3Id> = extent_ids
-        .into_iter()
-        .zip(exists.iter())
-        .filter_map(|(id, &exists)| if exists { None } else { Some(id) })
-        .collect();
+    let batches: Vec<Vec<B3Id>> = extent_ids.chunks(batch_size).map(<[_]>::to_vec).collect();
+    let mut checks = stream::iter(batches)
+        .map(|batch| async move {
+            let exists = storage.extents_exist(&batch).await?;
+            Ok::<_, StorageError>((batch, exists))
+        })
+        .buffered(concurrency);
+
+    let mut missing = Vec::new();
+    let mut checked = 0;
+    while let Some(result) = checks.next().await {
+        let (batch, exists) = result.map_err(CatalogError::Storage)?;
+
+        let mut found = Vec::new();
+        for (id, exists) in batch.iter().zip(exists) {
+            if exists {
+                found.push(*id);
+            } else {
+                missing.push(*id);
+            }
+        }
+        if !found.is_empty() {
+            present(&found)?;
+        }
+
+        checked += batch.len();
+        debug!(checked, total, "Checked extent batch");
+    }
 
     Ok(missing)
 }
@@ -619,32 +950,37 @@ async fn get_missing_extents_from_ids<S: Storage>(
 ///
 /// This struct decompresses the catalog to a temp file and provides methods to
 /// extract extent IDs and iterate over blob layouts without holding everything in memory.
-struct CatalogReader {
+pub(crate) struct CatalogReader {
     temp_file: NamedTempFile,
+    _reservation: Reservation,
 }
 
 impl CatalogReader {
     /// Create a new CatalogReader by decompressing the catalog data to a temp file.
-    fn new(data: &[u8]) -> Result<Self, CatalogError> {
+    ///
+    /// The temp file is counted against the scratch limit until the reader is dropped.
+    pub(crate) fn new(data: &[u8], scratch: &Scratch) -> Result<Self, CatalogError> {
         // Check if the data is zstd-compressed
         let is_compressed = data.len() >= 4 && data[0..4] == [0x28, 0xB5, 0x2F, 0xFD];
 
-        // Decompress if needed
-        let temp_file = if is_compressed {
-            let mut temp = NamedTempFile::new().map_err(CatalogError::Io)?;
-            let reader = BufReader::new(data.reader());
-            let mut decoder = zstd::stream::Decoder::new(reader).map_err(CatalogError::Io)?;
-            std::io::copy(&mut decoder, &mut temp).map_err(CatalogError::Io)?;
-            temp.flush().map_err(CatalogError::Io)?;
-            temp
-        } else {
-            let mut temp = NamedTempFile::new().map_err(CatalogError::Io)?;
-            temp.write_all(data).map_err(CatalogError::Io)?;
-            temp.flush().map_err(CatalogError::Io)?;
-            temp
-        };
+        let mut reservation = scratch.reserve(0)?;
+        let mut temp_file = scratch.temp_file()?;
+        {
+            let mut writer = ReservedWriter::new(&mut temp_file, &mut reservation);
+            if is_compressed {
+                let reader = BufReader::new(data.reader());
+                let mut decoder = zstd::stream::Decoder::new(reader)?;
+                std::io::copy(&mut decoder, &mut writer)?;
+            } else {
+                writer.write_all(data)?;
+            }
+            writer.flush()?;
+        }
 
-        Ok(Self { temp_file })
+        Ok(Self {
+            temp_file,
+            _reservation: reservation,
+        })
     }
 
     /// Open a SQLite connection to the catalog.
@@ -655,7 +991,7 @@ impl CatalogReader {
     }
 
     /// Extract all unique extent IDs from the catalog.
-    fn extent_ids(&self) -> Result<Vec<B3Id>, CatalogError> {
+    pub(crate) fn extent_ids(&self) -> Result<Vec<B3Id>, CatalogError> {
         let conn = self.open_connection()?;
 
         let mut extent_ids: Vec<B3Id> = Vec::new();
@@ -683,6 +1019,57 @@ impl CatalogReader {
         Ok(extent_ids)
     }
 
+    /// Get the paths of the files whose contents include an extent.
+    pub(crate) fn paths_for_extent(&self, extent_id: &B3Id) -> Result<Vec<String>, CatalogError> {
+        let conn = self.open_connection()?;
+        let query_err = |e: rusqlite::Error| {
+            CatalogError::InvalidCatalog(format!("Failed to query paths: {e}"))
+        };
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT DISTINCT f.path FROM files f
+                 JOIN blob_extents be ON be.blob_id = f.blob_id
+                 WHERE be.extent_id = ?1
+                 ORDER BY f.path",
+            )
+            .map_err(query_err)?;
+        let rows = stmt
+            .query_map([extent_id.as_slice()], |row| row.get::<_, Vec<u8>>(0))
+            .map_err(query_err)?;
+
+        rows.map(|row| {
+            row.map(|path| String::from_utf8_lossy(&path).into_owned())
+                .map_err(query_err)
+        })
+        .collect()
+    }
+
+    /// Read the catalog creation time (milliseconds since the epoch) from its metadata.
+    pub(crate) fn created(&self) -> Result<Option<i64>, CatalogError> {
+        self.metadata("created")
+    }
+
+    /// Read the machine ID from the catalog metadata.
+    pub(crate) fn machine(&self) -> Result<Option<String>, CatalogError> {
+        self.metadata("machine")
+    }
+
+    /// Read a metadata value, if it's present and of the expected type.
+    fn metadata<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, CatalogError> {
+        let conn = self.open_connection()?;
+        let value: Option<String> = conn
+            .query_row("SELECT value FROM metadata WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(|e| CatalogError::InvalidCatalog(format!("Failed to read metadata: {}", e)))?;
+        Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+    }
+
     /// Count the total number of blobs in the catalog.
     fn blob_count(&self) -> Result<u64, CatalogError> {
         let conn = self.open_connection()?;
@@ -693,7 +1080,7 @@ impl CatalogReader {
     }
 
     /// Create a batch iterator for processing blob layouts without loading all into memory.
-    fn blob_batches(&self, batch_size: usize) -> BlobBatchIterator<'_> {
+    pub(crate) fn blob_batches(&self, batch_size: usize) -> BlobBatchIterator<'_> {
         BlobBatchIterator {
             reader: self,
             batch_size,
@@ -704,7 +1091,7 @@ impl CatalogReader {
 }
 
 /// Iterator that yields batches of blob layouts from a catalog.
-struct BlobBatchIterator<'a> {
+pub(crate) struct BlobBatchIterator<'a> {
     reader: &'a CatalogReader,
     batch_size: usize,
     offset: usize,
@@ -713,7 +1100,7 @@ struct BlobBatchIterator<'a> {
 
 impl BlobBatchIterator<'_> {
     /// Get the next batch of blob layouts, or None if exhausted.
-    fn next_batch(&mut self) -> Result<Option<Vec<(B3Id, BlobLayout)>>, CatalogError> {
+    pub(crate) fn next_batch(&mut self) -> Result<Option<Vec<(B3Id, BlobLayout)>>, CatalogError> {
         let conn = self.reader.open_connection()?;
 
         // Get total count on first call
@@ -739,8 +1126,8 @@ impl BlobBatchIterator<'_> {
         let rows = stmt
             .query_map([self.batch_size as i64, self.offset as i64], |row| {
                 let blob_id: Vec<u8> = row.get(0)?;
-                let bytes: i64 = row.get(1)?;
-                Ok((blob_id, bytes as u64))
+                let bytes: u64 = row.get(1)?;
+                Ok((blob_id, bytes))
             })
             .map_err(|e| CatalogError::InvalidCatalog(format!("Failed to query blobs: {}", e)))?;
 
@@ -762,9 +1149,9 @@ impl BlobBatchIterator<'_> {
             let extent_rows = extent_stmt
                 .query_map([blob_id.as_slice()], |row| {
                     let extent_id: Vec<u8> = row.get(0)?;
-                    let offset: i64 = row.get(1)?;
-                    let bytes: i64 = row.get(2)?;
-                    Ok((extent_id, offset as u64, bytes as u64))
+                    let offset: u64 = row.get(1)?;
+                    let bytes: u64 = row.get(2)?;
+                    Ok((extent_id, offset, bytes))
                 })
                 .map_err(|e| {
                     CatalogError::InvalidCatalog(format!("Failed to query blob extents: {}", e))
@@ -789,15 +1176,24 @@ impl BlobBatchIterator<'_> {
                 });
             }
 
-            batch.push((
-                blob_id,
-                BlobLayout {
-                    total_bytes,
-                    extents,
-                },
-            ));
+            let layout = BlobLayout {
+                total_bytes,
+                extents,
+            };
+            layout.validate().map_err(|e| {
+                CatalogError::InvalidCatalog(format!(
+                    "Invalid layout of blob {}: {}",
+                    blob_id.as_hex(),
+                    e
+                ))
+            })?;
+            batch.push((blob_id, layout));
         }
 
+        // Fewer blobs than counted means the catalog is corrupt; don't spin on it
+        if batch.is_empty() {
+            return Ok(None);
+        }
         self.offset += batch.len();
         Ok(Some(batch))
     }
@@ -807,7 +1203,7 @@ fn parse_uuid(s: &str) -> Result<Uuid, CatalogError> {
     Uuid::parse_str(s).map_err(|_| CatalogError::InvalidUuid(s.to_string()))
 }
 
-fn parse_checksum(s: &str) -> Result<B3Id, CatalogError> {
+pub(super) fn parse_checksum(s: &str) -> Result<B3Id, CatalogError> {
     let bytes = hex::decode(s).map_err(|_| CatalogError::InvalidChecksum(s.to_string()))?;
     bytes
         .try_into()
@@ -838,10 +1234,29 @@ pub enum CatalogError {
     #[error("Storage error: {0}")]
     Storage(StorageError),
 
+    #[error("Server busy: {0}")]
+    Busy(#[from] ScratchFull),
+
     #[error("I/O error: {0}")]
-    Io(#[from] std::io::Error),
+    Io(std::io::Error),
 }
 
+impl From<std::io::Error> for CatalogError {
+    fn from(err: std::io::Error) -> Self {
+        // Running out of scratch space while writing surfaces as an I/O error
+        match err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<ScratchFull>())
+        {
+            Some(full) => CatalogError::Busy(*full),
+            None => CatalogError::Io(err),
+        }
+    }
+}
+
+/// How long to tell clients to wait before retrying when the server is busy, in seconds.
+const BUSY_RETRY_AFTER: u32 = 5;
+
 impl IntoResponse for CatalogError {
     fn into_response(self) -> axum::response::Response {
         use axum::http::StatusCode;
@@ -872,6 +1287,14 @@ impl IntoResponse for CatalogError {
                 error!(error = %e, "Storage error");
                 (StatusCode::INTERNAL_SERVER_ERROR, "Storage error", None)
             }
+            CatalogError::Busy(e) => {
+                warn!(error = %e, "Refusing request, out of scratch space");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Server busy",
+                    Some(e.to_string()),
+                )
+            }
             CatalogError::Io(e) => {
                 error!(error = %e, "I/O error");
                 (StatusCode::INTERNAL_SERVER_ERROR, "I/O error", None)
@@ -883,6 +1306,12 @@ impl IntoResponse for CatalogError {
             detail,
         };
 
-        (status, Json(body)).into_response()
+        let mut response = (status, Json(body)).into_response();
+        if matches!(self, CatalogError::Busy(_)) {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(BUSY_RETRY_AFTER));
+        }
+        response
     }
 }
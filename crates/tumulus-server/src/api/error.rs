@@ -3,6 +3,11 @@ use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use serde::Serialize;
 
+use crate::api::extents::ExtentUploadError;
+use crate::consistency::ConsistencyError;
+use crate::oplog::OpLogError;
+use crate::orphans::OrphanError;
+use crate::prune::PruneError;
 use crate::storage::StorageError;
 
 #[derive(Debug, Serialize)]
@@ -35,3 +40,78 @@ impl IntoResponse for StorageError {
         (status, Json(body)).into_response()
     }
 }
+
+impl IntoResponse for ExtentUploadError {
+    fn into_response(self) -> Response {
+        match self {
+            ExtentUploadError::Storage(e) => e.into_response(),
+            ExtentUploadError::Database(e) => {
+                tracing::error!(error = %e, "Database error");
+                let body = ErrorResponse {
+                    error: "Database error".to_string(),
+                    detail: None,
+                };
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+            }
+            ExtentUploadError::NotNeeded(_) => {
+                let body = ErrorResponse {
+                    error: "Extent not needed".to_string(),
+                    detail: Some(self.to_string()),
+                };
+                (StatusCode::CONFLICT, Json(body)).into_response()
+            }
+            ExtentUploadError::UnknownKey(_) => {
+                let body = ErrorResponse {
+                    error: "Unknown extent key".to_string(),
+                    detail: Some(self.to_string()),
+                };
+                (StatusCode::BAD_REQUEST, Json(body)).into_response()
+            }
+        }
+    }
+}
+
+impl IntoResponse for OpLogError {
+    fn into_response(self) -> Response {
+        tracing::error!(error = %self, "Operation log error");
+        let body = ErrorResponse {
+            error: "Operation log error".to_string(),
+            detail: None,
+        };
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+    }
+}
+
+impl IntoResponse for OrphanError {
+    fn into_response(self) -> Response {
+        match self {
+            OrphanError::Storage(e) => e.into_response(),
+            OrphanError::Database(e) => PruneError::Database(e).into_response(),
+        }
+    }
+}
+
+impl IntoResponse for ConsistencyError {
+    fn into_response(self) -> Response {
+        match self {
+            ConsistencyError::Storage(e) => e.into_response(),
+            ConsistencyError::Database(e) => PruneError::Database(e).into_response(),
+        }
+    }
+}
+
+impl IntoResponse for PruneError {
+    fn into_response(self) -> Response {
+        match self {
+            PruneError::Storage(e) => e.into_response(),
+            PruneError::Database(e) => {
+                tracing::error!(error = %e, "Database error");
+                let body = ErrorResponse {
+                    error: "Database error".to_string(),
+                    detail: None,
+                };
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+            }
+        }
+    }
+}
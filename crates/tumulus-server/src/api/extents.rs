@@ -1,16 +1,28 @@
 use axum::{
     Json, Router,
     body::Body,
-    extract::{Path, State},
-    http::{StatusCode, header},
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, Version, header},
     response::{IntoResponse, Response},
     routing::{get, head, post, put},
 };
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
 use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, ReadBuf};
 use tokio_util::io::StreamReader;
+use tracing::{debug, warn};
 
-use crate::storage::{Storage, StorageError};
+use crate::api::catalogs::{CatalogError, CatalogReader, parse_checksum};
+use crate::db::DbError;
+use crate::parity::ExtentParity;
+use crate::sketch::Sketcher;
+use crate::storage::{ExtentCheck, Storage, StorageError};
 use crate::{B3Id, api::AppState};
 
 pub fn router<S: Storage>() -> Router<AppState<S>> {
@@ -19,40 +31,157 @@ pub fn router<S: Storage>() -> Router<AppState<S>> {
         .route("/{id}", put(put_extent))
         .route("/{id}", head(head_extent))
         .route("/check", post(check_extents))
+        .route("/derive", post(derive_extent))
+        .route("/{id}/references", get(extent_references))
 }
 
 /// GET /extents/:id - Download extent data (streamed)
+///
+/// A single byte range can be asked for with a `Range` header, to read part of an extent.
 async fn get_extent<S: Storage>(
     State(state): State<AppState<S>>,
     Path(id): Path<String>,
+    headers: HeaderMap,
 ) -> Result<Response, StorageError> {
     let id = parse_id(&id)?;
 
     // Get metadata first for Content-Length
     let meta = state.storage.extent_meta(&id).await?;
 
-    // Get the stream
-    let stream = state.storage.get_extent(&id).await?;
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::ACCEPT_RANGES, "bytes");
+    let response = match requested_range(&headers, meta.size) {
+        RequestedRange::Whole => {
+            let stream = state.storage.get_extent(&id).await?;
+            response
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, meta.size)
+                .body(Body::from_stream(stream))
+        }
+        RequestedRange::Part { offset, length } => {
+            let stream = state.storage.get_extent_range(&id, offset, length).await?;
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_LENGTH, length)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", offset, offset + length - 1, meta.size),
+                )
+                .body(Body::from_stream(stream))
+        }
+        RequestedRange::Unsatisfiable => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", meta.size))
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
 
-    // Convert our stream to an axum Body
-    let body = Body::from_stream(stream);
+/// What a `Range` header asks for of an extent.
+#[derive(Debug, PartialEq, Eq)]
+enum RequestedRange {
+    Whole,
+    Part { offset: u64, length: u64 },
+    Unsatisfiable,
+}
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(header::CONTENT_LENGTH, meta.size)
-        .body(body)
-        .unwrap())
+/// Read the byte range a request asks for of an extent of `size` bytes.
+///
+/// Only single ranges are supported: requests for several ranges, or with a header that
+/// can't be parsed, get the whole extent, as HTTP allows.
+fn requested_range(headers: &HeaderMap, size: u64) -> RequestedRange {
+    let Some(spec) = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return RequestedRange::Whole;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RequestedRange::Whole;
+    };
+    let (start, end) = match (start.parse::<u64>(), end) {
+        // bytes=-N: the last N bytes
+        (Err(_), end) if start.is_empty() => match end.parse::<u64>() {
+            Ok(0) => return RequestedRange::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return RequestedRange::Whole,
+        },
+        // bytes=N-: from N to the end
+        (Ok(start), "") => (start, size.saturating_sub(1)),
+        (Ok(start), end) => match end.parse::<u64>() {
+            Ok(end) if end >= start => (start, end.min(size.saturating_sub(1))),
+            _ => return RequestedRange::Whole,
+        },
+        (Err(_), _) => return RequestedRange::Whole,
+    };
+    if start >= size {
+        return RequestedRange::Unsatisfiable;
+    }
+    RequestedRange::Part {
+        offset: start,
+        length: end - start + 1,
+    }
+}
+
+/// Error type for extent uploads.
+#[derive(Debug, thiserror::Error)]
+pub enum ExtentUploadError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+
+    #[error("Extent {0} is not needed by any catalog being uploaded")]
+    NotNeeded(B3Id),
+
+    #[error("Extent IDs keyed with key {0} can't be verified by this server")]
+    UnknownKey(String),
+}
+
+/// Header declaring which key extent IDs are derived with.
+const EXTENT_KEY_HEADER: &str = "x-extent-key";
+
+/// How to check extent data against its ID, from the key the request declares.
+fn extent_check<S: Storage>(
+    state: &AppState<S>,
+    headers: &HeaderMap,
+) -> Result<ExtentCheck, ExtentUploadError> {
+    let Some(key_id) = headers.get(EXTENT_KEY_HEADER) else {
+        return Ok(ExtentCheck::Hash);
+    };
+    let key_id = String::from_utf8_lossy(key_id.as_bytes());
+    match state
+        .options
+        .extent_keys
+        .iter()
+        .find(|key| key.key_id() == key_id)
+    {
+        Some(key) => Ok(ExtentCheck::Keyed(*key.hashing_key())),
+        None if state.options.trusted_extent_keys.trusts(&key_id) => Ok(ExtentCheck::Trusted),
+        None => Err(ExtentUploadError::UnknownKey(key_id.into_owned())),
+    }
 }
 
 /// PUT /extents/:id - Upload extent data (streamed)
+///
+/// With strict extents, only extents that a pending or uploading catalog is missing are
+/// accepted; others are refused with 409 Conflict. Extents with keyed IDs declare their key
+/// with an `X-Extent-Key` header, and are verified with it if the server has it.
 async fn put_extent<S: Storage>(
     State(state): State<AppState<S>>,
     Path(id): Path<String>,
     request: axum::extract::Request,
-) -> Result<impl IntoResponse, StorageError> {
+) -> Result<impl IntoResponse, ExtentUploadError> {
     let id = parse_id(&id)?;
 
+    if state.options.strict_extents && !state.db.lock().unwrap().is_extent_wanted(&id)? {
+        return Err(ExtentUploadError::NotNeeded(id));
+    }
+    let check = extent_check(&state, request.headers())?;
+
     // Get Content-Length header for size hint
     let size_hint = request
         .headers()
@@ -60,21 +189,61 @@ async fn put_extent<S: Storage>(
         .and_then(|v| v.to_str().ok())
         .and_then(|s| s.parse::<u64>().ok());
 
+    let version = request.version();
+
     // Convert the request body to an AsyncRead
     let body = request.into_body();
     let stream = body.into_data_stream();
     let stream = stream.map_err(std::io::Error::other);
-    let reader = StreamReader::new(stream);
+    let sketcher = Arc::new(Mutex::new(Sketcher::default()));
+    let ended = Arc::new(AtomicBool::new(false));
+    let reader = SketchingReader {
+        inner: StreamReader::new(stream),
+        sketcher: Arc::clone(&sketcher),
+        ended: Arc::clone(&ended),
+    };
 
     let created = state
         .storage
-        .put_extent(&id, Box::new(reader), size_hint)
+        .put_extent(&id, Box::new(reader), size_hint, check)
         .await?;
 
     if created {
-        Ok(StatusCode::CREATED)
+        // The extent was read in full, so the sketch is of its contents
+        let sketch = std::mem::take(&mut *sketcher.lock().unwrap()).finish();
+        if let Err(err) = state.storage.put_sketch(&id, sketch.encode()).await {
+            warn!(extent = %id.as_hex(), %err, "Failed to store extent sketch");
+        }
+        store_parity(&state, &id).await;
+        Ok(StatusCode::CREATED.into_response())
+    } else if version <= Version::HTTP_11 && size_hint != Some(0) && !ended.load(Ordering::Relaxed)
+    {
+        // Already existed, with the body left unread: an HTTP/1 connection can't be reused
+        // after that, and saying so stops clients from sending their next request on it
+        Ok((StatusCode::OK, [(header::CONNECTION, "close")]).into_response())
     } else {
-        Ok(StatusCode::OK) // Already existed
+        Ok(StatusCode::OK.into_response()) // Already existed
+    }
+}
+
+/// Store the parity block of a newly stored extent, if the server keeps them.
+///
+/// The parity is of the data as read back from storage. Failing to store it doesn't fail
+/// the upload, as a scrub adds parity to extents without it.
+async fn store_parity<S: Storage>(state: &AppState<S>, id: &B3Id) {
+    let Some(scheme) = state.options.parity else {
+        return;
+    };
+    let result = async {
+        let data = state.storage.get_extent_bytes(id).await?;
+        let parity = tokio::task::spawn_blocking(move || ExtentParity::compute(scheme, &data))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|err| StorageError::InvalidData(err.to_string()))?;
+        state.storage.put_parity(id, parity.encode()).await
+    };
+    if let Err(err) = result.await {
+        warn!(extent = %id.as_hex(), %err, "Failed to store extent parity");
     }
 }
 
@@ -89,6 +258,7 @@ async fn head_extent<S: Storage>(
     Ok(Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_LENGTH, meta.size)
+        .header(header::ACCEPT_RANGES, "bytes")
         .body(Body::empty())
         .unwrap())
 }
@@ -114,7 +284,202 @@ async fn check_extents<S: Storage>(
     Ok(Json(CheckResponse { exists }))
 }
 
-fn parse_id(s: &str) -> Result<B3Id, StorageError> {
+/// Largest extent that can be derived, as it's assembled in memory.
+const MAX_DERIVED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Request to assemble an extent from parts of stored ones.
+#[derive(Deserialize)]
+struct DeriveRequest {
+    /// ID of the extent to create
+    id: String,
+    /// Parts of existing extents, concatenated in order
+    segments: Vec<DeriveSegment>,
+}
+
+#[derive(Deserialize)]
+struct DeriveSegment {
+    /// ID of the stored extent to take bytes from
+    source: String,
+    offset: u64,
+    length: u64,
+}
+
+/// POST /extents/derive - Create an extent from byte ranges of stored extents
+///
+/// The assembled data must hash to the requested ID (checked as for uploads), so this
+/// can't store anything that couldn't have been uploaded. Returns 201 Created if stored, 200 OK if it already
+/// existed, and 404 Not Found if a source extent isn't in storage. With strict extents,
+/// the same extents as can be uploaded can be derived.
+async fn derive_extent<S: Storage>(
+    State(state): State<AppState<S>>,
+    headers: HeaderMap,
+    Json(req): Json<DeriveRequest>,
+) -> Result<impl IntoResponse, ExtentUploadError> {
+    let id = parse_id(&req.id)?;
+    if state.storage.extent_exists(&id).await? {
+        return Ok(StatusCode::OK);
+    }
+    if state.options.strict_extents && !state.db.lock().unwrap().is_extent_wanted(&id)? {
+        return Err(ExtentUploadError::NotNeeded(id));
+    }
+    let check = extent_check(&state, &headers)?;
+    if req.segments.is_empty() {
+        return Err(StorageError::InvalidData("no segments to derive from".into()).into());
+    }
+
+    let total = req
+        .segments
+        .iter()
+        .try_fold(0u64, |total, segment| total.checked_add(segment.length))
+        .filter(|&total| total <= MAX_DERIVED_SIZE)
+        .ok_or_else(|| {
+            StorageError::InvalidData(format!(
+                "derived extents can be at most {MAX_DERIVED_SIZE} bytes"
+            ))
+        })?;
+
+    let mut data = Vec::with_capacity(total as usize);
+    for segment in &req.segments {
+        let source = state
+            .storage
+            .get_extent_bytes(&parse_id(&segment.source)?)
+            .await?;
+        let range = segment
+            .offset
+            .checked_add(segment.length)
+            .filter(|&end| end <= source.len() as u64)
+            .map(|end| segment.offset as usize..end as usize)
+            .ok_or_else(|| {
+                StorageError::InvalidData(format!(
+                    "segment {}+{} is beyond the end of extent {}",
+                    segment.offset, segment.length, segment.source
+                ))
+            })?;
+        data.extend_from_slice(&source[range]);
+    }
+
+    let mut sketcher = Sketcher::default();
+    sketcher.update(&data);
+    let created = state
+        .storage
+        .put_extent(&id, Box::new(io::Cursor::new(data)), Some(total), check)
+        .await?;
+
+    if created {
+        if let Err(err) = state
+            .storage
+            .put_sketch(&id, sketcher.finish().encode())
+            .await
+        {
+            warn!(extent = %id.as_hex(), %err, "Failed to store extent sketch");
+        }
+        store_parity(&state, &id).await;
+        debug!(extent = %id.as_hex(), segments = req.segments.len(), "Derived extent");
+        Ok(StatusCode::CREATED)
+    } else {
+        Ok(StatusCode::OK)
+    }
+}
+
+#[derive(Deserialize)]
+struct ReferencesParams {
+    /// Also list the paths of the files that use the extent in each catalog
+    #[serde(default)]
+    paths: bool,
+}
+
+#[derive(Serialize)]
+struct ReferencesResponse {
+    /// Size of the extent in storage, if it's there
+    size: Option<u64>,
+    /// Catalogs referencing the extent, oldest first
+    catalogs: Vec<CatalogReference>,
+}
+
+#[derive(Serialize)]
+struct CatalogReference {
+    id: String,
+    status: &'static str,
+    /// When the catalog was uploaded (seconds since the epoch)
+    created_at: i64,
+    /// Paths of files using the extent, as stored in the catalog (so encrypted if its
+    /// paths are)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paths: Option<Vec<String>>,
+}
+
+/// GET /extents/:id/references - List the catalogs that reference an extent
+///
+/// With `?paths=true`, each catalog is read to also list the files using the extent.
+async fn extent_references<S: Storage>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<String>,
+    Query(params): Query<ReferencesParams>,
+) -> Result<impl IntoResponse, CatalogError> {
+    let id = parse_checksum(&id)?;
+
+    let size = match state.storage.extent_meta(&id).await {
+        Ok(meta) => Some(meta.size),
+        Err(StorageError::NotFound) => None,
+        Err(err) => return Err(CatalogError::Storage(err)),
+    };
+
+    let infos = state.db.lock().unwrap().get_extent_references(&id)?;
+
+    let mut catalogs = Vec::with_capacity(infos.len());
+    for info in infos {
+        let paths = if params.paths {
+            let data = state
+                .storage
+                .get_catalog(info.id)
+                .await
+                .map_err(CatalogError::Storage)?;
+            let reader = CatalogReader::new(&data, &state.scratch)?;
+            Some(reader.paths_for_extent(&id)?)
+        } else {
+            None
+        };
+
+        catalogs.push(CatalogReference {
+            id: info.id.simple().to_string(),
+            status: info.status.as_str(),
+            created_at: info.created_at,
+            paths,
+        });
+    }
+
+    Ok(Json(ReferencesResponse { size, catalogs }))
+}
+
+/// A reader which sketches the data read through it.
+struct SketchingReader<R> {
+    inner: R,
+    sketcher: Arc<Mutex<Sketcher>>,
+    /// Set once the end of the data is read.
+    ended: Arc<AtomicBool>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SketchingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let room = buf.remaining() > 0;
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[before..];
+            if read.is_empty() && room {
+                self.ended.store(true, Ordering::Relaxed);
+            }
+            self.sketcher.lock().unwrap().update(read);
+        }
+        poll
+    }
+}
+
+pub(super) fn parse_id(s: &str) -> Result<B3Id, StorageError> {
     let bytes = hex::decode(s).map_err(|_| StorageError::InvalidData("invalid hex".into()))?;
     bytes
         .try_into()
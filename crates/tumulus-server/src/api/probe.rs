@@ -0,0 +1,35 @@
+//! Link probe handler, for clients to measure their throughput to the server.
+//!
+//! - POST /probe - Receive and discard a body, returning how many bytes arrived
+
+use axum::{
+    Json, Router, body::Bytes, extract::DefaultBodyLimit, response::IntoResponse, routing::post,
+};
+use serde::Serialize;
+
+use crate::api::AppState;
+use crate::storage::Storage;
+
+/// Largest probe body accepted.
+const MAX_PROBE_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn router<S: Storage>() -> Router<AppState<S>> {
+    Router::new()
+        .route("/", post(probe))
+        .layer(DefaultBodyLimit::max(MAX_PROBE_SIZE))
+}
+
+#[derive(Serialize)]
+struct ProbeResponse {
+    received: usize,
+}
+
+/// POST /probe - Discard the request body
+///
+/// Clients time this with an empty body to measure latency, and with a larger one to
+/// measure throughput. Nothing is stored.
+async fn probe(body: Bytes) -> impl IntoResponse {
+    Json(ProbeResponse {
+        received: body.len(),
+    })
+}
@@ -1,4 +1,4 @@
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::B3Id;
 
@@ -37,6 +37,10 @@ pub enum BlobDecodeError {
     NotSorted,
     #[error("Overlapping extents")]
     Overlapping,
+    #[error("Extent past the end of the blob")]
+    OutOfBounds,
+    #[error("Trailing data")]
+    TrailingData,
 }
 
 impl BlobLayout {
@@ -67,6 +71,76 @@ impl BlobLayout {
         buf.freeze()
     }
 
+    /// Decode from binary format.
+    ///
+    /// Layouts that don't [validate](Self::validate) are rejected.
+    pub fn decode(mut data: &[u8]) -> Result<Self, BlobDecodeError> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(BlobDecodeError::Truncated);
+        }
+        let version = data.get_u8();
+        if version != BLOB_VERSION {
+            return Err(BlobDecodeError::InvalidVersion(version));
+        }
+        let id_size = data.get_u8();
+        if id_size != EXTENT_ID_SIZE {
+            return Err(BlobDecodeError::InvalidExtentIdSize(id_size));
+        }
+        let total_bytes = data.get_u64_le();
+        let count = data.get_u64_le();
+
+        // Check the count against the data before allocating for it
+        let expected = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(Self::EXTENT_ENTRY_SIZE))
+            .ok_or(BlobDecodeError::Truncated)?;
+        if data.len() < expected {
+            return Err(BlobDecodeError::Truncated);
+        }
+        if data.len() > expected {
+            return Err(BlobDecodeError::TrailingData);
+        }
+
+        let mut extents: Vec<BlobExtent> = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let offset = data.get_u64_le();
+            let length = data.get_u64_le();
+            let mut extent_id = [0u8; EXTENT_ID_SIZE as usize];
+            data.copy_to_slice(&mut extent_id);
+            extents.push(BlobExtent {
+                offset,
+                length,
+                extent_id: extent_id.into(),
+            });
+        }
+
+        let layout = Self {
+            total_bytes,
+            extents,
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    /// Check that extents are in order, don't overlap, and are within the blob.
+    pub fn validate(&self) -> Result<(), BlobDecodeError> {
+        let mut end = 0;
+        for (i, extent) in self.extents.iter().enumerate() {
+            if i > 0 && extent.offset < self.extents[i - 1].offset {
+                return Err(BlobDecodeError::NotSorted);
+            }
+            if extent.offset < end {
+                return Err(BlobDecodeError::Overlapping);
+            }
+            end = extent
+                .offset
+                .checked_add(extent.length)
+                .filter(|&end| end <= self.total_bytes)
+                .ok_or(BlobDecodeError::OutOfBounds)?;
+        }
+        Ok(())
+    }
+
     /// Iterate over all regions including holes
     pub fn regions(&self) -> Vec<BlobRegion> {
         let mut regions = Vec::new();
@@ -101,6 +175,81 @@ impl BlobLayout {
 mod tests {
     use super::*;
 
+    #[test]
+    fn roundtrip() {
+        let layout = BlobLayout {
+            total_bytes: 1024,
+            extents: vec![
+                BlobExtent {
+                    offset: 100,
+                    length: 100,
+                    extent_id: [1u8; 32].into(),
+                },
+                BlobExtent {
+                    offset: 200,
+                    length: 824,
+                    extent_id: [2u8; 32].into(),
+                },
+            ],
+        };
+        let decoded = BlobLayout::decode(&layout.encode()).unwrap();
+        assert_eq!(decoded.total_bytes, layout.total_bytes);
+        assert_eq!(decoded.extents.len(), 2);
+        assert_eq!(decoded.extents[1].offset, 200);
+        assert_eq!(decoded.extents[1].extent_id, layout.extents[1].extent_id);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_layouts() {
+        let extent = |offset, length| BlobExtent {
+            offset,
+            length,
+            extent_id: [1u8; 32].into(),
+        };
+        let encode = |total_bytes, extents| {
+            BlobLayout {
+                total_bytes,
+                extents,
+            }
+            .encode()
+        };
+
+        assert!(matches!(
+            BlobLayout::decode(&encode(100, vec![extent(50, 10), extent(0, 10)])),
+            Err(BlobDecodeError::NotSorted)
+        ));
+        assert!(matches!(
+            BlobLayout::decode(&encode(100, vec![extent(0, 10), extent(5, 10)])),
+            Err(BlobDecodeError::Overlapping)
+        ));
+        assert!(matches!(
+            BlobLayout::decode(&encode(100, vec![extent(95, 10)])),
+            Err(BlobDecodeError::OutOfBounds)
+        ));
+        assert!(matches!(
+            BlobLayout::decode(&encode(u64::MAX, vec![extent(u64::MAX, 1)])),
+            Err(BlobDecodeError::OutOfBounds)
+        ));
+
+        let valid = encode(100, vec![extent(0, 10)]);
+        assert!(matches!(
+            BlobLayout::decode(&valid[..valid.len() - 1]),
+            Err(BlobDecodeError::Truncated)
+        ));
+        assert!(matches!(
+            BlobLayout::decode(&[valid.as_ref(), &[0]].concat()),
+            Err(BlobDecodeError::TrailingData)
+        ));
+
+        // A huge extent count must not be allocated for
+        let mut huge = valid[..BlobLayout::HEADER_SIZE].to_vec();
+        huge[10..18].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            BlobLayout::decode(&huge),
+            Err(BlobDecodeError::Truncated)
+        ));
+    }
+
     #[test]
     fn regions_with_holes() {
         let layout = BlobLayout {
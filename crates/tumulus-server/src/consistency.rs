@@ -0,0 +1,171 @@
+//! Checking that the upload database agrees with storage.
+//!
+//! The database and storage are separate, so restoring one from a snapshot that doesn't
+//! match the other leaves them disagreeing: the database lists catalogs as uploaded whose
+//! files aren't in storage, or storage has catalogs the database doesn't know about. This
+//! is checked when the server starts, which also expires upload sessions that were
+//! initiated but never sent a catalog.
+//!
+//! The check only compares catalog lists, so it's fast enough to run on every start. It
+//! reports discrepancies without repairing them; see [`crate::rebuild`] for that.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::db::{CatalogStatus, DbError, UploadDb};
+use crate::storage::{Storage, StorageError};
+
+/// Error type for consistency checks.
+#[derive(Debug, Error)]
+pub enum ConsistencyError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+}
+
+/// Pending upload sessions older than this are expired unless configured otherwise.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Where the upload database and storage disagree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// Catalogs the database has received (uploading or complete) that aren't in storage.
+    pub missing: Vec<Uuid>,
+    /// Catalogs in storage that the database doesn't know about.
+    pub untracked: Vec<Uuid>,
+    /// Pending catalogs initiated longer ago than the session TTL.
+    pub stale: Vec<Uuid>,
+}
+
+impl ConsistencyReport {
+    /// Whether the database and storage agree, ignoring stale sessions.
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.untracked.is_empty()
+    }
+}
+
+/// Compare the catalogs in the upload database with those in storage.
+///
+/// Catalogs are listed in the order the database has them, oldest first; untracked ones
+/// by ID.
+pub async fn check_consistency<S: Storage>(
+    storage: &S,
+    db: &Mutex<UploadDb>,
+    session_ttl: Duration,
+) -> Result<ConsistencyReport, ConsistencyError> {
+    let stored: HashSet<Uuid> = storage.list_catalogs().await?.into_iter().collect();
+    let catalogs = db.lock().unwrap().list_catalogs()?;
+    let cutoff = unix_now().saturating_sub(session_ttl.as_secs() as i64);
+
+    let mut report = ConsistencyReport::default();
+    let known: HashSet<Uuid> = catalogs.iter().map(|c| c.id).collect();
+    for catalog in &catalogs {
+        match catalog.status {
+            CatalogStatus::Pending if catalog.created_at <= cutoff => {
+                report.stale.push(catalog.id);
+            }
+            CatalogStatus::Pending => {}
+            CatalogStatus::Uploading | CatalogStatus::Complete => {
+                if !stored.contains(&catalog.id) {
+                    report.missing.push(catalog.id);
+                }
+            }
+        }
+    }
+    report.untracked = stored
+        .into_iter()
+        .filter(|id| !known.contains(id))
+        .collect();
+    report.untracked.sort();
+
+    info!(
+        missing = report.missing.len(),
+        untracked = report.untracked.len(),
+        stale = report.stale.len(),
+        "Checked upload database against storage"
+    );
+    Ok(report)
+}
+
+/// Delete stale upload sessions from the database, skipping any no longer pending.
+///
+/// Returns how many were deleted.
+pub fn expire_sessions(db: &Mutex<UploadDb>, stale: &[Uuid]) -> Result<usize, DbError> {
+    let db = db.lock().unwrap();
+    let mut expired = 0;
+    for &id in stale {
+        match db.get_catalog(id)? {
+            Some(info) if info.status == CatalogStatus::Pending => {
+                db.delete_catalog(id)?;
+                debug!(catalog_id = %id, "Expired upload session");
+                expired += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(expired)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::storage::FsStorage;
+
+    #[tokio::test]
+    async fn finds_discrepancies_and_expires_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        storage.init().await.unwrap();
+
+        let db = UploadDb::open_in_memory().unwrap();
+        let [stored, missing, untracked, stale, fresh] = [(); 5].map(|_| Uuid::new_v4());
+        for id in [stored, missing] {
+            db.create_catalog(id, &[0xaa; 32].into()).unwrap();
+            db.update_status(id, CatalogStatus::Complete).unwrap();
+        }
+        for id in [stale, fresh] {
+            db.create_catalog(id, &[0xbb; 32].into()).unwrap();
+        }
+        db.set_created_at(stale, unix_now() - 7200).unwrap();
+        for id in [stored, untracked] {
+            storage
+                .put_catalog(id, Bytes::from_static(b"catalog"))
+                .await
+                .unwrap();
+        }
+        let db = Mutex::new(db);
+
+        let report = check_consistency(&storage, &db, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ConsistencyReport {
+                missing: vec![missing],
+                untracked: vec![untracked],
+                stale: vec![stale],
+            }
+        );
+        assert!(!report.is_consistent());
+
+        assert_eq!(expire_sessions(&db, &report.stale).unwrap(), 1);
+        assert!(db.lock().unwrap().get_catalog(stale).unwrap().is_none());
+        assert!(db.lock().unwrap().get_catalog(fresh).unwrap().is_some());
+        assert_eq!(expire_sessions(&db, &report.stale).unwrap(), 0);
+    }
+}
@@ -6,10 +6,12 @@
 use std::path::Path;
 
 use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
 
 use crate::B3Id;
+use crate::oplog::{OpLog, Operation};
 
 /// Database error type.
 #[derive(Debug, Error)]
@@ -19,10 +21,20 @@ pub enum DbError {
 
     #[error("Catalog not found: {0}")]
     CatalogNotFound(Uuid),
+
+    #[error("Operation log error: {0}")]
+    OpLog(#[from] std::io::Error),
+
+    #[error(
+        "Extent references are incomplete for catalogs received before they were recorded; \
+         start the server to backfill them, or run rebuild-index"
+    )]
+    ReferencesIncomplete,
 }
 
 /// Status of a catalog upload.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CatalogStatus {
     /// Catalog upload initiated but not yet received
     Pending,
@@ -33,7 +45,7 @@ pub enum CatalogStatus {
 }
 
 impl CatalogStatus {
-    fn as_str(&self) -> &'static str {
+    pub(crate) fn as_str(&self) -> &'static str {
         match self {
             CatalogStatus::Pending => "pending",
             CatalogStatus::Uploading => "uploading",
@@ -58,18 +70,30 @@ pub struct CatalogInfo {
     pub checksum: B3Id,
     pub status: CatalogStatus,
     pub created_at: i64,
+    /// The machine the catalog is from, once the catalog has been received.
+    pub machine: Option<String>,
+}
+
+/// How much of a sketch of extents a catalog references.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharedExtents {
+    /// How many of the sketch's extents the catalog references.
+    pub extents: u64,
+    /// Total size of those extents, in bytes.
+    pub bytes: u64,
 }
 
 /// Database handle for tracking catalog uploads.
 pub struct UploadDb {
     conn: Connection,
+    oplog: Option<OpLog>,
 }
 
 impl UploadDb {
     /// Open or create the upload tracking database.
     pub fn open(path: &Path) -> Result<Self, DbError> {
         let conn = Connection::open(path)?;
-        let db = Self { conn };
+        let db = Self { conn, oplog: None };
         db.init_schema()?;
         Ok(db)
     }
@@ -78,13 +102,33 @@ impl UploadDb {
     #[cfg(test)]
     pub fn open_in_memory() -> Result<Self, DbError> {
         let conn = Connection::open_in_memory()?;
-        let db = Self { conn };
+        let db = Self { conn, oplog: None };
         db.init_schema()?;
         Ok(db)
     }
 
+    /// Log every change from now on.
+    pub fn with_oplog(mut self, oplog: OpLog) -> Self {
+        self.oplog = Some(oplog);
+        self
+    }
+
+    /// The operation log, if changes are logged.
+    pub fn oplog(&self) -> Option<&OpLog> {
+        self.oplog.as_ref()
+    }
+
+    /// Append a change to the operation log, if there is one.
+    fn record(&self, op: impl FnOnce() -> Operation) -> Result<(), DbError> {
+        if let Some(oplog) = &self.oplog {
+            oplog.append(op())?;
+        }
+        Ok(())
+    }
+
     /// Initialize the database schema.
     fn init_schema(&self) -> Result<(), DbError> {
+        let had_references = self.has_table("extent_references")?;
         self.conn.execute_batch(
             r#"
             CREATE TABLE IF NOT EXISTS catalogs (
@@ -106,8 +150,98 @@ impl UploadDb {
             );
 
             CREATE INDEX IF NOT EXISTS idx_catalog_extents_extent ON catalog_extents(extent_id);
+
+            -- Every extent each received catalog references, present in storage or not
+            CREATE TABLE IF NOT EXISTS extent_references (
+                extent_id BLOB NOT NULL,
+                catalog_id BLOB NOT NULL,
+                PRIMARY KEY (extent_id, catalog_id),
+                FOREIGN KEY (catalog_id) REFERENCES catalogs(id) ON DELETE CASCADE
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_extent_references_catalog ON extent_references(catalog_id);
+
+            -- Tables that still need filling in for data from before they existed
+            CREATE TABLE IF NOT EXISTS backfills (
+                name TEXT PRIMARY KEY
+            );
             "#,
         )?;
+        self.migrate(had_references)?;
+        Ok(())
+    }
+
+    /// Bring databases created by older versions up to the current schema.
+    fn migrate(&self, had_references: bool) -> Result<(), DbError> {
+        // Extent references are only recorded as catalogs are received, so older databases
+        // have none for the catalogs they already had (whose catalog extents are only the
+        // ones that were missing), until they're read again from storage
+        if !had_references {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO backfills (name)
+                 SELECT 'extent_references' WHERE EXISTS (
+                     SELECT 1 FROM catalogs WHERE status != ?1
+                 )",
+                params![CatalogStatus::Pending.as_str()],
+            )?;
+        }
+
+        // Extents confirmed present in storage, so that checks can resume after a restart
+        self.add_column_if_missing("catalog_extents", "verified", "INTEGER NOT NULL DEFAULT 0")?;
+        // The machine ID from catalog metadata, to apply retention per machine
+        self.add_column_if_missing("catalogs", "machine", "TEXT")?;
+        Ok(())
+    }
+
+    /// Whether the database has a table.
+    fn has_table(&self, table: &str) -> Result<bool, DbError> {
+        let exists = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            params![table],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    /// Whether extent references are recorded for every catalog received, rather than only
+    /// those received since the database was upgraded to record them.
+    pub fn references_complete(&self) -> Result<bool, DbError> {
+        let pending: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM backfills WHERE name = 'extent_references')",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(!pending)
+    }
+
+    /// Record that extent references have been filled in for every catalog received.
+    pub fn mark_references_complete(&self) -> Result<(), DbError> {
+        self.conn
+            .execute("DELETE FROM backfills WHERE name = 'extent_references'", [])?;
+        Ok(())
+    }
+
+    /// Refuse to answer from extent references while they're incomplete.
+    fn check_references_complete(&self) -> Result<(), DbError> {
+        if self.references_complete()? {
+            Ok(())
+        } else {
+            Err(DbError::ReferencesIncomplete)
+        }
+    }
+
+    /// Add a column to a table unless it already has it.
+    fn add_column_if_missing(&self, table: &str, column: &str, decl: &str) -> Result<(), DbError> {
+        if self
+            .conn
+            .prepare(&format!("SELECT {column} FROM {table} LIMIT 0"))
+            .is_err()
+        {
+            self.conn.execute(
+                &format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"),
+                [],
+            )?;
+        }
         Ok(())
     }
 
@@ -116,51 +250,18 @@ impl UploadDb {
         let result = self
             .conn
             .query_row(
-                "SELECT id, checksum, status, created_at FROM catalogs WHERE id = ?1",
+                "SELECT id, checksum, status, created_at, machine FROM catalogs WHERE id = ?1",
                 params![id.as_bytes().as_slice()],
-                |row| {
-                    let id_bytes: Vec<u8> = row.get(0)?;
-                    let checksum_bytes: Vec<u8> = row.get(1)?;
-                    let status_str: String = row.get(2)?;
-                    let created_at: i64 = row.get(3)?;
-
-                    Ok((id_bytes, checksum_bytes, status_str, created_at))
-                },
+                catalog_info_from_row,
             )
             .optional()?;
-
-        match result {
-            Some((id_bytes, checksum_bytes, status_str, created_at)) => {
-                let id = Uuid::from_slice(&id_bytes).map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(0, "id".into(), rusqlite::types::Type::Blob)
-                })?;
-                let checksum: B3Id = checksum_bytes.try_into().map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(
-                        1,
-                        "checksum".into(),
-                        rusqlite::types::Type::Blob,
-                    )
-                })?;
-                let status = CatalogStatus::from_str(&status_str).ok_or_else(|| {
-                    rusqlite::Error::InvalidColumnType(
-                        2,
-                        "status".into(),
-                        rusqlite::types::Type::Text,
-                    )
-                })?;
-
-                Ok(Some(CatalogInfo {
-                    id,
-                    checksum,
-                    status,
-                    created_at,
-                }))
-            }
-            None => Ok(None),
-        }
+        Ok(result)
     }
 
     /// Look up a catalog by checksum.
+    ///
+    /// If several catalogs share the checksum, ones that have been received are
+    /// preferred over pending ones, then the most recent.
     pub fn find_catalog_by_checksum(
         &self,
         checksum: &B3Id,
@@ -168,48 +269,14 @@ impl UploadDb {
         let result = self
             .conn
             .query_row(
-                "SELECT id, checksum, status, created_at FROM catalogs WHERE checksum = ?1 LIMIT 1",
+                r#"SELECT id, checksum, status, created_at, machine FROM catalogs
+                WHERE checksum = ?1
+                ORDER BY status = 'pending', created_at DESC LIMIT 1"#,
                 params![checksum.as_slice()],
-                |row| {
-                    let id_bytes: Vec<u8> = row.get(0)?;
-                    let checksum_bytes: Vec<u8> = row.get(1)?;
-                    let status_str: String = row.get(2)?;
-                    let created_at: i64 = row.get(3)?;
-
-                    Ok((id_bytes, checksum_bytes, status_str, created_at))
-                },
+                catalog_info_from_row,
             )
             .optional()?;
-
-        match result {
-            Some((id_bytes, checksum_bytes, status_str, created_at)) => {
-                let id = Uuid::from_slice(&id_bytes).map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(0, "id".into(), rusqlite::types::Type::Blob)
-                })?;
-                let checksum: B3Id = checksum_bytes.try_into().map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(
-                        1,
-                        "checksum".into(),
-                        rusqlite::types::Type::Blob,
-                    )
-                })?;
-                let status = CatalogStatus::from_str(&status_str).ok_or_else(|| {
-                    rusqlite::Error::InvalidColumnType(
-                        2,
-                        "status".into(),
-                        rusqlite::types::Type::Text,
-                    )
-                })?;
-
-                Ok(Some(CatalogInfo {
-                    id,
-                    checksum,
-                    status,
-                    created_at,
-                }))
-            }
-            None => Ok(None),
-        }
+        Ok(result)
     }
 
     /// Create a new catalog entry.
@@ -222,9 +289,159 @@ impl UploadDb {
                 CatalogStatus::Pending.as_str()
             ],
         )?;
+        if self.oplog.is_some() {
+            let created_at = self.conn.query_row(
+                "SELECT created_at FROM catalogs WHERE id = ?1",
+                params![id.as_bytes().as_slice()],
+                |row| row.get(0),
+            )?;
+            self.record(|| Operation::CatalogCreated {
+                id,
+                checksum: *checksum,
+                created_at,
+            })?;
+        }
         Ok(())
     }
 
+    /// Override when a catalog was created (seconds since the epoch).
+    pub fn set_created_at(&self, id: Uuid, created_at: i64) -> Result<(), DbError> {
+        let rows = self.conn.execute(
+            "UPDATE catalogs SET created_at = ?1 WHERE id = ?2",
+            params![created_at, id.as_bytes().as_slice()],
+        )?;
+        if rows == 0 {
+            return Err(DbError::CatalogNotFound(id));
+        }
+        self.record(|| Operation::CreatedAtSet { id, created_at })
+    }
+
+    /// Record which machine a catalog is from.
+    pub fn set_machine(&self, id: Uuid, machine: &str) -> Result<(), DbError> {
+        let rows = self.conn.execute(
+            "UPDATE catalogs SET machine = ?1 WHERE id = ?2",
+            params![machine, id.as_bytes().as_slice()],
+        )?;
+        if rows == 0 {
+            return Err(DbError::CatalogNotFound(id));
+        }
+        self.record(|| Operation::MachineSet {
+            id,
+            machine: machine.to_string(),
+        })
+    }
+
+    /// List all catalogs, oldest first.
+    pub fn list_catalogs(&self) -> Result<Vec<CatalogInfo>, DbError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, checksum, status, created_at, machine FROM catalogs
+             ORDER BY created_at, id",
+        )?;
+        let rows = stmt.query_map([], catalog_info_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Find the extents referenced by the given catalogs and by no other catalog.
+    ///
+    /// Fails while extent references are incomplete.
+    pub fn exclusive_extents(&self, catalog_ids: &[Uuid]) -> Result<Vec<B3Id>, DbError> {
+        self.check_references_complete()?;
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS selected_catalogs (id BLOB PRIMARY KEY)",
+            [],
+        )?;
+        tx.execute("DELETE FROM selected_catalogs", [])?;
+        {
+            let mut stmt =
+                tx.prepare("INSERT OR IGNORE INTO selected_catalogs (id) VALUES (?1)")?;
+            for id in catalog_ids {
+                stmt.execute(params![id.as_bytes().as_slice()])?;
+            }
+        }
+
+        let extents = {
+            let mut stmt = tx.prepare(
+                "SELECT DISTINCT r.extent_id FROM extent_references r
+                 WHERE r.catalog_id IN (SELECT id FROM selected_catalogs)
+                 AND NOT EXISTS (
+                     SELECT 1 FROM extent_references o
+                     WHERE o.extent_id = r.extent_id
+                     AND o.catalog_id NOT IN (SELECT id FROM selected_catalogs)
+                 )",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                let extent_id: Vec<u8> = row.get(0)?;
+                B3Id::try_from(extent_id).map_err(|_| {
+                    rusqlite::Error::InvalidColumnType(
+                        0,
+                        "extent_id".into(),
+                        rusqlite::types::Type::Blob,
+                    )
+                })
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        tx.execute("DELETE FROM selected_catalogs", [])?;
+        tx.commit()?;
+        Ok(extents)
+    }
+
+    /// Find how much of a sketch of extents (IDs and sizes) each catalog references.
+    ///
+    /// Extents listed more than once in the sketch are counted once. Results are in the
+    /// order of `catalog_ids`.
+    pub fn shared_extents(
+        &self,
+        catalog_ids: &[Uuid],
+        sketch: &[(B3Id, u64)],
+    ) -> Result<Vec<SharedExtents>, DbError> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS sketch_extents (
+                 extent_id BLOB PRIMARY KEY,
+                 bytes INTEGER NOT NULL
+             )",
+            [],
+        )?;
+        tx.execute("DELETE FROM sketch_extents", [])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO sketch_extents (extent_id, bytes) VALUES (?1, ?2)",
+            )?;
+            for (extent_id, bytes) in sketch {
+                stmt.execute(params![
+                    extent_id.as_slice(),
+                    i64::try_from(*bytes).unwrap_or(i64::MAX)
+                ])?;
+            }
+        }
+
+        let shared = {
+            let mut stmt = tx.prepare(
+                "SELECT COUNT(*), COALESCE(SUM(s.bytes), 0) FROM sketch_extents s
+                 JOIN extent_references r ON r.extent_id = s.extent_id
+                 WHERE r.catalog_id = ?1",
+            )?;
+            catalog_ids
+                .iter()
+                .map(|id| {
+                    stmt.query_row(params![id.as_bytes().as_slice()], |row| {
+                        Ok(SharedExtents {
+                            extents: row.get::<_, i64>(0)? as u64,
+                            bytes: row.get::<_, i64>(1)? as u64,
+                        })
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        tx.execute("DELETE FROM sketch_extents", [])?;
+        tx.commit()?;
+        Ok(shared)
+    }
+
     /// Generate a new unique catalog ID.
     pub fn generate_catalog_id(&self) -> Uuid {
         Uuid::new_v4()
@@ -239,7 +456,7 @@ impl UploadDb {
         if rows == 0 {
             return Err(DbError::CatalogNotFound(id));
         }
-        Ok(())
+        self.record(|| Operation::StatusChanged { id, status })
     }
 
     /// Store the list of extent IDs needed for a catalog.
@@ -266,7 +483,10 @@ impl UploadDb {
             ])?;
         }
 
-        Ok(())
+        self.record(|| Operation::CatalogExtentsSet {
+            id: catalog_id,
+            extents: extent_ids.to_vec(),
+        })
     }
 
     /// Get the list of extent IDs needed for a catalog.
@@ -296,16 +516,176 @@ impl UploadDb {
         Ok(extents)
     }
 
+    /// Get the extent IDs needed for a catalog that haven't yet been seen in storage.
+    pub fn get_unverified_catalog_extents(&self, catalog_id: Uuid) -> Result<Vec<B3Id>, DbError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT extent_id FROM catalog_extents WHERE catalog_id = ?1 AND verified = 0",
+        )?;
+
+        let rows = stmt.query_map(params![catalog_id.as_bytes().as_slice()], |row| {
+            let extent_id: Vec<u8> = row.get(0)?;
+            Ok(extent_id)
+        })?;
+
+        let mut extents = Vec::new();
+        for row in rows {
+            let extent_id: B3Id = row?.try_into().map_err(|_| {
+                rusqlite::Error::InvalidColumnType(
+                    0,
+                    "extent_id".into(),
+                    rusqlite::types::Type::Blob,
+                )
+            })?;
+            extents.push(extent_id);
+        }
+
+        Ok(extents)
+    }
+
+    /// Whether a catalog still being uploaded needs an extent.
+    pub fn is_extent_wanted(&self, extent_id: &B3Id) -> Result<bool, DbError> {
+        let wanted = self.conn.query_row(
+            "SELECT EXISTS (
+                 SELECT 1 FROM catalog_extents ce JOIN catalogs c ON c.id = ce.catalog_id
+                 WHERE ce.extent_id = ?1 AND c.status IN (?2, ?3)
+             )",
+            params![
+                extent_id.as_slice(),
+                CatalogStatus::Pending.as_str(),
+                CatalogStatus::Uploading.as_str()
+            ],
+            |row| row.get(0),
+        )?;
+        Ok(wanted)
+    }
+
+    /// Whether any catalog in the database, complete or not, uses an extent.
+    ///
+    /// Fails while extent references are incomplete.
+    pub fn is_extent_referenced(&self, extent_id: &B3Id) -> Result<bool, DbError> {
+        self.check_references_complete()?;
+        let referenced = self.conn.query_row(
+            "SELECT EXISTS (
+                 SELECT 1 FROM extent_references r JOIN catalogs c ON c.id = r.catalog_id
+                 WHERE r.extent_id = ?1
+             ) OR EXISTS (
+                 SELECT 1 FROM catalog_extents ce JOIN catalogs c ON c.id = ce.catalog_id
+                 WHERE ce.extent_id = ?1
+             )",
+            params![extent_id.as_slice()],
+            |row| row.get(0),
+        )?;
+        Ok(referenced)
+    }
+
+    /// Record that some of a catalog's extents have been seen in storage.
+    pub fn mark_extents_verified(
+        &self,
+        catalog_id: Uuid,
+        extent_ids: &[B3Id],
+    ) -> Result<(), DbError> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE catalog_extents SET verified = 1 WHERE catalog_id = ?1 AND extent_id = ?2",
+            )?;
+            for extent_id in extent_ids {
+                stmt.execute(params![
+                    catalog_id.as_bytes().as_slice(),
+                    extent_id.as_slice()
+                ])?;
+            }
+        }
+        tx.commit()?;
+        self.record(|| Operation::ExtentsVerified {
+            id: catalog_id,
+            extents: extent_ids.to_vec(),
+        })
+    }
+
+    /// Record every extent a catalog references, replacing any previous record.
+    pub fn set_extent_references(
+        &self,
+        catalog_id: Uuid,
+        extent_ids: &[B3Id],
+    ) -> Result<(), DbError> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM extent_references WHERE catalog_id = ?1",
+            params![catalog_id.as_bytes().as_slice()],
+        )?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO extent_references (extent_id, catalog_id) VALUES (?1, ?2)",
+            )?;
+            for extent_id in extent_ids {
+                stmt.execute(params![
+                    extent_id.as_slice(),
+                    catalog_id.as_bytes().as_slice()
+                ])?;
+            }
+        }
+        tx.commit()?;
+        self.record(|| Operation::ExtentReferencesSet {
+            id: catalog_id,
+            extents: extent_ids.to_vec(),
+        })
+    }
+
+    /// Get the catalogs that reference an extent, oldest first.
+    pub fn get_extent_references(&self, extent_id: &B3Id) -> Result<Vec<CatalogInfo>, DbError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT c.id, c.checksum, c.status, c.created_at, c.machine
+             FROM extent_references r JOIN catalogs c ON c.id = r.catalog_id
+             WHERE r.extent_id = ?1
+             ORDER BY c.created_at, c.id",
+        )?;
+        let rows = stmt.query_map(params![extent_id.as_slice()], catalog_info_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
     /// Delete a catalog and its associated extents.
     pub fn delete_catalog(&self, id: Uuid) -> Result<(), DbError> {
+        // Foreign keys aren't enforced, so references are removed explicitly
+        self.conn.execute(
+            "DELETE FROM extent_references WHERE catalog_id = ?1",
+            params![id.as_bytes().as_slice()],
+        )?;
         self.conn.execute(
             "DELETE FROM catalogs WHERE id = ?1",
             params![id.as_bytes().as_slice()],
         )?;
-        Ok(())
+        self.record(|| Operation::CatalogDeleted { id })
     }
 }
 
+/// Read a [`CatalogInfo`] from a row of `id, checksum, status, created_at, machine`.
+fn catalog_info_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<CatalogInfo> {
+    let id_bytes: Vec<u8> = row.get(0)?;
+    let checksum_bytes: Vec<u8> = row.get(1)?;
+    let status_str: String = row.get(2)?;
+    let created_at: i64 = row.get(3)?;
+    let machine: Option<String> = row.get(4)?;
+
+    let id = Uuid::from_slice(&id_bytes).map_err(|_| {
+        rusqlite::Error::InvalidColumnType(0, "id".into(), rusqlite::types::Type::Blob)
+    })?;
+    let checksum: B3Id = checksum_bytes.try_into().map_err(|_| {
+        rusqlite::Error::InvalidColumnType(1, "checksum".into(), rusqlite::types::Type::Blob)
+    })?;
+    let status = CatalogStatus::from_str(&status_str).ok_or_else(|| {
+        rusqlite::Error::InvalidColumnType(2, "status".into(), rusqlite::types::Type::Text)
+    })?;
+
+    Ok(CatalogInfo {
+        id,
+        checksum,
+        status,
+        created_at,
+        machine,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -371,6 +751,224 @@ mod tests {
         assert!(retrieved.contains(&[0x03u8; 32].into()));
     }
 
+    #[test]
+    fn verified_extents() {
+        let db = UploadDb::open_in_memory().unwrap();
+        let id = Uuid::new_v4();
+        let checksum = [0x42u8; 32].into();
+
+        db.create_catalog(id, &checksum).unwrap();
+
+        let extents: Vec<B3Id> = vec![[0x01u8; 32].into(), [0x02u8; 32].into()];
+        db.set_catalog_extents(id, &extents).unwrap();
+        assert_eq!(db.get_unverified_catalog_extents(id).unwrap().len(), 2);
+
+        db.mark_extents_verified(id, &extents[..1]).unwrap();
+        let unverified = db.get_unverified_catalog_extents(id).unwrap();
+        assert_eq!(unverified, vec![[0x02u8; 32].into()]);
+
+        // Membership is unchanged
+        assert_eq!(db.get_catalog_extents(id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn wanted_extents() {
+        let db = UploadDb::open_in_memory().unwrap();
+        let id = Uuid::new_v4();
+        let extent: B3Id = [0x01u8; 32].into();
+
+        db.create_catalog(id, &[0x42u8; 32].into()).unwrap();
+        assert!(!db.is_extent_wanted(&extent).unwrap());
+
+        db.set_catalog_extents(id, &[extent]).unwrap();
+        db.update_status(id, CatalogStatus::Uploading).unwrap();
+        assert!(db.is_extent_wanted(&extent).unwrap());
+        assert!(!db.is_extent_wanted(&[0x02u8; 32].into()).unwrap());
+
+        db.update_status(id, CatalogStatus::Complete).unwrap();
+        assert!(!db.is_extent_wanted(&extent).unwrap());
+    }
+
+    #[test]
+    fn referenced_extents() {
+        let db = UploadDb::open_in_memory().unwrap();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let needed: B3Id = [0x01u8; 32].into();
+        let stored: B3Id = [0x02u8; 32].into();
+
+        db.create_catalog(a, &[0x42u8; 32].into()).unwrap();
+        db.set_catalog_extents(a, &[needed]).unwrap();
+        db.create_catalog(b, &[0x43u8; 32].into()).unwrap();
+        db.set_extent_references(b, &[stored]).unwrap();
+        db.update_status(b, CatalogStatus::Complete).unwrap();
+
+        assert!(db.is_extent_referenced(&needed).unwrap());
+        assert!(db.is_extent_referenced(&stored).unwrap());
+        assert!(!db.is_extent_referenced(&[0x03u8; 32].into()).unwrap());
+
+        db.delete_catalog(a).unwrap();
+        assert!(!db.is_extent_referenced(&needed).unwrap());
+    }
+
+    #[test]
+    fn shared_extents() {
+        let db = UploadDb::open_in_memory().unwrap();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        db.create_catalog(a, &[0x42u8; 32].into()).unwrap();
+        db.create_catalog(b, &[0x43u8; 32].into()).unwrap();
+
+        let (one, two, three): (B3Id, B3Id, B3Id) = (
+            [0x01u8; 32].into(),
+            [0x02u8; 32].into(),
+            [0x03u8; 32].into(),
+        );
+        db.set_extent_references(a, &[one, two]).unwrap();
+        db.set_extent_references(b, &[two, three]).unwrap();
+
+        // Listed twice, counted once
+        let sketch = [
+            (one, 100),
+            (two, 1000),
+            (two, 1000),
+            ([0x04u8; 32].into(), 5),
+        ];
+        let shared = db.shared_extents(&[a, b, Uuid::new_v4()], &sketch).unwrap();
+        assert_eq!(
+            shared,
+            [
+                SharedExtents {
+                    extents: 2,
+                    bytes: 1100
+                },
+                SharedExtents {
+                    extents: 1,
+                    bytes: 1000
+                },
+                SharedExtents::default(),
+            ]
+        );
+        assert_eq!(
+            db.shared_extents(&[a], &[]).unwrap(),
+            [SharedExtents::default()]
+        );
+    }
+
+    #[test]
+    fn extent_references() {
+        let db = UploadDb::open_in_memory().unwrap();
+        let a = Uuid::new_v4();
+        let b = Uuid::new_v4();
+        db.create_catalog(a, &[0x42u8; 32].into()).unwrap();
+        db.create_catalog(b, &[0x43u8; 32].into()).unwrap();
+        db.set_created_at(a, 1).unwrap();
+        db.set_created_at(b, 2).unwrap();
+
+        let shared: B3Id = [0x01u8; 32].into();
+        let only_b: B3Id = [0x02u8; 32].into();
+        db.set_extent_references(a, &[shared]).unwrap();
+        db.set_extent_references(b, &[shared, only_b]).unwrap();
+
+        let refs: Vec<Uuid> = db
+            .get_extent_references(&shared)
+            .unwrap()
+            .iter()
+            .map(|info| info.id)
+            .collect();
+        assert_eq!(refs, vec![a, b]);
+        assert_eq!(db.get_extent_references(&only_b).unwrap().len(), 1);
+
+        // Replaced, not added to
+        db.set_extent_references(b, &[only_b]).unwrap();
+        assert_eq!(db.get_extent_references(&shared).unwrap().len(), 1);
+    }
+
+    /// The schema of databases from before extent references were recorded.
+    const REFERENCELESS_SCHEMA: &str = r#"
+        CREATE TABLE catalogs (
+            id BLOB PRIMARY KEY,
+            checksum BLOB NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE TABLE catalog_extents (
+            catalog_id BLOB NOT NULL,
+            extent_id BLOB NOT NULL,
+            PRIMARY KEY (catalog_id, extent_id)
+        );
+    "#;
+
+    /// Make a database as older versions would have, with a catalog of each status.
+    fn referenceless_db(path: &Path, statuses: &[CatalogStatus]) -> Vec<Uuid> {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(REFERENCELESS_SCHEMA).unwrap();
+        let mut ids = Vec::new();
+        for status in statuses {
+            let id = Uuid::new_v4();
+            conn.execute(
+                "INSERT INTO catalogs (id, checksum, status) VALUES (?1, ?2, ?3)",
+                params![
+                    id.as_bytes().as_slice(),
+                    [0x42u8; 32].as_slice(),
+                    status.as_str()
+                ],
+            )
+            .unwrap();
+            ids.push(id);
+        }
+        ids
+    }
+
+    #[test]
+    fn references_incomplete_after_upgrade() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uploads.db");
+        let ids = referenceless_db(&path, &[CatalogStatus::Complete]);
+        let extent: B3Id = [0x01u8; 32].into();
+
+        let db = UploadDb::open(&path).unwrap();
+        assert!(!db.references_complete().unwrap());
+        assert!(matches!(
+            db.is_extent_referenced(&extent),
+            Err(DbError::ReferencesIncomplete)
+        ));
+        assert!(matches!(
+            db.exclusive_extents(&ids),
+            Err(DbError::ReferencesIncomplete)
+        ));
+
+        db.set_extent_references(ids[0], &[extent]).unwrap();
+        db.mark_references_complete().unwrap();
+        assert!(db.is_extent_referenced(&extent).unwrap());
+        drop(db);
+        assert!(
+            UploadDb::open(&path)
+                .unwrap()
+                .references_complete()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn references_complete_without_received_catalogs() {
+        assert!(
+            UploadDb::open_in_memory()
+                .unwrap()
+                .references_complete()
+                .unwrap()
+        );
+
+        // Pending catalogs haven't been received, so reference nothing yet
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uploads.db");
+        referenceless_db(&path, &[CatalogStatus::Pending]);
+        assert!(
+            UploadDb::open(&path)
+                .unwrap()
+                .references_complete()
+                .unwrap()
+        );
+    }
+
     #[test]
     fn delete_catalog() {
         let db = UploadDb::open_in_memory().unwrap();
@@ -378,8 +976,16 @@ mod tests {
         let checksum = [0x42u8; 32].into();
 
         db.create_catalog(id, &checksum).unwrap();
+        db.set_extent_references(id, &[[0x01u8; 32].into()])
+            .unwrap();
         db.delete_catalog(id).unwrap();
 
+        assert!(
+            db.get_extent_references(&[0x01u8; 32].into())
+                .unwrap()
+                .is_empty()
+        );
+
         let info = db.get_catalog(id).unwrap();
         assert!(info.is_none());
     }
@@ -0,0 +1,64 @@
+//! Entry points for the fuzz targets in `fuzz/`, which can't reach crate internals.
+
+use crate::api::CatalogReader;
+use crate::scratch::Scratch;
+
+/// Most scratch space an input may decompress to.
+const SCRATCH_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Read a catalog as uploaded to the server, the way the server reads it.
+pub fn read_catalog(data: &[u8]) {
+    let scratch = Scratch::new(None, Some(SCRATCH_LIMIT));
+    let Ok(reader) = CatalogReader::new(data, &scratch) else {
+        return;
+    };
+
+    if let Ok(ids) = reader.extent_ids()
+        && let Some(id) = ids.first()
+    {
+        let _ = reader.paths_for_extent(id);
+    }
+    let _ = reader.created();
+    let _ = reader.machine();
+    let mut batches = reader.blob_batches(64);
+    while let Ok(Some(_)) = batches.next_batch() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::BlobLayout;
+
+    /// Run a fuzz target's corpus, so seeds and past crashes are checked without a fuzzer.
+    fn corpus(target: &str) -> Vec<Vec<u8>> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../fuzz/corpus")
+            .join(target);
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn blob_layout_corpus() {
+        let inputs = corpus("blob_layout");
+        assert!(!inputs.is_empty());
+        for data in inputs {
+            if let Ok(layout) = BlobLayout::decode(&data) {
+                assert_eq!(layout.encode().as_ref(), data.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn catalog_reader_corpus() {
+        let inputs = corpus("catalog_reader");
+        assert!(!inputs.is_empty());
+        for data in inputs {
+            super::read_catalog(&data);
+        }
+    }
+}
@@ -6,17 +6,49 @@
 pub mod api;
 pub mod blob;
 pub mod config;
+pub mod consistency;
 pub mod db;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing;
+pub mod oplog;
+pub mod orphans;
+pub mod parity;
+pub mod prune;
+pub mod rebuild;
+pub mod scratch;
+pub mod scrub;
+pub mod server;
+pub mod sketch;
 pub mod storage;
+pub mod trace;
 
 pub use api::{
-    CatalogError, ErrorResponse, FinalizeResponse, InitiateRequest, InitiateResponse,
-    UploadResponse, router,
+    ApiOptions, AuthToken, CatalogError, ErrorResponse, ExtentUploadError, FinalizeResponse,
+    InitiateRequest, InitiateResponse, TrustedKeys, UploadResponse, router, router_with_options,
 };
 pub use blob::{BlobDecodeError, BlobExtent, BlobLayout, BlobRegion};
 pub use config::Config;
-pub use db::{CatalogInfo, CatalogStatus, DbError, UploadDb};
-pub use storage::{ByteReader, ByteStream, FsStorage, ObjectMeta, Storage, StorageError};
+pub use consistency::{
+    ConsistencyError, ConsistencyReport, DEFAULT_SESSION_TTL, check_consistency, expire_sessions,
+};
+pub use db::{CatalogInfo, CatalogStatus, DbError, SharedExtents, UploadDb};
+pub use oplog::{LogEntry, OpLog, OpLogError, Operation, read_log, replay};
+pub use orphans::{
+    OrphanDeletion, OrphanError, OrphanExtent, OrphanReport, delete_orphans, find_orphans,
+};
+pub use parity::{ExtentParity, ParityCheck, ParityDecodeError, ParityScheme};
+pub use prune::{PruneError, PruneEstimate, PrunePlan, RetentionPolicy, estimate_prune};
+pub use rebuild::{RebuildError, RebuildReport, backfill_references, rebuild_index};
+pub use scratch::{Reservation, Scratch, ScratchFull};
+pub use scrub::{DamagedExtent, ScrubError, ScrubOptions, ScrubReport, scrub};
+pub use server::{Server, ServerBuilder, ServerError, ServerHandle};
+pub use sketch::{ExtentSketch, Sketcher};
+pub use storage::{
+    ByteReader, ByteStream, CatalogPage, CatalogStream, ExtentCheck, FsStorage, ObjectMeta,
+    Storage, StorageError, TracedStorage,
+};
+pub use trace::TraceParent;
 
 // Re-export B3Id from tumulus crate
 pub use tumulus::B3Id;
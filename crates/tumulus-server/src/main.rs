@@ -1,11 +1,22 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use lloggs::LoggingArgs;
 use tracing::info;
 
-use tumulus_server::{api, db::UploadDb, storage::FsStorage};
+use tumulus::{ExtentKey, SecretSource, SecretsProvider};
+use tumulus_server::{
+    ApiOptions, DEFAULT_SESSION_TTL, ParityScheme, RetentionPolicy, ScrubOptions, Server,
+    TrustedKeys, backfill_references,
+    db::UploadDb,
+    delete_orphans, find_orphans,
+    orphans::{DEFAULT_MIN_AGE, parse_min_age},
+    read_log, rebuild_index, replay, scrub,
+    storage::FsStorage,
+};
 
 #[derive(Parser)]
 #[command(name = "tumulus-server")]
@@ -19,10 +30,131 @@ struct Args {
     #[arg(long, short)]
     storage: PathBuf,
 
+    /// Number of extents to check for existence per storage call
+    #[arg(long, default_value = "1000")]
+    check_batch_size: usize,
+
+    /// Maximum number of extent existence checks in flight at once
+    #[arg(long, default_value = "4")]
+    check_concurrency: usize,
+
+    /// Directory for temporary files while processing catalogs [default: <STORAGE>/scratch]
+    #[arg(long)]
+    scratch_dir: Option<PathBuf>,
+
+    /// Maximum bytes of scratch space in use at once; requests beyond are refused with 503
+    #[arg(long)]
+    max_scratch_bytes: Option<u64>,
+
+    /// Refuse uploads of extents that no catalog being uploaded is missing
+    #[arg(long)]
+    strict_extents: bool,
+
+    /// Secret that clients derive keyed extent IDs with (as given to `tumulus catalog
+    /// --extent-key`), to verify extents uploaded with it (can be specified multiple times).
+    /// Read from a file path, or from `env:NAME`, `file:PATH`, `cmd:COMMAND`, or
+    /// `keychain:SERVICE/ACCOUNT`
+    #[arg(long, value_name = "SECRET")]
+    extent_key: Vec<SecretSource>,
+
+    /// Store extents with keyed IDs derived with this key, given by its ID as recorded in
+    /// catalogs' `extent_key` metadata, without verifying them (can be specified multiple
+    /// times). Only for keys whose clients are all trusted
+    #[arg(long, value_name = "KEY_ID")]
+    trust_extent_key: Vec<String>,
+
+    /// Retention: keep this many of each machine's most recent catalogs
+    #[arg(long)]
+    keep_last: Option<usize>,
+
+    /// Retention: keep the most recent catalog of this many days, per machine
+    #[arg(long)]
+    keep_daily: Option<usize>,
+
+    /// Retention: keep the most recent catalog of this many weeks, per machine
+    #[arg(long)]
+    keep_weekly: Option<usize>,
+
+    /// Retention: keep the most recent catalog of this many months, per machine
+    #[arg(long)]
+    keep_monthly: Option<usize>,
+
+    /// Bearer token clients must present, read from a file path, or from `env:NAME`,
+    /// `file:PATH`, `cmd:COMMAND`, or `keychain:SERVICE/ACCOUNT`
+    #[arg(long, value_name = "SECRET")]
+    token: Option<SecretSource>,
+
+    /// Expire catalog uploads still pending (initiated, with no catalog sent) after this
+    /// long, when the server starts (e.g. `36h` or `PT1H`) [default: 24h]
+    #[arg(long, value_parser = parse_min_age)]
+    session_ttl: Option<Duration>,
+
+    /// Log every change to the upload tracking database to this file, for replicas to follow
+    #[arg(long)]
+    oplog: Option<PathBuf>,
+
+    /// Store Reed-Solomon parity with every new extent, as DATA+PARITY shards (like `8+2`,
+    /// which repairs up to 2 damaged shards in every 8 for 25% more space), so `scrub` can
+    /// repair corruption. For servers keeping a single copy of their data.
+    #[arg(long, value_name = "SCHEME")]
+    parity: Option<ParityScheme>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[command(flatten)]
     logging: LoggingArgs,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Rebuild the upload tracking database from the contents of storage, then exit
+    RebuildIndex {
+        /// Print the ID of every extent that no catalog references
+        #[arg(long)]
+        list_orphans: bool,
+    },
+
+    /// Report extents in storage that no catalog references, such as those left behind by
+    /// abandoned uploads, then exit
+    Orphans {
+        /// Leave out extents stored more recently than this (e.g. `36h` or `PT1H`) [default: 24h]
+        #[arg(long, value_parser = parse_min_age)]
+        min_age: Option<Duration>,
+
+        /// Print the ID of every orphan extent reported
+        #[arg(long)]
+        list: bool,
+
+        /// Delete the orphan extents reported
+        #[arg(long)]
+        delete: bool,
+    },
+
+    /// Check every extent in storage for corruption, repairing what parity allows, then exit
+    ///
+    /// With --parity, extents without parity are given it. Extents with keyed IDs and no
+    /// parity are only checked with their key given as --extent-key. Each extent damaged
+    /// beyond repair is printed with the byte ranges of it that are lost.
+    Scrub {
+        /// Only report damage, without repairing it or adding parity
+        #[arg(long)]
+        no_repair: bool,
+    },
+
+    /// Apply an operation log to the upload tracking database, then exit
+    ///
+    /// The database must be empty, or have had every entry up to --after applied.
+    ReplayLog {
+        /// Operation log file
+        log: PathBuf,
+
+        /// Sequence number of the last entry already applied
+        #[arg(long, default_value = "0")]
+        after: u64,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
@@ -33,24 +165,190 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         _ => "trace",
     })?;
 
-    info!(listen = %args.listen, storage = ?args.storage, "Starting server");
+    if let Some(Command::ReplayLog { log, after }) = args.command {
+        let db_path = args.storage.join("uploads.db");
+        let db = UploadDb::open(&db_path)?;
+        let entries = read_log(&log, after)?;
+        match replay(&entries, &db)? {
+            Some(last) => eprintln!("Applied entries {} to {last} to {:?}", after + 1, db_path),
+            None => eprintln!("No entries after {after}"),
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Orphans {
+        min_age,
+        list,
+        delete,
+    }) = args.command
+    {
+        let storage = FsStorage::new(&args.storage);
+        storage.init().await?;
+        let db = Mutex::new(UploadDb::open(&args.storage.join("uploads.db"))?);
+
+        // Catalogs received before extent references were recorded still count
+        backfill_references(&storage, &db).await?;
+        let report = find_orphans(&storage, &db, min_age.unwrap_or(DEFAULT_MIN_AGE)).await?;
+
+        eprintln!(
+            "Orphan extents: {} ({} bytes)",
+            report.orphans.len(),
+            report.orphan_bytes
+        );
+        eprintln!(
+            "  Too recent to report: {} ({} bytes)",
+            report.recent, report.recent_bytes
+        );
+        if list {
+            for orphan in &report.orphans {
+                println!("{}", orphan.id);
+            }
+        }
+        if delete {
+            let deletion = delete_orphans(&storage, &db, &report.orphans).await?;
+            eprintln!(
+                "Deleted {} extents ({} bytes)",
+                deletion.deleted, deletion.bytes
+            );
+            if deletion.kept > 0 {
+                eprintln!("  Kept {} now referenced by a catalog", deletion.kept);
+            }
+        }
 
-    // Initialize storage
-    let storage = FsStorage::new(&args.storage);
-    storage.init().await?;
+        return Ok(());
+    }
 
-    // Initialize upload tracking database
-    let db_path = args.storage.join("uploads.db");
-    let db = UploadDb::open(&db_path)?;
-    info!(db_path = ?db_path, "Initialized upload tracking database");
+    if let Some(Command::Scrub { no_repair }) = args.command {
+        let storage = FsStorage::new(&args.storage);
+        storage.init().await?;
+        let options = ScrubOptions {
+            repair: !no_repair,
+            parity: args.parity,
+            extent_keys: args
+                .extent_key
+                .iter()
+                .map(|key| ExtentKey::from_secret(key))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let report = scrub(&storage, &options).await?;
+
+        let (repaired, parity_rebuilt, parity_added) = if no_repair {
+            (
+                "Repairable",
+                "Damaged parity",
+                "Without parity (can be added)",
+            )
+        } else {
+            ("Repaired", "Parity rebuilt", "Parity added")
+        };
+        eprintln!(
+            "Checked {} extents ({} bytes)",
+            report.checked, report.bytes
+        );
+        eprintln!("  {repaired}: {}", report.repaired.len());
+        eprintln!("  {parity_rebuilt}: {}", report.parity_rebuilt);
+        eprintln!("  {parity_added}: {}", report.parity_added);
+        eprintln!("  Without parity: {}", report.unprotected);
+        eprintln!("  Damaged beyond repair: {}", report.damaged.len());
+        for damaged in &report.damaged {
+            let lost: Vec<String> = damaged
+                .lost
+                .iter()
+                .map(|range| format!("{}-{}", range.start, range.end))
+                .collect();
+            println!("{} {}", damaged.id, lost.join(","));
+        }
+        if !report.damaged.is_empty() {
+            return Err(
+                format!("{} extents are damaged beyond repair", report.damaged.len()).into(),
+            );
+        }
+
+        return Ok(());
+    }
+
+    if let Some(Command::RebuildIndex { list_orphans }) = args.command {
+        let storage = FsStorage::new(&args.storage);
+        storage.init().await?;
+        let db_path = args.storage.join("uploads.db");
+        let db = UploadDb::open(&db_path)?;
+
+        let options = ApiOptions {
+            check_batch_size: args.check_batch_size,
+            check_concurrency: args.check_concurrency,
+            ..ApiOptions::default()
+        };
+        let report = rebuild_index(&storage, &db, &options).await?;
+
+        eprintln!("Index rebuilt in {:?}", db_path);
+        eprintln!("  Complete catalogs: {}", report.complete.len());
+        eprintln!("  Incomplete catalogs: {}", report.incomplete.len());
+        if !report.failed.is_empty() {
+            eprintln!("  Unreadable catalogs: {}", report.failed.len());
+            for id in &report.failed {
+                eprintln!("    {}", id.simple());
+            }
+        }
+        eprintln!("  Orphan extents: {}", report.orphan_extents.len());
+        if list_orphans {
+            for id in &report.orphan_extents {
+                println!("{}", id);
+            }
+        }
+
+        return Ok(());
+    }
+
+    info!(listen = %args.listen, storage = ?args.storage, "Starting server");
 
-    // Build router
-    let app = api::router(storage, db);
+    let options = ApiOptions {
+        check_batch_size: args.check_batch_size,
+        check_concurrency: args.check_concurrency,
+        scratch_dir: args.scratch_dir,
+        max_scratch_bytes: args.max_scratch_bytes,
+        retention: RetentionPolicy {
+            keep_last: args.keep_last,
+            keep_daily: args.keep_daily,
+            keep_weekly: args.keep_weekly,
+            keep_monthly: args.keep_monthly,
+        },
+        strict_extents: args.strict_extents,
+        auth: None,
+        extent_keys: args
+            .extent_key
+            .iter()
+            .map(|key| ExtentKey::from_secret(key))
+            .collect::<Result<_, _>>()?,
+        trusted_extent_keys: if args.trust_extent_key.is_empty() {
+            TrustedKeys::None
+        } else {
+            TrustedKeys::Only(args.trust_extent_key)
+        },
+        session_ttl: args.session_ttl.unwrap_or(DEFAULT_SESSION_TTL),
+        parity: args.parity,
+    };
+    let mut builder = Server::builder()
+        .fs_storage(&args.storage)
+        .options(options)
+        .listen(args.listen);
+    if let Some(path) = args.oplog {
+        builder = builder.oplog_path(path);
+    }
+    if let Some(token) = args.token {
+        builder = builder.auth_token(&token.fetch_text()?);
+    }
+    let server = builder.start().await?;
 
-    // Start server
-    let listener = tokio::net::TcpListener::bind(&args.listen).await?;
-    info!("Listening on {}", args.listen);
-    axum::serve(listener, app).await?;
+    // Finish in-flight requests on Ctrl-C
+    let shutdown = server.shutdown_token();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Shutting down");
+            shutdown.cancel();
+        }
+    });
+    server.wait().await?;
 
     Ok(())
 }
@@ -0,0 +1,286 @@
+//! Operation log of changes to the upload tracking database.
+//!
+//! When enabled, every change made through [`UploadDb`] is also appended to a log file as
+//! a line of JSON, numbered in sequence. Replaying the log in order into an empty database
+//! reconstructs the original exactly, including creation times, so the log can stand in for
+//! a backup of the database, or be followed by a replica which applies new entries as they
+//! come (from `GET /admin/oplog?after=N`).
+//!
+//! Entries are appended after the change is made to the database; if appending fails, the
+//! operation fails, but the change stays in the database.
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::B3Id;
+use crate::db::{CatalogStatus, DbError, UploadDb};
+
+/// Operation log error type.
+#[derive(Debug, Error)]
+pub enum OpLogError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid log entry on line {line}: {source}")]
+    InvalidEntry {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("Log entry {found} out of sequence, expected {expected}")]
+    OutOfSequence { expected: u64, found: u64 },
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+}
+
+/// A change to the upload tracking database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    CatalogCreated {
+        id: Uuid,
+        checksum: B3Id,
+        created_at: i64,
+    },
+    CreatedAtSet {
+        id: Uuid,
+        created_at: i64,
+    },
+    MachineSet {
+        id: Uuid,
+        machine: String,
+    },
+    StatusChanged {
+        id: Uuid,
+        status: CatalogStatus,
+    },
+    CatalogExtentsSet {
+        id: Uuid,
+        extents: Vec<B3Id>,
+    },
+    ExtentsVerified {
+        id: Uuid,
+        extents: Vec<B3Id>,
+    },
+    ExtentReferencesSet {
+        id: Uuid,
+        extents: Vec<B3Id>,
+    },
+    CatalogDeleted {
+        id: Uuid,
+    },
+}
+
+impl Operation {
+    /// Apply the change to a database, which logs it in turn if it has a log of its own.
+    pub fn apply(&self, db: &UploadDb) -> Result<(), DbError> {
+        match self {
+            Self::CatalogCreated {
+                id,
+                checksum,
+                created_at,
+            } => {
+                db.create_catalog(*id, checksum)?;
+                db.set_created_at(*id, *created_at)
+            }
+            Self::CreatedAtSet { id, created_at } => db.set_created_at(*id, *created_at),
+            Self::MachineSet { id, machine } => db.set_machine(*id, machine),
+            Self::StatusChanged { id, status } => db.update_status(*id, *status),
+            Self::CatalogExtentsSet { id, extents } => db.set_catalog_extents(*id, extents),
+            Self::ExtentsVerified { id, extents } => db.mark_extents_verified(*id, extents),
+            Self::ExtentReferencesSet { id, extents } => db.set_extent_references(*id, extents),
+            Self::CatalogDeleted { id } => db.delete_catalog(*id),
+        }
+    }
+}
+
+/// A numbered operation in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Position in the log, from 1.
+    pub seq: u64,
+    /// When the operation was logged, in milliseconds since the epoch.
+    pub at: i64,
+    #[serde(flatten)]
+    pub op: Operation,
+}
+
+/// An operation log file, open for appending.
+#[derive(Debug)]
+pub struct OpLog {
+    path: PathBuf,
+    file: File,
+    last_seq: Cell<u64>,
+}
+
+impl OpLog {
+    /// Open or create a log, to append after its last entry.
+    pub fn open(path: &Path) -> Result<Self, OpLogError> {
+        let last_seq = match File::open(path) {
+            Ok(_) => read_log(path, 0)?.last().map_or(0, |entry| entry.seq),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            last_seq: Cell::new(last_seq),
+        })
+    }
+
+    /// Path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sequence number of the last entry, or 0 if there are none.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.get()
+    }
+
+    /// Append an operation and sync it to disk, returning its sequence number.
+    pub fn append(&self, op: Operation) -> io::Result<u64> {
+        let entry = LogEntry {
+            seq: self.last_seq.get() + 1,
+            at: jiff::Timestamp::now().as_millisecond(),
+            op,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        (&self.file).write_all(&line)?;
+        self.file.sync_data()?;
+        self.last_seq.set(entry.seq);
+        Ok(entry.seq)
+    }
+}
+
+/// Read the entries of a log after a sequence number, checking they're in sequence.
+pub fn read_log(path: &Path, after: u64) -> Result<Vec<LogEntry>, OpLogError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    let mut expected = 1;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: LogEntry =
+            serde_json::from_str(&line).map_err(|source| OpLogError::InvalidEntry {
+                line: i + 1,
+                source,
+            })?;
+        if entry.seq != expected {
+            return Err(OpLogError::OutOfSequence {
+                expected,
+                found: entry.seq,
+            });
+        }
+        expected += 1;
+        if entry.seq > after {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Apply log entries to a database in order, returning the last sequence number applied.
+///
+/// The database must be in the state the log was at just before the first entry: empty to
+/// replay from the start, or a replica which has applied everything before.
+pub fn replay(entries: &[LogEntry], db: &UploadDb) -> Result<Option<u64>, OpLogError> {
+    let mut last = None;
+    for entry in entries {
+        if let Some(last) = last
+            && entry.seq != last + 1
+        {
+            return Err(OpLogError::OutOfSequence {
+                expected: last + 1,
+                found: entry.seq,
+            });
+        }
+        entry.op.apply(db)?;
+        last = Some(entry.seq);
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_reconstructs_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("oplog.jsonl");
+
+        let db = UploadDb::open_in_memory()
+            .unwrap()
+            .with_oplog(OpLog::open(&log_path).unwrap());
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let extents: Vec<B3Id> = vec![[1u8; 32].into(), [2u8; 32].into()];
+        db.create_catalog(a, &[0xaa; 32].into()).unwrap();
+        db.set_created_at(a, 1_700_000_000).unwrap();
+        db.set_machine(a, "machine").unwrap();
+        db.set_extent_references(a, &extents).unwrap();
+        db.set_catalog_extents(a, &extents).unwrap();
+        db.update_status(a, CatalogStatus::Uploading).unwrap();
+        db.mark_extents_verified(a, &extents[..1]).unwrap();
+        db.create_catalog(b, &[0xbb; 32].into()).unwrap();
+        db.delete_catalog(b).unwrap();
+
+        let entries = read_log(&log_path, 0).unwrap();
+        assert_eq!(entries.len(), 9);
+        assert_eq!(read_log(&log_path, 7).unwrap(), entries[7..]);
+
+        let replica = UploadDb::open_in_memory().unwrap();
+        assert_eq!(replay(&entries, &replica).unwrap(), Some(9));
+
+        let info = |db: &UploadDb| {
+            let info = db.get_catalog(a).unwrap().unwrap();
+            (info.checksum, info.status, info.created_at, info.machine)
+        };
+        assert_eq!(info(&replica), info(&db));
+        assert_eq!(
+            replica.get_unverified_catalog_extents(a).unwrap(),
+            vec![extents[1]]
+        );
+        assert_eq!(replica.get_extent_references(&extents[0]).unwrap().len(), 1);
+        assert!(replica.get_catalog(b).unwrap().is_none());
+
+        // Appending carries on from the end of an existing log
+        let log = OpLog::open(&log_path).unwrap();
+        assert_eq!(log.last_seq(), 9);
+        assert_eq!(log.append(Operation::CatalogDeleted { id: a }).unwrap(), 10);
+    }
+
+    #[test]
+    fn gaps_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("oplog.jsonl");
+        std::fs::write(
+            &log_path,
+            concat!(
+                r#"{"seq":1,"at":0,"op":"catalog_deleted","id":"00000000-0000-0000-0000-000000000000"}"#,
+                "\n",
+                r#"{"seq":3,"at":0,"op":"catalog_deleted","id":"00000000-0000-0000-0000-000000000000"}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            read_log(&log_path, 0),
+            Err(OpLogError::OutOfSequence {
+                expected: 2,
+                found: 3
+            })
+        ));
+    }
+}
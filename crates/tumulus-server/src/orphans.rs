@@ -0,0 +1,248 @@
+//! Finding and deleting extents that no catalog references.
+//!
+//! Extents are uploaded before the catalog that needs them is finalized, so an upload that
+//! is abandoned part-way leaves extents in storage that nothing refers to. An extent is an
+//! orphan when no catalog in the upload database uses it, whether complete or still being
+//! uploaded. Recently stored orphans are most likely part of an upload in progress whose
+//! catalog hasn't been registered yet, so only orphans older than a minimum age are
+//! reported.
+//!
+//! Deleting re-checks each extent against the database just before removing it, but an
+//! upload starting to use an extent in between can't be ruled out: keep the minimum age well
+//! above how long an upload takes.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use jiff::SignedDuration;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::B3Id;
+use crate::db::{DbError, UploadDb};
+use crate::storage::{Storage, StorageError};
+
+/// Error type for orphan extent reports and cleanup.
+#[derive(Debug, Error)]
+pub enum OrphanError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+}
+
+/// Orphans stored more recently than this are left out unless asked otherwise.
+pub const DEFAULT_MIN_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// Parse a minimum age, as a duration like `24h` or `PT24H`.
+pub fn parse_min_age(s: &str) -> Result<Duration, String> {
+    let duration: SignedDuration = s
+        .parse()
+        .map_err(|err| format!("invalid duration: {err}"))?;
+    Duration::try_from(duration).map_err(|_| "duration can't be negative".to_string())
+}
+
+/// An extent in storage that no catalog references.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OrphanExtent {
+    pub id: B3Id,
+    /// Size of the extent
+    pub bytes: u64,
+    /// Seconds since the extent was stored, if storage knows
+    pub age_secs: Option<u64>,
+}
+
+/// Extents in storage that no catalog references.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OrphanReport {
+    /// Orphans at least the minimum age, oldest first
+    pub orphans: Vec<OrphanExtent>,
+    /// Total size of the orphans listed
+    pub orphan_bytes: u64,
+    /// How many orphans were left out for being too recent, or of unknown age
+    pub recent: usize,
+    /// Total size of the orphans left out
+    pub recent_bytes: u64,
+}
+
+/// What deleting orphans did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OrphanDeletion {
+    /// How many extents were deleted
+    pub deleted: usize,
+    /// Bytes freed
+    pub bytes: u64,
+    /// How many extents were kept because a catalog had come to reference them
+    pub kept: usize,
+}
+
+/// Find the extents in storage that no catalog references, and that were stored at least
+/// `min_age` ago.
+///
+/// Extents whose age storage doesn't know are only listed when `min_age` is zero.
+pub async fn find_orphans<S: Storage>(
+    storage: &S,
+    db: &Mutex<UploadDb>,
+    min_age: Duration,
+) -> Result<OrphanReport, OrphanError> {
+    let extent_ids = storage.list_extents().await?;
+    let unreferenced: Vec<B3Id> = {
+        let db = db.lock().unwrap();
+        let mut unreferenced = Vec::new();
+        for id in extent_ids {
+            if !db.is_extent_referenced(&id)? {
+                unreferenced.push(id);
+            }
+        }
+        unreferenced
+    };
+
+    let now = SystemTime::now();
+    let mut report = OrphanReport::default();
+    for id in unreferenced {
+        let meta = match storage.extent_meta(&id).await {
+            Ok(meta) => meta,
+            // Deleted since it was listed
+            Err(StorageError::NotFound) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let age = meta
+            .created
+            .map(|created| now.duration_since(created).unwrap_or_default());
+
+        if min_age.is_zero() || age.is_some_and(|age| age >= min_age) {
+            report.orphan_bytes += meta.size;
+            report.orphans.push(OrphanExtent {
+                id,
+                bytes: meta.size,
+                age_secs: age.map(|age| age.as_secs()),
+            });
+        } else {
+            report.recent += 1;
+            report.recent_bytes += meta.size;
+        }
+    }
+    report
+        .orphans
+        .sort_by_key(|orphan| std::cmp::Reverse(orphan.age_secs));
+
+    info!(
+        orphans = report.orphans.len(),
+        bytes = report.orphan_bytes,
+        recent = report.recent,
+        "Found orphan extents"
+    );
+    Ok(report)
+}
+
+/// Delete orphan extents (and their sketches and parity), skipping any that a catalog now references.
+pub async fn delete_orphans<S: Storage>(
+    storage: &S,
+    db: &Mutex<UploadDb>,
+    orphans: &[OrphanExtent],
+) -> Result<OrphanDeletion, OrphanError> {
+    let mut deletion = OrphanDeletion::default();
+    for orphan in orphans {
+        if db.lock().unwrap().is_extent_referenced(&orphan.id)? {
+            debug!(extent = %orphan.id.as_hex(), "Orphan extent now referenced, keeping");
+            deletion.kept += 1;
+            continue;
+        }
+        if storage.delete_extent(&orphan.id).await? {
+            deletion.deleted += 1;
+            deletion.bytes += orphan.bytes;
+        }
+    }
+
+    info!(
+        deleted = deletion.deleted,
+        bytes = deletion.bytes,
+        kept = deletion.kept,
+        "Deleted orphan extents"
+    );
+    Ok(deletion)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::{ExtentCheck, FsStorage};
+
+    async fn put(storage: &FsStorage, data: &'static [u8]) -> B3Id {
+        let id = B3Id::from(blake3::hash(data));
+        storage
+            .put_extent(&id, Box::new(Cursor::new(data)), None, ExtentCheck::Hash)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn finds_and_deletes_unreferenced_extents() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        storage.init().await.unwrap();
+
+        let complete = put(&storage, b"complete").await;
+        let pending = put(&storage, b"pending").await;
+        let orphan = put(&storage, b"orphan").await;
+
+        let db = UploadDb::open_in_memory().unwrap();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        db.create_catalog(a, &[0xaa; 32].into()).unwrap();
+        db.set_extent_references(a, &[complete]).unwrap();
+        db.create_catalog(b, &[0xbb; 32].into()).unwrap();
+        db.set_catalog_extents(b, &[pending]).unwrap();
+        let db = Mutex::new(db);
+
+        // Everything just stored is too recent
+        let report = find_orphans(&storage, &db, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert!(report.orphans.is_empty());
+        assert_eq!((report.recent, report.recent_bytes), (1, 6));
+
+        let report = find_orphans(&storage, &db, Duration::ZERO).await.unwrap();
+        let ids: Vec<B3Id> = report.orphans.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![orphan]);
+        assert_eq!(report.orphan_bytes, 6);
+
+        let deletion = delete_orphans(&storage, &db, &report.orphans)
+            .await
+            .unwrap();
+        assert_eq!((deletion.deleted, deletion.bytes, deletion.kept), (1, 6, 0));
+        assert!(!storage.extent_exists(&orphan).await.unwrap());
+        assert!(storage.extent_exists(&complete).await.unwrap());
+        assert!(storage.extent_exists(&pending).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn keeps_extents_referenced_since_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        storage.init().await.unwrap();
+        let extent = put(&storage, b"late").await;
+        let db = Mutex::new(UploadDb::open_in_memory().unwrap());
+
+        let report = find_orphans(&storage, &db, Duration::ZERO).await.unwrap();
+        assert_eq!(report.orphans.len(), 1);
+
+        {
+            let db = db.lock().unwrap();
+            let id = Uuid::new_v4();
+            db.create_catalog(id, &[0xcc; 32].into()).unwrap();
+            db.set_catalog_extents(id, &[extent]).unwrap();
+        }
+        let deletion = delete_orphans(&storage, &db, &report.orphans)
+            .await
+            .unwrap();
+        assert_eq!((deletion.deleted, deletion.kept), (0, 1));
+        assert!(storage.extent_exists(&extent).await.unwrap());
+    }
+}
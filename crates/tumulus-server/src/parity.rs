@@ -0,0 +1,378 @@
+//! Reed-Solomon parity for extents, so corruption can be repaired without another copy.
+//!
+//! For servers keeping a single copy of their data, as on one archival disk, each extent can
+//! be stored with a parity block alongside it. The extent is cut into equal data shards
+//! (the last one padded with zeros), from which parity shards are computed; the BLAKE3
+//! hash of every shard is kept too, so damaged shards can be told apart from intact ones.
+//! Any damaged shards up to the number of parity shards can then be rebuilt, and when more
+//! are damaged, the hashes say exactly which ranges of the extent are lost.
+//!
+//! A parity block is a version byte, the number of data and parity shards, the extent's
+//! length, the shard length, the shard hashes, and the parity shards.
+
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+const PARITY_VERSION: u8 = 0x01;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParityDecodeError {
+    #[error("Invalid version: {0}")]
+    InvalidVersion(u8),
+    #[error("Truncated data")]
+    Truncated,
+    #[error("Invalid scheme: {0}")]
+    InvalidScheme(String),
+}
+
+/// How many data and parity shards extents are cut into.
+///
+/// Up to `parity_shards` damaged shards can be repaired, at a cost of
+/// `parity_shards / data_shards` of the extent's size in extra storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParityScheme {
+    pub data_shards: u8,
+    pub parity_shards: u8,
+}
+
+impl ParityScheme {
+    fn codec(&self) -> Result<ReedSolomon, ParityDecodeError> {
+        ReedSolomon::new(self.data_shards.into(), self.parity_shards.into())
+            .map_err(|err| ParityDecodeError::InvalidScheme(format!("{self}: {err:?}")))
+    }
+}
+
+impl Default for ParityScheme {
+    /// 8 data shards and 2 parity shards: 25% extra storage.
+    fn default() -> Self {
+        Self {
+            data_shards: 8,
+            parity_shards: 2,
+        }
+    }
+}
+
+impl fmt::Display for ParityScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", self.data_shards, self.parity_shards)
+    }
+}
+
+impl FromStr for ParityScheme {
+    type Err = String;
+
+    /// Parse a scheme written as `DATA+PARITY`, like `8+2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (data, parity) = s
+            .split_once('+')
+            .ok_or_else(|| format!("expected DATA+PARITY shards, like 8+2, got {s:?}"))?;
+        let shards = |n: &str| {
+            n.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("invalid shard count {n:?}"))
+        };
+        let scheme = Self {
+            data_shards: shards(data)?,
+            parity_shards: shards(parity)?,
+        };
+        scheme.codec().map_err(|err| err.to_string())?;
+        Ok(scheme)
+    }
+}
+
+/// The parity block of an extent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentParity {
+    pub scheme: ParityScheme,
+    /// Length of the extent.
+    pub length: u64,
+    /// Hashes of the data shards, then the parity shards.
+    shard_hashes: Vec<[u8; 32]>,
+    /// The parity shards.
+    parity: Vec<Vec<u8>>,
+}
+
+/// What checking an extent against its parity found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParityCheck {
+    /// The extent and its parity are intact.
+    Intact,
+    /// The extent is intact, but some parity shards are damaged, so the parity block should
+    /// be computed again.
+    ParityDamaged,
+    /// The extent was damaged, and has been rebuilt from its intact shards.
+    Repaired {
+        data: Vec<u8>,
+        /// How many shards were rebuilt.
+        shards: usize,
+    },
+    /// Too many shards are damaged to rebuild the extent; these ranges of it are lost.
+    Lost { ranges: Vec<Range<u64>> },
+}
+
+impl ExtentParity {
+    /// Header size in bytes
+    const HEADER_SIZE: usize = 1 + 1 + 1 + 8 + 4;
+
+    /// Compute the parity block of an extent.
+    pub fn compute(scheme: ParityScheme, data: &[u8]) -> Result<Self, ParityDecodeError> {
+        let codec = scheme.codec()?;
+        let shard_len = Self::shard_len(scheme, data.len() as u64);
+        let mut shards = Self::data_shards(scheme, shard_len, data);
+        shards.extend((0..scheme.parity_shards).map(|_| vec![0; shard_len]));
+        codec
+            .encode(&mut shards)
+            .map_err(|err| ParityDecodeError::InvalidScheme(format!("{err:?}")))?;
+
+        Ok(Self {
+            scheme,
+            length: data.len() as u64,
+            shard_hashes: shards.iter().map(|shard| hash(shard)).collect(),
+            parity: shards.split_off(scheme.data_shards.into()),
+        })
+    }
+
+    /// Shards are at least a byte, so empty extents still have some.
+    fn shard_len(scheme: ParityScheme, length: u64) -> usize {
+        (length.div_ceil(scheme.data_shards.into()) as usize).max(1)
+    }
+
+    /// Cut extent data into data shards, padding with zeros.
+    fn data_shards(scheme: ParityScheme, shard_len: usize, data: &[u8]) -> Vec<Vec<u8>> {
+        (0..scheme.data_shards as usize)
+            .map(|i| {
+                let start = (i * shard_len).min(data.len());
+                let end = (start + shard_len).min(data.len());
+                let mut shard = data[start..end].to_vec();
+                shard.resize(shard_len, 0);
+                shard
+            })
+            .collect()
+    }
+
+    /// Check stored extent data against the parity, rebuilding it if it's damaged.
+    ///
+    /// Data of the wrong length is damaged too: missing bytes are taken as zeros, which
+    /// damages the shards they're in, and extra bytes are cut off.
+    pub fn check(&self, data: &[u8]) -> ParityCheck {
+        let shard_len = Self::shard_len(self.scheme, self.length);
+        let data_len = self.length.min(data.len() as u64) as usize;
+        let mut shards = Self::data_shards(self.scheme, shard_len, &data[..data_len]);
+        shards.extend(self.parity.iter().cloned());
+
+        let damaged: Vec<usize> = shards
+            .iter()
+            .zip(&self.shard_hashes)
+            .enumerate()
+            .filter(|(_, (shard, expected))| hash(shard) != **expected)
+            .map(|(i, _)| i)
+            .collect();
+        let data_shards = self.scheme.data_shards as usize;
+        let data_damaged = damaged.iter().any(|&i| i < data_shards);
+        if !data_damaged {
+            if data.len() as u64 == self.length {
+                return if damaged.is_empty() {
+                    ParityCheck::Intact
+                } else {
+                    ParityCheck::ParityDamaged
+                };
+            }
+            // Only the length was wrong
+            let mut data: Vec<u8> = shards.into_iter().take(data_shards).flatten().collect();
+            data.truncate(self.length as usize);
+            return ParityCheck::Repaired { data, shards: 0 };
+        }
+
+        if damaged.len() > self.scheme.parity_shards as usize {
+            let mut ranges: Vec<Range<u64>> = Vec::new();
+            for &i in damaged.iter().filter(|&&i| i < data_shards) {
+                let start = (i * shard_len) as u64;
+                let end = (start + shard_len as u64).min(self.length);
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+            }
+            return ParityCheck::Lost { ranges };
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        for &i in &damaged {
+            shards[i] = None;
+        }
+        // Enough shards are intact, so this can't fail
+        if let Ok(codec) = self.scheme.codec()
+            && codec.reconstruct_data(&mut shards).is_ok()
+        {
+            let mut rebuilt: Vec<u8> = shards
+                .into_iter()
+                .take(data_shards)
+                .flat_map(Option::unwrap_or_default)
+                .collect();
+            rebuilt.truncate(self.length as usize);
+            return ParityCheck::Repaired {
+                data: rebuilt,
+                shards: damaged.iter().filter(|&&i| i < data_shards).count(),
+            };
+        }
+        ParityCheck::Lost {
+            ranges: std::iter::once(0..self.length).collect(),
+        }
+    }
+
+    /// Encode to binary format.
+    pub fn encode(&self) -> Bytes {
+        let shard_len = Self::shard_len(self.scheme, self.length);
+        let mut buf = BytesMut::with_capacity(
+            Self::HEADER_SIZE + self.shard_hashes.len() * 32 + self.parity.len() * shard_len,
+        );
+        buf.put_u8(PARITY_VERSION);
+        buf.put_u8(self.scheme.data_shards);
+        buf.put_u8(self.scheme.parity_shards);
+        buf.put_u64_le(self.length);
+        buf.put_u32_le(shard_len as u32);
+        for hash in &self.shard_hashes {
+            buf.put_slice(hash);
+        }
+        for shard in &self.parity {
+            buf.put_slice(shard);
+        }
+        buf.freeze()
+    }
+
+    /// Decode from binary format.
+    pub fn decode(mut data: &[u8]) -> Result<Self, ParityDecodeError> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(ParityDecodeError::Truncated);
+        }
+        let version = data.get_u8();
+        if version != PARITY_VERSION {
+            return Err(ParityDecodeError::InvalidVersion(version));
+        }
+        let scheme = ParityScheme {
+            data_shards: data.get_u8(),
+            parity_shards: data.get_u8(),
+        };
+        scheme.codec()?;
+        let length = data.get_u64_le();
+        let shard_len = data.get_u32_le() as usize;
+        if shard_len != Self::shard_len(scheme, length) {
+            return Err(ParityDecodeError::InvalidScheme(format!(
+                "shards of {shard_len} bytes for {length} bytes of data"
+            )));
+        }
+
+        let shards = scheme.data_shards as usize + scheme.parity_shards as usize;
+        if data.len() != shards * 32 + scheme.parity_shards as usize * shard_len {
+            return Err(ParityDecodeError::Truncated);
+        }
+        let shard_hashes = (0..shards)
+            .map(|_| {
+                let mut hash = [0; 32];
+                data.copy_to_slice(&mut hash);
+                hash
+            })
+            .collect();
+        let parity = data.chunks(shard_len).map(<[u8]>::to_vec).collect();
+
+        Ok(Self {
+            scheme,
+            length,
+            shard_hashes,
+            parity,
+        })
+    }
+}
+
+fn hash(shard: &[u8]) -> [u8; 32] {
+    *blake3::hash(shard).as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn parses_schemes() {
+        assert_eq!(
+            "4+2".parse::<ParityScheme>(),
+            Ok(ParityScheme {
+                data_shards: 4,
+                parity_shards: 2
+            })
+        );
+        assert!("4".parse::<ParityScheme>().is_err());
+        assert!("0+2".parse::<ParityScheme>().is_err());
+        assert!("200+100".parse::<ParityScheme>().is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let parity = ExtentParity::compute(ParityScheme::default(), &extent()).unwrap();
+        assert_eq!(ExtentParity::decode(&parity.encode()).unwrap(), parity);
+        assert!(ExtentParity::decode(&parity.encode()[..20]).is_err());
+    }
+
+    #[test]
+    fn repairs_up_to_parity_shards() {
+        let data = extent();
+        let parity = ExtentParity::compute(ParityScheme::default(), &data).unwrap();
+        assert_eq!(parity.check(&data), ParityCheck::Intact);
+
+        // Shards are 1250 bytes: damage the first and the last
+        let mut damaged = data.clone();
+        damaged[10] ^= 0xff;
+        damaged[9_999] ^= 0xff;
+        assert_eq!(
+            parity.check(&damaged),
+            ParityCheck::Repaired {
+                data: data.clone(),
+                shards: 2
+            }
+        );
+
+        // Truncated data is damaged where it's missing
+        assert_eq!(
+            parity.check(&data[..9_000]),
+            ParityCheck::Repaired {
+                data: data.clone(),
+                shards: 1
+            }
+        );
+    }
+
+    #[test]
+    fn reports_lost_ranges() {
+        let data = extent();
+        let parity = ExtentParity::compute(ParityScheme::default(), &data).unwrap();
+
+        let mut damaged = data.clone();
+        for offset in [0, 1_300, 2_600, 9_999] {
+            damaged[offset] ^= 0xff;
+        }
+        assert_eq!(
+            parity.check(&damaged),
+            ParityCheck::Lost {
+                ranges: vec![0..3_750, 8_750..10_000]
+            }
+        );
+    }
+
+    #[test]
+    fn damaged_parity_is_noticed() {
+        let data = extent();
+        let mut parity = ExtentParity::compute(ParityScheme::default(), &data).unwrap();
+        parity.parity[1][0] ^= 0xff;
+        assert_eq!(parity.check(&data), ParityCheck::ParityDamaged);
+    }
+}
@@ -0,0 +1,325 @@
+//! Retention rules and estimating what pruning catalogs would free.
+//!
+//! Retention rules decide which catalogs to keep, separately for each machine: the
+//! `keep_last` most recent, plus the most recent catalog in each of the `keep_daily` most
+//! recent days that have one (and likewise weekly and monthly, all in UTC). A catalog kept
+//! by any rule is kept. Only complete catalogs are subject to the rules; uploads still in
+//! progress are always kept. With no rules at all, everything is kept.
+//!
+//! Pruning frees the catalog files themselves, and the extents that no remaining catalog
+//! references, as recorded in the extent reference index.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use futures::{StreamExt, TryStreamExt, stream};
+use jiff::{Timestamp, civil::Date, tz::TimeZone};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::B3Id;
+use crate::db::{CatalogInfo, CatalogStatus, DbError, UploadDb};
+use crate::storage::{Storage, StorageError};
+
+/// Error type for prune estimation.
+#[derive(Debug, Error)]
+pub enum PruneError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+}
+
+/// Which catalogs to keep when pruning.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Keep this many of the most recent catalogs.
+    pub keep_last: Option<usize>,
+    /// Keep the most recent catalog of this many days.
+    pub keep_daily: Option<usize>,
+    /// Keep the most recent catalog of this many (ISO) weeks.
+    pub keep_weekly: Option<usize>,
+    /// Keep the most recent catalog of this many months.
+    pub keep_monthly: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Whether no rules are set, so that everything is kept.
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+    }
+
+    /// This policy, with the rules set in `overrides` replacing its own.
+    pub fn with_overrides(&self, overrides: &RetentionPolicy) -> Self {
+        Self {
+            keep_last: overrides.keep_last.or(self.keep_last),
+            keep_daily: overrides.keep_daily.or(self.keep_daily),
+            keep_weekly: overrides.keep_weekly.or(self.keep_weekly),
+            keep_monthly: overrides.keep_monthly.or(self.keep_monthly),
+        }
+    }
+
+    /// Split catalogs into those to keep and those to remove.
+    pub fn apply(&self, catalogs: &[CatalogInfo]) -> (Vec<CatalogInfo>, Vec<CatalogInfo>) {
+        if self.is_empty() {
+            return (catalogs.to_vec(), Vec::new());
+        }
+
+        let mut by_machine: BTreeMap<Option<&str>, Vec<&CatalogInfo>> = BTreeMap::new();
+        for catalog in catalogs
+            .iter()
+            .filter(|c| c.status == CatalogStatus::Complete)
+        {
+            by_machine
+                .entry(catalog.machine.as_deref())
+                .or_default()
+                .push(catalog);
+        }
+
+        let mut keep: HashSet<Uuid> = HashSet::new();
+        for mut group in by_machine.into_values() {
+            group.sort_by_key(|c| std::cmp::Reverse((c.created_at, c.id)));
+
+            keep.extend(group.iter().take(self.keep_last.unwrap_or(0)).map(|c| c.id));
+            keep_per_period(&mut keep, &group, self.keep_daily, |date| {
+                (date.year(), date.day_of_year())
+            });
+            keep_per_period(&mut keep, &group, self.keep_weekly, |date| {
+                let week = date.iso_week_date();
+                (week.year(), week.week() as i16)
+            });
+            keep_per_period(&mut keep, &group, self.keep_monthly, |date| {
+                (date.year(), date.month() as i16)
+            });
+        }
+
+        catalogs
+            .iter()
+            .cloned()
+            .partition(|c| c.status != CatalogStatus::Complete || keep.contains(&c.id))
+    }
+}
+
+/// Keep the most recent catalog of each of the `count` most recent periods, given catalogs
+/// sorted newest first.
+fn keep_per_period(
+    keep: &mut HashSet<Uuid>,
+    newest_first: &[&CatalogInfo],
+    count: Option<usize>,
+    period: impl Fn(Date) -> (i16, i16),
+) {
+    let Some(count) = count else {
+        return;
+    };
+
+    let mut last = None;
+    let mut kept = 0;
+    for catalog in newest_first {
+        if kept >= count {
+            break;
+        }
+        let Ok(created) = Timestamp::from_second(catalog.created_at) else {
+            continue;
+        };
+        let key = period(created.to_zoned(TimeZone::UTC).date());
+        if last != Some(key) {
+            keep.insert(catalog.id);
+            last = Some(key);
+            kept += 1;
+        }
+    }
+}
+
+/// Which catalogs pruning would remove, and which extents that would leave unreferenced.
+#[derive(Debug, Clone, Default)]
+pub struct PrunePlan {
+    /// Catalogs to keep, oldest first.
+    pub kept: Vec<CatalogInfo>,
+    /// Catalogs to remove, oldest first.
+    pub removed: Vec<CatalogInfo>,
+    /// Extents referenced only by removed catalogs.
+    pub unreferenced_extents: Vec<B3Id>,
+}
+
+impl PrunePlan {
+    /// Evaluate a retention policy against the catalogs in the database.
+    pub fn new(db: &UploadDb, policy: &RetentionPolicy) -> Result<Self, DbError> {
+        let (kept, removed) = policy.apply(&db.list_catalogs()?);
+        let removed_ids: Vec<Uuid> = removed.iter().map(|c| c.id).collect();
+        let unreferenced_extents = if removed_ids.is_empty() {
+            Vec::new()
+        } else {
+            db.exclusive_extents(&removed_ids)?
+        };
+
+        Ok(Self {
+            kept,
+            removed,
+            unreferenced_extents,
+        })
+    }
+}
+
+/// How much space carrying out a [`PrunePlan`] would free.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneEstimate {
+    /// Size of each removed catalog file, in the order of [`PrunePlan::removed`].
+    pub catalog_sizes: Vec<u64>,
+    /// How many of the unreferenced extents are in storage.
+    pub stored_extents: usize,
+    /// Total size of the unreferenced extents in storage.
+    pub extent_bytes: u64,
+}
+
+impl PruneEstimate {
+    /// Total size of the removed catalog files.
+    pub fn catalog_bytes(&self) -> u64 {
+        self.catalog_sizes.iter().sum()
+    }
+
+    /// Total space freed.
+    pub fn total_bytes(&self) -> u64 {
+        self.catalog_bytes() + self.extent_bytes
+    }
+}
+
+/// Measure how much space a prune plan would free, looking up at most `concurrency` sizes
+/// in storage at once.
+///
+/// Catalogs and extents that aren't in storage count for nothing.
+pub async fn estimate_prune<S: Storage>(
+    storage: Arc<S>,
+    plan: &PrunePlan,
+    concurrency: usize,
+) -> Result<PruneEstimate, PruneError> {
+    let mut catalog_sizes = Vec::with_capacity(plan.removed.len());
+    for catalog in &plan.removed {
+        catalog_sizes.push(match storage.catalog_meta(catalog.id).await {
+            Ok(meta) => meta.size,
+            Err(StorageError::NotFound) => 0,
+            Err(err) => return Err(err.into()),
+        });
+    }
+
+    let sizes: Vec<Option<u64>> = stream::iter(plan.unreferenced_extents.iter().copied())
+        .map(|id| {
+            let storage = Arc::clone(&storage);
+            async move {
+                match storage.extent_meta(&id).await {
+                    Ok(meta) => Ok(Some(meta.size)),
+                    Err(StorageError::NotFound) => Ok(None),
+                    Err(err) => Err(err),
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    let estimate = PruneEstimate {
+        catalog_sizes,
+        stored_extents: sizes.iter().flatten().count(),
+        extent_bytes: sizes.iter().flatten().sum(),
+    };
+    debug!(
+        catalogs = plan.removed.len(),
+        extents = estimate.stored_extents,
+        bytes = estimate.total_bytes(),
+        "Estimated prune"
+    );
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86400;
+
+    fn catalog(n: u8, machine: &str, created_at: i64) -> CatalogInfo {
+        CatalogInfo {
+            id: Uuid::from_bytes([n; 16]),
+            checksum: [n; 32].into(),
+            status: CatalogStatus::Complete,
+            created_at,
+            machine: Some(machine.to_string()),
+        }
+    }
+
+    fn ids(catalogs: &[CatalogInfo]) -> Vec<u8> {
+        catalogs.iter().map(|c| c.id.as_bytes()[0]).collect()
+    }
+
+    #[test]
+    fn empty_policy_keeps_everything() {
+        let catalogs = vec![catalog(1, "a", 0), catalog(2, "a", DAY)];
+        let (kept, removed) = RetentionPolicy::default().apply(&catalogs);
+        assert_eq!(ids(&kept), vec![1, 2]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn keep_last_per_machine() {
+        let catalogs = vec![
+            catalog(1, "a", 0),
+            catalog(2, "b", DAY),
+            catalog(3, "a", 2 * DAY),
+            catalog(4, "a", 3 * DAY),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let (kept, removed) = policy.apply(&catalogs);
+        assert_eq!(ids(&kept), vec![2, 3, 4]);
+        assert_eq!(ids(&removed), vec![1]);
+    }
+
+    #[test]
+    fn keep_daily_takes_newest_of_each_day() {
+        // 2024-01-01 and 2024-01-02, twice each, then 2024-01-03
+        let base = 1_704_067_200;
+        let catalogs = vec![
+            catalog(1, "a", base + 100),
+            catalog(2, "a", base + 200),
+            catalog(3, "a", base + DAY + 100),
+            catalog(4, "a", base + DAY + 200),
+            catalog(5, "a", base + 2 * DAY),
+        ];
+        let policy = RetentionPolicy {
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+        let (kept, removed) = policy.apply(&catalogs);
+        assert_eq!(ids(&kept), vec![4, 5]);
+        assert_eq!(ids(&removed), vec![1, 2, 3]);
+
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            keep_monthly: Some(1),
+            ..Default::default()
+        };
+        let (kept, _) = policy.apply(&catalogs);
+        assert_eq!(ids(&kept), vec![5]);
+    }
+
+    #[test]
+    fn incomplete_catalogs_are_kept() {
+        let mut pending = catalog(2, "a", DAY);
+        pending.status = CatalogStatus::Uploading;
+        let catalogs = vec![catalog(1, "a", 0), pending];
+        let policy = RetentionPolicy {
+            keep_last: Some(0),
+            ..Default::default()
+        };
+        let (kept, removed) = policy.apply(&catalogs);
+        assert_eq!(ids(&kept), vec![2]);
+        assert_eq!(ids(&removed), vec![1]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Rebuilding the upload tracking database from storage contents.
+//!
+//! If `uploads.db` is lost, the catalogs and extents in storage are still intact, but the
+//! server no longer knows about them. This scans storage, re-reads every catalog, and
+//! repopulates the catalog and catalog extent tables with the status each catalog would
+//! have if it had been uploaded normally. Databases from before extent references were
+//! recorded are backfilled the same way, by reading their catalogs again.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use thiserror::Error;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::B3Id;
+use crate::api::{ApiOptions, CatalogError, CatalogReader, find_missing_extents};
+use crate::db::{CatalogStatus, DbError, UploadDb};
+use crate::scratch::Scratch;
+use crate::storage::{Storage, StorageError};
+
+/// Error type for index rebuilding.
+#[derive(Debug, Error)]
+pub enum RebuildError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+
+    #[error("Catalog error: {0}")]
+    Catalog(#[from] CatalogError),
+}
+
+/// Summary of an index rebuild.
+#[derive(Debug, Clone, Default)]
+pub struct RebuildReport {
+    /// Catalogs whose extents are all present in storage.
+    pub complete: Vec<Uuid>,
+    /// Catalogs with some extents missing from storage.
+    pub incomplete: Vec<Uuid>,
+    /// Catalogs that couldn't be read.
+    pub failed: Vec<Uuid>,
+    /// Extents present in storage but not referenced by any catalog.
+    pub orphan_extents: Vec<B3Id>,
+}
+
+/// Rebuild the upload tracking database from the contents of storage.
+///
+/// Catalogs already in the database are updated in place; their checksum is kept. Catalogs
+/// that are new to the database are recorded with the checksum of the stored file. Extents
+/// are checked for in storage in batches, as set by `options`, as they are during uploads.
+///
+/// A catalog that can't be read is reported as failed and otherwise skipped. Errors from
+/// storage itself or the database abort the rebuild.
+pub async fn rebuild_index<S: Storage>(
+    storage: &S,
+    db: &UploadDb,
+    options: &ApiOptions,
+) -> Result<RebuildReport, RebuildError> {
+    let catalog_ids = storage.list_catalogs().await?;
+    info!(
+        catalogs = catalog_ids.len(),
+        "Rebuilding index from storage"
+    );
+
+    let mut report = RebuildReport::default();
+    let mut referenced: HashSet<B3Id> = HashSet::new();
+    let scratch = Scratch::unlimited();
+
+    for catalog_id in catalog_ids {
+        let data = storage.get_catalog(catalog_id).await?;
+
+        let reader = match CatalogReader::new(&data, &scratch) {
+            Ok(reader) => reader,
+            Err(err) => {
+                warn!(%catalog_id, %err, "Failed to read catalog, skipping");
+                report.failed.push(catalog_id);
+                continue;
+            }
+        };
+
+        let extent_ids = match reader.extent_ids() {
+            Ok(ids) => ids,
+            Err(err) => {
+                warn!(%catalog_id, %err, "Failed to read catalog extents, skipping");
+                report.failed.push(catalog_id);
+                continue;
+            }
+        };
+
+        if db.get_catalog(catalog_id)?.is_none() {
+            let checksum = B3Id::from(blake3::hash(&data));
+            db.create_catalog(catalog_id, &checksum)?;
+        }
+        if let Some(created) = reader.created()? {
+            db.set_created_at(catalog_id, created / 1000)?;
+        }
+        if let Some(machine) = reader.machine()? {
+            db.set_machine(catalog_id, &machine)?;
+        }
+
+        db.set_catalog_extents(catalog_id, &extent_ids)?;
+        db.set_extent_references(catalog_id, &extent_ids)?;
+        let missing = find_missing_extents(storage, options, extent_ids.clone(), |present| {
+            Ok(db.mark_extents_verified(catalog_id, present)?)
+        })
+        .await?
+        .len();
+
+        if missing == 0 {
+            db.update_status(catalog_id, CatalogStatus::Complete)?;
+            report.complete.push(catalog_id);
+        } else {
+            db.update_status(catalog_id, CatalogStatus::Uploading)?;
+            report.incomplete.push(catalog_id);
+        }
+
+        debug!(
+            %catalog_id,
+            extents = extent_ids.len(),
+            missing,
+            "Rebuilt catalog entry"
+        );
+        referenced.extend(extent_ids);
+    }
+
+    report.orphan_extents = storage
+        .list_extents()
+        .await?
+        .into_iter()
+        .filter(|id| !referenced.contains(id))
+        .collect();
+
+    info!(
+        complete = report.complete.len(),
+        incomplete = report.incomplete.len(),
+        failed = report.failed.len(),
+        orphan_extents = report.orphan_extents.len(),
+        "Index rebuild complete"
+    );
+    db.mark_references_complete()?;
+
+    Ok(report)
+}
+
+/// Fill in the extent references of catalogs received before the database recorded them,
+/// by reading the catalogs again from storage.
+///
+/// Returns whether extent references are complete afterwards: catalogs that are missing
+/// from storage or can't be read leave them incomplete, until [`rebuild_index`] is run.
+pub async fn backfill_references<S: Storage>(
+    storage: &S,
+    db: &Mutex<UploadDb>,
+) -> Result<bool, RebuildError> {
+    if db.lock().unwrap().references_complete()? {
+        return Ok(true);
+    }
+    let catalog_ids: Vec<Uuid> = db
+        .lock()
+        .unwrap()
+        .list_catalogs()?
+        .into_iter()
+        .filter(|catalog| catalog.status != CatalogStatus::Pending)
+        .map(|catalog| catalog.id)
+        .collect();
+    info!(
+        catalogs = catalog_ids.len(),
+        "Backfilling extent references from storage"
+    );
+
+    let scratch = Scratch::unlimited();
+    let mut complete = true;
+    for catalog_id in catalog_ids {
+        let data = match storage.get_catalog(catalog_id).await {
+            Ok(data) => data,
+            Err(StorageError::NotFound) => {
+                warn!(%catalog_id, "Catalog missing from storage, can't backfill its references");
+                complete = false;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let extent_ids = match CatalogReader::new(&data, &scratch)
+            .and_then(|reader| reader.extent_ids())
+        {
+            Ok(ids) => ids,
+            Err(err) => {
+                warn!(%catalog_id, %err, "Failed to read catalog extents, can't backfill its references");
+                complete = false;
+                continue;
+            }
+        };
+        db.lock()
+            .unwrap()
+            .set_extent_references(catalog_id, &extent_ids)?;
+        debug!(%catalog_id, extents = extent_ids.len(), "Backfilled extent references");
+    }
+
+    if complete {
+        db.lock().unwrap().mark_references_complete()?;
+        info!("Extent references backfilled");
+    } else {
+        warn!("Extent references are still incomplete; run rebuild-index to finish them");
+    }
+    Ok(complete)
+}
@@ -0,0 +1,260 @@
+//! Accounting for scratch space used while processing catalogs.
+//!
+//! Catalogs are decompressed to temporary files to be read, and patches are applied in
+//! memory; both can be much larger than the request that caused them. Scratch space is
+//! reserved before use and released when the reservation is dropped, and reservations
+//! that would go over the configured limit are refused, so the server can turn clients
+//! away until space frees up instead of filling its disk.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tempfile::NamedTempFile;
+use tracing::{debug, warn};
+
+/// Prefix of scratch files, to find them again after a crash.
+const SCRATCH_PREFIX: &str = ".tumulus-scratch-";
+
+/// A scratch reservation was refused because the limit would be exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Scratch space exhausted: {requested} bytes requested, {in_use} of {limit} in use")]
+pub struct ScratchFull {
+    pub requested: u64,
+    pub in_use: u64,
+    pub limit: u64,
+}
+
+/// Scratch space: a directory for temporary files, and a limit on bytes in use at once.
+#[derive(Debug, Clone)]
+pub struct Scratch {
+    inner: Arc<ScratchInner>,
+}
+
+#[derive(Debug)]
+struct ScratchInner {
+    dir: Option<PathBuf>,
+    limit: Option<u64>,
+    in_use: AtomicU64,
+}
+
+impl Scratch {
+    /// Create scratch space in a directory (or the system temporary directory), with an
+    /// optional limit on the bytes in use at once.
+    pub fn new(dir: Option<PathBuf>, limit: Option<u64>) -> Self {
+        Self {
+            inner: Arc::new(ScratchInner {
+                dir,
+                limit,
+                in_use: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Scratch space in the system temporary directory, without a limit.
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    /// Bytes currently reserved.
+    pub fn in_use(&self) -> u64 {
+        self.inner.in_use.load(Ordering::Acquire)
+    }
+
+    /// Reserve scratch bytes, released when the reservation is dropped.
+    pub fn reserve(&self, bytes: u64) -> Result<Reservation, ScratchFull> {
+        self.try_add(bytes)?;
+        Ok(Reservation {
+            scratch: self.clone(),
+            bytes,
+        })
+    }
+
+    fn try_add(&self, bytes: u64) -> Result<(), ScratchFull> {
+        let Some(limit) = self.inner.limit else {
+            self.inner.in_use.fetch_add(bytes, Ordering::AcqRel);
+            return Ok(());
+        };
+
+        self.inner
+            .in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                in_use.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .map(|_| ())
+            .map_err(|in_use| ScratchFull {
+                requested: bytes,
+                in_use,
+                limit,
+            })
+    }
+
+    /// Create a temporary file in the scratch directory, creating the directory if needed.
+    pub fn temp_file(&self) -> io::Result<NamedTempFile> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(SCRATCH_PREFIX);
+        match self.inner.dir {
+            Some(ref dir) => {
+                fs::create_dir_all(dir)?;
+                builder.tempfile_in(dir)
+            }
+            None => builder.tempfile(),
+        }
+    }
+
+    /// Delete scratch files left behind by a previous run (after a crash, for example).
+    ///
+    /// Only call this at startup, when no scratch files are in use. Without a scratch
+    /// directory nothing is deleted, as the system's temporary directory may hold the
+    /// scratch files of other servers. Returns how many files were deleted.
+    pub fn clean_stragglers(&self) -> io::Result<usize> {
+        let Some(ref dir) = self.inner.dir else {
+            return Ok(0);
+        };
+        fs::create_dir_all(dir)?;
+        clean_dir(dir)
+    }
+}
+
+fn clean_dir(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(SCRATCH_PREFIX)
+        {
+            continue;
+        }
+        match fs::remove_file(entry.path()) {
+            Ok(()) => {
+                debug!(path = ?entry.path(), "Removed leftover scratch file");
+                removed += 1;
+            }
+            Err(err) => warn!(path = ?entry.path(), %err, "Failed to remove leftover scratch file"),
+        }
+    }
+    Ok(removed)
+}
+
+/// Reserved scratch bytes, released on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    scratch: Scratch,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Reserve more bytes under this reservation.
+    pub fn grow(&mut self, bytes: u64) -> Result<(), ScratchFull> {
+        self.scratch.try_add(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Bytes reserved.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.scratch
+            .inner
+            .in_use
+            .fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// A writer that grows a reservation to cover everything written through it.
+///
+/// Writes that would go over the limit fail with an I/O error wrapping [`ScratchFull`].
+pub(crate) struct ReservedWriter<'a, W> {
+    inner: W,
+    reservation: &'a mut Reservation,
+}
+
+impl<'a, W: Write> ReservedWriter<'a, W> {
+    pub(crate) fn new(inner: W, reservation: &'a mut Reservation) -> Self {
+        Self { inner, reservation }
+    }
+}
+
+impl<W: Write> Write for ReservedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.reservation
+            .grow(buf.len() as u64)
+            .map_err(io::Error::other)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_are_limited_and_released() {
+        let scratch = Scratch::new(None, Some(100));
+
+        let mut a = scratch.reserve(60).unwrap();
+        assert!(scratch.reserve(50).is_err());
+        assert!(a.grow(50).is_err());
+        a.grow(20).unwrap();
+        assert_eq!(scratch.in_use(), 80);
+        assert_eq!(a.bytes(), 80);
+
+        drop(a);
+        assert_eq!(scratch.in_use(), 0);
+        let _b = scratch.reserve(100).unwrap();
+    }
+
+    #[test]
+    fn writes_count_against_limit() {
+        let scratch = Scratch::new(None, Some(10));
+        let mut reservation = scratch.reserve(0).unwrap();
+        let mut writer = ReservedWriter::new(Vec::new(), &mut reservation);
+
+        writer.write_all(b"12345678").unwrap();
+        let err = writer.write_all(b"12345678").unwrap_err();
+        assert!(err.get_ref().unwrap().is::<ScratchFull>());
+        assert_eq!(scratch.in_use(), 8);
+    }
+
+    #[test]
+    fn stragglers() {
+        let dir = tempfile::tempdir().unwrap();
+        let scratch = Scratch::new(Some(dir.path().to_path_buf()), None);
+
+        let kept = scratch.temp_file().unwrap();
+        let (_, leftover) = scratch.temp_file().unwrap().keep().unwrap();
+        fs::write(dir.path().join("unrelated"), "").unwrap();
+
+        // The kept file is still open here, but it's removed too: only call at startup
+        assert_eq!(scratch.clean_stragglers().unwrap(), 2);
+        assert!(!leftover.exists());
+        assert!(dir.path().join("unrelated").exists());
+        drop(kept);
+    }
+
+    #[test]
+    fn shared_temp_dir_is_left_alone() {
+        let scratch = Scratch::new(None, None);
+        let (_, other) = scratch.temp_file().unwrap().keep().unwrap();
+        assert_eq!(scratch.clean_stragglers().unwrap(), 0);
+        assert!(other.exists());
+        fs::remove_file(other).unwrap();
+    }
+}
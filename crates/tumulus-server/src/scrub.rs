@@ -0,0 +1,248 @@
+//! Checking stored extents for corruption, and repairing what can be.
+//!
+//! Every extent in storage is read back in full. Extents with a parity block (see
+//! [`crate::parity`]) are checked shard by shard against it: damage up to the number of
+//! parity shards is repaired by writing the rebuilt extent back, damaged parity blocks are
+//! computed again, and beyond that the ranges of the extent that are lost are reported.
+//!
+//! Extents without parity can only be checked against their ID, so damage to them is
+//! reported for the whole extent. Extents with keyed IDs can only be checked with their key,
+//! and are reported as damaged without it. When scrubbing with a parity scheme, extents
+//! without parity that check out get a parity block, so parity can be turned on for a
+//! server that already has data.
+
+use std::ops::Range;
+
+use bytes::Bytes;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+use tumulus::ExtentKey;
+
+use crate::B3Id;
+use crate::parity::{ExtentParity, ParityCheck, ParityScheme};
+use crate::storage::{Storage, StorageError};
+
+/// Error type for scrubs.
+#[derive(Debug, Error)]
+pub enum ScrubError {
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// How to scrub.
+#[derive(Debug, Clone, Default)]
+pub struct ScrubOptions {
+    /// Write repaired extents and parity blocks back to storage.
+    pub repair: bool,
+    /// Add parity blocks with this scheme to intact extents that don't have one.
+    pub parity: Option<ParityScheme>,
+    /// Keys to check keyed extent IDs with.
+    pub extent_keys: Vec<ExtentKey>,
+}
+
+/// An extent with damage that couldn't be repaired.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DamagedExtent {
+    pub id: B3Id,
+    /// Byte ranges of the extent that are lost.
+    pub lost: Vec<Range<u64>>,
+}
+
+/// What a scrub found and did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ScrubReport {
+    /// How many extents were checked
+    pub checked: usize,
+    /// Bytes of extent data read
+    pub bytes: u64,
+    /// Extents that were damaged and have been rebuilt from their parity (or would be,
+    /// without repairing)
+    pub repaired: Vec<B3Id>,
+    /// How many damaged parity blocks were computed again
+    pub parity_rebuilt: usize,
+    /// How many extents were given a parity block
+    pub parity_added: usize,
+    /// How many extents have no parity block, and weren't given one
+    pub unprotected: usize,
+    /// Extents with damage that couldn't be repaired
+    pub damaged: Vec<DamagedExtent>,
+}
+
+/// Check every extent in storage, repairing those that can be if `options.repair` is set.
+pub async fn scrub<S: Storage>(
+    storage: &S,
+    options: &ScrubOptions,
+) -> Result<ScrubReport, ScrubError> {
+    let mut report = ScrubReport::default();
+    for id in storage.list_extents().await? {
+        let data = match storage.get_extent_bytes(&id).await {
+            Ok(data) => data,
+            // Deleted since it was listed
+            Err(StorageError::NotFound) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        report.checked += 1;
+        report.bytes += data.len() as u64;
+
+        let parity = match storage.get_parity(&id).await {
+            Ok(parity) => match ExtentParity::decode(&parity) {
+                Ok(parity) => Some(parity),
+                Err(err) => {
+                    warn!(extent = %id.as_hex(), %err, "Unreadable parity block, ignoring it");
+                    None
+                }
+            },
+            Err(StorageError::NotFound) => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let Some(parity) = parity else {
+            if !matches_id(&id, &data, &options.extent_keys) {
+                warn!(extent = %id.as_hex(), "Extent is damaged, and has no parity to repair it");
+                report.damaged.push(DamagedExtent {
+                    id,
+                    lost: std::iter::once(0..data.len() as u64).collect(),
+                });
+            } else if let Some(scheme) = options.parity {
+                if options.repair {
+                    store_parity(storage, &id, scheme, &data).await?;
+                }
+                report.parity_added += 1;
+            } else {
+                report.unprotected += 1;
+            }
+            continue;
+        };
+
+        match parity.check(&data) {
+            ParityCheck::Intact => {}
+            ParityCheck::ParityDamaged => {
+                warn!(extent = %id.as_hex(), "Parity block is damaged");
+                if options.repair {
+                    store_parity(storage, &id, parity.scheme, &data).await?;
+                }
+                report.parity_rebuilt += 1;
+            }
+            ParityCheck::Repaired { data, shards } => {
+                warn!(extent = %id.as_hex(), shards, "Extent is damaged, repairing it from parity");
+                if options.repair {
+                    storage.replace_extent(&id, Bytes::from(data)).await?;
+                    // Parity shards may have been damaged too
+                    let data = storage.get_extent_bytes(&id).await?;
+                    store_parity(storage, &id, parity.scheme, &data).await?;
+                }
+                report.repaired.push(id);
+            }
+            ParityCheck::Lost { ranges } => {
+                warn!(extent = %id.as_hex(), ?ranges, "Extent is damaged beyond repair");
+                report.damaged.push(DamagedExtent { id, lost: ranges });
+            }
+        }
+    }
+
+    info!(
+        checked = report.checked,
+        bytes = report.bytes,
+        repaired = report.repaired.len(),
+        damaged = report.damaged.len(),
+        "Scrubbed extents"
+    );
+    Ok(report)
+}
+
+/// Whether extent data hashes to its ID, plain or with any of the keys.
+fn matches_id(id: &B3Id, data: &[u8], keys: &[ExtentKey]) -> bool {
+    B3Id::hash(data) == *id || keys.iter().any(|key| key.hash(data) == *id)
+}
+
+/// Compute and store the parity block of an extent.
+async fn store_parity<S: Storage>(
+    storage: &S,
+    id: &B3Id,
+    scheme: ParityScheme,
+    data: &[u8],
+) -> Result<(), ScrubError> {
+    let parity = ExtentParity::compute(scheme, data)
+        .map_err(|err| StorageError::InvalidData(err.to_string()))?;
+    storage.put_parity(id, parity.encode()).await?;
+    debug!(extent = %id.as_hex(), %scheme, "Stored extent parity");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::storage::{ExtentCheck, FsStorage};
+
+    async fn store(storage: &FsStorage, data: &[u8]) -> B3Id {
+        let id = B3Id::hash(data);
+        storage
+            .put_extent(
+                &id,
+                Box::new(Cursor::new(data.to_vec())),
+                None,
+                ExtentCheck::Hash,
+            )
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn repairs_and_reports_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        storage.init().await.unwrap();
+
+        let data: Vec<u8> = (0..8_000u32).map(|i| (i % 253) as u8).collect();
+        let protected = store(&storage, &data).await;
+        let unprotected = store(&storage, b"no parity here").await;
+
+        // Turning parity on adds it to the intact extents
+        let options = ScrubOptions {
+            repair: true,
+            parity: Some(ParityScheme::default()),
+            extent_keys: Vec::new(),
+        };
+        let report = scrub(&storage, &options).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.parity_added, 2);
+        assert!(report.damaged.is_empty());
+
+        // One damaged shard is repaired
+        let mut damaged = data.clone();
+        damaged[100] ^= 0xff;
+        storage
+            .replace_extent(&protected, Bytes::from(damaged.clone()))
+            .await
+            .unwrap();
+        let report = scrub(&storage, &options).await.unwrap();
+        assert_eq!(report.repaired, vec![protected]);
+        assert_eq!(
+            storage.get_extent_bytes(&protected).await.unwrap(),
+            Bytes::from(data.clone())
+        );
+
+        // Three aren't, and the lost ranges are reported; as is damage without parity
+        for offset in [1_100, 2_100] {
+            damaged[offset] ^= 0xff;
+        }
+        storage
+            .replace_extent(&protected, Bytes::from(damaged))
+            .await
+            .unwrap();
+        storage.delete_extent(&unprotected).await.unwrap();
+        storage
+            .replace_extent(&unprotected, Bytes::from_static(b"no parity HERE"))
+            .await
+            .unwrap();
+        let report = scrub(&storage, &options).await.unwrap();
+        assert!(report.repaired.is_empty());
+        let mut lost: Vec<_> = report.damaged.iter().map(|d| d.lost.clone()).collect();
+        lost.sort_by_key(|ranges| ranges[0].end);
+        assert_eq!(lost, vec![vec![0..14], vec![0..3_000]]);
+    }
+}
@@ -0,0 +1,250 @@
+//! Running the server from another program.
+//!
+//! The `tumulus-server` binary is a thin wrapper around [`Server::builder()`], which can
+//! equally be used to embed a server in a larger application: either started on its own
+//! listener with [`ServerBuilder::start`], which returns a [`ServerHandle`] to shut it down
+//! gracefully, or as a [`Router`] to mount alongside other routes with
+//! [`ServerBuilder::router`].
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use axum::Router;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::api::{ApiOptions, AuthToken, router_with_options};
+use crate::consistency::{ConsistencyError, check_consistency, expire_sessions};
+use crate::db::{DbError, UploadDb};
+use crate::oplog::{OpLog, OpLogError};
+use crate::rebuild::{RebuildError, backfill_references};
+use crate::scratch::Scratch;
+use crate::storage::{FsStorage, Storage, StorageError};
+
+/// Errors from setting up or running a server.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("No storage configured")]
+    MissingStorage,
+
+    #[error("Operation log error: {0}")]
+    OpLog(#[from] OpLogError),
+
+    #[error("No upload database path configured")]
+    MissingDbPath,
+
+    #[error("Consistency check failed: {0}")]
+    Consistency(#[from] ConsistencyError),
+
+    #[error("Backfill failed: {0}")]
+    Backfill(#[from] RebuildError),
+}
+
+/// Entry point to configuring a server.
+pub struct Server;
+
+impl Server {
+    /// Configure a server, by default on filesystem storage.
+    pub fn builder() -> ServerBuilder<FsStorage> {
+        ServerBuilder {
+            storage: None,
+            storage_path: None,
+            db_path: None,
+            oplog_path: None,
+            options: ApiOptions::default(),
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+        }
+    }
+}
+
+/// Configuration for a server, from [`Server::builder()`].
+pub struct ServerBuilder<S: Storage> {
+    storage: Option<S>,
+    storage_path: Option<PathBuf>,
+    db_path: Option<PathBuf>,
+    oplog_path: Option<PathBuf>,
+    options: ApiOptions,
+    listen: SocketAddr,
+}
+
+impl ServerBuilder<FsStorage> {
+    /// Store data in a directory, which is created if needed.
+    ///
+    /// Unless set otherwise, the upload database is `uploads.db` and scratch files go in
+    /// `scratch/`, both in that directory.
+    pub fn fs_storage(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        self.storage = Some(FsStorage::new(&path));
+        self.storage_path = Some(path);
+        self
+    }
+}
+
+impl<S: Storage> ServerBuilder<S> {
+    /// Store data in another backend, which must already be initialised.
+    pub fn storage<T: Storage>(self, storage: T) -> ServerBuilder<T> {
+        ServerBuilder {
+            storage: Some(storage),
+            storage_path: None,
+            db_path: self.db_path,
+            oplog_path: self.oplog_path,
+            options: self.options,
+            listen: self.listen,
+        }
+    }
+
+    /// Where to keep the upload tracking database.
+    pub fn db_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.db_path = Some(path.into());
+        self
+    }
+
+    /// Log every change to the upload database to this file (see [`crate::oplog`]).
+    pub fn oplog_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.oplog_path = Some(path.into());
+        self
+    }
+
+    /// Tunables for the API handlers.
+    pub fn options(mut self, options: ApiOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Require clients to present this bearer token.
+    pub fn auth_token(mut self, token: &str) -> Self {
+        self.options.auth = Some(AuthToken::new(token));
+        self
+    }
+
+    /// Address to listen on when started (by default `127.0.0.1:3000`).
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listen = addr;
+        self
+    }
+
+    /// Set up storage, the upload database, and scratch space, and build the API router.
+    ///
+    /// Scratch files left behind by a previous run are deleted, so only do this once per
+    /// scratch directory. The upload database is checked against storage (see
+    /// [`crate::consistency`]): discrepancies are logged, and stale upload sessions expired.
+    pub async fn router(mut self) -> Result<Router, ServerError> {
+        let storage = self.storage.take().ok_or(ServerError::MissingStorage)?;
+        if let Some(path) = &self.storage_path {
+            FsStorage::new(path).init().await?;
+        }
+
+        let db_path = self
+            .db_path
+            .or_else(|| self.storage_path.as_ref().map(|p| p.join("uploads.db")))
+            .ok_or(ServerError::MissingDbPath)?;
+        let mut db = UploadDb::open(&db_path)?;
+        info!(db_path = ?db_path, "Initialized upload tracking database");
+        if let Some(path) = &self.oplog_path {
+            let oplog = OpLog::open(path)?;
+            info!(oplog_path = ?path, last_seq = oplog.last_seq(), "Logging database changes");
+            db = db.with_oplog(oplog);
+        }
+
+        let db = Mutex::new(db);
+        let report = check_consistency(&storage, &db, self.options.session_ttl).await?;
+        for id in &report.missing {
+            warn!(catalog_id = %id, "Catalog in upload database is missing from storage");
+        }
+        for id in &report.untracked {
+            warn!(catalog_id = %id, "Catalog in storage is missing from upload database");
+        }
+        let expired = expire_sessions(&db, &report.stale)?;
+        if expired > 0 {
+            info!(expired, "Expired stale upload sessions");
+        }
+        // Before anything can go by which extents catalogs reference
+        backfill_references(&storage, &db).await?;
+        let db = db.into_inner().unwrap();
+
+        // Clean up scratch files left over if the server was killed mid-request
+        let mut options = self.options;
+        if options.scratch_dir.is_none() {
+            options.scratch_dir = self.storage_path.as_ref().map(|p| p.join("scratch"));
+        }
+        let removed = Scratch::new(options.scratch_dir.clone(), None).clean_stragglers()?;
+        if removed > 0 {
+            info!(removed, scratch_dir = ?options.scratch_dir, "Removed leftover scratch files");
+        }
+
+        Ok(router_with_options(storage, db, options))
+    }
+
+    /// Set up the server and start serving in the background.
+    pub async fn start(self) -> Result<ServerHandle, ServerError> {
+        let listen = self.listen;
+        let app = self.router().await?;
+
+        let listener = TcpListener::bind(listen).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Listening on {}", local_addr);
+
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+                .into_future(),
+        );
+
+        Ok(ServerHandle {
+            local_addr,
+            shutdown,
+            task,
+        })
+    }
+}
+
+/// A running server, from [`ServerBuilder::start`].
+///
+/// Dropping the handle leaves the server running.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<std::io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Token which shuts the server down when cancelled.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Stop accepting connections, and wait for in-flight requests to finish.
+    pub async fn shutdown(self) -> Result<(), ServerError> {
+        self.shutdown.cancel();
+        self.wait().await
+    }
+
+    /// Wait for the server to stop, after its shutdown token is cancelled.
+    pub async fn wait(self) -> Result<(), ServerError> {
+        match self.task.await {
+            Ok(result) => Ok(result?),
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => Err(std::io::Error::other(err).into()),
+        }
+    }
+}
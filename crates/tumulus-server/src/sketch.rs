@@ -0,0 +1,240 @@
+//! Similarity sketches of extent contents.
+//!
+//! When an extent is uploaded, its contents are summarised in two small sketches so that
+//! tooling can look at how similar extents are to each other without downloading them:
+//!
+//! - a bottom-k MinHash: the [`MINHASH_SIZE`] smallest distinct hashes of the extent's
+//!   [`SHINGLE_SIZE`]-byte shingles, from which the Jaccard similarity of two extents'
+//!   shingle sets can be estimated;
+//! - a 64-bit SimHash over a sample of the same shingles, where similar extents have
+//!   hashes a small Hamming distance apart.
+//!
+//! Shingles are hashed with a rolling buzhash, so sketching is a single pass over the data.
+
+use std::collections::BTreeSet;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const SKETCH_VERSION: u8 = 0x01;
+
+/// How many hashes the MinHash keeps.
+pub const MINHASH_SIZE: usize = 128;
+
+/// Length of the shingles (overlapping byte windows) that are hashed.
+pub const SHINGLE_SIZE: usize = 32;
+
+/// One in this many shingles (by hash) is a SimHash feature.
+const SIMHASH_SAMPLING: u64 = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SketchDecodeError {
+    #[error("Invalid version: {0}")]
+    InvalidVersion(u8),
+    #[error("Truncated data")]
+    Truncated,
+}
+
+/// Similarity sketches of an extent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentSketch {
+    /// The smallest shingle hashes, in ascending order. Extents shorter than a shingle, or
+    /// with few distinct shingles, have fewer than [`MINHASH_SIZE`].
+    pub minhash: Vec<u64>,
+    /// The SimHash of the sampled shingles.
+    pub simhash: u64,
+}
+
+impl ExtentSketch {
+    /// Header size in bytes
+    const HEADER_SIZE: usize = 1 + 8 + 2;
+
+    /// Sketch a complete extent.
+    pub fn of(data: &[u8]) -> Self {
+        let mut sketcher = Sketcher::default();
+        sketcher.update(data);
+        sketcher.finish()
+    }
+
+    /// Encode to binary format.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(Self::HEADER_SIZE + self.minhash.len() * 8);
+        buf.put_u8(SKETCH_VERSION);
+        buf.put_u64_le(self.simhash);
+        buf.put_u16_le(self.minhash.len() as u16);
+        for hash in &self.minhash {
+            buf.put_u64_le(*hash);
+        }
+        buf.freeze()
+    }
+
+    /// Decode from binary format.
+    pub fn decode(mut data: &[u8]) -> Result<Self, SketchDecodeError> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(SketchDecodeError::Truncated);
+        }
+        let version = data.get_u8();
+        if version != SKETCH_VERSION {
+            return Err(SketchDecodeError::InvalidVersion(version));
+        }
+        let simhash = data.get_u64_le();
+        let count = data.get_u16_le() as usize;
+        if data.len() < count * 8 {
+            return Err(SketchDecodeError::Truncated);
+        }
+        let minhash = (0..count).map(|_| data.get_u64_le()).collect();
+        Ok(Self { minhash, simhash })
+    }
+
+    /// Estimate the Jaccard similarity of the shingle sets of two extents (0 to 1).
+    pub fn similarity(&self, other: &Self) -> f64 {
+        let a: BTreeSet<u64> = self.minhash.iter().copied().collect();
+        let b: BTreeSet<u64> = other.minhash.iter().copied().collect();
+        let union: Vec<u64> = a.union(&b).copied().take(MINHASH_SIZE).collect();
+        if union.is_empty() {
+            return 1.0;
+        }
+        let shared = union
+            .iter()
+            .filter(|h| a.contains(h) && b.contains(h))
+            .count();
+        shared as f64 / union.len() as f64
+    }
+}
+
+/// Builds an [`ExtentSketch`] from data given in pieces.
+#[derive(Debug, Clone)]
+pub struct Sketcher {
+    window: [u8; SHINGLE_SIZE],
+    seen: usize,
+    hash: u64,
+    minhash: BTreeSet<u64>,
+    simhash: [i64; 64],
+}
+
+impl Default for Sketcher {
+    fn default() -> Self {
+        Self {
+            window: [0; SHINGLE_SIZE],
+            seen: 0,
+            hash: 0,
+            minhash: BTreeSet::new(),
+            simhash: [0; 64],
+        }
+    }
+}
+
+impl Sketcher {
+    /// Add the next piece of data.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            let slot = self.seen % SHINGLE_SIZE;
+            let out = self.window[slot];
+            self.window[slot] = byte;
+            self.seen += 1;
+
+            self.hash = self.hash.rotate_left(1) ^ BUZHASH[byte as usize];
+            if self.seen > SHINGLE_SIZE {
+                self.hash ^= BUZHASH[out as usize].rotate_left(SHINGLE_SIZE as u32);
+            }
+            if self.seen >= SHINGLE_SIZE {
+                self.add_shingle(mix(self.hash));
+            }
+        }
+    }
+
+    fn add_shingle(&mut self, hash: u64) {
+        if self.minhash.len() < MINHASH_SIZE {
+            self.minhash.insert(hash);
+        } else if self.minhash.last().is_some_and(|&max| hash < max) && self.minhash.insert(hash) {
+            self.minhash.pop_last();
+        }
+
+        if hash.is_multiple_of(SIMHASH_SAMPLING) {
+            let feature = mix(hash);
+            for (bit, weight) in self.simhash.iter_mut().enumerate() {
+                *weight += if feature >> bit & 1 == 1 { 1 } else { -1 };
+            }
+        }
+    }
+
+    /// Finish sketching.
+    pub fn finish(self) -> ExtentSketch {
+        let simhash = self
+            .simhash
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |hash, (bit, _)| hash | 1 << bit);
+        ExtentSketch {
+            minhash: self.minhash.into_iter().collect(),
+            simhash,
+        }
+    }
+}
+
+/// Spread the bits of a hash (the splitmix64 finaliser).
+const fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// The buzhash table: a fixed pseudo-random value per byte, so sketches are comparable
+/// across servers and versions.
+static BUZHASH: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = mix(0x9e3779b97f4a7c15u64.wrapping_mul(i as u64 + 1));
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        (0..len as u64).map(|i| mix(seed ^ i) as u8).collect()
+    }
+
+    #[test]
+    fn streaming_matches_whole() {
+        let data = pseudo_random(10_000, 1);
+        let mut sketcher = Sketcher::default();
+        for chunk in data.chunks(333) {
+            sketcher.update(chunk);
+        }
+        assert_eq!(sketcher.finish(), ExtentSketch::of(&data));
+    }
+
+    #[test]
+    fn similar_data_has_similar_sketches() {
+        let data = pseudo_random(64 * 1024, 1);
+        let mut edited = data.clone();
+        edited[1000..1100].copy_from_slice(&pseudo_random(100, 2));
+        let other = pseudo_random(64 * 1024, 3);
+
+        let sketch = ExtentSketch::of(&data);
+        let edited = ExtentSketch::of(&edited);
+        let other = ExtentSketch::of(&other);
+
+        assert_eq!(sketch.minhash.len(), MINHASH_SIZE);
+        assert!(sketch.similarity(&edited) > 0.9);
+        assert!(sketch.similarity(&other) < 0.1);
+        assert!((sketch.simhash ^ edited.simhash).count_ones() < 8);
+        assert!((sketch.simhash ^ other.simhash).count_ones() > 16);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let sketch = ExtentSketch::of(&pseudo_random(1000, 1));
+        assert_eq!(ExtentSketch::decode(&sketch.encode()).unwrap(), sketch);
+
+        let short = ExtentSketch::of(b"tiny");
+        assert!(short.minhash.is_empty());
+        assert_eq!(ExtentSketch::decode(&short.encode()).unwrap(), short);
+        assert!(ExtentSketch::decode(&[SKETCH_VERSION]).is_err());
+    }
+}
@@ -5,9 +5,11 @@ use tokio::io::AsyncRead;
 use uuid::Uuid;
 
 mod fs;
+mod traced;
 mod types;
 
 pub use fs::FsStorage;
+pub use traced::TracedStorage;
 pub use types::{ObjectMeta, StorageError};
 
 use crate::B3Id;
@@ -18,19 +20,56 @@ pub type ByteStream = Box<dyn Stream<Item = Result<Bytes, StorageError>> + Send
 /// A boxed async reader for streaming writes
 pub type ByteReader = Box<dyn AsyncRead + Send + Unpin>;
 
+/// A boxed stream of catalog IDs, in no particular order
+pub type CatalogStream = Box<dyn Stream<Item = Result<Uuid, StorageError>> + Send + Unpin>;
+
+/// A page of catalog IDs, from [`Storage::list_catalogs_page`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CatalogPage {
+    /// IDs in order.
+    pub ids: Vec<Uuid>,
+    /// Where the next page starts, if there are more catalogs.
+    pub next: Option<Uuid>,
+}
+
+/// How extent data is checked against its ID when stored.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ExtentCheck {
+    /// The ID is the BLAKE3 hash of the data.
+    #[default]
+    Hash,
+    /// The ID is the BLAKE3 hash of the data keyed with this key.
+    Keyed([u8; 32]),
+    /// The ID is taken as given, for keyed IDs whose key isn't known.
+    Trusted,
+}
+
+impl ExtentCheck {
+    /// A hasher to compute the ID with, unless it's trusted.
+    pub fn hasher(&self) -> Option<blake3::Hasher> {
+        match self {
+            Self::Hash => Some(blake3::Hasher::new()),
+            Self::Keyed(key) => Some(blake3::Hasher::new_keyed(key)),
+            Self::Trusted => None,
+        }
+    }
+}
+
 #[async_trait]
 pub trait Storage: Send + Sync + 'static {
     // --- Extents ---
 
     /// Store extent data from a stream.
     /// Returns Ok(true) if newly stored, Ok(false) if already existed.
-    /// MUST verify that BLAKE3(data) == id, return HashMismatch if not.
+    /// MUST verify that the data hashes to the id as the `check` says, and return
+    /// HashMismatch if not.
     /// The `size_hint` is optional but helps with pre-allocation.
     async fn put_extent(
         &self,
         id: &B3Id,
         data: ByteReader,
         size_hint: Option<u64>,
+        check: ExtentCheck,
     ) -> Result<bool, StorageError>;
 
     /// Get extent data as a stream.
@@ -50,6 +89,24 @@ pub trait Storage: Send + Sync + 'static {
         Ok(Bytes::from(total))
     }
 
+    /// Get `length` bytes of extent data from `offset` as a stream.
+    /// Returns InvalidData if the range isn't within the extent.
+    /// Default implementation slices the whole extent.
+    async fn get_extent_range(
+        &self,
+        id: &B3Id,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        let data = self.get_extent_bytes(id).await?;
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= data.len() as u64)
+            .ok_or_else(|| StorageError::InvalidData("range past the end of extent".into()))?;
+        let part = data.slice(offset as usize..end as usize);
+        Ok(Box::new(futures::stream::iter([Ok(part)])))
+    }
+
     /// Check if extent exists.
     async fn extent_exists(&self, id: &B3Id) -> Result<bool, StorageError>;
 
@@ -60,6 +117,39 @@ pub trait Storage: Send + Sync + 'static {
     /// Get extent metadata without fetching data.
     async fn extent_meta(&self, id: &B3Id) -> Result<ObjectMeta, StorageError>;
 
+    /// List all extent IDs.
+    async fn list_extents(&self) -> Result<Vec<B3Id>, StorageError>;
+
+    /// Delete an extent, its sketch, and its parity.
+    /// Returns Ok(true) if it was deleted, Ok(false) if it didn't exist.
+    async fn delete_extent(&self, id: &B3Id) -> Result<bool, StorageError>;
+
+    /// Replace the stored data of an extent, as when repairing it. The data isn't checked
+    /// against the ID, so callers must have.
+    async fn replace_extent(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError>;
+
+    /// Store the similarity sketch of an extent (see [`crate::sketch`]), replacing any
+    /// existing one.
+    /// Default implementation doesn't keep sketches, so they're computed when needed.
+    async fn put_sketch(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let _ = (id, data);
+        Ok(())
+    }
+
+    /// Get the similarity sketch of an extent.
+    /// Default implementation has none, as it doesn't keep them.
+    async fn get_sketch(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let _ = id;
+        Err(StorageError::NotFound)
+    }
+
+    /// Store the parity block of an extent (see [`crate::parity`]), replacing any existing
+    /// one.
+    async fn put_parity(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError>;
+
+    /// Get the parity block of an extent.
+    async fn get_parity(&self, id: &B3Id) -> Result<Bytes, StorageError>;
+
     // --- Blobs ---
 
     /// Store blob layout data.
@@ -91,4 +181,43 @@ pub trait Storage: Send + Sync + 'static {
 
     /// List all catalog IDs.
     async fn list_catalogs(&self) -> Result<Vec<Uuid>, StorageError>;
+
+    /// Stream all catalog IDs, in no particular order.
+    /// Default implementation streams the list from `list_catalogs`.
+    async fn stream_catalogs(&self) -> Result<CatalogStream, StorageError> {
+        let ids = self.list_catalogs().await?;
+        Ok(Box::new(futures::stream::iter(ids.into_iter().map(Ok))))
+    }
+
+    /// List up to `limit` (at least one) catalog IDs in order, starting after `after`.
+    /// Default implementation keeps the lowest IDs from `stream_catalogs`, so only a page
+    /// of IDs is held at once.
+    async fn list_catalogs_page(
+        &self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<CatalogPage, StorageError> {
+        use futures::TryStreamExt;
+        let limit = limit.max(1);
+        let mut stream = self.stream_catalogs().await?;
+
+        // The lowest limit + 1 IDs, to know whether there's a next page
+        let mut lowest = std::collections::BinaryHeap::new();
+        while let Some(id) = stream.try_next().await? {
+            if after.is_some_and(|after| id <= after) {
+                continue;
+            }
+            lowest.push(id);
+            if lowest.len() > limit.saturating_add(1) {
+                lowest.pop();
+            }
+        }
+
+        let mut ids = lowest.into_sorted_vec();
+        let next = (ids.len() > limit).then(|| {
+            ids.truncate(limit);
+            ids[limit - 1]
+        });
+        Ok(CatalogPage { ids, next })
+    }
 }
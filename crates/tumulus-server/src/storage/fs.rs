@@ -2,15 +2,17 @@ use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt, stream};
 use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
 use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
 use crate::B3Id;
 
-use super::{ByteReader, ByteStream, ObjectMeta, Storage, StorageError};
+use super::{
+    ByteReader, ByteStream, CatalogStream, ExtentCheck, ObjectMeta, Storage, StorageError,
+};
 
 pub struct FsStorage {
     base_path: PathBuf,
@@ -28,6 +30,8 @@ impl FsStorage {
         fs::create_dir_all(self.base_path.join("extents")).await?;
         fs::create_dir_all(self.base_path.join("blobs")).await?;
         fs::create_dir_all(self.base_path.join("catalogs")).await?;
+        fs::create_dir_all(self.base_path.join("sketches")).await?;
+        fs::create_dir_all(self.base_path.join("parity")).await?;
         Ok(())
     }
 
@@ -69,6 +73,7 @@ impl Storage for FsStorage {
         id: &B3Id,
         mut data: ByteReader,
         size_hint: Option<u64>,
+        check: ExtentCheck,
     ) -> Result<bool, StorageError> {
         let path = self.sharded_path("extents", id);
 
@@ -87,7 +92,7 @@ impl Storage for FsStorage {
         let temp_path = temp.path().to_path_buf();
 
         let mut file = File::create(&temp_path).await?;
-        let mut hasher = blake3::Hasher::new();
+        let mut hasher = check.hasher();
 
         // Pre-allocate buffer based on size hint
         let buf_size = size_hint
@@ -100,7 +105,9 @@ impl Storage for FsStorage {
             if n == 0 {
                 break;
             }
-            hasher.update(&buf[..n]);
+            if let Some(ref mut hasher) = hasher {
+                hasher.update(&buf[..n]);
+            }
             file.write_all(&buf[..n]).await?;
         }
 
@@ -108,8 +115,9 @@ impl Storage for FsStorage {
         drop(file);
 
         // Verify hash
-        let actual = hasher.finalize();
-        if actual != id.0 {
+        if let Some(actual) = hasher.map(|hasher| hasher.finalize())
+            && actual != id.0
+        {
             // Clean up temp file
             let _ = fs::remove_file(&temp_path).await;
             return Err(StorageError::HashMismatch {
@@ -144,6 +152,36 @@ impl Storage for FsStorage {
         Ok(Box::new(mapped))
     }
 
+    async fn get_extent_range(
+        &self,
+        id: &B3Id,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        let path = self.sharded_path("extents", id);
+
+        let mut file = File::open(&path).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                StorageError::NotFound
+            } else {
+                StorageError::Io(e)
+            }
+        })?;
+        let size = file.metadata().await?.len();
+        if offset.checked_add(length).is_none_or(|end| end > size) {
+            return Err(StorageError::InvalidData(
+                "range past the end of extent".into(),
+            ));
+        }
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let reader = BufReader::with_capacity(64 * 1024, file.take(length));
+        let stream = ReaderStream::new(reader);
+        let mapped = stream.map(|result| result.map_err(StorageError::Io));
+
+        Ok(Box::new(mapped))
+    }
+
     async fn extent_exists(&self, id: &B3Id) -> Result<bool, StorageError> {
         let path = self.sharded_path("extents", id);
         Ok(fs::try_exists(&path).await.unwrap_or(false))
@@ -167,12 +205,115 @@ impl Storage for FsStorage {
             }
         })?;
 
+        // Extents are never rewritten, so the mtime stands in where birth time isn't recorded
         Ok(ObjectMeta {
             size: metadata.len(),
-            created: metadata.created().ok(),
+            created: metadata.created().or_else(|_| metadata.modified()).ok(),
         })
     }
 
+    async fn list_extents(&self) -> Result<Vec<B3Id>, StorageError> {
+        let extents_dir = self.base_path.join("extents");
+
+        // If directory doesn't exist, return empty list
+        if !fs::try_exists(&extents_dir).await.unwrap_or(false) {
+            return Ok(Vec::new());
+        }
+
+        // Walk the two shard levels, then reassemble the hex ID from the path components
+        let mut ids = Vec::new();
+        let mut first_level = fs::read_dir(&extents_dir).await?;
+        while let Some(first) = first_level.next_entry().await? {
+            if !first.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let mut second_level = fs::read_dir(first.path()).await?;
+            while let Some(second) = second_level.next_entry().await? {
+                if !second.file_type().await?.is_dir() {
+                    continue;
+                }
+
+                let mut entries = fs::read_dir(second.path()).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let hex = format!(
+                        "{}{}{}",
+                        first.file_name().to_string_lossy(),
+                        second.file_name().to_string_lossy(),
+                        entry.file_name().to_string_lossy()
+                    );
+
+                    // Skips leftover tempfiles and anything else that isn't an extent
+                    if let Ok(bytes) = hex::decode(&hex)
+                        && let Ok(id) = B3Id::try_from(bytes)
+                    {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    async fn delete_extent(&self, id: &B3Id) -> Result<bool, StorageError> {
+        let deleted = match fs::remove_file(self.sharded_path("extents", id)).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        for prefix in ["sketches", "parity"] {
+            match fs::remove_file(self.sharded_path(prefix, id)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn replace_extent(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let path = self.sharded_path("extents", id);
+        self.atomic_write(&path, &data).await?;
+        Ok(())
+    }
+
+    async fn put_sketch(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let path = self.sharded_path("sketches", id);
+        self.atomic_write(&path, &data).await?;
+        Ok(())
+    }
+
+    async fn get_sketch(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let path = self.sharded_path("sketches", id);
+        let data = fs::read(&path).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                StorageError::NotFound
+            } else {
+                StorageError::Io(e)
+            }
+        })?;
+        Ok(Bytes::from(data))
+    }
+
+    async fn put_parity(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let path = self.sharded_path("parity", id);
+        self.atomic_write(&path, &data).await?;
+        Ok(())
+    }
+
+    async fn get_parity(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let path = self.sharded_path("parity", id);
+        let data = fs::read(&path).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                StorageError::NotFound
+            } else {
+                StorageError::Io(e)
+            }
+        })?;
+        Ok(Bytes::from(data))
+    }
+
     async fn put_blob(&self, id: &B3Id, data: Bytes) -> Result<bool, StorageError> {
         let path = self.sharded_path("blobs", id);
 
@@ -258,24 +399,32 @@ impl Storage for FsStorage {
     }
 
     async fn list_catalogs(&self) -> Result<Vec<Uuid>, StorageError> {
-        let catalogs_dir = self.base_path.join("catalogs");
-
-        // If directory doesn't exist, return empty list
-        if !fs::try_exists(&catalogs_dir).await.unwrap_or(false) {
-            return Ok(Vec::new());
-        }
+        self.stream_catalogs().await?.try_collect().await
+    }
 
-        let mut entries = fs::read_dir(&catalogs_dir).await?;
-        let mut ids = Vec::new();
+    async fn stream_catalogs(&self) -> Result<CatalogStream, StorageError> {
+        let catalogs_dir = self.base_path.join("catalogs");
 
-        while let Some(entry) = entries.next_entry().await? {
-            if let Some(name) = entry.file_name().to_str()
-                && let Ok(uuid) = Uuid::parse_str(name)
-            {
-                ids.push(uuid);
+        // If directory doesn't exist, there's nothing to list
+        let entries = match fs::read_dir(&catalogs_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Box::new(stream::empty()));
             }
-        }
-
-        Ok(ids)
+            Err(e) => return Err(e.into()),
+        };
+
+        // Read entries as the stream is polled, skipping temporary files
+        let ids = stream::try_unfold(entries, |mut entries| async move {
+            while let Some(entry) = entries.next_entry().await? {
+                if let Some(name) = entry.file_name().to_str()
+                    && let Ok(uuid) = Uuid::parse_str(name)
+                {
+                    return Ok(Some((uuid, entries)));
+                }
+            }
+            Ok::<_, StorageError>(None)
+        });
+        Ok(Box::new(Box::pin(ids)))
     }
 }
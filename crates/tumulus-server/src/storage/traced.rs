@@ -0,0 +1,244 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::{Instrument, Span, debug_span, field};
+use uuid::Uuid;
+
+use crate::B3Id;
+
+use super::{
+    ByteReader, ByteStream, CatalogPage, CatalogStream, ExtentCheck, ObjectMeta, Storage,
+    StorageError,
+};
+
+/// Storage that runs every call in a tracing span.
+///
+/// Spans are named `storage`, with the operation, the kind of object (`extent`, `sketch`,
+/// `parity`, `blob`, or `catalog`), its ID, and its size once known. They're entered within the span
+/// of the request that made the call, so traces show how long each request spends in
+/// storage, and backends can read the request's trace context with
+/// [`TraceParent::current`](crate::trace::TraceParent::current) to pass it on.
+pub struct TracedStorage<S> {
+    inner: S,
+}
+
+impl<S: Storage> TracedStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    /// The wrapped storage.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// A span for a call on one object.
+fn object_span(op: &'static str, kind: &'static str, id: &dyn std::fmt::Display) -> Span {
+    debug_span!("storage", op, kind, id = %id, size = field::Empty)
+}
+
+/// A span for a call on many objects, or none in particular.
+fn listing_span(op: &'static str, kind: &'static str) -> Span {
+    debug_span!("storage", op, kind, count = field::Empty)
+}
+
+#[async_trait]
+impl<S: Storage> Storage for TracedStorage<S> {
+    async fn put_extent(
+        &self,
+        id: &B3Id,
+        data: ByteReader,
+        size_hint: Option<u64>,
+        check: ExtentCheck,
+    ) -> Result<bool, StorageError> {
+        let span = object_span("put", "extent", id);
+        if let Some(size) = size_hint {
+            span.record("size", size);
+        }
+        self.inner
+            .put_extent(id, data, size_hint, check)
+            .instrument(span)
+            .await
+    }
+
+    async fn get_extent(&self, id: &B3Id) -> Result<ByteStream, StorageError> {
+        self.inner
+            .get_extent(id)
+            .instrument(object_span("get", "extent", id))
+            .await
+    }
+
+    async fn get_extent_bytes(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let span = object_span("get", "extent", id);
+        let data = self
+            .inner
+            .get_extent_bytes(id)
+            .instrument(span.clone())
+            .await?;
+        span.record("size", data.len());
+        Ok(data)
+    }
+
+    async fn get_extent_range(
+        &self,
+        id: &B3Id,
+        offset: u64,
+        length: u64,
+    ) -> Result<ByteStream, StorageError> {
+        let span = object_span("get_range", "extent", id);
+        span.record("size", length);
+        self.inner
+            .get_extent_range(id, offset, length)
+            .instrument(span)
+            .await
+    }
+
+    async fn extent_exists(&self, id: &B3Id) -> Result<bool, StorageError> {
+        self.inner
+            .extent_exists(id)
+            .instrument(object_span("exists", "extent", id))
+            .await
+    }
+
+    async fn extents_exist(&self, ids: &[B3Id]) -> Result<Vec<bool>, StorageError> {
+        let span = listing_span("exists", "extent");
+        span.record("count", ids.len());
+        self.inner.extents_exist(ids).instrument(span).await
+    }
+
+    async fn extent_meta(&self, id: &B3Id) -> Result<ObjectMeta, StorageError> {
+        let span = object_span("meta", "extent", id);
+        let meta = self.inner.extent_meta(id).instrument(span.clone()).await?;
+        span.record("size", meta.size);
+        Ok(meta)
+    }
+
+    async fn list_extents(&self) -> Result<Vec<B3Id>, StorageError> {
+        let span = listing_span("list", "extent");
+        let ids = self.inner.list_extents().instrument(span.clone()).await?;
+        span.record("count", ids.len());
+        Ok(ids)
+    }
+
+    async fn delete_extent(&self, id: &B3Id) -> Result<bool, StorageError> {
+        self.inner
+            .delete_extent(id)
+            .instrument(object_span("delete", "extent", id))
+            .await
+    }
+
+    async fn replace_extent(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let span = object_span("replace", "extent", id);
+        span.record("size", data.len());
+        self.inner.replace_extent(id, data).instrument(span).await
+    }
+
+    async fn put_sketch(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let span = object_span("put", "sketch", id);
+        span.record("size", data.len());
+        self.inner.put_sketch(id, data).instrument(span).await
+    }
+
+    async fn get_sketch(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let span = object_span("get", "sketch", id);
+        let data = self.inner.get_sketch(id).instrument(span.clone()).await?;
+        span.record("size", data.len());
+        Ok(data)
+    }
+
+    async fn put_parity(&self, id: &B3Id, data: Bytes) -> Result<(), StorageError> {
+        let span = object_span("put", "parity", id);
+        span.record("size", data.len());
+        self.inner.put_parity(id, data).instrument(span).await
+    }
+
+    async fn get_parity(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let span = object_span("get", "parity", id);
+        let data = self.inner.get_parity(id).instrument(span.clone()).await?;
+        span.record("size", data.len());
+        Ok(data)
+    }
+
+    async fn put_blob(&self, id: &B3Id, data: Bytes) -> Result<bool, StorageError> {
+        let span = object_span("put", "blob", id);
+        span.record("size", data.len());
+        self.inner.put_blob(id, data).instrument(span).await
+    }
+
+    async fn get_blob(&self, id: &B3Id) -> Result<Bytes, StorageError> {
+        let span = object_span("get", "blob", id);
+        let data = self.inner.get_blob(id).instrument(span.clone()).await?;
+        span.record("size", data.len());
+        Ok(data)
+    }
+
+    async fn blob_exists(&self, id: &B3Id) -> Result<bool, StorageError> {
+        self.inner
+            .blob_exists(id)
+            .instrument(object_span("exists", "blob", id))
+            .await
+    }
+
+    async fn blob_meta(&self, id: &B3Id) -> Result<ObjectMeta, StorageError> {
+        let span = object_span("meta", "blob", id);
+        let meta = self.inner.blob_meta(id).instrument(span.clone()).await?;
+        span.record("size", meta.size);
+        Ok(meta)
+    }
+
+    async fn put_catalog(&self, id: Uuid, data: Bytes) -> Result<(), StorageError> {
+        let span = object_span("put", "catalog", &id.simple());
+        span.record("size", data.len());
+        self.inner.put_catalog(id, data).instrument(span).await
+    }
+
+    async fn get_catalog(&self, id: Uuid) -> Result<Bytes, StorageError> {
+        let span = object_span("get", "catalog", &id.simple());
+        let data = self.inner.get_catalog(id).instrument(span.clone()).await?;
+        span.record("size", data.len());
+        Ok(data)
+    }
+
+    async fn catalog_exists(&self, id: Uuid) -> Result<bool, StorageError> {
+        self.inner
+            .catalog_exists(id)
+            .instrument(object_span("exists", "catalog", &id.simple()))
+            .await
+    }
+
+    async fn catalog_meta(&self, id: Uuid) -> Result<ObjectMeta, StorageError> {
+        let span = object_span("meta", "catalog", &id.simple());
+        let meta = self.inner.catalog_meta(id).instrument(span.clone()).await?;
+        span.record("size", meta.size);
+        Ok(meta)
+    }
+
+    async fn list_catalogs(&self) -> Result<Vec<Uuid>, StorageError> {
+        let span = listing_span("list", "catalog");
+        let ids = self.inner.list_catalogs().instrument(span.clone()).await?;
+        span.record("count", ids.len());
+        Ok(ids)
+    }
+
+    async fn stream_catalogs(&self) -> Result<CatalogStream, StorageError> {
+        self.inner
+            .stream_catalogs()
+            .instrument(listing_span("stream", "catalog"))
+            .await
+    }
+
+    async fn list_catalogs_page(
+        &self,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<CatalogPage, StorageError> {
+        let span = listing_span("list_page", "catalog");
+        let page = self
+            .inner
+            .list_catalogs_page(after, limit)
+            .instrument(span.clone())
+            .await?;
+        span.record("count", page.ids.len());
+        Ok(page)
+    }
+}
@@ -0,0 +1,139 @@
+//! Trace context from incoming requests.
+//!
+//! Clients that trace their uploads send a W3C `traceparent` header. Each request is
+//! handled in a `request` span recording the trace and parent span IDs from it, so the
+//! server's spans (including those of [`TracedStorage`](crate::storage::TracedStorage))
+//! can be tied to the client's trace. The context is also available to storage backends
+//! with [`TraceParent::current`], to pass on to services they call in turn.
+
+use std::fmt;
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::{Instrument, field, info_span};
+
+/// Name of the header carrying the trace context.
+pub const TRACEPARENT: &str = "traceparent";
+
+tokio::task_local! {
+    static CURRENT: Option<TraceParent>;
+}
+
+/// A W3C trace context, as in the `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceParent {
+    /// Parse a `traceparent` header value.
+    ///
+    /// Only version 00 is understood; all-zero IDs are invalid.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let (version, trace_id, parent_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || parts.next().is_some() {
+            return None;
+        }
+
+        let mut parsed = Self {
+            trace_id: [0; 16],
+            parent_id: [0; 8],
+            flags: 0,
+        };
+        hex::decode_to_slice(trace_id, &mut parsed.trace_id).ok()?;
+        hex::decode_to_slice(parent_id, &mut parsed.parent_id).ok()?;
+        let mut flag = [0];
+        hex::decode_to_slice(flags, &mut flag).ok()?;
+        parsed.flags = flag[0];
+
+        if parsed.trace_id == [0; 16] || parsed.parent_id == [0; 8] {
+            return None;
+        }
+        Some(parsed)
+    }
+
+    /// The trace context of the request being handled, if it had one.
+    ///
+    /// Only set on the request's own task: work spawned onto other tasks doesn't see it.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|parent| *parent).ok().flatten()
+    }
+
+    /// Whether the caller is recording the trace.
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.parent_id),
+            self.flags
+        )
+    }
+}
+
+/// Handle a request in a span, with the trace context from its `traceparent` header.
+pub(crate) async fn trace_request(request: Request, next: Next) -> Response {
+    let parent = request
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(TraceParent::parse);
+
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        trace_id = field::Empty,
+        parent_id = field::Empty,
+    );
+    if let Some(parent) = parent {
+        span.record("trace_id", hex::encode(parent.trace_id));
+        span.record("parent_id", hex::encode(parent.parent_id));
+    }
+
+    CURRENT
+        .scope(parent, next.run(request))
+        .instrument(span)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let parent = TraceParent::parse(header).unwrap();
+        assert_eq!(parent.trace_id[0], 0x4b);
+        assert_eq!(parent.parent_id[7], 0xb7);
+        assert!(parent.sampled());
+        assert_eq!(parent.to_string(), header);
+    }
+
+    #[test]
+    fn refuses_bad_traceparents() {
+        for header in [
+            "",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceParent::parse(header), None, "{header}");
+        }
+    }
+}
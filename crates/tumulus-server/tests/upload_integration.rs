@@ -6,21 +6,23 @@
 #![allow(dead_code)]
 
 use std::fs;
-use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use reqwest::blocking::Client;
-use rusqlite::{Connection, params};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::io::Write;
 use tempfile::TempDir;
-use tokio::sync::oneshot;
 use uuid::Uuid;
 
-use tumulus::{B3Id, create_catalog_schema, process_file, write_catalog};
-use tumulus_server::{FsStorage, UploadDb, router};
+use extentria::RangeReaderImpl as _;
+use tumulus::{B3Id, ExtentKey};
+use tumulus_server::{
+    ApiOptions, CatalogStatus, ExtentCheck, ExtentParity, FsStorage, ParityCheck, ParityScheme,
+    Server, Storage, TrustedKeys, UploadDb, backfill_references, find_orphans, rebuild_index,
+};
+use tumulus_testkit::{CatalogFixture, TestServer};
 
 /// Request body for initiating a catalog upload.
 #[derive(Debug, Serialize)]
@@ -64,6 +66,23 @@ struct ErrorResponse {
 #[derive(Debug, Serialize)]
 struct CheckCatalogsRequest {
     ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sketch: Option<Vec<SketchExtent>>,
+}
+
+/// An extent in a catalog sketch.
+#[derive(Debug, Serialize)]
+struct SketchExtent {
+    id: String,
+    bytes: u64,
+}
+
+/// How much of a sketch an existing catalog shares.
+#[derive(Debug, Deserialize)]
+struct SharedSummary {
+    id: String,
+    extents: u64,
+    bytes: u64,
 }
 
 /// Response from checking catalog existence.
@@ -71,222 +90,8 @@ struct CheckCatalogsRequest {
 #[derive(Debug, Deserialize)]
 struct CheckCatalogsResponse {
     existing: Vec<String>,
-}
-
-/// Test server handle that manages the server lifecycle.
-struct TestServer {
-    addr: SocketAddr,
-    shutdown_tx: Option<oneshot::Sender<()>>,
-    #[allow(dead_code)]
-    runtime: Arc<tokio::runtime::Runtime>,
-    _storage_dir: TempDir,
-}
-
-impl TestServer {
-    /// Start a new test server with a temporary storage directory.
-    fn start() -> Self {
-        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
-
-        // Create temporary storage directory
-        let storage_dir = TempDir::new().expect("Failed to create temp storage dir");
-
-        // Initialize storage and database
-        let storage = FsStorage::new(storage_dir.path());
-        runtime.block_on(async {
-            storage.init().await.expect("Failed to init storage");
-        });
-
-        let db_path = storage_dir.path().join("uploads.db");
-        let db = UploadDb::open(&db_path).expect("Failed to open upload db");
-
-        // Build router
-        let app = router(storage, db);
-
-        // Bind to a random available port
-        let listener = runtime.block_on(async {
-            tokio::net::TcpListener::bind("127.0.0.1:0")
-                .await
-                .expect("Failed to bind")
-        });
-        let addr = listener.local_addr().expect("Failed to get local addr");
-
-        // Create shutdown channel
-        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
-
-        // Spawn server in background
-        let rt = Arc::clone(&runtime);
-        std::thread::spawn(move || {
-            rt.block_on(async move {
-                axum::serve(listener, app)
-                    .with_graceful_shutdown(async {
-                        let _ = shutdown_rx.await;
-                    })
-                    .await
-                    .expect("Server error");
-            });
-        });
-
-        // Give server a moment to start
-        std::thread::sleep(std::time::Duration::from_millis(50));
-
-        TestServer {
-            addr,
-            shutdown_tx: Some(shutdown_tx),
-            runtime,
-            _storage_dir: storage_dir,
-        }
-    }
-
-    fn url(&self) -> String {
-        format!("http://{}", self.addr)
-    }
-
-    fn storage_path(&self) -> &Path {
-        self._storage_dir.path()
-    }
-}
-
-impl Drop for TestServer {
-    fn drop(&mut self) {
-        if let Some(tx) = self.shutdown_tx.take() {
-            let _ = tx.send(());
-        }
-    }
-}
-
-/// Test fixture that creates a test source directory and catalog using the tumulus library.
-struct TestFixture {
-    _source_dir: TempDir,
-    _catalog_dir: TempDir,
-    catalog_path: std::path::PathBuf,
-    catalog_id: Uuid,
-    catalog_checksum: String,
-    extent_ids: Vec<String>,
-    /// The file contents used to create this fixture (for extent lookup)
-    file_contents: Vec<(&'static str, &'static str)>,
-}
-
-impl TestFixture {
-    /// Create a new test fixture with some test files.
-    fn new() -> Self {
-        Self::with_files(&[
-            ("file1.txt", "Hello, world!"),
-            ("file2.txt", "This is a test file with some content."),
-            ("subdir/file3.txt", "Nested file content here."),
-        ])
-    }
-
-    /// Create a test fixture with custom file contents.
-    fn with_files(files: &[(&'static str, &'static str)]) -> Self {
-        let source_dir = TempDir::new().expect("Failed to create source dir");
-        let catalog_dir = TempDir::new().expect("Failed to create catalog dir");
-        let catalog_path = catalog_dir.path().join("test.catalog");
-
-        // Create the test files
-        for (path, content) in files {
-            let full_path = source_dir.path().join(path);
-            if let Some(parent) = full_path.parent() {
-                fs::create_dir_all(parent).unwrap();
-            }
-            fs::write(&full_path, content).unwrap();
-        }
-
-        // Create a catalog using the tumulus library
-        let catalog_id = Uuid::new_v4();
-        let conn = Connection::open(&catalog_path).expect("Failed to create catalog db");
-
-        // Initialize schema using tumulus library
-        create_catalog_schema(&conn).expect("Failed to create schema");
-
-        // Insert metadata
-        let machine_id = "test-machine-id";
-        conn.execute(
-            "INSERT INTO metadata (key, value) VALUES ('id', ?)",
-            params![json!(catalog_id.simple().to_string()).to_string()],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO metadata (key, value) VALUES ('machine', ?)",
-            params![json!(machine_id).to_string()],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO metadata (key, value) VALUES ('source_path', ?)",
-            params![json!(source_dir.path().to_string_lossy()).to_string()],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO metadata (key, value) VALUES ('created', ?)",
-            params![
-                json!(
-                    std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as i64
-                )
-                .to_string()
-            ],
-        )
-        .unwrap();
-
-        // Process files using tumulus library
-        let mut file_infos = Vec::new();
-        for (path, _content) in files {
-            let full_path = source_dir.path().join(path);
-            let file_info =
-                process_file(&full_path, source_dir.path()).expect("Failed to process file");
-            file_infos.push(file_info);
-        }
-
-        // Write catalog using tumulus library
-        write_catalog(&conn, &file_infos).expect("Failed to write catalog");
-
-        // Collect extent IDs from the catalog
-        let extent_ids = {
-            let mut stmt = conn
-                .prepare("SELECT DISTINCT extent_id FROM blob_extents WHERE extent_id IS NOT NULL")
-                .unwrap();
-            let rows = stmt
-                .query_map([], |row| {
-                    let extent_id: Vec<u8> = row.get(0)?;
-                    let id = B3Id::try_from(extent_id).expect("Invalid extent ID");
-                    Ok(id.as_hex())
-                })
-                .unwrap();
-            rows.map(|r| r.unwrap()).collect::<Vec<_>>()
-        };
-
-        drop(conn);
-
-        // Compute catalog checksum
-        let catalog_data = fs::read(&catalog_path).expect("Failed to read catalog");
-        let catalog_checksum = blake3::hash(&catalog_data).to_hex().to_string();
-
-        TestFixture {
-            _source_dir: source_dir,
-            _catalog_dir: catalog_dir,
-            catalog_path,
-            catalog_id,
-            catalog_checksum,
-            extent_ids,
-            file_contents: files.to_vec(),
-        }
-    }
-
-    fn catalog_data(&self) -> Vec<u8> {
-        fs::read(&self.catalog_path).expect("Failed to read catalog")
-    }
-
-    /// Find extent data by hash from the source files.
-    fn find_extent_data(&self, extent_id: &str) -> Vec<u8> {
-        for (_path, content) in &self.file_contents {
-            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
-            if hash.to_lowercase() == extent_id.to_lowercase() {
-                return content.as_bytes().to_vec();
-            }
-        }
-        panic!("Extent {} not found in test files", extent_id);
-    }
+    #[serde(default)]
+    shared: Option<Vec<SharedSummary>>,
 }
 
 // ============================================================================
@@ -322,7 +127,7 @@ fn test_initiate_new_catalog() {
 #[test]
 fn test_full_upload_flow() {
     let server = TestServer::start();
-    let fixture = TestFixture::new();
+    let fixture = CatalogFixture::new();
     let client = Client::new();
 
     // Step 1: Initiate upload
@@ -443,7 +248,7 @@ fn test_full_upload_flow() {
 #[test]
 fn test_resume_upload_no_missing_extents() {
     let server = TestServer::start();
-    let fixture = TestFixture::new();
+    let fixture = CatalogFixture::new();
     let client = Client::new();
 
     // Complete a full upload first
@@ -513,10 +318,61 @@ fn test_resume_upload_no_missing_extents() {
     );
 }
 
+#[test]
+fn test_resume_upload_by_checksum() {
+    let server = TestServer::start();
+    let fixture = CatalogFixture::new();
+    let client = Client::new();
+
+    // Initiate and upload catalog but NOT extents
+    client
+        .post(format!("{}/catalogs", server.url()))
+        .json(&InitiateRequest {
+            id: fixture.catalog_id,
+            checksum: fixture.catalog_checksum.clone(),
+        })
+        .send()
+        .expect("Initiate failed");
+    client
+        .put(format!(
+            "{}/catalogs/{}",
+            server.url(),
+            fixture.catalog_id.simple()
+        ))
+        .body(fixture.catalog_data())
+        .send()
+        .expect("Upload failed");
+
+    // Initiate again under a new ID with the same checksum
+    let resp = client
+        .post(format!("{}/catalogs", server.url()))
+        .json(&InitiateRequest {
+            id: Uuid::new_v4(),
+            checksum: fixture.catalog_checksum.clone(),
+        })
+        .send()
+        .expect("Second initiate failed");
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let body: InitiateResponse = resp.json().expect("Failed to parse response");
+    assert_eq!(body.id, fixture.catalog_id.simple().to_string());
+    assert!(body.resuming);
+
+    let mut missing = body.missing_extents.expect("Missing extents not returned");
+    let mut expected: Vec<String> = fixture
+        .extent_ids
+        .iter()
+        .map(|id| id.to_lowercase())
+        .collect();
+    missing.sort();
+    expected.sort();
+    assert_eq!(missing, expected);
+}
+
 #[test]
 fn test_resume_upload_with_missing_extents() {
     let server = TestServer::start();
-    let fixture = TestFixture::new();
+    let fixture = CatalogFixture::new();
     let client = Client::new();
 
     // Need at least 2 extents to test partial upload
@@ -585,6 +441,81 @@ fn test_resume_upload_with_missing_extents() {
     );
 }
 
+#[test]
+fn test_missing_extents_in_batches() {
+    let fixture = CatalogFixture::new();
+    let client = Client::new();
+    assert!(
+        fixture.extent_ids.len() > 2,
+        "Fixture needs several extents"
+    );
+
+    // Upload every other extent, then find the missing ones on resume
+    let missing_with = |options: ApiOptions| {
+        let server = TestServer::start_with_options(options);
+        client
+            .post(format!("{}/catalogs", server.url()))
+            .json(&InitiateRequest {
+                id: fixture.catalog_id,
+                checksum: fixture.catalog_checksum.clone(),
+            })
+            .send()
+            .expect("Initiate failed");
+        client
+            .put(format!(
+                "{}/catalogs/{}",
+                server.url(),
+                fixture.catalog_id.simple()
+            ))
+            .body(fixture.catalog_data())
+            .send()
+            .expect("Upload failed");
+        for extent_id in fixture.extent_ids.iter().step_by(2) {
+            client
+                .put(format!(
+                    "{}/extents/{}",
+                    server.url(),
+                    extent_id.to_lowercase()
+                ))
+                .body(find_extent_data(&fixture, extent_id))
+                .send()
+                .expect("Extent upload failed");
+        }
+
+        let resp = client
+            .post(format!("{}/catalogs", server.url()))
+            .json(&InitiateRequest {
+                id: fixture.catalog_id,
+                checksum: fixture.catalog_checksum.clone(),
+            })
+            .send()
+            .expect("Resume initiate failed");
+        let resume: InitiateResponse = resp.json().expect("Failed to parse resume response");
+        assert!(resume.resuming);
+        let mut missing = resume.missing_extents.unwrap_or_default();
+        missing.sort();
+        missing
+    };
+
+    let mut expected: Vec<String> = fixture
+        .extent_ids
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .map(|id| id.to_lowercase())
+        .collect();
+    expected.sort();
+
+    let unbatched = missing_with(ApiOptions::default());
+    let batched = missing_with(ApiOptions {
+        check_batch_size: 2,
+        check_concurrency: 2,
+        ..ApiOptions::default()
+    });
+    assert_eq!(unbatched, expected);
+    assert_eq!(batched, expected);
+}
+
 #[test]
 fn test_extent_hash_verification() {
     let server = TestServer::start();
@@ -641,12 +572,29 @@ fn test_extent_already_exists() {
     // Should succeed but indicate already existed
     assert!(resp.status().is_success());
     // Could be 200 OK (already exists) or 201 (re-created) depending on implementation
+    if resp.status().as_u16() == 200 {
+        // The body went unread, so the HTTP/1.1 connection isn't kept
+        assert_eq!(resp.headers()["connection"], "close");
+    }
+
+    // HTTP/2 streams can be reset without the connection, which forbids the header
+    let client = Client::builder().http2_prior_knowledge().build().unwrap();
+    for _ in 0..2 {
+        let resp = client
+            .put(format!("{}/extents/{}", server.url(), extent_id))
+            .body(data.to_vec())
+            .send()
+            .expect("HTTP/2 upload failed");
+        assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+        assert!(resp.status().is_success());
+        assert!(resp.headers().get("connection").is_none());
+    }
 }
 
 #[test]
 fn test_finalize_with_missing_extents() {
     let server = TestServer::start();
-    let fixture = TestFixture::new();
+    let fixture = CatalogFixture::new();
     let client = Client::new();
 
     // Initiate and upload catalog but NOT extents
@@ -705,7 +653,7 @@ fn test_finalize_with_missing_extents() {
 #[test]
 fn test_catalog_checksum_mismatch() {
     let server = TestServer::start();
-    let fixture = TestFixture::new();
+    let fixture = CatalogFixture::new();
     let client = Client::new();
 
     // First upload with correct checksum
@@ -755,6 +703,294 @@ fn test_catalog_checksum_mismatch() {
     }
 }
 
+#[test]
+fn test_catalog_upload_over_scratch_limit() {
+    let server = TestServer::start_with_options(ApiOptions {
+        scratch_dir: Some("scratch".into()),
+        max_scratch_bytes: Some(1024),
+        ..Default::default()
+    });
+    let fixture = CatalogFixture::new();
+    let client = Client::new();
+
+    client
+        .post(format!("{}/catalogs", server.url()))
+        .json(&InitiateRequest {
+            id: fixture.catalog_id,
+            checksum: fixture.catalog_checksum.clone(),
+        })
+        .send()
+        .expect("Initiate failed");
+
+    // The decompressed catalog is bigger than the whole scratch space
+    let resp = client
+        .put(format!(
+            "{}/catalogs/{}",
+            server.url(),
+            fixture.catalog_id.simple()
+        ))
+        .body(fixture.catalog_data())
+        .send()
+        .expect("Upload failed");
+
+    assert_eq!(resp.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().contains_key(reqwest::header::RETRY_AFTER));
+    let err: ErrorResponse = resp.json().expect("Failed to parse error");
+    assert_eq!(err.error, "Server busy");
+
+    // The partial scratch file was cleaned up
+    let scratch = server.storage_path().join("scratch");
+    assert_eq!(fs::read_dir(scratch).unwrap().count(), 0);
+}
+
+#[test]
+fn test_extent_references() {
+    let server = TestServer::start();
+    let client = Client::new();
+
+    let first =
+        CatalogFixture::with_files(&[("file1.txt", "Hello, world!"), ("other.txt", "Other")]);
+    let second = CatalogFixture::with_files(&[("moved/hello.txt", "Hello, world!")]);
+    for fixture in [&first, &second] {
+        client
+            .post(format!("{}/catalogs", server.url()))
+            .json(&InitiateRequest {
+                id: fixture.catalog_id,
+                checksum: fixture.catalog_checksum.clone(),
+            })
+            .send()
+            .expect("Initiate failed");
+        let resp = client
+            .put(format!(
+                "{}/catalogs/{}",
+                server.url(),
+                fixture.catalog_id.simple()
+            ))
+            .body(fixture.catalog_data())
+            .send()
+            .expect("Upload failed");
+        assert!(resp.status().is_success(), "Status: {}", resp.status());
+    }
+
+    let shared = blake3::hash(b"Hello, world!").to_hex().to_string();
+    let resp = client
+        .get(format!(
+            "{}/extents/{}/references?paths=true",
+            server.url(),
+            shared
+        ))
+        .send()
+        .expect("References request failed");
+    assert!(resp.status().is_success(), "Status: {}", resp.status());
+
+    let body: serde_json::Value = resp.json().expect("Failed to parse response");
+    // Not uploaded yet
+    assert!(body["size"].is_null());
+    let mut refs: Vec<(String, serde_json::Value)> = body["catalogs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["id"].as_str().unwrap().to_string(), c["paths"].clone()))
+        .collect();
+    refs.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut expected = vec![
+        (first.catalog_id.simple().to_string(), json!(["file1.txt"])),
+        (
+            second.catalog_id.simple().to_string(),
+            json!(["moved/hello.txt"]),
+        ),
+    ];
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(refs, expected);
+
+    // Without paths, catalogs aren't read
+    let other = blake3::hash(b"Other").to_hex().to_string();
+    let body: serde_json::Value = client
+        .get(format!("{}/extents/{}/references", server.url(), other))
+        .send()
+        .expect("References request failed")
+        .json()
+        .expect("Failed to parse response");
+    assert_eq!(body["catalogs"].as_array().unwrap().len(), 1);
+    assert!(body["catalogs"][0].get("paths").is_none());
+}
+
+#[test]
+fn test_prune_dry_run() {
+    let server = TestServer::start();
+    let client = Client::new();
+
+    let first = CatalogFixture::with_files(&[("shared.txt", "Hello, world!"), ("a.txt", "only A")]);
+    let second =
+        CatalogFixture::with_files(&[("shared.txt", "Hello, world!"), ("b.txt", "only B")]);
+    upload_complete(&server, &client, &first);
+    upload_complete(&server, &client, &second);
+
+    // Deleting isn't supported
+    let resp = client
+        .post(format!("{}/admin/prune?keep_last=1", server.url()))
+        .send()
+        .expect("Prune request failed");
+    assert_eq!(resp.status().as_u16(), 400);
+
+    // Without rules, nothing is pruned
+    let body: serde_json::Value = client
+        .post(format!("{}/admin/prune?dry_run=true", server.url()))
+        .send()
+        .expect("Prune request failed")
+        .json()
+        .expect("Failed to parse response");
+    assert_eq!(body["kept"].as_array().unwrap().len(), 2);
+    assert_eq!(body["total_bytes"], 0);
+
+    let resp = client
+        .post(format!(
+            "{}/admin/prune?dry_run=true&keep_last=1",
+            server.url()
+        ))
+        .send()
+        .expect("Prune request failed");
+    assert!(resp.status().is_success(), "Status: {}", resp.status());
+    let body: serde_json::Value = resp.json().expect("Failed to parse response");
+
+    assert_eq!(body["kept"].as_array().unwrap().len(), 1);
+    let removed = body["removed"].as_array().unwrap();
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0]["machine"], "test-machine-id");
+
+    // Only the removed catalog's own file is freed, not the shared one
+    let removed_id = removed[0]["id"].as_str().unwrap();
+    let removed_fixture = if removed_id == first.catalog_id.simple().to_string() {
+        &first
+    } else {
+        &second
+    };
+    let catalog_bytes = removed_fixture.catalog_data().len() as u64;
+    assert_eq!(body["unreferenced_extents"], 1);
+    assert_eq!(body["extent_bytes"], 6);
+    assert_eq!(body["catalog_bytes"], catalog_bytes);
+    assert_eq!(body["total_bytes"], catalog_bytes + 6);
+
+    // Nothing was deleted
+    let catalogs: Vec<String> = client
+        .get(format!("{}/catalogs", server.url()))
+        .send()
+        .expect("List request failed")
+        .json()
+        .expect("Failed to parse response");
+    assert_eq!(catalogs.len(), 2);
+}
+
+#[test]
+fn test_probe_discards_body() {
+    let server = TestServer::start();
+    let client = Client::new();
+
+    let body: serde_json::Value = client
+        .post(format!("{}/probe", server.url()))
+        .body(vec![0u8; 1024 * 1024])
+        .send()
+        .expect("Probe request failed")
+        .json()
+        .expect("Failed to parse response");
+    assert_eq!(body["received"], 1024 * 1024);
+
+    // Nothing was stored
+    assert!(
+        fs::read_dir(server.storage_path().join("extents"))
+            .map_or(true, |mut dir| dir.next().is_none())
+    );
+}
+
+#[test]
+fn test_extent_parity() {
+    let server = TestServer::start_with_options(ApiOptions {
+        parity: Some(ParityScheme::default()),
+        ..Default::default()
+    });
+    let client = Client::new();
+
+    let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7919 % 251) as u8).collect();
+    let id = blake3::hash(&data).to_hex().to_string();
+    let resp = client
+        .put(format!("{}/extents/{}", server.url(), id))
+        .body(data.clone())
+        .send()
+        .expect("Extent upload failed");
+    assert_eq!(resp.status().as_u16(), 201);
+
+    // Parity is stored alongside, and can rebuild damaged data
+    let parity_path = server
+        .storage_path()
+        .join("parity")
+        .join(&id[0..2])
+        .join(&id[2..4])
+        .join(&id[4..]);
+    let parity = ExtentParity::decode(&fs::read(parity_path).expect("Parity not stored"))
+        .expect("Invalid parity");
+    assert_eq!(parity.length, data.len() as u64);
+    let mut damaged = data.clone();
+    damaged[0] ^= 0xff;
+    assert_eq!(
+        parity.check(&damaged),
+        ParityCheck::Repaired { data, shards: 1 }
+    );
+}
+
+#[test]
+fn test_extent_sketches() {
+    let server = TestServer::start();
+    let client = Client::new();
+
+    let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7919 % 251) as u8).collect();
+    let id = blake3::hash(&data).to_hex().to_string();
+    let resp = client
+        .put(format!("{}/extents/{}", server.url(), id))
+        .body(data)
+        .send()
+        .expect("Extent upload failed");
+    assert_eq!(resp.status().as_u16(), 201);
+
+    // Sketched at upload
+    let sketch_path = server
+        .storage_path()
+        .join("sketches")
+        .join(&id[0..2])
+        .join(&id[2..4])
+        .join(&id[4..]);
+    assert!(sketch_path.exists(), "Sketch not stored");
+
+    let sketch: serde_json::Value = client
+        .get(format!("{}/admin/sketches/{}", server.url(), id))
+        .send()
+        .expect("Sketch request failed")
+        .json()
+        .expect("Failed to parse response");
+    assert_eq!(sketch["id"], id.as_str());
+    assert_eq!(sketch["simhash"].as_str().unwrap().len(), 16);
+    assert!(!sketch["minhash"].as_array().unwrap().is_empty());
+
+    // Sketched again on demand if missing
+    fs::remove_file(&sketch_path).unwrap();
+    let missing = "00".repeat(32);
+    let resp: serde_json::Value = client
+        .post(format!("{}/admin/sketches", server.url()))
+        .json(&json!({ "ids": [id, missing] }))
+        .send()
+        .expect("Sketches request failed")
+        .json()
+        .expect("Failed to parse response");
+    assert_eq!(resp["sketches"][0], sketch);
+    assert!(resp["sketches"][1].is_null());
+    assert!(sketch_path.exists(), "Sketch not stored again");
+
+    let resp = client
+        .get(format!("{}/admin/sketches/{}", server.url(), missing))
+        .send()
+        .expect("Sketch request failed");
+    assert_eq!(resp.status().as_u16(), 404);
+}
+
 #[test]
 fn test_check_catalogs_empty() {
     let server = TestServer::start();
@@ -766,6 +1002,7 @@ fn test_check_catalogs_empty() {
             Uuid::new_v4().simple().to_string(),
             Uuid::new_v4().simple().to_string(),
         ],
+        sketch: None,
     };
 
     let resp = client
@@ -784,7 +1021,7 @@ fn test_check_catalogs_empty() {
 fn test_check_catalogs_with_existing() {
     let server = TestServer::start();
     let client = Client::new();
-    let fixture = TestFixture::new();
+    let fixture = CatalogFixture::new();
 
     // First, upload a catalog completely
     let catalog_data = fixture.catalog_data();
@@ -849,6 +1086,7 @@ fn test_check_catalogs_with_existing() {
             Uuid::new_v4().simple().to_string(),
             Uuid::new_v4().simple().to_string(),
         ],
+        sketch: None,
     };
 
     let resp = client
@@ -865,6 +1103,44 @@ fn test_check_catalogs_with_existing() {
         check_resp.existing[0].to_lowercase(),
         fixture.catalog_id.simple().to_string().to_lowercase()
     );
+    assert!(check_resp.shared.is_none());
+
+    // With a sketch of two of the catalog's extents (one listed twice) and an unknown one
+    let sampled = &upload_resp.missing_extents[..2];
+    let mut sketch: Vec<SketchExtent> = sampled
+        .iter()
+        .map(|id| SketchExtent {
+            id: id.to_lowercase(),
+            bytes: find_extent_data(&fixture, id).len() as u64,
+        })
+        .collect();
+    sketch.push(SketchExtent {
+        id: sketch[0].id.clone(),
+        bytes: sketch[0].bytes,
+    });
+    sketch.push(SketchExtent {
+        id: "ab".repeat(32),
+        bytes: 1 << 20,
+    });
+    let expected_bytes = sketch[0].bytes + sketch[1].bytes;
+
+    let check_req = CheckCatalogsRequest {
+        ids: vec![fixture.catalog_id.simple().to_string()],
+        sketch: Some(sketch),
+    };
+    let resp = client
+        .post(format!("{}/catalogs/check", server.url()))
+        .json(&check_req)
+        .send()
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    let check_resp: CheckCatalogsResponse = resp.json().unwrap();
+    let shared = check_resp.shared.unwrap();
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].id, check_resp.existing[0]);
+    assert_eq!(shared[0].extents, 2);
+    assert_eq!(shared[0].bytes, expected_bytes);
 }
 
 #[test]
@@ -873,14 +1149,14 @@ fn test_patch_upload() {
     let client = Client::new();
 
     // Create reference fixture with initial files
-    let reference_fixture = TestFixture::with_files(&[
+    let reference_fixture = CatalogFixture::with_files(&[
         ("file1.txt", "Hello, world!"),
         ("file2.txt", "This is a test file with some content."),
         ("subdir/file3.txt", "Nested file content here."),
     ]);
 
     // Create target fixture with modified files (some same, some different)
-    let target_fixture = TestFixture::with_files(&[
+    let target_fixture = CatalogFixture::with_files(&[
         ("file1.txt", "Hello, world!"), // Same as reference
         ("file2.txt", "This is MODIFIED content in the test file."), // Different
         ("subdir/file3.txt", "Nested file content here."), // Same as reference
@@ -1043,6 +1319,7 @@ fn test_patch_upload() {
     // Verify the catalog was stored correctly by checking it exists
     let check_req = CheckCatalogsRequest {
         ids: vec![target_fixture.catalog_id.simple().to_string()],
+        sketch: None,
     };
     let resp = client
         .post(format!("{}/catalogs/check", server.url()))
@@ -1054,11 +1331,920 @@ fn test_patch_upload() {
     assert_eq!(check_resp.existing.len(), 1);
 }
 
-// ============================================================================
-// Helper Functions
-// ============================================================================
-
-/// Find the content data for an extent by its hash.
-fn find_extent_data(fixture: &TestFixture, extent_id: &str) -> Vec<u8> {
+#[test]
+fn test_rebuild_index() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage_dir = TempDir::new().unwrap();
+    let storage = FsStorage::new(storage_dir.path());
+    runtime.block_on(storage.init()).unwrap();
+
+    // One catalog with every extent stored, one with nothing stored
+    let complete = CatalogFixture::new();
+    let incomplete = CatalogFixture::with_files(&[("lonely.txt", "Nobody uploaded me.")]);
+    let orphan = b"Referenced by no catalog";
+
+    runtime.block_on(async {
+        for fixture in [&complete, &incomplete] {
+            storage
+                .put_catalog(fixture.catalog_id, fixture.catalog_data().into())
+                .await
+                .unwrap();
+        }
+        for extent_id in &complete.extent_ids {
+            let data = complete.find_extent_data(extent_id);
+            let id = B3Id::try_from(hex::decode(extent_id).unwrap()).unwrap();
+            storage
+                .put_extent(
+                    &id,
+                    Box::new(std::io::Cursor::new(data)),
+                    None,
+                    ExtentCheck::Hash,
+                )
+                .await
+                .unwrap();
+        }
+        storage
+            .put_extent(
+                &B3Id::hash(orphan),
+                Box::new(std::io::Cursor::new(orphan.to_vec())),
+                None,
+                ExtentCheck::Hash,
+            )
+            .await
+            .unwrap();
+    });
+
+    // A fresh database, as if uploads.db had been lost, checking extents one at a time
+    let db = UploadDb::open(&storage_dir.path().join("rebuilt.db")).unwrap();
+    let options = ApiOptions {
+        check_batch_size: 1,
+        ..ApiOptions::default()
+    };
+    let report = runtime
+        .block_on(rebuild_index(&storage, &db, &options))
+        .expect("Rebuild failed");
+
+    assert_eq!(report.complete, vec![complete.catalog_id]);
+    assert_eq!(report.incomplete, vec![incomplete.catalog_id]);
+    assert!(report.failed.is_empty());
+    assert_eq!(report.orphan_extents, vec![B3Id::hash(orphan)]);
+
+    let info = db.get_catalog(complete.catalog_id).unwrap().unwrap();
+    assert_eq!(info.status, CatalogStatus::Complete);
+    let info = db.get_catalog(incomplete.catalog_id).unwrap().unwrap();
+    assert_eq!(info.status, CatalogStatus::Uploading);
+    assert_eq!(
+        db.get_unverified_catalog_extents(incomplete.catalog_id)
+            .unwrap()
+            .len(),
+        incomplete.extent_ids.len()
+    );
+}
+
+#[test]
+fn test_orphans_after_upgrade() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage_dir = TempDir::new().unwrap();
+    let storage = FsStorage::new(storage_dir.path());
+    runtime.block_on(storage.init()).unwrap();
+
+    let fixture = CatalogFixture::new();
+    runtime.block_on(async {
+        storage
+            .put_catalog(fixture.catalog_id, fixture.catalog_data().into())
+            .await
+            .unwrap();
+        for extent_id in &fixture.extent_ids {
+            let data = fixture.find_extent_data(extent_id);
+            let id = B3Id::try_from(hex::decode(extent_id).unwrap()).unwrap();
+            storage
+                .put_extent(
+                    &id,
+                    Box::new(std::io::Cursor::new(data)),
+                    None,
+                    ExtentCheck::Hash,
+                )
+                .await
+                .unwrap();
+        }
+    });
+
+    // A database from before extent references were recorded, holding the complete catalog
+    let db_path = storage_dir.path().join("uploads.db");
+    {
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE catalogs (
+                id BLOB PRIMARY KEY,
+                checksum BLOB NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+            CREATE INDEX idx_catalogs_checksum ON catalogs(checksum);
+            CREATE INDEX idx_catalogs_status ON catalogs(status);
+            CREATE TABLE catalog_extents (
+                catalog_id BLOB NOT NULL,
+                extent_id BLOB NOT NULL,
+                PRIMARY KEY (catalog_id, extent_id),
+                FOREIGN KEY (catalog_id) REFERENCES catalogs(id) ON DELETE CASCADE
+            );
+            CREATE INDEX idx_catalog_extents_extent ON catalog_extents(extent_id);
+            "#,
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO catalogs (id, checksum, status) VALUES (?1, ?2, 'complete')",
+            rusqlite::params![
+                fixture.catalog_id.as_bytes().as_slice(),
+                hex::decode(&fixture.catalog_checksum).unwrap()
+            ],
+        )
+        .unwrap();
+    }
+
+    let db = Mutex::new(UploadDb::open(&db_path).unwrap());
+    runtime
+        .block_on(find_orphans(&storage, &db, Duration::ZERO))
+        .expect_err("Orphans found from incomplete references");
+
+    assert!(
+        runtime
+            .block_on(backfill_references(&storage, &db))
+            .unwrap()
+    );
+    let report = runtime
+        .block_on(find_orphans(&storage, &db, Duration::ZERO))
+        .unwrap();
+    assert!(
+        report.orphans.is_empty(),
+        "Extents of a cataloged upload reported as orphans: {:?}",
+        report.orphans
+    );
+}
+
+#[test]
+fn test_strict_extents() {
+    let server = TestServer::start_with_options(ApiOptions {
+        strict_extents: true,
+        ..Default::default()
+    });
+    let client = Client::new();
+    let fixture = CatalogFixture::new();
+
+    let put = |data: &[u8]| {
+        client
+            .put(format!("{}/extents/{}", server.url(), B3Id::hash(data)))
+            .body(data.to_vec())
+            .send()
+            .expect("Extent upload failed")
+    };
+
+    // Not in any catalog
+    let resp = put(b"stray data");
+    assert_eq!(resp.status().as_u16(), 409);
+    let error: ErrorResponse = resp.json().unwrap();
+    assert_eq!(error.error, "Extent not needed");
+
+    // Nor can it be derived
+    let resp = client
+        .post(format!("{}/extents/derive", server.url()))
+        .json(&serde_json::json!({ "id": B3Id::hash(b"stray").as_hex(), "segments": [] }))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 409);
+
+    // Extents the catalog is missing are accepted
+    upload_complete(&server, &client, &fixture);
+
+    // But not once the catalog is complete
+    let data = fixture.find_extent_data(&fixture.extent_ids[0]);
+    assert_eq!(put(&data).status().as_u16(), 409);
+    assert_eq!(put(b"stray data").status().as_u16(), 409);
+}
+
+#[test]
+fn test_keyed_extents() {
+    let key = ExtentKey::new(&[7; 32]);
+    let other = ExtentKey::new(&[8; 32]);
+    let verifying = TestServer::start_with_options(ApiOptions {
+        extent_keys: vec![key.clone()],
+        ..Default::default()
+    });
+    let trusting = TestServer::start_with_options(ApiOptions {
+        trusted_extent_keys: TrustedKeys::Only(vec![other.key_id().to_string()]),
+        ..Default::default()
+    });
+    let client = Client::new();
+
+    let put = |server: &TestServer, id: B3Id, key: &ExtentKey, data: &[u8]| {
+        client
+            .put(format!("{}/extents/{}", server.url(), id))
+            .header("x-extent-key", key.key_id())
+            .body(data.to_vec())
+            .send()
+            .expect("Extent upload failed")
+    };
+
+    // Verified with the server's copy of the key
+    assert_eq!(
+        put(&verifying, key.hash(b"a"), &key, b"a")
+            .status()
+            .as_u16(),
+        201
+    );
+    let resp = put(&verifying, B3Id::hash(b"b"), &key, b"b");
+    assert_eq!(resp.status().as_u16(), 400);
+    let error: ErrorResponse = resp.json().unwrap();
+    assert_eq!(error.error, "Hash mismatch");
+
+    // Keys the server doesn't have are refused
+    let resp = put(&verifying, other.hash(b"c"), &other, b"c");
+    assert_eq!(resp.status().as_u16(), 400);
+    let error: ErrorResponse = resp.json().unwrap();
+    assert_eq!(error.error, "Unknown extent key");
+
+    // Unless they're trusted
+    assert_eq!(
+        put(&trusting, other.hash(b"c"), &other, b"c")
+            .status()
+            .as_u16(),
+        201
+    );
+    // But only the keys that are
+    let resp = put(&trusting, B3Id::hash(b"e"), &key, b"not e");
+    assert_eq!(resp.status().as_u16(), 400);
+    let error: ErrorResponse = resp.json().unwrap();
+    assert_eq!(error.error, "Unknown extent key");
+
+    // Plain IDs are still checked
+    let resp = client
+        .put(format!("{}/extents/{}", trusting.url(), key.hash(b"d")))
+        .body(b"d".to_vec())
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+}
+
+#[test]
+fn test_ranged_extent_reads() {
+    let server = TestServer::start();
+    let client = Client::new();
+    let data = b"Hello, world!";
+    let id = B3Id::hash(data);
+    client
+        .put(format!("{}/extents/{}", server.url(), id))
+        .body(data.to_vec())
+        .send()
+        .unwrap();
+
+    let get = |range: &str| {
+        client
+            .get(format!("{}/extents/{}", server.url(), id))
+            .header("Range", range)
+            .send()
+            .unwrap()
+    };
+
+    let resp = get("bytes=7-11");
+    assert_eq!(resp.status().as_u16(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 7-11/13");
+    assert_eq!(&resp.bytes().unwrap()[..], b"world");
+    assert_eq!(&get("bytes=7-").bytes().unwrap()[..], b"world!");
+    assert_eq!(&get("bytes=-6").bytes().unwrap()[..], b"world!");
+    assert_eq!(&get("bytes=10-100").bytes().unwrap()[..], b"ld!");
+
+    // Past the end, nothing can be sent
+    let resp = get("bytes=13-");
+    assert_eq!(resp.status().as_u16(), 416);
+    assert_eq!(resp.headers()["content-range"], "bytes */13");
+
+    // Several ranges aren't supported, so the whole extent is sent
+    let resp = get("bytes=0-1,3-4");
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(&resp.bytes().unwrap()[..], data);
+}
+
+#[test]
+fn test_blob_fetcher() {
+    use std::io::{Seek, SeekFrom};
+
+    let server = TestServer::start();
+    let client = Client::new();
+
+    // Data, a hole, and more data, cut into several extents
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("file");
+    let mut contents: Vec<u8> = (0..500_000u32).map(|i| (i % 251) as u8).collect();
+    contents[200_000..300_000].fill(0);
+    let mut file = fs::File::create(&path).unwrap();
+    file.write_all(&contents[..200_000]).unwrap();
+    file.set_len(300_000).unwrap();
+    file.seek(SeekFrom::Start(300_000)).unwrap();
+    file.write_all(&contents[300_000..]).unwrap();
+    drop(file);
+
+    let blob = tumulus::process_file(&path, dir.path())
+        .unwrap()
+        .blob
+        .unwrap();
+    assert!(blob.extents.len() > 2);
+    for extent in blob.extents.iter().filter(|e| !e.range.is_zero()) {
+        let range =
+            extent.range.offset as usize..(extent.range.offset + extent.range.length) as usize;
+        client
+            .put(format!("{}/extents/{}", server.url(), extent.extent_id))
+            .body(contents[range].to_vec())
+            .send()
+            .unwrap();
+    }
+
+    let fetcher = tumulus::BlobFetcher::new(reqwest::Client::new(), server.url()).with_parallel(2);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for range in [
+        0..500_000,
+        10..20,
+        100_000..400_000,
+        199_990..300_010,
+        450_000..600_000,
+    ] {
+        let read = runtime
+            .block_on(fetcher.read(&blob, range.clone()))
+            .unwrap();
+        let end = range.end.min(500_000) as usize;
+        assert_eq!(read, contents[range.start as usize..end], "{range:?}");
+    }
+
+    // Missing extents are errors
+    let mut missing = blob.clone();
+    missing.extents[0].extent_id = B3Id::hash(b"missing");
+    let err = runtime.block_on(fetcher.read(&missing, 0..10)).unwrap_err();
+    assert!(
+        matches!(err, tumulus::FetchError::Status { status, .. } if status.as_u16() == 404),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_restore_entries() {
+    let server = TestServer::start();
+    let client = Client::new();
+
+    let tree = tumulus_testkit::TestTree::new();
+    tree.file("docs/readme.txt", "read me")
+        .sparse(
+            "docs/sparse.bin",
+            300_000,
+            &[(0, b"start"), (250_000, b"end")],
+        )
+        .symlink("docs/link", "readme.txt")
+        .hardlink("docs/same.txt", "docs/readme.txt")
+        .file("other.txt", "not restored");
+    let preallocated = tree.preallocated("docs/preallocated.bin", 65536);
+    let fixture = CatalogFixture::of_tree(tree);
+    upload_complete(&server, &client, &fixture);
+
+    // As a system backup would catalog them, with hardlinks
+    let files = tumulus::read_catalog_files(&fixture.open())
+        .unwrap()
+        .into_iter()
+        .map(|info| {
+            let path = fixture.tree.join(&info.relative_path);
+            info.with_system_metadata(&path).unwrap()
+        })
+        .collect();
+    let catalog = tumulus::CatalogTree::new(files);
+    let docs = catalog.find("docs").unwrap();
+    let entries = catalog.entries_under([docs]);
+
+    let target = TempDir::new().unwrap();
+    let fetcher = tumulus::BlobFetcher::new(reqwest::Client::new(), server.url());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let report = runtime
+        .block_on(tumulus::restore_entries(
+            &entries,
+            target.path(),
+            &fetcher,
+            None,
+        ))
+        .unwrap();
+    assert_eq!(report.restored, 5);
+    assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+    // Only data ranges are written: not holes, preallocated ranges, or hardlinks again
+    let sparse = entries
+        .iter()
+        .find(|entry| entry.relative_path == "docs/sparse.bin")
+        .and_then(|entry| entry.blob.as_ref())
+        .unwrap();
+    let sparse_data: u64 = sparse
+        .extents
+        .iter()
+        .filter(|extent| !extent.range.hole && !extent.range.unwritten)
+        .map(|extent| extent.range.end().min(sparse.bytes) - extent.range.offset)
+        .sum();
+    assert_eq!(report.bytes, 7 + sparse_data);
+
+    let restored = |path: &str| target.path().join(path);
+    assert_eq!(fs::read(restored("docs/readme.txt")).unwrap(), b"read me");
+    assert_eq!(
+        fs::read(restored("docs/sparse.bin")).unwrap(),
+        fs::read(fixture.tree.join("docs/sparse.bin")).unwrap()
+    );
+    assert_eq!(
+        fs::read_link(restored("docs/link")).unwrap(),
+        std::path::Path::new("readme.txt")
+    );
+    assert!(!restored("other.txt").exists());
+    {
+        use std::os::unix::fs::MetadataExt as _;
+        let inode = |path: &str| fs::metadata(restored(path)).unwrap().ino();
+        assert_eq!(inode("docs/readme.txt"), inode("docs/same.txt"));
+    }
+
+    // Preallocated ranges are allocated again, where FIEMAP can tell
+    if preallocated {
+        let file = fs::File::open(restored("docs/preallocated.bin")).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 65536);
+        let mut reader = extentria::RangeReader::new();
+        let ranges: Vec<extentria::DataRange> = reader
+            .read_ranges(&file)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        if reader.last_method() == extentria::Method::Fiemap {
+            assert!(ranges.iter().all(|r| r.unwritten), "{ranges:?}");
+        }
+    }
+
+    // Times are cataloged to the second
+    let modified = |path: &std::path::Path| {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    assert_eq!(
+        modified(&restored("docs/readme.txt")),
+        modified(&fixture.tree.join("docs/readme.txt"))
+    );
+}
+
+#[test]
+fn test_extent_uploader() {
+    use std::io::Cursor;
+
+    use tumulus::{ExtentUploader, UploadExtentError};
+
+    let server = TestServer::start();
+    let client = Client::new();
+    let uploader = ExtentUploader::new(reqwest::Client::new(), server.url());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let exists = |id: B3Id| {
+        client
+            .head(format!("{}/extents/{}", server.url(), id))
+            .send()
+            .unwrap()
+            .status()
+            .is_success()
+    };
+
+    // Streamed in several chunks, from async and blocking readers
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let id = B3Id::hash(&data);
+    let created = runtime
+        .block_on(uploader.upload(id, Cursor::new(data.clone()), data.len() as u64))
+        .unwrap();
+    assert!(created);
+    assert!(exists(id));
+    let created = runtime
+        .block_on(uploader.upload_blocking(id, Cursor::new(data.clone()), data.len() as u64))
+        .unwrap();
+    assert!(!created, "already stored");
+
+    // Only the given length is read
+    let other = B3Id::hash(&data[..1000]);
+    runtime
+        .block_on(uploader.upload_blocking(other, Cursor::new(data.clone()), 1000))
+        .unwrap();
+    assert!(exists(other));
+
+    // Data that doesn't match its ID is never stored
+    let wrong = B3Id::hash(b"something else");
+    let err = runtime
+        .block_on(uploader.upload(wrong, Cursor::new(data.clone()), data.len() as u64))
+        .unwrap_err();
+    assert!(
+        matches!(err, UploadExtentError::Changed { actual, .. } if actual == id),
+        "{err}"
+    );
+    assert!(!exists(wrong));
+
+    // Readers that end early too
+    let short = B3Id::hash(b"short");
+    let err = runtime
+        .block_on(uploader.upload_blocking(short, Cursor::new(b"short".to_vec()), 10))
+        .unwrap_err();
+    assert!(
+        matches!(err, UploadExtentError::Length { actual: 5, .. }),
+        "{err}"
+    );
+    assert!(!exists(short));
+}
+
+#[test]
+fn test_derive_extent() {
+    let server = TestServer::start();
+    let client = Client::new();
+
+    let (a, b) = (b"Hello, world! ".as_slice(), b"Goodbye, moon.".as_slice());
+    for data in [a, b] {
+        client
+            .put(format!("{}/extents/{}", server.url(), B3Id::hash(data)))
+            .body(data.to_vec())
+            .send()
+            .unwrap();
+    }
+
+    let derive = |id: B3Id, segments: serde_json::Value| {
+        client
+            .post(format!("{}/extents/derive", server.url()))
+            .json(&serde_json::json!({ "id": id.as_hex(), "segments": segments }))
+            .send()
+            .unwrap()
+    };
+
+    // "world! Goodbye" from the end of one and the start of the other
+    let target = B3Id::hash(b"world! Goodbye");
+    let segments = serde_json::json!([
+        { "source": B3Id::hash(a).as_hex(), "offset": 7, "length": 7 },
+        { "source": B3Id::hash(b).as_hex(), "offset": 0, "length": 7 },
+    ]);
+    assert_eq!(derive(target, segments.clone()).status().as_u16(), 201);
+    let data = client
+        .get(format!("{}/extents/{}", server.url(), target))
+        .send()
+        .unwrap()
+        .bytes()
+        .unwrap();
+    assert_eq!(&data[..], b"world! Goodbye");
+    assert_eq!(derive(target, segments.clone()).status().as_u16(), 200);
+
+    // Data that doesn't hash to the ID is refused
+    let resp = derive(B3Id::hash(b"something else"), segments);
+    assert_eq!(resp.status().as_u16(), 400);
+    let error: ErrorResponse = resp.json().unwrap();
+    assert_eq!(error.error, "Hash mismatch");
+
+    // As are ranges past the end of a source, and unknown sources
+    let beyond =
+        serde_json::json!([{ "source": B3Id::hash(a).as_hex(), "offset": 10, "length": 10 }]);
+    assert_eq!(derive(B3Id::hash(b"x"), beyond).status().as_u16(), 400);
+    let unknown =
+        serde_json::json!([{ "source": B3Id::hash(b"?").as_hex(), "offset": 0, "length": 1 }]);
+    assert_eq!(derive(B3Id::hash(b"x"), unknown).status().as_u16(), 404);
+    assert_eq!(
+        derive(B3Id::hash(b""), serde_json::json!([]))
+            .status()
+            .as_u16(),
+        400
+    );
+}
+
+#[test]
+fn test_orphan_report() {
+    let server = TestServer::start();
+    let client = Client::new();
+    let fixture = CatalogFixture::new();
+    upload_complete(&server, &client, &fixture);
+
+    let stray = b"abandoned upload";
+    let resp = client
+        .put(format!("{}/extents/{}", server.url(), B3Id::hash(stray)))
+        .body(stray.to_vec())
+        .send()
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    let report = |min_age: &str| -> serde_json::Value {
+        client
+            .get(format!("{}/admin/orphans?min_age={min_age}", server.url()))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap()
+    };
+
+    let all = report("0s");
+    let orphans = all["orphans"].as_array().unwrap();
+    assert_eq!(orphans.len(), 1);
+    assert_eq!(orphans[0]["id"], B3Id::hash(stray).as_hex());
+    assert_eq!(all["orphan_bytes"], stray.len());
+
+    let old = report("1h");
+    assert!(old["orphans"].as_array().unwrap().is_empty());
+    assert_eq!(old["recent"], 1);
+    assert_eq!(old["recent_bytes"], stray.len());
+
+    let resp = client
+        .get(format!("{}/admin/orphans?min_age=-1h", server.url()))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+}
+
+#[test]
+fn test_consistency_check() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage_dir = TempDir::new().unwrap();
+    let (complete, lost, stale) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    {
+        let db = UploadDb::open(&storage_dir.path().join("uploads.db")).unwrap();
+        for id in [complete, lost] {
+            db.create_catalog(id, &B3Id::hash(id.as_bytes())).unwrap();
+            db.update_status(id, CatalogStatus::Complete).unwrap();
+        }
+        db.create_catalog(stale, &B3Id::hash(b"stale")).unwrap();
+        db.set_created_at(stale, 1).unwrap();
+    }
+    let storage = FsStorage::new(storage_dir.path());
+    runtime.block_on(async {
+        storage.init().await.unwrap();
+        storage
+            .put_catalog(complete, bytes::Bytes::from_static(b"catalog"))
+            .await
+            .unwrap();
+    });
+
+    let server = runtime
+        .block_on(
+            Server::builder()
+                .fs_storage(storage_dir.path())
+                .listen("127.0.0.1:0".parse().unwrap())
+                .start(),
+        )
+        .expect("Failed to start server");
+
+    let report: serde_json::Value = Client::new()
+        .get(format!("http://{}/admin/consistency", server.local_addr()))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(report["consistent"], false);
+    assert_eq!(report["missing"], json!([lost.simple().to_string()]));
+    assert_eq!(report["untracked"], json!([]));
+    // Expired when the server started
+    assert_eq!(report["stale"], json!([]));
+
+    runtime
+        .block_on(server.shutdown())
+        .expect("Shutdown failed");
+    let db = UploadDb::open(&storage_dir.path().join("uploads.db")).unwrap();
+    assert!(db.get_catalog(stale).unwrap().is_none());
+    assert!(db.get_catalog(complete).unwrap().is_some());
+}
+
+#[test]
+fn test_paginated_catalog_list() {
+    let server = TestServer::start();
+    let client = Client::new();
+    let storage = FsStorage::new(server.storage_path());
+    let mut ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+    server.runtime().block_on(async {
+        for &id in &ids {
+            storage
+                .put_catalog(id, bytes::Bytes::from_static(b"catalog"))
+                .await
+                .unwrap();
+        }
+    });
+    ids.sort();
+    let ids: Vec<String> = ids.iter().map(|id| id.simple().to_string()).collect();
+
+    let mut listed = Vec::new();
+    let mut token: Option<String> = None;
+    let mut pages = 0;
+    loop {
+        let mut url = format!("{}/catalogs?limit=2", server.url());
+        if let Some(token) = &token {
+            url.push_str(&format!("&continuation_token={token}"));
+        }
+        let resp = client.get(url).send().unwrap();
+        assert!(resp.status().is_success());
+        token = resp
+            .headers()
+            .get("x-continuation-token")
+            .map(|token| token.to_str().unwrap().to_string());
+        let page: Vec<String> = resp.json().unwrap();
+        assert!(page.len() <= 2);
+        listed.extend(page);
+        pages += 1;
+        if token.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(listed, ids);
+
+    let mut all: Vec<String> = client
+        .get(format!("{}/catalogs", server.url()))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    all.sort();
+    assert_eq!(all, ids);
+
+    let resp = client
+        .get(format!(
+            "{}/catalogs?continuation_token=nonsense",
+            server.url()
+        ))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 400);
+}
+
+#[test]
+fn test_catalog_head() {
+    let server = TestServer::start();
+    let client = Client::new();
+    let fixture = CatalogFixture::new();
+    let url = format!("{}/catalogs/{}", server.url(), fixture.catalog_id.simple());
+
+    let resp = client.head(&url).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+
+    client
+        .post(format!("{}/catalogs", server.url()))
+        .json(&InitiateRequest {
+            id: fixture.catalog_id,
+            checksum: fixture.catalog_checksum.clone(),
+        })
+        .send()
+        .unwrap();
+    let resp = client.head(&url).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let headers = resp.headers();
+    assert_eq!(headers["x-catalog-status"], "pending");
+    assert_eq!(
+        headers["x-catalog-checksum"],
+        fixture.catalog_checksum.as_str()
+    );
+    assert_eq!(
+        headers["etag"],
+        format!("\"{}\"", fixture.catalog_checksum).as_str()
+    );
+    assert!(headers.contains_key("last-modified"));
+    assert!(
+        headers["x-catalog-created"]
+            .to_str()
+            .unwrap()
+            .parse::<i64>()
+            .unwrap()
+            > 0
+    );
+
+    upload_complete(&server, &client, &fixture);
+    let resp = client.head(&url).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["x-catalog-status"], "complete");
+    let size: u64 = resp.headers()["content-length"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(size > 0);
+    assert!(resp.bytes().unwrap().is_empty());
+}
+
+#[test]
+fn test_catalog_download() {
+    let server = TestServer::start();
+    let client = Client::new();
+    let fixture = CatalogFixture::new();
+    let url = format!("{}/catalogs/{}", server.url(), fixture.catalog_id.simple());
+
+    let resp = client.get(&url).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+
+    // Pending catalogs haven't been received yet
+    client
+        .post(format!("{}/catalogs", server.url()))
+        .json(&InitiateRequest {
+            id: fixture.catalog_id,
+            checksum: fixture.catalog_checksum.clone(),
+        })
+        .send()
+        .unwrap();
+    let resp = client.get(&url).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+
+    upload_complete(&server, &client, &fixture);
+    let resp = client.get(&url).send().unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["x-catalog-status"], "complete");
+    assert_eq!(
+        resp.headers()["x-catalog-checksum"],
+        fixture.catalog_checksum.as_str()
+    );
+    assert_eq!(resp.bytes().unwrap(), fixture.catalog_data());
+}
+
+#[test]
+fn test_embedded_server() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage_dir = TempDir::new().unwrap();
+
+    let server = runtime
+        .block_on(
+            Server::builder()
+                .fs_storage(storage_dir.path())
+                .auth_token("sesame")
+                .listen("127.0.0.1:0".parse().unwrap())
+                .start(),
+        )
+        .expect("Failed to start server");
+    assert!(storage_dir.path().join("uploads.db").exists());
+
+    let client = Client::new();
+    let url = format!("http://{}/probe", server.local_addr());
+
+    let resp = client.post(&url).body("ping").send().unwrap();
+    assert_eq!(resp.status(), 401);
+    let error: ErrorResponse = resp.json().unwrap();
+    assert_eq!(error.error, "Unauthorized");
+
+    let resp = client
+        .post(&url)
+        .bearer_auth("wrong")
+        .body("ping")
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let resp = client
+        .post(&url)
+        .bearer_auth("sesame")
+        .body("ping")
+        .send()
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    runtime
+        .block_on(server.shutdown())
+        .expect("Shutdown failed");
+    assert!(client.post(&url).body("ping").send().is_err());
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Find the content data for an extent by its hash.
+/// Upload a fixture's catalog and all its extents, and finalize it.
+fn upload_complete(server: &TestServer, client: &Client, fixture: &CatalogFixture) {
+    client
+        .post(format!("{}/catalogs", server.url()))
+        .json(&InitiateRequest {
+            id: fixture.catalog_id,
+            checksum: fixture.catalog_checksum.clone(),
+        })
+        .send()
+        .expect("Initiate failed");
+
+    let resp = client
+        .put(format!(
+            "{}/catalogs/{}",
+            server.url(),
+            fixture.catalog_id.simple()
+        ))
+        .body(fixture.catalog_data())
+        .send()
+        .expect("Upload failed");
+    let upload: UploadResponse = resp.json().expect("Failed to parse upload response");
+
+    for extent_id in &upload.missing_extents {
+        let resp = client
+            .put(format!("{}/extents/{}", server.url(), extent_id))
+            .body(fixture.find_extent_data(extent_id))
+            .send()
+            .expect("Extent upload failed");
+        assert!(resp.status().is_success(), "Status: {}", resp.status());
+    }
+
+    let resp = client
+        .post(format!(
+            "{}/catalogs/{}",
+            server.url(),
+            fixture.catalog_id.simple()
+        ))
+        .send()
+        .expect("Finalize failed");
+    assert_eq!(resp.status().as_u16(), 204);
+}
+
+fn find_extent_data(fixture: &CatalogFixture, extent_id: &str) -> Vec<u8> {
     fixture.find_extent_data(extent_id)
 }
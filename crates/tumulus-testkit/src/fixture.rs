@@ -0,0 +1,153 @@
+//! Catalogs of test trees.
+
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::json;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+use tumulus::{B3Id, create_catalog_schema, process_file, write_catalog};
+
+use crate::tree::TestTree;
+
+/// A catalog of a [`TestTree`], as the client would upload it.
+#[derive(Debug)]
+pub struct CatalogFixture {
+    pub tree: TestTree,
+    _catalog_dir: TempDir,
+    pub catalog_path: PathBuf,
+    pub catalog_id: Uuid,
+    /// BLAKE3 hash of the catalog file, in hex.
+    pub catalog_checksum: String,
+    /// IDs of the distinct extents of the catalog, in hex.
+    pub extent_ids: Vec<String>,
+}
+
+impl CatalogFixture {
+    /// Catalog a few small files.
+    pub fn new() -> Self {
+        Self::with_files(&[
+            ("file1.txt", "Hello, world!"),
+            ("file2.txt", "This is a test file with some content."),
+            ("subdir/file3.txt", "Nested file content here."),
+        ])
+    }
+
+    /// Catalog regular files, given as (path, contents).
+    pub fn with_files(files: &[(&str, &str)]) -> Self {
+        Self::of_tree(TestTree::with_files(files))
+    }
+
+    /// Catalog everything in a tree.
+    pub fn of_tree(tree: TestTree) -> Self {
+        let catalog_dir = TempDir::new().expect("Failed to create catalog dir");
+        let catalog_path = catalog_dir.path().join("test.catalog");
+        let catalog_id = Uuid::new_v4();
+
+        let conn = Connection::open(&catalog_path).expect("Failed to create catalog db");
+        create_catalog_schema(&conn).expect("Failed to create schema");
+
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        for (key, value) in [
+            ("id", json!(catalog_id.simple().to_string())),
+            ("machine", json!("test-machine-id")),
+            ("source_path", json!(tree.path().to_string_lossy())),
+            ("created", json!(created)),
+        ] {
+            conn.execute(
+                "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+                params![key, value.to_string()],
+            )
+            .unwrap();
+        }
+
+        let file_infos: Vec<_> = tree
+            .entries()
+            .iter()
+            .map(|path| {
+                process_file(&tree.join(path), tree.path()).expect("Failed to process file")
+            })
+            .collect();
+        write_catalog(&conn, &file_infos).expect("Failed to write catalog");
+
+        let extent_ids = {
+            let mut stmt = conn
+                .prepare("SELECT DISTINCT extent_id FROM blob_extents WHERE extent_id IS NOT NULL")
+                .unwrap();
+            stmt.query_map([], |row| {
+                let id = B3Id::try_from(row.get::<_, Vec<u8>>(0)?).expect("Invalid extent ID");
+                Ok(id.as_hex())
+            })
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+        };
+        drop(conn);
+
+        let catalog_data = fs::read(&catalog_path).expect("Failed to read catalog");
+        let catalog_checksum = blake3::hash(&catalog_data).to_hex().to_string();
+
+        Self {
+            tree,
+            _catalog_dir: catalog_dir,
+            catalog_path,
+            catalog_id,
+            catalog_checksum,
+            extent_ids,
+        }
+    }
+
+    /// Contents of the catalog file.
+    pub fn catalog_data(&self) -> Vec<u8> {
+        fs::read(&self.catalog_path).expect("Failed to read catalog")
+    }
+
+    /// Open the catalog.
+    pub fn open(&self) -> Connection {
+        Connection::open(&self.catalog_path).expect("Failed to open catalog")
+    }
+
+    /// Read the data of an extent (by hex ID) from the tree.
+    pub fn find_extent_data(&self, extent_id: &str) -> Vec<u8> {
+        let id = hex::decode(extent_id).expect("Invalid extent ID");
+        let location = self
+            .open()
+            .query_row(
+                "SELECT f.path, be.offset, be.bytes FROM blob_extents be
+                 JOIN files f ON f.blob_id = be.blob_id
+                 WHERE be.extent_id = ?1 LIMIT 1",
+                [id],
+                |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, u64>(1)?,
+                        row.get::<_, u64>(2)?,
+                    ))
+                },
+            )
+            .optional()
+            .unwrap();
+        let Some((path, offset, bytes)) = location else {
+            panic!("Extent {extent_id} not found in catalog");
+        };
+
+        let path = self.tree.join(Path::new(&*String::from_utf8_lossy(&path)));
+        let mut data = vec![0; bytes as usize];
+        File::open(&path)
+            .and_then(|file| file.read_exact_at(&mut data, offset))
+            .expect("Failed to read extent");
+        data
+    }
+}
+
+impl Default for CatalogFixture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,127 @@
+//! Consistency checks for catalogs.
+
+use rusqlite::Connection;
+
+/// Run a query returning rows that break an invariant, and panic if there are any.
+fn assert_none(conn: &Connection, what: &str, sql: &str) {
+    let mut stmt = conn.prepare(sql).expect("Invalid invariant query");
+    let columns = stmt.column_count();
+    let rows: Vec<String> = stmt
+        .query_map([], |row| {
+            (0..columns)
+                .map(|i| {
+                    row.get::<_, rusqlite::types::Value>(i)
+                        .map(|v| format!("{v:?}"))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|values| values.join(", "))
+        })
+        .expect("Failed to check invariant")
+        .collect::<Result<_, _>>()
+        .expect("Failed to read invariant row");
+    assert!(rows.is_empty(), "{what}:\n  {}", rows.join("\n  "));
+}
+
+/// Check that a catalog is internally consistent:
+///
+/// - its identifying metadata is present;
+/// - every blob is covered by contiguous extents from offset 0, which add up to its size
+///   and its extent count;
+/// - every extent a blob uses is in `extents`, with the same size;
+/// - every blob a file or stream refers to is in `blobs`.
+pub fn assert_catalog_invariants(conn: &Connection) {
+    for key in ["id", "machine", "created"] {
+        let present: bool = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM metadata WHERE key = ?1)",
+                [key],
+                |row| row.get(0),
+            )
+            .expect("Failed to read metadata");
+        assert!(present, "Catalog has no {key:?} metadata");
+    }
+
+    assert_none(
+        conn,
+        "Blobs whose extents don't add up",
+        "SELECT b.blob_id, b.bytes, b.extents, COALESCE(SUM(be.bytes), 0), COUNT(be.offset)
+         FROM blobs b LEFT JOIN blob_extents be ON be.blob_id = b.blob_id
+         GROUP BY b.blob_id
+         HAVING COALESCE(SUM(be.bytes), 0) != b.bytes OR COUNT(be.offset) != b.extents",
+    );
+    assert_none(
+        conn,
+        "Blob extents not contiguous",
+        "SELECT blob_id, offset, expected FROM (
+             SELECT blob_id, offset,
+                 COALESCE(SUM(bytes) OVER (
+                     PARTITION BY blob_id ORDER BY offset
+                     ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+                 ), 0) AS expected
+             FROM blob_extents
+         ) WHERE offset != expected",
+    );
+    assert_none(
+        conn,
+        "Blob extents not in extents",
+        "SELECT be.blob_id, be.offset, be.extent_id FROM blob_extents be
+         LEFT JOIN extents e ON e.extent_id = be.extent_id
+         WHERE be.extent_id IS NOT NULL AND (e.extent_id IS NULL OR e.bytes != be.bytes)",
+    );
+    assert_none(
+        conn,
+        "Blob extents of unknown blobs",
+        "SELECT blob_id, offset FROM blob_extents
+         WHERE blob_id NOT IN (SELECT blob_id FROM blobs)",
+    );
+    assert_none(
+        conn,
+        "Files with unknown blobs",
+        "SELECT path, blob_id FROM files
+         WHERE blob_id IS NOT NULL AND blob_id NOT IN (SELECT blob_id FROM blobs)",
+    );
+    assert_none(
+        conn,
+        "Streams with unknown blobs",
+        "SELECT path, name, blob_id FROM streams
+         WHERE blob_id NOT IN (SELECT blob_id FROM blobs)",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CatalogFixture, TestTree};
+
+    #[test]
+    fn catalogs_of_varied_trees_hold() {
+        let tree = TestTree::with_files(&[("plain.txt", "plain"), ("empty", "")]);
+        tree.sparse(
+            "sparse.bin",
+            4 << 20,
+            &[(1 << 20, b"middle"), (3 << 20, b"end")],
+        )
+        .hardlink("hardlink.txt", "plain.txt")
+        .symlink("dir/symlink", "../plain.txt");
+        tree.file("original.bin", vec![7; 128 * 1024]);
+        tree.reflink("reflink.bin", "original.bin");
+        tree.xattr("plain.txt", "user.tumulus.test", b"value");
+
+        let fixture = CatalogFixture::of_tree(tree);
+        assert_catalog_invariants(&fixture.open());
+        for extent_id in &fixture.extent_ids {
+            let data = fixture.find_extent_data(extent_id);
+            assert_eq!(blake3::hash(&data).to_hex().as_str(), extent_id);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Blobs whose extents don't add up")]
+    fn broken_catalog_fails() {
+        let fixture = CatalogFixture::new();
+        let conn = fixture.open();
+        conn.execute("UPDATE blobs SET bytes = bytes + 1", [])
+            .unwrap();
+        assert_catalog_invariants(&conn);
+    }
+}
@@ -0,0 +1,17 @@
+//! Shared helpers for tumulus tests, on Unix.
+//!
+//! - [`TestTree`] builds source directories, including sparse, reflinked, hardlinked, and
+//!   xattr'd files where the filesystem supports them;
+//! - [`CatalogFixture`] catalogs such a directory;
+//! - [`TestServer`] runs an in-process server on temporary storage;
+//! - [`assert_catalog_invariants`] checks a catalog is internally consistent.
+
+pub mod fixture;
+pub mod invariants;
+pub mod server;
+pub mod tree;
+
+pub use fixture::CatalogFixture;
+pub use invariants::assert_catalog_invariants;
+pub use server::TestServer;
+pub use tree::TestTree;
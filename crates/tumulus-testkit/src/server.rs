@@ -0,0 +1,77 @@
+//! In-process servers.
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use tumulus_server::{ApiOptions, Server, ServerHandle};
+
+/// A server on temporary filesystem storage, shut down on drop.
+///
+/// It runs on its own runtime, so tests can talk to it with blocking clients.
+pub struct TestServer {
+    handle: Option<ServerHandle>,
+    runtime: Runtime,
+    storage_dir: TempDir,
+}
+
+impl TestServer {
+    /// Start a server with default options.
+    pub fn start() -> Self {
+        Self::start_with_options(ApiOptions::default())
+    }
+
+    /// Start a server with custom options.
+    ///
+    /// A relative scratch directory is taken to be within the storage directory.
+    pub fn start_with_options(mut options: ApiOptions) -> Self {
+        let runtime = Runtime::new().expect("Failed to start runtime");
+        let storage_dir = TempDir::new().expect("Failed to create temp storage dir");
+        options.scratch_dir = options.scratch_dir.map(|dir| storage_dir.path().join(dir));
+
+        let handle = runtime
+            .block_on(
+                Server::builder()
+                    .fs_storage(storage_dir.path())
+                    .options(options)
+                    .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+                    .start(),
+            )
+            .expect("Failed to start server");
+
+        Self {
+            handle: Some(handle),
+            runtime,
+            storage_dir,
+        }
+    }
+
+    /// Address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.handle.as_ref().unwrap().local_addr()
+    }
+
+    /// Base URL of the server.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr())
+    }
+
+    /// Storage directory of the server.
+    pub fn storage_path(&self) -> &Path {
+        self.storage_dir.path()
+    }
+
+    /// Runtime the server runs on, to drive async code against its storage.
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.runtime.block_on(handle.shutdown());
+        }
+    }
+}
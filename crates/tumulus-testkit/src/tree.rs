@@ -0,0 +1,252 @@
+//! Building source directories to catalog.
+
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+/// A temporary directory tree, deleted on drop.
+///
+/// Builder methods panic on failure, except for the features a filesystem may not have,
+/// which instead report whether they took effect so tests can skip what they can't check.
+#[derive(Debug)]
+pub struct TestTree {
+    dir: TempDir,
+}
+
+impl TestTree {
+    /// Create an empty tree in the system temporary directory.
+    pub fn new() -> Self {
+        Self {
+            dir: TempDir::new().expect("Failed to create temp dir"),
+        }
+    }
+
+    /// Create an empty tree inside a directory, to test on a particular filesystem.
+    pub fn new_in(parent: impl AsRef<Path>) -> Self {
+        Self {
+            dir: TempDir::new_in(parent).expect("Failed to create temp dir"),
+        }
+    }
+
+    /// Create a tree of regular files, given as (path, contents).
+    pub fn with_files(files: &[(&str, &str)]) -> Self {
+        let tree = Self::new();
+        for (path, contents) in files {
+            tree.file(path, contents);
+        }
+        tree
+    }
+
+    /// Root of the tree.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Absolute path of an entry in the tree.
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.dir.path().join(path)
+    }
+
+    /// Paths of everything in the tree except directories, relative to the root, sorted.
+    pub fn entries(&self) -> Vec<PathBuf> {
+        fn walk(root: &Path, dir: &Path, entries: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(dir).expect("Failed to read dir") {
+                let path = entry.expect("Failed to read dir entry").path();
+                if path.symlink_metadata().unwrap().is_dir() {
+                    walk(root, &path, entries);
+                } else {
+                    entries.push(path.strip_prefix(root).unwrap().to_path_buf());
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        walk(self.path(), self.path(), &mut entries);
+        entries.sort();
+        entries
+    }
+
+    /// Create a directory and its parents.
+    pub fn dir(&self, path: impl AsRef<Path>) -> &Self {
+        fs::create_dir_all(self.join(path)).expect("Failed to create dir");
+        self
+    }
+
+    /// Write a regular file, creating its parents.
+    pub fn file(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> &Self {
+        fs::write(self.create_parents(path.as_ref()), contents).expect("Failed to write file");
+        self
+    }
+
+    /// Write a file of `len` bytes with data only at the given offsets, leaving the rest
+    /// as holes on filesystems with sparse files (and zeroes elsewhere).
+    pub fn sparse(&self, path: impl AsRef<Path>, len: u64, data: &[(u64, &[u8])]) -> &Self {
+        let file = File::create(self.create_parents(path.as_ref())).expect("Failed to create file");
+        file.set_len(len).expect("Failed to size file");
+        for (offset, bytes) in data {
+            file.write_all_at(bytes, *offset)
+                .expect("Failed to write file");
+        }
+        file.sync_all().expect("Failed to sync file");
+        self
+    }
+
+    /// Make a file of `len` bytes preallocated but unwritten, reading as zeroes.
+    ///
+    /// Returns whether the filesystem could; if not, the file is left empty.
+    pub fn preallocated(&self, path: impl AsRef<Path>, len: u64) -> bool {
+        let file = File::create(self.create_parents(path.as_ref())).expect("Failed to create file");
+        sys::preallocate(&file, len).is_ok()
+    }
+
+    /// Make a symlink.
+    pub fn symlink(&self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> &Self {
+        std::os::unix::fs::symlink(target, self.create_parents(path.as_ref()))
+            .expect("Failed to create symlink");
+        self
+    }
+
+    /// Make a hard link to an existing file of the tree.
+    pub fn hardlink(&self, path: impl AsRef<Path>, existing: impl AsRef<Path>) -> &Self {
+        fs::hard_link(self.join(existing), self.create_parents(path.as_ref()))
+            .expect("Failed to create hard link");
+        self
+    }
+
+    /// Make a copy of an existing file of the tree which shares its extents.
+    ///
+    /// Returns whether the filesystem could; if not, the file is copied normally.
+    pub fn reflink(&self, path: impl AsRef<Path>, existing: impl AsRef<Path>) -> bool {
+        let source = self.join(existing);
+        let target = self.create_parents(path.as_ref());
+        match sys::reflink(&source, &target) {
+            Ok(()) => true,
+            Err(_) => {
+                let _ = fs::remove_file(&target);
+                fs::copy(&source, &target).expect("Failed to copy file");
+                false
+            }
+        }
+    }
+
+    /// Set an extended attribute on an entry of the tree (not following symlinks).
+    ///
+    /// Returns whether the filesystem could.
+    pub fn xattr(&self, path: impl AsRef<Path>, name: &str, value: &[u8]) -> bool {
+        sys::set_xattr(&self.join(path), name, value).is_ok()
+    }
+
+    fn create_parents(&self, path: &Path) -> PathBuf {
+        let path = self.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Failed to create parent dirs");
+        }
+        path
+    }
+}
+
+impl Default for TestTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use nix::libc;
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn reflink(source: &Path, target: &Path) -> io::Result<()> {
+        use std::fs::File;
+        use std::os::fd::AsRawFd;
+
+        let source = File::open(source)?;
+        let target = File::create(target)?;
+        if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn reflink(source: &Path, target: &Path) -> io::Result<()> {
+        let (source, target) = (c_path(source)?, c_path(target)?);
+        if unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn reflink(_source: &Path, _target: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn preallocate(file: &std::fs::File, len: u64) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn preallocate(_file: &std::fs::File, _len: u64) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let (path, name) = (c_path(path)?, CString::new(name)?);
+        let result = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let (path, name) = (c_path(path)?, CString::new(name)?);
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
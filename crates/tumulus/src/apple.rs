@@ -0,0 +1,160 @@
+//! macOS Finder metadata: `com.apple.*` extended attributes and resource forks.
+//!
+//! Finder tags, Finder info, quarantine flags, and the like are extended attributes in the
+//! `com.apple.` namespace. They're small, so they're kept inline in the file's `attributes`
+//! column under `xattrs`, as [`crate::xattr`] encodes them. Resource forks can be large, so they're instead
+//! a secondary data stream of the file, much like an NTFS alternate data stream: a blob of
+//! their own, recorded in the `streams` table as [`RESOURCE_FORK`], and read and written
+//! through the file's `..namedfork/rsrc` path.
+//!
+//! Capturing only finds anything on macOS; elsewhere restoring is skipped.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{Value, json};
+
+use crate::xattr::{Xattrs, xattrs_from_attributes, xattrs_to_value};
+
+/// Name of the resource fork stream.
+pub const RESOURCE_FORK: &str = "rsrc";
+
+/// Namespace of the captured extended attributes.
+const XATTR_PREFIX: &[u8] = b"com.apple.";
+
+/// The resource fork as an extended attribute, which is captured as a stream instead.
+const RESOURCE_FORK_XATTR: &[u8] = b"com.apple.ResourceFork";
+
+/// Whether an extended attribute is captured as Finder metadata.
+fn is_finder_xattr(name: &[u8]) -> bool {
+    name.starts_with(XATTR_PREFIX) && name != RESOURCE_FORK_XATTR
+}
+
+/// Finder metadata of a file, as extended attributes by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppleMetadata {
+    pub xattrs: Xattrs,
+}
+
+impl AppleMetadata {
+    /// Whether there's no metadata.
+    pub fn is_empty(&self) -> bool {
+        self.xattrs.is_empty()
+    }
+
+    /// Encode as the `attributes` column value.
+    pub fn to_attributes(&self) -> Value {
+        json!({ "xattrs": xattrs_to_value(&self.xattrs) })
+    }
+
+    /// Decode from the `attributes` column value, ignoring anything malformed.
+    pub fn from_attributes(attributes: &Value) -> Self {
+        let mut xattrs = xattrs_from_attributes(attributes);
+        xattrs.retain(|name, _| is_finder_xattr(name));
+        Self { xattrs }
+    }
+}
+
+/// Path through which a named stream of a file is read and written.
+pub fn stream_path(path: &Path, name: &str) -> PathBuf {
+    path.join("..namedfork").join(name)
+}
+
+/// Read the `com.apple.*` extended attributes of a file (without following symlinks).
+///
+/// Filesystems without extended attributes have none.
+#[cfg(target_os = "macos")]
+pub fn read_apple_metadata(path: &Path) -> io::Result<AppleMetadata> {
+    let xattrs = crate::xattr::read_matching(path, is_finder_xattr)?;
+    Ok(AppleMetadata { xattrs })
+}
+
+/// Read the Finder metadata of a file (there is none on this platform).
+#[cfg(not(target_os = "macos"))]
+pub fn read_apple_metadata(_path: &Path) -> io::Result<AppleMetadata> {
+    Ok(AppleMetadata::default())
+}
+
+/// Set the extended attributes of a file from its Finder metadata (without following
+/// symlinks). Returns whether they were restored, which they aren't outside of macOS.
+#[cfg(target_os = "macos")]
+pub fn restore_apple_metadata(path: &Path, metadata: &AppleMetadata) -> io::Result<bool> {
+    crate::xattr::write_all(path, &metadata.xattrs)?;
+    Ok(true)
+}
+
+/// Set the extended attributes of a file (not supported on this platform).
+#[cfg(not(target_os = "macos"))]
+pub fn restore_apple_metadata(_path: &Path, _metadata: &AppleMetadata) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Write the resource fork of an existing file. Returns whether it was restored, which
+/// it isn't outside of macOS.
+#[cfg(target_os = "macos")]
+pub fn restore_resource_fork(path: &Path, data: &[u8]) -> io::Result<bool> {
+    std::fs::write(stream_path(path, RESOURCE_FORK), data)?;
+    Ok(true)
+}
+
+/// Write the resource fork of a file (not supported on this platform).
+#[cfg(not(target_os = "macos"))]
+pub fn restore_resource_fork(_path: &Path, _data: &[u8]) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    use super::*;
+
+    #[test]
+    fn attributes_round_trip() {
+        let metadata = AppleMetadata {
+            xattrs: BTreeMap::from([
+                (b"com.apple.FinderInfo".to_vec(), vec![0, 1, 2, 255]),
+                (
+                    b"com.apple.quarantine".to_vec(),
+                    b"0081;abc;Safari;".to_vec(),
+                ),
+            ]),
+        };
+        let attributes = metadata.to_attributes();
+        assert_eq!(
+            attributes["xattrs"]["com.apple.FinderInfo"],
+            json!(STANDARD.encode([0, 1, 2, 255]))
+        );
+        assert_eq!(AppleMetadata::from_attributes(&attributes), metadata);
+    }
+
+    #[test]
+    fn malformed_attributes_are_ignored() {
+        let attributes = json!({
+            "xattrs": {
+                "com.apple.metadata:_kMDItemUserTags": STANDARD.encode(b"tags"),
+                "com.apple.FinderInfo": "not base64!",
+                "user.other": STANDARD.encode(b"x"),
+                "com.apple.ResourceFork": STANDARD.encode(b"fork"),
+            }
+        });
+        let metadata = AppleMetadata::from_attributes(&attributes);
+        assert_eq!(
+            metadata.xattrs.keys().collect::<Vec<_>>(),
+            [b"com.apple.metadata:_kMDItemUserTags"]
+        );
+        assert!(AppleMetadata::from_attributes(&json!("sealed")).is_empty());
+    }
+
+    #[test]
+    fn stream_paths() {
+        assert_eq!(
+            stream_path(Path::new("dir/file"), RESOURCE_FORK),
+            Path::new("dir/file/..namedfork/rsrc")
+        );
+    }
+}
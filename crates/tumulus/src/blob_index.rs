@@ -0,0 +1,332 @@
+//! Client-side index of uploaded blobs, for skipping work on files already backed up.
+//!
+//! Once an upload completes, every blob in its catalog is recorded here with its extents,
+//! along with the server it's now known to be on. A later scan that finds a file the same
+//! size as a recorded blob hashes the whole file first, and if the hash matches, takes the
+//! recorded extents instead of hashing each one: a copy of a file that's already been
+//! uploaded costs a single read. Uploads can then skip asking the server about the extents
+//! of blobs it's known to have.
+//!
+//! The index is a SQLite database with the catalog's `blobs` and `blob_extents` tables, and
+//! a `presence` table of which blobs are on which servers. Servers can lose data, so
+//! presence is only a hint: the server still decides what's missing when a catalog is
+//! uploaded, and blobs it reports missing are [forgotten](BlobIndex::forget_extents).
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use extentria::DataRange;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::B3Id;
+use crate::extents::{BlobInfo, ExtentInfo};
+
+/// An index of uploaded blobs and the servers they're on.
+///
+/// Lookups take `&self`, so a scan can share one index between threads.
+#[derive(Debug)]
+pub struct BlobIndex {
+    conn: Mutex<Connection>,
+    sizes: HashSet<u64>,
+}
+
+impl BlobIndex {
+    /// Open an index, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Use an index in an open database, creating its tables if needed.
+    pub fn from_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS blobs (
+                blob_id BLOB PRIMARY KEY,
+                bytes INTEGER NOT NULL,
+                extents INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_blobs_bytes ON blobs(bytes);
+
+            CREATE TABLE IF NOT EXISTS blob_extents (
+                blob_id BLOB NOT NULL,
+                extent_id BLOB,
+                offset INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                fs_extent INTEGER NOT NULL,
+                preallocated INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (blob_id, offset)
+            );
+            CREATE INDEX IF NOT EXISTS idx_blob_extents_extent ON blob_extents(extent_id);
+
+            CREATE TABLE IF NOT EXISTS presence (
+                blob_id BLOB NOT NULL,
+                server TEXT NOT NULL,
+                PRIMARY KEY (blob_id, server)
+            );
+            CREATE INDEX IF NOT EXISTS idx_presence_server ON presence(server);
+            "#,
+        )?;
+
+        let sizes = conn
+            .prepare("SELECT DISTINCT bytes FROM blobs")?
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .map(|bytes| bytes.map(|bytes| bytes as u64))
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            sizes,
+        })
+    }
+
+    /// How many blobs are indexed.
+    pub fn len(&self) -> usize {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM blobs", [], |row| row.get::<_, i64>(0))
+            .map_or(0, |count| count as usize)
+    }
+
+    /// Whether no blobs are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// Whether any indexed blob is this many bytes, so a file of that size is worth hashing
+    /// whole to [look up](Self::get).
+    pub fn has_size(&self, bytes: u64) -> bool {
+        self.sizes.contains(&bytes)
+    }
+
+    /// An indexed blob, with its extents.
+    pub fn get(&self, blob_id: &B3Id) -> rusqlite::Result<Option<BlobInfo>> {
+        let conn = self.conn.lock().unwrap();
+        let Some(bytes) = conn
+            .query_row(
+                "SELECT bytes FROM blobs WHERE blob_id = ?1",
+                [blob_id.as_slice()],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT extent_id, offset, bytes, fs_extent, preallocated
+            FROM blob_extents WHERE blob_id = ?1 ORDER BY offset",
+        )?;
+        let extents = stmt
+            .query_map([blob_id.as_slice()], |row| {
+                let extent_id: Option<Vec<u8>> = row.get(0)?;
+                let preallocated: bool = row.get(4)?;
+                let mut range =
+                    DataRange::new(row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64);
+                // Sparse holes and preallocated ranges have no extent ID
+                let extent_id = match extent_id.and_then(|id| B3Id::try_from(id).ok()) {
+                    Some(id) => id,
+                    None => {
+                        range.hole = !preallocated;
+                        range.unwritten = preallocated;
+                        B3Id::from([0u8; 32])
+                    }
+                };
+                Ok(ExtentInfo {
+                    extent_id,
+                    range,
+                    fs_extent: row.get::<_, i64>(3)? as u32,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(BlobInfo {
+            blob_id: *blob_id,
+            bytes: bytes as u64,
+            extents,
+        }))
+    }
+
+    /// Record every blob of a catalog as being on `server`, returning how many there were.
+    pub fn record_catalog(
+        &mut self,
+        catalog: &Connection,
+        server: &str,
+    ) -> rusqlite::Result<usize> {
+        let conn = self.conn.get_mut().unwrap();
+        let tx = conn.transaction()?;
+        let mut recorded = 0;
+        {
+            let mut blob_stmt = tx.prepare(
+                "INSERT OR IGNORE INTO blobs (blob_id, bytes, extents) VALUES (?1, ?2, ?3)",
+            )?;
+            let mut extent_stmt = tx.prepare(
+                "INSERT OR IGNORE INTO blob_extents
+                    (blob_id, extent_id, offset, bytes, fs_extent, preallocated)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            let mut presence_stmt =
+                tx.prepare("INSERT OR IGNORE INTO presence (blob_id, server) VALUES (?1, ?2)")?;
+
+            let mut stmt = catalog.prepare("SELECT blob_id, bytes, extents FROM blobs")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let blob_id: Vec<u8> = row.get(0)?;
+                let bytes: i64 = row.get(1)?;
+                blob_stmt.execute(params![blob_id, bytes, row.get::<_, i64>(2)?])?;
+                presence_stmt.execute(params![blob_id, server])?;
+                self.sizes.insert(bytes as u64);
+                recorded += 1;
+            }
+
+            let mut stmt = catalog.prepare(
+                "SELECT blob_id, extent_id, offset, bytes, fs_extent, preallocated FROM blob_extents",
+            )?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                extent_stmt.execute(params![
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, Option<Vec<u8>>>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, bool>(5)?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(recorded)
+    }
+
+    /// IDs of the extents of blobs known to be on `server`.
+    pub fn extents_on(&self, server: &str) -> rusqlite::Result<HashSet<B3Id>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT be.extent_id
+            FROM blob_extents be
+            JOIN presence p ON p.blob_id = be.blob_id
+            WHERE p.server = ?1 AND be.extent_id IS NOT NULL",
+        )?;
+        let rows = stmt.query_map([server], |row| row.get::<_, Vec<u8>>(0))?;
+        let mut extents = HashSet::new();
+        for row in rows {
+            if let Ok(id) = B3Id::try_from(row?) {
+                extents.insert(id);
+            }
+        }
+        Ok(extents)
+    }
+
+    /// Stop taking the blobs with any of these extents to be on `server`, as when the
+    /// server says it's missing them. Returns how many blobs were forgotten.
+    pub fn forget_extents<'a>(
+        &mut self,
+        server: &str,
+        extent_ids: impl IntoIterator<Item = &'a B3Id>,
+    ) -> rusqlite::Result<usize> {
+        let conn = self.conn.get_mut().unwrap();
+        let tx = conn.transaction()?;
+        let mut forgotten = 0;
+        {
+            let mut stmt = tx.prepare(
+                "DELETE FROM presence WHERE server = ?1 AND blob_id IN (
+                    SELECT blob_id FROM blob_extents WHERE extent_id = ?2
+                )",
+            )?;
+            for extent_id in extent_ids {
+                forgotten += stmt.execute(params![server, extent_id.as_slice()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(forgotten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{create_catalog_schema, write_catalog};
+    use crate::file::FileInfo;
+
+    fn file(path: &str, blob: BlobInfo) -> FileInfo {
+        FileInfo {
+            relative_path: path.into(),
+            root: None,
+            blob: Some(blob),
+            ts_created: None,
+            ts_modified: None,
+            ts_accessed: None,
+            ts_changed: None,
+            unix_mode: None,
+            unix_owner_id: None,
+            unix_group_id: None,
+            fs_inode: None,
+            fs_change_cookie: None,
+            special: None,
+            priority: None,
+            attributes: None,
+            streams: Vec::new(),
+        }
+    }
+
+    fn blob(data: &[u8]) -> BlobInfo {
+        BlobInfo {
+            blob_id: B3Id::hash(data),
+            bytes: data.len() as u64 + 10,
+            extents: vec![
+                ExtentInfo {
+                    extent_id: B3Id::hash(data),
+                    range: DataRange::new(0, data.len() as u64),
+                    fs_extent: 1,
+                },
+                ExtentInfo {
+                    extent_id: B3Id::from([0u8; 32]),
+                    range: DataRange::hole(data.len() as u64, 10),
+                    fs_extent: 2,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn records_and_forgets_uploaded_blobs() {
+        let catalog = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&catalog).unwrap();
+        let (a, b) = (blob(b"first"), blob(b"second!"));
+        write_catalog(&catalog, &[file("a", a.clone()), file("b", b.clone())]).unwrap();
+
+        let mut index = BlobIndex::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        assert!(index.is_empty());
+        assert_eq!(index.record_catalog(&catalog, "http://one").unwrap(), 2);
+        assert_eq!(index.len(), 2);
+        assert!(index.has_size(a.bytes));
+        assert!(!index.has_size(1));
+
+        let found = index.get(&a.blob_id).unwrap().unwrap();
+        assert_eq!(found.bytes, a.bytes);
+        assert_eq!(found.extents.len(), 2);
+        assert_eq!(found.extents[0].extent_id, a.extents[0].extent_id);
+        assert!(found.extents[1].range.hole);
+        assert!(index.get(&B3Id::hash(b"other")).unwrap().is_none());
+
+        let on_one = index.extents_on("http://one").unwrap();
+        assert_eq!(
+            on_one,
+            HashSet::from([a.extents[0].extent_id, b.extents[0].extent_id])
+        );
+        assert!(index.extents_on("http://two").unwrap().is_empty());
+
+        assert_eq!(
+            index
+                .forget_extents("http://one", [&a.extents[0].extent_id])
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            index.extents_on("http://one").unwrap(),
+            HashSet::from([b.extents[0].extent_id])
+        );
+        // Layouts are kept for scans
+        assert!(index.get(&a.blob_id).unwrap().is_some());
+    }
+}
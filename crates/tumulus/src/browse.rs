@@ -0,0 +1,278 @@
+//! Navigating the file tree of a catalog.
+//!
+//! Catalogs list their entries by path. [`CatalogTree`] puts them back into a tree, with
+//! directory sizes added up, so a catalog can be browsed a directory at a time and paths
+//! picked out of it to restore.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::B3Id;
+use crate::file::FileInfo;
+
+/// A node of a [`CatalogTree`].
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    /// Last component of the path, empty for the root.
+    pub name: String,
+    /// Path in the catalog, empty for the root.
+    pub path: String,
+    pub parent: Option<usize>,
+    /// Children, directories first, then by name.
+    pub children: Vec<usize>,
+    /// Index of the node's catalog entry in [`CatalogTree::files`], if it has one: directories
+    /// the catalog only implies by having entries in them don't.
+    pub entry: Option<usize>,
+    pub is_dir: bool,
+    /// Size of a file's data, or of all the data under a directory.
+    pub bytes: u64,
+    /// How many entries other than directories are under a directory; 1 for others.
+    pub files: u64,
+}
+
+/// How much data is under a node, and how much of it is stored once deduplicated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Size of the data.
+    pub bytes: u64,
+    /// How many entries other than directories there are.
+    pub files: u64,
+    /// Size of the distinct extents the data is made of, leaving out holes.
+    pub stored_bytes: u64,
+}
+
+impl TreeStats {
+    /// How many times over the data is larger than what's stored for it.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            1.0
+        } else {
+            self.bytes as f64 / self.stored_bytes as f64
+        }
+    }
+}
+
+/// The entries of a catalog, as a tree of directories.
+#[derive(Debug, Clone)]
+pub struct CatalogTree {
+    files: Vec<FileInfo>,
+    nodes: Vec<TreeNode>,
+}
+
+impl CatalogTree {
+    /// The root node.
+    pub const ROOT: usize = 0;
+
+    /// Build the tree of a catalog's entries, as read with
+    /// [`read_catalog_files`](crate::read_catalog_files) (and decrypted, if need be).
+    pub fn new(files: Vec<FileInfo>) -> Self {
+        let mut tree = Self {
+            files: Vec::new(),
+            nodes: vec![TreeNode {
+                name: String::new(),
+                path: String::new(),
+                parent: None,
+                children: Vec::new(),
+                entry: None,
+                is_dir: true,
+                bytes: 0,
+                files: 0,
+            }],
+        };
+        let mut by_path = HashMap::from([(String::new(), Self::ROOT)]);
+
+        for (index, file) in files.iter().enumerate() {
+            let id = tree.node_for(&mut by_path, &file.relative_path);
+            let node = &mut tree.nodes[id];
+            node.entry = Some(index);
+            node.is_dir |= is_directory(file);
+            if !node.is_dir {
+                node.bytes = file.blob.as_ref().map_or(0, |blob| blob.bytes);
+                node.files = 1;
+            }
+        }
+        tree.files = files;
+
+        // Children come after their parents, so adding up backwards sees them first
+        for id in (1..tree.nodes.len()).rev() {
+            let (bytes, files) = (tree.nodes[id].bytes, tree.nodes[id].files);
+            if let Some(parent) = tree.nodes[id].parent {
+                tree.nodes[parent].bytes += bytes;
+                tree.nodes[parent].files += files;
+            }
+        }
+        for id in 0..tree.nodes.len() {
+            let mut children = std::mem::take(&mut tree.nodes[id].children);
+            children.sort_by(|&a, &b| {
+                let (a, b) = (&tree.nodes[a], &tree.nodes[b]);
+                b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name))
+            });
+            tree.nodes[id].children = children;
+        }
+        tree
+    }
+
+    /// Find or make the node for a path, and the directories above it.
+    fn node_for(&mut self, by_path: &mut HashMap<String, usize>, path: &str) -> usize {
+        if let Some(&id) = by_path.get(path) {
+            return id;
+        }
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (self.node_for(by_path, parent), name),
+            None => (Self::ROOT, path),
+        };
+        self.nodes[parent].is_dir = true;
+
+        let id = self.nodes.len();
+        self.nodes.push(TreeNode {
+            name: name.to_string(),
+            path: path.to_string(),
+            parent: Some(parent),
+            children: Vec::new(),
+            entry: None,
+            is_dir: false,
+            bytes: 0,
+            files: 0,
+        });
+        self.nodes[parent].children.push(id);
+        by_path.insert(path.to_string(), id);
+        id
+    }
+
+    /// The catalog's entries, in the order they were given.
+    pub fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    pub fn node(&self, id: usize) -> &TreeNode {
+        &self.nodes[id]
+    }
+
+    /// Find the node at a path in the catalog.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        let mut id = Self::ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            id = *self.nodes[id]
+                .children
+                .iter()
+                .find(|&&child| self.nodes[child].name == name)?;
+        }
+        Some(id)
+    }
+
+    /// Add up the data under a node, and what's stored for it.
+    ///
+    /// This goes through everything under the node, so is best done for one node at a time.
+    pub fn stats(&self, id: usize) -> TreeStats {
+        let mut extents: HashSet<B3Id> = HashSet::new();
+        let mut stored_bytes = 0;
+        for entry in self.entries_under([id]) {
+            for extent in entry.blob.iter().flat_map(|blob| &blob.extents) {
+                if !extent.range.is_zero() && extents.insert(extent.extent_id) {
+                    stored_bytes += extent.range.length;
+                }
+            }
+        }
+        let node = &self.nodes[id];
+        TreeStats {
+            bytes: node.bytes,
+            files: node.files,
+            stored_bytes,
+        }
+    }
+
+    /// The catalog entries of some nodes and everything under them, parents first.
+    pub fn entries_under(&self, ids: impl IntoIterator<Item = usize>) -> Vec<&FileInfo> {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<usize> = ids.into_iter().collect();
+        let mut entries = Vec::new();
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let node = &self.nodes[id];
+            entries.extend(node.entry.map(|entry| &self.files[entry]));
+            stack.extend(&node.children);
+        }
+        entries.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        entries
+    }
+}
+
+/// Whether a catalog entry is a directory.
+fn is_directory(file: &FileInfo) -> bool {
+    file.special
+        .as_ref()
+        .and_then(|special| special.get("type")?.as_str())
+        == Some("directory")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use walkdir::WalkDir;
+
+    use super::*;
+    use crate::file::process_file;
+
+    fn scan(root: &Path) -> Vec<FileInfo> {
+        WalkDir::new(root)
+            .into_iter()
+            .map(|entry| process_file(entry.unwrap().path(), root).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn adds_up_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub/deeper")).unwrap();
+        fs::write(dir.path().join("top"), b"0123456789").unwrap();
+        fs::write(dir.path().join("sub/file"), b"contents").unwrap();
+        fs::write(dir.path().join("sub/deeper/copy"), b"contents").unwrap();
+
+        let tree = CatalogTree::new(scan(dir.path()));
+        let root = tree.node(CatalogTree::ROOT);
+        assert_eq!((root.bytes, root.files), (26, 3));
+        let names: Vec<&str> = root
+            .children
+            .iter()
+            .map(|&id| tree.node(id).name.as_str())
+            .collect();
+        assert_eq!(names, ["sub", "top"]);
+
+        let sub = tree.find("sub").unwrap();
+        assert!(tree.node(sub).is_dir);
+        assert_eq!(
+            tree.stats(sub),
+            TreeStats {
+                bytes: 16,
+                files: 2,
+                stored_bytes: 8,
+            }
+        );
+        assert_eq!(tree.stats(sub).dedup_ratio(), 2.0);
+        assert_eq!(
+            tree.find("sub/deeper/copy").map(|id| tree.node(id).bytes),
+            Some(8)
+        );
+        assert_eq!(tree.find("sub/missing"), None);
+    }
+
+    #[test]
+    fn entries_under_marked_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), b"x").unwrap();
+        fs::write(dir.path().join("other"), b"y").unwrap();
+
+        let tree = CatalogTree::new(scan(dir.path()));
+        let marked = [tree.find("a").unwrap(), tree.find("a/b/file").unwrap()];
+        let paths: Vec<&str> = tree
+            .entries_under(marked)
+            .iter()
+            .map(|entry| entry.relative_path.as_str())
+            .collect();
+        assert_eq!(paths, ["a", "a/b", "a/b/file"]);
+    }
+}
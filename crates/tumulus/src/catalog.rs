@@ -1,12 +1,21 @@
 //! Catalog database schema and writing functionality.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::panic;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
 
 use rusqlite::{Connection, params};
+use tracing::debug;
+
+use extentria::DataRange;
 
 use crate::B3Id;
-use crate::extents::ExtentInfo;
-use crate::file::FileInfo;
+use crate::extents::{BlobInfo, ExtentInfo};
+use crate::file::{FileInfo, StreamInfo};
+use crate::tree::{SUBTREE_HASH_VERSION, TreeHashes};
 
 /// Statistics about the catalog after writing.
 #[derive(Debug, Clone)]
@@ -17,6 +26,7 @@ pub struct CatalogStats {
     pub total_bytes: i64,
     pub unique_bytes: i64,
     pub sparse_bytes: i64,
+    pub preallocated_bytes: i64,
 }
 
 impl CatalogStats {
@@ -70,6 +80,7 @@ pub fn create_catalog_schema(conn: &Connection) -> rusqlite::Result<()> {
             offset INTEGER NOT NULL,
             bytes INTEGER NOT NULL,
             fs_extent INTEGER NOT NULL,
+            preallocated INTEGER NOT NULL DEFAULT 0,
             PRIMARY KEY (blob_id, offset)
         );
         CREATE INDEX IF NOT EXISTS idx_blob_extents_blob ON blob_extents(blob_id);
@@ -91,6 +102,9 @@ pub fn create_catalog_schema(conn: &Connection) -> rusqlite::Result<()> {
             unix_group_name TEXT,
             special TEXT,
             fs_inode INTEGER,
+            fs_change_cookie INTEGER,
+            root TEXT,
+            priority INTEGER,
             extra TEXT
         );
         CREATE INDEX IF NOT EXISTS idx_files_path ON files(path);
@@ -99,6 +113,26 @@ pub fn create_catalog_schema(conn: &Connection) -> rusqlite::Result<()> {
         CREATE INDEX IF NOT EXISTS idx_files_ts_changed ON files(ts_changed);
         CREATE INDEX IF NOT EXISTS idx_files_ts_modified ON files(ts_modified);
         CREATE INDEX IF NOT EXISTS idx_files_ts_accessed ON files(ts_accessed);
+
+        CREATE TABLE IF NOT EXISTS trees (
+            path BLOB PRIMARY KEY,
+            tree_id BLOB NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_trees_tree ON trees(tree_id);
+
+        CREATE TABLE IF NOT EXISTS streams (
+            path BLOB NOT NULL,
+            name TEXT NOT NULL,
+            blob_id BLOB NOT NULL,
+            PRIMARY KEY (path, name)
+        );
+        CREATE INDEX IF NOT EXISTS idx_streams_blob ON streams(blob_id);
+
+        CREATE TABLE IF NOT EXISTS renames (
+            path BLOB PRIMARY KEY,
+            previous_path BLOB NOT NULL,
+            matched_by TEXT NOT NULL
+        );
         "#,
     )
 }
@@ -106,35 +140,48 @@ pub fn create_catalog_schema(conn: &Connection) -> rusqlite::Result<()> {
 /// Write file information to the catalog database.
 ///
 /// This handles deduplication of blobs and extents, and returns statistics
-/// about the written data.
+/// about the written data. The blobs of secondary streams are stored like file blobs.
 pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Result<CatalogStats> {
+    insert_files(conn, file_infos)?;
+    catalog_stats(conn)
+}
+
+/// Insert a batch of files in one transaction.
+///
+/// Blobs already in the catalog, from an earlier batch, are left as they are.
+fn insert_files(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Result<()> {
+    let all_blobs = || {
+        file_infos.iter().flat_map(|file_info| {
+            file_info
+                .blob
+                .iter()
+                .chain(file_info.streams.iter().map(|stream| &stream.blob))
+        })
+    };
+
     // Deduplicate blobs before inserting - only process each unique blob once
     // Also deduplicate extents within each blob by offset
     let mut seen_blobs: HashMap<B3Id, Vec<&ExtentInfo>> = HashMap::new();
-    for file_info in file_infos {
-        if let Some(ref blob) = file_info.blob {
-            seen_blobs.entry(blob.blob_id).or_insert_with(|| {
-                // Deduplicate extents by offset within this blob
-                let mut extents_by_offset: HashMap<u64, &ExtentInfo> = HashMap::new();
-                for extent in &blob.extents {
-                    extents_by_offset
-                        .entry(extent.range.offset)
-                        .or_insert(extent);
-                }
-                extents_by_offset.into_values().collect()
-            });
-        }
+    for blob in all_blobs() {
+        seen_blobs.entry(blob.blob_id).or_insert_with(|| {
+            // Deduplicate extents by offset within this blob
+            let mut extents_by_offset: HashMap<u64, &ExtentInfo> = HashMap::new();
+            for extent in &blob.extents {
+                extents_by_offset
+                    .entry(extent.range.offset)
+                    .or_insert(extent);
+            }
+            extents_by_offset.into_values().collect()
+        });
     }
 
     // Also collect blob metadata (bytes, extent count) separately
     let mut blob_metadata: HashMap<B3Id, (u64, usize)> = HashMap::new();
-    for file_info in file_infos {
-        if let Some(ref blob) = file_info.blob {
-            blob_metadata.entry(blob.blob_id).or_insert_with(|| {
-                let extent_count = seen_blobs.get(&blob.blob_id).map(|e| e.len()).unwrap_or(0);
-                (blob.bytes, extent_count)
-            });
-        }
+    for blob in all_blobs() {
+        blob_metadata.entry(blob.blob_id).or_insert_with(|| {
+            let extent_count = seen_blobs.get(&blob.blob_id).map(|e| e.len()).unwrap_or(0);
+            (blob.bytes, extent_count)
+        });
     }
 
     // Insert extents, blobs, blob_extents, and files
@@ -143,19 +190,19 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
     {
         let mut extent_stmt =
             tx.prepare("INSERT OR IGNORE INTO extents (extent_id, bytes) VALUES (?1, ?2)")?;
-        let mut blob_stmt =
-            tx.prepare("INSERT INTO blobs (blob_id, bytes, extents) VALUES (?1, ?2, ?3)")?;
+        let mut blob_stmt = tx
+            .prepare("INSERT OR IGNORE INTO blobs (blob_id, bytes, extents) VALUES (?1, ?2, ?3)")?;
         let mut blob_extent_stmt = tx.prepare(
-            "INSERT INTO blob_extents (blob_id, extent_id, offset, bytes, fs_extent) VALUES (?1, ?2, ?3, ?4, ?5)",
+            "INSERT INTO blob_extents (blob_id, extent_id, offset, bytes, fs_extent, preallocated) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
         )?;
 
         // Insert unique blobs and their extents
         for (blob_id, extents) in &seen_blobs {
             let (bytes, extent_count) = blob_metadata.get(blob_id).copied().unwrap_or((0, 0));
 
-            // Insert extents (skip sparse holes - they have no extent_id)
+            // Insert extents (skip sparse holes and preallocated ranges - they have no extent_id)
             for extent in extents {
-                if !extent.range.hole {
+                if !extent.range.is_zero() {
                     extent_stmt.execute(params![
                         extent.extent_id.as_slice(),
                         extent.range.length as i64
@@ -163,16 +210,19 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
                 }
             }
 
-            // Insert blob
-            blob_stmt.execute(params![
+            // Insert blob, unless an earlier batch had it
+            let inserted = blob_stmt.execute(params![
                 blob_id.as_slice(),
                 bytes as i64,
                 extent_count as i64
             ])?;
+            if inserted == 0 {
+                continue;
+            }
 
-            // Insert blob_extents (include sparse holes with null extent_id)
+            // Insert blob_extents (include sparse holes and preallocated ranges with null extent_id)
             for extent in extents {
-                let extent_id: Option<&[u8]> = if extent.range.hole {
+                let extent_id: Option<&[u8]> = if extent.range.is_zero() {
                     None
                 } else {
                     Some(extent.extent_id.as_slice())
@@ -182,7 +232,8 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
                     extent_id,
                     extent.range.offset as i64,
                     extent.range.length as i64,
-                    extent.fs_extent as i64
+                    extent.fs_extent as i64,
+                    extent.range.unwritten
                 ])?;
             }
         }
@@ -191,9 +242,12 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
         let mut file_stmt = tx.prepare(
             r#"INSERT INTO files (
                 path, blob_id, ts_created, ts_changed, ts_modified, ts_accessed,
-                unix_mode, unix_owner_id, unix_group_id, special, fs_inode
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"#,
+                unix_mode, unix_owner_id, unix_group_id, special, fs_inode, fs_change_cookie, root,
+                priority, attributes
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)"#,
         )?;
+        let mut stream_stmt =
+            tx.prepare("INSERT INTO streams (path, name, blob_id) VALUES (?1, ?2, ?3)")?;
 
         for file_info in file_infos {
             file_stmt.execute(params![
@@ -208,13 +262,26 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
                 file_info.unix_group_id,
                 file_info.special.as_ref().map(|v| v.to_string()),
                 file_info.fs_inode.map(|i| i as i64),
+                file_info.fs_change_cookie,
+                file_info.root,
+                file_info.priority,
+                file_info.attributes.as_ref().map(|v| v.to_string()),
             ])?;
+            for stream in &file_info.streams {
+                stream_stmt.execute(params![
+                    file_info.relative_path.as_bytes(),
+                    stream.name,
+                    stream.blob.blob_id.as_slice(),
+                ])?;
+            }
         }
     }
 
-    tx.commit()?;
+    tx.commit()
+}
 
-    // Calculate statistics using SQL
+/// Calculate statistics about the data written to a catalog.
+pub fn catalog_stats(conn: &Connection) -> rusqlite::Result<CatalogStats> {
     let file_count: i64 = conn.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
 
     let total_extents: i64 =
@@ -235,7 +302,13 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
         })?;
 
     let sparse_bytes: i64 = conn.query_row(
-        "SELECT COALESCE(SUM(bytes), 0) FROM blob_extents WHERE extent_id IS NULL",
+        "SELECT COALESCE(SUM(bytes), 0) FROM blob_extents WHERE extent_id IS NULL AND preallocated = 0",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let preallocated_bytes: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(bytes), 0) FROM blob_extents WHERE preallocated = 1",
         [],
         |row| row.get(0),
     )?;
@@ -247,5 +320,471 @@ pub fn write_catalog(conn: &Connection, file_infos: &[FileInfo]) -> rusqlite::Re
         total_bytes,
         unique_bytes,
         sparse_bytes,
+        preallocated_bytes,
     })
 }
+
+/// Writes files to a catalog from its own thread.
+///
+/// The writer owns the connection, and files are sent to it in batches, each written in
+/// its own transaction, so scanning can carry on while earlier files are written. At most
+/// `capacity` batches wait to be written: past that, [`write`](Self::write) blocks until
+/// the writer catches up, and [`pending`](Self::pending) says how far behind it is.
+pub struct CatalogWriter {
+    sender: Option<SyncSender<Vec<FileInfo>>>,
+    thread: Option<JoinHandle<rusqlite::Result<Connection>>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl CatalogWriter {
+    /// Start writing to a catalog, with up to `capacity` batches waiting.
+    ///
+    /// The schema must already have been created.
+    pub fn new(conn: Connection, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<FileInfo>>(capacity.max(1));
+        let pending = Arc::new(AtomicUsize::new(0));
+        let thread = thread::spawn({
+            let pending = pending.clone();
+            move || {
+                for batch in receiver {
+                    let result = insert_files(&conn, &batch);
+                    pending.fetch_sub(1, Ordering::Relaxed);
+                    result?;
+                }
+                Ok(conn)
+            }
+        });
+
+        Self {
+            sender: Some(sender),
+            thread: Some(thread),
+            pending,
+        }
+    }
+
+    /// Queue a batch of files to be written, waiting if too many already are.
+    ///
+    /// If writing an earlier batch failed, that error is returned, and nothing more can
+    /// be written.
+    pub fn write(&mut self, batch: Vec<FileInfo>) -> rusqlite::Result<()> {
+        let Some(ref sender) = self.sender else {
+            return Err(rusqlite::Error::InvalidQuery);
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let sent = match sender.try_send(batch) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(batch)) => {
+                debug!(
+                    pending = self.pending(),
+                    "Catalog writer is behind, waiting"
+                );
+                sender.send(batch).map_err(drop)
+            }
+            Err(TrySendError::Disconnected(_)) => Err(()),
+        };
+        if sent.is_err() {
+            // The writer stopped on an error, which is only known once it's joined
+            self.sender = None;
+            self.join()?;
+        }
+        Ok(())
+    }
+
+    /// How many batches are waiting to be written, or being written.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Wait for every batch to be written, and get the connection back with statistics
+    /// about the catalog.
+    pub fn finish(mut self) -> rusqlite::Result<(Connection, CatalogStats)> {
+        self.sender = None;
+        let conn = self.join()?;
+        let stats = catalog_stats(&conn)?;
+        Ok((conn, stats))
+    }
+
+    fn join(&mut self) -> rusqlite::Result<Connection> {
+        let thread = self.thread.take().ok_or(rusqlite::Error::InvalidQuery)?;
+        match thread.join() {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl Drop for CatalogWriter {
+    fn drop(&mut self) {
+        // Let the writer finish what it was sent, so the catalog isn't left half-written
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Write the per-directory subtree hashes to the catalog database.
+pub fn write_tree_hashes(conn: &Connection, hashes: &TreeHashes) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO trees (path, tree_id) VALUES (?1, ?2)")?;
+        for (path, tree_id) in &hashes.subtrees {
+            stmt.execute(params![path.as_bytes(), tree_id.as_slice()])?;
+        }
+    }
+    tx.commit()
+}
+
+/// The version of the subtree hashes in a catalog, if it has any.
+///
+/// `schema` is `main`, or the name of an attached catalog.
+pub fn subtree_hash_version(conn: &Connection, schema: &str) -> rusqlite::Result<Option<u32>> {
+    let version = conn.query_row(
+        &format!(
+            r#"SELECT json_extract(value, '$.version') FROM "{schema}".metadata WHERE key = 'trees'"#
+        ),
+        [],
+        |row| row.get(0),
+    );
+    match version {
+        Ok(version) => Ok(version),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Read the per-directory subtree hashes back out of a catalog database.
+///
+/// Catalogs without subtree hashes of the current [`SUBTREE_HASH_VERSION`] have none.
+pub fn read_tree_hashes(conn: &Connection) -> rusqlite::Result<BTreeMap<String, B3Id>> {
+    if subtree_hash_version(conn, "main")? != Some(SUBTREE_HASH_VERSION) {
+        return Ok(BTreeMap::new());
+    }
+
+    let mut stmt = conn.prepare("SELECT path, tree_id FROM trees")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+    let mut hashes = BTreeMap::new();
+    for row in rows {
+        let (path, tree_id) = row?;
+        if let (Ok(path), Ok(tree_id)) = (String::from_utf8(path), B3Id::try_from(tree_id)) {
+            hashes.insert(path, tree_id);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Read the file entries back out of a catalog database.
+///
+/// This is the inverse of [`write_catalog`]: each file comes back with its blob and extents,
+/// so the entries can be carried over into another catalog.
+pub fn read_catalog_files(conn: &Connection) -> rusqlite::Result<Vec<FileInfo>> {
+    let mut blobs: HashMap<Vec<u8>, BlobInfo> = HashMap::new();
+    {
+        let mut stmt = conn.prepare("SELECT blob_id, bytes FROM blobs")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for row in rows {
+            let (blob_id, bytes) = row?;
+            let Ok(id) = B3Id::try_from(blob_id.clone()) else {
+                continue;
+            };
+            blobs.insert(
+                blob_id,
+                BlobInfo {
+                    blob_id: id,
+                    bytes: bytes as u64,
+                    extents: Vec::new(),
+                },
+            );
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT blob_id, extent_id, offset, bytes, fs_extent, preallocated
+            FROM blob_extents ORDER BY blob_id, offset",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, Option<Vec<u8>>>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, bool>(5)?,
+            ))
+        })?;
+        for row in rows {
+            let (blob_id, extent_id, offset, bytes, fs_extent, preallocated) = row?;
+            let Some(blob) = blobs.get_mut(&blob_id) else {
+                continue;
+            };
+
+            // Sparse holes and preallocated ranges have no extent ID
+            let mut range = DataRange::new(offset as u64, bytes as u64);
+            let extent_id = match extent_id.and_then(|id| B3Id::try_from(id).ok()) {
+                Some(id) => id,
+                None => {
+                    range.hole = !preallocated;
+                    range.unwritten = preallocated;
+                    B3Id::from([0u8; 32])
+                }
+            };
+            blob.extents.push(ExtentInfo {
+                extent_id,
+                range,
+                fs_extent: fs_extent as u32,
+            });
+        }
+    }
+
+    // Catalogs may predate the streams table
+    let mut streams: HashMap<Vec<u8>, Vec<StreamInfo>> = HashMap::new();
+    let has_streams: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'streams'",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_streams {
+        let mut stmt = conn.prepare("SELECT path, name, blob_id FROM streams ORDER BY name")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Vec<u8>>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (path, name, blob_id) = row?;
+            if let Some(blob) = blobs.get(&blob_id) {
+                streams.entry(path).or_default().push(StreamInfo {
+                    name,
+                    blob: blob.clone(),
+                });
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        r#"SELECT
+            path, blob_id, ts_created, ts_changed, ts_modified, ts_accessed,
+            unix_mode, unix_owner_id, unix_group_id, special, fs_inode, fs_change_cookie, root,
+            priority, attributes
+        FROM files ORDER BY file_id"#,
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let path: Vec<u8> = row.get(0)?;
+        let blob_id: Option<Vec<u8>> = row.get(1)?;
+        let special: Option<String> = row.get(9)?;
+        let attributes: Option<String> = row.get(14)?;
+        Ok(FileInfo {
+            relative_path: String::from_utf8_lossy(&path).into_owned(),
+            root: row.get(12)?,
+            blob: blob_id.and_then(|id| blobs.get(&id).cloned()),
+            ts_created: row.get(2)?,
+            ts_changed: row.get(3)?,
+            ts_modified: row.get(4)?,
+            ts_accessed: row.get(5)?,
+            unix_mode: row.get(6)?,
+            unix_owner_id: row.get(7)?,
+            unix_group_id: row.get(8)?,
+            special: special.and_then(|s| serde_json::from_str(&s).ok()),
+            fs_inode: row.get::<_, Option<i64>>(10)?.map(|i| i as u64),
+            fs_change_cookie: row.get(11)?,
+            priority: row.get(13)?,
+            attributes: attributes.and_then(|s| serde_json::from_str(&s).ok()),
+            streams: streams.get(&path).cloned().unwrap_or_default(),
+        })
+    })?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_back_written_files() {
+        let extent = |byte: u8, offset, fs_extent| ExtentInfo {
+            extent_id: B3Id::from([byte; 32]),
+            range: DataRange::new(offset, 10),
+            fs_extent,
+        };
+        let mut hole = extent(0, 20, 2);
+        hole.range.hole = true;
+
+        let file = FileInfo {
+            relative_path: "dir/file".to_string(),
+            root: None,
+            blob: Some(BlobInfo {
+                blob_id: B3Id::from([9; 32]),
+                bytes: 30,
+                extents: vec![extent(1, 0, 0), extent(2, 10, 1), hole],
+            }),
+            ts_created: None,
+            ts_modified: Some(1_700_000_000_000),
+            ts_accessed: None,
+            ts_changed: None,
+            unix_mode: Some(0o100644),
+            unix_owner_id: Some(1000),
+            unix_group_id: Some(1000),
+            fs_inode: Some(42),
+            fs_change_cookie: None,
+            priority: Some(0),
+            special: None,
+            attributes: Some(serde_json::json!({ "xattrs": { "com.apple.FinderInfo": "AAEC" } })),
+            streams: vec![StreamInfo {
+                name: "rsrc".to_string(),
+                blob: BlobInfo {
+                    blob_id: B3Id::from([8; 32]),
+                    bytes: 10,
+                    extents: vec![extent(3, 0, 0)],
+                },
+            }],
+        };
+        let link = FileInfo {
+            relative_path: "dir/link".to_string(),
+            blob: None,
+            special: Some(serde_json::json!({ "type": "symlink", "target": "file" })),
+            attributes: None,
+            streams: Vec::new(),
+            ..file.clone()
+        };
+
+        let conn = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&conn).unwrap();
+        write_catalog(&conn, &[file.clone(), link.clone()]).unwrap();
+
+        let files = read_catalog_files(&conn).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].relative_path, file.relative_path);
+        assert_eq!(files[0].ts_modified, file.ts_modified);
+        assert_eq!(files[0].fs_inode, file.fs_inode);
+        assert_eq!(files[0].priority, Some(0));
+        assert_eq!(files[0].attributes, file.attributes);
+        assert_eq!(files[0].streams.len(), 1);
+        assert_eq!(files[0].streams[0].name, "rsrc");
+        assert_eq!(files[0].streams[0].blob.blob_id, B3Id::from([8; 32]));
+        assert_eq!(
+            files[0].streams[0].blob.extents[0].extent_id,
+            B3Id::from([3; 32])
+        );
+        assert!(files[1].streams.is_empty());
+        assert_eq!(files[1].special, link.special);
+        assert!(files[1].blob.is_none());
+
+        let blob = files[0].blob.as_ref().unwrap();
+        assert_eq!(blob.blob_id, B3Id::from([9; 32]));
+        assert_eq!(blob.bytes, 30);
+        let ranges: Vec<_> = blob.extents.iter().map(|e| e.range).collect();
+        let expected: Vec<_> = file.blob.unwrap().extents.iter().map(|e| e.range).collect();
+        assert_eq!(ranges, expected);
+        assert_eq!(blob.extents[1].extent_id, B3Id::from([2; 32]));
+    }
+
+    fn file_with_blob(path: &str, blob_byte: u8, extent_byte: u8) -> FileInfo {
+        FileInfo {
+            relative_path: path.to_string(),
+            root: None,
+            blob: Some(BlobInfo {
+                blob_id: B3Id::from([blob_byte; 32]),
+                bytes: 10,
+                extents: vec![ExtentInfo {
+                    extent_id: B3Id::from([extent_byte; 32]),
+                    range: DataRange::new(0, 10),
+                    fs_extent: 0,
+                }],
+            }),
+            ts_created: None,
+            ts_modified: None,
+            ts_accessed: None,
+            ts_changed: None,
+            unix_mode: None,
+            unix_owner_id: None,
+            unix_group_id: None,
+            fs_inode: None,
+            fs_change_cookie: None,
+            priority: None,
+            special: None,
+            attributes: None,
+            streams: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn read_back_tree_hashes_of_current_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&conn).unwrap();
+        let hashes = TreeHashes {
+            root: B3Id::from([1; 32]),
+            subtrees: [("", 1), ("a", 2), ("a/b", 3)]
+                .into_iter()
+                .map(|(path, byte)| (path.to_string(), B3Id::from([byte; 32])))
+                .collect(),
+        };
+        write_tree_hashes(&conn, &hashes).unwrap();
+
+        // Without a version, they're from before subtree hashes were versioned
+        assert!(read_tree_hashes(&conn).unwrap().is_empty());
+
+        conn.execute(
+            "INSERT INTO metadata (key, value) VALUES ('trees', ?1)",
+            [format!(r#"{{"version":{SUBTREE_HASH_VERSION}}}"#)],
+        )
+        .unwrap();
+        assert_eq!(
+            subtree_hash_version(&conn, "main").unwrap(),
+            Some(SUBTREE_HASH_VERSION)
+        );
+        assert_eq!(read_tree_hashes(&conn).unwrap(), hashes.subtrees);
+    }
+
+    #[test]
+    fn writer_matches_single_write() {
+        let files = vec![
+            file_with_blob("a", 1, 1),
+            file_with_blob("b", 2, 2),
+            file_with_blob("c", 1, 1),
+            file_with_blob("d", 3, 2),
+        ];
+
+        let conn = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&conn).unwrap();
+        let expected = write_catalog(&conn, &files).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&conn).unwrap();
+        let mut writer = CatalogWriter::new(conn, 1);
+        // The same blob in two batches is only stored once
+        for batch in files.chunks(2) {
+            writer.write(batch.to_vec()).unwrap();
+        }
+        let (conn, stats) = writer.finish().unwrap();
+
+        assert_eq!(stats.file_count, expected.file_count);
+        assert_eq!(stats.total_extents, expected.total_extents);
+        assert_eq!(stats.unique_extent_count, expected.unique_extent_count);
+        assert_eq!(stats.total_bytes, expected.total_bytes);
+        assert_eq!(stats.unique_bytes, expected.unique_bytes);
+        let paths: Vec<_> = read_catalog_files(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.relative_path)
+            .collect();
+        assert_eq!(paths, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn writer_reports_failed_batches() {
+        // No schema, so the first batch fails
+        let conn = Connection::open_in_memory().unwrap();
+        let mut writer = CatalogWriter::new(conn, 1);
+        writer.write(vec![file_with_blob("a", 1, 1)]).unwrap();
+        assert!(writer.finish().is_err());
+    }
+}
@@ -1,915 +0,0 @@
-//! Upload catalogs to a tumulus server.
-//!
-//! This command takes a catalog file, verifies it matches the local machine,
-//! and uploads it to a tumulus server.
-//!
-//! Supports delta uploads using `--reference` to specify previous catalog files.
-//! When references are provided and the server knows one of them, a binary patch
-//! is generated and uploaded instead of the full catalog.
-
-use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom, Write},
-    path::{Path, PathBuf},
-    sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
-    },
-};
-
-use clap::Args;
-use rayon::prelude::*;
-use reqwest::blocking::Client;
-use rusqlite::Connection;
-use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info, warn};
-use uuid::Uuid;
-
-use tumulus::{decompress_file, is_zstd_compressed, open_catalog};
-
-/// Upload a catalog to a tumulus server
-#[derive(Args, Debug)]
-pub struct UploadArgs {
-    /// Path to the catalog file to upload
-    catalog: PathBuf,
-
-    /// Server URL (e.g., http://localhost:3000)
-    #[arg(long, short)]
-    server: String,
-
-    /// Skip machine ID verification
-    #[arg(long)]
-    skip_machine_check: bool,
-
-    /// Override the source path from the catalog with a different path
-    #[arg(long)]
-    override_source: Option<PathBuf>,
-
-    /// Number of parallel upload threads (default: 32)
-    #[arg(long, short = 'j', default_value = "32")]
-    parallel: usize,
-
-    /// Reference catalogs to use for delta uploads.
-    /// When provided, the tool will check if the server knows any of these catalogs
-    /// and use the most recent one to generate a binary patch instead of uploading
-    /// the full catalog.
-    #[arg(long, short = 'r')]
-    reference: Vec<PathBuf>,
-}
-
-/// Request body for initiating a catalog upload.
-#[derive(Debug, Serialize)]
-struct InitiateRequest {
-    id: Uuid,
-    checksum: String,
-}
-
-/// Response from initiating a catalog upload.
-#[derive(Debug, Deserialize)]
-struct InitiateResponse {
-    id: String,
-    resuming: bool,
-    #[serde(default)]
-    missing_extents: Option<Vec<String>>,
-}
-
-/// Response from uploading a catalog.
-#[derive(Debug, Deserialize)]
-struct UploadResponse {
-    missing_extents: Vec<String>,
-}
-
-/// Request body for checking catalog existence.
-#[derive(Debug, Serialize)]
-struct CheckCatalogsRequest {
-    ids: Vec<String>,
-}
-
-/// Response from checking catalog existence.
-/// Returns catalog IDs sorted by preference (best choice first).
-#[derive(Debug, Deserialize)]
-struct CheckCatalogsResponse {
-    existing: Vec<String>,
-}
-
-/// Metadata about a reference catalog on disk.
-#[derive(Debug, Clone)]
-struct ReferenceCatalogInfo {
-    path: PathBuf,
-    id: Uuid,
-}
-
-/// Response from finalizing a catalog.
-#[derive(Debug, Deserialize)]
-struct FinalizeResponse {
-    complete: bool,
-    #[serde(default)]
-    missing_extents: Option<Vec<String>>,
-}
-
-/// Error response from the server.
-#[derive(Debug, Deserialize)]
-struct ErrorResponse {
-    error: String,
-    #[serde(default)]
-    detail: Option<String>,
-}
-
-#[derive(Debug, thiserror::Error)]
-enum UploadError {
-    #[error("Failed to open catalog: {0}")]
-    OpenCatalog(String),
-
-    #[error("Machine ID mismatch: catalog is for '{catalog}', but this machine is '{local}'")]
-    MachineIdMismatch { catalog: String, local: String },
-
-    #[error("Source path does not exist: {0}")]
-    SourcePathNotFound(PathBuf),
-
-    #[error("Missing metadata in catalog: {0}")]
-    MissingMetadata(String),
-
-    #[error("Invalid metadata value: {0}")]
-    InvalidMetadata(String),
-
-    #[error("HTTP error: {0}")]
-    Http(#[from] reqwest::Error),
-
-    #[error("Server error: {error}{}", detail.as_ref().map(|d| format!(" - {}", d)).unwrap_or_default())]
-    Server {
-        error: String,
-        detail: Option<String>,
-    },
-
-    #[error("I/O error: {0}")]
-    Io(#[from] std::io::Error),
-
-    #[error("Database error: {0}")]
-    Database(#[from] rusqlite::Error),
-
-    #[error(
-        "Catalog ID changed by server from {original} to {new}. Please update the catalog and retry."
-    )]
-    IdChanged { original: Uuid, new: Uuid },
-
-    #[error(
-        "Extent {extent_id} has changed on disk: expected hash {expected}, got {actual}. Aborting upload."
-    )]
-    ExtentChanged {
-        extent_id: String,
-        expected: String,
-        actual: String,
-    },
-
-    #[error("Extent {extent_id} not found in catalog")]
-    ExtentNotInCatalog { extent_id: String },
-
-    #[error("File not found for extent {extent_id}: {path}")]
-    FileNotFound { extent_id: String, path: PathBuf },
-
-    #[error("Failed to read reference catalog: {0}")]
-    ReferenceCatalog(String),
-
-    #[error("Binary diff error: {0}")]
-    BinaryDiff(String),
-}
-
-/// Metadata extracted from the catalog.
-struct CatalogMetadata {
-    id: Uuid,
-    machine_id: String,
-    source_path: Option<PathBuf>,
-}
-
-/// Information about where to find an extent on disk.
-#[derive(Debug, Clone)]
-struct ExtentLocation {
-    /// Relative path to the file containing this extent
-    file_path: String,
-    /// Offset within the file where the extent starts
-    offset: u64,
-    /// Length of the extent in bytes
-    length: u64,
-}
-
-pub fn run(args: UploadArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if let Err(e) = run_inner(args) {
-        error!("{}", e);
-        std::process::exit(1);
-    }
-
-    Ok(())
-}
-
-fn run_inner(args: UploadArgs) -> Result<(), UploadError> {
-    info!(catalog = ?args.catalog, server = %args.server, "Starting catalog upload");
-
-    // Open and read catalog metadata
-    let (conn, _tempfile) =
-        open_catalog(&args.catalog).map_err(|e| UploadError::OpenCatalog(e.to_string()))?;
-
-    let metadata = read_catalog_metadata(&conn)?;
-    info!(
-        catalog_id = %metadata.id,
-        machine_id = %metadata.machine_id,
-        source_path = ?metadata.source_path,
-        "Read catalog metadata"
-    );
-
-    // Verify machine ID matches
-    if !args.skip_machine_check {
-        let local_machine_id = tumulus::get_machine_id()
-            .map_err(|e| UploadError::OpenCatalog(format!("Failed to get machine ID: {}", e)))?;
-
-        if metadata.machine_id != local_machine_id {
-            return Err(UploadError::MachineIdMismatch {
-                catalog: metadata.machine_id,
-                local: local_machine_id,
-            });
-        }
-        debug!("Machine ID verified");
-    } else {
-        warn!("Skipping machine ID verification");
-    }
-
-    // Determine the source path to use
-    let source_path = if let Some(ref override_path) = args.override_source {
-        info!(
-            catalog_path = ?metadata.source_path,
-            override_path = ?override_path,
-            "Using overridden source path"
-        );
-        override_path.clone()
-    } else if let Some(ref catalog_path) = metadata.source_path {
-        catalog_path.clone()
-    } else {
-        return Err(UploadError::MissingMetadata(
-            "source_path (use --override-source to specify one)".to_string(),
-        ));
-    };
-
-    // Verify source path exists
-    if !source_path.exists() {
-        return Err(UploadError::SourcePathNotFound(source_path));
-    }
-    debug!(path = ?source_path, "Source path verified");
-
-    // Build extent location map from catalog
-    let extent_locations = build_extent_location_map(&conn)?;
-    info!(
-        extent_count = extent_locations.len(),
-        "Built extent location map"
-    );
-
-    // Configure thread pool for parallel uploads
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(args.parallel)
-        .build_global()
-        .ok(); // Ignore error if pool already initialized
-    info!(
-        threads = args.parallel,
-        "Configured parallel upload threads"
-    );
-
-    // Compute checksum of the catalog file
-    let catalog_data = fs::read(&args.catalog)?;
-    let checksum = blake3::hash(&catalog_data);
-    let checksum_hex = checksum.to_hex().to_string();
-    info!(checksum = %checksum_hex, size = catalog_data.len(), "Computed catalog checksum");
-
-    // Create HTTP client
-    let client = Client::new();
-    let server_url = args.server.trim_end_matches('/');
-
-    // Step 1: Initiate upload
-    info!("Initiating upload with server");
-    let initiate_resp = initiate_upload(&client, server_url, metadata.id, &checksum_hex)?;
-
-    // Check if server assigned a different ID
-    let server_id = Uuid::parse_str(&initiate_resp.id).map_err(|_| {
-        UploadError::InvalidMetadata(format!("Invalid UUID from server: {}", initiate_resp.id))
-    })?;
-
-    if server_id != metadata.id {
-        return Err(UploadError::IdChanged {
-            original: metadata.id,
-            new: server_id,
-        });
-    }
-
-    let missing_extents = if initiate_resp.resuming {
-        info!(
-            missing_count = initiate_resp
-                .missing_extents
-                .as_ref()
-                .map(|v| v.len())
-                .unwrap_or(0),
-            "Resuming existing upload"
-        );
-        initiate_resp.missing_extents.unwrap_or_default()
-    } else {
-        // Check if we should try delta upload with reference catalogs
-        let delta_result = if !args.reference.is_empty() {
-            try_delta_upload(
-                &client,
-                server_url,
-                server_id,
-                &args.catalog,
-                &args.reference,
-            )?
-        } else {
-            None
-        };
-
-        if let Some(upload_resp) = delta_result {
-            // Delta upload succeeded
-            info!(
-                missing_count = upload_resp.missing_extents.len(),
-                "Catalog uploaded via delta patch"
-            );
-            upload_resp.missing_extents
-        } else {
-            // Step 2: Upload the catalog data (full upload)
-            info!("Uploading catalog data");
-            let upload_resp = upload_catalog(&client, server_url, server_id, &catalog_data)?;
-            info!(
-                missing_count = upload_resp.missing_extents.len(),
-                "Catalog uploaded"
-            );
-            upload_resp.missing_extents
-        }
-    };
-
-    // Step 3 & 4: Upload extents and finalize in a loop until complete
-    let mut current_missing = missing_extents;
-    let mut attempt = 0;
-
-    loop {
-        attempt += 1;
-
-        // Upload missing extents
-        if !current_missing.is_empty() {
-            info!(
-                attempt,
-                count = current_missing.len(),
-                "Uploading missing extents"
-            );
-
-            upload_extents(
-                &client,
-                server_url,
-                &current_missing,
-                &extent_locations,
-                &source_path,
-            )?;
-
-            info!(
-                attempt,
-                count = current_missing.len(),
-                "Finished uploading extents"
-            );
-        }
-
-        // Try to finalize
-        info!(attempt, "Finalizing upload");
-        let finalize_resp = finalize_upload(&client, server_url, server_id)?;
-
-        match finalize_resp {
-            None => {
-                // 204 No Content - success!
-                break;
-            }
-            Some(resp) if resp.complete => {
-                // Explicitly complete
-                break;
-            }
-            Some(resp) => {
-                // Not complete, get the new list of missing extents
-                current_missing = resp.missing_extents.unwrap_or_default();
-                warn!(
-                    attempt,
-                    missing_count = current_missing.len(),
-                    "Finalization reported missing extents, continuing upload"
-                );
-
-                if current_missing.is_empty() {
-                    // Server said not complete but no missing extents? Weird, but treat as done
-                    warn!(
-                        "Server reported incomplete but no missing extents, treating as complete"
-                    );
-                    break;
-                }
-            }
-        }
-    }
-
-    info!(catalog_id = %server_id, "Upload complete!");
-    Ok(())
-}
-
-/// Try to upload the catalog using a delta patch against a reference catalog.
-/// Returns Some(UploadResponse) if successful, None if no suitable reference was found.
-fn try_delta_upload(
-    client: &Client,
-    server_url: &str,
-    catalog_id: Uuid,
-    target_catalog: &Path,
-    reference_paths: &[PathBuf],
-) -> Result<Option<UploadResponse>, UploadError> {
-    // Read metadata from each reference catalog
-    let mut reference_infos = Vec::new();
-    for path in reference_paths {
-        match read_reference_catalog_info(path) {
-            Ok(info) => {
-                debug!(path = ?path, id = %info.id, "Found reference catalog");
-                reference_infos.push(info);
-            }
-            Err(e) => {
-                warn!(path = ?path, error = %e, "Failed to read reference catalog, skipping");
-            }
-        }
-    }
-
-    if reference_infos.is_empty() {
-        info!("No valid reference catalogs found, falling back to full upload");
-        return Ok(None);
-    }
-
-    // Ask the server which of these catalogs it knows about
-    let check_req = CheckCatalogsRequest {
-        ids: reference_infos
-            .iter()
-            .map(|r| r.id.simple().to_string())
-            .collect(),
-    };
-
-    let url = format!("{}/catalogs/check", server_url);
-    let resp = client.post(&url).json(&check_req).send()?;
-
-    if !resp.status().is_success() {
-        warn!("Server doesn't support catalog check endpoint, falling back to full upload");
-        return Ok(None);
-    }
-
-    let check_resp: CheckCatalogsResponse = resp.json()?;
-
-    if check_resp.existing.is_empty() {
-        info!("Server doesn't have any of the reference catalogs, falling back to full upload");
-        return Ok(None);
-    }
-
-    // Server returns IDs sorted by preference (best first), so use the first one we have locally
-    let best_reference = check_resp.existing.iter().find_map(|server_id| {
-        reference_infos
-            .iter()
-            .find(|r| r.id.simple().to_string().to_lowercase() == server_id.to_lowercase())
-    });
-
-    let best_reference = match best_reference {
-        Some(r) => r,
-        None => {
-            info!("No matching reference catalog found on server, falling back to full upload");
-            return Ok(None);
-        }
-    };
-
-    info!(
-        reference_id = %best_reference.id,
-        reference_path = ?best_reference.path,
-        "Using reference catalog for delta upload"
-    );
-
-    // Decompress both catalogs to get raw SQLite data
-    let target_data = decompress_catalog_data(target_catalog)?;
-    let reference_data = decompress_catalog_data(&best_reference.path)?;
-
-    info!(
-        target_size = target_data.len(),
-        reference_size = reference_data.len(),
-        "Decompressed catalogs for diff"
-    );
-
-    // Read the compressed catalog size for comparison
-    let compressed_catalog_size = fs::metadata(target_catalog)?.len();
-
-    // Generate binary diff using qbsdiff
-    let mut patch_data = Vec::new();
-    qbsdiff::Bsdiff::new(&reference_data, &target_data)
-        .compare(&mut patch_data)
-        .map_err(|e| UploadError::BinaryDiff(format!("Failed to generate diff: {}", e)))?;
-
-    info!(patch_size = patch_data.len(), "Generated binary patch");
-
-    // Compress the patch
-    let mut compressed_patch = Vec::new();
-    {
-        let mut encoder =
-            zstd::stream::Encoder::new(&mut compressed_patch, 19).map_err(UploadError::Io)?;
-        encoder.write_all(&patch_data).map_err(UploadError::Io)?;
-        encoder.finish().map_err(UploadError::Io)?;
-    }
-
-    let savings = compressed_catalog_size as f64 - compressed_patch.len() as f64;
-    let savings_pct = (savings / compressed_catalog_size as f64) * 100.0;
-
-    debug!(
-        compressed_patch_size = compressed_patch.len(),
-        compressed_catalog_size = compressed_catalog_size,
-        savings_bytes = savings as i64,
-        savings_pct = format!("{:.1}%", savings_pct),
-        "Delta upload: patch vs full catalog"
-    );
-
-    // Compute checksum of the decompressed target (what the patch reconstructs)
-    let target_checksum = blake3::hash(&target_data).to_hex().to_string();
-
-    // Upload via patch endpoint
-    let url = format!(
-        "{}/catalogs/{}/patch?reference={}&checksum={}",
-        server_url,
-        catalog_id.simple(),
-        best_reference.id.simple(),
-        target_checksum
-    );
-
-    let resp = client
-        .put(&url)
-        .header("Content-Type", "application/octet-stream")
-        .body(compressed_patch)
-        .send()?;
-
-    if !resp.status().is_success() {
-        let error_resp: ErrorResponse = resp.json()?;
-        return Err(UploadError::Server {
-            error: error_resp.error,
-            detail: error_resp.detail,
-        });
-    }
-
-    let upload_resp: UploadResponse = resp.json()?;
-    Ok(Some(upload_resp))
-}
-
-/// Read metadata from a reference catalog file.
-fn read_reference_catalog_info(path: &Path) -> Result<ReferenceCatalogInfo, UploadError> {
-    let (conn, _tempfile) = open_catalog(path).map_err(|e| {
-        UploadError::ReferenceCatalog(format!("Failed to open {}: {}", path.display(), e))
-    })?;
-
-    // Read catalog ID
-    let id_str: String = conn
-        .query_row("SELECT value FROM metadata WHERE key = 'id'", [], |row| {
-            row.get(0)
-        })
-        .map_err(|_| UploadError::ReferenceCatalog(format!("Missing id in {}", path.display())))?;
-
-    let id_str: String = serde_json::from_str(&id_str)
-        .map_err(|_| UploadError::ReferenceCatalog(format!("Invalid id in {}", path.display())))?;
-
-    let id = Uuid::parse_str(&id_str).map_err(|_| {
-        UploadError::ReferenceCatalog(format!("Invalid UUID in {}", path.display()))
-    })?;
-
-    Ok(ReferenceCatalogInfo {
-        path: path.to_path_buf(),
-        id,
-    })
-}
-
-/// Decompress a catalog file and return the raw SQLite data.
-fn decompress_catalog_data(path: &Path) -> Result<Vec<u8>, UploadError> {
-    if is_zstd_compressed(path)? {
-        // Decompress to a temp file and read it
-        let temp_file = tempfile::NamedTempFile::new()?;
-        decompress_file(path, temp_file.path())?;
-        Ok(fs::read(temp_file.path())?)
-    } else {
-        // Already uncompressed
-        Ok(fs::read(path)?)
-    }
-}
-
-fn read_catalog_metadata(conn: &Connection) -> Result<CatalogMetadata, UploadError> {
-    // Read catalog ID
-    let id_str: String = conn
-        .query_row("SELECT value FROM metadata WHERE key = 'id'", [], |row| {
-            row.get(0)
-        })
-        .map_err(|_| UploadError::MissingMetadata("id".to_string()))?;
-
-    // Parse the JSON string value
-    let id_str: String = serde_json::from_str(&id_str)
-        .map_err(|_| UploadError::InvalidMetadata(format!("Invalid id value: {}", id_str)))?;
-
-    let id = Uuid::parse_str(&id_str)
-        .map_err(|_| UploadError::InvalidMetadata(format!("Invalid UUID: {}", id_str)))?;
-
-    // Read machine ID
-    let machine_str: String = conn
-        .query_row(
-            "SELECT value FROM metadata WHERE key = 'machine'",
-            [],
-            |row| row.get(0),
-        )
-        .map_err(|_| UploadError::MissingMetadata("machine".to_string()))?;
-
-    let machine_id: String = serde_json::from_str(&machine_str).map_err(|_| {
-        UploadError::InvalidMetadata(format!("Invalid machine value: {}", machine_str))
-    })?;
-
-    // Read source path (optional)
-    let source_path: Option<PathBuf> = conn
-        .query_row(
-            "SELECT value FROM metadata WHERE key = 'source_path'",
-            [],
-            |row| row.get::<_, String>(0),
-        )
-        .ok()
-        .and_then(|s| serde_json::from_str::<String>(&s).ok())
-        .map(PathBuf::from);
-
-    Ok(CatalogMetadata {
-        id,
-        machine_id,
-        source_path,
-    })
-}
-
-/// Build a map from extent ID (hex) to its location on disk.
-///
-/// This queries the catalog to find all extents and which files contain them.
-fn build_extent_location_map(
-    conn: &Connection,
-) -> Result<HashMap<String, ExtentLocation>, UploadError> {
-    let mut map = HashMap::new();
-
-    // Query to find extent locations:
-    // For each extent, find a file that contains it via:
-    // files.blob_id -> blob_extents.blob_id -> blob_extents.extent_id
-    let mut stmt = conn.prepare(
-        r#"
-        SELECT
-            hex(be.extent_id) as extent_id,
-            f.path,
-            be.offset,
-            be.bytes
-        FROM blob_extents be
-        JOIN files f ON f.blob_id = be.blob_id
-        WHERE be.extent_id IS NOT NULL
-        "#,
-    )?;
-
-    let rows = stmt.query_map([], |row| {
-        let extent_id: String = row.get(0)?;
-        let path_bytes: Vec<u8> = row.get(1)?;
-        let offset: i64 = row.get(2)?;
-        let bytes: i64 = row.get(3)?;
-
-        Ok((extent_id, path_bytes, offset as u64, bytes as u64))
-    })?;
-
-    for row in rows {
-        let (extent_id, path_bytes, offset, length) = row?;
-
-        // Convert path bytes to string
-        let file_path = String::from_utf8_lossy(&path_bytes).to_string();
-
-        // Only insert if we don't already have this extent
-        // (multiple files might reference the same extent due to dedup)
-        map.entry(extent_id.to_lowercase())
-            .or_insert(ExtentLocation {
-                file_path,
-                offset,
-                length,
-            });
-    }
-
-    Ok(map)
-}
-
-fn initiate_upload(
-    client: &Client,
-    server_url: &str,
-    catalog_id: Uuid,
-    checksum: &str,
-) -> Result<InitiateResponse, UploadError> {
-    let url = format!("{}/catalogs", server_url);
-    let req = InitiateRequest {
-        id: catalog_id,
-        checksum: checksum.to_string(),
-    };
-
-    let resp = client.post(&url).json(&req).send()?;
-
-    if !resp.status().is_success() && resp.status().as_u16() != 303 {
-        let error_resp: ErrorResponse = resp.json()?;
-        return Err(UploadError::Server {
-            error: error_resp.error,
-            detail: error_resp.detail,
-        });
-    }
-
-    let initiate_resp: InitiateResponse = resp.json()?;
-    Ok(initiate_resp)
-}
-
-fn upload_catalog(
-    client: &Client,
-    server_url: &str,
-    catalog_id: Uuid,
-    data: &[u8],
-) -> Result<UploadResponse, UploadError> {
-    let url = format!("{}/catalogs/{}", server_url, catalog_id.simple());
-
-    let resp = client
-        .put(&url)
-        .header("Content-Type", "application/octet-stream")
-        .body(data.to_vec())
-        .send()?;
-
-    if !resp.status().is_success() {
-        let error_resp: ErrorResponse = resp.json()?;
-        return Err(UploadError::Server {
-            error: error_resp.error,
-            detail: error_resp.detail,
-        });
-    }
-
-    let upload_resp: UploadResponse = resp.json()?;
-    Ok(upload_resp)
-}
-
-/// Upload a list of extents to the server in parallel.
-///
-/// For each extent:
-/// 1. Look up its location in the catalog
-/// 2. Read from the source file at the specified offset
-/// 3. Compute BLAKE3 hash while reading
-/// 4. If hash doesn't match, abort the entire upload
-/// 5. Stream data to server
-fn upload_extents(
-    client: &Client,
-    server_url: &str,
-    extent_ids: &[String],
-    extent_locations: &HashMap<String, ExtentLocation>,
-    source_path: &Path,
-) -> Result<(), UploadError> {
-    let total = extent_ids.len();
-    let completed = Arc::new(AtomicUsize::new(0));
-    let last_logged = Arc::new(AtomicUsize::new(0));
-
-    // Use rayon to upload extents in parallel
-    // The reqwest Client is Clone and uses an internal connection pool
-    extent_ids
-        .par_iter()
-        .try_for_each(|extent_id_hex| -> Result<(), UploadError> {
-            let extent_id_lower = extent_id_hex.to_lowercase();
-
-            // Find the extent location in our map
-            let location = extent_locations.get(&extent_id_lower).ok_or_else(|| {
-                UploadError::ExtentNotInCatalog {
-                    extent_id: extent_id_hex.clone(),
-                }
-            })?;
-
-            debug!(
-                extent = %extent_id_hex,
-                file = %location.file_path,
-                offset = location.offset,
-                length = location.length,
-                "Uploading extent"
-            );
-
-            // Construct full path to the file
-            let file_path = source_path.join(&location.file_path);
-
-            if !file_path.exists() {
-                return Err(UploadError::FileNotFound {
-                    extent_id: extent_id_hex.clone(),
-                    path: file_path,
-                });
-            }
-
-            // Read the extent data and compute hash
-            let extent_data = read_extent_with_hash_check(
-                &file_path,
-                location.offset,
-                location.length,
-                extent_id_hex,
-            )?;
-
-            // Use the shared client - it has an internal connection pool
-            upload_extent(client, server_url, extent_id_hex, &extent_data)?;
-
-            // Update progress
-            let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
-
-            // Log progress every 100 extents or at completion
-            // Use compare_exchange to avoid duplicate logs from multiple threads
-            let last = last_logged.load(Ordering::Relaxed);
-            if done == total
-                || (done >= last + 100
-                    && last_logged
-                        .compare_exchange(last, done, Ordering::Relaxed, Ordering::Relaxed)
-                        .is_ok())
-            {
-                info!(
-                    progress = format!("{}/{}", done, total),
-                    "Extent upload progress"
-                );
-            }
-
-            Ok(())
-        })?;
-
-    Ok(())
-}
-
-/// Read extent data from a file and verify the hash matches.
-///
-/// Returns the extent data if the hash matches, or an error if it doesn't.
-fn read_extent_with_hash_check(
-    file_path: &Path,
-    offset: u64,
-    length: u64,
-    expected_hash_hex: &str,
-) -> Result<Vec<u8>, UploadError> {
-    let mut file = File::open(file_path)?;
-
-    // Seek to the extent offset
-    file.seek(SeekFrom::Start(offset))?;
-
-    // Read the extent data
-    let mut data = vec![0u8; length as usize];
-    file.read_exact(&mut data)?;
-
-    // Compute the BLAKE3 hash
-    let actual_hash = blake3::hash(&data);
-    let actual_hash_hex = actual_hash.to_hex().to_string();
-
-    // Compare (case-insensitive)
-    if actual_hash_hex.to_lowercase() != expected_hash_hex.to_lowercase() {
-        return Err(UploadError::ExtentChanged {
-            extent_id: expected_hash_hex.to_string(),
-            expected: expected_hash_hex.to_string(),
-            actual: actual_hash_hex,
-        });
-    }
-
-    Ok(data)
-}
-
-/// Upload a single extent to the server.
-fn upload_extent(
-    client: &Client,
-    server_url: &str,
-    extent_id: &str,
-    data: &[u8],
-) -> Result<(), UploadError> {
-    let url = format!("{}/extents/{}", server_url, extent_id.to_lowercase());
-
-    let resp = client
-        .put(&url)
-        .header("Content-Type", "application/octet-stream")
-        .header("Content-Length", data.len())
-        .body(data.to_vec())
-        .send()?;
-
-    // 200 OK = already existed, 201 Created = newly stored
-    if !resp.status().is_success() {
-        let error_resp: ErrorResponse = resp.json()?;
-        return Err(UploadError::Server {
-            error: error_resp.error,
-            detail: error_resp.detail,
-        });
-    }
-
-    Ok(())
-}
-
-fn finalize_upload(
-    client: &Client,
-    server_url: &str,
-    catalog_id: Uuid,
-) -> Result<Option<FinalizeResponse>, UploadError> {
-    let url = format!("{}/catalogs/{}", server_url, catalog_id.simple());
-
-    let resp = client.post(&url).send()?;
-
-    if resp.status().as_u16() == 204 {
-        // Success, no content
-        return Ok(None);
-    }
-
-    if !resp.status().is_success() {
-        let error_resp: ErrorResponse = resp.json()?;
-        return Err(UploadError::Server {
-            error: error_resp.error,
-            detail: error_resp.detail,
-        });
-    }
-
-    let finalize_resp: FinalizeResponse = resp.json()?;
-    Ok(Some(finalize_resp))
-}
@@ -9,13 +9,19 @@ use std::{
     path::Path,
 };
 
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 use tempfile::NamedTempFile;
 use tracing::debug;
 
 /// The magic bytes at the start of a zstd compressed file.
 const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
 
+/// The bit of a zstd frame header descriptor saying the frame ends with a checksum.
+const ZSTD_CHECKSUM_FLAG: u8 = 0x04;
+
+/// Extension of sidecar files holding a catalog's BLAKE3 hash, as written by `b3sum`.
+pub const CHECKSUM_SIDECAR_EXTENSION: &str = "b3";
+
 /// Default compression level for zstd (1-22, higher = better compression but slower).
 pub const DEFAULT_COMPRESSION_LEVEL: i32 = 19;
 
@@ -55,6 +61,8 @@ pub fn compress_file_with_level(
     let output_writer = BufWriter::new(output_file);
 
     let mut encoder = zstd::stream::Encoder::new(output_writer, level)?;
+    // So that corruption is caught when decompressing
+    encoder.include_checksum(true)?;
     io::copy(&mut BufReader::new(input_reader), &mut encoder)?;
     encoder.finish()?;
 
@@ -97,6 +105,19 @@ pub fn decompress_to_tempfile(input_path: &Path) -> io::Result<NamedTempFile> {
     Ok(temp_file)
 }
 
+/// How to open a catalog.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatalogOpenOptions {
+    /// Check the catalog's integrity before opening it.
+    ///
+    /// The catalog's BLAKE3 hash is checked against its sidecar file (the catalog path
+    /// with `.b3` appended) if there is one, and compressed catalogs are decompressed in
+    /// full, which checks zstd frame checksums where frames have them.
+    pub verify: bool,
+    /// Open the database read-only and immutable, for archived catalogs.
+    pub read_only: bool,
+}
+
 /// Open a catalog database, automatically decompressing if necessary.
 ///
 /// If the file is zstd compressed, it will be decompressed to a temporary file
@@ -106,18 +127,104 @@ pub fn decompress_to_tempfile(input_path: &Path) -> io::Result<NamedTempFile> {
 /// Returns `(Connection, Option<NamedTempFile>)` - the tempfile must be kept alive
 /// as long as the connection is in use.
 pub fn open_catalog(path: &Path) -> io::Result<(Connection, Option<NamedTempFile>)> {
-    if is_zstd_compressed(path)? {
+    open_catalog_with_options(path, CatalogOpenOptions::default())
+}
+
+/// Open a catalog database, automatically decompressing if necessary, with options.
+///
+/// See [`open_catalog`]. Integrity failures are errors of kind [`io::ErrorKind::InvalidData`].
+pub fn open_catalog_with_options(
+    path: &Path,
+    options: CatalogOpenOptions,
+) -> io::Result<(Connection, Option<NamedTempFile>)> {
+    if options.verify {
+        verify_checksum_sidecar(path)?;
+    }
+
+    let compressed = is_zstd_compressed(path)?;
+    if compressed && options.verify && !has_frame_checksum(path)? {
+        debug!(?path, "Compressed catalog has no frame checksum");
+    }
+    let temp_file = if compressed {
         debug!(?path, "Opening compressed catalog");
-        let temp_file = decompress_to_tempfile(path)?;
-        let conn = Connection::open(temp_file.path())
-            .map_err(|e| io::Error::other(format!("Failed to open decompressed catalog: {}", e)))?;
-        Ok((conn, Some(temp_file)))
+        Some(decompress_to_tempfile(path).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to decompress catalog: {e}"),
+            )
+        })?)
     } else {
         debug!(?path, "Opening uncompressed catalog");
-        let conn = Connection::open(path)
-            .map_err(|e| io::Error::other(format!("Failed to open catalog: {}", e)))?;
-        Ok((conn, None))
+        None
+    };
+    let db_path = temp_file.as_ref().map_or(path, |t| t.path());
+
+    let conn = if options.read_only {
+        Connection::open_with_flags(
+            immutable_uri(db_path),
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+    } else {
+        Connection::open(db_path)
+    }
+    .map_err(|e| io::Error::other(format!("Failed to open catalog: {}", e)))?;
+    Ok((conn, temp_file))
+}
+
+/// Check a catalog's BLAKE3 hash against its sidecar file, if it has one.
+fn verify_checksum_sidecar(path: &Path) -> io::Result<()> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(CHECKSUM_SIDECAR_EXTENSION);
+    let expected = match std::fs::read_to_string(&sidecar) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // b3sum writes the hash then the file name
+    let expected = expected.split_whitespace().next().unwrap_or_default();
+
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    let actual = hasher.finalize().to_hex();
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Catalog checksum mismatch: expected {expected}, got {actual}"),
+        ));
     }
+    debug!(?path, "Catalog checksum verified");
+    Ok(())
+}
+
+/// Whether the first zstd frame of a file ends with a checksum.
+fn has_frame_checksum(path: &Path) -> io::Result<bool> {
+    let mut header = [0u8; 5];
+    File::open(path)?.read_exact(&mut header)?;
+    Ok(header[4] & ZSTD_CHECKSUM_FLAG != 0)
+}
+
+/// A SQLite URI opening a database as immutable, which also keeps SQLite from creating
+/// journal files next to it.
+fn immutable_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file:");
+    if !path.starts_with('/') && path.chars().nth(1) == Some(':') {
+        // Windows drive paths
+        uri.push('/');
+    }
+    for c in path.chars() {
+        match c {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?immutable=1");
+    uri
 }
 
 /// Compress a catalog file in-place.
@@ -154,6 +261,73 @@ mod tests {
         assert!(!super::is_zstd_compressed(temp2.path()).unwrap());
     }
 
+    fn catalog(compressed: bool) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("catalog.db");
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (1);")
+            .unwrap();
+        drop(conn);
+        if !compressed {
+            return (dir, db);
+        }
+        let path = dir.path().join("catalog.db.zst");
+        super::compress_file(&db, &path).unwrap();
+        (dir, path)
+    }
+
+    fn sidecar(path: &std::path::Path, hash: &str) {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".b3");
+        std::fs::write(sidecar, format!("{hash}  catalog\n")).unwrap();
+    }
+
+    #[test]
+    fn verified_read_only_open() {
+        let options = super::CatalogOpenOptions {
+            verify: true,
+            read_only: true,
+        };
+        for compressed in [false, true] {
+            let (_dir, path) = catalog(compressed);
+            if compressed {
+                assert!(super::has_frame_checksum(&path).unwrap());
+            }
+            sidecar(
+                &path,
+                &blake3::hash(&std::fs::read(&path).unwrap()).to_hex(),
+            );
+
+            let (conn, _temp) = super::open_catalog_with_options(&path, options).unwrap();
+            let x: i64 = conn.query_row("SELECT x FROM t", [], |r| r.get(0)).unwrap();
+            assert_eq!(x, 1);
+            assert!(conn.execute("INSERT INTO t VALUES (2)", []).is_err());
+        }
+    }
+
+    #[test]
+    fn corrupt_catalogs_are_refused() {
+        let options = super::CatalogOpenOptions {
+            verify: true,
+            ..Default::default()
+        };
+
+        // Mismatched sidecar
+        let (_dir, path) = catalog(false);
+        sidecar(&path, &blake3::hash(b"other").to_hex());
+        let err = super::open_catalog_with_options(&path, options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Corrupted compressed data, caught by the frame checksum
+        let (_dir, path) = catalog(true);
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+        let err = super::open_catalog_with_options(&path, options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn compress_decompress_roundtrip() {
         let original_data = b"Hello, this is test data for compression!";
@@ -0,0 +1,609 @@
+//! Differences between two catalogs.
+//!
+//! The catalogs are compared over a single connection, with the previous catalog
+//! attached under a schema name (`ATTACH DATABASE ... AS <schema>`).
+
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::{Connection, params};
+
+use crate::B3Id;
+use crate::catalog::subtree_hash_version;
+use crate::tree::SUBTREE_HASH_VERSION;
+
+/// How a rename was matched between two catalogs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameMatch {
+    /// Same inode and birth time: the very same file, possibly with new contents.
+    Inode,
+    /// Same blob: identical contents under a new path.
+    Blob,
+}
+
+impl RenameMatch {
+    /// Name of the match, as recorded in catalogs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Inode => "inode",
+            Self::Blob => "blob",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "inode" => Some(Self::Inode),
+            "blob" => Some(Self::Blob),
+            _ => None,
+        }
+    }
+}
+
+/// A file that moved from one path to another between two catalogs.
+///
+/// Paths are as stored in the catalogs, which needn't be UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    /// Path in the previous catalog.
+    pub from: Vec<u8>,
+    /// Path in the current catalog.
+    pub to: Vec<u8>,
+    /// Blob ID in the current catalog, if the file has contents.
+    pub blob_id: Option<B3Id>,
+    /// How the two paths were paired up.
+    pub matched_by: RenameMatch,
+}
+
+/// A file row that only exists on one side of the comparison.
+#[derive(Debug, Clone)]
+struct UnpairedFile {
+    path: Vec<u8>,
+    blob_id: Option<B3Id>,
+    inode: Option<i64>,
+    ts_created: Option<i64>,
+    /// Whether the file is known to be empty.
+    empty: bool,
+}
+
+/// Detect files that were renamed between a previous catalog and the current one.
+///
+/// `conn` holds the current catalog as its main schema, and `previous` is the schema
+/// name under which the previous catalog is attached.
+///
+/// Only paths that appear on a single side are considered: a file which disappeared
+/// from the previous catalog and a file which appeared in the current one are paired
+/// up if they share an inode and birth time (which identifies the same file on the
+/// same filesystem, even if its contents changed), or failing that, the same blob.
+/// Empty files all share a blob, so they're only paired by inode. Each path is paired
+/// at most once; directories and other special files are ignored.
+///
+/// Directories whose subtree hash is the same in both catalogs hold the same files, so
+/// their files aren't looked at.
+pub fn detect_renames(conn: &Connection, previous: &str) -> rusqlite::Result<Vec<Rename>> {
+    let ranges = outside_of(&unchanged_subtrees(conn, previous)?);
+    let removed = unpaired_files(conn, previous, "main", &ranges)?;
+    let added = unpaired_files(conn, "main", previous, &ranges)?;
+
+    let mut by_identity: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    let mut by_blob: HashMap<B3Id, Vec<usize>> = HashMap::new();
+    for (idx, file) in removed.iter().enumerate() {
+        if let (Some(inode), Some(created)) = (file.inode, file.ts_created) {
+            by_identity.entry((inode, created)).or_default().push(idx);
+        }
+        if let Some(blob_id) = file.blob_id
+            && !file.empty
+        {
+            by_blob.entry(blob_id).or_default().push(idx);
+        }
+    }
+
+    let mut used: HashSet<usize> = HashSet::new();
+    let mut renames = Vec::new();
+    let mut leftover = Vec::new();
+
+    // Identity matches are stronger, so they get first pick of the removed files
+    for file in added {
+        let candidate = match (file.inode, file.ts_created) {
+            (Some(inode), Some(created)) => by_identity
+                .get(&(inode, created))
+                .and_then(|idxs| idxs.iter().find(|idx| !used.contains(idx)).copied()),
+            _ => None,
+        };
+
+        match candidate {
+            Some(idx) => {
+                used.insert(idx);
+                renames.push(Rename {
+                    from: removed[idx].path.clone(),
+                    to: file.path,
+                    blob_id: file.blob_id,
+                    matched_by: RenameMatch::Inode,
+                });
+            }
+            None => leftover.push(file),
+        }
+    }
+
+    for file in leftover {
+        let Some(blob_id) = file.blob_id.filter(|_| !file.empty) else {
+            continue;
+        };
+
+        if let Some(idx) = by_blob
+            .get(&blob_id)
+            .and_then(|idxs| idxs.iter().find(|idx| !used.contains(idx)).copied())
+        {
+            used.insert(idx);
+            renames.push(Rename {
+                from: removed[idx].path.clone(),
+                to: file.path,
+                blob_id: Some(blob_id),
+                matched_by: RenameMatch::Blob,
+            });
+        }
+    }
+
+    renames.sort_by(|a, b| a.to.cmp(&b.to));
+    Ok(renames)
+}
+
+/// Detect renames from a previous catalog, and record them in the current one.
+///
+/// Replaces any renames recorded before, and returns those recorded. See [`detect_renames`].
+pub fn record_renames(conn: &Connection, previous: &str) -> rusqlite::Result<Vec<Rename>> {
+    let renames = detect_renames(conn, previous)?;
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM main.renames", [])?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO main.renames (path, previous_path, matched_by) VALUES (?1, ?2, ?3)",
+        )?;
+        for rename in &renames {
+            stmt.execute(params![rename.to, rename.from, rename.matched_by.as_str()])?;
+        }
+    }
+    tx.commit()?;
+    Ok(renames)
+}
+
+/// Read the renames recorded in a catalog by [`record_renames`], in path order.
+///
+/// Catalogs that predate recorded renames have none.
+pub fn recorded_renames(conn: &Connection) -> rusqlite::Result<Vec<Rename>> {
+    let has_renames: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'renames')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_renames {
+        return Ok(Vec::new());
+    }
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT r.previous_path, r.path, f.blob_id, r.matched_by
+        FROM renames r
+        LEFT JOIN files f ON f.path = r.path
+        ORDER BY r.path
+        "#,
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let from: Vec<u8> = row.get(0)?;
+        let to: Vec<u8> = row.get(1)?;
+        let blob_id: Option<Vec<u8>> = row.get(2)?;
+        let matched_by: String = row.get(3)?;
+        Ok((from, to, blob_id, matched_by))
+    })?;
+
+    let mut renames = Vec::new();
+    for row in rows {
+        let (from, to, blob_id, matched_by) = row?;
+        let Some(matched_by) = RenameMatch::from_str(&matched_by) else {
+            continue;
+        };
+        renames.push(Rename {
+            from,
+            to,
+            blob_id: blob_id.and_then(|b| B3Id::try_from(b).ok()),
+            matched_by,
+        });
+    }
+    Ok(renames)
+}
+
+/// List the directories whose subtree hash differs between a previous catalog and the
+/// current one, in path order.
+///
+/// A directory whose hash is unchanged has identical contents all the way down, so it and
+/// everything below it can be skipped entirely. Directories that only exist in the current
+/// catalog are included; ones that were removed are not.
+///
+/// Returns `None` if either catalog has no subtree hashes of the current version.
+pub fn changed_directories(
+    conn: &Connection,
+    previous: &str,
+) -> rusqlite::Result<Option<Vec<Vec<u8>>>> {
+    for schema in ["main", previous] {
+        if subtree_hash_version(conn, schema)? != Some(SUBTREE_HASH_VERSION) {
+            return Ok(None);
+        }
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT t.path
+        FROM main.trees t
+        WHERE NOT EXISTS (
+            SELECT 1 FROM "{previous}".trees p
+            WHERE p.path = t.path AND p.tree_id = t.tree_id
+        )
+        ORDER BY t.path
+        "#
+    ))?;
+
+    let rows = stmt.query_map([], |row| row.get(0))?;
+
+    rows.collect::<rusqlite::Result<_>>().map(Some)
+}
+
+/// The directory containing a path, or `None` for the root.
+fn parent_dir(path: &[u8]) -> Option<&[u8]> {
+    if path.is_empty() {
+        return None;
+    }
+    Some(match path.iter().rposition(|&b| b == b'/') {
+        Some(idx) => &path[..idx],
+        None => b"",
+    })
+}
+
+/// The top-most directories whose subtree hash is the same in the current catalog and in
+/// `previous`.
+///
+/// Empty if either catalog has no subtree hashes of the current version.
+fn unchanged_subtrees(conn: &Connection, previous: &str) -> rusqlite::Result<Vec<Vec<u8>>> {
+    for schema in ["main", previous] {
+        if subtree_hash_version(conn, schema)? != Some(SUBTREE_HASH_VERSION) {
+            return Ok(Vec::new());
+        }
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT t.path
+        FROM main.trees t
+        JOIN "{previous}".trees p ON p.path = t.path AND p.tree_id = t.tree_id
+        "#
+    ))?;
+    let paths: Vec<Vec<u8>> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let unchanged: HashSet<&[u8]> = paths.iter().map(Vec::as_slice).collect();
+    let is_top = |path: &[u8]| {
+        let mut parent = parent_dir(path);
+        while let Some(dir) = parent {
+            if unchanged.contains(dir) {
+                return false;
+            }
+            parent = parent_dir(dir);
+        }
+        true
+    };
+    Ok(paths.iter().filter(|path| is_top(path)).cloned().collect())
+}
+
+/// A range of paths, from inclusive to exclusive, either end being unbounded if `None`.
+type PathRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// The ranges of paths outside of some directories, none of which contains another.
+fn outside_of(dirs: &[Vec<u8>]) -> Vec<PathRange> {
+    if dirs.iter().any(Vec::is_empty) {
+        return Vec::new();
+    }
+
+    // Everything below `dir` sorts from `dir/` up to `dir0`, as `0` follows `/`
+    let mut inside: Vec<(Vec<u8>, Vec<u8>)> = dirs
+        .iter()
+        .map(|dir| {
+            (
+                [dir.as_slice(), b"/"].concat(),
+                [dir.as_slice(), b"0"].concat(),
+            )
+        })
+        .collect();
+    inside.sort();
+
+    let mut ranges = Vec::with_capacity(inside.len() + 1);
+    let mut from = None;
+    for (start, end) in inside {
+        ranges.push((from, Some(start)));
+        from = Some(end);
+    }
+    ranges.push((from, None));
+    ranges
+}
+
+/// Query regular files in `schema` whose path doesn't exist in `other`, within some ranges
+/// of paths.
+fn unpaired_files(
+    conn: &Connection,
+    schema: &str,
+    other: &str,
+    ranges: &[PathRange],
+) -> rusqlite::Result<Vec<UnpairedFile>> {
+    let mut files = Vec::new();
+    for (from, to) in ranges {
+        let mut bounds = String::new();
+        let mut params: Vec<&[u8]> = Vec::new();
+        if let Some(from) = from {
+            params.push(from);
+            bounds.push_str(&format!(" AND f.path >= ?{}", params.len()));
+        }
+        if let Some(to) = to {
+            params.push(to);
+            bounds.push_str(&format!(" AND f.path < ?{}", params.len()));
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT f.path, f.blob_id, f.fs_inode, f.ts_created, b.bytes = 0
+            FROM "{schema}".files f
+            LEFT JOIN "{schema}".blobs b ON b.blob_id = f.blob_id
+            WHERE f.special IS NULL{bounds}
+            AND NOT EXISTS (
+                SELECT 1 FROM "{other}".files o
+                WHERE o.path = f.path
+            )
+            ORDER BY f.path
+            "#
+        ))?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+            let path: Vec<u8> = row.get(0)?;
+            let blob_id: Option<Vec<u8>> = row.get(1)?;
+            let blob_id = blob_id.and_then(|b| B3Id::try_from(b).ok());
+            // Regular files without a blob are empty too
+            let empty: Option<bool> = row.get(4)?;
+            Ok(UnpairedFile {
+                path,
+                empty: empty.unwrap_or(blob_id.is_none()),
+                blob_id,
+                inode: row.get(2)?,
+                ts_created: row.get(3)?,
+            })
+        })?;
+        for row in rows {
+            files.push(row?);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{Connection, params};
+
+    use super::*;
+    use crate::create_catalog_schema;
+
+    fn insert_file(
+        conn: &Connection,
+        schema: &str,
+        path: impl AsRef<[u8]>,
+        blob: u8,
+        inode: Option<i64>,
+        created: Option<i64>,
+    ) {
+        conn.execute(
+            &format!(
+                r#"INSERT INTO "{schema}".files (path, blob_id, fs_inode, ts_created)
+                VALUES (?1, ?2, ?3, ?4)"#
+            ),
+            params![path.as_ref(), [blob; 32].as_slice(), inode, created],
+        )
+        .unwrap();
+    }
+
+    fn set_subtree_hash_version(conn: &Connection, schema: &str, version: u32) {
+        conn.execute(
+            &format!(
+                r#"INSERT OR REPLACE INTO "{schema}".metadata (key, value) VALUES ('trees', ?1)"#
+            ),
+            [serde_json::json!({ "version": version }).to_string()],
+        )
+        .unwrap();
+    }
+
+    fn setup() -> (Connection, tempfile::NamedTempFile) {
+        let previous = tempfile::NamedTempFile::new().unwrap();
+        create_catalog_schema(&Connection::open(previous.path()).unwrap()).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&conn).unwrap();
+        conn.execute(
+            "ATTACH DATABASE ?1 AS previous",
+            [previous.path().to_string_lossy().as_ref()],
+        )
+        .unwrap();
+        (conn, previous)
+    }
+
+    #[test]
+    fn changed_directories_skip_unchanged() {
+        let (conn, _previous) = setup();
+        for (schema, tree) in [("previous", 1u8), ("main", 2u8)] {
+            set_subtree_hash_version(&conn, schema, SUBTREE_HASH_VERSION);
+            for (path, id) in [("", tree), ("a", 3), ("b", tree)] {
+                conn.execute(
+                    &format!(r#"INSERT INTO "{schema}".trees (path, tree_id) VALUES (?1, ?2)"#),
+                    params![path.as_bytes(), [id; 32].as_slice()],
+                )
+                .unwrap();
+            }
+        }
+
+        let changed = changed_directories(&conn, "previous").unwrap();
+        assert_eq!(changed, Some(vec![b"".to_vec(), b"b".to_vec()]));
+
+        // Hashes of another version can't be compared
+        set_subtree_hash_version(&conn, "previous", SUBTREE_HASH_VERSION + 1);
+        assert_eq!(changed_directories(&conn, "previous").unwrap(), None);
+    }
+
+    #[test]
+    fn unchanged_subtrees_not_looked_into() {
+        let (conn, _previous) = setup();
+        for schema in ["previous", "main"] {
+            set_subtree_hash_version(&conn, schema, SUBTREE_HASH_VERSION);
+            for (path, id) in [("", schema.len() as u8), ("same", 1), ("same/deeper", 2)] {
+                conn.execute(
+                    &format!(r#"INSERT INTO "{schema}".trees (path, tree_id) VALUES (?1, ?2)"#),
+                    params![path.as_bytes(), [id; 32].as_slice()],
+                )
+                .unwrap();
+            }
+        }
+        // Paths inside "same" couldn't really differ, which shows they aren't looked at
+        insert_file(&conn, "previous", "same/deeper/old.txt", 1, None, None);
+        insert_file(&conn, "main", "same/deeper/new.txt", 1, None, None);
+        insert_file(&conn, "previous", "same-ish/old.txt", 2, None, None);
+        insert_file(&conn, "main", "same0/new.txt", 2, None, None);
+
+        let renames = detect_renames(&conn, "previous").unwrap();
+        assert_eq!(
+            renames
+                .iter()
+                .map(|r| (r.from.as_slice(), r.to.as_slice()))
+                .collect::<Vec<_>>(),
+            vec![(b"same-ish/old.txt".as_slice(), b"same0/new.txt".as_slice())]
+        );
+    }
+
+    #[test]
+    fn ranges_outside_of_directories() {
+        let range = |from: Option<&str>, to: Option<&str>| {
+            (
+                from.map(|s| s.as_bytes().to_vec()),
+                to.map(|s| s.as_bytes().to_vec()),
+            )
+        };
+        assert_eq!(
+            outside_of(&[b"b".to_vec(), b"a".to_vec()]),
+            vec![
+                range(None, Some("a/")),
+                range(Some("a0"), Some("b/")),
+                range(Some("b0"), None),
+            ]
+        );
+        assert_eq!(outside_of(&[]), vec![range(None, None)]);
+        assert!(outside_of(&[b"a".to_vec(), Vec::new()]).is_empty());
+    }
+
+    #[test]
+    fn rename_by_blob() {
+        let (conn, _previous) = setup();
+        insert_file(&conn, "previous", "unchanged.txt", 1, None, None);
+        insert_file(&conn, "previous", "old.txt", 2, None, None);
+        insert_file(&conn, "main", "unchanged.txt", 1, None, None);
+        insert_file(&conn, "main", "new.txt", 2, None, None);
+        insert_file(&conn, "main", "added.txt", 3, None, None);
+
+        let renames = detect_renames(&conn, "previous").unwrap();
+        assert_eq!(
+            renames,
+            vec![Rename {
+                from: b"old.txt".to_vec(),
+                to: b"new.txt".to_vec(),
+                blob_id: Some([2u8; 32].into()),
+                matched_by: RenameMatch::Blob,
+            }]
+        );
+    }
+
+    #[test]
+    fn non_utf8_renames_kept_as_bytes() {
+        let (conn, _previous) = setup();
+        insert_file(&conn, "previous", b"caf\xe9.txt", 2, None, None);
+        insert_file(&conn, "main", b"new\xff.txt", 2, None, None);
+
+        record_renames(&conn, "previous").unwrap();
+        let renames = recorded_renames(&conn).unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].from, b"caf\xe9.txt");
+        assert_eq!(renames[0].to, b"new\xff.txt");
+    }
+
+    #[test]
+    fn rename_by_inode_beats_blob() {
+        let (conn, _previous) = setup();
+        // Same contents as b.txt, but a.txt is the same inode as the new file
+        insert_file(&conn, "previous", "a.txt", 5, Some(42), Some(1000));
+        insert_file(&conn, "previous", "b.txt", 6, Some(43), Some(1000));
+        insert_file(&conn, "main", "c.txt", 6, Some(42), Some(1000));
+
+        let renames = detect_renames(&conn, "previous").unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].from, b"a.txt");
+        assert_eq!(renames[0].matched_by, RenameMatch::Inode);
+    }
+
+    #[test]
+    fn empty_files_only_paired_by_inode() {
+        let (conn, _previous) = setup();
+        for schema in ["previous", "main"] {
+            conn.execute(
+                &format!(
+                    r#"INSERT INTO "{schema}".blobs (blob_id, bytes, extents)
+                    VALUES (?1, 0, 0)"#
+                ),
+                [[9u8; 32].as_slice()],
+            )
+            .unwrap();
+        }
+        insert_file(&conn, "previous", "empty1", 9, Some(5), Some(1000));
+        insert_file(&conn, "previous", "empty2", 9, Some(6), Some(1000));
+        insert_file(&conn, "main", "moved", 9, Some(5), Some(1000));
+        insert_file(&conn, "main", "other", 9, Some(7), Some(2000));
+
+        let renames = detect_renames(&conn, "previous").unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(
+            (renames[0].from.as_slice(), renames[0].to.as_slice()),
+            (&b"empty1"[..], &b"moved"[..])
+        );
+        assert_eq!(renames[0].matched_by, RenameMatch::Inode);
+    }
+
+    #[test]
+    fn renames_are_recorded() {
+        let (conn, _previous) = setup();
+        insert_file(&conn, "previous", "old.txt", 2, None, None);
+        insert_file(&conn, "main", "new.txt", 2, None, None);
+        assert!(recorded_renames(&conn).unwrap().is_empty());
+
+        let renames = record_renames(&conn, "previous").unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(recorded_renames(&conn).unwrap(), renames);
+
+        // Recording again replaces the previous renames
+        conn.execute(
+            "DELETE FROM main.files WHERE path = ?1",
+            [b"new.txt".as_slice()],
+        )
+        .unwrap();
+        assert!(record_renames(&conn, "previous").unwrap().is_empty());
+        assert!(recorded_renames(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn each_path_paired_once() {
+        let (conn, _previous) = setup();
+        insert_file(&conn, "previous", "old.txt", 7, None, None);
+        insert_file(&conn, "main", "copy1.txt", 7, None, None);
+        insert_file(&conn, "main", "copy2.txt", 7, None, None);
+
+        let renames = detect_renames(&conn, "previous").unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].to, b"copy1.txt");
+    }
+}
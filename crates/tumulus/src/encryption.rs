@@ -0,0 +1,425 @@
+//! Client-side encryption of file names and other revealing catalog fields.
+//!
+//! Paths are encrypted one component at a time, deterministically: the same name under
+//! the same key always encrypts to the same token, so directory structure, tree hashes,
+//! and comparisons between catalogs keep working on the encrypted paths. Other fields
+//! (symlink targets, source paths, hostnames, ...) are encrypted with a random nonce, and
+//! so are the times, mode, and owner of each file, sealed together with its attributes.
+//!
+//! Blob and extent sizes, inode numbers, and change cookies stay in the clear: the server
+//! needs the layout of blobs to store and serve them, and sees extent sizes on upload.
+//!
+//! Both use ChaCha20-Poly1305. The deterministic mode derives the nonce from a keyed
+//! BLAKE3 hash of the plaintext (a synthetic IV), and checks it again on decryption.
+//! Tokens are unpadded URL-safe base64 of the nonce followed by the ciphertext and tag,
+//! so they never contain a `/`.
+
+use std::io;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::file::FileInfo;
+use crate::secrets::{SecretError, SecretsProvider};
+
+/// Length of a catalog encryption key.
+pub const KEY_LEN: usize = 32;
+
+/// Domain separation for deterministically encrypted path components.
+const AAD_NAME: &[u8] = b"tumulus name";
+/// Domain separation for randomly encrypted fields.
+const AAD_FIELD: &[u8] = b"tumulus field";
+
+/// Error type for catalog decryption.
+#[derive(Debug, Error)]
+pub enum CipherError {
+    #[error("Encrypted value is not valid base64")]
+    Encoding,
+
+    #[error("Encrypted value failed authentication (wrong key or corrupted data)")]
+    Authentication,
+
+    #[error("Decrypted value is not valid UTF-8")]
+    Utf8,
+
+    #[error("Decrypted value is not valid JSON")]
+    Json,
+}
+
+/// Read a key given as 32 raw bytes or 64 hex characters.
+pub(crate) fn parse_key(contents: &[u8]) -> io::Result<[u8; KEY_LEN]> {
+    if contents.len() == KEY_LEN {
+        return Ok(contents.try_into().expect("length checked"));
+    }
+    let mut key = [0u8; KEY_LEN];
+    hex::decode_to_slice(contents.trim_ascii(), &mut key).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "key file must contain 32 raw bytes or 64 hex characters",
+        )
+    })?;
+    Ok(key)
+}
+
+/// Fields of a file entry sealed together in place of its attributes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SealedFields {
+    ts_created: Option<i64>,
+    ts_modified: Option<i64>,
+    ts_accessed: Option<i64>,
+    ts_changed: Option<i64>,
+    unix_mode: Option<u32>,
+    unix_owner_id: Option<u32>,
+    unix_group_id: Option<u32>,
+    attributes: Option<serde_json::Value>,
+}
+
+/// Encrypts and decrypts catalog paths and fields with a single key.
+pub struct CatalogCipher {
+    name_key: LessSafeKey,
+    siv_key: [u8; KEY_LEN],
+    field_key: LessSafeKey,
+    key_id: String,
+    rng: SystemRandom,
+}
+
+impl std::fmt::Debug for CatalogCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CatalogCipher")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CatalogCipher {
+    /// Create a cipher from a catalog key. Subkeys for each purpose are derived from it.
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        let derive = |context: &str| blake3::derive_key(context, key);
+        let aead_key = |bytes: [u8; KEY_LEN]| {
+            LessSafeKey::new(
+                UnboundKey::new(&CHACHA20_POLY1305, &bytes).expect("key has the right length"),
+            )
+        };
+
+        Self {
+            name_key: aead_key(derive("tumulus catalog 2025 name encryption")),
+            siv_key: derive("tumulus catalog 2025 name synthetic iv"),
+            field_key: aead_key(derive("tumulus catalog 2025 field encryption")),
+            key_id: blake3::Hash::from(derive("tumulus catalog 2025 key id")).to_hex()[..16]
+                .to_string(),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Create a cipher from a key given as 32 raw bytes or 64 hex characters.
+    pub fn from_key_bytes(contents: &[u8]) -> io::Result<Self> {
+        Ok(Self::new(&parse_key(contents)?))
+    }
+
+    /// Create a cipher from a key fetched from a secrets provider.
+    pub fn from_secret(provider: &dyn SecretsProvider) -> Result<Self, SecretError> {
+        Ok(Self::from_key_bytes(&provider.fetch()?)?)
+    }
+
+    /// A short identifier for the key, safe to store alongside the data it encrypts.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Deterministically encrypt a single path component.
+    pub fn encrypt_name(&self, name: &str) -> String {
+        let siv = blake3::keyed_hash(&self.siv_key, name.as_bytes());
+        let nonce: [u8; NONCE_LEN] = siv.as_bytes()[..NONCE_LEN].try_into().unwrap();
+        seal(&self.name_key, nonce, AAD_NAME, name)
+    }
+
+    /// Decrypt a single path component.
+    pub fn decrypt_name(&self, token: &str) -> Result<String, CipherError> {
+        let (nonce, name) = open(&self.name_key, AAD_NAME, token)?;
+        let siv = blake3::keyed_hash(&self.siv_key, name.as_bytes());
+        if siv.as_bytes()[..NONCE_LEN] != nonce {
+            return Err(CipherError::Authentication);
+        }
+        Ok(name)
+    }
+
+    /// Deterministically encrypt a normalised path, component by component.
+    pub fn encrypt_path(&self, path: &str) -> String {
+        if path.is_empty() {
+            return String::new();
+        }
+        path.split('/')
+            .map(|name| self.encrypt_name(name))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Decrypt a path encrypted with [`encrypt_path`](Self::encrypt_path).
+    pub fn decrypt_path(&self, path: &str) -> Result<String, CipherError> {
+        if path.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(path
+            .split('/')
+            .map(|token| self.decrypt_name(token))
+            .collect::<Result<Vec<_>, _>>()?
+            .join("/"))
+    }
+
+    /// Encrypt a field with a random nonce.
+    pub fn seal(&self, plaintext: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .expect("system random number generator failed");
+        seal(&self.field_key, nonce, AAD_FIELD, plaintext)
+    }
+
+    /// Decrypt a field encrypted with [`seal`](Self::seal).
+    pub fn open(&self, token: &str) -> Result<String, CipherError> {
+        open(&self.field_key, AAD_FIELD, token).map(|(_, plaintext)| plaintext)
+    }
+
+    /// Encrypt a JSON value with a random nonce, replacing it with a JSON string.
+    pub fn seal_value(&self, value: &serde_json::Value) -> serde_json::Value {
+        serde_json::Value::String(self.seal(&value.to_string()))
+    }
+
+    /// Decrypt a JSON value encrypted with [`seal_value`](Self::seal_value).
+    pub fn open_value(&self, value: &serde_json::Value) -> Result<serde_json::Value, CipherError> {
+        let token = value.as_str().ok_or(CipherError::Json)?;
+        serde_json::from_str(&self.open(token)?).map_err(|_| CipherError::Json)
+    }
+
+    /// Encrypt the revealing fields of a file entry: its path and root prefix
+    /// deterministically, and its special file info (like symlink targets) randomly. Its
+    /// times, mode, owner, and attributes are sealed together randomly as its attributes,
+    /// and cleared from their own fields.
+    ///
+    /// Sealed special info and attributes are stored as JSON strings in place of the usual
+    /// objects.
+    pub fn encrypt_file(&self, mut info: FileInfo) -> FileInfo {
+        info.relative_path = self.encrypt_path(&info.relative_path);
+        info.root = info.root.map(|root| self.encrypt_path(&root));
+        info.special = info.special.map(|special| self.seal_value(&special));
+        let fields = SealedFields {
+            ts_created: info.ts_created.take(),
+            ts_modified: info.ts_modified.take(),
+            ts_accessed: info.ts_accessed.take(),
+            ts_changed: info.ts_changed.take(),
+            unix_mode: info.unix_mode.take(),
+            unix_owner_id: info.unix_owner_id.take(),
+            unix_group_id: info.unix_group_id.take(),
+            attributes: info.attributes.take(),
+        };
+        let fields = serde_json::to_value(fields).expect("sealed fields serialize");
+        info.attributes = Some(self.seal_value(&fields));
+        info
+    }
+
+    /// Decrypt a file entry encrypted with [`encrypt_file`](Self::encrypt_file).
+    pub fn decrypt_file(&self, mut info: FileInfo) -> Result<FileInfo, CipherError> {
+        info.relative_path = self.decrypt_path(&info.relative_path)?;
+        info.root = info.root.map(|root| self.decrypt_path(&root)).transpose()?;
+        info.special = match info.special {
+            Some(sealed @ serde_json::Value::String(_)) => Some(self.open_value(&sealed)?),
+            other => other,
+        };
+        if let Some(sealed @ serde_json::Value::String(_)) = &info.attributes {
+            let fields: SealedFields =
+                serde_json::from_value(self.open_value(sealed)?).map_err(|_| CipherError::Json)?;
+            info.ts_created = fields.ts_created;
+            info.ts_modified = fields.ts_modified;
+            info.ts_accessed = fields.ts_accessed;
+            info.ts_changed = fields.ts_changed;
+            info.unix_mode = fields.unix_mode;
+            info.unix_owner_id = fields.unix_owner_id;
+            info.unix_group_id = fields.unix_group_id;
+            info.attributes = fields.attributes;
+        }
+        Ok(info)
+    }
+}
+
+fn seal(key: &LessSafeKey, nonce: [u8; NONCE_LEN], aad: &[u8], plaintext: &str) -> String {
+    let mut data = plaintext.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad),
+        &mut data,
+    )
+    .expect("plaintext is within ChaCha20-Poly1305 limits");
+
+    let mut token = nonce.to_vec();
+    token.extend_from_slice(&data);
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+fn open(
+    key: &LessSafeKey,
+    aad: &[u8],
+    token: &str,
+) -> Result<([u8; NONCE_LEN], String), CipherError> {
+    let data = URL_SAFE_NO_PAD
+        .decode(token)
+        .map_err(|_| CipherError::Encoding)?;
+    if data.len() < NONCE_LEN {
+        return Err(CipherError::Encoding);
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+    let mut ciphertext = ciphertext.to_vec();
+    let plaintext = key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut ciphertext,
+        )
+        .map_err(|_| CipherError::Authentication)?;
+
+    let plaintext = String::from_utf8(plaintext.to_vec()).map_err(|_| CipherError::Utf8)?;
+    Ok((nonce, plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_deterministic_per_component() {
+        let cipher = CatalogCipher::new(&[7; KEY_LEN]);
+
+        let a = cipher.encrypt_path("home/user/notes.txt");
+        let b = cipher.encrypt_path("home/other/notes.txt");
+        assert_eq!(a, cipher.encrypt_path("home/user/notes.txt"));
+        assert_eq!(a.split('/').count(), 3);
+        assert_eq!(a.split('/').next(), b.split('/').next());
+        assert_eq!(a.rsplit('/').next(), b.rsplit('/').next());
+        assert!(!a.contains("notes"));
+
+        assert_eq!(cipher.decrypt_path(&a).unwrap(), "home/user/notes.txt");
+        assert_eq!(cipher.encrypt_path(""), "");
+    }
+
+    #[test]
+    fn fields_are_randomised() {
+        let cipher = CatalogCipher::new(&[7; KEY_LEN]);
+
+        let a = cipher.seal("/srv/data");
+        let b = cipher.seal("/srv/data");
+        assert_ne!(a, b);
+        assert_eq!(cipher.open(&a).unwrap(), "/srv/data");
+        assert_eq!(cipher.open(&b).unwrap(), "/srv/data");
+
+        let value = serde_json::json!("/srv/data");
+        let sealed = cipher.seal_value(&value);
+        assert!(sealed.is_string() && sealed != value);
+        assert_eq!(cipher.open_value(&sealed).unwrap(), value);
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let cipher = CatalogCipher::new(&[7; KEY_LEN]);
+        let other = CatalogCipher::new(&[8; KEY_LEN]);
+        assert_ne!(cipher.key_id(), other.key_id());
+
+        let name = cipher.encrypt_name("secret");
+        assert!(matches!(
+            other.decrypt_name(&name),
+            Err(CipherError::Authentication)
+        ));
+        // Names and fields don't decrypt as each other
+        assert!(cipher.open(&name).is_err());
+    }
+
+    fn revealing_file() -> FileInfo {
+        FileInfo {
+            relative_path: "private-root/www-data/hidden-link".to_string(),
+            root: Some("private-root".to_string()),
+            blob: None,
+            ts_created: Some(1_700_000_000_001),
+            ts_modified: Some(1_700_000_000_002),
+            ts_accessed: Some(1_700_000_000_003),
+            ts_changed: Some(1_700_000_000_004),
+            unix_mode: Some(0o104755),
+            unix_owner_id: Some(4242),
+            unix_group_id: Some(4343),
+            fs_inode: None,
+            fs_change_cookie: None,
+            priority: None,
+            attributes: Some(serde_json::json!({ "xattrs": { "com.apple.quarantine": "MDA4MQ" } })),
+            streams: Vec::new(),
+            special: Some(serde_json::json!({ "type": "symlink", "target": "/etc/shadow-target" })),
+        }
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let cipher = CatalogCipher::new(&[7; KEY_LEN]);
+        let info = revealing_file();
+
+        let encrypted = cipher.encrypt_file(info.clone());
+        let root = encrypted.root.clone().unwrap();
+        assert!(encrypted.relative_path.starts_with(&format!("{root}/")));
+        assert!(encrypted.special.as_ref().unwrap().is_string());
+        assert!(encrypted.attributes.as_ref().unwrap().is_string());
+
+        let decrypted = cipher.decrypt_file(encrypted).unwrap();
+        assert_eq!(decrypted.relative_path, info.relative_path);
+        assert_eq!(decrypted.root, info.root);
+        assert_eq!(decrypted.special, info.special);
+        assert_eq!(decrypted.attributes, info.attributes);
+        assert_eq!(decrypted.ts_created, info.ts_created);
+        assert_eq!(decrypted.ts_modified, info.ts_modified);
+        assert_eq!(decrypted.ts_accessed, info.ts_accessed);
+        assert_eq!(decrypted.ts_changed, info.ts_changed);
+        assert_eq!(decrypted.unix_mode, info.unix_mode);
+        assert_eq!(decrypted.unix_owner_id, info.unix_owner_id);
+        assert_eq!(decrypted.unix_group_id, info.unix_group_id);
+    }
+
+    #[test]
+    fn nothing_revealing_is_written_in_the_clear() {
+        let cipher = CatalogCipher::new(&[7; KEY_LEN]);
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::create_catalog_schema(&conn).unwrap();
+        crate::write_catalog(&conn, &[cipher.encrypt_file(revealing_file())]).unwrap();
+
+        let row: Vec<rusqlite::types::Value> = conn
+            .query_row(
+                "SELECT ts_created, ts_changed, ts_modified, ts_accessed, unix_mode,
+                    unix_owner_id, unix_group_id, unix_owner_name, unix_group_name
+                FROM files",
+                [],
+                |row| (0..9).map(|i| row.get(i)).collect(),
+            )
+            .unwrap();
+        assert!(
+            row.iter()
+                .all(|value| *value == rusqlite::types::Value::Null)
+        );
+
+        let text: String = conn
+            .query_row(
+                "SELECT CAST(path AS TEXT) || root || special || attributes FROM files",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        for revealing in [
+            "private-root",
+            "www-data",
+            "hidden-link",
+            "shadow-target",
+            "quarantine",
+            "1700000000",
+            "4242",
+        ] {
+            assert!(!text.contains(revealing), "{revealing} in {text}");
+        }
+    }
+}
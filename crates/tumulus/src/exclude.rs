@@ -0,0 +1,201 @@
+//! Automatic exclusion of paths that should never be backed up.
+//!
+//! Some paths are dangerous or pointless to catalog: kernel pseudo-filesystems (whose
+//! files may block forever when read), swapfiles (which are huge and meaningless once
+//! the machine is off), and the storage directory of a co-located tumulus server (which
+//! would make the backup eat itself).
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use fs_info::{get_fs_type, is_pseudo_fs};
+
+/// Well-known mount points of pseudo-filesystems, excluded even if they can't be detected
+/// as such (`/dev` is usually a devtmpfs, which looks like any other tmpfs).
+#[cfg(target_os = "linux")]
+const PSEUDO_PATHS: &[&str] = &["/proc", "/sys", "/dev", "/run"];
+#[cfg(all(unix, not(target_os = "linux")))]
+const PSEUDO_PATHS: &[&str] = &["/proc", "/dev"];
+#[cfg(windows)]
+const PSEUDO_PATHS: &[&str] = &[];
+
+/// Why a path was automatically excluded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExclusionReason {
+    /// A well-known pseudo-filesystem mount point.
+    PseudoPath,
+    /// A mount point of a pseudo-filesystem of the given type.
+    PseudoFs(String),
+    /// An active swapfile.
+    Swapfile,
+    /// The storage directory of a tumulus server.
+    ServerStorage,
+}
+
+impl fmt::Display for ExclusionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PseudoPath => write!(f, "pseudo-filesystem mount point"),
+            Self::PseudoFs(fs_type) => write!(f, "{fs_type} pseudo-filesystem"),
+            Self::Swapfile => write!(f, "swapfile"),
+            Self::ServerStorage => write!(f, "tumulus server storage"),
+        }
+    }
+}
+
+/// Decides which paths to skip while walking a source tree.
+#[derive(Debug, Clone, Default)]
+pub struct AutoExclude {
+    swapfiles: Vec<PathBuf>,
+    allow: Vec<PathBuf>,
+}
+
+impl AutoExclude {
+    /// Create an auto-excluder, never excluding any path under `allow`.
+    pub fn new(allow: Vec<PathBuf>) -> Self {
+        let swapfiles = active_swapfiles().unwrap_or_default();
+        Self { swapfiles, allow }
+    }
+
+    /// Check whether a path should be excluded.
+    ///
+    /// `is_dir` and `crosses_mount` come from the directory walk: the filesystem type is
+    /// only looked up for directories which are on a different device than the source root.
+    pub fn check(&self, path: &Path, is_dir: bool, crosses_mount: bool) -> Option<ExclusionReason> {
+        if self.allow.iter().any(|allowed| path.starts_with(allowed)) {
+            return None;
+        }
+
+        if !is_dir {
+            return self
+                .swapfiles
+                .iter()
+                .any(|swap| swap == path)
+                .then_some(ExclusionReason::Swapfile);
+        }
+
+        if PSEUDO_PATHS.iter().any(|pseudo| path == Path::new(pseudo)) {
+            return Some(ExclusionReason::PseudoPath);
+        }
+
+        if crosses_mount
+            && let Ok(Some(fs_type)) = get_fs_type(path)
+            && is_pseudo_fs(&fs_type)
+        {
+            return Some(ExclusionReason::PseudoFs(fs_type));
+        }
+
+        if is_server_storage(path) {
+            return Some(ExclusionReason::ServerStorage);
+        }
+
+        None
+    }
+}
+
+/// The device a file is on, to detect mount points while walking.
+#[cfg(unix)]
+pub fn device_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+/// The device a file is on (not available on this platform).
+#[cfg(not(unix))]
+pub fn device_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Whether a directory looks like the storage directory of a tumulus server.
+fn is_server_storage(path: &Path) -> bool {
+    path.join("uploads.db").is_file()
+        && path.join("extents").is_dir()
+        && path.join("catalogs").is_dir()
+}
+
+/// List active swapfiles (not swap partitions).
+#[cfg(target_os = "linux")]
+fn active_swapfiles() -> io::Result<Vec<PathBuf>> {
+    Ok(parse_proc_swaps(&fs::read_to_string("/proc/swaps")?))
+}
+
+/// List active swapfiles (not supported on this platform).
+#[cfg(not(target_os = "linux"))]
+fn active_swapfiles() -> io::Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+/// Parse the contents of `/proc/swaps`, keeping only file-backed swap.
+///
+/// Whitespace in paths is octal-escaped by the kernel (`\040` for a space).
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_swaps(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let path = fields.next()?;
+            (fields.next()? == "file").then(|| PathBuf::from(unescape_octal(path)))
+        })
+        .collect()
+}
+
+/// Undo the kernel's octal escaping of whitespace and backslashes.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn unescape_octal(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(digits) = bytes.get(i + 1..i + 4)
+            && let Ok(digits) = std::str::from_utf8(digits)
+            && let Ok(byte) = u8::from_str_radix(digits, 8)
+        {
+            out.push(byte);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proc_swaps() {
+        let contents = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n\
+            /dev/nvme0n1p3                          partition\t8388604\t\t0\t\t-2\n\
+            /swap\\040file                           file\t\t1048572\t\t0\t\t-3\n";
+        assert_eq!(
+            parse_proc_swaps(contents),
+            vec![PathBuf::from("/swap file")]
+        );
+    }
+
+    #[test]
+    fn server_storage_and_allowlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = dir.path().join("storage");
+        fs::create_dir_all(storage.join("extents")).unwrap();
+        fs::create_dir_all(storage.join("catalogs")).unwrap();
+        fs::write(storage.join("uploads.db"), "").unwrap();
+
+        let exclude = AutoExclude::new(Vec::new());
+        assert_eq!(exclude.check(dir.path(), true, false), None);
+        assert_eq!(
+            exclude.check(&storage, true, false),
+            Some(ExclusionReason::ServerStorage)
+        );
+
+        let exclude = AutoExclude::new(vec![storage.clone()]);
+        assert_eq!(exclude.check(&storage, true, false), None);
+    }
+}
@@ -1,13 +1,16 @@
 //! Extent and blob processing functionality.
 
-use std::{fs::File, io, path::Path};
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
 
-use blake3::Hasher;
 use extentria::{DataRange, RangeReader, RangeReaderImpl};
 use memmap2::Mmap;
 use tracing::debug;
 
-use crate::B3Id;
+use crate::{B3Id, ExtentKey};
 
 /// Maximum size for a single extent chunk (128 KB).
 pub const MAX_EXTENT_SIZE: u64 = 128 * 1024;
@@ -31,76 +34,100 @@ pub struct BlobInfo {
     pub extents: Vec<ExtentInfo>,
 }
 
-/// Convert a DataRange to one or more ExtentInfo entries, subchunking large extents.
+/// Convert a DataRange to an ExtentInfo entry, computing its hash if it has data.
 ///
-/// If the extent is larger than MAX_EXTENT_SIZE, it will be split into multiple
-/// chunks, each with its own hash. All chunks share the same fs_extent value.
-fn range_to_extent_infos(range: DataRange, mmap: &Mmap, fs_extent: u32) -> Vec<ExtentInfo> {
-    if range.hole {
-        // Sparse holes are not subchunked
-        return vec![ExtentInfo {
+/// Ranges are expected to be no longer than MAX_EXTENT_SIZE already, as from a reader
+/// [split](RangeReaderImpl::split_at) at it. Returns `None` for ranges past the end of
+/// the file's data.
+fn range_to_extent_info(
+    range: DataRange,
+    data: &[u8],
+    fs_extent: u32,
+    key: Option<&ExtentKey>,
+) -> Option<ExtentInfo> {
+    if range.is_zero() {
+        // Sparse holes and preallocated ranges have no data to hash
+        return Some(ExtentInfo {
             extent_id: B3Id::from([0u8; 32]),
             range,
             fs_extent,
-        }];
+        });
     }
 
-    let start = (range.offset as usize).min(mmap.len());
-    let end = (start + range.length as usize).min(mmap.len());
-    let total_len = (end - start) as u64;
-
-    if total_len == 0 {
-        return vec![];
+    let start = (range.offset as usize).min(data.len());
+    let end = (start + range.length as usize).min(data.len());
+    if start == end {
+        return None;
     }
 
-    // If extent fits in one chunk, no subchunking needed
-    if total_len <= MAX_EXTENT_SIZE {
-        let slice = &mmap[start..end];
-        let extent_id = B3Id::hash(slice);
-
-        return vec![ExtentInfo {
-            extent_id,
-            range: DataRange::new(range.offset, total_len),
-            fs_extent,
-        }];
-    }
-
-    // Subchunk the extent into MAX_EXTENT_SIZE pieces
-    let mut chunks = Vec::new();
-    let mut chunk_start = start;
-    let mut chunk_offset = range.offset;
-
-    while chunk_start < end {
-        let chunk_end = (chunk_start + MAX_EXTENT_SIZE as usize).min(end);
-        let chunk_len = (chunk_end - chunk_start) as u64;
-
-        let slice = &mmap[chunk_start..chunk_end];
-        let extent_id = B3Id::hash(slice);
-
+    if range.continued {
         debug!(
             fs_extent,
-            offset = chunk_offset,
-            bytes = chunk_len,
+            offset = range.offset,
+            bytes = end - start,
             "Created subchunk"
         );
-
-        chunks.push(ExtentInfo {
-            extent_id,
-            range: DataRange::new(chunk_offset, chunk_len),
-            fs_extent,
-        });
-
-        chunk_start = chunk_end;
-        chunk_offset += chunk_len;
     }
+    Some(ExtentInfo {
+        extent_id: B3Id::hash_with(&data[start..end], key),
+        range: DataRange::new(range.offset, (end - start) as u64),
+        fs_extent,
+    })
+}
+
+/// Convert a file's ranges to ExtentInfo entries.
+///
+/// Each filesystem extent gets a unique fs_extent index, which the subchunks split from it
+/// (marked [`continued`](DataRange::continued)) share. Without ranges, the whole file is
+/// taken as one extent, still subchunked.
+fn ranges_to_extent_infos(
+    ranges: Vec<DataRange>,
+    data: &[u8],
+    key: Option<&ExtentKey>,
+) -> Vec<ExtentInfo> {
+    let ranges = if ranges.is_empty() {
+        let whole = DataRange::new(0, data.len() as u64).split_at(MAX_EXTENT_SIZE);
+        std::iter::successors(Some(whole), |(_, rest)| {
+            rest.map(|rest| rest.split_at(MAX_EXTENT_SIZE))
+        })
+        .map(|(piece, _)| piece)
+        .collect()
+    } else {
+        ranges
+    };
 
-    chunks
+    let mut fs_extent_idx: u32 = 0;
+    ranges
+        .into_iter()
+        .filter_map(|range| {
+            if !range.continued {
+                fs_extent_idx += 1;
+            }
+            range_to_extent_info(range, data, fs_extent_idx, key)
+        })
+        .collect()
 }
 
 /// Process a file's extents and compute its blob information.
 ///
 /// Returns `None` for empty files or files that cannot have extents.
 pub fn process_file_extents(path: &Path) -> io::Result<Option<BlobInfo>> {
+    process_file_extents_with_reader(path, &mut RangeReader::new(), None)
+}
+
+/// Process a file's extents with a reusable RangeReader for better performance
+/// when processing multiple files.
+///
+/// The reader is set to [split](RangeReaderImpl::split_at) ranges at MAX_EXTENT_SIZE, so
+/// extents come from it already subchunked. Files stored entirely
+/// [inline](extentria::RangeFlags::inline) with their metadata, as small files are on
+/// btrfs, are read into memory rather than mapped. With a key, extent and blob IDs are
+/// keyed hashes of their data (see [`ExtentKey`]).
+pub fn process_file_extents_with_reader<R: RangeReaderImpl>(
+    path: &Path,
+    reader: &mut R,
+    key: Option<&ExtentKey>,
+) -> io::Result<Option<BlobInfo>> {
     debug!(?path, "Processing file extents");
 
     let file = File::open(path)?;
@@ -108,120 +135,110 @@ pub fn process_file_extents(path: &Path) -> io::Result<Option<BlobInfo>> {
 
     if file_len == 0 {
         return Ok(Some(BlobInfo {
-            blob_id: B3Id::hash(&[]),
+            blob_id: B3Id::hash_with(&[], key),
             bytes: 0,
             extents: Vec::new(),
         }));
     }
 
-    let mmap = unsafe { Mmap::map(&file)? };
-
     // Get extent information using cross-platform API
-    let mut reader = RangeReader::new();
-    let ranges: Result<Vec<DataRange>, _> = reader.read_ranges(&file)?.collect();
-    let ranges = ranges?;
-
-    if ranges.is_empty() {
-        // No extents reported, treat whole file as one extent
-        // Still apply subchunking if file is large
-        let single_range = DataRange::new(0, file_len);
-        let extents = range_to_extent_infos(single_range, &mmap, 1);
-
-        let mut blob_hasher = Hasher::new();
-        blob_hasher.update(&mmap[..]);
-        let blob_id = B3Id::from(blob_hasher.finalize());
+    reader.split_at(Some(MAX_EXTENT_SIZE));
+    let ranges: Vec<DataRange> = reader.read_ranges(&file)?.collect::<Result<_, _>>()?;
 
+    // Inline data has no blocks of its own to map
+    if !ranges.is_empty() && ranges.iter().all(|range| range.flags.inline) {
+        debug!(?path, bytes = file_len, "Reading inline file");
+        let data = fs::read(path)?;
         return Ok(Some(BlobInfo {
-            blob_id,
-            bytes: file_len,
-            extents,
+            blob_id: B3Id::hash_with(&data, key),
+            bytes: data.len() as u64,
+            extents: ranges_to_extent_infos(ranges, &data, key),
         }));
     }
 
-    // Convert ranges to ExtentInfo with subchunking, computing hashes for data ranges
-    // Each filesystem extent gets a unique fs_extent index
-    let mut extents: Vec<ExtentInfo> = Vec::new();
-    let mut fs_extent_idx: u32 = 0;
-
-    for range in ranges {
-        fs_extent_idx += 1;
-        let chunk_infos = range_to_extent_infos(range, &mmap, fs_extent_idx);
-        extents.extend(chunk_infos);
-    }
-
-    // Compute blob hash (hash of full file contents)
-    let mut blob_hasher = Hasher::new();
-    blob_hasher.update_rayon(&mmap[..]);
-    let blob_id = B3Id::from(blob_hasher.finalize());
+    let mmap = unsafe { Mmap::map(&file)? };
+    let extents = ranges_to_extent_infos(ranges, &mmap, key);
 
     Ok(Some(BlobInfo {
-        blob_id,
+        blob_id: hash_mapped(&mmap, key),
         bytes: file_len,
         extents,
     }))
 }
 
-/// Process a file's extents with a reusable RangeReader for better performance
-/// when processing multiple files.
-pub fn process_file_extents_with_reader(
-    path: &Path,
-    reader: &mut RangeReader,
-) -> io::Result<Option<BlobInfo>> {
-    debug!(?path, "Processing file extents");
-
+/// Hash a file's whole contents, giving the ID its blob would have, without hashing its
+/// extents.
+pub fn hash_file(path: &Path, key: Option<&ExtentKey>) -> io::Result<B3Id> {
     let file = File::open(path)?;
-    let file_len = file.metadata()?.len();
-
-    if file_len == 0 {
-        return Ok(Some(BlobInfo {
-            blob_id: B3Id::hash(&[]),
-            bytes: 0,
-            extents: Vec::new(),
-        }));
+    if file.metadata()?.len() == 0 {
+        return Ok(B3Id::hash_with(&[], key));
     }
-
     let mmap = unsafe { Mmap::map(&file)? };
+    Ok(hash_mapped(&mmap, key))
+}
 
-    // Get extent information using cross-platform API
-    let ranges: Result<Vec<DataRange>, _> = reader.read_ranges(&file)?.collect();
-    let ranges = ranges?;
-
-    if ranges.is_empty() {
-        // No extents reported, treat whole file as one extent
-        // Still apply subchunking if file is large
-        let single_range = DataRange::new(0, file_len);
-        let extents = range_to_extent_infos(single_range, &mmap, 1);
+/// Compute a blob hash (hash of full file contents).
+fn hash_mapped(mmap: &Mmap, key: Option<&ExtentKey>) -> B3Id {
+    let mut blob_hasher = B3Id::hasher(key);
+    blob_hasher.update_rayon(&mmap[..]);
+    B3Id::from(blob_hasher.finalize())
+}
 
-        let mut blob_hasher = Hasher::new();
-        blob_hasher.update(&mmap[..]);
-        let blob_id = B3Id::from(blob_hasher.finalize());
+#[cfg(test)]
+mod tests {
+    use extentria::RangeFlags;
 
-        return Ok(Some(BlobInfo {
-            blob_id,
-            bytes: file_len,
-            extents,
-        }));
-    }
+    use super::*;
 
-    // Convert ranges to ExtentInfo with subchunking, computing hashes for data ranges
-    // Each filesystem extent gets a unique fs_extent index
-    let mut extents: Vec<ExtentInfo> = Vec::new();
-    let mut fs_extent_idx: u32 = 0;
+    #[test]
+    fn inline_ranges_are_hashed_from_memory() {
+        let data = b"small file kept in metadata";
+        let range = DataRange::new(0, 4096).with_flags(RangeFlags {
+            inline: true,
+            ..Default::default()
+        });
 
-    for range in ranges {
-        fs_extent_idx += 1;
-        let chunk_infos = range_to_extent_infos(range, &mmap, fs_extent_idx);
-        extents.extend(chunk_infos);
+        let extents = ranges_to_extent_infos(vec![range], data, None);
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].extent_id, B3Id::hash(data));
+        assert_eq!(extents[0].range.length, data.len() as u64);
+        assert_eq!(extents[0].fs_extent, 1);
     }
 
-    // Compute blob hash (hash of full file contents)
-    let mut blob_hasher = Hasher::new();
-    blob_hasher.update_rayon(&mmap[..]);
-    let blob_id = B3Id::from(blob_hasher.finalize());
-
-    Ok(Some(BlobInfo {
-        blob_id,
-        bytes: file_len,
-        extents,
-    }))
+    #[test]
+    fn sparse_ranges_are_subchunked_around_holes() {
+        use extentria::MockRangeReader;
+
+        let size = 2 * MAX_EXTENT_SIZE + 4096;
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut temp, &vec![7; size as usize]).unwrap();
+
+        // Whatever the temp dir's filesystem, the file is read as having a hole
+        let mut reader = MockRangeReader::with_ranges([
+            DataRange::new(0, 4096),
+            DataRange::hole(4096, 4096),
+            DataRange::new(8192, size - 8192),
+        ]);
+        let blob = process_file_extents_with_reader(temp.path(), &mut reader, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reader.reads(), 1);
+        assert_eq!(blob.bytes, size);
+
+        let layout: Vec<_> = blob
+            .extents
+            .iter()
+            .map(|extent| (extent.fs_extent, extent.range.to_tuple(), extent.range.hole))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (1, (0, 4096), false),
+                (2, (4096, 4096), true),
+                (3, (8192, MAX_EXTENT_SIZE), false),
+                (3, (8192 + MAX_EXTENT_SIZE, MAX_EXTENT_SIZE - 4096), false),
+            ]
+        );
+        assert_eq!(blob.extents[1].extent_id, B3Id::from([0u8; 32]));
+    }
 }
@@ -0,0 +1,258 @@
+//! Reading byte ranges of a blob from the server.
+//!
+//! A blob is stored as its extents, so reading part of a file (for a mounted catalog, or a
+//! ranged restore) only needs the extents covering that part, and only the bytes of them
+//! that fall within it. [`plan_read`] works out which, and [`BlobFetcher`] fetches them with
+//! ranged requests and puts the bytes together, with zeros for holes.
+
+use std::ops::Range;
+
+use futures::{StreamExt, TryStreamExt, stream};
+use reqwest::{Client, StatusCode, header};
+
+use crate::B3Id;
+use crate::extents::BlobInfo;
+use crate::migrate::ExtentSlice;
+
+/// A part of a read, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPart {
+    /// Bytes of a stored extent.
+    Extent(ExtentSlice),
+    /// Zero bytes: sparse holes, preallocated ranges, and gaps in the layout.
+    Zeros(u64),
+}
+
+impl ReadPart {
+    /// How many bytes this part is.
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Extent(slice) => slice.length,
+            Self::Zeros(length) => *length,
+        }
+    }
+
+    /// Whether this part is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Work out which extents, and which bytes of them, make up a range of a blob.
+///
+/// The range is cut short at the end of the blob. Parts are in order and add up to the
+/// length of the range, with adjacent zeros merged.
+pub fn plan_read(blob: &BlobInfo, range: Range<u64>) -> Vec<ReadPart> {
+    let end = range.end.min(blob.bytes);
+    let mut parts = Vec::new();
+    let mut position = range.start;
+    let mut push = |part: ReadPart| match (parts.last_mut(), part) {
+        (Some(ReadPart::Zeros(last)), ReadPart::Zeros(length)) => *last += length,
+        (_, part) => parts.push(part),
+    };
+
+    for extent in &blob.extents {
+        let extent_end = extent.range.offset + extent.range.length;
+        if extent_end <= position || extent.range.length == 0 {
+            continue;
+        }
+        if extent.range.offset >= end {
+            break;
+        }
+        if extent.range.offset > position {
+            push(ReadPart::Zeros(extent.range.offset - position));
+            position = extent.range.offset;
+        }
+
+        let length = extent_end.min(end) - position;
+        if extent.range.is_zero() {
+            push(ReadPart::Zeros(length));
+        } else {
+            push(ReadPart::Extent(ExtentSlice {
+                source: extent.extent_id,
+                offset: position - extent.range.offset,
+                length,
+            }));
+        }
+        position += length;
+    }
+    if position < end {
+        push(ReadPart::Zeros(end - position));
+    }
+    parts
+}
+
+/// Error fetching blob data.
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Server returned {status} for extent {extent}")]
+    Status { extent: B3Id, status: StatusCode },
+
+    #[error("Server returned {actual} bytes of extent {extent}, expected {expected}")]
+    Length {
+        extent: B3Id,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+/// Fetches byte ranges of blobs from the server.
+///
+/// The client should be set up as for uploads, with the auth token and extent key headers
+/// the server needs. Extents are fetched a few at a time, and only the bytes needed of each.
+/// As partial extents can't be checked against their ID, data is taken as the server sends
+/// it.
+#[derive(Debug, Clone)]
+pub struct BlobFetcher {
+    client: Client,
+    server_url: String,
+    parallel: usize,
+}
+
+impl BlobFetcher {
+    /// Create a fetcher for a server.
+    pub fn new(client: Client, server_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            server_url: server_url.into().trim_end_matches('/').to_string(),
+            parallel: 8,
+        }
+    }
+
+    /// Set how many extents to fetch at once.
+    pub fn with_parallel(mut self, parallel: usize) -> Self {
+        self.parallel = parallel.max(1);
+        self
+    }
+
+    /// Read a range of a blob.
+    ///
+    /// The range is cut short at the end of the blob.
+    pub async fn read(&self, blob: &BlobInfo, range: Range<u64>) -> Result<Vec<u8>, FetchError> {
+        let parts = plan_read(blob, range);
+        let mut data = Vec::with_capacity(parts.iter().map(ReadPart::len).sum::<u64>() as usize);
+        let mut fetched = stream::iter(parts)
+            .map(|part| async move {
+                match part {
+                    ReadPart::Extent(slice) => self.fetch(slice).await,
+                    ReadPart::Zeros(length) => Ok(vec![0; length as usize]),
+                }
+            })
+            .buffered(self.parallel);
+        while let Some(bytes) = fetched.try_next().await? {
+            data.extend_from_slice(&bytes);
+        }
+        Ok(data)
+    }
+
+    /// Fetch a slice of an extent.
+    async fn fetch(&self, slice: ExtentSlice) -> Result<Vec<u8>, FetchError> {
+        let url = format!("{}/extents/{}", self.server_url, slice.source);
+        let end = slice.offset + slice.length - 1;
+        let resp = self
+            .client
+            .get(&url)
+            .header(header::RANGE, format!("bytes={}-{}", slice.offset, end))
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let bytes = resp.bytes().await?;
+        let bytes = match status {
+            StatusCode::PARTIAL_CONTENT => &bytes[..],
+            // Without range support, the whole extent comes back
+            StatusCode::OK => bytes
+                .get(slice.offset as usize..=end as usize)
+                .unwrap_or_default(),
+            status => {
+                return Err(FetchError::Status {
+                    extent: slice.source,
+                    status,
+                });
+            }
+        };
+        if bytes.len() as u64 != slice.length {
+            return Err(FetchError::Length {
+                extent: slice.source,
+                expected: slice.length,
+                actual: bytes.len() as u64,
+            });
+        }
+        Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use extentria::DataRange;
+
+    use super::*;
+    use crate::extents::ExtentInfo;
+
+    fn blob() -> BlobInfo {
+        let extent = |id: u8, range| ExtentInfo {
+            extent_id: B3Id::from([id; 32]),
+            range,
+            fs_extent: 0,
+        };
+        BlobInfo {
+            blob_id: B3Id::from([0; 32]),
+            bytes: 50,
+            extents: vec![
+                extent(1, DataRange::new(0, 10)),
+                extent(2, DataRange::new(10, 10)),
+                extent(0, DataRange::hole(20, 10)),
+                extent(0, DataRange::unwritten(30, 10)),
+                extent(3, DataRange::new(40, 10)),
+            ],
+        }
+    }
+
+    fn slice(id: u8, offset: u64, length: u64) -> ReadPart {
+        ReadPart::Extent(ExtentSlice {
+            source: B3Id::from([id; 32]),
+            offset,
+            length,
+        })
+    }
+
+    #[test]
+    fn plans_only_needed_bytes() {
+        let blob = blob();
+        assert_eq!(plan_read(&blob, 5..15), [slice(1, 5, 5), slice(2, 0, 5)]);
+        assert_eq!(plan_read(&blob, 12..14), [slice(2, 2, 2)]);
+        assert_eq!(
+            plan_read(&blob, 15..45),
+            [slice(2, 5, 5), ReadPart::Zeros(20), slice(3, 0, 5)]
+        );
+        assert_eq!(plan_read(&blob, 0..50).len(), 4);
+    }
+
+    #[test]
+    fn plan_stops_at_end_of_blob() {
+        let blob = blob();
+        assert_eq!(plan_read(&blob, 45..100), [slice(3, 5, 5)]);
+        assert!(plan_read(&blob, 50..100).is_empty());
+        assert!(plan_read(&blob, 10..10).is_empty());
+    }
+
+    #[test]
+    fn gaps_read_as_zeros() {
+        let mut blob = blob();
+        // Bytes not covered by any extent, inside the blob or past its last extent
+        blob.bytes = 60;
+        blob.extents.remove(1);
+        assert_eq!(
+            plan_read(&blob, 5..60),
+            [
+                slice(1, 5, 5),
+                ReadPart::Zeros(30),
+                slice(3, 0, 10),
+                ReadPart::Zeros(10)
+            ]
+        );
+    }
+}
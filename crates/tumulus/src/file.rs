@@ -1,21 +1,31 @@
 //! File metadata and processing functionality.
 
-use std::{fs, io, path::Path};
+use std::{
+    fs, io,
+    path::{Component, Path},
+};
 
-use crate::B3Id;
+use crate::{B3Id, ExtentKey};
 
 #[cfg(unix)]
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
 use extentria::RangeReader;
 use serde_json::json;
+use tracing::{debug, warn};
 
-use crate::extents::{BlobInfo, process_file_extents, process_file_extents_with_reader};
+use crate::apple::{RESOURCE_FORK, read_apple_metadata, stream_path};
+use crate::blob_index::BlobIndex;
+use crate::extents::{BlobInfo, hash_file, process_file_extents, process_file_extents_with_reader};
+use crate::manifest::ManifestEntry;
+use crate::xattr::{read_xattrs, xattrs_to_value};
 
 /// Information about a file to be cataloged
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub relative_path: String,
+    /// Prefix of the source root this file was found under, for multi-root catalogs.
+    pub root: Option<String>,
     pub blob: Option<BlobInfo>,
     pub ts_created: Option<i64>,
     pub ts_modified: Option<i64>,
@@ -25,7 +35,23 @@ pub struct FileInfo {
     pub unix_owner_id: Option<u32>,
     pub unix_group_id: Option<u32>,
     pub fs_inode: Option<u64>,
+    /// Filesystem-reported change cookie, which changes whenever the file does (the USN on
+    /// Windows).
+    pub fs_change_cookie: Option<i64>,
     pub special: Option<serde_json::Value>,
+    /// Priority rank from the catalog's priority patterns (lower first), if any were given.
+    pub priority: Option<u32>,
+    /// Extended metadata, like macOS Finder attributes.
+    pub attributes: Option<serde_json::Value>,
+    /// Secondary data streams, like macOS resource forks.
+    pub streams: Vec<StreamInfo>,
+}
+
+/// A secondary data stream of a file, stored as a blob of its own.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub name: String,
+    pub blob: BlobInfo,
 }
 
 /// Extract Unix-specific metadata from file metadata.
@@ -43,7 +69,14 @@ fn extract_platform_metadata(
     Option<u32>,
     Option<u64>,
 ) {
-    let ts_created = None; // Linux doesn't have creation time in standard stat
+    use std::time::UNIX_EPOCH;
+
+    // Birth time isn't in the standard stat, but std uses statx where available
+    let ts_created = metadata
+        .created()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as i64);
     let ts_modified = metadata.mtime().checked_mul(1000);
     let ts_accessed = metadata.atime().checked_mul(1000);
     let ts_changed = metadata.ctime().checked_mul(1000);
@@ -124,6 +157,207 @@ fn extract_platform_metadata(
     )
 }
 
+/// Describe a special (non-regular) file for the `special` column.
+fn special_info(path: &Path, metadata: &fs::Metadata) -> io::Result<Option<serde_json::Value>> {
+    let file_type = metadata.file_type();
+    Ok(if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        Some(json!({
+            "type": "symlink",
+            "target": target.to_string_lossy()
+        }))
+    } else if file_type.is_dir() {
+        Some(json!({ "type": "directory" }))
+    } else if !file_type.is_file() {
+        Some(device_info(metadata))
+    } else {
+        None
+    })
+}
+
+/// Describe a device, FIFO, or socket.
+#[cfg(unix)]
+fn device_info(metadata: &fs::Metadata) -> serde_json::Value {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    if file_type.is_block_device() {
+        json!({ "type": "block", "rdev": metadata.rdev() })
+    } else if file_type.is_char_device() {
+        json!({ "type": "char", "rdev": metadata.rdev() })
+    } else if file_type.is_fifo() {
+        json!({ "type": "fifo" })
+    } else if file_type.is_socket() {
+        json!({ "type": "socket" })
+    } else {
+        json!({ "type": "other" })
+    }
+}
+
+/// Describe a device, FIFO, or socket (no further detail on this platform).
+#[cfg(not(unix))]
+fn device_info(_metadata: &fs::Metadata) -> serde_json::Value {
+    json!({ "type": "other" })
+}
+
+/// Get the filesystem's change cookie for a file, if it has one.
+///
+/// On Windows this is the NTFS/ReFS update sequence number of the last change to the
+/// file. Elsewhere there's none: Linux doesn't expose the inode change attribute to
+/// userspace, and `st_gen` on macOS and FreeBSD is the inode generation, which only
+/// changes when the inode is reused, so the ctime is the best change signal.
+#[cfg(windows)]
+fn change_cookie(path: &Path, metadata: &fs::Metadata) -> Option<i64> {
+    use std::os::windows::io::AsRawHandle;
+
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+    use windows_sys::Win32::System::Ioctl::FSCTL_READ_FILE_USN_DATA;
+
+    if !metadata.is_file() {
+        return None;
+    }
+
+    let file = fs::File::open(path).ok()?;
+    // Room for a USN_RECORD_V3 with a long filename, aligned for the 64-bit fields
+    let mut buffer = [0u64; 128];
+    let mut bytes_returned: u32 = 0;
+
+    let result = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as _,
+            FSCTL_READ_FILE_USN_DATA,
+            std::ptr::null(),
+            0,
+            buffer.as_mut_ptr() as *mut _,
+            std::mem::size_of_val(&buffer) as u32,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if result == 0 {
+        return None;
+    }
+
+    // USN records start with RecordLength (u32), MajorVersion (u16), MinorVersion (u16),
+    // then file reference numbers which are 64-bit in V2 and 128-bit in V3, then the USN.
+    let bytes: &[u8] = unsafe {
+        std::slice::from_raw_parts(buffer.as_ptr() as *const u8, bytes_returned as usize)
+    };
+    let major = u16::from_le_bytes(bytes.get(4..6)?.try_into().ok()?);
+    let usn_offset = match major {
+        2 => 24,
+        3 => 40,
+        _ => return None,
+    };
+    let usn = i64::from_le_bytes(bytes.get(usn_offset..usn_offset + 8)?.try_into().ok()?);
+    Some(usn)
+}
+
+/// Get the filesystem's change cookie for a file (not available on this platform).
+#[cfg(not(windows))]
+fn change_cookie(_path: &Path, _metadata: &fs::Metadata) -> Option<i64> {
+    None
+}
+
+impl FileInfo {
+    /// Move this file under a source root prefix, for multi-root catalogs.
+    ///
+    /// The relative path becomes `<prefix>/<relative path>`, or just `<prefix>` for the
+    /// root directory itself.
+    pub fn with_root(mut self, prefix: &str) -> Self {
+        self.relative_path = if self.relative_path.is_empty() {
+            prefix.to_string()
+        } else {
+            format!("{}/{}", prefix, self.relative_path)
+        };
+        self.root = Some(prefix.to_string());
+        self
+    }
+
+    /// Capture the file's macOS Finder metadata: its `com.apple.*` extended attributes,
+    /// and its resource fork if it's a regular file with one.
+    ///
+    /// `path` is where the file is on disk, and `key` is as for
+    /// [`process_file_with_reader()`]. This does nothing on other platforms.
+    pub fn with_apple_metadata(
+        mut self,
+        path: &Path,
+        reader: &mut RangeReader,
+        key: Option<&ExtentKey>,
+    ) -> io::Result<Self> {
+        let metadata = read_apple_metadata(path)?;
+        if !metadata.is_empty() {
+            self.attributes = Some(metadata.to_attributes());
+        }
+
+        if cfg!(target_os = "macos") && self.special.is_none() {
+            let fork = stream_path(path, RESOURCE_FORK);
+            if fs::metadata(&fork).is_ok_and(|m| m.len() > 0)
+                && let Some(blob) = process_file_extents_with_reader(&fork, reader, key)?
+            {
+                self.streams.push(StreamInfo {
+                    name: RESOURCE_FORK.to_string(),
+                    blob,
+                });
+            }
+        }
+        Ok(self)
+    }
+
+    /// Capture what a full-system restore needs besides the file itself: all of its
+    /// extended attributes (see [`crate::xattr`]), including ACLs and capabilities, under
+    /// `xattrs`, and for files with more than one hardlink, their count under `links`.
+    ///
+    /// The hardlinks of a file are the entries of the same root with the same `fs_inode`.
+    pub fn with_system_metadata(mut self, path: &Path) -> io::Result<Self> {
+        let xattrs = read_xattrs(path)?;
+        let links = hardlinks(&fs::symlink_metadata(path)?);
+        if xattrs.is_empty() && links.is_none() {
+            return Ok(self);
+        }
+
+        let mut attributes = match self.attributes.take() {
+            Some(serde_json::Value::Object(attributes)) => attributes,
+            _ => serde_json::Map::new(),
+        };
+        if !xattrs.is_empty() {
+            attributes.insert("xattrs".into(), xattrs_to_value(&xattrs));
+        }
+        if let Some(links) = links {
+            attributes.insert("links".into(), json!(links));
+        }
+        self.attributes = Some(serde_json::Value::Object(attributes));
+        Ok(self)
+    }
+}
+
+/// How many hardlinks a file has, if it has more than one.
+#[cfg(unix)]
+fn hardlinks(metadata: &fs::Metadata) -> Option<u64> {
+    (!metadata.is_dir() && metadata.nlink() > 1).then(|| metadata.nlink())
+}
+
+/// How many hardlinks a file has (not tracked on this platform).
+#[cfg(not(unix))]
+fn hardlinks(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Compute the catalog path prefix for a source root in a multi-root catalog.
+///
+/// This is the absolute path with its root (and drive prefix on Windows) removed, in
+/// normalised form: `/var/lib` becomes `var/lib`. The filesystem root itself has an empty
+/// prefix.
+pub fn root_prefix(root: &Path) -> String {
+    root.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 /// Process a file and extract its metadata and blob information.
 ///
 /// The `source_root` is used to compute the relative path for the file.
@@ -145,23 +379,9 @@ pub fn process_file(path: &Path, source_root: &Path) -> io::Result<FileInfo> {
         unix_group_id,
         fs_inode,
     ) = extract_platform_metadata(&metadata);
+    let fs_change_cookie = change_cookie(path, &metadata);
 
-    // Handle special files
-    let file_type = metadata.file_type();
-    let special = if file_type.is_symlink() {
-        let target = fs::read_link(path)?;
-        Some(json!({
-            "type": "symlink",
-            "target": target.to_string_lossy()
-        }))
-    } else if file_type.is_dir() {
-        Some(json!({ "type": "directory" }))
-    } else if !file_type.is_file() {
-        // Block device, char device, fifo, socket
-        Some(json!({ "type": "other" }))
-    } else {
-        None
-    };
+    let special = special_info(path, &metadata)?;
 
     // Only process regular files for blob/extent data
     let blob = if metadata.is_file() && metadata.len() > 0 {
@@ -179,6 +399,7 @@ pub fn process_file(path: &Path, source_root: &Path) -> io::Result<FileInfo> {
 
     Ok(FileInfo {
         relative_path,
+        root: None,
         blob,
         ts_created,
         ts_modified,
@@ -188,18 +409,148 @@ pub fn process_file(path: &Path, source_root: &Path) -> io::Result<FileInfo> {
         unix_owner_id,
         unix_group_id,
         fs_inode,
+        fs_change_cookie,
         special,
+        priority: None,
+        attributes: None,
+        streams: Vec::new(),
     })
 }
 
 /// Process a file with a reusable RangeReader for better performance.
 ///
 /// This is more efficient when processing multiple files as it reuses
-/// the internal buffer for extent queries (on platforms that use buffers).
+/// the internal buffer for extent queries (on platforms that use buffers). With a key,
+/// extent and blob IDs are keyed hashes of their data (see [`ExtentKey`]).
 pub fn process_file_with_reader(
     path: &Path,
     source_root: &Path,
     reader: &mut RangeReader,
+    key: Option<&ExtentKey>,
+) -> io::Result<FileInfo> {
+    scan_file(path, source_root, key, |_| {
+        process_file_extents_with_reader(path, reader, key)
+    })
+}
+
+/// Process a file a manifest describes, without reading it if the manifest has its layout.
+///
+/// The manifest's layout is only used if the file is still the size the manifest says;
+/// otherwise, or if the manifest has no layout for it, the file is read as by
+/// [`process_file_with_reader`], and a hash differing from the manifest's is logged.
+pub fn process_file_from_manifest(
+    path: &Path,
+    source_root: &Path,
+    entry: &ManifestEntry,
+    reader: &mut RangeReader,
+    key: Option<&ExtentKey>,
+) -> io::Result<FileInfo> {
+    scan_file(path, source_root, key, |metadata| {
+        if metadata.len() == entry.size
+            && let Some(blob) = entry.blob()
+        {
+            return Ok(Some(blob));
+        }
+
+        let blob = process_file_extents_with_reader(path, reader, key)?;
+        if let Some(ref blob) = blob
+            && blob.blob_id != entry.hash
+        {
+            warn!(?path, manifest = %entry.hash, actual = %blob.blob_id, "File differs from the manifest");
+        }
+        Ok(blob)
+    })
+}
+
+/// Process a file, reusing the extents of an identical blob from the index if there is one.
+///
+/// Files the same size as an indexed blob are hashed whole first: if the hash matches, the
+/// indexed extents are taken instead of hashing the file's own. Otherwise, the file is read
+/// as by [`process_file_with_reader`], so files that only match in size are read twice.
+pub fn process_file_with_index(
+    path: &Path,
+    source_root: &Path,
+    index: &BlobIndex,
+    reader: &mut RangeReader,
+    key: Option<&ExtentKey>,
+) -> io::Result<FileInfo> {
+    scan_file(path, source_root, key, |metadata| {
+        if index.has_size(metadata.len()) {
+            let blob_id = hash_file(path, key)?;
+            match index.get(&blob_id) {
+                Ok(Some(blob)) if blob.bytes == metadata.len() => {
+                    debug!(?path, blob = %blob_id, "Reusing extents of an indexed blob");
+                    return Ok(Some(blob));
+                }
+                Ok(_) => {}
+                Err(err) => warn!(?path, %err, "Couldn't look up file in the blob index"),
+            }
+        }
+        process_file_extents_with_reader(path, reader, key)
+    })
+}
+
+/// Process a file, reusing its blob from a previous catalog if it hasn't changed since.
+///
+/// The file is unchanged if it's still the same size, inode, and modification and change
+/// times (to the second), and has the same change cookie where the filesystem gives one,
+/// which unlike the times can't be set back by tools. As times are only kept to the
+/// second, a file whose times fall in or after the second the previous scan started at
+/// (`previous_started`, in milliseconds) could have changed again unseen after it was
+/// read, so it's never taken as unchanged. Otherwise, the file is read as by
+/// [`process_file_with_reader`].
+pub fn process_file_with_previous(
+    path: &Path,
+    source_root: &Path,
+    previous: &FileInfo,
+    previous_started: i64,
+    reader: &mut RangeReader,
+    key: Option<&ExtentKey>,
+) -> io::Result<FileInfo> {
+    scan_file(path, source_root, key, |metadata| {
+        if let Some(blob) = &previous.blob
+            && !is_racy(previous, previous_started)
+            && is_unchanged(previous, blob, path, metadata)
+        {
+            debug!(?path, blob = %blob.blob_id, "Reusing the blob of an unchanged file");
+            return Ok(Some(blob.clone()));
+        }
+        process_file_extents_with_reader(path, reader, key)
+    })
+}
+
+/// Whether a file was modified or changed in or after the second its scan started, so
+/// that it could have changed again within the same second after it was read.
+fn is_racy(previous: &FileInfo, started: i64) -> bool {
+    let started = started.div_euclid(1000);
+    [previous.ts_modified, previous.ts_changed]
+        .into_iter()
+        .flatten()
+        .any(|ts| ts.div_euclid(1000) >= started)
+}
+
+/// Whether a file is as it was when `previous`, with `blob`, was scanned.
+fn is_unchanged(
+    previous: &FileInfo,
+    blob: &BlobInfo,
+    path: &Path,
+    metadata: &fs::Metadata,
+) -> bool {
+    let (_, ts_modified, _, ts_changed, _, _, _, fs_inode) = extract_platform_metadata(metadata);
+    blob.bytes == metadata.len()
+        && ts_modified.is_some()
+        && ts_modified == previous.ts_modified
+        && ts_changed == previous.ts_changed
+        && fs_inode == previous.fs_inode
+        && change_cookie(path, metadata) == previous.fs_change_cookie
+}
+
+/// Scan a file's metadata, with `read_blob` to get the blob of non-empty regular files.
+fn scan_file(
+    path: &Path,
+    source_root: &Path,
+    key: Option<&ExtentKey>,
+    read_blob: impl FnOnce(&fs::Metadata) -> io::Result<Option<BlobInfo>>,
 ) -> io::Result<FileInfo> {
     let metadata = fs::symlink_metadata(path)?;
     let relative_path = path
@@ -218,31 +569,17 @@ pub fn process_file_with_reader(
         unix_group_id,
         fs_inode,
     ) = extract_platform_metadata(&metadata);
+    let fs_change_cookie = change_cookie(path, &metadata);
 
-    // Handle special files
-    let file_type = metadata.file_type();
-    let special = if file_type.is_symlink() {
-        let target = fs::read_link(path)?;
-        Some(json!({
-            "type": "symlink",
-            "target": target.to_string_lossy()
-        }))
-    } else if file_type.is_dir() {
-        Some(json!({ "type": "directory" }))
-    } else if !file_type.is_file() {
-        // Block device, char device, fifo, socket
-        Some(json!({ "type": "other" }))
-    } else {
-        None
-    };
+    let special = special_info(path, &metadata)?;
 
     // Only process regular files for blob/extent data
     let blob = if metadata.is_file() && metadata.len() > 0 {
-        process_file_extents_with_reader(path, reader)?
+        read_blob(&metadata)?
     } else if metadata.is_file() {
         // Zero-sized file still gets a blob
         Some(BlobInfo {
-            blob_id: B3Id::hash(&[]),
+            blob_id: B3Id::hash_with(&[], key),
             bytes: 0,
             extents: Vec::new(),
         })
@@ -252,6 +589,7 @@ pub fn process_file_with_reader(
 
     Ok(FileInfo {
         relative_path,
+        root: None,
         blob,
         ts_created,
         ts_modified,
@@ -261,6 +599,158 @@ pub fn process_file_with_reader(
         unix_owner_id,
         unix_group_id,
         fs_inode,
+        fs_change_cookie,
         special,
+        priority: None,
+        attributes: None,
+        streams: Vec::new(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use extentria::RangeReaderImpl;
+
+    use super::*;
+
+    #[test]
+    fn root_prefixes() {
+        assert_eq!(root_prefix(Path::new("/etc")), "etc");
+        assert_eq!(root_prefix(Path::new("/var/lib")), "var/lib");
+        assert_eq!(root_prefix(Path::new("/")), "");
+    }
+
+    #[test]
+    fn with_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "hello").unwrap();
+
+        let info = process_file(&file, dir.path()).unwrap().with_root("srv");
+        assert_eq!(info.relative_path, "srv/a.txt");
+        assert_eq!(info.root.as_deref(), Some("srv"));
+
+        let info = process_file(dir.path(), dir.path())
+            .unwrap()
+            .with_root("srv");
+        assert_eq!(info.relative_path, "srv");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn system_metadata_records_hardlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, link, alone) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("c"),
+        );
+        fs::write(&file, "shared").unwrap();
+        fs::hard_link(&file, &link).unwrap();
+        fs::write(&alone, "alone").unwrap();
+
+        let info = process_file(&link, dir.path())
+            .unwrap()
+            .with_system_metadata(&link)
+            .unwrap();
+        let attributes = info.attributes.unwrap();
+        assert_eq!(attributes["links"], json!(2));
+        assert_eq!(
+            info.fs_inode,
+            process_file(&file, dir.path()).unwrap().fs_inode
+        );
+
+        let info = process_file(&alone, dir.path())
+            .unwrap()
+            .with_system_metadata(&alone)
+            .unwrap();
+        assert!(info.attributes.is_none_or(|a| a.get("links").is_none()));
+    }
+
+    #[test]
+    fn copies_reuse_indexed_extents() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let (original, copy, other) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("c"),
+        );
+        fs::write(&original, &data).unwrap();
+        fs::write(&copy, &data).unwrap();
+        fs::write(&other, vec![1u8; data.len()]).unwrap();
+
+        // Mark the indexed layout, to tell it apart from a fresh scan
+        let mut uploaded = process_file(&original, dir.path()).unwrap();
+        for extent in &mut uploaded.blob.as_mut().unwrap().extents {
+            extent.fs_extent += 100;
+        }
+        let catalog = rusqlite::Connection::open_in_memory().unwrap();
+        crate::catalog::create_catalog_schema(&catalog).unwrap();
+        crate::catalog::write_catalog(&catalog, &[uploaded.clone()]).unwrap();
+        let mut index =
+            BlobIndex::from_connection(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        index.record_catalog(&catalog, "server").unwrap();
+
+        let mut reader = RangeReader::new();
+        let blob = process_file_with_index(&copy, dir.path(), &index, &mut reader, None)
+            .unwrap()
+            .blob
+            .unwrap();
+        let expected = uploaded.blob.unwrap();
+        assert_eq!(blob.blob_id, expected.blob_id);
+        assert_eq!(blob.extents.len(), expected.extents.len());
+        assert!(blob.extents.iter().all(|extent| extent.fs_extent > 100));
+
+        let blob = process_file_with_index(&other, dir.path(), &index, &mut reader, None)
+            .unwrap()
+            .blob
+            .unwrap();
+        assert_ne!(blob.blob_id, expected.blob_id);
+        assert!(blob.extents.iter().all(|extent| extent.fs_extent < 100));
+    }
+
+    #[test]
+    fn unchanged_files_reuse_previous_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, "hello").unwrap();
+
+        // Mark the previous blob, to tell it apart from a fresh scan
+        let mut previous = process_file(&path, dir.path()).unwrap();
+        let marker = B3Id::hash(b"previous");
+        previous.blob.as_mut().unwrap().blob_id = marker;
+
+        let mut reader = RangeReader::new();
+        // Scanned well after the file was written
+        let started = previous.ts_changed.unwrap() + 10_000;
+        let mut rescan = |previous: &FileInfo, started: i64| {
+            process_file_with_previous(&path, dir.path(), previous, started, &mut reader, None)
+                .unwrap()
+                .blob
+                .unwrap()
+                .blob_id
+        };
+        assert_eq!(rescan(&previous, started), marker);
+
+        // Scanned within the same second the file was written, so it may have been written
+        // again after being read without its times showing it
+        let racy = previous.ts_changed.unwrap() + 999;
+        assert_ne!(rescan(&previous, racy), marker);
+
+        // A change cookie that no longer matches
+        let mut changed = previous.clone();
+        changed.fs_change_cookie = Some(changed.fs_change_cookie.unwrap_or(0) + 1);
+        assert_ne!(rescan(&changed, started), marker);
+
+        // A different modification time
+        let mut changed = previous.clone();
+        changed.ts_modified = changed.ts_modified.map(|ts| ts - 1000);
+        assert_ne!(rescan(&changed, started), marker);
+
+        fs::write(&path, "hello, world").unwrap();
+        assert_ne!(rescan(&previous, started), marker);
+    }
+}
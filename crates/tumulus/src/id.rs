@@ -2,8 +2,16 @@
 //!
 //! This module provides the `B3Id` type, a newtype wrapper around `blake3::Hash`
 //! used for extent IDs, blob IDs, and other content-addressed identifiers.
+//!
+//! Plain BLAKE3 hashes of data anyone has can be matched against a catalog or a server's
+//! storage, revealing which well-known files a machine holds. An [`ExtentKey`] makes
+//! extent and blob IDs keyed hashes instead, which only match within a repository using
+//! the same key.
+
+use std::{array::TryFromSliceError, io, ops::Deref};
 
-use std::{array::TryFromSliceError, ops::Deref};
+use crate::encryption::{KEY_LEN, parse_key};
+use crate::secrets::{SecretError, SecretsProvider};
 
 /// Newtype for blake3 hashes used as IDs (extent IDs, blob IDs, etc.)
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -16,6 +24,22 @@ impl B3Id {
         Self(blake3::hash(data))
     }
 
+    /// Create a B3Id by hashing the given data, keyed if there's a key.
+    pub fn hash_with(data: &[u8], key: Option<&ExtentKey>) -> Self {
+        match key {
+            Some(key) => key.hash(data),
+            None => Self::hash(data),
+        }
+    }
+
+    /// A hasher for data too large to hash in one go, keyed if there's a key.
+    pub fn hasher(key: Option<&ExtentKey>) -> blake3::Hasher {
+        match key {
+            Some(key) => blake3::Hasher::new_keyed(&key.key),
+            None => blake3::Hasher::new(),
+        }
+    }
+
     /// Get the underlying bytes as a slice.
     pub fn as_slice(&self) -> &[u8] {
         self.0.as_bytes().as_slice()
@@ -67,6 +91,78 @@ impl std::fmt::Display for B3Id {
     }
 }
 
+/// Serialized as hex.
+impl serde::Serialize for B3Id {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.as_hex())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for B3Id {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        blake3::Hash::from_hex(hex.as_bytes())
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Key for deriving extent and blob IDs with keyed BLAKE3.
+///
+/// The hashing key is derived from a repository secret, so the same secret can be used
+/// for other purposes (like [`CatalogCipher`](crate::CatalogCipher)) without the keys
+/// being related.
+#[derive(Clone)]
+pub struct ExtentKey {
+    key: [u8; KEY_LEN],
+    key_id: String,
+}
+
+impl std::fmt::Debug for ExtentKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtentKey")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExtentKey {
+    /// Create an extent key from a repository secret.
+    pub fn new(secret: &[u8; KEY_LEN]) -> Self {
+        let derive = |context: &str| blake3::derive_key(context, secret);
+        Self {
+            key: derive("tumulus extent 2025 id key"),
+            key_id: blake3::Hash::from(derive("tumulus extent 2025 key id")).to_hex()[..16]
+                .to_string(),
+        }
+    }
+
+    /// Create an extent key from a secret given as 32 raw bytes or 64 hex characters.
+    pub fn from_key_bytes(contents: &[u8]) -> io::Result<Self> {
+        Ok(Self::new(&parse_key(contents)?))
+    }
+
+    /// Create an extent key from a secret fetched from a secrets provider.
+    pub fn from_secret(provider: &dyn SecretsProvider) -> Result<Self, SecretError> {
+        Ok(Self::from_key_bytes(&provider.fetch()?)?)
+    }
+
+    /// A short identifier for the key, safe to store alongside the IDs it derives.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The BLAKE3 key IDs are derived with, for servers that verify keyed extents.
+    pub fn hashing_key(&self) -> &[u8; KEY_LEN] {
+        &self.key
+    }
+
+    /// Derive the ID of some data.
+    pub fn hash(&self, data: &[u8]) -> B3Id {
+        B3Id(blake3::keyed_hash(&self.key, data))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,6 +175,15 @@ mod tests {
         assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    #[test]
+    fn serde_as_hex() {
+        let id = B3Id::hash(b"hello world");
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id.as_hex()));
+        assert_eq!(serde_json::from_str::<B3Id>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<B3Id>("\"abc\"").is_err());
+    }
+
     #[test]
     fn display_matches_as_hex() {
         let id = B3Id::hash(b"test data");
@@ -103,6 +208,24 @@ mod tests {
         assert!(B3Id::try_from(bad_bytes).is_err());
     }
 
+    #[test]
+    fn keyed_ids_depend_on_the_key() {
+        let key = ExtentKey::new(&[1; KEY_LEN]);
+        let other = ExtentKey::new(&[2; KEY_LEN]);
+        let id = B3Id::hash_with(b"data", Some(&key));
+
+        assert_eq!(id, key.hash(b"data"));
+        assert_ne!(id, B3Id::hash(b"data"));
+        assert_ne!(id, other.hash(b"data"));
+        assert_ne!(key.key_id(), other.key_id());
+        assert_eq!(B3Id::hash_with(b"data", None), B3Id::hash(b"data"));
+
+        let mut hasher = B3Id::hasher(Some(&key));
+        hasher.update(b"da");
+        hasher.update(b"ta");
+        assert_eq!(B3Id::from(hasher.finalize()), id);
+    }
+
     #[test]
     fn as_slice() {
         let id = B3Id::hash(b"slice test");
@@ -3,24 +3,76 @@
 //! This library provides functionality to build snapshot catalogs from directory trees,
 //! tracking file extents, blobs, and metadata in a SQLite database.
 
+pub mod apple;
+pub mod blob_index;
+pub mod browse;
 pub mod catalog;
 pub mod compression;
+pub mod diff;
+pub mod encryption;
+pub mod exclude;
 pub mod extents;
+pub mod fetch;
 pub mod file;
 pub mod id;
 pub mod machine;
+pub mod manifest;
+pub mod migrate;
+pub mod names;
+pub mod priority;
+pub mod restore;
+pub mod secrets;
+pub mod special;
+pub mod system;
+pub mod transport;
 pub mod tree;
+pub mod upload;
+pub mod verify;
+pub mod xattr;
 
-pub use catalog::{CatalogStats, create_catalog_schema, write_catalog};
+pub use apple::{
+    AppleMetadata, read_apple_metadata, restore_apple_metadata, restore_resource_fork, stream_path,
+};
+pub use blob_index::BlobIndex;
+pub use browse::{CatalogTree, TreeNode, TreeStats};
+pub use catalog::{
+    CatalogStats, CatalogWriter, catalog_stats, create_catalog_schema, read_catalog_files,
+    read_tree_hashes, subtree_hash_version, write_catalog, write_tree_hashes,
+};
 pub use compression::{
-    DEFAULT_COMPRESSION_LEVEL, compress_catalog_in_place, compress_file, decompress_file,
-    is_zstd_compressed, open_catalog,
+    CatalogOpenOptions, DEFAULT_COMPRESSION_LEVEL, compress_catalog_in_place, compress_file,
+    decompress_file, is_zstd_compressed, open_catalog, open_catalog_with_options,
+};
+pub use diff::{
+    Rename, RenameMatch, changed_directories, detect_renames, record_renames, recorded_renames,
 };
+pub use encryption::{CatalogCipher, CipherError};
+pub use exclude::{AutoExclude, ExclusionReason};
 pub use extentria::{RangeReader, RangeReaderImpl};
 pub use extents::{
-    BlobInfo, ExtentInfo, MAX_EXTENT_SIZE, process_file_extents, process_file_extents_with_reader,
+    BlobInfo, ExtentInfo, MAX_EXTENT_SIZE, hash_file, process_file_extents,
+    process_file_extents_with_reader,
 };
-pub use file::{FileInfo, process_file, process_file_with_reader};
-pub use id::B3Id;
+pub use fetch::{BlobFetcher, FetchError, ReadPart, plan_read};
+pub use file::{
+    FileInfo, StreamInfo, process_file, process_file_from_manifest, process_file_with_index,
+    process_file_with_previous, process_file_with_reader, root_prefix,
+};
+pub use id::{B3Id, ExtentKey};
 pub use machine::{get_hostname, get_machine_id};
-pub use tree::compute_tree_hash;
+pub use manifest::{Manifest, ManifestEntry, ManifestError};
+pub use migrate::{DerivedExtent, ExtentSlice, plan_migration};
+pub use names::{RestoreNames, catalog_name_rules, plan_restore_names};
+pub use priority::PriorityPatterns;
+pub use restore::{RestoreError, RestoreReport, restore_entries};
+pub use secrets::{SecretError, SecretSource, SecretsProvider};
+pub use special::{SkipReason, SpecialRestore, recreate_special};
+pub use system::system_manifest;
+pub use transport::{LocalHandler, Transport};
+pub use tree::{
+    PreviousTree, SUBTREE_HASH_VERSION, TreeHasher, TreeHashes, compute_tree_hash,
+    compute_tree_hashes,
+};
+pub use upload::{ExtentUploader, UploadExtentError};
+pub use verify::{Difference, Problem, VerifyOptions, VerifyReport, verify_tree};
+pub use xattr::{Xattrs, read_xattrs, restore_xattrs};
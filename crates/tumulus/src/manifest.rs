@@ -0,0 +1,251 @@
+//! Pre-hashed input manifests, for pipelines that already know what files contain.
+//!
+//! A manifest is JSON lines, one entry per file, keyed by catalog path (relative to the
+//! source, or under its root prefix in multi-root catalogs):
+//!
+//! ```json
+//! {"path": "dir/file", "size": 200000, "hash": "<blob ID>", "extents": [
+//!     {"offset": 0, "length": 131072, "id": "<extent ID>"},
+//!     {"offset": 131072, "length": 68928, "id": "<extent ID>"}
+//! ]}
+//! ```
+//!
+//! IDs are BLAKE3 hashes of the data as hex, keyed if the catalog uses an extent key:
+//! `hash` of the whole file, and each extent's `id` of its bytes. Extents are at most
+//! [`MAX_EXTENT_SIZE`] long, and holes and preallocated ranges have `"hole": true` or
+//! `"unwritten": true` instead of an ID. Files with extents aren't read at all; files
+//! without are scanned as usual, and their hash is checked against the manifest's.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use extentria::DataRange;
+use serde::Deserialize;
+
+use crate::B3Id;
+use crate::extents::{BlobInfo, ExtentInfo, MAX_EXTENT_SIZE};
+
+/// Error reading a manifest.
+#[derive(Debug, thiserror::Error)]
+pub enum ManifestError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("line {line}: {source}")]
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("line {line}: {reason}")]
+    Invalid { line: usize, reason: String },
+}
+
+/// A file as a manifest describes it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub hash: B3Id,
+    /// The file's layout, if known.
+    #[serde(default)]
+    pub extents: Option<Vec<ManifestExtent>>,
+}
+
+/// A range of a file in a manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestExtent {
+    pub offset: u64,
+    pub length: u64,
+    /// ID of the range's data, absent for holes and preallocated ranges.
+    #[serde(default)]
+    pub id: Option<B3Id>,
+    #[serde(default)]
+    pub hole: bool,
+    #[serde(default)]
+    pub unwritten: bool,
+}
+
+impl ManifestEntry {
+    /// The blob this entry describes, if it has extents.
+    ///
+    /// Each extent is counted as its own filesystem extent.
+    pub fn blob(&self) -> Option<BlobInfo> {
+        let extents = self.extents.as_ref()?;
+        Some(BlobInfo {
+            blob_id: self.hash,
+            bytes: self.size,
+            extents: extents
+                .iter()
+                .zip(1..)
+                .map(|(extent, fs_extent)| ExtentInfo {
+                    extent_id: extent.id.unwrap_or(B3Id::from([0; 32])),
+                    range: if extent.hole {
+                        DataRange::hole(extent.offset, extent.length)
+                    } else if extent.unwritten {
+                        DataRange::unwritten(extent.offset, extent.length)
+                    } else {
+                        DataRange::new(extent.offset, extent.length)
+                    },
+                    fs_extent,
+                })
+                .collect(),
+        })
+    }
+
+    /// Check that the extents are in order, within the file, and have IDs where needed.
+    fn validate(&self) -> Result<(), String> {
+        let Some(ref extents) = self.extents else {
+            return Ok(());
+        };
+        let mut end = 0;
+        for extent in extents {
+            if extent.offset < end {
+                return Err(format!(
+                    "extent at {} overlaps the one before",
+                    extent.offset
+                ));
+            }
+            if extent.length == 0 {
+                return Err(format!("extent at {} is empty", extent.offset));
+            }
+            end = extent.offset + extent.length;
+            if end > self.size {
+                return Err(format!(
+                    "extent at {} is past the end of the file",
+                    extent.offset
+                ));
+            }
+            match (extent.hole || extent.unwritten, extent.id) {
+                (true, Some(_)) => {
+                    return Err(format!("extent at {} has no data but an ID", extent.offset));
+                }
+                (false, None) => {
+                    return Err(format!("extent at {} has no ID", extent.offset));
+                }
+                (false, Some(_)) if extent.length > MAX_EXTENT_SIZE => {
+                    return Err(format!(
+                        "extent at {} is longer than {MAX_EXTENT_SIZE} bytes",
+                        extent.offset
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files described by a manifest, by catalog path.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    entries: HashMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Read a manifest file.
+    pub fn open(path: &Path) -> Result<Self, ManifestError> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a manifest. Blank lines are skipped, and later entries replace earlier ones
+    /// for the same path.
+    pub fn read(reader: impl BufRead) -> Result<Self, ManifestError> {
+        let mut entries = HashMap::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: ManifestEntry =
+                serde_json::from_str(&line).map_err(|source| ManifestError::Json {
+                    line: line_number,
+                    source,
+                })?;
+            entry.validate().map_err(|reason| ManifestError::Invalid {
+                line: line_number,
+                reason,
+            })?;
+            entries.insert(entry.path.clone(), entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// The entry for a catalog path.
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.get(path)
+    }
+
+    /// How many files the manifest describes.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the manifest describes no files.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(byte: u8) -> String {
+        B3Id::from([byte; 32]).as_hex()
+    }
+
+    #[test]
+    fn reads_entries() {
+        let manifest = format!(
+            r#"{{"path": "a", "size": 20, "hash": "{}", "extents": [{{"offset": 0, "length": 10, "id": "{}"}}, {{"offset": 10, "length": 10, "hole": true}}]}}
+
+{{"path": "b", "size": 5, "hash": "{}"}}
+"#,
+            hex(1),
+            hex(2),
+            hex(3)
+        );
+        let manifest = Manifest::read(manifest.as_bytes()).unwrap();
+        assert_eq!(manifest.len(), 2);
+
+        let blob = manifest.get("a").unwrap().blob().unwrap();
+        assert_eq!(blob.blob_id, B3Id::from([1; 32]));
+        assert_eq!(blob.bytes, 20);
+        assert_eq!(blob.extents[0].extent_id, B3Id::from([2; 32]));
+        assert_eq!(blob.extents[1].range, DataRange::hole(10, 10));
+        assert!(manifest.get("b").unwrap().blob().is_none());
+    }
+
+    #[test]
+    fn refuses_bad_layouts() {
+        let entry = |extents: &str| {
+            format!(
+                r#"{{"path": "a", "size": 20, "hash": "{}", "extents": [{extents}]}}"#,
+                hex(1)
+            )
+        };
+        let id = hex(2);
+        for extents in [
+            format!(r#"{{"offset": 0, "length": 30, "id": "{id}"}}"#),
+            format!(
+                r#"{{"offset": 0, "length": 10, "id": "{id}"}}, {{"offset": 5, "length": 5, "id": "{id}"}}"#
+            ),
+            r#"{"offset": 0, "length": 10}"#.to_string(),
+            format!(r#"{{"offset": 0, "length": 10, "hole": true, "id": "{id}"}}"#),
+        ] {
+            let err = Manifest::read(entry(&extents).as_bytes()).unwrap_err();
+            assert!(
+                matches!(err, ManifestError::Invalid { line: 1, .. }),
+                "{err}"
+            );
+        }
+        assert!(matches!(
+            Manifest::read(&b"{}"[..]),
+            Err(ManifestError::Json { line: 1, .. })
+        ));
+    }
+}
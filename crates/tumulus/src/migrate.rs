@@ -0,0 +1,317 @@
+//! Carrying extents over to a catalog of the same tree chunked differently.
+//!
+//! When extents are cut differently (after a change to how files are chunked), a new
+//! catalog of an unchanged tree has none of the extents of the previous one, even though
+//! all its data is already stored. Each new extent can instead be described as byte ranges
+//! of previous extents at the same place in the same file, for the server to assemble.
+//!
+//! As in [`crate::diff`], the catalogs are compared over a single connection, with the
+//! previous catalog attached under a schema name.
+
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::Connection;
+
+use crate::{B3Id, detect_renames};
+
+/// A byte range of an extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentSlice {
+    pub source: B3Id,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// An extent of the current catalog, as the concatenation of slices of previous extents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivedExtent {
+    pub id: B3Id,
+    pub bytes: u64,
+    pub segments: Vec<ExtentSlice>,
+}
+
+/// A contiguous part of a file or stream, as laid out in a catalog.
+#[derive(Debug, Clone, Copy)]
+struct Part {
+    offset: u64,
+    bytes: u64,
+    /// None for sparse and preallocated ranges
+    extent_id: Option<B3Id>,
+}
+
+/// File or stream path, and stream name.
+type Location = (Vec<u8>, Option<String>);
+
+/// Read where every file and stream's data is in a catalog, sorted by offset.
+fn layouts(conn: &Connection, schema: &str) -> rusqlite::Result<HashMap<Location, Vec<Part>>> {
+    let has_streams: bool = conn.query_row(
+        &format!(
+            r#"SELECT COUNT(*) > 0 FROM "{schema}".sqlite_master
+            WHERE type = 'table' AND name = 'streams'"#
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    let streams_query = if has_streams {
+        format!(
+            r#"
+            UNION ALL
+            SELECT s.path, s.name, be.offset, be.bytes, be.extent_id
+            FROM "{schema}".streams s
+            JOIN "{schema}".blob_extents be ON be.blob_id = s.blob_id
+            "#
+        )
+    } else {
+        String::new()
+    };
+    let mut stmt = conn.prepare(&format!(
+        r#"
+        SELECT f.path, NULL, be.offset, be.bytes, be.extent_id
+        FROM "{schema}".files f
+        JOIN "{schema}".blob_extents be ON be.blob_id = f.blob_id
+        {streams_query}
+        "#
+    ))?;
+
+    let mut layouts: HashMap<Location, Vec<Part>> = HashMap::new();
+    let rows = stmt.query_map([], |row| {
+        let extent_id: Option<Vec<u8>> = row.get(4)?;
+        Ok((
+            (row.get(0)?, row.get(1)?),
+            Part {
+                offset: row.get(2)?,
+                bytes: row.get(3)?,
+                extent_id: extent_id.and_then(|id| B3Id::try_from(id).ok()),
+            },
+        ))
+    })?;
+    for row in rows {
+        let (location, part) = row?;
+        layouts.entry(location).or_default().push(part);
+    }
+    for parts in layouts.values_mut() {
+        parts.sort_by_key(|part| part.offset);
+    }
+    Ok(layouts)
+}
+
+/// Cover a byte range of a file with slices of the previous extents there, if they have
+/// data for all of it.
+fn cover(previous: &[Part], offset: u64, bytes: u64) -> Option<Vec<ExtentSlice>> {
+    let end = offset + bytes;
+    let first = previous.partition_point(|part| part.offset + part.bytes <= offset);
+
+    let mut segments = Vec::new();
+    let mut at = offset;
+    for part in &previous[first..] {
+        if at == end {
+            break;
+        }
+        if part.offset > at {
+            return None;
+        }
+        let until = (part.offset + part.bytes).min(end);
+        segments.push(ExtentSlice {
+            source: part.extent_id?,
+            offset: at - part.offset,
+            length: until - at,
+        });
+        at = until;
+    }
+    (at == end).then_some(segments)
+}
+
+/// Work out how to derive the extents of the current catalog that the previous one
+/// doesn't have, from those it does.
+///
+/// `conn` holds the current catalog as its main schema, and `previous` is the schema name
+/// under which the previous catalog is attached. Files are matched by path (and stream
+/// name), or for files that were renamed (see [`detect_renames`]) by their previous path,
+/// and each new extent is taken from the previous extents at the same offsets of the same
+/// file. Extents which can't be entirely covered that way, because the file is
+/// new, grew, or was sparse there before, are left out, as are those the previous catalog
+/// already has.
+///
+/// This doesn't check the file is unchanged: a derived extent whose data differs won't
+/// hash to its ID, and the server refuses it.
+pub fn plan_migration(conn: &Connection, previous: &str) -> rusqlite::Result<Vec<DerivedExtent>> {
+    let previous_layouts = layouts(conn, previous)?;
+    let previous_extents: HashSet<B3Id> = previous_layouts
+        .values()
+        .flatten()
+        .filter_map(|part| part.extent_id)
+        .collect();
+
+    let mut current: Vec<(Location, Vec<Part>)> = layouts(conn, "main")?.into_iter().collect();
+    current.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let renamed: HashMap<Vec<u8>, Vec<u8>> = detect_renames(conn, previous)?
+        .into_iter()
+        .map(|rename| (rename.to, rename.from))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut derived = Vec::new();
+    for (location, parts) in current {
+        let previous_parts = previous_layouts.get(&location).or_else(|| {
+            let (path, stream) = &location;
+            let from = renamed.get(path)?;
+            previous_layouts.get(&(from.clone(), stream.clone()))
+        });
+        let Some(previous_parts) = previous_parts else {
+            continue;
+        };
+        for part in parts {
+            let Some(id) = part.extent_id else {
+                continue;
+            };
+            if previous_extents.contains(&id) || seen.contains(&id) {
+                continue;
+            }
+            if let Some(segments) = cover(previous_parts, part.offset, part.bytes) {
+                seen.insert(id);
+                derived.push(DerivedExtent {
+                    id,
+                    bytes: part.bytes,
+                    segments,
+                });
+            }
+        }
+    }
+    Ok(derived)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use crate::create_catalog_schema;
+
+    fn id(n: u8) -> B3Id {
+        [n; 32].into()
+    }
+
+    /// Add a file whose blob is made of (bytes, extent) parts.
+    fn insert_file(conn: &Connection, schema: &str, path: &str, blob: u8, parts: &[(u64, u8)]) {
+        conn.execute(
+            &format!(r#"INSERT INTO "{schema}".files (path, blob_id) VALUES (?1, ?2)"#),
+            params![path.as_bytes(), id(blob).as_slice()],
+        )
+        .unwrap();
+        let mut offset = 0;
+        for &(bytes, extent) in parts {
+            let extent = (extent != 0).then(|| id(extent));
+            conn.execute(
+                &format!(
+                    r#"INSERT INTO "{schema}".blob_extents
+                    (blob_id, extent_id, offset, bytes, fs_extent) VALUES (?1, ?2, ?3, ?4, 0)"#
+                ),
+                params![
+                    id(blob).as_slice(),
+                    extent.as_ref().map(|e| e.as_slice()),
+                    offset,
+                    bytes
+                ],
+            )
+            .unwrap();
+            offset += bytes;
+        }
+    }
+
+    fn setup() -> (Connection, tempfile::NamedTempFile) {
+        let previous = tempfile::NamedTempFile::new().unwrap();
+        create_catalog_schema(&Connection::open(previous.path()).unwrap()).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        create_catalog_schema(&conn).unwrap();
+        conn.execute(
+            "ATTACH DATABASE ?1 AS previous",
+            [previous.path().to_string_lossy()],
+        )
+        .unwrap();
+        (conn, previous)
+    }
+
+    #[test]
+    fn rechunked_extents_are_derived() {
+        let (conn, _previous) = setup();
+        // Previously 100 + 100 bytes, now 50 + 120 + 30
+        insert_file(&conn, "previous", "file", 1, &[(100, 10), (100, 11)]);
+        insert_file(&conn, "main", "file", 2, &[(50, 20), (120, 21), (30, 22)]);
+
+        let derived = plan_migration(&conn, "previous").unwrap();
+        let slice = |source, offset, length| ExtentSlice {
+            source: id(source),
+            offset,
+            length,
+        };
+        assert_eq!(
+            derived,
+            vec![
+                DerivedExtent {
+                    id: id(20),
+                    bytes: 50,
+                    segments: vec![slice(10, 0, 50)],
+                },
+                DerivedExtent {
+                    id: id(21),
+                    bytes: 120,
+                    segments: vec![slice(10, 50, 50), slice(11, 0, 70)],
+                },
+                DerivedExtent {
+                    id: id(22),
+                    bytes: 30,
+                    segments: vec![slice(11, 70, 30)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_files_are_followed() {
+        let (conn, _previous) = setup();
+        insert_file(&conn, "previous", "old", 1, &[(100, 10)]);
+        insert_file(&conn, "main", "new", 1, &[(60, 20), (40, 21)]);
+
+        let derived = plan_migration(&conn, "previous").unwrap();
+        let sources: Vec<(B3Id, u64)> = derived
+            .iter()
+            .map(|d| (d.segments[0].source, d.segments[0].offset))
+            .collect();
+        assert_eq!(sources, [(id(10), 0), (id(10), 60)]);
+    }
+
+    #[test]
+    fn uncovered_extents_are_left_out() {
+        let (conn, _previous) = setup();
+        // Sparse at the start before, and shorter
+        insert_file(&conn, "previous", "file", 1, &[(50, 0), (50, 10)]);
+        insert_file(&conn, "main", "file", 2, &[(40, 20), (60, 21), (10, 22)]);
+        // Unchanged extents are already stored
+        insert_file(&conn, "previous", "same", 3, &[(10, 30)]);
+        insert_file(&conn, "main", "same", 3, &[(10, 30)]);
+        // New file
+        insert_file(&conn, "main", "new", 4, &[(10, 40)]);
+
+        let derived = plan_migration(&conn, "previous").unwrap();
+        let ids: Vec<B3Id> = derived.iter().map(|d| d.id).collect();
+        assert!(ids.is_empty(), "{ids:?}");
+
+        // Only where the previous layout has data
+        conn.execute(
+            "DELETE FROM main.blob_extents WHERE blob_id = ?1",
+            [id(2).as_slice()],
+        )
+        .unwrap();
+        conn.execute(
+            "DELETE FROM main.files WHERE path = ?1",
+            [b"file".as_slice()],
+        )
+        .unwrap();
+        insert_file(&conn, "main", "file", 2, &[(50, 0), (50, 21)]);
+        let derived = plan_migration(&conn, "previous").unwrap();
+        assert_eq!(derived.len(), 1);
+        assert_eq!(derived[0].segments[0].source, id(10));
+    }
+}
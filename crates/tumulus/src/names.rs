@@ -0,0 +1,167 @@
+//! Adapting file names to the filesystem they're restored onto.
+//!
+//! Catalogs record how their source filesystem compares names, in the `fs_case_sensitive`
+//! and `fs_normalization` metadata. Restoring onto a filesystem with other rules can go
+//! wrong in two ways: names that were distinct at the source can land on the same file
+//! (`Makefile` and `makefile` on a case-insensitive target, or the composed and
+//! decomposed forms of `café` on APFS), and names that HFS+ silently decomposed come back
+//! in a form nobody typed, on filesystems that compare names byte for byte.
+//!
+//! The first can't be fixed without renaming files, so such collisions are reported; the
+//! second is undone by normalizing those names back to the composed form (NFC).
+
+use std::collections::BTreeMap;
+
+use fs_info::{NameRules, Normalization};
+use icu_normalizer::ComposingNormalizerBorrowed;
+use rusqlite::Connection;
+
+/// How restored file names need adapting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreNames {
+    /// Paths to restore under another name, as (catalog path, restored path).
+    pub renamed: Vec<(String, String)>,
+    /// Groups of restored paths that would be the same file on the target, which can't
+    /// all be restored.
+    pub collisions: Vec<Vec<String>>,
+}
+
+/// Read the source name rules recorded in a catalog, if it has them.
+pub fn catalog_name_rules(conn: &Connection) -> Option<NameRules> {
+    let value = |key: &str| {
+        conn.query_row("SELECT value FROM metadata WHERE key = ?1", [key], |row| {
+            row.get::<_, String>(0)
+        })
+        .ok()
+        .and_then(|value| serde_json::from_str::<serde_json::Value>(&value).ok())
+    };
+
+    let case_sensitive = value("fs_case_sensitive")?.as_bool()?;
+    let normalization = Normalization::from_name(value("fs_normalization")?.as_str()?)?;
+    Some(NameRules {
+        case_sensitive,
+        normalization,
+    })
+}
+
+/// Work out how to restore paths from a catalog made with `source` rules (if known)
+/// onto a filesystem with `target` rules.
+pub fn plan_restore_names<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    source: Option<NameRules>,
+    target: NameRules,
+) -> RestoreNames {
+    let nfc = ComposingNormalizerBorrowed::new_nfc();
+    // Decomposition was forced by the source, not chosen, so undo it where it would stick
+    let recompose = source.is_some_and(|source| source.normalization == Normalization::Nfd)
+        && target.normalization == Normalization::Exact;
+
+    let mut renamed = Vec::new();
+    let mut by_key: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for path in paths {
+        let restored = if recompose {
+            nfc.normalize(path).into_owned()
+        } else {
+            path.to_string()
+        };
+        if restored != path {
+            renamed.push((path.to_string(), restored.clone()));
+        }
+
+        let mut key = restored.clone();
+        if target.normalization != Normalization::Exact {
+            key = nfc.normalize(&key).into_owned();
+        }
+        if !target.case_sensitive {
+            key = key.to_lowercase();
+        }
+        by_key.entry(key).or_default().push(restored);
+    }
+
+    let collisions = by_key
+        .into_values()
+        .map(|mut group| {
+            group.sort();
+            group.dedup();
+            group
+        })
+        .filter(|group| group.len() > 1)
+        .collect();
+
+    RestoreNames {
+        renamed,
+        collisions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINUX: NameRules = NameRules {
+        case_sensitive: true,
+        normalization: Normalization::Exact,
+    };
+    const APFS: NameRules = NameRules {
+        case_sensitive: false,
+        normalization: Normalization::Insensitive,
+    };
+    const HFS: NameRules = NameRules {
+        case_sensitive: false,
+        normalization: Normalization::Nfd,
+    };
+
+    #[test]
+    fn case_collisions() {
+        let paths = ["src/Makefile", "src/makefile", "src/main.c"];
+        assert_eq!(
+            plan_restore_names(paths, Some(LINUX), LINUX),
+            RestoreNames::default()
+        );
+
+        let plan = plan_restore_names(paths, Some(LINUX), APFS);
+        assert!(plan.renamed.is_empty());
+        assert_eq!(plan.collisions, vec![vec!["src/Makefile", "src/makefile"]]);
+    }
+
+    #[test]
+    fn normalization() {
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+
+        // Distinct on Linux, the same file on APFS
+        let plan = plan_restore_names([composed, decomposed], Some(LINUX), APFS);
+        assert_eq!(plan.collisions, vec![vec![decomposed, composed]]);
+
+        // Forced decomposition is undone when it would stick
+        let plan = plan_restore_names([decomposed], Some(HFS), LINUX);
+        assert_eq!(
+            plan.renamed,
+            vec![(decomposed.to_string(), composed.to_string())]
+        );
+        assert!(
+            plan_restore_names([decomposed], None, LINUX)
+                .renamed
+                .is_empty()
+        );
+        assert!(
+            plan_restore_names([decomposed], Some(HFS), APFS)
+                .renamed
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn read_rules_from_catalog() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::create_catalog_schema(&conn).unwrap();
+        assert_eq!(catalog_name_rules(&conn), None);
+
+        conn.execute_batch(
+            r#"INSERT INTO metadata (key, value) VALUES
+                ('fs_case_sensitive', 'false'), ('fs_normalization', '"nfd"')"#,
+        )
+        .unwrap();
+        assert_eq!(catalog_name_rules(&conn), Some(HFS));
+    }
+}
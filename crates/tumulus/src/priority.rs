@@ -0,0 +1,105 @@
+//! Priority ordering of files, so the most valuable data is safe early in a long backup.
+//!
+//! Priority patterns are globs over normalised catalog paths. A pattern with a `/` is
+//! matched against the whole path, and one without is matched against each name in it
+//! (like in a `.gitignore`). A path also matches if any of its parent directories do, so
+//! `Documents` prioritises everything in any `Documents` directory.
+//!
+//! In patterns, `*` matches any run of characters within a name, `**` matches across
+//! directories, and `?` matches a single character.
+
+/// An ordered list of priority patterns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PriorityPatterns {
+    patterns: Vec<String>,
+}
+
+impl PriorityPatterns {
+    /// Create from patterns, highest priority first.
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self { patterns }
+    }
+
+    /// The patterns, highest priority first.
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Whether there are no patterns, so every path has the same priority.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// The priority rank of a path: the index of the first pattern it matches, or the
+    /// number of patterns if it matches none. Lower ranks go first.
+    pub fn rank(&self, path: &str) -> u32 {
+        self.patterns
+            .iter()
+            .position(|pattern| matches(pattern, path))
+            .unwrap_or(self.patterns.len()) as u32
+    }
+}
+
+/// Whether a path, or any of its parent directories, matches a pattern.
+fn matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        let pattern = pattern.trim_matches('/');
+        path.match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .chain(std::iter::once(path))
+            .any(|prefix| glob(pattern.as_bytes(), prefix.as_bytes()))
+    } else {
+        path.split('/')
+            .any(|name| glob(pattern.as_bytes(), name.as_bytes()))
+    }
+}
+
+/// Match a glob against a path, with `*` and `?` not matching `/`, and `**` matching
+/// anything.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directories at all
+            let rest_after_slash = rest.strip_prefix(b"/").unwrap_or(rest);
+            glob(rest_after_slash, text) || (0..text.len()).any(|i| glob(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let name_len = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=name_len).any(|i| glob(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob(rest, tail)),
+        [p, rest @ ..] => matches!(text, [c, tail @ ..] if c == p && glob(rest, tail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"*.db", b"app.db"));
+        assert!(!glob(b"*.db", b"var/app.db"));
+        assert!(glob(b"var/*/app.db", b"var/lib/app.db"));
+        assert!(glob(b"var/**/app.db", b"var/lib/x/app.db"));
+        assert!(glob(b"var/**/app.db", b"var/app.db"));
+        assert!(glob(b"f?le", b"file"));
+        assert!(!glob(b"f?le", b"f/le"));
+    }
+
+    #[test]
+    fn ranks() {
+        let patterns = PriorityPatterns::new(vec![
+            "*.sqlite".to_string(),
+            "Documents".to_string(),
+            "srv/db/".to_string(),
+        ]);
+        assert_eq!(patterns.rank("home/user/app.sqlite"), 0);
+        assert_eq!(patterns.rank("home/user/Documents/cv.pdf"), 1);
+        assert_eq!(patterns.rank("home/user/Documents/notes.sqlite"), 0);
+        assert_eq!(patterns.rank("srv/db/data/base/1"), 2);
+        assert_eq!(patterns.rank("srv/dbx"), 3);
+        assert_eq!(PriorityPatterns::default().rank("anything"), 0);
+    }
+}
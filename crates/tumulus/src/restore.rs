@@ -0,0 +1,565 @@
+//! Restoring catalog entries from the server.
+//!
+//! Entries are recreated under a target directory at their catalog paths: directories,
+//! regular files with their data fetched by a [`BlobFetcher`], symlinks, and special files
+//! as far as [`recreate_special`] can. Permissions and modification times are set from the
+//! catalog, and so are extended attributes where the platform can. Files cataloged with
+//! hardlinks are linked again to the first of their entries restored. Ownership, Finder
+//! metadata, and secondary streams aren't restored. Holes are left unwritten, and
+//! preallocated ranges are preallocated again where the platform can.
+//!
+//! Names are adapted to the target filesystem as [`plan_restore_names`] works out: entries
+//! whose names would be the same file as an earlier entry's there are skipped.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use extentria::{DataRange, ExtentError};
+use fs_info::{NameRules, Normalization, get_name_rules};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::fetch::{BlobFetcher, FetchError};
+use crate::file::FileInfo;
+use crate::names::{RestoreNames, plan_restore_names};
+use crate::special::{SpecialRestore, recreate_special};
+use crate::xattr::{restore_xattrs, xattrs_from_attributes};
+
+/// How much of a file to fetch at once.
+const FETCH_CHUNK: u64 = 16 * 1024 * 1024;
+
+/// Error restoring entries.
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("Failed to restore {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Failed to fetch {path}: {source}")]
+    Fetch { path: String, source: FetchError },
+}
+
+/// What a restore did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// How many entries were restored.
+    pub restored: usize,
+    /// Bytes of file data written.
+    pub bytes: u64,
+    /// How many entries were restored under a name normalized for the target.
+    pub renamed: usize,
+    /// Entries that weren't restored, with why.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Restore catalog entries under `target`, fetching file data with `fetcher`.
+///
+/// Entries should be given parents first, as from
+/// [`CatalogTree::entries_under`](crate::browse::CatalogTree::entries_under); directories
+/// above them are created as needed. Existing files are replaced, never written through.
+/// Paths that would land outside the target, or go through a symlink or other non-directory
+/// under it (as a hostile catalog could arrange with a symlink entry), are skipped.
+///
+/// `source_names` are the name rules of the filesystem the catalog was made from, if it
+/// recorded them.
+pub async fn restore_entries(
+    entries: &[&FileInfo],
+    target: &Path,
+    fetcher: &BlobFetcher,
+    source_names: Option<NameRules>,
+) -> Result<RestoreReport, RestoreError> {
+    let mut report = RestoreReport::default();
+    let mut directories = Vec::new();
+    // First restored path of each hardlinked file, by root and inode
+    let mut linked: HashMap<(Option<&str>, u64), PathBuf> = HashMap::new();
+
+    let plan = plan_restore_names(
+        entries.iter().map(|entry| entry.relative_path.as_str()),
+        source_names,
+        target_name_rules(target),
+    );
+    let mut names = Names::new(&plan);
+
+    for &entry in entries {
+        let restored = match names.restored(&entry.relative_path) {
+            Ok(restored) => restored,
+            Err(reason) => {
+                report.skipped.push((entry.relative_path.clone(), reason));
+                continue;
+            }
+        };
+        let Some(path) = restore_path(target, restored) else {
+            report
+                .skipped
+                .push((entry.relative_path.clone(), "path leaves the target".into()));
+            continue;
+        };
+        let io_error = |source| RestoreError::Io {
+            path: path.clone(),
+            source,
+        };
+        if let Err(reason) = create_parents(target, restored).map_err(io_error)? {
+            report.skipped.push((entry.relative_path.clone(), reason));
+            continue;
+        }
+
+        let kind = entry
+            .special
+            .as_ref()
+            .and_then(|special| special.get("type")?.as_str());
+        match (kind, &entry.special) {
+            (Some("directory"), _) => {
+                create_dir(&path).map_err(io_error)?;
+                // Set last, so read-only directories can still be filled
+                directories.push((path, entry));
+            }
+            (Some("symlink"), Some(special)) => {
+                let target = special.get("target").and_then(Value::as_str);
+                match target {
+                    Some(target) => {
+                        if let Err(reason) = make_symlink(target, &path).map_err(io_error)? {
+                            report.skipped.push((entry.relative_path.clone(), reason));
+                            continue;
+                        }
+                    }
+                    None => {
+                        report
+                            .skipped
+                            .push((entry.relative_path.clone(), "symlink has no target".into()));
+                        continue;
+                    }
+                }
+            }
+            (Some(_), Some(special)) => {
+                match recreate_special(&path, special, entry.unix_mode).map_err(io_error)? {
+                    SpecialRestore::Created => {}
+                    SpecialRestore::Skipped(reason) => {
+                        report
+                            .skipped
+                            .push((entry.relative_path.clone(), reason.to_string()));
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                let links = entry
+                    .attributes
+                    .as_ref()
+                    .and_then(|attributes| attributes.get("links")?.as_u64());
+                let key = links
+                    .filter(|&links| links > 1)
+                    .and(entry.fs_inode)
+                    .map(|inode| (entry.root.as_deref(), inode));
+                match key.and_then(|key| linked.get(&key)) {
+                    Some(first) => {
+                        remove_existing(&path).map_err(io_error)?;
+                        fs::hard_link(first, &path).map_err(io_error)?;
+                    }
+                    None => {
+                        report.bytes += restore_file(entry, &path, fetcher).await?;
+                        set_metadata(entry, &path).map_err(io_error)?;
+                        if let Some(key) = key {
+                            linked.insert(key, path.clone());
+                        }
+                    }
+                }
+            }
+        }
+        debug!(path = %entry.relative_path, "Restored entry");
+        report.restored += 1;
+        if restored != entry.relative_path {
+            report.renamed += 1;
+        }
+    }
+
+    for (path, entry) in directories.iter().rev() {
+        set_metadata(entry, path).map_err(|source| RestoreError::Io {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    Ok(report)
+}
+
+/// Restored names, as planned for the target.
+struct Names<'a> {
+    renamed: HashMap<&'a str, &'a str>,
+    /// Group of each restored path that collides with others.
+    collisions: HashMap<&'a str, usize>,
+    /// The path restored first out of each group of colliding ones.
+    claimed: HashMap<usize, &'a str>,
+}
+
+impl<'a> Names<'a> {
+    fn new(plan: &'a RestoreNames) -> Self {
+        Self {
+            renamed: plan
+                .renamed
+                .iter()
+                .map(|(from, to)| (from.as_str(), to.as_str()))
+                .collect(),
+            collisions: plan
+                .collisions
+                .iter()
+                .enumerate()
+                .flat_map(|(group, paths)| paths.iter().map(move |path| (path.as_str(), group)))
+                .collect(),
+            claimed: HashMap::new(),
+        }
+    }
+
+    /// The path to restore a catalog path at, or why it can't be: the first of colliding
+    /// paths is restored, and the others would overwrite it.
+    fn restored<'p>(&mut self, path: &'p str) -> Result<&'p str, String>
+    where
+        'a: 'p,
+    {
+        let restored = self.renamed.get(path).copied().unwrap_or(path);
+        if let Some((&colliding, &group)) = self.collisions.get_key_value(restored) {
+            let first = *self.claimed.entry(group).or_insert(colliding);
+            if first != restored {
+                return Err(format!("same file as {first} on the target"));
+            }
+        }
+        Ok(restored)
+    }
+}
+
+/// How the filesystem of the target compares names, from the nearest directory that exists.
+fn target_name_rules(target: &Path) -> NameRules {
+    target
+        .ancestors()
+        .find_map(|dir| get_name_rules(dir).ok())
+        .unwrap_or(NameRules {
+            case_sensitive: true,
+            normalization: Normalization::Exact,
+        })
+}
+
+/// Where to restore a catalog path under the target, unless it would leave it.
+fn restore_path(target: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path);
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| target.join(relative))
+}
+
+/// Create the directories between the target and a catalog path, or say why it can't be
+/// restored: every one must be a real directory, so symlinks left by earlier entries aren't
+/// followed out of the target.
+fn create_parents(target: &Path, path: &str) -> io::Result<Result<(), String>> {
+    let Some(parent) = Path::new(path).parent() else {
+        return Ok(Ok(()));
+    };
+    let mut dir = target.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => {
+                return Ok(Err(format!(
+                    "{} is not a directory",
+                    dir.strip_prefix(target).unwrap_or(&dir).display()
+                )));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Create a directory, replacing anything but a directory already there.
+fn create_dir(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => {
+            fs::remove_file(path)?;
+            fs::create_dir(path)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir(path),
+        Err(err) => Err(err),
+    }
+}
+
+/// Remove a file, symlink, or special file in the way of a new one.
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Create a regular file afresh, without following a symlink or writing through another
+/// link to a file already there.
+fn create_file(path: &Path) -> io::Result<File> {
+    remove_existing(path)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.custom_flags(nix::libc::O_NOFOLLOW);
+    }
+    options.open(path)
+}
+
+/// Write a regular file's data, returning how many bytes were written.
+///
+/// Only data ranges are written, so holes stay sparse; preallocated ranges are allocated
+/// afterwards, and read as zeros like holes where they can't be.
+async fn restore_file(
+    entry: &FileInfo,
+    path: &Path,
+    fetcher: &BlobFetcher,
+) -> Result<u64, RestoreError> {
+    let io_error = |source| RestoreError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut file = create_file(path).map_err(io_error)?;
+    let Some(blob) = &entry.blob else {
+        return Ok(0);
+    };
+
+    // Adjacent data ranges are fetched together
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for extent in &blob.extents {
+        let range = &extent.range;
+        if range.hole || range.unwritten || range.offset >= blob.bytes {
+            continue;
+        }
+        let end = range.end().min(blob.bytes);
+        match runs.last_mut() {
+            Some(run) if run.end == range.offset => run.end = end,
+            _ => runs.push(range.offset..end),
+        }
+    }
+
+    let mut written = 0;
+    for run in runs {
+        file.seek(SeekFrom::Start(run.start)).map_err(io_error)?;
+        let mut offset = run.start;
+        while offset < run.end {
+            let end = (offset + FETCH_CHUNK).min(run.end);
+            let data =
+                fetcher
+                    .read(blob, offset..end)
+                    .await
+                    .map_err(|source| RestoreError::Fetch {
+                        path: entry.relative_path.clone(),
+                        source,
+                    })?;
+            file.write_all(&data).map_err(io_error)?;
+            written += data.len() as u64;
+            offset = end;
+        }
+    }
+    file.set_len(blob.bytes).map_err(io_error)?;
+
+    let ranges: Vec<DataRange> = blob.extents.iter().map(|extent| extent.range).collect();
+    match extentria::preallocate(&file, &ranges) {
+        Ok(()) => {}
+        Err(err @ ExtentError::Unsupported { .. }) => {
+            debug!(?path, %err, "Preallocated ranges left as holes");
+        }
+        Err(err) => return Err(io_error(err.into())),
+    }
+    Ok(written)
+}
+
+/// Make a symlink, or say why it can't be.
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &Path) -> io::Result<Result<(), String>> {
+    remove_existing(path)?;
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(Ok(()))
+}
+
+/// Make a symlink (not supported on this platform).
+#[cfg(not(unix))]
+fn make_symlink(_target: &str, _path: &Path) -> io::Result<Result<(), String>> {
+    Ok(Err("symlinks are not supported on this platform".into()))
+}
+
+/// Set an entry's extended attributes, permissions, and modification time.
+///
+/// Extended attributes that need privileges, or that the target filesystem doesn't
+/// support, are left out with a warning.
+fn set_metadata(entry: &FileInfo, path: &Path) -> io::Result<()> {
+    if let Some(attributes) = &entry.attributes {
+        let xattrs = xattrs_from_attributes(attributes);
+        if !xattrs.is_empty() {
+            match restore_xattrs(path, &xattrs) {
+                Ok(_) => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                    ) =>
+                {
+                    warn!(?path, %err, "Extended attributes not restored");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    if let Some(modified) = entry.ts_modified {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(modified.max(0) as u64);
+        File::open(path)?.set_modified(modified)?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = entry.unix_mode {
+        use std::os::unix::fs::PermissionsExt as _;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_in_the_target() {
+        let target = Path::new("/restore");
+        assert_eq!(
+            restore_path(target, "a/b"),
+            Some(PathBuf::from("/restore/a/b"))
+        );
+        assert_eq!(restore_path(target, "../escape"), None);
+        assert_eq!(restore_path(target, "/etc/passwd"), None);
+        assert_eq!(restore_path(target, "a/../../escape"), None);
+    }
+
+    fn entry(path: &str, special: Option<Value>) -> FileInfo {
+        FileInfo {
+            relative_path: path.into(),
+            root: None,
+            blob: None,
+            ts_created: None,
+            ts_modified: None,
+            ts_accessed: None,
+            ts_changed: None,
+            unix_mode: None,
+            unix_owner_id: None,
+            unix_group_id: None,
+            fs_inode: None,
+            fs_change_cookie: None,
+            special,
+            priority: None,
+            attributes: None,
+            streams: Vec::new(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hostile_symlinks_stay_in_the_target() {
+        let outside = tempfile::tempdir().unwrap();
+        let victim = outside.path().join("victim.txt");
+        fs::write(&victim, "untouched").unwrap();
+        let symlink = |path: &str, target: &Path| {
+            entry(
+                path,
+                Some(serde_json::json!({ "type": "symlink", "target": target })),
+            )
+        };
+        let directory = |path: &str| entry(path, Some(serde_json::json!({ "type": "directory" })));
+
+        let entries = [
+            // Files and directories under a symlink out of the target
+            symlink("a", outside.path()),
+            entry("a/x", None),
+            directory("a/d"),
+            // A file or directory over a symlink replaces it
+            symlink("b", &victim),
+            entry("b", None),
+            symlink("c", outside.path()),
+            directory("c"),
+        ];
+        let entries: Vec<&FileInfo> = entries.iter().collect();
+
+        let target = tempfile::tempdir().unwrap();
+        let fetcher = BlobFetcher::new(reqwest::Client::new(), "http://unused.invalid");
+        let report = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(restore_entries(&entries, target.path(), &fetcher, None))
+            .unwrap();
+
+        let skipped: Vec<&str> = report
+            .skipped
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect();
+        assert_eq!(skipped, ["a/x", "a/d"]);
+        assert_eq!(report.restored, 5);
+        assert!(!outside.path().join("x").exists());
+        assert!(!outside.path().join("d").exists());
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "untouched");
+        assert!(
+            fs::symlink_metadata(target.path().join("b"))
+                .unwrap()
+                .is_file()
+        );
+        assert!(
+            fs::symlink_metadata(target.path().join("c"))
+                .unwrap()
+                .is_dir()
+        );
+    }
+
+    #[test]
+    fn colliding_names_restored_once() {
+        let case_insensitive = NameRules {
+            case_sensitive: false,
+            normalization: Normalization::Insensitive,
+        };
+        let plan = plan_restore_names(
+            ["src/makefile", "src/Makefile", "src/main.c"],
+            None,
+            case_insensitive,
+        );
+        let mut names = Names::new(&plan);
+        assert_eq!(names.restored("src/makefile"), Ok("src/makefile"));
+        assert_eq!(
+            names.restored("src/Makefile"),
+            Err("same file as src/makefile on the target".into())
+        );
+        assert_eq!(names.restored("src/main.c"), Ok("src/main.c"));
+    }
+
+    #[test]
+    fn decomposed_names_recomposed() {
+        let hfs = NameRules {
+            case_sensitive: false,
+            normalization: Normalization::Nfd,
+        };
+        let entries = [entry("cafe\u{301}", None)];
+        let entries: Vec<&FileInfo> = entries.iter().collect();
+
+        let target = tempfile::tempdir().unwrap();
+        if target_name_rules(target.path()).normalization != Normalization::Exact {
+            return;
+        }
+        let fetcher = BlobFetcher::new(reqwest::Client::new(), "http://unused.invalid");
+        let report = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(restore_entries(
+                &entries,
+                target.path(),
+                &fetcher,
+                Some(hfs),
+            ))
+            .unwrap();
+
+        assert_eq!(report.renamed, 1);
+        assert!(target.path().join("caf\u{e9}").is_file());
+        assert!(!target.path().join("cafe\u{301}").exists());
+    }
+}
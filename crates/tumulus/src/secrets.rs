@@ -0,0 +1,243 @@
+//! Sources for secrets like catalog keys and server tokens.
+//!
+//! Secrets are given on the command line as a spec naming where to get them from, so they
+//! don't have to be written in plaintext anywhere tumulus reads its arguments from:
+//!
+//! - `env:NAME`: the value of an environment variable
+//! - `file:PATH` (or just a path): the contents of a file
+//! - `cmd:COMMAND`: the standard output of a command, split into arguments shell-style
+//! - `keychain:SERVICE/ACCOUNT`: an item in the system keyring (the Secret Service via
+//!   `secret-tool` on Linux and BSDs, the login keychain via `security` on macOS)
+
+use std::{
+    env, fmt, fs, io,
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+/// Error type for secret retrieval.
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("Invalid secret spec '{0}' (expected env:, file:, cmd:, or keychain:)")]
+    InvalidSpec(String),
+
+    #[error("Environment variable {0} is not set")]
+    NotSet(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Secret command `{command}` failed: {status}")]
+    CommandFailed { command: String, status: String },
+
+    #[error("Secret is empty")]
+    Empty,
+
+    #[error("Keychain secrets are not supported on this platform")]
+    Unsupported,
+}
+
+/// Something that can provide a secret on demand.
+///
+/// Implementations fetch the secret every time it's asked for, and don't cache it.
+pub trait SecretsProvider: fmt::Debug + Send + Sync {
+    /// Fetch the secret.
+    fn fetch(&self) -> Result<Vec<u8>, SecretError>;
+
+    /// Fetch the secret as text, with surrounding whitespace (like a trailing newline)
+    /// removed, for secrets like tokens that are always text.
+    fn fetch_text(&self) -> Result<String, SecretError> {
+        let secret = self.fetch()?;
+        let text = String::from_utf8(secret)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(SecretError::Empty);
+        }
+        Ok(text.to_string())
+    }
+}
+
+/// The built-in secret sources, parsed from a secret spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecretSource {
+    /// An environment variable.
+    Env(String),
+    /// A file.
+    File(PathBuf),
+    /// The standard output of a command.
+    Command(Vec<String>),
+    /// An item in the system keyring.
+    Keychain { service: String, account: String },
+}
+
+impl FromStr for SecretSource {
+    type Err = SecretError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || SecretError::InvalidSpec(spec.to_string());
+        let Some((scheme, rest)) = spec.split_once(':') else {
+            return Ok(Self::File(PathBuf::from(spec)));
+        };
+
+        match scheme {
+            "env" if !rest.is_empty() => Ok(Self::Env(rest.to_string())),
+            "file" if !rest.is_empty() => Ok(Self::File(PathBuf::from(rest))),
+            "cmd" => match shlex::split(rest) {
+                Some(args) if !args.is_empty() => Ok(Self::Command(args)),
+                _ => Err(invalid()),
+            },
+            "keychain" => match rest.split_once('/') {
+                Some((service, account)) if !service.is_empty() && !account.is_empty() => {
+                    Ok(Self::Keychain {
+                        service: service.to_string(),
+                        account: account.to_string(),
+                    })
+                }
+                _ => Err(invalid()),
+            },
+            // A Windows path like C:\keys\catalog.key
+            _ if scheme.len() == 1 => Ok(Self::File(PathBuf::from(spec))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl SecretsProvider for SecretSource {
+    fn fetch(&self) -> Result<Vec<u8>, SecretError> {
+        let secret = match self {
+            Self::Env(name) => env::var_os(name)
+                .ok_or_else(|| SecretError::NotSet(name.clone()))?
+                .into_encoded_bytes(),
+            Self::File(path) => fs::read(path)?,
+            Self::Command(args) => run_command(args)?,
+            Self::Keychain { service, account } => {
+                run_command(&keychain_command(service, account)?)?
+            }
+        };
+
+        if secret.is_empty() {
+            return Err(SecretError::Empty);
+        }
+        Ok(secret)
+    }
+}
+
+/// Run a command and return its standard output. Its standard error is passed through,
+/// so that it can prompt or explain failures.
+fn run_command(args: &[String]) -> Result<Vec<u8>, SecretError> {
+    let output = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(SecretError::CommandFailed {
+            command: args[0].clone(),
+            status: output.status.to_string(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// The command which prints a keyring item (macOS).
+#[cfg(target_os = "macos")]
+fn keychain_command(service: &str, account: &str) -> Result<Vec<String>, SecretError> {
+    Ok(vec![
+        "security".into(),
+        "find-generic-password".into(),
+        "-s".into(),
+        service.into(),
+        "-a".into(),
+        account.into(),
+        "-w".into(),
+    ])
+}
+
+/// The command which prints a keyring item (Secret Service, via libsecret).
+#[cfg(all(unix, not(target_os = "macos")))]
+fn keychain_command(service: &str, account: &str) -> Result<Vec<String>, SecretError> {
+    Ok(vec![
+        "secret-tool".into(),
+        "lookup".into(),
+        "service".into(),
+        service.into(),
+        "account".into(),
+        account.into(),
+    ])
+}
+
+/// The command which prints a keyring item (not supported on this platform).
+#[cfg(not(unix))]
+fn keychain_command(_service: &str, _account: &str) -> Result<Vec<String>, SecretError> {
+    Err(SecretError::Unsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(
+            "env:TUMULUS_KEY".parse::<SecretSource>().unwrap(),
+            SecretSource::Env("TUMULUS_KEY".into())
+        );
+        assert_eq!(
+            "/etc/tumulus/key".parse::<SecretSource>().unwrap(),
+            SecretSource::File("/etc/tumulus/key".into())
+        );
+        assert_eq!(
+            "file:key:with:colons".parse::<SecretSource>().unwrap(),
+            SecretSource::File("key:with:colons".into())
+        );
+        assert_eq!(
+            "cmd:pass show 'tumulus/catalog key'"
+                .parse::<SecretSource>()
+                .unwrap(),
+            SecretSource::Command(vec![
+                "pass".into(),
+                "show".into(),
+                "tumulus/catalog key".into()
+            ])
+        );
+        assert_eq!(
+            "keychain:tumulus/backup".parse::<SecretSource>().unwrap(),
+            SecretSource::Keychain {
+                service: "tumulus".into(),
+                account: "backup".into()
+            }
+        );
+        assert!("keychain:tumulus".parse::<SecretSource>().is_err());
+        assert!("cmd:".parse::<SecretSource>().is_err());
+        assert!("vault:secret".parse::<SecretSource>().is_err());
+    }
+
+    #[test]
+    fn fetch_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        fs::write(&path, "s3cret\n").unwrap();
+
+        let source = SecretSource::File(path);
+        assert_eq!(source.fetch().unwrap(), b"s3cret\n");
+        assert_eq!(source.fetch_text().unwrap(), "s3cret");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fetch_from_command() {
+        let source: SecretSource = "cmd:printf 's3cret'".parse().unwrap();
+        assert_eq!(source.fetch().unwrap(), b"s3cret");
+
+        let source: SecretSource = "cmd:false".parse().unwrap();
+        assert!(matches!(
+            source.fetch(),
+            Err(SecretError::CommandFailed { .. })
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+//! Recreating special files (devices, FIFOs, and sockets) from their catalog entries.
+//!
+//! Catalogs record these with their type (and device number, for devices) in the
+//! `special` column. FIFOs can always be recreated; devices need privileges, and are
+//! skipped with a reason when they can't be created, so that a restore can report them
+//! rather than fail. Sockets are never recreated: they only exist while a program is
+//! listening on them, and that program will make a new one.
+
+use std::{fmt, io, path::Path};
+
+use serde_json::Value;
+
+/// What happened when recreating a special file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecialRestore {
+    /// The file was created.
+    Created,
+    /// The file was not created, for the given reason.
+    Skipped(SkipReason),
+}
+
+/// Why a special file was not recreated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Creating device nodes needs privileges this process doesn't have.
+    NotPermitted,
+    /// Sockets are made by the programs that listen on them.
+    Socket,
+    /// Special files can't be created on this platform.
+    Unsupported,
+    /// The catalog entry isn't a device, FIFO, or socket (or is missing details).
+    NotApplicable,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotPermitted => write!(f, "creating device nodes requires privileges"),
+            Self::Socket => write!(f, "sockets are recreated by the programs that use them"),
+            Self::Unsupported => write!(f, "special files are not supported on this platform"),
+            Self::NotApplicable => write!(f, "not a device, FIFO, or socket entry"),
+        }
+    }
+}
+
+/// Recreate a device, FIFO, or socket at `path` from its `special` catalog entry.
+///
+/// `unix_mode` is the file's mode from the catalog; only its permission bits are used.
+/// Whatever is at `path` already is replaced, unless it's a directory, but only once the
+/// new node exists, so it's kept when the node can't be made. I/O errors other than
+/// lacking permission are returned as errors.
+#[cfg(unix)]
+pub fn recreate_special(
+    path: &Path,
+    special: &Value,
+    unix_mode: Option<u32>,
+) -> io::Result<SpecialRestore> {
+    use nix::errno::Errno;
+    use nix::sys::stat::{Mode, SFlag, mknod};
+    use nix::unistd::mkfifo;
+
+    let perm = Mode::from_bits_truncate(unix_mode.unwrap_or(0o644) as _);
+    let kind = match special.get("type").and_then(Value::as_str) {
+        Some("fifo") => {
+            replace_with(path, |temp| mkfifo(temp, perm))?;
+            return Ok(SpecialRestore::Created);
+        }
+        Some("socket") => return Ok(SpecialRestore::Skipped(SkipReason::Socket)),
+        Some("block") => SFlag::S_IFBLK,
+        Some("char") => SFlag::S_IFCHR,
+        _ => return Ok(SpecialRestore::Skipped(SkipReason::NotApplicable)),
+    };
+
+    let Some(rdev) = special.get("rdev").and_then(Value::as_u64) else {
+        return Ok(SpecialRestore::Skipped(SkipReason::NotApplicable));
+    };
+
+    match replace_with(path, |temp| mknod(temp, kind, perm, rdev as _)) {
+        Ok(()) => Ok(SpecialRestore::Created),
+        Err(err)
+            if matches!(
+                Errno::from_raw(err.raw_os_error().unwrap_or(0)),
+                Errno::EPERM | Errno::EACCES
+            ) =>
+        {
+            Ok(SpecialRestore::Skipped(SkipReason::NotPermitted))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Make a node with `make` under a temporary name next to `path`, then rename it over
+/// whatever is there.
+#[cfg(unix)]
+fn replace_with(path: &Path, make: impl Fn(&Path) -> nix::Result<()>) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let node = tempfile::Builder::new()
+        .prefix(".tumulus-")
+        .make_in(dir, |temp| make(temp).map_err(io::Error::from))?;
+    node.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// Recreate a device, FIFO, or socket (not supported on this platform).
+#[cfg(not(unix))]
+pub fn recreate_special(
+    _path: &Path,
+    _special: &Value,
+    _unix_mode: Option<u32>,
+) -> io::Result<SpecialRestore> {
+    Ok(SpecialRestore::Skipped(SkipReason::Unsupported))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pipe");
+
+        let outcome = recreate_special(&path, &json!({ "type": "fifo" }), Some(0o600)).unwrap();
+        assert_eq!(outcome, SpecialRestore::Created);
+        assert!(
+            std::fs::symlink_metadata(&path)
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+    }
+
+    #[test]
+    fn replaces_existing_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pipe");
+        let fifo = json!({ "type": "fifo" });
+
+        std::fs::write(&path, "in the way").unwrap();
+        assert_eq!(
+            recreate_special(&path, &fifo, None).unwrap(),
+            SpecialRestore::Created
+        );
+        // Restoring over an earlier restore
+        assert_eq!(
+            recreate_special(&path, &fifo, None).unwrap(),
+            SpecialRestore::Created
+        );
+        assert!(
+            std::fs::symlink_metadata(&path)
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+
+        // Directories are left alone
+        let dir_path = dir.path().join("dir");
+        std::fs::create_dir(&dir_path).unwrap();
+        assert!(recreate_special(&dir_path, &fifo, None).is_err());
+        assert!(dir_path.is_dir());
+    }
+
+    #[test]
+    fn kept_when_not_permitted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("null");
+        std::fs::write(&path, "in the way").unwrap();
+
+        // /dev/null, which only privileged processes can create
+        let device = json!({ "type": "char", "rdev": nix::sys::stat::makedev(1, 3) });
+        match recreate_special(&path, &device, None).unwrap() {
+            SpecialRestore::Created => {
+                let file_type = std::fs::symlink_metadata(&path).unwrap().file_type();
+                assert!(file_type.is_char_device());
+            }
+            SpecialRestore::Skipped(reason) => {
+                assert_eq!(reason, SkipReason::NotPermitted);
+                assert_eq!(std::fs::read_to_string(&path).unwrap(), "in the way");
+            }
+        }
+        // No temporary node is left behind either way
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn socket_and_others_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("thing");
+
+        assert_eq!(
+            recreate_special(&path, &json!({ "type": "socket" }), None).unwrap(),
+            SpecialRestore::Skipped(SkipReason::Socket)
+        );
+        assert_eq!(
+            recreate_special(&path, &json!({ "type": "directory" }), None).unwrap(),
+            SpecialRestore::Skipped(SkipReason::NotApplicable)
+        );
+        assert_eq!(
+            recreate_special(&path, &json!({ "type": "char" }), None).unwrap(),
+            SpecialRestore::Skipped(SkipReason::NotApplicable)
+        );
+        assert!(!path.exists());
+    }
+}
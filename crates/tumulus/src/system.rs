@@ -0,0 +1,129 @@
+//! Manifest of the machine's storage layout, for full-system backups.
+//!
+//! Restoring a whole system onto new disks needs more than the files: the partition
+//! layout and the mounts (and the fstab that sets them up) say how to recreate the
+//! filesystems the files go back into.
+
+use std::{fs, path::Path};
+
+use fs_info::is_pseudo_fs;
+use serde_json::{Value, json};
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_imports))]
+use crate::exclude::unescape_octal;
+
+/// Gather the storage layout manifest for a system rooted at `root`.
+///
+/// The fstab is read from under `root`; partitions and mounts are those of the running
+/// machine, with pseudo-filesystems left out. Anything that can't be read is `null`.
+pub fn system_manifest(root: &Path) -> Value {
+    let fstab = fs::read_to_string(root.join("etc/fstab")).ok();
+    json!({
+        "fstab": fstab,
+        "partitions": partitions(),
+        "mounts": mounts(),
+    })
+}
+
+/// List the block devices and partitions of the running machine.
+#[cfg(target_os = "linux")]
+fn partitions() -> Option<Value> {
+    Some(parse_partitions(
+        &fs::read_to_string("/proc/partitions").ok()?,
+    ))
+}
+
+/// List the block devices and partitions (not supported on this platform).
+#[cfg(not(target_os = "linux"))]
+fn partitions() -> Option<Value> {
+    None
+}
+
+/// List the real (not pseudo) filesystems mounted on the running machine.
+#[cfg(target_os = "linux")]
+fn mounts() -> Option<Value> {
+    Some(parse_mounts(&fs::read_to_string("/proc/self/mounts").ok()?))
+}
+
+/// List the mounted filesystems (not supported on this platform).
+#[cfg(not(target_os = "linux"))]
+fn mounts() -> Option<Value> {
+    None
+}
+
+/// Parse `/proc/partitions`: a header, a blank line, then `major minor #blocks name`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_partitions(contents: &str) -> Value {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let major: u64 = fields.next()?.parse().ok()?;
+            let minor: u64 = fields.next()?.parse().ok()?;
+            let blocks: u64 = fields.next()?.parse().ok()?;
+            let name = fields.next()?;
+            // Sizes are in 1 KiB blocks
+            Some(json!({
+                "name": name,
+                "major": major,
+                "minor": minor,
+                "bytes": blocks * 1024,
+            }))
+        })
+        .collect()
+}
+
+/// Parse `/proc/self/mounts` (fstab format), leaving out pseudo-filesystems.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_mounts(contents: &str) -> Value {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = unescape_octal(fields.next()?);
+            let target = unescape_octal(fields.next()?);
+            let fs_type = fields.next()?;
+            let options = fields.next()?;
+            (!is_pseudo_fs(fs_type)).then(|| {
+                json!({
+                    "source": source,
+                    "target": target,
+                    "fs_type": fs_type,
+                    "options": options,
+                })
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions() {
+        let contents = "major minor  #blocks  name\n\n 259        0  500107608 nvme0n1\n 259        1     524288 nvme0n1p1\n";
+        assert_eq!(
+            parse_partitions(contents),
+            json!([
+                { "name": "nvme0n1", "major": 259, "minor": 0, "bytes": 500107608u64 * 1024 },
+                { "name": "nvme0n1p1", "major": 259, "minor": 1, "bytes": 524288u64 * 1024 },
+            ])
+        );
+    }
+
+    #[test]
+    fn mounts() {
+        let contents = "proc /proc proc rw,relatime 0 0\n\
+            /dev/nvme0n1p2 / btrfs rw,subvol=/@ 0 0\n\
+            /dev/sda1 /mnt/my\\040disk ext4 rw 0 0\n";
+        assert_eq!(
+            parse_mounts(contents),
+            json!([
+                { "source": "/dev/nvme0n1p2", "target": "/", "fs_type": "btrfs", "options": "rw,subvol=/@" },
+                { "source": "/dev/sda1", "target": "/mnt/my disk", "fs_type": "ext4", "options": "rw" },
+            ])
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Sending requests to a server, or to a stand-in for one running in-process.
+//!
+//! Requests are built as for a [`reqwest::Client`], then sent with [`Transport::send`]:
+//! over the network, or to a [`LocalHandler`] if there is one, such as the server's own
+//! handlers run over a repository directory, with no listening socket.
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use reqwest::{Client, IntoUrl, Method, Request, RequestBuilder, Response, header::HeaderMap};
+
+/// Handles requests in-process instead of sending them over the network.
+pub trait LocalHandler: Send + Sync {
+    fn handle(&self, request: Request) -> BoxFuture<'static, reqwest::Result<Response>>;
+}
+
+/// Where requests go, and headers they all carry.
+#[derive(Clone)]
+pub struct Transport {
+    client: Client,
+    headers: HeaderMap,
+    local: Option<Arc<dyn LocalHandler>>,
+}
+
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("client", &self.client)
+            .field("local", &self.local.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl From<Client> for Transport {
+    fn from(client: Client) -> Self {
+        Self::new(client)
+    }
+}
+
+impl Transport {
+    /// Send requests over the network with a client.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            headers: HeaderMap::new(),
+            local: None,
+        }
+    }
+
+    /// Add these headers to every request.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Hand requests to a local handler instead of sending them over the network.
+    pub fn with_local(mut self, handler: Arc<dyn LocalHandler>) -> Self {
+        self.local = Some(handler);
+        self
+    }
+
+    /// Start building a request.
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .headers(self.headers.clone())
+    }
+
+    /// Start building a GET request.
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    /// Start building a POST request.
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    /// Start building a PUT request.
+    pub fn put(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::PUT, url)
+    }
+
+    /// Send a request built with this transport.
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let request = request.build()?;
+        match &self.local {
+            Some(local) => local.handle(request).await,
+            None => self.client.execute(request).await,
+        }
+    }
+}
@@ -1,12 +1,279 @@
 //! Tree hash computation for snapshot deduplication.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
 
 use blake3::Hasher;
 
 use crate::B3Id;
 use crate::file::FileInfo;
 
+/// Version of the directory subtree hashes, recorded in catalogs alongside them.
+///
+/// Subtree hashes from catalogs of another version can't be compared or reused.
+pub const SUBTREE_HASH_VERSION: u32 = 1;
+
+/// Entry kind marker for files in a directory hash.
+const KIND_FILE: u8 = b'f';
+/// Entry kind marker for subdirectories in a directory hash.
+const KIND_DIR: u8 = b'd';
+
+/// The hash of every directory in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeHashes {
+    /// Hash of the root directory.
+    pub root: B3Id,
+    /// Hash of each directory subtree, by directory path. The root is at `""`.
+    pub subtrees: BTreeMap<String, B3Id>,
+}
+
+/// The path of the directory containing `path`, or `None` for the root.
+fn parent_of(path: &str) -> Option<&str> {
+    if path.is_empty() {
+        return None;
+    }
+    Some(path.rsplit_once('/').map_or("", |(parent, _)| parent))
+}
+
+/// The last component of `path`.
+fn name_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+/// Hash a directory from its entries, as `(name, kind, hash)`.
+fn hash_dir(entries: &mut [(String, u8, B3Id)]) -> B3Id {
+    entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+
+    let mut hasher = Hasher::new();
+    for (name, kind, hash) in entries.iter() {
+        let name_bytes = name.as_bytes();
+        hasher.update(&[*kind]);
+        hasher.update(&(name_bytes.len() as u32).to_le_bytes());
+        hasher.update(name_bytes);
+        hasher.update(hash.as_slice());
+    }
+    B3Id::from(hasher.finalize())
+}
+
+/// The file blobs and directory hashes of a previous catalog, for [`TreeHasher`] to reuse.
+#[derive(Debug, Default)]
+pub struct PreviousTree {
+    /// Kind and hash of each file with contents and each directory, by path.
+    entries: HashMap<String, (u8, B3Id)>,
+    /// How many of those each directory directly contains.
+    counts: HashMap<String, usize>,
+}
+
+impl PreviousTree {
+    /// Gather a previous catalog's files, by path and blob ID, and its subtree hashes.
+    ///
+    /// The subtree hashes must be of the current [`SUBTREE_HASH_VERSION`].
+    pub fn new(
+        files: impl IntoIterator<Item = (String, B3Id)>,
+        subtrees: impl IntoIterator<Item = (String, B3Id)>,
+    ) -> Self {
+        let mut previous = Self::default();
+        let files = files.into_iter().map(|(path, id)| (path, (KIND_FILE, id)));
+        let dirs = subtrees
+            .into_iter()
+            .map(|(path, id)| (path, (KIND_DIR, id)));
+        for (path, entry) in files.chain(dirs) {
+            if let Some(parent) = parent_of(&path) {
+                *previous.counts.entry(parent.to_string()).or_default() += 1;
+            }
+            previous.entries.insert(path, entry);
+        }
+        previous
+    }
+}
+
+/// A directory being hashed.
+#[derive(Debug, Default)]
+struct DirNode {
+    /// Entries below it not finished yet, and the directory itself if it was registered.
+    pending: usize,
+    /// Its files with contents and its subdirectories with contents below, as `(name, kind,
+    /// hash)`.
+    entries: Vec<(String, u8, B3Id)>,
+    /// Whether any entry differs from the previous catalog.
+    changed: bool,
+}
+
+#[derive(Debug, Default)]
+struct HasherState {
+    nodes: HashMap<String, DirNode>,
+    subtrees: BTreeMap<String, B3Id>,
+    reused: usize,
+}
+
+impl HasherState {
+    /// Add an entry to its directory, noting whether it's the same as in the previous catalog.
+    fn add_entry(&mut self, previous: &PreviousTree, path: &str, kind: u8, hash: B3Id) {
+        let Some(parent) = parent_of(path) else {
+            return;
+        };
+        let Some(node) = self.nodes.get_mut(parent) else {
+            return;
+        };
+        if previous.entries.get(path) != Some(&(kind, hash)) {
+            node.changed = true;
+        }
+        node.entries.push((name_of(path).to_string(), kind, hash));
+    }
+
+    /// Count one pending entry of `path` as done, and hash the directories this completes.
+    fn complete(&mut self, previous: &PreviousTree, path: &str) {
+        let mut path = path.to_string();
+        loop {
+            let Some(node) = self.nodes.get_mut(&path) else {
+                return;
+            };
+            node.pending -= 1;
+            if node.pending > 0 {
+                return;
+            }
+            let Some(mut node) = self.nodes.remove(&path) else {
+                return;
+            };
+            let reusable = !node.changed
+                && previous.counts.get(&path).copied().unwrap_or_default() == node.entries.len();
+            // Directories without contents below them aren't part of the tree, except the root
+            if !node.entries.is_empty() || path.is_empty() {
+                let hash = match previous.entries.get(&path) {
+                    Some((KIND_DIR, hash)) if reusable => {
+                        self.reused += 1;
+                        *hash
+                    }
+                    _ => hash_dir(&mut node.entries),
+                };
+                self.add_entry(previous, &path, KIND_DIR, hash);
+                self.subtrees.insert(path.clone(), hash);
+            }
+
+            match parent_of(&path) {
+                Some(parent) => path = parent.to_string(),
+                None => return,
+            }
+        }
+    }
+}
+
+/// Hashes directories as a scan completes them.
+///
+/// Every entry the scan will produce is [registered](Self::register) up front. Entries are
+/// then [finished](Self::finish) in any order, from any thread, and each directory is hashed
+/// as soon as everything in it is finished. Directories whose entries are all the same as in
+/// a [previous catalog](Self::with_previous) take their hash from it instead.
+#[derive(Debug, Default)]
+pub struct TreeHasher {
+    previous: PreviousTree,
+    state: Mutex<HasherState>,
+}
+
+impl TreeHasher {
+    /// Hash a tree from scratch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash a tree, reusing the hashes of a previous catalog's unchanged directories.
+    pub fn with_previous(previous: PreviousTree) -> Self {
+        Self {
+            previous,
+            ..Self::default()
+        }
+    }
+
+    /// Register an entry by path, before any entry is finished.
+    ///
+    /// Directories above it that aren't registered themselves are added as needed.
+    pub fn register(&mut self, path: &str) {
+        let nodes = &mut self.state.get_mut().unwrap().nodes;
+        let known = nodes.contains_key(path);
+        nodes.entry(path.to_string()).or_default().pending += 1;
+        if known {
+            return;
+        }
+
+        let mut path = path;
+        while let Some(parent) = parent_of(path) {
+            let known = nodes.contains_key(parent);
+            nodes.entry(parent.to_string()).or_default().pending += 1;
+            if known {
+                break;
+            }
+            path = parent;
+        }
+    }
+
+    /// Finish a registered entry, with the blob ID of its contents if it has any.
+    pub fn finish(&self, path: &str, blob_id: Option<&B3Id>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.nodes.contains_key(path) {
+            return;
+        }
+        if let Some(blob_id) = blob_id {
+            state.add_entry(&self.previous, path, KIND_FILE, *blob_id);
+        }
+        state.complete(&self.previous, path);
+    }
+
+    /// The hashes of the whole tree, if every registered entry was finished.
+    pub fn into_hashes(self) -> Option<TreeHashes> {
+        let mut state = self.state.into_inner().unwrap();
+        if !state.nodes.is_empty() {
+            return None;
+        }
+        // Only an empty tree has no root yet
+        let root = *state
+            .subtrees
+            .entry(String::new())
+            .or_insert_with(|| hash_dir(&mut []));
+        Some(TreeHashes {
+            root,
+            subtrees: state.subtrees,
+        })
+    }
+
+    /// How many directories took their hash from the previous catalog.
+    pub fn reused(&self) -> usize {
+        self.state.lock().unwrap().reused
+    }
+}
+
+/// Compute the hash of every directory subtree for a set of files.
+///
+/// This is a Merkle tree over directories: each directory is hashed from its entries (files
+/// by blob ID, subdirectories by their own hash), so that two directories with identical
+/// file contents hash to the same thing, and a change to a file only changes the hashes of
+/// its ancestor directories. During a scan, [`TreeHasher`] computes the same hashes as
+/// directories are completed.
+///
+/// Each directory hash is a BLAKE3 hash of its entries, byte-wise sorted by name, with
+/// each entry being:
+/// - 1 byte: `f` for a file, `d` for a directory
+/// - 4 bytes (u32 LE): size of the name (N)
+/// - N bytes: name in bytes
+/// - 32 bytes: blob ID for a file, directory hash for a directory
+///
+/// Files without blobs (special files like symlinks) are not included, and directories are
+/// only included if they contain files with blobs somewhere below. The root always is.
+pub fn compute_tree_hashes(files: &[FileInfo]) -> TreeHashes {
+    let mut hasher = TreeHasher::new();
+    for file in files {
+        hasher.register(&file.relative_path);
+    }
+    for file in files {
+        hasher.finish(
+            &file.relative_path,
+            file.blob.as_ref().map(|blob| &blob.blob_id),
+        );
+    }
+    hasher
+        .into_hashes()
+        .expect("every registered entry is finished")
+}
+
 /// Compute the tree hash for a set of files.
 ///
 /// The tree hash is a BLAKE3 hash of a rigidly-structured mapping from file paths
@@ -41,3 +308,151 @@ pub fn compute_tree_hash(files: &[FileInfo]) -> B3Id {
 
     B3Id::from(hasher.finalize())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extents::BlobInfo;
+
+    fn file(path: &str, blob: u8) -> FileInfo {
+        FileInfo {
+            relative_path: path.to_string(),
+            root: None,
+            blob: Some(BlobInfo {
+                blob_id: B3Id::from([blob; 32]),
+                bytes: 0,
+                extents: Vec::new(),
+            }),
+            ts_created: None,
+            ts_modified: None,
+            ts_accessed: None,
+            ts_changed: None,
+            unix_mode: None,
+            unix_owner_id: None,
+            unix_group_id: None,
+            fs_inode: None,
+            fs_change_cookie: None,
+            priority: None,
+            attributes: None,
+            streams: Vec::new(),
+            special: None,
+        }
+    }
+
+    #[test]
+    fn order_independent() {
+        let a = [file("a/x", 1), file("b/y", 2), file("c", 3)];
+        let b = [file("c", 3), file("b/y", 2), file("a/x", 1)];
+        assert_eq!(compute_tree_hashes(&a), compute_tree_hashes(&b));
+    }
+
+    #[test]
+    fn change_only_affects_ancestors() {
+        let before = compute_tree_hashes(&[file("a/x", 1), file("b/c/y", 2), file("b/z", 3)]);
+        let after = compute_tree_hashes(&[file("a/x", 1), file("b/c/y", 9), file("b/z", 3)]);
+
+        assert_ne!(before.root, after.root);
+        assert_ne!(before.subtrees["b"], after.subtrees["b"]);
+        assert_ne!(before.subtrees["b/c"], after.subtrees["b/c"]);
+        assert_eq!(before.subtrees["a"], after.subtrees["a"]);
+        assert_eq!(before.subtrees[""], before.root);
+    }
+
+    #[test]
+    fn same_contents_same_subtree() {
+        let hashes = compute_tree_hashes(&[file("one/x", 1), file("two/x", 1)]);
+        assert_eq!(hashes.subtrees["one"], hashes.subtrees["two"]);
+    }
+
+    #[test]
+    fn file_and_dir_are_distinct() {
+        // A file and a directory with the same name and hash mustn't collide
+        let as_file = compute_tree_hashes(&[file("a", 1)]).root;
+        let as_dir = compute_tree_hashes(&[file("a/b", 1)]);
+        let reparented = compute_tree_hashes(&[file("b", 1)]).root;
+        assert_eq!(as_dir.subtrees["a"], reparented);
+        assert_ne!(as_file, as_dir.root);
+    }
+
+    fn scan(hasher: &mut TreeHasher, entries: &[(&str, Option<u8>)]) {
+        for (path, _) in entries {
+            hasher.register(path);
+        }
+        // Entries finish in any order, directories included
+        for (path, blob) in entries.iter().rev() {
+            hasher.finish(path, blob.map(|blob| B3Id::from([blob; 32])).as_ref());
+        }
+    }
+
+    #[test]
+    fn scan_matches_file_list() {
+        let mut hasher = TreeHasher::new();
+        scan(
+            &mut hasher,
+            &[
+                ("", None),
+                ("a", None),
+                ("a/x", Some(1)),
+                ("b", None),
+                ("b/c", None),
+                ("b/c/y", Some(2)),
+                ("b/link", None),
+                ("empty", None),
+            ],
+        );
+        let hashes = hasher.into_hashes().unwrap();
+        assert_eq!(
+            hashes,
+            compute_tree_hashes(&[file("a/x", 1), file("b/c/y", 2)])
+        );
+        assert!(!hashes.subtrees.contains_key("empty"));
+    }
+
+    #[test]
+    fn unfinished_scan_has_no_hashes() {
+        let mut hasher = TreeHasher::new();
+        hasher.register("a/x");
+        hasher.register("a/y");
+        hasher.finish("a/x", Some(&B3Id::from([1; 32])));
+        assert_eq!(hasher.into_hashes(), None);
+    }
+
+    #[test]
+    fn reuses_unchanged_subtrees() {
+        let before = [file("a/x", 1), file("b/c/y", 2), file("b/z", 3)];
+        let after = [("a/x", Some(1)), ("b/c/y", Some(9)), ("b/z", Some(3))];
+
+        // Stand in a hash for "a" which can only have come from the previous catalog
+        let mut subtrees = compute_tree_hashes(&before).subtrees;
+        subtrees.insert("a".into(), B3Id::from([0xaa; 32]));
+        let previous = PreviousTree::new(
+            before.iter().map(|f| {
+                let blob = f.blob.as_ref().unwrap();
+                (f.relative_path.clone(), blob.blob_id)
+            }),
+            subtrees,
+        );
+
+        let mut hasher = TreeHasher::with_previous(previous);
+        scan(&mut hasher, &after);
+        assert_eq!(hasher.reused(), 1);
+        let hashes = hasher.into_hashes().unwrap();
+        assert_eq!(hashes.subtrees["a"], B3Id::from([0xaa; 32]));
+        assert_eq!(
+            hashes.subtrees["b"],
+            compute_tree_hashes(&[file("b/c/y", 9), file("b/z", 3)]).subtrees["b"]
+        );
+    }
+
+    #[test]
+    fn added_file_is_a_change() {
+        let before = [file("a/x", 1)];
+        let previous = PreviousTree::new(
+            [("a/x".to_string(), B3Id::from([1; 32]))],
+            compute_tree_hashes(&before).subtrees,
+        );
+        let mut hasher = TreeHasher::with_previous(previous);
+        scan(&mut hasher, &[("a/x", Some(1)), ("a/y", Some(2))]);
+        assert_eq!(hasher.reused(), 0);
+    }
+}
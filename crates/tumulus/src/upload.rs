@@ -0,0 +1,238 @@
+//! Uploading extents to the server, streamed from any reader.
+//!
+//! Extent data is sent as it's read, in chunks, and hashed on the way: callers can feed
+//! it straight from files, decompressors, or a local cache without holding whole extents
+//! in memory. If the data doesn't hash to the extent's ID, the upload is abandoned before
+//! its last bytes are sent, so the server never stores it.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use futures::{SinkExt, StreamExt, channel::mpsc, stream};
+use reqwest::{Body, StatusCode, header};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::transport::Transport;
+use crate::{B3Id, ExtentKey};
+
+/// Size of the chunks extent data is read and sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Error uploading an extent.
+#[derive(Debug, thiserror::Error)]
+pub enum UploadExtentError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Extent {extent} has changed: its data now hashes to {actual}")]
+    Changed { extent: B3Id, actual: B3Id },
+
+    #[error("Read {actual} bytes of extent {extent}, expected {expected}")]
+    Length {
+        extent: B3Id,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Server returned {status} for extent {extent}: {error}{}", detail.as_ref().map(|d| format!(" - {}", d)).unwrap_or_default())]
+    Server {
+        extent: B3Id,
+        status: StatusCode,
+        error: String,
+        detail: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    #[serde(default)]
+    detail: Option<String>,
+}
+
+/// Uploads extents to the server.
+///
+/// The client should be set up with the auth token and extent key headers the server
+/// needs. For catalogs with keyed IDs, the uploader needs the same key to check data
+/// against IDs.
+#[derive(Debug, Clone)]
+pub struct ExtentUploader {
+    transport: Transport,
+    server_url: String,
+    key: Option<ExtentKey>,
+}
+
+impl ExtentUploader {
+    /// Create an uploader for a server, sending requests through `transport` (a plain
+    /// [`reqwest::Client`] converts into one).
+    pub fn new(transport: impl Into<Transport>, server_url: impl Into<String>) -> Self {
+        Self {
+            transport: transport.into(),
+            server_url: server_url.into().trim_end_matches('/').to_string(),
+            key: None,
+        }
+    }
+
+    /// Check data against keyed extent IDs.
+    pub fn with_key(mut self, key: Option<ExtentKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Upload `length` bytes from an async reader as an extent.
+    ///
+    /// Returns whether the server stored it, or already had it.
+    pub async fn upload<R>(
+        &self,
+        extent: B3Id,
+        reader: R,
+        length: u64,
+    ) -> Result<bool, UploadExtentError>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let chunks = stream::unfold(
+            (reader, vec![0; CHUNK_SIZE], length),
+            |(mut reader, mut buf, remaining)| async move {
+                if remaining == 0 {
+                    return None;
+                }
+                let want = buf.len().min(remaining as usize);
+                let chunk = reader
+                    .read(&mut buf[..want])
+                    .await
+                    .map(|read| buf[..read].to_vec());
+                let remaining = match chunk {
+                    Ok(ref chunk) if !chunk.is_empty() => remaining - chunk.len() as u64,
+                    // Errors and early ends are the last chunk
+                    _ => 0,
+                };
+                Some((chunk, (reader, buf, remaining)))
+            },
+        );
+        self.send(extent, length, chunks).await
+    }
+
+    /// Upload `length` bytes from a blocking reader as an extent.
+    ///
+    /// The reader is read on tokio's blocking pool, a few chunks ahead of the upload.
+    pub async fn upload_blocking<R>(
+        &self,
+        extent: B3Id,
+        mut reader: R,
+        length: u64,
+    ) -> Result<bool, UploadExtentError>
+    where
+        R: Read + Send + 'static,
+    {
+        let (mut sender, receiver) = mpsc::channel(4);
+        let reading = tokio::task::spawn_blocking(move || {
+            let mut remaining = length;
+            let mut buf = vec![0; CHUNK_SIZE];
+            while remaining > 0 {
+                let want = buf.len().min(remaining as usize);
+                let chunk = reader
+                    .read(&mut buf[..want])
+                    .map(|read| buf[..read].to_vec());
+                let read = match chunk {
+                    Ok(ref chunk) if !chunk.is_empty() => chunk.len() as u64,
+                    // Errors and early ends are the last chunk
+                    _ => remaining,
+                };
+                // A dropped receiver means the upload stopped: nothing more to read for
+                if futures::executor::block_on(sender.send(chunk)).is_err() {
+                    break;
+                }
+                remaining -= read;
+            }
+        });
+        let result = self.send(extent, length, receiver).await;
+        reading.abort();
+        result
+    }
+
+    /// Upload an extent from chunks of its data, checking them as they go.
+    async fn send<S>(&self, extent: B3Id, length: u64, chunks: S) -> Result<bool, UploadExtentError>
+    where
+        S: futures::Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        // Why the body stopped, if it was the data's fault rather than the connection's
+        let failure: Arc<Mutex<Option<UploadExtentError>>> = Arc::default();
+        let checked = stream::unfold(
+            (
+                Box::pin(chunks),
+                B3Id::hasher(self.key.as_ref()),
+                0u64,
+                failure.clone(),
+            ),
+            move |(mut chunks, mut hasher, mut sent, failure)| async move {
+                if sent == length {
+                    return None;
+                }
+                let fail = |err: UploadExtentError| {
+                    let message = err.to_string();
+                    *failure.lock().unwrap() = Some(err);
+                    io::Error::new(io::ErrorKind::InvalidData, message)
+                };
+
+                let chunk = match chunks.next().await {
+                    Some(Ok(chunk)) if !chunk.is_empty() => chunk,
+                    Some(Err(err)) => {
+                        let err = fail(UploadExtentError::Io(err));
+                        return Some((Err(err), (chunks, hasher, length, failure)));
+                    }
+                    _ => {
+                        let err = fail(UploadExtentError::Length {
+                            extent,
+                            expected: length,
+                            actual: sent,
+                        });
+                        return Some((Err(err), (chunks, hasher, length, failure)));
+                    }
+                };
+                hasher.update(&chunk);
+                sent += chunk.len() as u64;
+
+                // The last chunk is only sent once the whole extent is known to be right
+                if sent == length {
+                    let actual = B3Id::from(hasher.finalize());
+                    if actual != extent {
+                        let err = fail(UploadExtentError::Changed { extent, actual });
+                        return Some((Err(err), (chunks, hasher, length, failure)));
+                    }
+                }
+                Some((Ok(chunk), (chunks, hasher, sent, failure)))
+            },
+        );
+
+        let url = format!("{}/extents/{}", self.server_url, extent);
+        let request = self
+            .transport
+            .put(&url)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, length)
+            .body(Body::wrap_stream(checked));
+        let result = self.transport.send(request).await;
+        if let Some(err) = failure.lock().unwrap().take() {
+            return Err(err);
+        }
+
+        // 200 OK = already existed, 201 Created = newly stored
+        let resp = result?;
+        let status = resp.status();
+        if !status.is_success() {
+            let error: ErrorResponse = resp.json().await?;
+            return Err(UploadExtentError::Server {
+                extent,
+                status,
+                error: error.error,
+                detail: error.detail,
+            });
+        }
+        Ok(status == StatusCode::CREATED)
+    }
+}
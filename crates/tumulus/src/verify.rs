@@ -0,0 +1,353 @@
+//! Checking a tree on disk against a catalog, as after a restore.
+//!
+//! Every entry of the catalog is scanned again where it should be under the target
+//! directory, the same way it was when cataloging, and compared field by field: type,
+//! size, contents (by blob ID), the layout of data, holes, and preallocated ranges, and
+//! metadata. Entries under the target that aren't in the catalog are reported too, so a
+//! clean report means the tree is exactly what was cataloged.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use extentria::{RangeReader, RangeReaderImpl};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::{Value, json};
+use walkdir::WalkDir;
+
+use crate::extents::BlobInfo;
+use crate::file::{FileInfo, process_file_with_reader};
+use crate::{B3Id, ExtentKey};
+
+/// What to compare besides contents and layout.
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyOptions {
+    /// Compare owner and group, which only a privileged restore can set.
+    pub ownership: bool,
+    /// Compare modification times.
+    pub times: bool,
+    /// Compare the extended attributes and hardlinks of system backups, as captured by
+    /// [`FileInfo::with_system_metadata()`].
+    pub system_metadata: bool,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            ownership: true,
+            times: true,
+            system_metadata: false,
+        }
+    }
+}
+
+/// A field of an entry that differs from the catalog.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    pub field: &'static str,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+/// An entry that isn't as the catalog has it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Problem {
+    /// In the catalog, but not on disk.
+    Missing { path: String },
+    /// On disk, but not in the catalog.
+    Extra { path: String },
+    /// On disk, but couldn't be scanned.
+    Unreadable { path: String, error: String },
+    /// On disk, with some fields different from the catalog.
+    Different {
+        path: String,
+        differences: Vec<Difference>,
+    },
+}
+
+/// The outcome of checking a tree against a catalog.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    /// Entries in the catalog.
+    pub entries: usize,
+    /// Entries found on disk exactly as cataloged.
+    pub verified: usize,
+    /// Everything that differs, sorted by path.
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// Whether the tree is exactly as cataloged.
+    pub fn is_faithful(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the tree under `target` against a catalog's entries.
+///
+/// Entries are expected at their catalog path under `target`, and must have been
+/// decrypted if the catalog's paths are encrypted. For catalogs with keyed IDs, `key` must
+/// be the key they were made with, or every file's contents will differ.
+pub fn verify_tree(
+    files: &[FileInfo],
+    target: &Path,
+    key: Option<&ExtentKey>,
+    options: VerifyOptions,
+) -> VerifyReport {
+    let mut problems: Vec<Problem> = files
+        .par_iter()
+        .map_init(RangeReader::new, |reader, expected| {
+            let path = target.join(&expected.relative_path);
+            let scanned = process_file_with_reader(&path, target, reader, key)
+                .and_then(|info| {
+                    if expected.attributes.is_some() || !expected.streams.is_empty() {
+                        info.with_apple_metadata(&path, reader, key)
+                    } else {
+                        Ok(info)
+                    }
+                })
+                .and_then(|info| {
+                    if options.system_metadata {
+                        info.with_system_metadata(&path)
+                    } else {
+                        Ok(info)
+                    }
+                });
+            let path = expected.relative_path.clone();
+            match scanned {
+                Ok(actual) => {
+                    let differences = compare(expected, &actual, options);
+                    (!differences.is_empty()).then_some(Problem::Different { path, differences })
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    Some(Problem::Missing { path })
+                }
+                Err(err) => Some(Problem::Unreadable {
+                    path,
+                    error: err.to_string(),
+                }),
+            }
+        })
+        .flatten()
+        .collect();
+
+    let mismatched = problems.len();
+    let cataloged: HashSet<&str> = files.iter().map(|f| f.relative_path.as_str()).collect();
+    problems.extend(
+        WalkDir::new(target)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry
+                    .path()
+                    .strip_prefix(target)
+                    .ok()?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                (!cataloged.contains(path.as_str())).then_some(Problem::Extra { path })
+            }),
+    );
+    problems.sort_by(|a, b| problem_path(a).cmp(problem_path(b)));
+
+    VerifyReport {
+        entries: files.len(),
+        verified: files.len() - mismatched,
+        problems,
+    }
+}
+
+fn problem_path(problem: &Problem) -> &str {
+    match problem {
+        Problem::Missing { path }
+        | Problem::Extra { path }
+        | Problem::Unreadable { path, .. }
+        | Problem::Different { path, .. } => path,
+    }
+}
+
+/// Compare a scanned entry to its catalog entry.
+fn compare(expected: &FileInfo, actual: &FileInfo, options: VerifyOptions) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    let mut check = |field, expected: Value, actual: Value| {
+        if expected != actual {
+            differences.push(Difference {
+                field,
+                expected,
+                actual,
+            });
+        }
+    };
+
+    check("type", json!(expected.special), json!(actual.special));
+    let size = |blob: &Option<BlobInfo>| blob.as_ref().map(|b| b.bytes);
+    check(
+        "size",
+        json!(size(&expected.blob)),
+        json!(size(&actual.blob)),
+    );
+    let id = |blob: &Option<BlobInfo>| blob.as_ref().map(|b| b.blob_id);
+    check(
+        "content",
+        json!(id(&expected.blob)),
+        json!(id(&actual.blob)),
+    );
+    check(
+        "layout",
+        json!(expected.blob.as_ref().map(layout)),
+        json!(actual.blob.as_ref().map(layout)),
+    );
+    check("mode", json!(expected.unix_mode), json!(actual.unix_mode));
+    if options.ownership {
+        check(
+            "owner",
+            json!(expected.unix_owner_id),
+            json!(actual.unix_owner_id),
+        );
+        check(
+            "group",
+            json!(expected.unix_group_id),
+            json!(actual.unix_group_id),
+        );
+    }
+    if options.times {
+        check(
+            "modified",
+            json!(expected.ts_modified),
+            json!(actual.ts_modified),
+        );
+    }
+    if expected.attributes.is_some() {
+        check(
+            "attributes",
+            json!(expected.attributes),
+            json!(actual.attributes),
+        );
+    }
+    let streams = |info: &FileInfo| -> Vec<(String, B3Id)> {
+        info.streams
+            .iter()
+            .map(|s| (s.name.clone(), s.blob.blob_id))
+            .collect()
+    };
+    check("streams", json!(streams(expected)), json!(streams(actual)));
+
+    differences
+}
+
+/// A blob's data, holes, and preallocated ranges, with adjacent ranges of the same kind
+/// merged so that chunking doesn't matter.
+fn layout(blob: &BlobInfo) -> Vec<(u64, u64, &'static str)> {
+    let mut ranges: Vec<(u64, u64, &'static str)> = Vec::new();
+    for extent in &blob.extents {
+        let range = extent.range;
+        let kind = if range.hole {
+            "hole"
+        } else if range.unwritten {
+            "unwritten"
+        } else {
+            "data"
+        };
+        match ranges.last_mut() {
+            Some((offset, length, last)) if *last == kind && *offset + *length == range.offset => {
+                *length += range.length
+            }
+            _ => ranges.push((range.offset, range.length, kind)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Scan a tree the way the catalog command does.
+    fn scan(root: &Path) -> Vec<FileInfo> {
+        let mut reader = RangeReader::new();
+        WalkDir::new(root)
+            .into_iter()
+            .map(|entry| process_file_with_reader(entry.unwrap().path(), root, &mut reader, None))
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn identical_tree_is_faithful() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/file"), b"contents").unwrap();
+        fs::write(dir.path().join("empty"), b"").unwrap();
+
+        let files = scan(dir.path());
+        let report = verify_tree(&files, dir.path(), None, VerifyOptions::default());
+        assert!(report.is_faithful(), "{:?}", report.problems);
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.verified, 4);
+    }
+
+    #[test]
+    fn differences_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("changed"), b"contents").unwrap();
+        fs::write(dir.path().join("gone"), b"contents").unwrap();
+        let files = scan(dir.path());
+
+        fs::write(dir.path().join("changed"), b"other contents").unwrap();
+        fs::remove_file(dir.path().join("gone")).unwrap();
+        fs::write(dir.path().join("new"), b"").unwrap();
+
+        let options = VerifyOptions {
+            times: false,
+            ..Default::default()
+        };
+        let report = verify_tree(&files, dir.path(), None, options);
+        assert_eq!(report.verified, 1);
+
+        let Problem::Different { path, differences } = &report.problems[0] else {
+            panic!("{:?}", report.problems);
+        };
+        assert_eq!(path, "changed");
+        let fields: Vec<_> = differences.iter().map(|d| d.field).collect();
+        assert_eq!(fields, ["size", "content", "layout"]);
+        assert_eq!(
+            report.problems[1..],
+            [
+                Problem::Missing {
+                    path: "gone".into()
+                },
+                Problem::Extra { path: "new".into() },
+            ]
+        );
+    }
+
+    #[test]
+    fn layout_merges_chunks() {
+        use extentria::DataRange;
+
+        use crate::extents::ExtentInfo;
+
+        let extent = |range| ExtentInfo {
+            extent_id: B3Id::from([0; 32]),
+            range,
+            fs_extent: 0,
+        };
+        let blob = BlobInfo {
+            blob_id: B3Id::from([0; 32]),
+            bytes: 40,
+            extents: vec![
+                extent(DataRange::new(0, 10)),
+                extent(DataRange::new(10, 10)),
+                extent(DataRange::hole(20, 10)),
+                extent(DataRange::unwritten(30, 10)),
+            ],
+        };
+        assert_eq!(
+            layout(&blob),
+            [(0, 20, "data"), (20, 10, "hole"), (30, 10, "unwritten")]
+        );
+    }
+}
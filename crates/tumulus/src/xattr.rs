@@ -0,0 +1,322 @@
+//! Extended attributes of every namespace, for full-system backups.
+//!
+//! Beyond user metadata, extended attributes hold POSIX ACLs (`system.posix_acl_access`
+//! and `system.posix_acl_default`), file capabilities (`security.capability`), and
+//! security labels, which a system needs back to work as it did. They're kept in the
+//! file's `attributes` column under `xattrs`, base64-encoded, like macOS Finder metadata
+//! (see [`crate::apple`]), which reads and writes them through this module too.
+//!
+//! Names are bytes, as the filesystem has them. Those that are UTF-8 are kept as they
+//! are; others, and those starting with [`BASE64_NAME`], are kept base64-encoded behind
+//! that prefix.
+//!
+//! Capturing only finds anything on Linux.
+
+use std::{collections::BTreeMap, io, path::Path};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::{Map, Value, json};
+
+/// Extended attribute values by name.
+pub type Xattrs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Prefix of base64-encoded names in the `xattrs` object.
+pub const BASE64_NAME: &str = "base64:";
+
+/// Encode a name as a key of the `xattrs` object.
+fn encode_name(name: &[u8]) -> String {
+    match std::str::from_utf8(name) {
+        Ok(name) if !name.starts_with(BASE64_NAME) => name.to_string(),
+        _ => format!("{BASE64_NAME}{}", STANDARD.encode(name)),
+    }
+}
+
+/// Decode a key of the `xattrs` object back to a name.
+fn decode_name(key: &str) -> Option<Vec<u8>> {
+    match key.strip_prefix(BASE64_NAME) {
+        Some(encoded) => STANDARD.decode(encoded).ok(),
+        None => Some(key.as_bytes().to_vec()),
+    }
+}
+
+/// Encode extended attributes as the `xattrs` value of the `attributes` column.
+pub fn xattrs_to_value(xattrs: &Xattrs) -> Value {
+    let xattrs: Map<String, Value> = xattrs
+        .iter()
+        .map(|(name, value)| (encode_name(name), json!(STANDARD.encode(value))))
+        .collect();
+    Value::Object(xattrs)
+}
+
+/// Decode extended attributes from the `attributes` column value, ignoring anything
+/// malformed.
+pub fn xattrs_from_attributes(attributes: &Value) -> Xattrs {
+    attributes
+        .get("xattrs")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| {
+            let value = STANDARD.decode(value.as_str()?).ok()?;
+            Some((decode_name(name)?, value))
+        })
+        .collect()
+}
+
+/// Read the extended attributes of a file whose names `keep` (without following
+/// symlinks).
+///
+/// Filesystems without extended attributes have none.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn read_matching(path: &Path, keep: impl Fn(&[u8]) -> bool) -> io::Result<Xattrs> {
+    let names = match sys::list(path) {
+        Ok(names) => names,
+        Err(err) if err.raw_os_error() == Some(nix::libc::ENOTSUP) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names.into_iter().filter(|name| keep(name)) {
+        match sys::get(path, &name) {
+            Ok(value) => {
+                xattrs.insert(name, value);
+            }
+            // Removed since listing
+            Err(err) if err.raw_os_error() == Some(sys::NO_ATTR) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Set extended attributes on a file (without following symlinks).
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn write_all(path: &Path, xattrs: &Xattrs) -> io::Result<()> {
+    for (name, value) in xattrs {
+        sys::set(path, name, value)?;
+    }
+    Ok(())
+}
+
+/// Read all the extended attributes of a file (without following symlinks).
+///
+/// Filesystems without extended attributes have none.
+#[cfg(target_os = "linux")]
+pub fn read_xattrs(path: &Path) -> io::Result<Xattrs> {
+    read_matching(path, |_| true)
+}
+
+/// Read the extended attributes of a file (not supported on this platform).
+#[cfg(not(target_os = "linux"))]
+pub fn read_xattrs(_path: &Path) -> io::Result<Xattrs> {
+    Ok(BTreeMap::new())
+}
+
+/// Set extended attributes on a file (without following symlinks). Returns whether they
+/// were restored, which they aren't outside of Linux.
+#[cfg(target_os = "linux")]
+pub fn restore_xattrs(path: &Path, xattrs: &Xattrs) -> io::Result<bool> {
+    write_all(path, xattrs)?;
+    Ok(true)
+}
+
+/// Set extended attributes on a file (not supported on this platform).
+#[cfg(not(target_os = "linux"))]
+pub fn restore_xattrs(_path: &Path, _xattrs: &Xattrs) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::{
+        ffi::{CString, c_char, c_void},
+        io,
+        os::unix::ffi::OsStrExt,
+        path::Path,
+        ptr,
+    };
+
+    use nix::libc::{self, ERANGE};
+
+    /// Error of getting an attribute the file doesn't have.
+    #[cfg(target_os = "linux")]
+    pub const NO_ATTR: i32 = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    pub const NO_ATTR: i32 = libc::ENOATTR;
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    /// Call a size-then-fill function until the buffer is big enough, as the value may
+    /// grow between the calls.
+    fn read_sized(mut call: impl FnMut(*mut c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = call(ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; size as usize];
+            let read = call(buf.as_mut_ptr().cast(), buf.len());
+            if read < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(ERANGE) {
+                    continue;
+                }
+                return Err(err);
+            }
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+    }
+
+    pub fn list(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let names = read_sized(|buf, size| unsafe { raw::list(path.as_ptr(), buf.cast(), size) })?;
+        Ok(names
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect())
+    }
+
+    pub fn get(path: &Path, name: &[u8]) -> io::Result<Vec<u8>> {
+        let (path, name) = (c_string(path.as_os_str().as_bytes())?, c_string(name)?);
+        read_sized(|buf, size| unsafe { raw::get(path.as_ptr(), name.as_ptr(), buf, size) })
+    }
+
+    pub fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let (path, name) = (c_string(path.as_os_str().as_bytes())?, c_string(name)?);
+        let result = unsafe {
+            raw::set(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The platform's calls that don't follow symlinks.
+    #[cfg(target_os = "linux")]
+    mod raw {
+        use super::*;
+
+        pub unsafe fn list(path: *const c_char, buf: *mut c_char, size: usize) -> isize {
+            unsafe { libc::llistxattr(path, buf, size) }
+        }
+
+        pub unsafe fn get(
+            path: *const c_char,
+            name: *const c_char,
+            buf: *mut c_void,
+            size: usize,
+        ) -> isize {
+            unsafe { libc::lgetxattr(path, name, buf, size) }
+        }
+
+        pub unsafe fn set(
+            path: *const c_char,
+            name: *const c_char,
+            value: *const c_void,
+            size: usize,
+        ) -> i32 {
+            unsafe { libc::lsetxattr(path, name, value, size, 0) }
+        }
+    }
+
+    /// The platform's calls that don't follow symlinks.
+    #[cfg(target_os = "macos")]
+    mod raw {
+        use super::*;
+        use nix::libc::XATTR_NOFOLLOW;
+
+        pub unsafe fn list(path: *const c_char, buf: *mut c_char, size: usize) -> isize {
+            unsafe { libc::listxattr(path, buf, size, XATTR_NOFOLLOW) }
+        }
+
+        pub unsafe fn get(
+            path: *const c_char,
+            name: *const c_char,
+            buf: *mut c_void,
+            size: usize,
+        ) -> isize {
+            unsafe { libc::getxattr(path, name, buf, size, 0, XATTR_NOFOLLOW) }
+        }
+
+        pub unsafe fn set(
+            path: *const c_char,
+            name: *const c_char,
+            value: *const c_void,
+            size: usize,
+        ) -> i32 {
+            unsafe { libc::setxattr(path, name, value, size, 0, XATTR_NOFOLLOW) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_round_trip() {
+        let xattrs = BTreeMap::from([
+            (b"security.capability".to_vec(), vec![1, 0, 0, 2]),
+            (b"user.note".to_vec(), b"hello".to_vec()),
+        ]);
+        let attributes = json!({ "xattrs": xattrs_to_value(&xattrs) });
+        assert_eq!(
+            attributes["xattrs"]["user.note"],
+            json!(STANDARD.encode(b"hello"))
+        );
+        assert_eq!(xattrs_from_attributes(&attributes), xattrs);
+        assert!(xattrs_from_attributes(&json!({ "xattrs": { "user.bad": 3 } })).is_empty());
+    }
+
+    #[test]
+    fn names_kept_as_bytes() {
+        let xattrs = BTreeMap::from([
+            (b"user.caf\xe9".to_vec(), b"latin-1".to_vec()),
+            (b"base64:user.x".to_vec(), b"prefixed".to_vec()),
+        ]);
+        let value = xattrs_to_value(&xattrs);
+        assert_eq!(
+            value.as_object().unwrap().keys().collect::<Vec<_>>(),
+            [
+                &format!("base64:{}", STANDARD.encode(b"base64:user.x")),
+                &format!("base64:{}", STANDARD.encode(b"user.caf\xe9")),
+            ]
+        );
+        assert_eq!(xattrs_from_attributes(&json!({ "xattrs": value })), xattrs);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_all_namespaces() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let xattrs = BTreeMap::from([
+            (b"user.tumulus.test".to_vec(), b"value".to_vec()),
+            (b"user.tumulus.\xff".to_vec(), b"binary name".to_vec()),
+        ]);
+        match restore_xattrs(file.path(), &xattrs) {
+            Ok(restored) => assert!(restored),
+            // No user xattrs on this filesystem
+            Err(err) if err.raw_os_error() == Some(nix::libc::ENOTSUP) => return,
+            Err(err) => panic!("{err}"),
+        }
+        let read = read_xattrs(file.path()).unwrap();
+        assert_eq!(
+            read.get(&b"user.tumulus.test"[..]),
+            Some(&b"value".to_vec())
+        );
+        assert_eq!(
+            read.get(&b"user.tumulus.\xff"[..]),
+            Some(&b"binary name".to_vec())
+        );
+    }
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tumulus_server::BlobLayout;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(layout) = BlobLayout::decode(data) {
+        // Anything that decodes must survive a roundtrip, and have sane regions
+        assert_eq!(layout.encode().as_ref(), data);
+        let _ = layout.regions();
+    }
+});